@@ -0,0 +1,129 @@
+//! Throughput of the frame codec, each compression algorithm compiled in, and session round
+//! trips over loopback.
+//!
+//!     cargo bench --bench codec
+//!
+//! Compare runs with `--save-baseline` and `--baseline` to catch regressions in the codec or
+//! the reliability layer.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use crumb::compression;
+use crumb::protocol::Frame;
+use crumb::session;
+use crumb::util::config::{CompressionType, Config, DeliveryMode};
+use std::io::IoSlice;
+use std::thread;
+use std::time::Duration;
+
+const SIZES: [usize; 3] = [64, 1024, 16 * 1024];
+
+const COMPRESSIONS: [CompressionType; 3] = [
+    CompressionType::None,
+    CompressionType::Zstd,
+    CompressionType::Gzip,
+];
+
+// Repetitive enough to compress, as telemetry payloads usually are.
+fn payload(len: usize) -> Vec<u8> {
+    b"{\"sensor\":\"temp-01\",\"value\":21.5}"
+        .iter()
+        .copied()
+        .cycle()
+        .take(len)
+        .collect()
+}
+
+fn frame_codec(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frame");
+    for len in SIZES {
+        let frame = Frame {
+            flags: Frame::RELIABLE,
+            seq: 7,
+            ..Frame::new(payload(len))
+        };
+        let bytes = frame.to_bytes();
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+        group.bench_with_input(BenchmarkId::new("encode", len), &frame, |b, frame| {
+            b.iter(|| black_box(frame.to_bytes()))
+        });
+        group.bench_with_input(
+            BenchmarkId::new("encode_vectored", len),
+            &frame,
+            |b, frame| {
+                b.iter(|| {
+                    frame.write_vectored(|slices: &[IoSlice<'_>]| {
+                        Ok(black_box(slices.iter().map(|s| s.len()).sum::<usize>()))
+                    })
+                })
+            },
+        );
+        group.bench_with_input(BenchmarkId::new("decode", len), &bytes, |b, bytes| {
+            b.iter(|| Frame::from_bytes(black_box(bytes)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn compression(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compression");
+    for algorithm in COMPRESSIONS.into_iter().filter(|c| c.is_supported()) {
+        for len in SIZES {
+            let payload = payload(len);
+            let (used, compressed) = compression::compress(algorithm, &payload).unwrap();
+            let id = format!("{:?}/{}", algorithm, len);
+            group.throughput(Throughput::Bytes(len as u64));
+            group.bench_with_input(BenchmarkId::new("compress", &id), &payload, |b, payload| {
+                b.iter(|| compression::compress(algorithm, black_box(payload)).unwrap())
+            });
+            group.bench_with_input(
+                BenchmarkId::new("decompress", &id),
+                &compressed,
+                |b, compressed| {
+                    b.iter(|| compression::decompress(used, black_box(compressed)).unwrap())
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+// One message out and its echo back through the session layer, reliable and not.
+fn session_round_trip(c: &mut Criterion) {
+    let mut group = c.benchmark_group("session_round_trip");
+    group.measurement_time(Duration::from_secs(3));
+    for delivery in [DeliveryMode::AtMostOnce, DeliveryMode::ExactlyOnce] {
+        let conf = Config {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            delivery,
+            ..Default::default()
+        };
+        let server = session::Server::init(&conf).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let shutdown = server.shutdown_handle().unwrap();
+        let echo = thread::spawn(move || server.serve(|message, _| Some(message.to_vec())));
+
+        let client = session::Client::init(&Config { port, ..conf }).unwrap();
+        let mut buffer = vec![0u8; 2048];
+        let message = payload(256);
+        let id = match delivery.is_reliable() {
+            true => "reliable",
+            false => "unreliable",
+        };
+        group.throughput(Throughput::Elements(1));
+        group.bench_function(id, |b| {
+            b.iter(|| {
+                client.send(&message).unwrap();
+                client.receive(&mut buffer).unwrap()
+            })
+        });
+
+        client.close();
+        shutdown.shutdown();
+        echo.join().unwrap().unwrap();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, frame_codec, compression, session_round_trip);
+criterion_main!(benches);
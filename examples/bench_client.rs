@@ -0,0 +1,209 @@
+//! Load generator measuring throughput, round-trip latency and CPU time against `bench_server`.
+//!
+//!     cargo run --release --example bench_client [session|raw] [messages] [payload bytes]
+//!
+//! Keeps `WINDOW` messages in flight, sending another as each echo comes back, and reports
+//! messages per second, latency percentiles and the CPU time the client process used. Messages
+//! whose echo has not arrived within `LOSS_TIMEOUT` are counted as lost. Use the same
+//! `CRUMB_*` configuration as the server.
+
+use crumb::session;
+use crumb::transport::Transport;
+use crumb::util::config::Config;
+use std::env;
+use std::error::Error;
+use std::io;
+use std::time::{Duration, Instant};
+
+// Messages in flight at once.
+const WINDOW: usize = 64;
+
+const LOSS_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let conf = config()?;
+    let mut args = env::args().skip(1);
+    let layer = args.next().unwrap_or_else(|| "session".to_string());
+    let messages: usize = args.next().map_or(Ok(100_000), |n| n.parse())?;
+    let payload_len: usize = args.next().map_or(Ok(64), |n| n.parse())?;
+
+    let channel: Box<dyn Channel> = match layer.as_str() {
+        "session" => Box::new(session::Client::init(&conf)?),
+        "raw" => Box::new(<dyn Transport>::from_config(&conf)?),
+        _ => return Err(format!("unknown layer {}", layer).into()),
+    };
+    channel.set_read_timeout(Some(LOSS_TIMEOUT))?;
+
+    let cpu_before = cpu_time();
+    let report = run(channel.as_ref(), messages, payload_len.max(8))?;
+    let cpu = cpu_time()
+        .zip(cpu_before)
+        .map(|(after, before)| after - before);
+
+    println!(
+        "{} over {:?}, delivery={:?}, compression={:?}, {} byte payloads",
+        layer, conf.transport_type, conf.delivery, conf.compression_type, payload_len
+    );
+    report.print(cpu);
+    Ok(())
+}
+
+// The two layers a client can be measured at.
+trait Channel {
+    fn send(&self, data: &[u8]) -> io::Result<usize>;
+    fn receive(&self, buffer: &mut [u8]) -> io::Result<usize>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Channel for session::Client {
+    fn send(&self, data: &[u8]) -> io::Result<usize> {
+        session::Client::send(self, data)
+    }
+
+    fn receive(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        session::Client::receive(self, buffer)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        session::Client::set_read_timeout(self, timeout)
+    }
+}
+
+impl Channel for Box<dyn Transport> {
+    fn send(&self, data: &[u8]) -> io::Result<usize> {
+        (**self).send(data)
+    }
+
+    fn receive(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        (**self).receive(buffer)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+}
+
+struct Report {
+    elapsed: Duration,
+    // Round trips of the messages that came back, sorted.
+    latencies: Vec<Duration>,
+    lost: usize,
+}
+
+fn run(channel: &dyn Channel, messages: usize, payload_len: usize) -> io::Result<Report> {
+    let mut sent_at: Vec<Option<Instant>> = vec![None; messages];
+    let mut payload = vec![0u8; payload_len];
+    let mut buffer = vec![0u8; payload_len.max(65_507)];
+    let mut latencies = Vec::with_capacity(messages);
+    let mut next = 0;
+    let mut in_flight = 0;
+    let mut lost = 0;
+
+    let start = Instant::now();
+    while next < messages || in_flight > 0 {
+        while in_flight < WINDOW && next < messages {
+            payload[..8].copy_from_slice(&(next as u64).to_be_bytes());
+            channel.send(&payload)?;
+            sent_at[next] = Some(Instant::now());
+            next += 1;
+            in_flight += 1;
+        }
+        match channel.receive(&mut buffer) {
+            Ok(received) if received >= 8 => {
+                let id = u64::from_be_bytes(buffer[..8].try_into().unwrap()) as usize;
+                if let Some(sent) = sent_at.get_mut(id).and_then(Option::take) {
+                    latencies.push(sent.elapsed());
+                    in_flight -= 1;
+                }
+            }
+            Ok(_) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                // Nothing came back for a whole timeout: whatever is still out is lost.
+                let cutoff = Instant::now() - LOSS_TIMEOUT;
+                for sent in sent_at[..next].iter_mut() {
+                    if sent.is_some_and(|sent| sent <= cutoff) {
+                        *sent = None;
+                        lost += 1;
+                        in_flight -= 1;
+                    }
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    latencies.sort_unstable();
+    Ok(Report {
+        elapsed: start.elapsed(),
+        latencies,
+        lost,
+    })
+}
+
+impl Report {
+    fn print(&self, cpu: Option<Duration>) {
+        let delivered = self.latencies.len();
+        println!(
+            "{} echoed, {} lost in {:.2?}: {:.0} msg/s",
+            delivered,
+            self.lost,
+            self.elapsed,
+            delivered as f64 / self.elapsed.as_secs_f64()
+        );
+        if delivered > 0 {
+            println!(
+                "latency p50 {:?}  p90 {:?}  p99 {:?}  max {:?}",
+                self.percentile(50.0),
+                self.percentile(90.0),
+                self.percentile(99.0),
+                self.latencies[delivered - 1]
+            );
+        }
+        if let Some(cpu) = cpu {
+            println!(
+                "cpu {:.2?} ({:.0}% of one core)",
+                cpu,
+                100.0 * cpu.as_secs_f64() / self.elapsed.as_secs_f64()
+            );
+        }
+    }
+
+    fn percentile(&self, p: f64) -> Duration {
+        let rank = (p / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+    }
+}
+
+// User plus system time this process has used.
+#[cfg(unix)]
+fn cpu_time() -> Option<Duration> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: getrusage fills in `usage` when it returns 0.
+    let usage = unsafe {
+        if libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) != 0 {
+            return None;
+        }
+        usage.assume_init()
+    };
+    let time = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+    Some(time(usage.ru_utime) + time(usage.ru_stime))
+}
+
+#[cfg(not(unix))]
+fn cpu_time() -> Option<Duration> {
+    None
+}
+
+// The `CRUMB_*` configuration. Benchmarks send raw payloads, so no .proto file is needed.
+fn config() -> crumb::Result<Config> {
+    let mut builder = Config::builder();
+    if env::var_os("CRUMB_PROTO_PATH").is_none() {
+        builder = builder.set("CRUMB_PROTO_PATH", "");
+    }
+    builder.build()
+}
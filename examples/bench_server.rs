@@ -0,0 +1,54 @@
+//! Echo server for `bench_client`.
+//!
+//!     cargo run --release --example bench_server [session|raw]
+//!
+//! Configured from `CRUMB_*` variables like any crumb server; run the client with the same
+//! `CRUMB_TRANSPORT`, `CRUMB_RELIABLE`, `CRUMB_COMPRESSION_TYPE` and `CRUMB_SECURITY` to measure
+//! that combination. `session` (the default, UDP only) echoes through the session layer, with its
+//! reliability, compression and encryption; `raw` echoes datagrams straight off the transport.
+
+use crumb::session;
+use crumb::transport::udp;
+use crumb::util::config::{Config, TransportType};
+use std::env;
+use std::error::Error;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let conf = config()?;
+    let layer = env::args().nth(1).unwrap_or_else(|| "session".to_string());
+    println!(
+        "echoing on {}:{} ({}, {:?}, delivery={:?}, compression={:?})",
+        conf.host, conf.port, layer, conf.transport_type, conf.delivery, conf.compression_type
+    );
+
+    match (layer.as_str(), conf.transport_type) {
+        ("session", TransportType::Udp) => session::Server::init(&conf)?.serve(echo)?,
+        ("raw", TransportType::Udp) => udp::Server::init(&conf)?.serve(echo)?,
+        #[cfg(feature = "quic")]
+        ("raw", TransportType::Quic) => {
+            let server = crumb::transport::quic::Server::init(&conf)?;
+            let mut buffer = vec![0u8; 65_507];
+            loop {
+                let (received, peer) = server.receive_from(&mut buffer)?;
+                server.send_to(&buffer[..received], peer)?;
+            }
+        }
+        (layer, transport) => {
+            return Err(format!("cannot run {} over {:?} in this build", layer, transport).into())
+        }
+    }
+    Ok(())
+}
+
+fn echo(message: &[u8], _: std::net::SocketAddr) -> Option<Vec<u8>> {
+    Some(message.to_vec())
+}
+
+// The `CRUMB_*` configuration. Benchmarks send raw payloads, so no .proto file is needed.
+fn config() -> crumb::Result<Config> {
+    let mut builder = Config::builder();
+    if env::var_os("CRUMB_PROTO_PATH").is_none() {
+        builder = builder.set("CRUMB_PROTO_PATH", "");
+    }
+    builder.build()
+}
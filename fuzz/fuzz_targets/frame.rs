@@ -0,0 +1,28 @@
+//! Feeds arbitrary datagrams to the frame decoder, which parses whatever the network delivers.
+//!
+//!     cargo +nightly fuzz run frame
+//!
+//! Most random inputs fail the checksum, so each is decoded twice: as it is, and with the
+//! checksum rewritten to match, which lets the fuzzer reach the fields behind it. Whatever
+//! decodes must encode back to the same bytes.
+
+#![no_main]
+
+use crumb::protocol::Frame;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    check(data);
+    if data.len() >= 5 {
+        let mut fixed = data.to_vec();
+        let checksum = crc32fast::hash(&fixed[5..]);
+        fixed[1..5].copy_from_slice(&checksum.to_be_bytes());
+        check(&fixed);
+    }
+});
+
+fn check(bytes: &[u8]) {
+    if let Ok(frame) = Frame::from_bytes(bytes) {
+        assert_eq!(frame.to_bytes(), bytes);
+    }
+}
@@ -0,0 +1,322 @@
+//! Command-line smoke tests for crumb deployments.
+//!
+//!     crumb [--config FILE] send [--raw] [MESSAGE...]
+//!     crumb [--config FILE] listen [--raw] [--echo]
+//!     crumb [--config FILE] ping [--raw] [COUNT]
+//!     crumb [--config FILE] replay CAPTURE
+//!     crumb [--config FILE] decode [FRAME...]
+//!     crumb [--config FILE] control COMMAND...
+//!
+//! Every subcommand is configured like an application would be: from `CRUMB_*` variables,
+//! layered over the TOML or YAML file given with `--config`. Messages go through the session
+//! layer, with its reliability, compression and pre-shared key, unless `--raw` is given, in
+//! which case they go straight over the configured transport, QUIC handshake included.
+//!
+//! `send` sends each MESSAGE, or each line of standard input if there are none. `listen` prints
+//! every message received, and with `--echo` sends it back. `ping` sends COUNT pings (default 4)
+//! a second apart and reports their round trips. Session servers, `listen` included, answer
+//! pings by themselves; with `--raw` the peer must be a `listen --raw --echo`. `replay` sends
+//! the frames a pcapng CAPTURE recorded as sent to the configured server again, as they were
+//! and with their original spacing; see `capture_path` for recording one. `decode` prints every
+//! header field of each FRAME, given in hex, or of the datagram read from standard input if
+//! there are none, and its payload; protobuf payloads are broken into their fields and their
+//! schema version checked against `proto_path`. `control` sends COMMAND to the configured
+//! server as a control request sealed with `control_key`, and prints its answer: `stats`,
+//! `sessions` or `log-level LEVEL`.
+
+use crumb::capture;
+use crumb::protocol;
+use crumb::schema::SchemaRegistry;
+use crumb::session;
+use crumb::transport::{udp, Transport};
+use crumb::util::config::{Config, TransportType};
+use crumb::Error;
+use std::env;
+use std::io::{self, BufRead, Read};
+use std::net::SocketAddr;
+use std::process::ExitCode;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "usage: crumb [--config FILE] <send [--raw] [MESSAGE...] | listen [--raw] [--echo] | ping [--raw] [COUNT] | replay CAPTURE | decode [FRAME...] | control COMMAND...>";
+
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long `ping` waits for each reply.
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn main() -> ExitCode {
+    match run(env::args().skip(1).collect()) {
+        Ok(()) => ExitCode::SUCCESS,
+        // Usage and config messages stand on their own.
+        Err(Error::Config(e)) => {
+            eprintln!("crumb: {}", e);
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            eprintln!("crumb: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(mut args: Vec<String>) -> crumb::Result<()> {
+    let config_file = match args.iter().position(|arg| arg == "--config") {
+        Some(i) if i + 1 < args.len() => Some(args.drain(i..i + 2).nth(1).unwrap()),
+        Some(_) => return Err(Error::Config(USAGE.into())),
+        None => None,
+    };
+    let raw = take_flag(&mut args, "--raw");
+    let echo = take_flag(&mut args, "--echo");
+    let conf = config(config_file.as_deref())?;
+
+    let Some(command) = args.first() else {
+        return Err(Error::Config(USAGE.into()));
+    };
+    let operands = &args[1..];
+    match command.as_str() {
+        "send" => send(&conf, raw, operands),
+        "listen" => listen(&conf, raw, echo),
+        "ping" => {
+            let count = match operands.first() {
+                Some(count) => count.parse().map_err(|e| Error::Config(Box::new(e)))?,
+                None => 4,
+            };
+            ping(&conf, raw, count)
+        }
+        "replay" => match operands {
+            [capture] => {
+                let sent = capture::replay(&conf, capture)?;
+                println!("replayed {} frames to {}:{}", sent, conf.host, conf.port);
+                Ok(())
+            }
+            _ => Err(Error::Config(USAGE.into())),
+        },
+        "decode" => decode(&conf, operands),
+        "control" if !operands.is_empty() => {
+            let client = session::Client::init(&conf)?;
+            println!("{}", client.control(&operands.join(" "))?);
+            client.close();
+            Ok(())
+        }
+        _ => Err(Error::Config(USAGE.into())),
+    }
+}
+
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let before = args.len();
+    args.retain(|arg| arg != flag);
+    args.len() != before
+}
+
+// The CLI sends raw payloads, so unlike applications it needs no .proto file.
+fn config(file: Option<&str>) -> crumb::Result<Config> {
+    let mut builder = Config::builder();
+    if let Some(file) = file {
+        builder = builder.file(file);
+    }
+    if env::var_os("CRUMB_PROTO_PATH").is_none() {
+        builder = builder.set("CRUMB_PROTO_PATH", "");
+    }
+    builder.build()
+}
+
+// A client at either layer.
+enum Client {
+    Session(session::Client),
+    Raw(Box<dyn Transport>),
+}
+
+impl Client {
+    fn init(conf: &Config, raw: bool) -> io::Result<Client> {
+        Ok(match raw {
+            true => Client::Raw(<dyn Transport>::from_config(conf)?),
+            false => Client::Session(session::Client::init(conf)?),
+        })
+    }
+
+    fn send(&self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            Client::Session(client) => client.send(data),
+            Client::Raw(transport) => transport.send(data),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Client::Session(client) => client.set_read_timeout(timeout),
+            Client::Raw(transport) => transport.set_read_timeout(timeout),
+        }
+    }
+
+    // Session servers answer pings themselves; a raw transport needs a peer echoing messages.
+    fn ping(&self, seq: u32, buffer: &mut [u8]) -> io::Result<Duration> {
+        let transport = match self {
+            Client::Session(client) => return client.ping(),
+            Client::Raw(transport) => transport,
+        };
+        let ping = format!("crumb ping {}", seq);
+        let sent_at = Instant::now();
+        transport.send(ping.as_bytes())?;
+        // Skip late replies to earlier pings.
+        loop {
+            let received = transport.receive(buffer)?;
+            if buffer[..received] == *ping.as_bytes() {
+                return Ok(sent_at.elapsed());
+            }
+        }
+    }
+
+    // Session clients linger until what they sent is acknowledged.
+    fn close(self) {
+        match self {
+            Client::Session(client) => client.close(),
+            Client::Raw(transport) => transport.close(),
+        }
+    }
+}
+
+fn send(conf: &Config, raw: bool, messages: &[String]) -> crumb::Result<()> {
+    let client = Client::init(conf, raw)?;
+    if messages.is_empty() {
+        for line in io::stdin().lock().lines() {
+            client.send(line?.as_bytes())?;
+        }
+    }
+    for message in messages {
+        client.send(message.as_bytes())?;
+    }
+    client.close();
+    Ok(())
+}
+
+fn listen(conf: &Config, raw: bool, echo: bool) -> crumb::Result<()> {
+    println!(
+        "listening on {} port {} ({:?}{})",
+        conf.bind_host,
+        conf.port,
+        conf.transport_type,
+        if raw { ", raw" } else { "" }
+    );
+    let print = move |message: &[u8], peer: SocketAddr| {
+        println!("{}: {}", peer, String::from_utf8_lossy(message));
+        echo.then(|| message.to_vec())
+    };
+    match (raw, conf.transport_type) {
+        (false, TransportType::Udp) => session::Server::init(conf)?.serve(print)?,
+        (true, TransportType::Udp) => udp::Server::init(conf)?.serve(print)?,
+        #[cfg(feature = "quic")]
+        (true, TransportType::Quic) => {
+            let server = crumb::transport::quic::Server::init(conf)?;
+            let mut buffer = vec![0u8; 65_507];
+            loop {
+                let (received, peer) = server.receive_from(&mut buffer)?;
+                if let Some(reply) = print(&buffer[..received], peer) {
+                    server.send_to(&reply, peer)?;
+                }
+            }
+        }
+        #[cfg(unix)]
+        (true, TransportType::Unix) => {
+            crumb::transport::unix::Server::init(conf)?.serve(|message, peer| {
+                println!("{}: {}", peer.display(), String::from_utf8_lossy(message));
+                echo.then(|| message.to_vec())
+            })?
+        }
+        (false, transport) => {
+            return Err(Error::Config(
+                format!("sessions are not available over {:?}, try --raw", transport).into(),
+            ))
+        }
+        #[allow(unreachable_patterns)]
+        (true, transport) => {
+            return Err(Error::Config(
+                format!("crumb was built without {:?} support", transport).into(),
+            ))
+        }
+    }
+    Ok(())
+}
+
+fn ping(conf: &Config, raw: bool, count: u32) -> crumb::Result<()> {
+    let client = Client::init(conf, raw)?;
+    client.set_read_timeout(Some(PING_TIMEOUT))?;
+    let mut buffer = vec![0u8; 1024];
+    let mut round_trips = Vec::new();
+
+    for seq in 0..count {
+        if seq > 0 {
+            thread::sleep(PING_INTERVAL);
+        }
+        match client.ping(seq, &mut buffer).map_err(Error::from) {
+            Ok(rtt) => {
+                println!(
+                    "reply from {}:{}: seq={} time={:.2?}",
+                    conf.host, conf.port, seq, rtt
+                );
+                round_trips.push(rtt);
+            }
+            Err(Error::Timeout) => println!("seq={} timed out", seq),
+            Err(e) => return Err(e),
+        }
+    }
+    client.close();
+
+    let received = round_trips.len() as u32;
+    println!(
+        "{} sent, {} received, {:.0}% loss",
+        count,
+        received,
+        100.0 * f64::from(count - received) / f64::from(count.max(1))
+    );
+    if let (Some(min), Some(max)) = (round_trips.iter().min(), round_trips.iter().max()) {
+        let avg = round_trips.iter().sum::<Duration>() / received;
+        println!("rtt min/avg/max = {:.2?}/{:.2?}/{:.2?}", min, avg, max);
+    }
+    if received == 0 {
+        // Every ping timed out, as any other failure ends the run early.
+        return Err(Error::Timeout);
+    }
+    Ok(())
+}
+
+fn decode(conf: &Config, frames: &[String]) -> crumb::Result<()> {
+    let frames = match frames.is_empty() {
+        true => {
+            let mut datagram = Vec::new();
+            io::stdin().lock().read_to_end(&mut datagram)?;
+            vec![datagram]
+        }
+        false => frames
+            .iter()
+            .map(|frame| unhex(frame).ok_or_else(|| format!("not hex: {}", frame)))
+            .collect::<Result<_, _>>()
+            .map_err(|e| Error::Config(e.into()))?,
+    };
+    let schema = SchemaRegistry::new(conf);
+    for (i, frame) in frames.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        print!("{}", protocol::inspect(frame).with_schema(&schema));
+    }
+    Ok(())
+}
+
+// Hex digits in either case, optionally separated by whitespace or colons as dumps often are.
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = text
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != ':')
+        .map(|c| c.to_digit(16).map(|digit| digit as u8))
+        .collect::<Option<_>>()?;
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    Some(
+        digits
+            .chunks(2)
+            .map(|pair| pair[0] << 4 | pair[1])
+            .collect(),
+    )
+}
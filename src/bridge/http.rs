@@ -0,0 +1,369 @@
+//! An HTTP ingestion shim, for producers that cannot speak crumb: the body of each
+//! `POST /messages` is sent as one message to the server at `host:port`.
+//!
+//! The body's `Content-Type` declares the message's payload format: JSON for `application/json`,
+//! MessagePack for `application/msgpack`, protobuf for `application/x-protobuf`, and raw bytes
+//! for `application/octet-stream` or no type at all. Other types are refused with `415`.
+//! Accepted messages are answered with `202 Accepted` once queued, and refused ones with a JSON
+//! error:
+//!
+//! ```text
+//! $ curl -H 'Content-Type: application/json' -d '{"temp":21.5}' localhost:8080/messages
+//! {"bytes":13}
+//! ```
+//!
+//! With `ingest_token` set, requests must carry it as `Authorization: Bearer <token>`. Requests
+//! are handled one at a time, so a slow producer holds up the others for up to
+//! `REQUEST_TIMEOUT`.
+
+use crate::protocol::Priority;
+use crate::session::{self, escape};
+use crate::util::config::{Config, PayloadFormat};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tracing::{debug, trace};
+
+const TARGET: &str = "crumb::bridge::http";
+
+// How long accepting blocks before checking whether the shim was stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a producer may take to send its whole request.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Request and header lines are read no further than this.
+const MAX_LINE: u64 = 8 * 1024;
+const MAX_HEADERS: usize = 64;
+
+/// Largest body accepted, as no datagram carries more. Messages are also held to the limits
+/// `Client::send` applies.
+pub const MAX_BODY: usize = 65_507;
+
+/// Accepts messages over HTTP and sends them on through a session client, from a thread of its
+/// own until dropped.
+pub struct Ingest {
+    local_addr: SocketAddr,
+    running: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Ingest {
+    /// Connects a session client to `host:port` and listens on `ingest_port`.
+    pub fn start(conf: &Config) -> io::Result<Ingest> {
+        let client = session::Client::init(conf)?;
+        let listener = TcpListener::bind((conf.bind_host.as_str(), conf.ingest_port))?;
+        // Accepting without blocking lets the thread notice the shim being stopped.
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let token = conf.ingest_token.clone();
+        let running = Arc::new(AtomicBool::new(true));
+
+        let worker = {
+            let running = running.clone();
+            thread::Builder::new()
+                .name("crumb-ingest".to_string())
+                .spawn(move || {
+                    while running.load(Ordering::Acquire) {
+                        match listener.accept() {
+                            Ok((stream, peer)) => {
+                                if let Err(e) = respond(&client, &token, stream) {
+                                    trace!(target: TARGET, %peer, error = %e, "request failed");
+                                }
+                            }
+                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                                thread::sleep(POLL_INTERVAL)
+                            }
+                            Err(e) => {
+                                debug!(target: TARGET, error = %e, "accepting a request failed");
+                                thread::sleep(POLL_INTERVAL);
+                            }
+                        }
+                    }
+                })?
+        };
+
+        debug!(target: TARGET, %local_addr, "accepting messages over HTTP");
+        Ok(Ingest {
+            local_addr,
+            running,
+            worker: Some(worker),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn stop(self) {
+        drop(self);
+    }
+}
+
+impl Drop for Ingest {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// The parts of a request the shim looks at.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Request {
+    method: String,
+    path: String,
+    content_type: Option<String>,
+    content_length: Option<usize>,
+    authorization: Option<String>,
+    expect_continue: bool,
+    chunked: bool,
+}
+
+// Answers one request, closing the connection after it.
+fn respond(client: &session::Client, token: &str, stream: TcpStream) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let (status, body) = match handle(client, token, &mut reader, &stream) {
+        Ok(bytes) => ("202 Accepted", format!("{{\"bytes\":{}}}", bytes)),
+        Err((status, message)) => {
+            trace!(target: TARGET, status, message, "request refused");
+            (status, format!("{{\"error\":\"{}\"}}", escape(&message)))
+        }
+    };
+    write!(
+        &stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+// Reads a request and sends its body on, returning the body's length, or the status and reason
+// to refuse it with.
+fn handle(
+    client: &session::Client,
+    token: &str,
+    reader: &mut impl BufRead,
+    mut stream: &TcpStream,
+) -> Result<usize, (&'static str, String)> {
+    let request = read_head(reader).map_err(|e| ("400 Bad Request", e.to_string()))?;
+    match (request.method.as_str(), request.path.as_str()) {
+        ("POST", "/messages") => {}
+        (_, "/messages") => return Err(("405 Method Not Allowed", "use POST".to_string())),
+        (_, path) => return Err(("404 Not Found", format!("no such path: {}", path))),
+    }
+    if !token.is_empty() && request.authorization.as_deref() != Some(&format!("Bearer {}", token)) {
+        return Err(("401 Unauthorized", "missing or wrong token".to_string()));
+    }
+    let format = payload_format(request.content_type.as_deref()).ok_or_else(|| {
+        (
+            "415 Unsupported Media Type",
+            format!(
+                "unsupported content type: {}",
+                request.content_type.unwrap_or_default()
+            ),
+        )
+    })?;
+    let len = match request.content_length {
+        Some(len) if !request.chunked => len,
+        _ => {
+            return Err((
+                "411 Length Required",
+                "a Content-Length is required".to_string(),
+            ))
+        }
+    };
+    if len > MAX_BODY {
+        return Err((
+            "413 Content Too Large",
+            format!("bodies are at most {} bytes", MAX_BODY),
+        ));
+    }
+    if request.expect_continue {
+        stream
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .map_err(|e| ("400 Bad Request", e.to_string()))?;
+    }
+    let mut body = vec![0u8; len];
+    reader
+        .read_exact(&mut body)
+        .map_err(|e| ("400 Bad Request", format!("incomplete body: {}", e)))?;
+
+    client
+        .send_as(format, Priority::Normal, &body)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidInput => ("413 Content Too Large", e.to_string()),
+            _ => ("503 Service Unavailable", e.to_string()),
+        })
+}
+
+// The request line and the headers the shim looks at.
+fn read_head(reader: &mut impl BufRead) -> io::Result<Request> {
+    let mut line = String::new();
+    let mut read_line = |line: &mut String| -> io::Result<()> {
+        line.clear();
+        reader.by_ref().take(MAX_LINE).read_line(line)?;
+        if !line.ends_with('\n') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "line too long or unterminated",
+            ));
+        }
+        Ok(())
+    };
+
+    read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let mut request = Request {
+        method: parts.next().unwrap_or_default().to_string(),
+        path: parts.next().unwrap_or_default().to_string(),
+        ..Default::default()
+    };
+    for _ in 0..MAX_HEADERS {
+        read_line(&mut line)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            return Ok(request);
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "malformed header",
+            ));
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-type" => request.content_type = Some(value.to_string()),
+            "content-length" => {
+                request.content_length = Some(value.parse().map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "invalid Content-Length")
+                })?)
+            }
+            "authorization" => request.authorization = Some(value.to_string()),
+            "expect" => request.expect_continue = value.eq_ignore_ascii_case("100-continue"),
+            "transfer-encoding" => request.chunked = !value.eq_ignore_ascii_case("identity"),
+            _ => {}
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "too many headers",
+    ))
+}
+
+// The payload format a body of `content_type` is declared as, ignoring parameters such as the
+// charset.
+fn payload_format(content_type: Option<&str>) -> Option<PayloadFormat> {
+    let Some(content_type) = content_type else {
+        return Some(PayloadFormat::Raw);
+    };
+    let media_type = content_type.split(';').next()?.trim().to_ascii_lowercase();
+    match media_type.as_str() {
+        "application/octet-stream" => Some(PayloadFormat::Raw),
+        "application/json" => Some(PayloadFormat::Json),
+        "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+            Some(PayloadFormat::Msgpack)
+        }
+        "application/protobuf" | "application/x-protobuf" | "application/vnd.google.protobuf" => {
+            Some(PayloadFormat::Protobuf)
+        }
+        media_type if media_type.ends_with("+json") => Some(PayloadFormat::Json),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(addr: SocketAddr, headers: &str, body: &[u8]) -> io::Result<String> {
+        let mut stream = TcpStream::connect(addr)?;
+        write!(
+            stream,
+            "POST /messages HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n{}\r\n",
+            body.len(),
+            headers
+        )?;
+        stream.write_all(body)?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    #[test]
+    fn posted_bodies_are_sent_on() -> io::Result<()> {
+        let conf = Config {
+            host: "127.0.0.1".to_string(),
+            port: 8142,
+            bind_host: "127.0.0.1".to_string(),
+            ingest_port: 0,
+            ingest_token: "secret".to_string(),
+            ..Default::default()
+        };
+        let server = session::Server::init(&conf)?;
+        server.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let ingest = Ingest::start(&conf)?;
+        let addr = ingest.local_addr();
+
+        let auth = "Authorization: Bearer secret\r\n";
+        let response = post(
+            addr,
+            &format!("{}Content-Type: application/json\r\n", auth),
+            b"{\"temp\":21.5}",
+        )?;
+        assert!(
+            response.starts_with("HTTP/1.1 202 Accepted\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.ends_with("{\"bytes\":13}"), "{}", response);
+        let mut buffer = [0u8; 64];
+        let (received, _) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"{\"temp\":21.5}");
+
+        let response = post(addr, &format!("{}Expect: 100-continue\r\n", auth), b"raw")?;
+        assert!(
+            response.starts_with("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 202"),
+            "{}",
+            response
+        );
+        let (received, _) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"raw");
+
+        assert!(post(addr, "", b"x")?.starts_with("HTTP/1.1 401"));
+        let response = post(addr, &format!("{}Content-Type: text/plain\r\n", auth), b"x")?;
+        assert!(response.starts_with("HTTP/1.1 415"), "{}", response);
+        Ok(())
+    }
+
+    #[test]
+    fn heads_are_parsed() {
+        let mut head: &[u8] = b"POST /messages HTTP/1.1\r\nContent-Type: application/json; charset=utf-8\r\ncontent-length: 4\r\nTransfer-Encoding: chunked\r\n\r\nbody";
+        let request = read_head(&mut head).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/messages");
+        assert_eq!(request.content_length, Some(4));
+        assert!(request.chunked);
+        assert_eq!(
+            payload_format(request.content_type.as_deref()),
+            Some(PayloadFormat::Json)
+        );
+        assert_eq!(head, b"body");
+
+        let mut unterminated: &[u8] = b"POST /messages HTTP/1.1\r\nHost: x";
+        assert!(read_head(&mut unterminated).is_err());
+        assert_eq!(payload_format(None), Some(PayloadFormat::Raw));
+        assert_eq!(
+            payload_format(Some("application/cloudevents+json")),
+            Some(PayloadFormat::Json)
+        );
+        assert_eq!(payload_format(Some("text/csv")), None);
+    }
+}
@@ -0,0 +1,349 @@
+//! A sink producing crumb pub/sub messages to a Kafka topic, for ingesting edge telemetry into
+//! stream processing.
+//!
+//! The sink subscribes to `kafka_filters` and produces each message it receives to `kafka_topic`,
+//! its payload as the record's value and, with `kafka_key`, part of its topic as the record's key,
+//! so that messages from one device land in one partition in order. Records are batched by the
+//! producer for up to `kafka_linger` or `kafka_batch_size` records, whichever comes first.
+//!
+//! ```no_run
+//! use crumb::bridge::kafka::Sink;
+//! use crumb::util::config::Config;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! // Keys `sensors/<device>/...` messages by device.
+//! let conf = Config {
+//!     kafka_brokers: "kafka1:9092,kafka2:9092".to_string(),
+//!     kafka_topic: "crumb-telemetry".to_string(),
+//!     kafka_filters: "sensors/#".to_string(),
+//!     kafka_key: "level:1".to_string(),
+//!     ..Config::from_env(None)?
+//! };
+//! let sink = Sink::start(&conf)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! With `KafkaDelivery::AtMostOnce`, messages arriving while the producer's queue is full are
+//! dropped; otherwise the sink waits for room. A dropped sink waits up to `FLUSH_TIMEOUT` for the
+//! cluster to acknowledge the records still outstanding.
+
+use crate::error::Error;
+use crate::pubsub;
+use crate::transport::is_timeout;
+use crate::util::config::{Config, KafkaDelivery};
+use rdkafka::config::{ClientConfig, RDKafkaLogLevel};
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::message::Message;
+use rdkafka::producer::{BaseRecord, DeliveryResult, Producer, ProducerContext, ThreadedProducer};
+use rdkafka::ClientContext;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, trace, warn};
+
+const TARGET: &str = "crumb::bridge::kafka";
+
+// How long the sink blocks before checking whether it was stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Pub/sub subscriptions travel in single datagrams, so they are repeated in case the broker
+// missed one or restarted.
+const RESUBSCRIBE_INTERVAL: Duration = Duration::from_secs(30);
+
+// How long to wait for room in a full producer queue before trying again.
+const QUEUE_FULL_DELAY: Duration = Duration::from_millis(10);
+
+/// How long a dropped sink waits for outstanding records to be acknowledged.
+pub const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Produces the crumb messages it receives to Kafka from a thread of its own, until dropped.
+pub struct Sink {
+    running: Arc<AtomicBool>,
+    producer: Arc<ThreadedProducer<Reports>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Sink {
+    /// Connects to the crumb broker at `host:port` and subscribes to `kafka_filters`. Fails with
+    /// `Error::Config` when `kafka_brokers` or `kafka_topic` is empty or `kafka_key` is invalid.
+    /// The Kafka cluster is connected to in the background.
+    pub fn start(conf: &Config) -> io::Result<Sink> {
+        if conf.kafka_brokers.is_empty() {
+            return Err(invalid(
+                "CRUMB_KAFKA_BROKERS",
+                "no brokers given".to_string(),
+            ));
+        }
+        if conf.kafka_topic.is_empty() {
+            return Err(invalid("CRUMB_KAFKA_TOPIC", "no topic given".to_string()));
+        }
+        let key = Key::parse(&conf.kafka_key).map_err(|e| invalid("CRUMB_KAFKA_KEY", e))?;
+        let filters: Vec<String> = conf
+            .kafka_filters
+            .split(',')
+            .map(str::trim)
+            .filter(|filter| !filter.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        let producer: ThreadedProducer<Reports> = client_config(conf)
+            .create_with_context(Reports::default())
+            .map_err(|e| invalid("CRUMB_KAFKA_BROKERS", e.to_string()))?;
+        let producer = Arc::new(producer);
+        let crumb = pubsub::Client::init(conf)?;
+        crumb.set_read_timeout(Some(POLL_INTERVAL))?;
+        for filter in &filters {
+            crumb.subscribe(filter)?;
+        }
+
+        let running = Arc::new(AtomicBool::new(true));
+        let worker = {
+            let running = running.clone();
+            let producer = producer.clone();
+            let topic = conf.kafka_topic.clone();
+            let drop_when_full = conf.kafka_delivery == KafkaDelivery::AtMostOnce;
+            thread::Builder::new()
+                .name("crumb-kafka-sink".to_string())
+                .spawn(move || {
+                    let mut buffer = vec![0u8; pubsub::MAX_MESSAGE_SIZE];
+                    let mut subscribed = Instant::now();
+                    while running.load(Ordering::Acquire) {
+                        if subscribed.elapsed() >= RESUBSCRIBE_INTERVAL {
+                            subscribed = Instant::now();
+                            for filter in &filters {
+                                if let Err(e) = crumb.subscribe(filter) {
+                                    debug!(target: TARGET, filter, error = %e, "resubscribing failed");
+                                }
+                            }
+                        }
+                        let (crumb_topic, len) = match crumb.receive(&mut buffer) {
+                            Ok(received) => received,
+                            Err(e) if is_timeout(&e) => continue,
+                            Err(e) => {
+                                debug!(target: TARGET, error = %e, "receive failed");
+                                thread::sleep(POLL_INTERVAL);
+                                continue;
+                            }
+                        };
+                        let mut record = BaseRecord::to(&topic).payload(&buffer[..len]);
+                        if let Some(key) = key.extract(&crumb_topic) {
+                            record = record.key(key);
+                        }
+                        loop {
+                            match producer.send(record) {
+                                Ok(()) => {
+                                    trace!(target: TARGET, topic = crumb_topic, bytes = len, "queued record");
+                                    break;
+                                }
+                                Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), unsent))
+                                    if !drop_when_full && running.load(Ordering::Acquire) =>
+                                {
+                                    record = unsent;
+                                    thread::sleep(QUEUE_FULL_DELAY);
+                                }
+                                Err((e, _)) => {
+                                    debug!(target: TARGET, topic = crumb_topic, error = %e, "dropped message for Kafka");
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    crumb.close();
+                })?
+        };
+
+        debug!(target: TARGET, brokers = conf.kafka_brokers, topic = conf.kafka_topic, "sink started");
+        Ok(Sink {
+            running,
+            producer,
+            worker: Some(worker),
+        })
+    }
+
+    /// Records the cluster has acknowledged.
+    pub fn delivered(&self) -> u64 {
+        self.producer.context().delivered.load(Ordering::Relaxed)
+    }
+
+    /// Records that could not be produced, after any retries.
+    pub fn failed(&self) -> u64 {
+        self.producer.context().failed.load(Ordering::Relaxed)
+    }
+
+    pub fn stop(self) {
+        drop(self);
+    }
+}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+        if let Err(e) = self.producer.flush(FLUSH_TIMEOUT) {
+            warn!(target: TARGET, error = %e, in_flight = self.producer.in_flight_count(), "records were not all delivered");
+        }
+    }
+}
+
+// The producer's settings for `conf`.
+fn client_config(conf: &Config) -> ClientConfig {
+    let mut config = ClientConfig::new();
+    config
+        .set("bootstrap.servers", &conf.kafka_brokers)
+        .set("batch.num.messages", conf.kafka_batch_size.to_string())
+        .set("linger.ms", conf.kafka_linger.as_millis().to_string())
+        .set_log_level(RDKafkaLogLevel::Info);
+    if !conf.node_id.is_empty() {
+        config.set("client.id", format!("crumb-{}", conf.node_id));
+    }
+    match conf.kafka_delivery {
+        KafkaDelivery::AtMostOnce => config.set("acks", "0").set("retries", "0"),
+        KafkaDelivery::AtLeastOnce => config.set("acks", "all"),
+        KafkaDelivery::Idempotent => config.set("acks", "all").set("enable.idempotence", "true"),
+    };
+    config
+}
+
+/// Counts delivery reports, and passes librdkafka's logs on to `tracing`.
+#[derive(Default)]
+struct Reports {
+    delivered: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl ClientContext for Reports {
+    fn log(&self, level: RDKafkaLogLevel, fac: &str, log_message: &str) {
+        match level {
+            RDKafkaLogLevel::Emerg
+            | RDKafkaLogLevel::Alert
+            | RDKafkaLogLevel::Critical
+            | RDKafkaLogLevel::Error => error!(target: TARGET, fac, "{}", log_message),
+            RDKafkaLogLevel::Warning => warn!(target: TARGET, fac, "{}", log_message),
+            RDKafkaLogLevel::Notice | RDKafkaLogLevel::Info => {
+                info!(target: TARGET, fac, "{}", log_message)
+            }
+            RDKafkaLogLevel::Debug => debug!(target: TARGET, fac, "{}", log_message),
+        }
+    }
+
+    fn error(&self, error: KafkaError, reason: &str) {
+        warn!(target: TARGET, error = %error, reason, "Kafka client error");
+    }
+}
+
+impl ProducerContext for Reports {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, result: &DeliveryResult<'_>, _: ()) {
+        match result {
+            Ok(_) => {
+                self.delivered.fetch_add(1, Ordering::Relaxed);
+            }
+            Err((e, message)) => {
+                self.failed.fetch_add(1, Ordering::Relaxed);
+                warn!(target: TARGET, topic = message.topic(), error = %e, "record was not delivered");
+            }
+        }
+    }
+}
+
+/// What records are keyed by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    None,
+    Topic,
+    Level(usize),
+}
+
+impl Key {
+    fn parse(key: &str) -> Result<Key, String> {
+        match key.trim() {
+            "" => Ok(Key::None),
+            "topic" => Ok(Key::Topic),
+            key => key
+                .strip_prefix("level:")
+                .and_then(|level| level.parse().ok())
+                .map(Key::Level)
+                .ok_or_else(|| format!("expected topic or level:N: {}", key)),
+        }
+    }
+
+    /// The key of a record for a message on `topic`. `None` when unkeyed, and when `topic` has
+    /// no such level.
+    fn extract<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        match *self {
+            Key::None => None,
+            Key::Topic => Some(topic),
+            Key::Level(level) => topic.split('/').nth(level),
+        }
+    }
+}
+
+fn invalid(var: &str, message: String) -> io::Error {
+    Error::config(format!("{}: {}", var, message)).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_are_keyed_by_topic_level() {
+        assert_eq!(Key::parse(""), Ok(Key::None));
+        assert_eq!(Key::parse("topic"), Ok(Key::Topic));
+        assert_eq!(Key::parse("level:1"), Ok(Key::Level(1)));
+        assert!(Key::parse("level:").is_err());
+        assert!(Key::parse("payload").is_err());
+
+        assert_eq!(Key::None.extract("sensors/dev7/temp"), None);
+        assert_eq!(
+            Key::Topic.extract("sensors/dev7/temp"),
+            Some("sensors/dev7/temp")
+        );
+        assert_eq!(Key::Level(1).extract("sensors/dev7/temp"), Some("dev7"));
+        assert_eq!(Key::Level(3).extract("sensors/dev7/temp"), None);
+    }
+
+    #[test]
+    fn delivery_guarantees_set_acks() {
+        let conf = |kafka_delivery| Config {
+            kafka_brokers: "kafka:9092".to_string(),
+            kafka_delivery,
+            ..Default::default()
+        };
+        let at_most_once = client_config(&conf(KafkaDelivery::AtMostOnce));
+        assert_eq!(at_most_once.get("acks"), Some("0"));
+        assert_eq!(at_most_once.get("retries"), Some("0"));
+        let at_least_once = client_config(&conf(KafkaDelivery::AtLeastOnce));
+        assert_eq!(at_least_once.get("acks"), Some("all"));
+        assert_eq!(at_least_once.get("enable.idempotence"), None);
+        let idempotent = client_config(&conf(KafkaDelivery::Idempotent));
+        assert_eq!(idempotent.get("enable.idempotence"), Some("true"));
+        assert_eq!(idempotent.get("linger.ms"), Some("5"));
+    }
+
+    #[test]
+    fn sinks_need_brokers_and_a_topic() {
+        let e = Sink::start(&Config {
+            kafka_brokers: "kafka:9092".to_string(),
+            ..Default::default()
+        })
+        .err()
+        .unwrap();
+        assert!(e.to_string().contains("CRUMB_KAFKA_TOPIC"), "{}", e);
+        let e = Sink::start(&Config {
+            kafka_brokers: "kafka:9092".to_string(),
+            kafka_topic: "telemetry".to_string(),
+            kafka_key: "level:x".to_string(),
+            ..Default::default()
+        })
+        .err()
+        .unwrap();
+        assert!(e.to_string().contains("CRUMB_KAFKA_KEY"), "{}", e);
+    }
+}
@@ -0,0 +1,8 @@
+//! Gateways carrying crumb traffic to and from other messaging systems and protocols.
+
+#[cfg(feature = "ingest")]
+pub mod http;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
@@ -0,0 +1,519 @@
+//! A gateway between a crumb pub/sub `Broker` and an MQTT broker, so edge traffic reaches
+//! existing MQTT infrastructure and commands published there reach the edge.
+//!
+//! Topics are mapped by `filter=topic` rules, `mqtt_uplink` for crumb to MQTT and
+//! `mqtt_downlink` for MQTT to crumb. A message is republished under the topic of the first rule
+//! whose filter matches its topic. When both the filter and the topic end in `#`, the levels the
+//! `#` matched are appended to the topic, so `sensors/#=site1/sensors/#` republishes
+//! `sensors/temp/kitchen` as `site1/sensors/temp/kitchen`; otherwise every match is republished
+//! under the topic as given.
+//!
+//! ```no_run
+//! use crumb::bridge::mqtt::Bridge;
+//! use crumb::util::config::Config;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let conf = Config {
+//!     mqtt_broker: "mqtt.example.com".to_string(),
+//!     mqtt_uplink: "sensors/#=site1/sensors/#".to_string(),
+//!     mqtt_downlink: "site1/commands/#=commands/#".to_string(),
+//!     ..Config::from_env(None)?
+//! };
+//! let bridge = Bridge::start(&conf)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Messages the bridge republishes on one side and then hears back, because it also subscribes
+//! to where they were republished, are not bridged again.
+
+use crate::error::Error;
+use crate::pubsub::{self, topic_matches};
+use crate::transport::is_timeout;
+use crate::util::config::Config;
+use rumqttc::{Event, MqttOptions, Packet, QoS, RecvTimeoutError};
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::{debug, trace, warn};
+
+const TARGET: &str = "crumb::bridge::mqtt";
+
+const DEFAULT_PORT: u16 = 1883;
+
+// How long the crumb side blocks before checking whether the bridge was stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Pub/sub subscriptions travel in single datagrams, so they are repeated in case the broker
+// missed one or restarted.
+const RESUBSCRIBE_INTERVAL: Duration = Duration::from_secs(30);
+
+// How long to wait before polling a broken MQTT connection again, which reconnects.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// Messages waiting to be sent to the MQTT broker before more are dropped.
+const MQTT_CAPACITY: usize = 256;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+// How long a republished message is watched for coming back.
+const ECHO_TTL: Duration = Duration::from_secs(10);
+
+/// Republishes crumb pub/sub messages to an MQTT broker and MQTT messages to crumb, each from a
+/// thread of its own, until dropped.
+pub struct Bridge {
+    running: Arc<AtomicBool>,
+    mqtt: rumqttc::Client,
+    uplink: Option<JoinHandle<()>>,
+    downlink: Option<JoinHandle<()>>,
+}
+
+impl Bridge {
+    /// Connects to the crumb broker at `host:port` and the MQTT broker at `mqtt_broker`, and
+    /// subscribes to the filters of both sides' rules. Fails with `Error::Config` when the
+    /// broker address or a rule is invalid. The MQTT connection is made, and remade whenever it
+    /// breaks, in the background.
+    pub fn start(conf: &Config) -> io::Result<Bridge> {
+        let uplink =
+            TopicMap::parse(&conf.mqtt_uplink).map_err(|e| invalid("CRUMB_MQTT_UPLINK", e))?;
+        let downlink =
+            TopicMap::parse(&conf.mqtt_downlink).map_err(|e| invalid("CRUMB_MQTT_DOWNLINK", e))?;
+        let (host, port) = broker_addr(&conf.mqtt_broker)
+            .ok_or_else(|| invalid("CRUMB_MQTT_BROKER", conf.mqtt_broker.clone()))?;
+        let qos = match conf.mqtt_qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            2 => QoS::ExactlyOnce,
+            qos => return Err(invalid("CRUMB_MQTT_QOS", qos.to_string())),
+        };
+        let client_id = match (conf.mqtt_client_id.as_str(), conf.node_id.as_str()) {
+            ("", "") => format!(
+                "crumb-{:08x}",
+                RandomState::new().hash_one(Instant::now()) as u32
+            ),
+            ("", node_id) => format!("crumb-{}", node_id),
+            (client_id, _) => client_id.to_string(),
+        };
+
+        let crumb = Arc::new(pubsub::Client::init(conf)?);
+        crumb.set_read_timeout(Some(POLL_INTERVAL))?;
+        for filter in uplink.filters() {
+            crumb.subscribe(filter)?;
+        }
+        let mut options = MqttOptions::new(client_id, host, port);
+        options.set_keep_alive(KEEP_ALIVE);
+        let (mqtt, mut connection) = rumqttc::Client::new(options, MQTT_CAPACITY);
+
+        let running = Arc::new(AtomicBool::new(true));
+        // Messages republished to each side, to recognise them when they come back.
+        let crumb_echoes = Arc::new(Mutex::new(Echoes::default()));
+        let mqtt_echoes = Arc::new(Mutex::new(Echoes::default()));
+
+        let uplink = {
+            let running = running.clone();
+            let crumb = crumb.clone();
+            let mqtt = mqtt.clone();
+            let crumb_echoes = crumb_echoes.clone();
+            let mqtt_echoes = mqtt_echoes.clone();
+            thread::Builder::new()
+                .name("crumb-mqtt-uplink".to_string())
+                .spawn(move || {
+                    let mut buffer = vec![0u8; pubsub::MAX_MESSAGE_SIZE];
+                    let mut subscribed = Instant::now();
+                    while running.load(Ordering::Acquire) {
+                        if subscribed.elapsed() >= RESUBSCRIBE_INTERVAL {
+                            subscribed = Instant::now();
+                            for filter in uplink.filters() {
+                                if let Err(e) = crumb.subscribe(filter) {
+                                    debug!(target: TARGET, filter, error = %e, "resubscribing failed");
+                                }
+                            }
+                        }
+                        let (topic, len) = match crumb.receive(&mut buffer) {
+                            Ok(received) => received,
+                            Err(e) if is_timeout(&e) => continue,
+                            Err(e) => {
+                                debug!(target: TARGET, error = %e, "receive failed");
+                                thread::sleep(POLL_INTERVAL);
+                                continue;
+                            }
+                        };
+                        let payload = &buffer[..len];
+                        if lock(&crumb_echoes).take(&topic, payload) {
+                            continue;
+                        }
+                        let Some(mapped) = uplink.map(&topic) else {
+                            continue;
+                        };
+                        lock(&mqtt_echoes).expect(&mapped, payload);
+                        match mqtt.try_publish(mapped.as_str(), qos, false, payload) {
+                            Ok(()) => trace!(target: TARGET, topic, mqtt_topic = mapped, "bridged to MQTT"),
+                            Err(e) => debug!(target: TARGET, topic, error = %e, "dropped message for MQTT"),
+                        }
+                    }
+                })?
+        };
+
+        let downlink = {
+            let running = running.clone();
+            let mqtt = mqtt.clone();
+            thread::Builder::new()
+                .name("crumb-mqtt-downlink".to_string())
+                .spawn(move || {
+                    while running.load(Ordering::Acquire) {
+                        let event = match connection.recv_timeout(POLL_INTERVAL) {
+                            Ok(event) => event,
+                            Err(RecvTimeoutError::Timeout) => continue,
+                            Err(RecvTimeoutError::Disconnected) => break,
+                        };
+                        match event {
+                            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                                debug!(target: TARGET, "connected to MQTT broker");
+                                for filter in downlink.filters() {
+                                    if let Err(e) = mqtt.try_subscribe(filter, qos) {
+                                        debug!(target: TARGET, filter, error = %e, "subscribing failed");
+                                    }
+                                }
+                            }
+                            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                                if lock(&mqtt_echoes).take(&publish.topic, &publish.payload) {
+                                    continue;
+                                }
+                                let Some(mapped) = downlink.map(&publish.topic) else {
+                                    continue;
+                                };
+                                lock(&crumb_echoes).expect(&mapped, &publish.payload);
+                                match crumb.publish(&mapped, &publish.payload) {
+                                    Ok(_) => trace!(target: TARGET, mqtt_topic = publish.topic, topic = mapped, "bridged to crumb"),
+                                    Err(e) => debug!(target: TARGET, topic = mapped, error = %e, "publish failed"),
+                                }
+                            }
+                            Ok(_) => {}
+                            Err(e) => {
+                                warn!(target: TARGET, error = %e, "MQTT connection failed");
+                                thread::sleep(RECONNECT_DELAY);
+                            }
+                        }
+                    }
+                })?
+        };
+
+        Ok(Bridge {
+            running,
+            mqtt,
+            uplink: Some(uplink),
+            downlink: Some(downlink),
+        })
+    }
+
+    pub fn stop(self) {
+        drop(self);
+    }
+}
+
+impl Drop for Bridge {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Err(e) = self.mqtt.try_disconnect() {
+            debug!(target: TARGET, error = %e, "disconnecting from MQTT broker failed");
+        }
+        if let Some(uplink) = self.uplink.take() {
+            let _ = uplink.join();
+        }
+        if let Some(downlink) = self.downlink.take() {
+            let _ = downlink.join();
+        }
+    }
+}
+
+/// `filter=topic` rules, tried in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TopicMap {
+    rules: Vec<(String, String)>,
+}
+
+impl TopicMap {
+    fn parse(rules: &str) -> Result<TopicMap, String> {
+        let rules = rules
+            .split(',')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+            .map(|rule| {
+                let (filter, topic) = rule
+                    .split_once('=')
+                    .map(|(filter, topic)| (filter.trim(), topic.trim()))
+                    .filter(|(filter, topic)| !filter.is_empty() && !topic.is_empty())
+                    .ok_or_else(|| format!("expected filter=topic: {}", rule))?;
+                let valid_filter = filter.split('/').enumerate().all(|(i, level)| {
+                    level == "#" && i == filter.split('/').count() - 1 || !level.contains('#')
+                });
+                let appends = topic == "#" || topic.ends_with("/#");
+                let valid_topic = !topic.contains('+')
+                    && !topic.trim_end_matches('#').contains('#')
+                    && (!appends || filter == "#" || filter.ends_with("/#"));
+                if !valid_filter || !valid_topic {
+                    return Err(format!("invalid rule: {}", rule));
+                }
+                Ok((filter.to_string(), topic.to_string()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(TopicMap { rules })
+    }
+
+    fn filters(&self) -> impl Iterator<Item = &str> {
+        self.rules.iter().map(|(filter, _)| filter.as_str())
+    }
+
+    /// The topic `topic` is republished under, if any rule matches it.
+    fn map(&self, topic: &str) -> Option<String> {
+        let (filter, target) = self
+            .rules
+            .iter()
+            .find(|(filter, _)| topic_matches(filter, topic))?;
+        let Some(prefix) = target.strip_suffix('#') else {
+            return Some(target.clone());
+        };
+        let fixed = filter.split('/').count() - 1;
+        let rest: Vec<&str> = topic.split('/').skip(fixed).collect();
+        Some(match rest.as_slice() {
+            [] => prefix.trim_end_matches('/').to_string(),
+            rest => format!("{}{}", prefix, rest.join("/")),
+        })
+    }
+}
+
+/// Messages republished to one side, by topic and payload hash, with how many times each is
+/// expected back and when it was last republished.
+#[derive(Default)]
+struct Echoes {
+    pending: HashMap<(String, u64), (usize, Instant)>,
+    hasher: RandomState,
+}
+
+impl Echoes {
+    fn expect(&mut self, topic: &str, payload: &[u8]) {
+        let now = Instant::now();
+        self.pending
+            .retain(|_, (_, at)| now.saturating_duration_since(*at) < ECHO_TTL);
+        let key = (topic.to_string(), self.hasher.hash_one(payload));
+        let (count, at) = self.pending.entry(key).or_insert((0, now));
+        *count += 1;
+        *at = now;
+    }
+
+    /// Whether `payload` on `topic` is a republished message coming back, which it no longer
+    /// is expected to once it has.
+    fn take(&mut self, topic: &str, payload: &[u8]) -> bool {
+        let key = (topic.to_string(), self.hasher.hash_one(payload));
+        let Some((count, at)) = self.pending.get_mut(&key) else {
+            return false;
+        };
+        if at.elapsed() >= ECHO_TTL {
+            self.pending.remove(&key);
+            return false;
+        }
+        *count -= 1;
+        if *count == 0 {
+            self.pending.remove(&key);
+        }
+        true
+    }
+}
+
+// `host[:port]`, with IPv6 addresses in brackets.
+fn broker_addr(addr: &str) -> Option<(String, u16)> {
+    let (host, port) = match addr.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+            (host, port.parse().ok()?)
+        }
+        _ => (addr, DEFAULT_PORT),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    (!host.is_empty()).then(|| (host.to_string(), port))
+}
+
+fn invalid(var: &str, message: String) -> io::Error {
+    Error::config(format!("{}: {}", var, message)).into()
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pubsub::Broker;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc;
+
+    #[test]
+    fn topics_are_mapped_by_the_first_matching_rule() {
+        let map =
+            TopicMap::parse("sensors/alarm=alarms, sensors/#=edge/sensors/#,#=all/#").unwrap();
+        assert_eq!(map.map("sensors/alarm").as_deref(), Some("alarms"));
+        assert_eq!(
+            map.map("sensors/temp/kitchen").as_deref(),
+            Some("edge/sensors/temp/kitchen")
+        );
+        assert_eq!(map.map("sensors").as_deref(), Some("edge/sensors"));
+        assert_eq!(
+            map.map("factory/line1").as_deref(),
+            Some("all/factory/line1")
+        );
+        assert_eq!(
+            map.filters().collect::<Vec<_>>(),
+            ["sensors/alarm", "sensors/#", "#"]
+        );
+
+        assert_eq!(TopicMap::parse("").unwrap().map("sensors"), None);
+        for invalid in [
+            "sensors",
+            "=a",
+            "sensors/+=a/+",
+            "sensors/+=a/#",
+            "a/#/b=c",
+            "a=b/#/c",
+        ] {
+            assert!(TopicMap::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn echoes_are_recognised_once() {
+        let mut echoes = Echoes::default();
+        echoes.expect("a", b"1");
+        echoes.expect("a", b"1");
+        assert!(!echoes.take("a", b"2"));
+        assert!(!echoes.take("b", b"1"));
+        assert!(echoes.take("a", b"1"));
+        assert!(echoes.take("a", b"1"));
+        assert!(!echoes.take("a", b"1"));
+    }
+
+    #[test]
+    fn broker_addresses_default_the_port() {
+        assert_eq!(broker_addr("mqtt"), Some(("mqtt".to_string(), 1883)));
+        assert_eq!(broker_addr("mqtt:8883"), Some(("mqtt".to_string(), 8883)));
+        assert_eq!(broker_addr("[::1]:8883"), Some(("::1".to_string(), 8883)));
+        assert_eq!(broker_addr("::1"), Some(("::1".to_string(), 1883)));
+        assert_eq!(broker_addr("mqtt:x"), None);
+        assert_eq!(broker_addr(""), None);
+    }
+
+    // Reads one MQTT packet, returning its type and body.
+    fn read_packet(stream: &mut TcpStream) -> io::Result<(u8, Vec<u8>)> {
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte)?;
+        let kind = byte[0] >> 4;
+        let (mut len, mut shift) = (0usize, 0);
+        loop {
+            stream.read_exact(&mut byte)?;
+            len |= usize::from(byte[0] & 0x7f) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body)?;
+        Ok((kind, body))
+    }
+
+    fn publish_packet(topic: &str, payload: &[u8]) -> Vec<u8> {
+        let len = 2 + topic.len() + payload.len();
+        let mut packet = vec![0x30, len as u8];
+        packet.extend_from_slice(&(topic.len() as u16).to_be_bytes());
+        packet.extend_from_slice(topic.as_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    // Just enough of an MQTT 3.1.1 broker for one client publishing and subscribing at QoS 0:
+    // publishes `factory/line1` once subscribed to, and passes on what the client publishes.
+    fn fake_mqtt_broker(listener: TcpListener, published: mpsc::Sender<(String, Vec<u8>)>) {
+        let Ok((mut stream, _)) = listener.accept() else {
+            return;
+        };
+        while let Ok((kind, body)) = read_packet(&mut stream) {
+            let reply = match kind {
+                // CONNECT
+                1 => vec![0x20, 2, 0, 0],
+                // PUBLISH
+                3 => {
+                    let len = usize::from(u16::from_be_bytes([body[0], body[1]]));
+                    let topic = String::from_utf8_lossy(&body[2..2 + len]).into_owned();
+                    let _ = published.send((topic, body[2 + len..].to_vec()));
+                    continue;
+                }
+                // SUBSCRIBE
+                8 => {
+                    let mut reply = vec![0x90, 3, body[0], body[1], 0];
+                    reply.extend_from_slice(&publish_packet("factory/line1", b"stop"));
+                    reply
+                }
+                // PINGREQ
+                12 => vec![0xd0, 0],
+                _ => break,
+            };
+            if stream.write_all(&reply).is_err() {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn messages_cross_the_bridge_both_ways() -> io::Result<()> {
+        let conf = Config {
+            host: "127.0.0.1".to_string(),
+            port: 8141,
+            bind_host: "127.0.0.1".to_string(),
+            ..Default::default()
+        };
+        let mut broker = Broker::init(&conf)?;
+        let shutdown = broker.shutdown_handle()?;
+        let broker = thread::spawn(move || broker.run());
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mqtt_broker = listener.local_addr()?.to_string();
+        let (published, from_bridge) = mpsc::channel();
+        let fake = thread::spawn(move || fake_mqtt_broker(listener, published));
+
+        let edge = pubsub::Client::init(&conf)?;
+        edge.set_read_timeout(Some(Duration::from_secs(5)))?;
+        edge.subscribe("commands/#")?;
+        let bridge = Bridge::start(&Config {
+            mqtt_broker,
+            mqtt_qos: 0,
+            mqtt_uplink: "sensors/#=edge/sensors/#".to_string(),
+            mqtt_downlink: "factory/#=commands/#".to_string(),
+            ..conf.clone()
+        })?;
+
+        let mut buffer = [0u8; 64];
+        let (topic, received) = edge.receive(&mut buffer)?;
+        assert_eq!(
+            (topic.as_str(), &buffer[..received]),
+            ("commands/line1", &b"stop"[..])
+        );
+
+        edge.publish("sensors/temp", b"21.5")?;
+        let (topic, payload) = from_bridge
+            .recv_timeout(Duration::from_secs(5))
+            .expect("nothing was bridged to MQTT");
+        assert_eq!(
+            (topic.as_str(), payload.as_slice()),
+            ("edge/sensors/temp", &b"21.5"[..])
+        );
+
+        drop(bridge);
+        fake.join().unwrap();
+        shutdown.shutdown();
+        broker.join().unwrap()
+    }
+}
@@ -0,0 +1,665 @@
+//! Recording session traffic to pcapng files, and replaying recordings, for debugging and load
+//! testing.
+//!
+//! With `capture_path` set, session clients and servers record every frame they send and
+//! receive. With `CapturePoint::Wire` frames are recorded as the datagrams on the wire, sealed
+//! if the session has a pre-shared key; with `CapturePoint::Plain` they are recorded before
+//! they are sealed and after they are opened, so that payloads can be read, and retransmissions
+//! of a frame are not recorded again.
+//!
+//! Each frame is recorded as a UDP datagram between the session's addresses, with its direction,
+//! so captures open in Wireshark and tcpdump like any other. `replay` sends the frames a capture
+//! recorded as sent again, keeping their original spacing:
+//!
+//! ```no_run
+//! use crumb::capture;
+//! use crumb::util::config::Config;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let conf = Config {
+//!     host: "staging.example.com".to_string(),
+//!     ..Config::from_env(None)?
+//! };
+//! let sent = capture::replay(&conf, "client.pcapng")?;
+//! println!("replayed {} frames", sent);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::transport::{bind_client, resolve_for};
+use crate::util::config::{CapturePoint, Config};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, trace};
+
+const TARGET: &str = "crumb::capture";
+
+const SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+
+const LINKTYPE_ETHERNET: u16 = 1;
+// Packets start at their IP header.
+const LINKTYPE_RAW: u16 = 101;
+
+const OPT_END: u16 = 0;
+const OPT_IF_TSRESOL: u16 = 9;
+const OPT_EPB_FLAGS: u16 = 2;
+
+const INBOUND: u32 = 0b01;
+const OUTBOUND: u32 = 0b10;
+
+const UDP: u8 = 17;
+const TTL: u8 = 64;
+
+// Blocks larger than this are not read, as no frame needs one.
+const MAX_BLOCK_LEN: usize = 1 << 20;
+
+/// Which way a recorded frame went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// A frame read back from a capture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub timestamp: SystemTime,
+    /// `None` for captures made by tools that do not record it.
+    pub direction: Option<Direction>,
+    pub source: SocketAddr,
+    pub dest: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+/// A pcapng file frames are recorded to, shared by every session of a client or server.
+pub struct Writer {
+    file: Mutex<BufWriter<File>>,
+}
+
+impl Writer {
+    /// Creates the file at `path`, replacing any that is there.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Writer> {
+        let mut file = BufWriter::new(File::create(path)?);
+        // A section of unknown length, then the one interface every frame is recorded on.
+        let mut header = Vec::with_capacity(48);
+        header.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&(-1i64).to_le_bytes());
+        write_block(&mut file, SECTION_HEADER, &header)?;
+        let mut interface = Vec::with_capacity(8);
+        interface.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        interface.extend_from_slice(&0u16.to_le_bytes());
+        interface.extend_from_slice(&0u32.to_le_bytes());
+        write_block(&mut file, INTERFACE_DESCRIPTION, &interface)?;
+        file.flush()?;
+        Ok(Writer {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// The writer `conf` asks sessions to record to, if any.
+    pub(crate) fn from_config(conf: &Config) -> io::Result<Option<Arc<Writer>>> {
+        if conf.capture_path.is_empty() {
+            return Ok(None);
+        }
+        debug!(target: TARGET, path = conf.capture_path, point = ?conf.capture_point, "capturing frames");
+        Ok(Some(Arc::new(Writer::create(&conf.capture_path)?)))
+    }
+
+    /// Records `payload` sent from `source` to `dest` now. Each record is flushed, so that a
+    /// capture survives the process being killed.
+    pub fn record(
+        &self,
+        direction: Direction,
+        source: SocketAddr,
+        dest: SocketAddr,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let datagram = ip_datagram(source, dest, payload);
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let mut block = Vec::with_capacity(36 + datagram.len());
+        block.extend_from_slice(&0u32.to_le_bytes());
+        block.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        block.extend_from_slice(&(micros as u32).to_le_bytes());
+        block.extend_from_slice(&(datagram.len() as u32).to_le_bytes());
+        block.extend_from_slice(&(datagram.len() as u32).to_le_bytes());
+        block.extend_from_slice(&datagram);
+        block.resize(block.len().next_multiple_of(4), 0);
+        block.extend_from_slice(&OPT_EPB_FLAGS.to_le_bytes());
+        block.extend_from_slice(&4u16.to_le_bytes());
+        let flags = match direction {
+            Direction::Inbound => INBOUND,
+            Direction::Outbound => OUTBOUND,
+        };
+        block.extend_from_slice(&flags.to_le_bytes());
+        block.extend_from_slice(&OPT_END.to_le_bytes());
+        block.extend_from_slice(&0u16.to_le_bytes());
+
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        write_block(&mut *file, ENHANCED_PACKET, &block)?;
+        file.flush()
+    }
+}
+
+/// One session's view of a `Writer`: where its frames go from and to, and which of them to
+/// record.
+pub(crate) struct Endpoint {
+    writer: Arc<Writer>,
+    point: CapturePoint,
+    local: SocketAddr,
+    peer: SocketAddr,
+}
+
+impl Endpoint {
+    pub(crate) fn new(
+        writer: Arc<Writer>,
+        point: CapturePoint,
+        local: SocketAddr,
+        peer: SocketAddr,
+    ) -> Endpoint {
+        Endpoint {
+            writer,
+            point,
+            local,
+            peer,
+        }
+    }
+
+    pub(crate) fn point(&self) -> CapturePoint {
+        self.point
+    }
+
+    /// Follows the session to new addresses, after reconnecting.
+    pub(crate) fn moved(&mut self, local: SocketAddr, peer: SocketAddr) {
+        self.local = local;
+        self.peer = peer;
+    }
+
+    pub(crate) fn sent(&self, bytes: &[u8]) {
+        self.record(Direction::Outbound, self.local, self.peer, bytes);
+    }
+
+    pub(crate) fn received(&self, bytes: &[u8]) {
+        self.record(Direction::Inbound, self.peer, self.local, bytes);
+    }
+
+    // Failing to record is logged rather than failing the session.
+    fn record(&self, direction: Direction, source: SocketAddr, dest: SocketAddr, bytes: &[u8]) {
+        if let Err(e) = self.writer.record(direction, source, dest, bytes) {
+            debug!(target: TARGET, error = %e, "recording frame failed");
+        }
+    }
+}
+
+/// Reads the UDP datagrams of a pcapng file back, from crumb or any other tool that records IP
+/// or Ethernet frames. Packets that are not UDP are skipped.
+pub struct Reader<R> {
+    input: R,
+    big_endian: bool,
+    // The link type and timestamp units per second of each interface in the current section.
+    interfaces: Vec<(u16, u64)>,
+}
+
+impl Reader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Reader::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(input: R) -> Reader<R> {
+        Reader {
+            input,
+            big_endian: false,
+            interfaces: Vec::new(),
+        }
+    }
+
+    /// The next UDP datagram, or `None` at the end of the file.
+    pub fn next_packet(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            let mut head = [0u8; 8];
+            match self.input.read_exact(&mut head) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+            let kind = u32::from_le_bytes(head[..4].try_into().unwrap());
+            if kind == SECTION_HEADER {
+                // The byte order magic that follows says how to read the section, this block's
+                // length included.
+                let mut magic = [0u8; 4];
+                self.input.read_exact(&mut magic)?;
+                self.big_endian = match u32::from_le_bytes(magic) {
+                    BYTE_ORDER_MAGIC => false,
+                    magic if magic.swap_bytes() == BYTE_ORDER_MAGIC => true,
+                    _ => return Err(invalid("unknown byte order magic")),
+                };
+                self.interfaces.clear();
+                let len = self.u32(&head[4..8]) as usize;
+                self.skip(len.checked_sub(12).ok_or_else(|| invalid("short block"))?)?;
+                continue;
+            }
+
+            let len = self.u32(&head[4..8]) as usize;
+            if !(12..=MAX_BLOCK_LEN).contains(&len) || !len.is_multiple_of(4) {
+                return Err(invalid("invalid block length"));
+            }
+            let mut body = vec![0u8; len - 8];
+            self.input.read_exact(&mut body)?;
+            body.truncate(len - 12);
+            match self.u32(&head[..4]) {
+                INTERFACE_DESCRIPTION if body.len() >= 8 => {
+                    let link_type = self.u16(&body[..2]);
+                    let mut units = 1_000_000;
+                    for (code, value) in self.options(&body[8..]) {
+                        if code == OPT_IF_TSRESOL && !value.is_empty() {
+                            units = match value[0] {
+                                resolution if resolution & 0x80 != 0 => {
+                                    1u64.checked_shl(u32::from(resolution & 0x7f))
+                                }
+                                resolution => 10u64.checked_pow(u32::from(resolution)),
+                            }
+                            .ok_or_else(|| invalid("unsupported timestamp resolution"))?;
+                        }
+                    }
+                    self.interfaces.push((link_type, units));
+                }
+                ENHANCED_PACKET if body.len() >= 20 => {
+                    if let Some(packet) = self.enhanced_packet(&body)? {
+                        return Ok(Some(packet));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn enhanced_packet(&self, body: &[u8]) -> io::Result<Option<Packet>> {
+        let interface = self.u32(&body[..4]) as usize;
+        let &(link_type, units) = self
+            .interfaces
+            .get(interface)
+            .ok_or_else(|| invalid("packet on an undescribed interface"))?;
+        let ticks = u64::from(self.u32(&body[4..8])) << 32 | u64::from(self.u32(&body[8..12]));
+        let captured = self.u32(&body[12..16]) as usize;
+        let data = body
+            .get(20..20 + captured)
+            .ok_or_else(|| invalid("truncated packet"))?;
+        let options = body
+            .get(20 + captured.next_multiple_of(4)..)
+            .unwrap_or_default();
+        let direction = self
+            .options(options)
+            .find(|(code, value)| *code == OPT_EPB_FLAGS && value.len() == 4)
+            .and_then(|(_, value)| match self.u32(value) & 0b11 {
+                INBOUND => Some(Direction::Inbound),
+                OUTBOUND => Some(Direction::Outbound),
+                _ => None,
+            });
+
+        let ip = match link_type {
+            LINKTYPE_RAW => data,
+            // Only untagged Ethernet frames are looked into.
+            LINKTYPE_ETHERNET
+                if data.len() >= 14 && matches!(data[12..14], [0x08, 0x00] | [0x86, 0xdd]) =>
+            {
+                &data[14..]
+            }
+            _ => return Ok(None),
+        };
+        let Some((source, dest, payload)) = parse_udp(ip) else {
+            trace!(target: TARGET, "skipping packet that is not UDP");
+            return Ok(None);
+        };
+        let since_epoch = Duration::from_secs(ticks / units)
+            + Duration::from_nanos((ticks % units) * 1_000_000_000 / units);
+        Ok(Some(Packet {
+            timestamp: UNIX_EPOCH + since_epoch,
+            direction,
+            source,
+            dest,
+            payload: payload.to_vec(),
+        }))
+    }
+
+    // The code and value of each option in `options`, up to the end of options.
+    fn options<'a>(&'a self, mut options: &'a [u8]) -> impl Iterator<Item = (u16, &'a [u8])> + 'a {
+        std::iter::from_fn(move || {
+            let code = self.u16(options.get(..2)?);
+            let len = self.u16(options.get(2..4)?) as usize;
+            let value = options.get(4..4 + len)?;
+            options = options
+                .get(4 + len.next_multiple_of(4)..)
+                .unwrap_or_default();
+            (code != OPT_END).then_some((code, value))
+        })
+    }
+
+    fn skip(&mut self, len: usize) -> io::Result<()> {
+        let skipped = io::copy(&mut (&mut self.input).take(len as u64), &mut io::sink())?;
+        match skipped as usize == len {
+            true => Ok(()),
+            false => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    fn u16(&self, bytes: &[u8]) -> u16 {
+        let bytes = [bytes[0], bytes[1]];
+        match self.big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        }
+    }
+
+    fn u32(&self, bytes: &[u8]) -> u32 {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        }
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = io::Result<Packet>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_packet().transpose()
+    }
+}
+
+/// Sends the frames the capture at `path` recorded as sent, and those of captures that do not
+/// record directions, to the server `conf` names, spaced as they were recorded. Returns how
+/// many were sent.
+pub fn replay<P: AsRef<Path>>(conf: &Config, path: P) -> io::Result<usize> {
+    let socket = bind_client(conf)?;
+    let dest = resolve_for(socket.local_addr()?, (conf.host.as_str(), conf.port))?;
+    let started = Instant::now();
+    let mut first = None;
+    let mut sent = 0;
+    for packet in Reader::open(path)? {
+        let packet = packet?;
+        if packet.direction == Some(Direction::Inbound) {
+            continue;
+        }
+        let first = *first.get_or_insert(packet.timestamp);
+        let offset = packet.timestamp.duration_since(first).unwrap_or_default();
+        if let Some(wait) = offset.checked_sub(started.elapsed()) {
+            thread::sleep(wait);
+        }
+        socket.send_to(&packet.payload, dest)?;
+        sent += 1;
+    }
+    debug!(target: TARGET, %dest, sent, "replayed capture");
+    Ok(sent)
+}
+
+fn write_block(out: &mut impl Write, kind: u32, body: &[u8]) -> io::Result<()> {
+    let len = (12 + body.len()) as u32;
+    out.write_all(&kind.to_le_bytes())?;
+    out.write_all(&len.to_le_bytes())?;
+    out.write_all(body)?;
+    out.write_all(&len.to_le_bytes())
+}
+
+// `payload` in UDP and IP headers from `source` to `dest`. IPv4 addresses are mapped to IPv6
+// when the other is IPv6.
+fn ip_datagram(source: SocketAddr, dest: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_len = 8 + payload.len();
+    let mut udp = Vec::with_capacity(udp_len);
+    udp.extend_from_slice(&source.port().to_be_bytes());
+    udp.extend_from_slice(&dest.port().to_be_bytes());
+    udp.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+
+    let (source_ip, dest_ip) = (source.ip().to_canonical(), dest.ip().to_canonical());
+    let mut datagram = match (source_ip, dest_ip) {
+        (IpAddr::V4(source_ip), IpAddr::V4(dest_ip)) => {
+            let mut header = vec![0x45, 0];
+            header.extend_from_slice(&((20 + udp_len) as u16).to_be_bytes());
+            header.extend_from_slice(&[0, 0, 0x40, 0, TTL, UDP, 0, 0]);
+            header.extend_from_slice(&source_ip.octets());
+            header.extend_from_slice(&dest_ip.octets());
+            let sum = checksum(&[&header]);
+            header[10..12].copy_from_slice(&sum.to_be_bytes());
+            let pseudo = pseudo_header(&source_ip.octets(), &dest_ip.octets(), udp_len);
+            set_udp_checksum(&mut udp, &pseudo);
+            header
+        }
+        (source_ip, dest_ip) => {
+            let (source_ip, dest_ip) = (v6(source_ip), v6(dest_ip));
+            let mut header = vec![0x60, 0, 0, 0];
+            header.extend_from_slice(&(udp_len as u16).to_be_bytes());
+            header.extend_from_slice(&[UDP, TTL]);
+            header.extend_from_slice(&source_ip.octets());
+            header.extend_from_slice(&dest_ip.octets());
+            let pseudo = pseudo_header(&source_ip.octets(), &dest_ip.octets(), udp_len);
+            set_udp_checksum(&mut udp, &pseudo);
+            header
+        }
+    };
+    datagram.extend_from_slice(&udp);
+    datagram
+}
+
+fn v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn pseudo_header(source: &[u8], dest: &[u8], udp_len: usize) -> Vec<u8> {
+    let mut pseudo = Vec::with_capacity(40);
+    pseudo.extend_from_slice(source);
+    pseudo.extend_from_slice(dest);
+    pseudo.extend_from_slice(&[0, UDP]);
+    pseudo.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    pseudo
+}
+
+fn set_udp_checksum(udp: &mut [u8], pseudo: &[u8]) {
+    // A sum of zero is sent as all ones, as zero means none was computed.
+    let sum = match checksum(&[pseudo, udp]) {
+        0 => 0xffff,
+        sum => sum,
+    };
+    udp[6..8].copy_from_slice(&sum.to_be_bytes());
+}
+
+// The Internet checksum of `parts` laid end to end, each of an even length but the last.
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        for pair in part.chunks(2) {
+            let word = match pair {
+                [high, low] => u16::from_be_bytes([*high, *low]),
+                [high] => u16::from_be_bytes([*high, 0]),
+                _ => 0,
+            };
+            sum += u32::from(word);
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// The addresses and payload of a UDP datagram in IP headers.
+fn parse_udp(ip: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let (source_ip, dest_ip, udp): (IpAddr, IpAddr, &[u8]) = match ip.first()? >> 4 {
+        4 => {
+            let header_len = usize::from(ip[0] & 0x0f) * 4;
+            let total_len = usize::from(u16::from_be_bytes([*ip.get(2)?, *ip.get(3)?]));
+            if *ip.get(9)? != UDP || header_len < 20 {
+                return None;
+            }
+            let source: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+            let dest: [u8; 4] = ip.get(16..20)?.try_into().ok()?;
+            (
+                Ipv4Addr::from(source).into(),
+                Ipv4Addr::from(dest).into(),
+                ip.get(header_len..total_len.min(ip.len()))?,
+            )
+        }
+        6 => {
+            if *ip.get(6)? != UDP {
+                return None;
+            }
+            let source: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+            let dest: [u8; 16] = ip.get(24..40)?.try_into().ok()?;
+            (
+                Ipv6Addr::from(source).into(),
+                Ipv6Addr::from(dest).into(),
+                ip.get(40..)?,
+            )
+        }
+        _ => return None,
+    };
+    let source_port = u16::from_be_bytes([*udp.first()?, *udp.get(1)?]);
+    let dest_port = u16::from_be_bytes([*udp.get(2)?, *udp.get(3)?]);
+    let len = usize::from(u16::from_be_bytes([*udp.get(4)?, *udp.get(5)?]));
+    let payload = udp.get(8..len.max(8).min(udp.len()))?;
+    Some((
+        SocketAddr::new(source_ip, source_port),
+        SocketAddr::new(dest_ip, dest_port),
+        payload,
+    ))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid capture: {}", message),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Frame;
+    use crate::session;
+    use crate::transport::udp;
+
+    #[test]
+    fn recorded_frames_read_back() -> io::Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("crumb-capture-{}.pcapng", std::process::id()));
+        let v4: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        let server: SocketAddr = "198.51.100.7:50505".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:4000".parse().unwrap();
+        let writer = Writer::create(&path)?;
+        let before = SystemTime::now();
+        writer.record(Direction::Outbound, v4, server, b"hello")?;
+        writer.record(Direction::Inbound, server, v4, b"")?;
+        writer.record(Direction::Outbound, v6, server, b"odd")?;
+
+        let packets = Reader::open(&path)?.collect::<io::Result<Vec<_>>>()?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(packets.len(), 3);
+        assert_eq!(packets[0].direction, Some(Direction::Outbound));
+        assert_eq!((packets[0].source, packets[0].dest), (v4, server));
+        assert_eq!(packets[0].payload, b"hello");
+        assert!(packets[0].timestamp >= before - Duration::from_millis(1));
+        assert_eq!(packets[1].direction, Some(Direction::Inbound));
+        assert_eq!(packets[1].payload, b"");
+        assert_eq!(packets[2].source, v6);
+        assert_eq!(
+            packets[2].dest,
+            SocketAddr::new(
+                Ipv4Addr::new(198, 51, 100, 7).to_ipv6_mapped().into(),
+                50505
+            )
+        );
+        assert_eq!(packets[2].payload, b"odd");
+        Ok(())
+    }
+
+    #[test]
+    fn sessions_are_captured_and_replayed() -> io::Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("crumb-session-{}.pcapng", std::process::id()));
+        let conf = Config {
+            host: "127.0.0.1".to_string(),
+            port: 8143,
+            bind_host: "127.0.0.1".to_string(),
+            ..Default::default()
+        };
+        let server = session::Server::init(&conf)?;
+        server.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let client = session::Client::init(&Config {
+            capture_path: path.to_string_lossy().into_owned(),
+            capture_point: CapturePoint::Plain,
+            ..conf.clone()
+        })?;
+        client.send(b"hello")?;
+        let mut buffer = [0u8; 128];
+        server.receive_from(&mut buffer)?;
+        client.close();
+
+        let packets = Reader::open(&path)?.collect::<io::Result<Vec<_>>>()?;
+        let mut sent = packets
+            .iter()
+            .filter(|packet| packet.direction == Some(Direction::Outbound));
+        // The session opens with the version exchange, then the client's node ID.
+        let first = sent.next().unwrap();
+        assert_eq!(first.dest, "127.0.0.1:8143".parse().unwrap());
+        assert!(Frame::from_bytes(&first.payload).unwrap().is_ping());
+        let mut frames = sent
+            .map(|packet| Frame::from_bytes(&packet.payload).unwrap())
+            .skip_while(Frame::is_ping);
+        assert!(frames.next().unwrap().hello);
+        let hello = frames.find(|frame| !frame.hello).unwrap();
+        assert_eq!(hello.payload, b"hello");
+        // The server's acknowledgement.
+        assert!(packets
+            .iter()
+            .any(|packet| packet.direction == Some(Direction::Inbound)));
+
+        let target = udp::Server::init(&Config {
+            port: 8144,
+            ..conf.clone()
+        })?;
+        target.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let sent = replay(
+            &Config {
+                port: 8144,
+                ..conf.clone()
+            },
+            &path,
+        )?;
+        std::fs::remove_file(&path)?;
+        assert!(sent >= 1);
+        let (received, _) = target.receive_from(&mut buffer)?;
+        assert_eq!(buffer[..received], first.payload[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn checksums_verify() {
+        let datagram = ip_datagram(
+            "192.0.2.1:4000".parse().unwrap(),
+            "198.51.100.7:50505".parse().unwrap(),
+            b"abc",
+        );
+        // A header or datagram with its checksum in place sums to zero.
+        assert_eq!(checksum(&[&datagram[..20]]), 0);
+        let pseudo = pseudo_header(&datagram[12..16], &datagram[16..20], 11);
+        assert_eq!(checksum(&[&pseudo, &datagram[20..]]), 0);
+    }
+}
@@ -0,0 +1,32 @@
+//! Code generation for the messages described by `proto_path`, for use from a build script.
+//!
+//! ```no_run
+//! // build.rs, with crumb as a build dependency using the `prost-build` feature.
+//! fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let conf = crumb::util::config::Config::from_env(None)?;
+//!     crumb::codegen::compile_proto(&conf)?;
+//!     Ok(())
+//! }
+//! ```
+//!
+//! The generated types can then be included with
+//! `include!(concat!(env!("OUT_DIR"), "/<package>.rs"))` and sent with `send_message` when crumb
+//! is built with the `prost` feature. Generation needs `protoc`, found on the `PATH` or through
+//! the `PROTOC` environment variable.
+
+use crate::util::config::Config;
+use std::io;
+use std::path::Path;
+
+/// Generates Rust types into `OUT_DIR` for every message in the configured `.proto` file,
+/// resolving imports relative to its directory. Cargo reruns the build script when the file
+/// changes.
+pub fn compile_proto(conf: &Config) -> io::Result<()> {
+    let proto = Path::new(&conf.proto_path);
+    let include = proto
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    println!("cargo:rerun-if-changed={}", proto.display());
+    prost_build::compile_protos(&[proto], &[include])
+}
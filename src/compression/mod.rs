@@ -1,2 +1,200 @@
+//! Payload compression.
+//!
+//! Every frame names the algorithm that compressed its payload in its header, so a receiver
+//! decodes whatever its peer chose regardless of its own `compression_type`. Peers therefore only
+//! need an algorithm in common with themselves: a sender whose algorithm was not compiled in, or
+//! whose output would not be smaller, sends the payload uncompressed.
 
+use crate::error::Error;
+use crate::util::config::CompressionType;
+#[cfg(test)]
+use std::io;
+use tracing::debug;
 
+const TARGET: &str = "crumb::compression";
+
+const TAG_NONE: u8 = 0;
+const TAG_ZSTD: u8 = 1;
+const TAG_GZIP: u8 = 2;
+
+// Bounds decompression so a small malicious payload cannot expand without limit.
+const MAX_DECOMPRESSED_SIZE: u64 = 16 * 1024 * 1024;
+
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+// How formats that are compressed already begin: zstd, gzip, zip, PNG, JPEG and Ogg, which
+// compression would only grow.
+const COMPRESSED_MAGIC: &[&[u8]] = &[
+    &[0x28, 0xb5, 0x2f, 0xfd],
+    &[0x1f, 0x8b],
+    b"PK\x03\x04",
+    b"\x89PNG",
+    &[0xff, 0xd8, 0xff],
+    b"OggS",
+];
+
+impl CompressionType {
+    /// Whether this build can compress and decompress with the algorithm.
+    pub fn is_supported(self) -> bool {
+        match self {
+            CompressionType::None => true,
+            CompressionType::Zstd => cfg!(feature = "zstd"),
+            CompressionType::Gzip => cfg!(feature = "gzip"),
+        }
+    }
+
+    /// The value naming the algorithm in a frame header.
+    pub(crate) fn tag(self) -> u8 {
+        match self {
+            CompressionType::None => TAG_NONE,
+            CompressionType::Zstd => TAG_ZSTD,
+            CompressionType::Gzip => TAG_GZIP,
+        }
+    }
+
+    pub(crate) fn from_tag(tag: u8) -> Option<CompressionType> {
+        match tag {
+            TAG_NONE => Some(CompressionType::None),
+            TAG_ZSTD => Some(CompressionType::Zstd),
+            TAG_GZIP => Some(CompressionType::Gzip),
+            _ => None,
+        }
+    }
+}
+
+/// Compresses `payload` with `preferred` if this build supports it and it helps, returning the
+/// algorithm actually used along with the result.
+pub fn compress(
+    preferred: CompressionType,
+    payload: &[u8],
+) -> crate::Result<(CompressionType, Vec<u8>)> {
+    let algorithm = match preferred.is_supported() {
+        true => preferred,
+        false => {
+            debug!(target: TARGET, ?preferred, "not compiled in, sending uncompressed");
+            CompressionType::None
+        }
+    };
+
+    let compressed: Option<Vec<u8>> = match algorithm {
+        CompressionType::None => None,
+        #[cfg(feature = "zstd")]
+        CompressionType::Zstd => {
+            Some(zstd::bulk::compress(payload, ZSTD_LEVEL).map_err(Error::codec)?)
+        }
+        #[cfg(feature = "gzip")]
+        CompressionType::Gzip => {
+            use std::io::Write;
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(payload).map_err(Error::codec)?;
+            Some(encoder.finish().map_err(Error::codec)?)
+        }
+        #[allow(unreachable_patterns)]
+        _ => None,
+    };
+
+    Ok(match compressed {
+        Some(compressed) if compressed.len() < payload.len() => (algorithm, compressed),
+        _ => (CompressionType::None, payload.to_vec()),
+    })
+}
+
+/// Whether `payload` starts as a file in a compressed format does, so that compressing it would
+/// be wasted effort.
+pub fn is_compressed(payload: &[u8]) -> bool {
+    COMPRESSED_MAGIC
+        .iter()
+        .any(|magic| payload.starts_with(magic))
+}
+
+/// Reverses `compress` for a payload compressed with `algorithm`.
+pub fn decompress(algorithm: CompressionType, body: &[u8]) -> crate::Result<Vec<u8>> {
+    if !algorithm.is_supported() {
+        return Err(Error::codec(format!(
+            "Unsupported compression: {:?}",
+            algorithm
+        )));
+    }
+
+    let mut payload = Vec::new();
+    match algorithm {
+        CompressionType::None => payload.extend_from_slice(body),
+        #[cfg(feature = "zstd")]
+        CompressionType::Zstd => {
+            use std::io::Read;
+            zstd::stream::read::Decoder::new(body)
+                .and_then(|decoder| {
+                    decoder
+                        .take(MAX_DECOMPRESSED_SIZE + 1)
+                        .read_to_end(&mut payload)
+                })
+                .map_err(Error::codec)?;
+        }
+        #[cfg(feature = "gzip")]
+        CompressionType::Gzip => {
+            use std::io::Read;
+            flate2::read::GzDecoder::new(body)
+                .take(MAX_DECOMPRESSED_SIZE + 1)
+                .read_to_end(&mut payload)
+                .map_err(Error::codec)?;
+        }
+        #[allow(unreachable_patterns)]
+        _ => unreachable!("unsupported algorithms are rejected above"),
+    }
+    if payload.len() as u64 > MAX_DECOMPRESSED_SIZE {
+        return Err(Error::codec("Decompressed payload too large"));
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &[u8] = b"crumb crumb crumb crumb crumb crumb crumb crumb crumb crumb crumb";
+
+    #[test]
+    fn round_trip_with_every_algorithm() -> io::Result<()> {
+        for algorithm in [
+            CompressionType::None,
+            CompressionType::Zstd,
+            CompressionType::Gzip,
+        ] {
+            let (used, body) = compress(algorithm, TEXT)?;
+            if algorithm.is_supported() {
+                assert_eq!(used, algorithm);
+            }
+            assert_eq!(decompress(used, &body)?, TEXT);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn incompressible_payloads_are_sent_as_is() -> io::Result<()> {
+        let encoded = compress(CompressionType::Zstd, b"abc")?;
+        assert_eq!(encoded, (CompressionType::None, b"abc".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_formats_are_recognised() -> io::Result<()> {
+        let (_, gzipped) = compress(CompressionType::Gzip, TEXT)?;
+        assert_eq!(
+            is_compressed(&gzipped),
+            CompressionType::Gzip.is_supported()
+        );
+        assert!(is_compressed(b"\x89PNG\r\n\x1a\n"));
+        assert!(!is_compressed(TEXT));
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_payloads_are_rejected() {
+        for algorithm in [CompressionType::Zstd, CompressionType::Gzip] {
+            let error = decompress(algorithm, &[1, 2, 3]).unwrap_err();
+            assert!(matches!(error, Error::Codec(_)));
+        }
+    }
+}
@@ -0,0 +1,388 @@
+mod rendezvous;
+pub mod stun;
+
+pub use rendezvous::{punch, Rendezvous};
+
+use crate::transport::udp::{Client, Server, ANNOUNCE_PREFIX};
+use crate::util::{self, config::Config};
+use std::collections::hash_map::{Entry, HashMap};
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::{debug, trace};
+
+const TARGET: &str = "crumb::discovery";
+
+// First line of every announcement, so unrelated traffic on the discovery port is ignored and
+// the format can be revised later.
+const ANNOUNCEMENT_HEADER: &str = "crumb-discovery/1";
+
+// How long the receiver blocks before checking whether discovery was stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Peers are forgotten after missing this many announcements.
+const EXPIRY_INTERVALS: u32 = 3;
+
+/// What a node periodically tells the rest of the mesh about itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announcement {
+    pub node_id: String,
+    /// Where the node's crumb server can be reached. An unspecified IP is replaced by the
+    /// announcement's source IP on receipt.
+    pub addr: SocketAddr,
+    pub capabilities: Vec<String>,
+}
+
+impl Announcement {
+    fn encode(&self) -> Vec<u8> {
+        format!(
+            "{}\n{}\n{}\n{}",
+            ANNOUNCEMENT_HEADER,
+            self.node_id,
+            self.addr,
+            self.capabilities.join(",")
+        )
+        .into_bytes()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Announcement> {
+        let mut lines = str::from_utf8(bytes).ok()?.split('\n');
+        if lines.next()? != ANNOUNCEMENT_HEADER {
+            return None;
+        }
+
+        let node_id = lines.next().filter(|id| !id.is_empty())?.to_string();
+        let addr = lines.next()?.parse().ok()?;
+        let capabilities = lines
+            .next()
+            .unwrap_or_default()
+            .split(',')
+            .filter(|capability| !capability.is_empty())
+            .map(String::from)
+            .collect();
+
+        Some(Announcement {
+            node_id,
+            addr,
+            capabilities,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub node_id: String,
+    pub addr: SocketAddr,
+    pub capabilities: Vec<String>,
+    pub last_seen: Instant,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    Joined(Peer),
+    /// A known peer announced a different address or capabilities.
+    Updated(Peer),
+    Expired(Peer),
+}
+
+/// Announces this node and tracks the peers heard from on the discovery port.
+///
+/// Announcements are broadcast on the local subnet, or sent to `multicast_group` when one is
+/// configured. Background threads are stopped when the `Discovery` is dropped.
+pub struct Discovery {
+    shared: Arc<Shared>,
+    running: Arc<AtomicBool>,
+    announcer: Option<JoinHandle<()>>,
+    receiver: Option<JoinHandle<()>>,
+}
+
+impl Discovery {
+    pub fn start(conf: &Config, capabilities: &[&str]) -> io::Result<Discovery> {
+        let node_id = util::node_id(conf);
+        let multicast = !conf.multicast_group.is_empty();
+        let discovery_conf = Config {
+            port: conf.discovery_port,
+            bind_port: 0,
+            broadcast: !multicast,
+            ..conf.clone()
+        };
+
+        let announcement = Announcement {
+            node_id: node_id.clone(),
+            addr: SocketAddr::new(
+                conf.bind_host
+                    .parse()
+                    .unwrap_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+                conf.port,
+            ),
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+        }
+        .encode();
+
+        let shared = Arc::new(Shared {
+            node_id,
+            expiry: conf.discovery_interval * EXPIRY_INTERVALS,
+            peers: Mutex::default(),
+            subscribers: Mutex::default(),
+        });
+        let running = Arc::new(AtomicBool::new(true));
+
+        let client = Client::init(&discovery_conf)?;
+        let server = Server::init(&discovery_conf)?;
+        server.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        let announcer = {
+            let shared = shared.clone();
+            let running = running.clone();
+            let port = conf.discovery_port;
+            let interval = conf.discovery_interval;
+            thread::Builder::new()
+                .name("crumb-discovery-announce".to_string())
+                .spawn(move || {
+                    while running.load(Ordering::Acquire) {
+                        let sent = match multicast {
+                            true => client.send(&announcement),
+                            false => client.send_broadcast(&announcement, port),
+                        };
+                        if let Err(e) = sent {
+                            debug!(target: TARGET, error = %e, "announcement failed");
+                        }
+                        shared.expire();
+                        thread::park_timeout(interval);
+                    }
+                    client.close();
+                })?
+        };
+
+        let receiver = {
+            let shared = shared.clone();
+            let running = running.clone();
+            thread::Builder::new()
+                .name("crumb-discovery-receive".to_string())
+                .spawn(move || {
+                    let mut buffer = [0u8; 1500];
+                    while running.load(Ordering::Acquire) {
+                        // Broadcast announcements carry the transport's announcement prefix;
+                        // multicast ones are sent as-is.
+                        match server.receive_from(&mut buffer) {
+                            Ok((received, source)) => {
+                                let payload = &buffer[..received];
+                                let payload =
+                                    payload.strip_prefix(ANNOUNCE_PREFIX).unwrap_or(payload);
+                                shared.observe(payload, source);
+                            }
+                            Err(e)
+                                if matches!(
+                                    e.kind(),
+                                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                                ) => {}
+                            Err(e) => debug!(target: TARGET, error = %e, "receive failed"),
+                        }
+                    }
+                    server.close();
+                })?
+        };
+
+        Ok(Discovery {
+            shared,
+            running,
+            announcer: Some(announcer),
+            receiver: Some(receiver),
+        })
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.shared.node_id
+    }
+
+    /// Peers heard from within the expiry window, ordered by node ID.
+    pub fn peers(&self) -> Vec<Peer> {
+        let now = Instant::now();
+        let mut peers: Vec<Peer> = lock(&self.shared.peers)
+            .values()
+            .filter(|peer| now.duration_since(peer.last_seen) < self.shared.expiry)
+            .cloned()
+            .collect();
+        peers.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+        peers
+    }
+
+    /// Returns a channel that receives every peer table change from now on.
+    pub fn subscribe(&self) -> mpsc::Receiver<PeerEvent> {
+        let (sender, receiver) = mpsc::channel();
+        lock(&self.shared.subscribers).push(sender);
+        receiver
+    }
+
+    pub fn stop(self) {
+        drop(self);
+    }
+}
+
+impl Drop for Discovery {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(announcer) = self.announcer.take() {
+            announcer.thread().unpark();
+            let _ = announcer.join();
+        }
+        if let Some(receiver) = self.receiver.take() {
+            let _ = receiver.join();
+        }
+    }
+}
+
+struct Shared {
+    node_id: String,
+    expiry: Duration,
+    peers: Mutex<HashMap<String, Peer>>,
+    subscribers: Mutex<Vec<mpsc::Sender<PeerEvent>>>,
+}
+
+impl Shared {
+    fn observe(&self, payload: &[u8], source: SocketAddr) {
+        let Some(mut announcement) = Announcement::decode(payload) else {
+            trace!(target: TARGET, %source, "ignoring datagram that is not an announcement");
+            return;
+        };
+        if announcement.node_id == self.node_id {
+            return;
+        }
+        if announcement.addr.ip().is_unspecified() {
+            announcement.addr.set_ip(source.ip().to_canonical());
+        }
+
+        let peer = Peer {
+            node_id: announcement.node_id,
+            addr: announcement.addr,
+            capabilities: announcement.capabilities,
+            last_seen: Instant::now(),
+        };
+        let event = match lock(&self.peers).entry(peer.node_id.clone()) {
+            Entry::Vacant(entry) => {
+                entry.insert(peer.clone());
+                Some(PeerEvent::Joined(peer))
+            }
+            Entry::Occupied(mut entry) => {
+                let known = entry.get();
+                let changed = known.addr != peer.addr || known.capabilities != peer.capabilities;
+                entry.insert(peer.clone());
+                changed.then_some(PeerEvent::Updated(peer))
+            }
+        };
+
+        if let Some(event) = event {
+            debug!(target: TARGET, ?event, "peer table changed");
+            self.notify(event);
+        }
+    }
+
+    fn expire(&self) {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        lock(&self.peers).retain(|_, peer| {
+            let alive = now.duration_since(peer.last_seen) < self.expiry;
+            if !alive {
+                expired.push(peer.clone());
+            }
+            alive
+        });
+
+        for peer in expired {
+            debug!(target: TARGET, node_id = %peer.node_id, "peer expired");
+            self.notify(PeerEvent::Expired(peer));
+        }
+    }
+
+    // Subscribers whose receiver has been dropped are removed.
+    fn notify(&self, event: PeerEvent) {
+        lock(&self.subscribers).retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announcement_round_trip() {
+        let announcement = Announcement {
+            node_id: "node-1".to_string(),
+            addr: "[::1]:50505".parse().unwrap(),
+            capabilities: vec!["telemetry".to_string(), "relay".to_string()],
+        };
+        assert_eq!(
+            Announcement::decode(&announcement.encode()),
+            Some(announcement)
+        );
+    }
+
+    #[test]
+    fn announcement_without_capabilities() {
+        let announcement = Announcement {
+            node_id: "node-1".to_string(),
+            addr: "127.0.0.1:50505".parse().unwrap(),
+            capabilities: vec![],
+        };
+        assert_eq!(
+            Announcement::decode(&announcement.encode()),
+            Some(announcement)
+        );
+    }
+
+    #[test]
+    fn garbage_is_not_an_announcement() {
+        assert_eq!(Announcement::decode(b""), None);
+        assert_eq!(Announcement::decode(b"hello"), None);
+        assert_eq!(
+            Announcement::decode(b"crumb-discovery/1\n\n127.0.0.1:1\n"),
+            None
+        );
+        assert_eq!(
+            Announcement::decode(b"crumb-discovery/1\nnode\nnot-an-addr\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn nodes_discover_and_expire_each_other() -> io::Result<()> {
+        let conf = |node_id: &str, port| Config {
+            port,
+            node_id: node_id.to_string(),
+            discovery_port: 8092,
+            discovery_interval: Duration::from_millis(50),
+            ..Default::default()
+        };
+
+        let a = Discovery::start(&conf("a", 9000), &["telemetry"])?;
+        let events = a.subscribe();
+        let b = Discovery::start(&conf("b", 9001), &["relay"])?;
+
+        match events.recv_timeout(Duration::from_secs(2)) {
+            Ok(PeerEvent::Joined(peer)) => {
+                assert_eq!(peer.node_id, "b");
+                assert_eq!(peer.addr, "127.0.0.1:9001".parse().unwrap());
+                assert_eq!(peer.capabilities, vec!["relay".to_string()]);
+            }
+            other => panic!("Expected b to join, got {:?}", other),
+        }
+        assert_eq!(a.peers().len(), 1);
+
+        b.stop();
+        match events.recv_timeout(Duration::from_secs(2)) {
+            Ok(PeerEvent::Expired(peer)) => assert_eq!(peer.node_id, "b"),
+            other => panic!("Expected b to expire, got {:?}", other),
+        }
+        assert!(a.peers().is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,353 @@
+//! NAT traversal for two clients that cannot reach each other directly. Both register with a
+//! `Rendezvous` reachable by each under the same token, and learn from it the address the other
+//! registered from, as seen from outside its NAT. Both then send punch packets to that address
+//! at once, so that each NAT sees outgoing traffic to the other and lets its replies in.
+//!
+//! ```no_run
+//! use crumb::discovery::punch;
+//! use crumb::session::Client;
+//! use crumb::util::config::Config;
+//! use std::time::Duration;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let conf = Config {
+//!     keepalive_interval: Duration::from_secs(15),
+//!     ..Config::from_env(None)?
+//! };
+//! // Run the same on the other side, with the same token.
+//! let direct = punch(&conf, "rendezvous.example.com:50507", "room-42", Duration::from_secs(10))?;
+//! let client = Client::init(&direct)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! NATs forget idle mappings, so the session should send keepalives. Punching fails between
+//! NATs that map each destination to a different port, as symmetric NATs do.
+
+use super::POLL_INTERVAL;
+use crate::transport::udp::Server;
+use crate::transport::{bind_client, for_socket, resolve_for};
+use crate::util::config::Config;
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::{debug, trace};
+
+const TARGET: &str = "crumb::discovery::rendezvous";
+
+// First line of registrations and of the rendezvous' replies to them.
+const REGISTER_HEADER: &str = "crumb-rendezvous/1";
+
+// First line of the packets punching through to the peer.
+const PUNCH_HEADER: &str = "crumb-punch/1";
+
+// How long a registration is kept without being renewed.
+const REGISTRATION_TTL: Duration = Duration::from_secs(30);
+
+// How often registrations and punches are sent until answered.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Pairs clients registering under the same token, telling each the other's address. Runs on a
+/// background thread until dropped.
+pub struct Rendezvous {
+    local_addr: SocketAddr,
+    running: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Rendezvous {
+    /// Starts answering registrations on the port a server with `conf` would listen on. The
+    /// server's ACL and rate limits apply to them.
+    pub fn start(conf: &Config) -> io::Result<Rendezvous> {
+        let server = Server::init(conf)?;
+        server.set_read_timeout(Some(POLL_INTERVAL))?;
+        let local_addr = server.local_addr()?;
+        let running = Arc::new(AtomicBool::new(true));
+
+        let worker = {
+            let running = running.clone();
+            thread::Builder::new()
+                .name("crumb-rendezvous".to_string())
+                .spawn(move || {
+                    let mut registrations = Registrations::new();
+                    let mut buffer = [0u8; 512];
+                    while running.load(Ordering::Acquire) {
+                        match server.receive_from(&mut buffer) {
+                            Ok((received, source)) => {
+                                let Some(token) = decode(REGISTER_HEADER, &buffer[..received])
+                                else {
+                                    trace!(target: TARGET, %source, "ignoring datagram that is not a registration");
+                                    continue;
+                                };
+                                let source = canonical(source);
+                                let pair = registrations.register(token, source);
+                                for (addr, peer) in pair {
+                                    let reply = encode(REGISTER_HEADER, token, Some(peer));
+                                    if let Err(e) = server.send_to(&reply, addr) {
+                                        debug!(target: TARGET, %addr, error = %e, "reply failed");
+                                    }
+                                }
+                            }
+                            Err(e)
+                                if matches!(
+                                    e.kind(),
+                                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                                ) => {}
+                            Err(e) => debug!(target: TARGET, error = %e, "receive failed"),
+                        }
+                    }
+                    server.close();
+                })?
+        };
+
+        debug!(target: TARGET, %local_addr, "rendezvous started");
+        Ok(Rendezvous {
+            local_addr,
+            running,
+            worker: Some(worker),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn stop(self) {
+        drop(self);
+    }
+}
+
+impl Drop for Rendezvous {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// The addresses registered under each token, with when each last registered. A token is taken
+/// by the first two addresses to register under it until they expire.
+struct Registrations {
+    tokens: HashMap<String, Vec<(SocketAddr, Instant)>>,
+}
+
+impl Registrations {
+    fn new() -> Registrations {
+        Registrations {
+            tokens: HashMap::new(),
+        }
+    }
+
+    // Registers `addr` under `token`, returning who to tell about whom once two have registered.
+    // Both are told again on every registration, in case an earlier reply was lost.
+    fn register(&mut self, token: &str, addr: SocketAddr) -> Vec<(SocketAddr, SocketAddr)> {
+        let now = Instant::now();
+        self.tokens.retain(|_, registered| {
+            registered.retain(|(_, at)| now.saturating_duration_since(*at) < REGISTRATION_TTL);
+            !registered.is_empty()
+        });
+
+        let registered = self.tokens.entry(token.to_string()).or_default();
+        match registered.iter().position(|(known, _)| *known == addr) {
+            Some(known) => registered[known].1 = now,
+            None if registered.len() < 2 => registered.push((addr, now)),
+            None => {
+                debug!(target: TARGET, token, %addr, "token already taken by two clients");
+                return Vec::new();
+            }
+        }
+        match registered.as_slice() {
+            [(first, _), (second, _)] => vec![(*first, *second), (*second, *first)],
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Registers with the rendezvous at `rendezvous` under `token`, then punches through to the
+/// client registered under the same token. Returns `conf` pointing at that client and bound to
+/// the local port the punching was done from, for a `session::Client` to take over. Fails with
+/// `ErrorKind::TimedOut` when the other client has not answered within `timeout`.
+pub fn punch<A: ToSocketAddrs>(
+    conf: &Config,
+    rendezvous: A,
+    token: &str,
+    timeout: Duration,
+) -> io::Result<Config> {
+    if token.is_empty() || token.contains('\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Rendezvous tokens must be a non-empty single line",
+        ));
+    }
+    let deadline = Instant::now() + timeout;
+    let socket = bind_client(conf)?;
+    socket.set_read_timeout(Some(RETRY_INTERVAL))?;
+    let local_addr = socket.local_addr()?;
+    let rendezvous = resolve_for(local_addr, rendezvous)?;
+    let mut buffer = [0u8; 512];
+
+    let registration = encode(REGISTER_HEADER, token, None);
+    let peer = 'registered: loop {
+        socket.send_to(&registration, rendezvous)?;
+        let sent = Instant::now();
+        while sent.elapsed() < RETRY_INTERVAL {
+            let Some((received, source)) = receive(&socket, &mut buffer, deadline)? else {
+                break;
+            };
+            if source != rendezvous {
+                continue;
+            }
+            let peer = str::from_utf8(&buffer[..received])
+                .ok()
+                .and_then(|reply| reply.strip_prefix(REGISTER_HEADER)?.strip_prefix('\n'))
+                .and_then(|reply| reply.strip_prefix(token)?.strip_prefix('\n'))
+                .and_then(|peer| peer.parse().ok());
+            if let Some(peer) = peer {
+                break 'registered for_socket(local_addr, peer);
+            }
+        }
+    };
+    debug!(target: TARGET, %peer, "punching through to peer");
+
+    // Anything from the peer shows the way in is open, as it may have stopped punching and
+    // started its session before this side heard its punches.
+    let punch = encode(PUNCH_HEADER, token, None);
+    'punched: loop {
+        socket.send_to(&punch, peer)?;
+        let sent = Instant::now();
+        while sent.elapsed() < RETRY_INTERVAL {
+            match receive(&socket, &mut buffer, deadline)? {
+                Some((_, source)) if source == peer => break 'punched,
+                Some(_) => {}
+                None => break,
+            }
+        }
+    }
+    // The peer may still be waiting to hear from this side.
+    socket.send_to(&punch, peer)?;
+    debug!(target: TARGET, %peer, "punched through to peer");
+
+    let peer = canonical(peer);
+    Ok(Config {
+        host: peer.ip().to_string(),
+        port: peer.port(),
+        bind_port: local_addr.port(),
+        ..conf.clone()
+    })
+}
+
+// The next datagram, or `None` once the retry interval passes without one. Fails once
+// `deadline` has passed.
+fn receive(
+    socket: &std::net::UdpSocket,
+    buffer: &mut [u8],
+    deadline: Instant,
+) -> io::Result<Option<(usize, SocketAddr)>> {
+    if Instant::now() >= deadline {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "Timed out punching through to the peer",
+        ));
+    }
+    match socket.recv_from(buffer) {
+        Ok(received) => Ok(Some(received)),
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            Ok(None)
+        }
+        // Unreachable peers are reported on some platforms, and expected until the punch works.
+        Err(e) if e.kind() == io::ErrorKind::ConnectionReset => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn encode(header: &str, token: &str, peer: Option<SocketAddr>) -> Vec<u8> {
+    match peer {
+        Some(peer) => format!("{}\n{}\n{}", header, token, peer),
+        None => format!("{}\n{}", header, token),
+    }
+    .into_bytes()
+}
+
+// The token of a datagram with no more than `header` and a token.
+fn decode<'a>(header: &str, bytes: &'a [u8]) -> Option<&'a str> {
+    str::from_utf8(bytes)
+        .ok()?
+        .strip_prefix(header)?
+        .strip_prefix('\n')
+        .filter(|token| !token.is_empty() && !token.contains('\n'))
+}
+
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Client;
+
+    #[test]
+    fn tokens_pair_two_clients() {
+        let mut registrations = Registrations::new();
+        let a: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        let b: SocketAddr = "198.51.100.7:5000".parse().unwrap();
+        let c: SocketAddr = "203.0.113.9:6000".parse().unwrap();
+        assert!(registrations.register("room", a).is_empty());
+        assert!(registrations.register("room", a).is_empty());
+        assert!(registrations.register("other", c).is_empty());
+        assert_eq!(registrations.register("room", b), vec![(a, b), (b, a)]);
+        assert_eq!(registrations.register("room", a), vec![(a, b), (b, a)]);
+        assert!(registrations.register("room", c).is_empty());
+    }
+
+    #[test]
+    fn punched_clients_hold_a_session() -> io::Result<()> {
+        let rendezvous = Rendezvous::start(&Config {
+            port: 8139,
+            bind_host: "127.0.0.1".to_string(),
+            ..Default::default()
+        })?;
+        let conf = Config {
+            bind_host: "127.0.0.1".to_string(),
+            ..Default::default()
+        };
+        let addr = rendezvous.local_addr();
+        let timeout = Duration::from_secs(5);
+        let other = {
+            let conf = conf.clone();
+            thread::spawn(move || punch(&conf, addr, "room", timeout))
+        };
+        let a = punch(&conf, addr, "room", timeout)?;
+        let b = other.join().unwrap()?;
+        assert_eq!(a.port, b.bind_port);
+        assert_eq!(b.port, a.bind_port);
+
+        let a = Client::init(&a)?;
+        let b = Client::init(&b)?;
+        a.send(b"hello")?;
+        let mut buffer = [0u8; 16];
+        let received = b.receive_timeout(&mut buffer, timeout)?;
+        assert_eq!(&buffer[..received], b"hello");
+        Ok(())
+    }
+
+    #[test]
+    fn tokens_are_single_lines() {
+        let conf = Config::default();
+        let e = punch(&conf, "127.0.0.1:9", "a\nb", Duration::ZERO).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(decode(PUNCH_HEADER, b"crumb-punch/1\n"), None);
+        assert_eq!(decode(PUNCH_HEADER, b"crumb-punch/1\nroom"), Some("room"));
+    }
+}
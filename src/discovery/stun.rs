@@ -0,0 +1,288 @@
+//! A STUN (RFC 5389) binding client, for a node to learn the address and port its NAT maps it
+//! to, so it can register them with a coordinator before any peer has reached it.
+//!
+//! ```no_run
+//! use crumb::discovery::stun::Client;
+//! use crumb::util::config::Config;
+//!
+//! # fn main() -> std::io::Result<()> {
+//! // A fixed bind_port, so that the session bound afterwards gets the same mapping.
+//! let conf = Config {
+//!     bind_port: 40000,
+//!     ..Default::default()
+//! };
+//! let public = Client::init(&conf)?.discover_public_addr("stun.l.google.com:19302")?;
+//! println!("reachable at {}", public);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::transport::{bind_client, resolve_for};
+use crate::util::config::Config;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+use tracing::{debug, trace};
+
+const TARGET: &str = "crumb::discovery::stun";
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const BINDING_ERROR: u16 = 0x0111;
+
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const HEADER_LEN: usize = 20;
+
+const MAPPED_ADDRESS: u16 = 0x0001;
+const ERROR_CODE: u16 = 0x0009;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+const FAMILY_V4: u8 = 0x01;
+const FAMILY_V6: u8 = 0x02;
+
+// Requests are sent again after this long, doubling each time, as RFC 5389 recommends.
+const INITIAL_RTO: Duration = Duration::from_millis(500);
+
+// Requests sent before giving up, which waits 15.5 seconds in all.
+const MAX_REQUESTS: u32 = 5;
+
+/// A UDP socket bound as a client with the same `Config` would be, for asking STUN servers what
+/// it looks like from outside.
+pub struct Client {
+    socket: UdpSocket,
+}
+
+impl Client {
+    pub fn init(conf: &Config) -> io::Result<Client> {
+        Ok(Client {
+            socket: bind_client(conf)?,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// The address and port `stun_server` sees this socket's requests come from. Fails with
+    /// `ErrorKind::TimedOut` when the server does not answer, and `ErrorKind::Other` when it
+    /// answers with an error.
+    pub fn discover_public_addr<A: ToSocketAddrs>(&self, stun_server: A) -> io::Result<SocketAddr> {
+        let server = resolve_for(self.socket.local_addr()?, stun_server)?;
+        let mut transaction = [0u8; 12];
+        OsRng.fill_bytes(&mut transaction);
+        let request = binding_request(&transaction);
+        let mut buffer = [0u8; 576];
+
+        let mut rto = INITIAL_RTO;
+        for _ in 0..MAX_REQUESTS {
+            self.socket.send_to(&request, server)?;
+            let deadline = Instant::now() + rto;
+            while let Some(wait) = deadline.checked_duration_since(Instant::now()) {
+                self.socket
+                    .set_read_timeout(Some(wait.max(Duration::from_millis(1))))?;
+                let (received, source) = match self.socket.recv_from(&mut buffer) {
+                    Ok(received) => received,
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                        ) =>
+                    {
+                        break
+                    }
+                    Err(e) => return Err(e),
+                };
+                if source != server {
+                    continue;
+                }
+                match parse_response(&buffer[..received], &transaction) {
+                    Some(result) => {
+                        if let Ok(addr) = &result {
+                            debug!(target: TARGET, %server, public_addr = %addr, "discovered public address");
+                        }
+                        return result;
+                    }
+                    None => trace!(target: TARGET, %server, "ignoring unrelated datagram"),
+                }
+            }
+            rto *= 2;
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "STUN server did not answer",
+        ))
+    }
+}
+
+fn binding_request(transaction: &[u8; 12]) -> Vec<u8> {
+    let mut request = Vec::with_capacity(HEADER_LEN);
+    request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(transaction);
+    request
+}
+
+// The outcome a response to `transaction` reports, or `None` for anything else. The
+// XOR-MAPPED-ADDRESS is preferred to the MAPPED-ADDRESS older servers send.
+fn parse_response(bytes: &[u8], transaction: &[u8; 12]) -> Option<io::Result<SocketAddr>> {
+    if bytes.len() < HEADER_LEN
+        || bytes[4..8] != MAGIC_COOKIE.to_be_bytes()
+        || bytes[8..HEADER_LEN] != transaction[..]
+    {
+        return None;
+    }
+    let kind = u16::from_be_bytes([bytes[0], bytes[1]]);
+    let len = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+    let mut attributes = bytes.get(HEADER_LEN..HEADER_LEN + len)?;
+
+    let mut mapped = None;
+    let mut xor_mapped = None;
+    let mut error = None;
+    while attributes.len() >= 4 {
+        let attribute = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let len = u16::from_be_bytes([attributes[2], attributes[3]]) as usize;
+        let value = attributes.get(4..4 + len)?;
+        match attribute {
+            MAPPED_ADDRESS => mapped = address(value, None),
+            XOR_MAPPED_ADDRESS => xor_mapped = address(value, Some(&bytes[4..HEADER_LEN])),
+            ERROR_CODE if value.len() >= 4 => {
+                let code = u16::from(value[2] & 0x07) * 100 + u16::from(value[3]);
+                error = Some((code, String::from_utf8_lossy(&value[4..]).into_owned()));
+            }
+            _ => {}
+        }
+        // Values are padded to a multiple of four bytes.
+        attributes = attributes
+            .get(4 + len.next_multiple_of(4)..)
+            .unwrap_or_default();
+    }
+
+    match kind {
+        BINDING_SUCCESS => Some(xor_mapped.or(mapped).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "STUN response carried no mapped address",
+            )
+        })),
+        BINDING_ERROR => {
+            let (code, reason) = error.unwrap_or((0, String::new()));
+            Some(Err(io::Error::other(format!(
+                "STUN server refused the request: {} {}",
+                code, reason
+            ))))
+        }
+        _ => None,
+    }
+}
+
+// An address attribute's value, XORed with the magic cookie and transaction ID when `xor` holds
+// them.
+fn address(value: &[u8], xor: Option<&[u8]>) -> Option<SocketAddr> {
+    let mask = |bytes: &[u8]| -> Vec<u8> {
+        match xor {
+            Some(xor) => bytes.iter().zip(xor).map(|(b, x)| b ^ x).collect(),
+            None => bytes.to_vec(),
+        }
+    };
+    let port = mask(value.get(2..4)?);
+    let port = u16::from_be_bytes([port[0], port[1]]);
+    let ip = match value[1] {
+        FAMILY_V4 => {
+            let ip: [u8; 4] = mask(value.get(4..8)?).try_into().ok()?;
+            IpAddr::V4(Ipv4Addr::from(ip))
+        }
+        FAMILY_V6 => {
+            let ip: [u8; 16] = mask(value.get(4..20)?).try_into().ok()?;
+            IpAddr::V6(Ipv6Addr::from(ip))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // A binding success response to `request` reporting `addr`, as an RFC 5389 server sends it.
+    fn response(request: &[u8], addr: SocketAddr) -> Vec<u8> {
+        let cookie_and_transaction = &request[4..HEADER_LEN];
+        let (family, ip) = match addr.ip().to_canonical() {
+            IpAddr::V4(ip) => (FAMILY_V4, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (FAMILY_V6, ip.octets().to_vec()),
+        };
+        let xor = |bytes: &[u8]| -> Vec<u8> {
+            bytes
+                .iter()
+                .zip(cookie_and_transaction)
+                .map(|(b, x)| b ^ x)
+                .collect()
+        };
+        let mut value = vec![0, family];
+        value.extend_from_slice(&xor(&addr.port().to_be_bytes()));
+        value.extend_from_slice(&xor(&ip));
+
+        let mut response = BINDING_SUCCESS.to_be_bytes().to_vec();
+        response.extend_from_slice(&(4 + value.len() as u16).to_be_bytes());
+        response.extend_from_slice(cookie_and_transaction);
+        response.extend_from_slice(&XOR_MAPPED_ADDRESS.to_be_bytes());
+        response.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        response.extend_from_slice(&value);
+        response
+    }
+
+    #[test]
+    fn public_address_is_reflected() -> io::Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0")?;
+        let server_addr = server.local_addr()?;
+        let responder = thread::spawn(move || -> io::Result<()> {
+            let mut buffer = [0u8; 64];
+            let (received, source) = server.recv_from(&mut buffer)?;
+            server.send_to(b"unrelated", source)?;
+            server.send_to(&response(&buffer[..received], source), source)?;
+            Ok(())
+        });
+
+        let client = Client::init(&Config {
+            bind_host: "127.0.0.1".to_string(),
+            ..Default::default()
+        })?;
+        let public = client.discover_public_addr(server_addr)?;
+        assert_eq!(public, client.local_addr()?);
+        responder.join().unwrap()
+    }
+
+    #[test]
+    fn responses_are_parsed() {
+        let transaction = [7u8; 12];
+        let request = binding_request(&transaction);
+        let v6: SocketAddr = "[2001:db8::1]:3478".parse().unwrap();
+        let v6_response = response(&request, v6);
+        assert_eq!(
+            parse_response(&v6_response, &transaction).unwrap().unwrap(),
+            v6
+        );
+        assert!(parse_response(&v6_response, &[8u8; 12]).is_none());
+
+        let mut mapped = BINDING_SUCCESS.to_be_bytes().to_vec();
+        mapped.extend_from_slice(&12u16.to_be_bytes());
+        mapped.extend_from_slice(&request[4..HEADER_LEN]);
+        mapped.extend_from_slice(&[0, 1, 0, 8, 0, FAMILY_V4, 0x13, 0x88, 192, 0, 2, 1]);
+        assert_eq!(
+            parse_response(&mapped, &transaction).unwrap().unwrap(),
+            "192.0.2.1:5000".parse().unwrap()
+        );
+
+        let mut refused = BINDING_ERROR.to_be_bytes().to_vec();
+        refused.extend_from_slice(&16u16.to_be_bytes());
+        refused.extend_from_slice(&request[4..HEADER_LEN]);
+        refused.extend_from_slice(&[0, 9, 0, 9, 0, 0, 4, 20]);
+        refused.extend_from_slice(b"Role!\0\0\0");
+        let e = parse_response(&refused, &transaction).unwrap().unwrap_err();
+        assert!(e.to_string().contains("420 Role!"), "{}", e);
+    }
+}
@@ -0,0 +1,163 @@
+//! The crate-wide error type.
+//!
+//! Loading configs, encoding payloads and the like return `crumb::Result`. Transports and
+//! sessions keep returning `io::Result`, as sockets do, but the errors they make themselves carry
+//! an `Error` inside, which `Error::from` recovers, so callers can match on the class of any
+//! failure:
+//!
+//! ```no_run
+//! use crumb::session::Client;
+//! use crumb::util::config::Config;
+//!
+//! let conf = Config::default();
+//! match Client::init(&conf).map_err(crumb::Error::from) {
+//!     Ok(_client) => {}
+//!     Err(crumb::Error::Config(e)) => eprintln!("fix the config: {}", e),
+//!     Err(e) => eprintln!("could not connect: {}", e),
+//! }
+//! ```
+
+use crate::protocol::FrameError;
+use std::{error, fmt, io};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+type Source = Box<dyn error::Error + Send + Sync>;
+
+/// What went wrong, by class. The variants other than `Timeout`, `PayloadTooLarge` and
+/// `AuthFailed` wrap the underlying error, which `source` returns.
+#[derive(Debug)]
+pub enum Error {
+    /// A config value, file or argument that cannot be used.
+    Config(Source),
+    /// A socket or file operation failed.
+    Io(io::Error),
+    /// TLS could not be set up, or its certificates or keys could not be loaded.
+    Tls(Source),
+    /// A payload could not be compressed, decompressed, serialized or deserialized.
+    Codec(Source),
+    /// A datagram was not a valid frame, or failed authentication.
+    Protocol(Source),
+    /// Nothing arrived before a read timeout.
+    Timeout,
+    /// A message would not fit in one datagram: `size` bytes, with framing, against the `max`
+    /// the transport or discovered path takes.
+    PayloadTooLarge { size: usize, max: usize },
+    /// The server's verifier rejected the session's `auth_token`.
+    AuthFailed,
+}
+
+impl Error {
+    pub(crate) fn config(e: impl Into<Source>) -> Error {
+        Error::Config(e.into())
+    }
+
+    #[cfg(feature = "quic")]
+    pub(crate) fn tls(e: impl Into<Source>) -> Error {
+        Error::Tls(e.into())
+    }
+
+    pub(crate) fn codec(e: impl Into<Source>) -> Error {
+        Error::Codec(e.into())
+    }
+
+    pub(crate) fn protocol(e: impl Into<Source>) -> Error {
+        Error::Protocol(e.into())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(e) => write!(f, "Invalid config: {}", e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Tls(e) => write!(f, "TLS error: {}", e),
+            Error::Codec(e) => write!(f, "Codec error: {}", e),
+            Error::Protocol(e) => write!(f, "Protocol error: {}", e),
+            Error::Timeout => write!(f, "Timed out"),
+            Error::PayloadTooLarge { size, max } => {
+                write!(f, "Payload too large: {} bytes, at most {} fit", size, max)
+            }
+            Error::AuthFailed => write!(f, "Authentication failed"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Config(e) | Error::Tls(e) | Error::Codec(e) | Error::Protocol(e) => {
+                Some(e.as_ref())
+            }
+            Error::Io(e) => Some(e),
+            Error::Timeout | Error::PayloadTooLarge { .. } | Error::AuthFailed => None,
+        }
+    }
+}
+
+/// Recovers the `Error` an I/O error from this crate carries. Others become `Io`, or `Timeout`
+/// for read timeouts.
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return *e.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Error::Timeout,
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl From<FrameError> for Error {
+    fn from(e: FrameError) -> Error {
+        Error::protocol(e)
+    }
+}
+
+/// Wraps the error in an I/O error of the closest kind, from which `Error::from` takes it back.
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        let kind = match e {
+            Error::Io(e) => return e,
+            Error::Config(_) | Error::Tls(_) | Error::PayloadTooLarge { .. } => {
+                io::ErrorKind::InvalidInput
+            }
+            Error::Codec(_) | Error::Protocol(_) => io::ErrorKind::InvalidData,
+            Error::Timeout => io::ErrorKind::TimedOut,
+            Error::AuthFailed => io::ErrorKind::PermissionDenied,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn classes_survive_io_errors() {
+        let e = io::Error::from(Error::config("port must not be 0"));
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(matches!(Error::from(e), Error::Config(_)));
+
+        let e = io::Error::from(Error::from(FrameError::Checksum));
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let e = Error::from(e);
+        assert_eq!(e.source().unwrap().to_string(), "Frame checksum mismatch");
+
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        let e = io::Error::from(Error::from(refused));
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(e.get_ref().is_none());
+        assert!(matches!(
+            Error::from(io::Error::from(io::ErrorKind::WouldBlock)),
+            Error::Timeout
+        ));
+
+        let e = io::Error::from(Error::AuthFailed);
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert!(matches!(Error::from(e), Error::AuthFailed));
+    }
+}
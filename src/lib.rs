@@ -1,5 +1,21 @@
-mod compression;
-mod session;
-mod stream;
-mod transport;
-mod util;
+#[cfg(any(feature = "mqtt", feature = "kafka", feature = "ingest"))]
+pub mod bridge;
+pub mod capture;
+#[cfg(feature = "prost-build")]
+pub mod codegen;
+pub mod compression;
+pub mod discovery;
+pub mod error;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod payload;
+pub mod protocol;
+pub mod pubsub;
+pub mod schema;
+pub mod security;
+pub mod session;
+pub mod stream;
+pub mod transport;
+pub mod util;
+
+pub use error::{Error, Result};
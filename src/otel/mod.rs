@@ -0,0 +1,182 @@
+//! Export of crumb's spans and session metrics through the OpenTelemetry SDK over OTLP/HTTP,
+//! so that nodes plug into an existing collector.
+//!
+//! ```no_run
+//! use crumb::otel::Telemetry;
+//! use crumb::session::Client;
+//! use crumb::util::config::Config;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! // Exports to OTEL_EXPORTER_OTLP_ENDPOINT, or http://localhost:4318 when it is unset.
+//! let telemetry = Telemetry::init()?;
+//! let client = Client::init(&Config::from_env(None)?)?;
+//! client.export_metrics(&telemetry);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The exporters read the standard variables: `OTEL_EXPORTER_OTLP_ENDPOINT` and the per-signal
+//! `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` and `OTEL_EXPORTER_OTLP_METRICS_ENDPOINT`,
+//! `OTEL_EXPORTER_OTLP_HEADERS`, `OTEL_EXPORTER_OTLP_TIMEOUT`, `OTEL_SERVICE_NAME`,
+//! `OTEL_RESOURCE_ATTRIBUTES` and `OTEL_METRIC_EXPORT_INTERVAL`.
+
+use crate::error::Error;
+use crate::protocol::TraceContext;
+use opentelemetry::metrics::{Meter, MeterProvider};
+use opentelemetry::trace::{
+    SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState, TracerProvider,
+};
+use opentelemetry::Context;
+use opentelemetry_otlp::{MetricExporter, SpanExporter};
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing::{debug, Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+
+const TARGET: &str = "crumb::otel";
+
+/// The instrumentation scope crumb's spans and metrics are reported under.
+const SCOPE: &str = "crumb";
+
+/// The tracer and meter providers exporting crumb's telemetry. Dropping it flushes and shuts
+/// both down, so keep it for as long as the process runs.
+pub struct Telemetry {
+    tracer: SdkTracerProvider,
+    meter: SdkMeterProvider,
+}
+
+impl Telemetry {
+    /// Builds OTLP/HTTP span and metric exporters configured from the `OTEL_*` variables.
+    /// Spans are exported in batches and metrics every `OTEL_METRIC_EXPORT_INTERVAL`, 60 seconds
+    /// by default, each from a thread of its own. Fails with `Error::Config` when the exporters
+    /// cannot be built from the environment.
+    pub fn from_env() -> crate::Result<Telemetry> {
+        let resource = Resource::builder().build();
+        let spans = SpanExporter::builder()
+            .with_http()
+            .build()
+            .map_err(Error::config)?;
+        let metrics = MetricExporter::builder()
+            .with_http()
+            .build()
+            .map_err(Error::config)?;
+        let tracer = SdkTracerProvider::builder()
+            .with_batch_exporter(spans)
+            .with_resource(resource.clone())
+            .build();
+        let meter = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(metrics).build())
+            .with_resource(resource)
+            .build();
+        debug!(target: TARGET, "telemetry exporters started");
+        Ok(Telemetry { tracer, meter })
+    }
+
+    /// Like `from_env`, and installs a global `tracing` subscriber that exports every span, so
+    /// crumb's session spans and the application's own are sent to the collector. Fails with
+    /// `Error::Config` when a global subscriber is already set; add `layer` to that one instead.
+    pub fn init() -> crate::Result<Telemetry> {
+        let telemetry = Telemetry::from_env()?;
+        let subscriber = tracing_subscriber::registry().with(telemetry.layer());
+        tracing::subscriber::set_global_default(subscriber).map_err(Error::config)?;
+        Ok(telemetry)
+    }
+
+    /// A `tracing` layer exporting the spans of the subscriber it is added to.
+    pub fn layer<S>(&self) -> OpenTelemetryLayer<S, opentelemetry_sdk::trace::Tracer>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        tracing_opentelemetry::layer().with_tracer(self.tracer.tracer(SCOPE))
+    }
+
+    /// The meter crumb's session metrics are recorded with.
+    pub(crate) fn meter(&self) -> Meter {
+        self.meter.meter(SCOPE)
+    }
+
+    /// Exports whatever spans and metrics are pending, without waiting for the next batch or
+    /// interval.
+    pub fn flush(&self) {
+        if let Err(e) = self.tracer.force_flush() {
+            debug!(target: TARGET, error = %e, "flushing spans failed");
+        }
+        if let Err(e) = self.meter.force_flush() {
+            debug!(target: TARGET, error = %e, "flushing metrics failed");
+        }
+    }
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Err(e) = self.tracer.shutdown() {
+            debug!(target: TARGET, error = %e, "shutting down the span exporter failed");
+        }
+        if let Err(e) = self.meter.shutdown() {
+            debug!(target: TARGET, error = %e, "shutting down the metric exporter failed");
+        }
+    }
+}
+
+impl TraceContext {
+    /// The context of the span the caller is in, to send with `Client::send_traced`. `None`
+    /// when that span is not being exported.
+    pub fn current() -> Option<TraceContext> {
+        let context = Span::current().context();
+        let span = context.span();
+        let span = span.span_context();
+        span.is_valid().then(|| {
+            TraceContext::new(
+                span.trace_id().to_bytes(),
+                span.span_id().to_bytes(),
+                span.is_sampled(),
+            )
+        })
+    }
+
+    /// Makes `span` a child of the remote span this context names, so the spans a consumer
+    /// handles the message in are exported as part of the sender's trace.
+    pub fn adopt(&self, span: &Span) {
+        let remote = SpanContext::new(
+            TraceId::from_bytes(self.trace_id),
+            SpanId::from_bytes(self.parent_id),
+            TraceFlags::new(self.flags),
+            true,
+            TraceState::default(),
+        );
+        let parent = Context::new().with_remote_span_context(remote);
+        if let Err(e) = span.set_parent(parent) {
+            debug!(target: TARGET, error = %e, "span not adopted into the trace");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::info_span;
+
+    #[test]
+    fn trace_contexts_join_exported_spans() {
+        let tracer = SdkTracerProvider::builder().build().tracer(SCOPE);
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+        tracing::subscriber::with_default(subscriber, || {
+            assert_eq!(TraceContext::current(), None);
+
+            let remote: TraceContext = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+                .parse()
+                .unwrap();
+            let span = info_span!("handling");
+            remote.adopt(&span);
+            let _enter = span.enter();
+            let current = TraceContext::current().unwrap();
+            assert_eq!(current.trace_id, remote.trace_id);
+            assert_ne!(current.parent_id, remote.parent_id);
+            assert!(current.is_sampled());
+        });
+    }
+}
@@ -0,0 +1,125 @@
+//! Payload formats.
+//!
+//! Every frame names the format of its payload in its header, so one transport can carry
+//! protobuf, JSON, MessagePack and raw payloads side by side and receivers decode each message
+//! by what its sender declared. `encode` and `decode` handle serde values in JSON (the `json`
+//! feature) and MessagePack (the `msgpack` feature); protobuf messages are handled by the
+//! `prost` feature.
+
+#[cfg(any(feature = "json", feature = "msgpack"))]
+use crate::error::Error;
+use crate::util::config::PayloadFormat;
+
+const TAG_RAW: u8 = 0;
+const TAG_PROTOBUF: u8 = 1;
+const TAG_JSON: u8 = 2;
+const TAG_MSGPACK: u8 = 3;
+
+impl PayloadFormat {
+    /// The value naming the format in a frame header.
+    pub(crate) fn tag(self) -> u8 {
+        match self {
+            PayloadFormat::Raw => TAG_RAW,
+            PayloadFormat::Protobuf => TAG_PROTOBUF,
+            PayloadFormat::Json => TAG_JSON,
+            PayloadFormat::Msgpack => TAG_MSGPACK,
+        }
+    }
+
+    pub(crate) fn from_tag(tag: u8) -> Option<PayloadFormat> {
+        match tag {
+            TAG_RAW => Some(PayloadFormat::Raw),
+            TAG_PROTOBUF => Some(PayloadFormat::Protobuf),
+            TAG_JSON => Some(PayloadFormat::Json),
+            TAG_MSGPACK => Some(PayloadFormat::Msgpack),
+            _ => None,
+        }
+    }
+}
+
+/// Serializes `value` in `format`, which must be a serde format compiled into this build.
+#[cfg(any(feature = "json", feature = "msgpack"))]
+pub fn encode<T: serde::Serialize>(format: PayloadFormat, value: &T) -> crate::Result<Vec<u8>> {
+    match format {
+        #[cfg(feature = "json")]
+        PayloadFormat::Json => serde_json::to_vec(value).map_err(Error::codec),
+        #[cfg(feature = "msgpack")]
+        PayloadFormat::Msgpack => rmp_serde::to_vec_named(value).map_err(Error::codec),
+        _ => Err(unsupported(format)),
+    }
+}
+
+/// Deserializes a payload that its sender declared to be in `format`.
+#[cfg(any(feature = "json", feature = "msgpack"))]
+pub fn decode<T: serde::de::DeserializeOwned>(
+    format: PayloadFormat,
+    payload: &[u8],
+) -> crate::Result<T> {
+    match format {
+        #[cfg(feature = "json")]
+        PayloadFormat::Json => serde_json::from_slice(payload).map_err(Error::codec),
+        #[cfg(feature = "msgpack")]
+        PayloadFormat::Msgpack => rmp_serde::from_slice(payload).map_err(Error::codec),
+        _ => Err(unsupported(format)),
+    }
+}
+
+#[cfg(any(feature = "json", feature = "msgpack"))]
+fn unsupported(format: PayloadFormat) -> Error {
+    Error::codec(format!(
+        "{:?} payloads are not serde values in this build",
+        format
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_round_trip() {
+        for format in [
+            PayloadFormat::Raw,
+            PayloadFormat::Protobuf,
+            PayloadFormat::Json,
+            PayloadFormat::Msgpack,
+        ] {
+            assert_eq!(PayloadFormat::from_tag(format.tag()), Some(format));
+        }
+        assert_eq!(PayloadFormat::from_tag(9), None);
+    }
+
+    #[cfg(all(feature = "json", feature = "msgpack"))]
+    #[test]
+    fn serde_formats_round_trip() -> crate::Result<()> {
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct Reading {
+            sensor: String,
+            value: f64,
+        }
+
+        let reading = Reading {
+            sensor: "north".to_string(),
+            value: 21.5,
+        };
+        let json = encode(PayloadFormat::Json, &reading)?;
+        assert_eq!(json, br#"{"sensor":"north","value":21.5}"#);
+        assert_eq!(decode::<Reading>(PayloadFormat::Json, &json)?, reading);
+
+        let msgpack = encode(PayloadFormat::Msgpack, &reading)?;
+        assert_eq!(
+            decode::<Reading>(PayloadFormat::Msgpack, &msgpack)?,
+            reading
+        );
+        assert!(decode::<Reading>(PayloadFormat::Json, &msgpack).is_err());
+        Ok(())
+    }
+
+    #[cfg(any(feature = "json", feature = "msgpack"))]
+    #[test]
+    fn non_serde_formats_are_rejected() {
+        let error = encode(PayloadFormat::Protobuf, &1).unwrap_err();
+        assert!(matches!(error, Error::Codec(_)));
+        assert!(decode::<u32>(PayloadFormat::Raw, b"1").is_err());
+    }
+}
@@ -0,0 +1,433 @@
+//! A readable account of a datagram, for debugging peers that disagree about what they sent.
+
+use super::trace::hex;
+use super::{checksum, header_len, Frame, FrameError, CHECKSUM};
+use crate::compression;
+use crate::schema::SchemaRegistry;
+use crate::util::config::{CompressionType, PayloadFormat};
+use std::fmt;
+
+// Nesting deeper than this is printed as bytes rather than decoded as a message.
+const MAX_DEPTH: usize = 16;
+
+/// What `inspect` made of a datagram.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameReport {
+    /// Length of the datagram in bytes.
+    pub len: usize,
+    /// The checksum the datagram carries, if it is long enough to carry one.
+    pub checksum: Option<u32>,
+    /// The checksum its contents actually have.
+    pub computed: u32,
+    /// The frame as a receiver would decode it, its checksum aside, or why it cannot be.
+    pub frame: Result<Frame, FrameError>,
+    /// The decompressed payload, unless it is sealed, encoded by a codec or failed to decompress.
+    pub payload: Option<Vec<u8>>,
+    /// Why the payload could not be decompressed.
+    pub payload_error: Option<String>,
+    /// The fields of a protobuf payload, if it parses as one.
+    pub fields: Option<Vec<Field>>,
+    /// Whether the schema version of a protobuf payload is one `with_schema` found loaded.
+    pub schema_known: Option<bool>,
+}
+
+/// One field of a protobuf message, decoded from the wire format alone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub number: u32,
+    pub value: Value,
+}
+
+/// A protobuf field value by wire type. Length-delimited values may be strings, bytes, packed
+/// numbers or nested messages, which the wire format does not distinguish.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Varint(u64),
+    Fixed64(u64),
+    Fixed32(u32),
+    Bytes(Vec<u8>),
+}
+
+/// Decodes `bytes` as far as they go: every header field, the checksum whether or not it
+/// matches, the payload decompressed, and a protobuf payload parsed into its fields. Never fails;
+/// whatever a receiver would reject is reported instead.
+pub fn inspect(bytes: &[u8]) -> FrameReport {
+    let mut computed = checksum(bytes.first().copied().unwrap_or_default());
+    computed.update(bytes.get(CHECKSUM.end..).unwrap_or_default());
+    let computed = computed.finalize();
+    let checksum = bytes
+        .get(CHECKSUM)
+        .map(|checksum| u32::from_be_bytes(checksum.try_into().unwrap()));
+    // Decode with the checksum corrected, so a corrupted frame still shows what it claims.
+    let frame = match checksum {
+        Some(checksum) if checksum != computed && bytes.len() >= header_len(bytes[0]) => {
+            let mut corrected = bytes.to_vec();
+            corrected[CHECKSUM].copy_from_slice(&computed.to_be_bytes());
+            Frame::from_bytes(&corrected)
+        }
+        _ => Frame::from_bytes(bytes),
+    };
+
+    let mut report = FrameReport {
+        len: bytes.len(),
+        checksum,
+        computed,
+        frame,
+        payload: None,
+        payload_error: None,
+        fields: None,
+        schema_known: None,
+    };
+    let Ok(frame) = &report.frame else {
+        return report;
+    };
+    // Only the sender's codec knows what an encoded payload holds.
+    if frame.nonce.is_some() || frame.encoded {
+        return report;
+    }
+    match compression::decompress(frame.compression, &frame.payload) {
+        Ok(payload) => {
+            if frame.format == PayloadFormat::Protobuf {
+                report.fields = decode_message(&payload, 0);
+            }
+            report.payload = Some(payload);
+        }
+        Err(e) => report.payload_error = Some(e.to_string()),
+    }
+    report
+}
+
+impl FrameReport {
+    /// Checks the schema version of a protobuf payload against `registry`. Frames without one,
+    /// and every frame while the registry has no schema loaded, are left unchecked.
+    pub fn with_schema(mut self, registry: &SchemaRegistry) -> FrameReport {
+        if let Ok(frame) = &self.frame {
+            if frame.format == PayloadFormat::Protobuf
+                && frame.schema != 0
+                && registry.current().is_some()
+            {
+                self.schema_known = Some(registry.is_known(frame.schema));
+            }
+        }
+        self
+    }
+
+    pub fn checksum_ok(&self) -> bool {
+        self.checksum == Some(self.computed)
+    }
+
+    /// Why a receiver would drop the datagram, if it would.
+    pub fn error(&self) -> Option<FrameError> {
+        match &self.frame {
+            Err(e) => Some(*e),
+            Ok(_) if !self.checksum_ok() => Some(FrameError::Checksum),
+            Ok(_) => None,
+        }
+    }
+}
+
+// Parses `bytes` as a whole protobuf message, or returns `None` if they are not one. Groups,
+// deprecated since proto2, are treated as malformed.
+fn decode_message(mut bytes: &[u8], depth: usize) -> Option<Vec<Field>> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+    let mut fields = Vec::new();
+    while !bytes.is_empty() {
+        let key = varint(&mut bytes)?;
+        let number = u32::try_from(key >> 3).ok().filter(|&number| number > 0)?;
+        let value = match key & 7 {
+            0 => Value::Varint(varint(&mut bytes)?),
+            1 => Value::Fixed64(u64::from_le_bytes(take(&mut bytes, 8)?.try_into().unwrap())),
+            2 => {
+                let len = usize::try_from(varint(&mut bytes)?).ok()?;
+                Value::Bytes(take(&mut bytes, len)?.to_vec())
+            }
+            5 => Value::Fixed32(u32::from_le_bytes(take(&mut bytes, 4)?.try_into().unwrap())),
+            _ => return None,
+        };
+        fields.push(Field { number, value });
+    }
+    Some(fields)
+}
+
+fn varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for (i, &byte) in bytes.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            *bytes = &bytes[i + 1..];
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    let (taken, rest) = (bytes.get(..len)?, bytes.get(len..)?);
+    *bytes = rest;
+    Some(taken)
+}
+
+fn flag_names(flags: u8) -> String {
+    const NAMES: [&str; 8] = [
+        "RELIABLE",
+        "ACK",
+        "ENCRYPTED",
+        "PROBE",
+        "PING",
+        "IDENTIFIED",
+        "STREAM",
+        "WINDOW",
+    ];
+    let names: Vec<_> = (0..8)
+        .filter(|bit| flags & (1 << bit) != 0)
+        .map(|bit| NAMES[bit])
+        .collect();
+    match names.is_empty() {
+        true => "none".to_string(),
+        false => names.join(" | "),
+    }
+}
+
+// Printable UTF-8 is shown as a string, and anything else that parses as a message as one.
+fn write_fields(f: &mut fmt::Formatter<'_>, fields: &[Field], depth: usize) -> fmt::Result {
+    let indent = "  ".repeat(depth + 1);
+    for field in fields {
+        write!(f, "{}{}: ", indent, field.number)?;
+        match &field.value {
+            Value::Varint(value) => writeln!(f, "varint {}", value)?,
+            Value::Fixed64(value) => writeln!(f, "fixed64 {:#018x}", value)?,
+            Value::Fixed32(value) => writeln!(f, "fixed32 {:#010x}", value)?,
+            Value::Bytes(bytes) => match std::str::from_utf8(bytes) {
+                Ok(text) if !text.chars().any(char::is_control) => writeln!(f, "{:?}", text)?,
+                _ => match decode_message(bytes, depth + 1).filter(|fields| !fields.is_empty()) {
+                    Some(fields) => {
+                        writeln!(f, "message {{")?;
+                        write_fields(f, &fields, depth + 1)?;
+                        writeln!(f, "{}}}", indent)?;
+                    }
+                    None => writeln!(f, "bytes {}", hex(bytes))?,
+                },
+            },
+        }
+    }
+    Ok(())
+}
+
+/// A multi-line dump, one header field per line followed by the payload.
+impl fmt::Display for FrameReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "length: {} bytes", self.len)?;
+        match self.checksum {
+            Some(checksum) if self.checksum_ok() => writeln!(f, "checksum: {:08x} (ok)", checksum)?,
+            Some(checksum) => writeln!(
+                f,
+                "checksum: {:08x} (bad, contents have {:08x})",
+                checksum, self.computed
+            )?,
+            None => {}
+        }
+        let frame = match &self.frame {
+            Ok(frame) => frame,
+            Err(e) => return writeln!(f, "error: {}", e),
+        };
+        writeln!(f, "version: {}", frame.version)?;
+        writeln!(
+            f,
+            "flags: {:#04x} ({})",
+            frame.flags,
+            flag_names(frame.flags)
+        )?;
+        match frame.compression {
+            CompressionType::None => writeln!(f, "compression: none")?,
+            compression if compression.is_supported() => {
+                writeln!(f, "compression: {:?}", compression)?
+            }
+            compression => writeln!(f, "compression: {:?} (not in this build)", compression)?,
+        }
+        match (frame.encoded, frame.compressed_last) {
+            (true, false) => writeln!(f, "codec: encoded after compression")?,
+            (true, true) => writeln!(f, "codec: encoded before compression")?,
+            (false, _) => {}
+        }
+        write!(f, "format: {:?}", frame.format)?;
+        match (frame.schema, self.schema_known) {
+            (0, _) => writeln!(f)?,
+            (schema, Some(true)) => writeln!(f, ", schema {:08x} (known)", schema)?,
+            (schema, Some(false)) => writeln!(f, ", schema {:08x} (unknown)", schema)?,
+            (schema, None) => writeln!(f, ", schema {:08x}", schema)?,
+        }
+        writeln!(f, "priority: {:?}", frame.priority)?;
+        writeln!(f, "seq: {}", frame.seq)?;
+        writeln!(f, "msg_id: {}", frame.msg_id)?;
+        if frame.msg_type != 0 {
+            writeln!(f, "msg_type: {}", frame.msg_type)?;
+        }
+        if let Some(nonce) = &frame.nonce {
+            writeln!(f, "nonce: {}", hex(nonce))?;
+        }
+        if let Some(identity) = &frame.identity {
+            writeln!(f, "identity: {:?}", String::from_utf8_lossy(identity))?;
+        }
+        if let Some(trace) = &frame.trace {
+            writeln!(f, "trace: {}", trace)?;
+        }
+        if let Some(key_id) = frame.key_id {
+            writeln!(f, "key_id: {}", key_id)?;
+        }
+        if let Some(timestamp) = frame.timestamp {
+            writeln!(f, "timestamp: {}us", timestamp)?;
+        }
+        for entry in &frame.tlv {
+            writeln!(f, "tlv {}: {}", entry.kind, hex(&entry.value))?;
+        }
+        if frame.control {
+            writeln!(f, "control: sealed request or answer")?;
+        }
+        if frame.auth {
+            writeln!(f, "auth: token or verdict")?;
+        }
+        if frame.hello {
+            writeln!(f, "hello: node ID announcement")?;
+        }
+        if frame.report {
+            writeln!(f, "report: receiver statistics")?;
+        }
+        if let Some(e) = self.error() {
+            writeln!(f, "error: {}", e)?;
+        }
+
+        write!(f, "payload: {} bytes", frame.payload.len())?;
+        let payload = match (&self.payload, &self.payload_error) {
+            _ if frame.nonce.is_some() => return writeln!(f, ", sealed"),
+            _ if frame.encoded => return writeln!(f, ", encoded"),
+            (_, Some(e)) => return writeln!(f, ", {}", e),
+            (Some(payload), _) => payload,
+            (None, None) => return writeln!(f),
+        };
+        if frame.compression != CompressionType::None {
+            write!(f, ", {} decompressed", payload.len())?;
+        }
+        writeln!(f)?;
+        match (&self.fields, frame.format) {
+            (Some(fields), _) => write_fields(f, fields, 0),
+            (None, PayloadFormat::Protobuf) if !payload.is_empty() => {
+                writeln!(f, "  not a protobuf message: {}", hex(payload))
+            }
+            (None, PayloadFormat::Raw | PayloadFormat::Json) => {
+                match std::str::from_utf8(payload) {
+                    Ok(text) if !payload.is_empty() => writeln!(f, "  {:?}", text),
+                    _ if payload.is_empty() => Ok(()),
+                    _ => writeln!(f, "  {}", hex(payload)),
+                }
+            }
+            (None, _) if !payload.is_empty() => writeln!(f, "  {}", hex(payload)),
+            (None, _) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Priority, TraceContext};
+
+    #[test]
+    fn protobuf_payloads_are_decoded() {
+        // Field 1 varint 150, field 2 "hi", field 3 a message holding field 1 varint 1.
+        let payload = vec![0x08, 0x96, 0x01, 0x12, 2, b'h', b'i', 0x1a, 2, 0x08, 0x01];
+        let frame = Frame {
+            flags: Frame::RELIABLE,
+            format: PayloadFormat::Protobuf,
+            priority: Priority::High,
+            schema: 0xdead_beef,
+            seq: 7,
+            msg_id: 42,
+            trace: Some(TraceContext::new([0xaa; 16], [0xbb; 8], true)),
+            ..Frame::new(payload.clone())
+        };
+        let report = inspect(&frame.to_bytes());
+        assert!(report.checksum_ok());
+        assert_eq!(report.error(), None);
+        assert_eq!(report.frame.as_ref(), Ok(&frame));
+        assert_eq!(report.payload, Some(payload));
+        assert_eq!(
+            report.fields,
+            Some(vec![
+                Field {
+                    number: 1,
+                    value: Value::Varint(150)
+                },
+                Field {
+                    number: 2,
+                    value: Value::Bytes(b"hi".to_vec())
+                },
+                Field {
+                    number: 3,
+                    value: Value::Bytes(vec![0x08, 0x01])
+                },
+            ])
+        );
+
+        let dump = report.to_string();
+        assert!(dump.contains("flags: 0x01 (RELIABLE)"), "{}", dump);
+        assert!(dump.contains("schema deadbeef\n"), "{}", dump);
+        assert!(dump.contains("trace: 00-aaaa"), "{}", dump);
+        assert!(
+            dump.contains("  1: varint 150\n  2: \"hi\"\n  3: message {\n    1: varint 1\n  }\n"),
+            "{}",
+            dump
+        );
+    }
+
+    #[test]
+    fn corrupted_frames_still_show_their_header() {
+        let mut bytes = Frame::ping(3).to_bytes();
+        bytes[1] ^= 0xff;
+        let report = inspect(&bytes);
+        assert!(!report.checksum_ok());
+        assert_eq!(report.error(), Some(FrameError::Checksum));
+        assert_eq!(report.frame.map(|frame| frame.seq), Ok(3));
+
+        let report = inspect(&bytes[..10]);
+        assert_eq!(report.error(), Some(FrameError::Truncated));
+        assert!(report
+            .to_string()
+            .contains("error: Frame shorter than its header"));
+        assert_eq!(inspect(&[]).checksum, None);
+    }
+
+    #[test]
+    fn schema_versions_are_checked_against_the_registry() {
+        use crate::util::config::Config;
+        use std::{env, fs};
+
+        let path = env::temp_dir().join(format!("crumb-inspect-{}.proto", std::process::id()));
+        fs::write(&path, "message A {}").unwrap();
+        let registry = SchemaRegistry::new(&Config {
+            proto_path: path.to_string_lossy().into_owned(),
+            ..Config::default()
+        });
+        fs::remove_file(&path).unwrap();
+        let version = registry.current().unwrap();
+
+        for (schema, known) in [(version, Some(true)), (version ^ 1, Some(false)), (0, None)] {
+            let frame = Frame {
+                format: PayloadFormat::Protobuf,
+                schema,
+                ..Frame::new(vec![0x08, 0x01])
+            };
+            let report = inspect(&frame.to_bytes()).with_schema(&registry);
+            assert_eq!(report.schema_known, known);
+        }
+    }
+
+    #[test]
+    fn malformed_protobuf_is_not_decoded() {
+        for payload in [&[0x08][..], &[0x0b, 0x00], &[0x00, 0x00], &[0x12, 5, 1]] {
+            assert_eq!(decode_message(payload, 0), None, "{:?}", payload);
+        }
+        assert_eq!(decode_message(&[], 0), Some(Vec::new()));
+    }
+}
@@ -0,0 +1,1097 @@
+//! The on-wire frame, specified here so that implementations in other languages can
+//! interoperate with crumb sessions.
+//!
+//! Every datagram carries exactly one frame. Integers are big endian:
+//!
+//! | Offset | Size | Field          | Meaning                                                                                            |
+//! |--------|------|----------------|----------------------------------------------------------------------------------------------------|
+//! | 0      | 1    | `version`      | Always 14 for this layout.                                                                         |
+//! | 1      | 4    | `checksum`     | CRC-32 (IEEE, as in zlib) of `version` followed by every byte from offset 5 on.                    |
+//! | 5      | 1    | `flags`        | Bits 0 to 7: `RELIABLE`, `ACK`, `ENCRYPTED`, `PROBE`, `PING`, `IDENTIFIED`, `STREAM`, `WINDOW`.    |
+//! | 6      | 1    | `compression`  | Bits 0 to 5: 0 none, 1 zstd, 2 gzip. Bits 6 and 7: `ENCODED`, `COMPRESSED_LAST`.                   |
+//! | 7      | 1    | `format`       | 0 raw, 1 protobuf, 2 JSON, 3 MessagePack.                                                          |
+//! | 8      | 1    | `priority`     | 0 control, 1 high, 2 normal, 3 bulk.                                                               |
+//! | 9      | 4    | `schema`       | Hash of the sender's schema for protobuf payloads, or 0.                                           |
+//! | 13     | 4    | `seq`          | Sequence number of a reliable or acknowledgement frame.                                            |
+//! | 17     | 8    | `msg_id`       | Sender-assigned message identifier.                                                                |
+//! | 25     | 1    | `extensions`   | Bits 0 to 7: `TRACE`, `CONTROL`, `AUTH`, `KEY_ID`, `HELLO`, `REPORT`, `TIMESTAMP`, `TLV`.          |
+//! | 26     | 2    | `msg_type`     | The kind of message the application sent, or 0.                                                    |
+//! | 28     | 24   | `nonce`        | Only in `ENCRYPTED` frames: the XChaCha20-Poly1305 nonce.                                          |
+//! | 52     | 1    | `identity_len` | Only in `IDENTIFIED` encrypted frames: the length of `identity`.                                   |
+//! | 53     | -    | `identity`     | Only in `IDENTIFIED` encrypted frames: the key's identity.                                         |
+//! | -      | 26   | `trace`        | Only with `TRACE`: the W3C trace context the message was sent in.                                  |
+//! | -      | 4    | `key_id`       | Only with `KEY_ID`: the id of the pre-shared key the frame is sealed with.                         |
+//! | -      | 4    | `timestamp`    | Only with `TIMESTAMP`: when the message was sent, in microseconds of the sender's wall clock.      |
+//! | -      | 1    | `tlv_len`      | Only with `TLV`: the length of `tlv`, at least 2.                                                  |
+//! | -      | -    | `tlv`          | Only with `TLV`: type-length-value entries, as described in `Tlv`.                                 |
+//! | -      | -    | `payload`      | The rest of the datagram, compressed and encoded per `compression`.                                |
+//!
+//! A `RELIABLE` frame is retransmitted until the receiver answers with an `ACK` frame carrying
+//! the same `seq`; `seq` is zero in frames that are neither. Receivers drop frames of versions
+//! they do not speak or with a bad checksum. `format` describes the payload once decompressed, and
+//! `schema` is the CRC-32 of the `.proto` file a protobuf payload was encoded with, so receivers
+//! can tell which version of the schema produced it. `priority` is the lane the sender queued
+//! the message in, most urgent first; frames the session layer makes itself are `control`.
+//! `msg_type` is the application's own numbering of the kinds of message it sends, so that
+//! receivers can hand each kind to its own handler; crumb assigns no types, and 0 means none
+//! was given.
+//!
+//! A `PROBE` frame tests whether datagrams of its size cross the path. Its payload is padding
+//! and is never delivered; the receiver answers with an `ACK | PROBE` frame whose `seq` is the
+//! size of the probe datagram in bytes.
+//!
+//! A `PING` frame asks the receiver to answer at once with an `ACK | PING` frame, the pong,
+//! carrying the same `seq`, so the sender can time a round trip through the receiver's whole
+//! stack. Neither is delivered. A ping may carry its sender's wall clock reading, 8 bytes of
+//! microseconds since the Unix epoch. Its pong then carries that reading back, followed by the
+//! receiver's readings as the ping arrived and as the pong left, so the sender can estimate the
+//! offset between the clocks as NTP does. Otherwise both have empty payloads.
+//!
+//! `compression` also says what else was done to the payload, so that a receiver can undo it in
+//! reverse. `ENCODED` marks a payload that passed through the sender's `session::FrameCodec`,
+//! which the receiver's must undo. Compression comes first unless `COMPRESSED_LAST` is set, in
+//! which case the payload was compressed after it was encoded. Receivers without a codec drop
+//! encoded messages.
+//!
+//! Version 13 is this layout without `TLV`, and version 12 is version 13 without `msg_type`, so
+//! its header is 26 bytes and the nonce follows at offset 26. The checksums of both leave out
+//! `version`, so a corrupted version byte can pass for another. Receivers read all three, and
+//! senders write the older versions to peers that have sent them nothing newer, leaving out
+//! what those have no room for, as `session` negotiates.
+//!
+//! The payload of an `ENCRYPTED` frame is the compressed payload sealed with
+//! XChaCha20-Poly1305 under a pre-shared key, followed by the 16 byte tag. The associated data
+//! is the header from offset 5 up to the payload, nonce included, so the header cannot be
+//! altered either. An `IDENTIFIED` frame also names the key it is sealed with, so a receiver
+//! holding many keys, one per peer, can tell which to open it with. The bit is ignored in frames
+//! that are not `ENCRYPTED`.
+//!
+//! The payload of an `ACK` frame is empty, or the receiver's window: 4 bytes counting the
+//! further messages it will take before its application catches up. A sender told a window
+//! must not have more unacknowledged `RELIABLE` frames outstanding than the last one it was
+//! told. A `WINDOW` frame with a 4 byte payload announces the window by itself, as a receiver
+//! does once its application makes room in a window it had announced as 0. One with an empty
+//! payload asks the receiver for its window, and is answered with the former; receivers that
+//! keep no window answer 4294967295. Neither is delivered.
+//!
+//! `extensions` says which optional sections follow the nonce and identity, in bit order. The
+//! `TRACE` section is the binary form of a W3C `traceparent`: the version (1 byte), trace id
+//! (16), parent span id (8) and flags (1). Receivers drop frames with extension bits they do not
+//! know, since they cannot tell where the payload starts. Encrypted frames authenticate the
+//! extensions along with the rest of the header.
+//!
+//! The `KEY_ID` section numbers the pre-shared key an `ENCRYPTED` frame is sealed with, so that
+//! receivers holding several keys while a fleet rotates to a new one can tell which opens it.
+//! Later keys have larger ids.
+//!
+//! The `TLV` section is the open-ended end of the header: entries of a type (1 byte), the length
+//! of the value (1 byte) and the value, filling `tlv_len` bytes exactly. Receivers skip the
+//! entries of types they do not know, so new metadata goes there rather than in new extension
+//! bits, which would have older receivers drop the frame.
+//!
+//! The `TIMESTAMP` section stamps a message with the time it was sent, in microseconds since the
+//! Unix epoch by the sender's wall clock, wrapping at 2^32. Receivers compare stamps from the
+//! same sender with each other, to play messages out at the pace they were sent and to measure
+//! how their transit time varies, which needs no agreement between the clocks. Receivers whose
+//! clock is synchronised with the sender's also read the one-way delay off them.
+//!
+//! `CONTROL` adds no section. It marks a message carrying an operator's control request, or the
+//! answer to one, sealed as `session::Client::control` describes, rather than a message for the
+//! application. Receivers that do not know it drop it like any unknown extension. `AUTH`
+//! likewise adds no section, and marks a client's token, or the server's verdict on it, in the
+//! handshake `session::Client` opens with when `auth_token` is set. `HELLO` adds no section
+//! either, and marks the message a session opens with to tell the peer its node ID. Nor does
+//! `REPORT`, which marks a receiver's periodic account of the messages it got from its peer.
+//!
+//! A `STREAM` frame carries a chunk of a blob too large for one message, laid out as described
+//! in `crate::stream`, rather than a message for the application.
+//!
+//! `inspect` takes a datagram apart field by field, for debugging implementations that disagree
+//! about what a frame means.
+
+mod inspect;
+mod tlv;
+mod trace;
+
+use crate::util::config::{CompressionType, PayloadFormat};
+pub use inspect::{inspect, Field, FrameReport, Value};
+use std::fmt;
+use std::io::{self, IoSlice};
+pub use tlv::{Tlv, FIRST_APPLICATION_TLV};
+pub use trace::TraceContext;
+
+/// The frame layout version this build speaks.
+pub const VERSION: u8 = 14;
+
+/// The oldest frame layout version this build reads, and writes to peers that speak no newer.
+pub const MIN_VERSION: u8 = 12;
+
+/// Length of the fixed header preceding the payload, or the nonce in encrypted frames.
+pub const HEADER_LEN: usize = 28;
+
+/// Length of the nonce in encrypted frames.
+pub const NONCE_LEN: usize = 24;
+
+/// Longest key identity an encrypted frame can carry.
+pub const MAX_IDENTITY_LEN: usize = 255;
+
+/// Length of the trace context in frames that carry one.
+pub const TRACE_LEN: usize = 26;
+
+/// Length of the key id in frames that carry one.
+pub const KEY_ID_LEN: usize = 4;
+
+/// Length of the timestamp in frames that carry one.
+pub const TIMESTAMP_LEN: usize = 4;
+
+/// Longest TLV extension area a frame can carry, its length aside.
+pub const MAX_TLV_LEN: usize = 255;
+
+// Longest header a frame can have: encrypted, with an identity and every extension.
+const MAX_HEADER_LEN: usize = HEADER_LEN
+    + NONCE_LEN
+    + 1
+    + MAX_IDENTITY_LEN
+    + TRACE_LEN
+    + KEY_ID_LEN
+    + TIMESTAMP_LEN
+    + 1
+    + MAX_TLV_LEN;
+
+// The `extensions` bit of the trace context section.
+const TRACE: u8 = 0x01;
+
+// The `extensions` bit marking control messages.
+const CONTROL: u8 = 0x02;
+
+// The `extensions` bit marking authentication messages.
+const AUTH: u8 = 0x04;
+
+// The `extensions` bit marking node ID announcements.
+const HELLO: u8 = 0x10;
+
+// The `extensions` bit marking receiver reports.
+const REPORT: u8 = 0x20;
+
+// The `extensions` bit of the key id section.
+const KEY_ID: u8 = 0x08;
+
+// The `extensions` bit of the timestamp section.
+const TIMESTAMP: u8 = 0x40;
+
+// The `extensions` bit of the TLV extension area.
+const TLV: u8 = 0x80;
+
+// The `compression` bit of payloads a codec encoded.
+const ENCODED: u8 = 0x40;
+
+// The `compression` bit of payloads compressed after they were encoded.
+const COMPRESSED_LAST: u8 = 0x80;
+
+const CHECKSUM: std::ops::Range<usize> = 1..5;
+
+// The checksum of a frame of `version`, to be fed every byte from offset 5 on. From version 14
+// it covers the version too, so a frame whose version is corrupted is not read in another
+// layout.
+fn checksum(version: u8) -> crc32fast::Hasher {
+    let mut checksum = crc32fast::Hasher::new();
+    if version >= 14 {
+        checksum.update(&[version]);
+    }
+    checksum
+}
+
+// Length of the fixed header of frames of `version`. Version 12 has no `msg_type`.
+fn header_len(version: u8) -> usize {
+    match version {
+        12 => HEADER_LEN - 2,
+        _ => HEADER_LEN,
+    }
+}
+
+/// The send lane a message is queued in. Senders drain more urgent lanes first, so a message
+/// is only ever held behind others of its own or a more urgent priority.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Priority {
+    /// Acknowledgements, pings and the like, and application messages that steer a session.
+    Control,
+    High,
+    #[default]
+    Normal,
+    /// Telemetry and other traffic that can wait.
+    Bulk,
+}
+
+impl Priority {
+    /// Every priority, most urgent first.
+    pub const ALL: [Priority; 4] = [
+        Priority::Control,
+        Priority::High,
+        Priority::Normal,
+        Priority::Bulk,
+    ];
+
+    /// The value naming the priority in a frame header, and its lane's index.
+    pub(crate) fn tag(self) -> u8 {
+        self as u8
+    }
+
+    pub(crate) fn from_tag(tag: u8) -> Option<Priority> {
+        Priority::ALL.get(tag as usize).copied()
+    }
+}
+
+/// One datagram's worth of the crumb protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Frame {
+    pub version: u8,
+    pub flags: u8,
+    pub compression: CompressionType,
+    pub format: PayloadFormat,
+    pub priority: Priority,
+    pub schema: u32,
+    pub seq: u32,
+    pub msg_id: u64,
+    /// The kind of message the application sent, 0 if it gave none.
+    pub msg_type: u16,
+    /// Present exactly when the frame is `ENCRYPTED`; `to_bytes` sets that flag from it.
+    pub nonce: Option<[u8; NONCE_LEN]>,
+    /// The identity of the key an encrypted frame is sealed with, if it names one. Only encoded
+    /// alongside a nonce, where `to_bytes` sets `IDENTIFIED` from it, and cut to
+    /// `MAX_IDENTITY_LEN` bytes.
+    pub identity: Option<Vec<u8>>,
+    /// The trace the message belongs to, carried in the `TRACE` extension when present.
+    pub trace: Option<TraceContext>,
+    /// The id of the pre-shared key an encrypted frame is sealed with, carried in the `KEY_ID`
+    /// extension when present.
+    pub key_id: Option<u32>,
+    /// When the message was sent, in microseconds of the sender's wall clock, carried in the
+    /// `TIMESTAMP` extension when present.
+    pub timestamp: Option<u32>,
+    /// Whether the message is a control request or answer, carried as the `CONTROL` extension.
+    pub control: bool,
+    /// Whether the message belongs to the authentication handshake, carried as the `AUTH`
+    /// extension.
+    pub auth: bool,
+    /// Whether the message announces the sender's node ID, carried as the `HELLO` extension.
+    pub hello: bool,
+    /// Whether the message is a receiver report, carried as the `REPORT` extension.
+    pub report: bool,
+    /// Whether the payload passed through the sender's codec, carried as the `ENCODED` bit of
+    /// `compression`.
+    pub encoded: bool,
+    /// Whether the payload was compressed after the codec encoded it rather than before,
+    /// carried as the `COMPRESSED_LAST` bit of `compression`.
+    pub compressed_last: bool,
+    /// The entries of the `TLV` extension area. Only frames of version 14 carry them, in at
+    /// most `MAX_TLV_LEN` bytes, each taking two more than its value. `encoded_len` fails for
+    /// frames with more, which sessions refuse to send, and `to_bytes` leaves out the entries
+    /// that do not fit.
+    pub tlv: Vec<Tlv>,
+    pub payload: Vec<u8>,
+}
+
+/// Why bytes could not be read as a `Frame`, or a `Frame` written as bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// Shorter than the fixed header.
+    Truncated,
+    /// Sent by a peer speaking another protocol version.
+    Version(u8),
+    /// Corrupted in transit.
+    Checksum,
+    /// The header names a compression algorithm this version does not define.
+    Compression(u8),
+    /// The header names a payload format this version does not define.
+    Format(u8),
+    /// The header names a priority this version does not define.
+    Priority(u8),
+    /// The header names extensions this version does not define.
+    Extensions(u8),
+    /// The TLV extension area is empty or ends partway through an entry.
+    Tlv,
+    /// The TLV entries of a frame to be sent take more than `MAX_TLV_LEN` bytes.
+    TlvTooLong(usize),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Truncated => write!(f, "Frame shorter than its header"),
+            FrameError::Version(version) => write!(f, "Unsupported frame version: {}", version),
+            FrameError::Checksum => write!(f, "Frame checksum mismatch"),
+            FrameError::Compression(tag) => write!(f, "Unknown compression tag: {}", tag),
+            FrameError::Format(tag) => write!(f, "Unknown payload format tag: {}", tag),
+            FrameError::Priority(tag) => write!(f, "Unknown priority tag: {}", tag),
+            FrameError::Extensions(bits) => write!(f, "Unknown header extensions: {:#04x}", bits),
+            FrameError::Tlv => write!(f, "Malformed TLV extension area"),
+            FrameError::TlvTooLong(len) => write!(
+                f,
+                "TLV entries take {} bytes, more than the {} that fit",
+                len, MAX_TLV_LEN
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl Frame {
+    /// Set on frames the receiver must acknowledge.
+    pub const RELIABLE: u8 = 0x01;
+    /// Set on acknowledgements of the reliable frame with the same `seq`.
+    pub const ACK: u8 = 0x02;
+    /// Set on frames sealed with a pre-shared key, which carry a nonce.
+    pub const ENCRYPTED: u8 = 0x04;
+    /// Set on path MTU probes and their acknowledgements.
+    pub const PROBE: u8 = 0x08;
+    /// Set on pings and their pongs.
+    pub const PING: u8 = 0x10;
+    /// Set on encrypted frames that carry the identity of their key.
+    pub const IDENTIFIED: u8 = 0x20;
+    /// Set on messages carrying a chunk of a stream, as laid out in `crate::stream`.
+    pub const STREAM: u8 = 0x40;
+    /// Set on receive window announcements and the queries asking for one.
+    pub const WINDOW: u8 = 0x80;
+
+    /// An uncompressed, unreliable frame of the newest version carrying raw `payload`.
+    pub fn new(payload: Vec<u8>) -> Frame {
+        Frame {
+            version: VERSION,
+            flags: 0,
+            compression: CompressionType::None,
+            format: PayloadFormat::Raw,
+            priority: Priority::Normal,
+            schema: 0,
+            seq: 0,
+            msg_id: 0,
+            msg_type: 0,
+            nonce: None,
+            identity: None,
+            trace: None,
+            key_id: None,
+            timestamp: None,
+            control: false,
+            auth: false,
+            hello: false,
+            report: false,
+            encoded: false,
+            compressed_last: false,
+            tlv: Vec::new(),
+            payload,
+        }
+    }
+
+    /// The acknowledgement of reliable frame `seq`.
+    pub fn ack(seq: u32) -> Frame {
+        Frame {
+            flags: Frame::ACK,
+            seq,
+            priority: Priority::Control,
+            ..Frame::new(Vec::new())
+        }
+    }
+
+    /// A path MTU probe whose payload pads it to `len` bytes once encoded with `overhead`
+    /// bytes of header, nonce and tag.
+    pub fn probe(len: usize, overhead: usize) -> Frame {
+        Frame {
+            flags: Frame::PROBE,
+            priority: Priority::Control,
+            ..Frame::new(vec![0; len.saturating_sub(overhead)])
+        }
+    }
+
+    /// The acknowledgement of a probe datagram of `len` bytes.
+    pub fn probe_ack(len: usize) -> Frame {
+        Frame {
+            flags: Frame::ACK | Frame::PROBE,
+            seq: len as u32,
+            priority: Priority::Control,
+            ..Frame::new(Vec::new())
+        }
+    }
+
+    /// Ping number `id`.
+    pub fn ping(id: u32) -> Frame {
+        Frame {
+            flags: Frame::PING,
+            seq: id,
+            priority: Priority::Control,
+            ..Frame::new(Vec::new())
+        }
+    }
+
+    /// The answer to ping `id`.
+    pub fn pong(id: u32) -> Frame {
+        Frame {
+            flags: Frame::ACK | Frame::PING,
+            seq: id,
+            priority: Priority::Control,
+            ..Frame::new(Vec::new())
+        }
+    }
+
+    /// An announcement that the receiver will take `credit` more messages.
+    pub fn window(credit: u32) -> Frame {
+        Frame {
+            flags: Frame::WINDOW,
+            priority: Priority::Control,
+            ..Frame::new(credit.to_be_bytes().to_vec())
+        }
+    }
+
+    /// A query asking the receiver to announce its window.
+    pub fn window_query() -> Frame {
+        Frame {
+            flags: Frame::WINDOW,
+            priority: Priority::Control,
+            ..Frame::new(Vec::new())
+        }
+    }
+
+    /// The receive window an acknowledgement or window announcement carries, if any.
+    pub fn credit(&self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.payload.as_slice().try_into().ok()?))
+    }
+
+    pub fn is_reliable(&self) -> bool {
+        self.flags & Frame::RELIABLE != 0
+    }
+
+    pub fn is_ack(&self) -> bool {
+        self.flags & Frame::ACK != 0
+    }
+
+    pub fn is_probe(&self) -> bool {
+        self.flags & Frame::PROBE != 0
+    }
+
+    pub fn is_ping(&self) -> bool {
+        self.flags & Frame::PING != 0
+    }
+
+    pub fn is_window(&self) -> bool {
+        self.flags & Frame::WINDOW != 0
+    }
+
+    /// The value of the first TLV entry of type `kind`, if the frame carries one.
+    pub fn find_tlv(&self, kind: u8) -> Option<&[u8]> {
+        self.tlv
+            .iter()
+            .find(|entry| entry.kind == kind)
+            .map(|entry| entry.value.as_slice())
+    }
+
+    /// The length of the frame as `to_bytes` encodes it. Fails if its TLV entries do not all
+    /// fit.
+    pub fn encoded_len(&self) -> Result<usize, FrameError> {
+        tlv::area_len(&self.tlv)?;
+        Ok(self.header().1 + self.payload.len())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (header, len) = self.header();
+        let mut bytes = Vec::with_capacity(len + self.payload.len());
+        bytes.extend_from_slice(&header[..len]);
+        bytes.extend_from_slice(&self.payload);
+        let mut checksum = checksum(self.version);
+        checksum.update(&bytes[CHECKSUM.end..]);
+        bytes[CHECKSUM].copy_from_slice(&checksum.finalize().to_be_bytes());
+        bytes
+    }
+
+    /// Encodes the frame as two slices, the header and the payload, and hands them to `write`
+    /// for a vectored send such as `Transport::send_vectored`, so the payload is never copied
+    /// into a contiguous buffer. The bytes sent are those of `to_bytes`.
+    pub fn write_vectored<T, F>(&self, write: F) -> io::Result<T>
+    where
+        F: FnOnce(&[IoSlice<'_>]) -> io::Result<T>,
+    {
+        let (mut header, len) = self.header();
+        let mut checksum = checksum(self.version);
+        checksum.update(&header[CHECKSUM.end..len]);
+        checksum.update(&self.payload);
+        header[CHECKSUM].copy_from_slice(&checksum.finalize().to_be_bytes());
+        write(&[IoSlice::new(&header[..len]), IoSlice::new(&self.payload)])
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Frame, FrameError> {
+        let version = match bytes.first() {
+            Some(&version) if (MIN_VERSION..=VERSION).contains(&version) => version,
+            Some(&version) => return Err(FrameError::Version(version)),
+            None => return Err(FrameError::Truncated),
+        };
+        let header_len = header_len(version);
+        if bytes.len() < header_len {
+            return Err(FrameError::Truncated);
+        }
+        let mut checksum = checksum(version);
+        checksum.update(&bytes[CHECKSUM.end..]);
+        if checksum.finalize().to_be_bytes() != bytes[CHECKSUM] {
+            return Err(FrameError::Checksum);
+        }
+
+        let tag = bytes[6] & !(ENCODED | COMPRESSED_LAST);
+        let compression = CompressionType::from_tag(tag).ok_or(FrameError::Compression(tag))?;
+        let format = PayloadFormat::from_tag(bytes[7]).ok_or(FrameError::Format(bytes[7]))?;
+        let priority = Priority::from_tag(bytes[8]).ok_or(FrameError::Priority(bytes[8]))?;
+        let flags = bytes[5];
+        let extensions = bytes[25];
+        let mut known = TRACE | CONTROL | AUTH | KEY_ID | HELLO | REPORT | TIMESTAMP;
+        if version >= 14 {
+            known |= TLV;
+        }
+        if extensions & !known != 0 {
+            return Err(FrameError::Extensions(extensions));
+        }
+        let (nonce, identity, rest) = match flags & Frame::ENCRYPTED {
+            0 => (None, None, &bytes[header_len..]),
+            _ => {
+                let nonce = bytes
+                    .get(header_len..header_len + NONCE_LEN)
+                    .ok_or(FrameError::Truncated)?;
+                let rest = &bytes[header_len + NONCE_LEN..];
+                let (identity, payload) = match flags & Frame::IDENTIFIED {
+                    0 => (None, rest),
+                    _ => {
+                        let (&len, rest) = rest.split_first().ok_or(FrameError::Truncated)?;
+                        let identity = rest.get(..len as usize).ok_or(FrameError::Truncated)?;
+                        (Some(identity.to_vec()), &rest[len as usize..])
+                    }
+                };
+                (Some(nonce.try_into().unwrap()), identity, payload)
+            }
+        };
+        let (trace, payload) = match extensions & TRACE {
+            0 => (None, rest),
+            _ => {
+                let trace = rest.get(..TRACE_LEN).ok_or(FrameError::Truncated)?;
+                let trace = TraceContext::from_bytes(trace.try_into().unwrap());
+                (Some(trace), &rest[TRACE_LEN..])
+            }
+        };
+        let (key_id, payload) = match extensions & KEY_ID {
+            0 => (None, payload),
+            _ => {
+                let key_id = payload.get(..KEY_ID_LEN).ok_or(FrameError::Truncated)?;
+                let key_id = u32::from_be_bytes(key_id.try_into().unwrap());
+                (Some(key_id), &payload[KEY_ID_LEN..])
+            }
+        };
+        let (timestamp, payload) = match extensions & TIMESTAMP {
+            0 => (None, payload),
+            _ => {
+                let timestamp = payload.get(..TIMESTAMP_LEN).ok_or(FrameError::Truncated)?;
+                let timestamp = u32::from_be_bytes(timestamp.try_into().unwrap());
+                (Some(timestamp), &payload[TIMESTAMP_LEN..])
+            }
+        };
+        let (tlv, payload) = match extensions & TLV {
+            0 => (Vec::new(), payload),
+            _ => {
+                let (&len, rest) = payload.split_first().ok_or(FrameError::Truncated)?;
+                let area = rest.get(..len as usize).ok_or(FrameError::Truncated)?;
+                (tlv::decode(area)?, &rest[len as usize..])
+            }
+        };
+        let msg_type = match version {
+            12 => 0,
+            _ => u16::from_be_bytes(bytes[26..28].try_into().unwrap()),
+        };
+        Ok(Frame {
+            version,
+            flags,
+            compression,
+            format,
+            priority,
+            schema: u32::from_be_bytes(bytes[9..13].try_into().unwrap()),
+            seq: u32::from_be_bytes(bytes[13..17].try_into().unwrap()),
+            msg_id: u64::from_be_bytes(bytes[17..25].try_into().unwrap()),
+            msg_type,
+            nonce,
+            identity,
+            trace,
+            key_id,
+            timestamp,
+            control: extensions & CONTROL != 0,
+            auth: extensions & AUTH != 0,
+            hello: extensions & HELLO != 0,
+            report: extensions & REPORT != 0,
+            encoded: bytes[6] & ENCODED != 0,
+            compressed_last: bytes[6] & COMPRESSED_LAST != 0,
+            tlv,
+            payload: payload.to_vec(),
+        })
+    }
+
+    /// The bytes an encrypted frame's payload is authenticated with: the header after the
+    /// checksum, nonce, identity and extensions included.
+    pub fn associated_data(&self) -> Vec<u8> {
+        let (header, len) = self.header();
+        header[CHECKSUM.end..len].to_vec()
+    }
+
+    // Everything before the payload, with the checksum left zero, and its length.
+    fn header(&self) -> ([u8; MAX_HEADER_LEN], usize) {
+        let mut header = [0; MAX_HEADER_LEN];
+        header[0] = self.version;
+        header[5] = match (self.nonce, &self.identity) {
+            (Some(_), Some(_)) => self.flags | Frame::ENCRYPTED | Frame::IDENTIFIED,
+            (Some(_), None) => (self.flags | Frame::ENCRYPTED) & !Frame::IDENTIFIED,
+            (None, _) => self.flags & !Frame::ENCRYPTED,
+        };
+        header[6] = self.compression.tag();
+        if self.encoded {
+            header[6] |= ENCODED;
+        }
+        if self.compressed_last {
+            header[6] |= COMPRESSED_LAST;
+        }
+        header[7] = self.format.tag();
+        header[8] = self.priority.tag();
+        header[9..13].copy_from_slice(&self.schema.to_be_bytes());
+        header[13..17].copy_from_slice(&self.seq.to_be_bytes());
+        header[17..25].copy_from_slice(&self.msg_id.to_be_bytes());
+        if self.version != 12 {
+            header[26..28].copy_from_slice(&self.msg_type.to_be_bytes());
+        }
+        if self.trace.is_some() {
+            header[25] |= TRACE;
+        }
+        if self.control {
+            header[25] |= CONTROL;
+        }
+        if self.auth {
+            header[25] |= AUTH;
+        }
+        if self.hello {
+            header[25] |= HELLO;
+        }
+        if self.report {
+            header[25] |= REPORT;
+        }
+        if self.key_id.is_some() {
+            header[25] |= KEY_ID;
+        }
+        if self.timestamp.is_some() {
+            header[25] |= TIMESTAMP;
+        }
+        let mut len = header_len(self.version);
+        if let Some(nonce) = &self.nonce {
+            header[len..len + NONCE_LEN].copy_from_slice(nonce);
+            len += NONCE_LEN;
+            if let Some(identity) = &self.identity {
+                let identity = &identity[..identity.len().min(MAX_IDENTITY_LEN)];
+                header[len] = identity.len() as u8;
+                header[len + 1..len + 1 + identity.len()].copy_from_slice(identity);
+                len += 1 + identity.len();
+            }
+        }
+        if let Some(trace) = self.trace {
+            header[len..len + TRACE_LEN].copy_from_slice(&trace.to_bytes());
+            len += TRACE_LEN;
+        }
+        if let Some(key_id) = self.key_id {
+            header[len..len + KEY_ID_LEN].copy_from_slice(&key_id.to_be_bytes());
+            len += KEY_ID_LEN;
+        }
+        if let Some(timestamp) = self.timestamp {
+            header[len..len + TIMESTAMP_LEN].copy_from_slice(&timestamp.to_be_bytes());
+            len += TIMESTAMP_LEN;
+        }
+        if self.version >= 14 {
+            let area = tlv::encode(&self.tlv, &mut header[len + 1..len + 1 + MAX_TLV_LEN]);
+            if area > 0 {
+                header[25] |= TLV;
+                header[len] = area as u8;
+                len += 1 + area;
+            }
+        }
+        (header, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn compression() -> impl Strategy<Value = CompressionType> {
+        prop_oneof![
+            Just(CompressionType::None),
+            Just(CompressionType::Zstd),
+            Just(CompressionType::Gzip),
+        ]
+    }
+
+    fn format() -> impl Strategy<Value = PayloadFormat> {
+        prop_oneof![
+            Just(PayloadFormat::Raw),
+            Just(PayloadFormat::Protobuf),
+            Just(PayloadFormat::Json),
+            Just(PayloadFormat::Msgpack),
+        ]
+    }
+
+    fn priority() -> impl Strategy<Value = Priority> {
+        proptest::sample::select(Priority::ALL.to_vec())
+    }
+
+    fn frame() -> impl Strategy<Value = Frame> {
+        (
+            any::<u8>(),
+            compression(),
+            format(),
+            priority(),
+            any::<u32>(),
+            any::<u32>(),
+            any::<(u64, u16)>(),
+            any::<Option<[u8; NONCE_LEN]>>(),
+            proptest::option::of(proptest::collection::vec(any::<u8>(), 0..=MAX_IDENTITY_LEN)),
+            any::<Option<[u8; TRACE_LEN]>>(),
+            any::<(
+                Option<u32>,
+                Option<u32>,
+                (bool, bool, bool, bool),
+                (bool, bool),
+            )>(),
+            (
+                proptest::collection::vec(
+                    (any::<u8>(), proptest::collection::vec(any::<u8>(), 0..48)),
+                    0..4,
+                ),
+                proptest::collection::vec(any::<u8>(), 0..256),
+            ),
+        )
+            .prop_map(
+                |(
+                    flags,
+                    compression,
+                    format,
+                    priority,
+                    schema,
+                    seq,
+                    (msg_id, msg_type),
+                    nonce,
+                    identity,
+                    trace,
+                    (key_id, timestamp, (control, auth, hello, report), (encoded, compressed_last)),
+                    (tlv, payload),
+                )| {
+                    let identity = nonce.and(identity);
+                    Frame {
+                        version: VERSION,
+                        flags: match (nonce, &identity) {
+                            (Some(_), Some(_)) => flags | Frame::ENCRYPTED | Frame::IDENTIFIED,
+                            (Some(_), None) => (flags | Frame::ENCRYPTED) & !Frame::IDENTIFIED,
+                            (None, _) => flags & !Frame::ENCRYPTED,
+                        },
+                        compression,
+                        format,
+                        priority,
+                        schema,
+                        seq,
+                        msg_id,
+                        msg_type,
+                        nonce,
+                        identity,
+                        trace: trace.as_ref().map(TraceContext::from_bytes),
+                        key_id,
+                        timestamp,
+                        control,
+                        auth,
+                        hello,
+                        report,
+                        encoded,
+                        compressed_last,
+                        tlv: tlv
+                            .into_iter()
+                            .map(|(kind, value)| Tlv::new(kind, value))
+                            .collect(),
+                        payload,
+                    }
+                },
+            )
+    }
+
+    // Arbitrary bytes that pass the version and checksum checks, to reach the fields behind them.
+    fn checksummed() -> impl Strategy<Value = Vec<u8>> {
+        proptest::collection::vec(any::<u8>(), HEADER_LEN..HEADER_LEN + NONCE_LEN + 96).prop_map(
+            |mut bytes| {
+                bytes[0] = VERSION;
+                let mut checksum = checksum(VERSION);
+                checksum.update(&bytes[CHECKSUM.end..]);
+                bytes[CHECKSUM].copy_from_slice(&checksum.finalize().to_be_bytes());
+                bytes
+            },
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(frame in frame()) {
+            let bytes = frame.to_bytes();
+            let nonce_len = frame.nonce.map_or(0, |nonce| nonce.len());
+            let identity_len = frame.identity.as_ref().map_or(0, |identity| 1 + identity.len());
+            let trace_len = frame.trace.map_or(0, |_| TRACE_LEN);
+            let key_id_len = frame.key_id.map_or(0, |_| KEY_ID_LEN);
+            let timestamp_len = frame.timestamp.map_or(0, |_| TIMESTAMP_LEN);
+            let tlv_len: usize = frame.tlv.iter().map(|entry| 2 + entry.value.len()).sum();
+            let tlv_len = if tlv_len > 0 { 1 + tlv_len } else { 0 };
+            prop_assert_eq!(
+                bytes.len(),
+                HEADER_LEN + nonce_len + identity_len + trace_len + key_id_len + timestamp_len
+                    + tlv_len + frame.payload.len()
+            );
+            prop_assert_eq!(Frame::from_bytes(&bytes), Ok(frame));
+        }
+
+        #[test]
+        fn vectored_encoding_matches(frame in frame()) {
+            let vectored = frame
+                .write_vectored(|slices| Ok(slices.iter().flat_map(|s| s.to_vec()).collect::<Vec<_>>()))
+                .unwrap();
+            prop_assert_eq!(vectored, frame.to_bytes());
+        }
+
+        #[test]
+        fn single_bit_errors_are_detected(frame in frame(), bit in any::<usize>()) {
+            let mut bytes = frame.to_bytes();
+            let bit = bit % ((bytes.len() - 1) * 8);
+            bytes[1 + bit / 8] ^= 1 << (bit % 8);
+            prop_assert_eq!(Frame::from_bytes(&bytes), Err(FrameError::Checksum));
+        }
+
+        #[test]
+        fn truncation_is_detected(frame in frame(), len in any::<usize>()) {
+            let bytes = frame.to_bytes();
+            let len = len % bytes.len();
+            prop_assert!(Frame::from_bytes(&bytes[..len]).is_err());
+        }
+
+        #[test]
+        fn arbitrary_bytes_never_panic(bytes in proptest::collection::vec(any::<u8>(), 0..512)) {
+            let _ = Frame::from_bytes(&bytes);
+        }
+
+        #[test]
+        fn decoded_bytes_encode_back(bytes in checksummed()) {
+            if let Ok(frame) = Frame::from_bytes(&bytes) {
+                prop_assert_eq!(frame.to_bytes(), bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn layout_matches_the_specification() {
+        let frame = Frame {
+            version: VERSION,
+            flags: Frame::RELIABLE,
+            compression: CompressionType::Gzip,
+            format: PayloadFormat::Protobuf,
+            priority: Priority::Bulk,
+            schema: 0xdead_beef,
+            seq: 0x0102_0304,
+            msg_id: 0x0506_0708_090a_0b0c,
+            msg_type: 0x0d0e,
+            nonce: None,
+            identity: None,
+            trace: None,
+            key_id: None,
+            timestamp: None,
+            control: false,
+            auth: false,
+            hello: false,
+            report: false,
+            encoded: false,
+            compressed_last: false,
+            tlv: Vec::new(),
+            payload: b"hi".to_vec(),
+        };
+        let bytes = frame.to_bytes();
+        assert_eq!(bytes[0], 14);
+        assert_eq!(
+            bytes[1..5],
+            crc32fast::hash(&[&bytes[..1], &bytes[5..]].concat()).to_be_bytes()
+        );
+        assert_eq!(
+            bytes[5..],
+            [
+                1, 2, 1, 3, 0xde, 0xad, 0xbe, 0xef, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 0, 0x0d,
+                0x0e, b'h', b'i'
+            ]
+        );
+
+        let control = Frame {
+            control: true,
+            ..frame.clone()
+        }
+        .to_bytes();
+        assert_eq!(control[25], 2);
+        let auth = Frame {
+            auth: true,
+            ..frame.clone()
+        }
+        .to_bytes();
+        assert_eq!(auth[25], 4);
+        let hello = Frame {
+            hello: true,
+            ..frame.clone()
+        }
+        .to_bytes();
+        assert_eq!(hello[25], 0x10);
+        let encoded = Frame {
+            encoded: true,
+            compressed_last: true,
+            ..frame.clone()
+        }
+        .to_bytes();
+        assert_eq!(encoded[6], 0xc2);
+        assert_eq!(control[28..], *b"hi");
+
+        let traced = Frame {
+            trace: Some(TraceContext::new([0xaa; 16], [0xbb; 8], true)),
+            ..frame.clone()
+        }
+        .to_bytes();
+        assert_eq!(traced[25], 1);
+        assert_eq!(traced[28], 0);
+        assert_eq!(traced[29..45], [0xaa; 16]);
+        assert_eq!(traced[45..53], [0xbb; 8]);
+        assert_eq!(traced[53..], [1, b'h', b'i']);
+
+        let keyed = Frame {
+            trace: Some(TraceContext::new([0xaa; 16], [0xbb; 8], true)),
+            key_id: Some(0x0a0b_0c0d),
+            ..frame.clone()
+        }
+        .to_bytes();
+        assert_eq!(keyed[25], 9);
+        assert_eq!(keyed[53..], [1, 0x0a, 0x0b, 0x0c, 0x0d, b'h', b'i']);
+
+        let stamped = Frame {
+            key_id: Some(0x0a0b_0c0d),
+            timestamp: Some(0x1122_3344),
+            ..frame.clone()
+        }
+        .to_bytes();
+        assert_eq!(stamped[25], 0x48);
+        assert_eq!(
+            stamped[28..],
+            [0x0a, 0x0b, 0x0c, 0x0d, 0x11, 0x22, 0x33, 0x44, b'h', b'i']
+        );
+
+        let extended = Frame {
+            timestamp: Some(0x1122_3344),
+            tlv: vec![Tlv::new(1, [0xaa]), Tlv::new(200, [])],
+            ..frame
+        }
+        .to_bytes();
+        assert_eq!(extended[25], 0xc0);
+        assert_eq!(extended[32..], [5, 1, 1, 0xaa, 200, 0, b'h', b'i']);
+    }
+
+    #[test]
+    fn tlv_entries_of_any_type_are_read() {
+        let frame = Frame {
+            tlv: vec![Tlv::new(FIRST_APPLICATION_TLV, *b"app"), Tlv::new(99, [])],
+            ..Frame::new(b"hi".to_vec())
+        };
+        let decoded = Frame::from_bytes(&frame.to_bytes()).unwrap();
+        assert_eq!(decoded.find_tlv(FIRST_APPLICATION_TLV), Some(&b"app"[..]));
+        assert_eq!(decoded.find_tlv(99), Some(&[][..]));
+        assert_eq!(decoded.find_tlv(1), None);
+
+        // Entries that do not fit are refused, and the whole area is left out in older versions.
+        let crowded = Frame {
+            tlv: vec![Tlv::new(1, [0; 250]), Tlv::new(2, [0; 8])],
+            ..frame.clone()
+        };
+        assert_eq!(crowded.encoded_len(), Err(FrameError::TlvTooLong(262)));
+        let full = Frame {
+            tlv: vec![Tlv::new(1, [0; 250]), Tlv::new(2, [0; 1])],
+            ..frame.clone()
+        };
+        assert_eq!(full.encoded_len(), Ok(HEADER_LEN + 1 + MAX_TLV_LEN + 2));
+        let older = Frame {
+            version: 13,
+            ..frame
+        }
+        .to_bytes();
+        assert_eq!((older[25], older.len()), (0, HEADER_LEN + 2));
+
+        for area in [&[][..], &[1, 2, 0]] {
+            let mut bytes = Frame::new(Vec::new()).to_bytes();
+            bytes[25] = TLV;
+            bytes.push(area.len() as u8);
+            bytes.extend_from_slice(area);
+            let mut checksum = checksum(VERSION);
+            checksum.update(&bytes[5..]);
+            bytes[1..5].copy_from_slice(&checksum.finalize().to_be_bytes());
+            assert_eq!(Frame::from_bytes(&bytes), Err(FrameError::Tlv));
+        }
+    }
+
+    #[test]
+    fn unknown_versions_and_tags_are_rejected() {
+        let mut bytes = Frame::ack(1).to_bytes();
+        bytes[0] = VERSION + 1;
+        assert_eq!(
+            Frame::from_bytes(&bytes),
+            Err(FrameError::Version(VERSION + 1))
+        );
+
+        for (offset, value, error) in [
+            (6, 9, FrameError::Compression(9)),
+            (7, 9, FrameError::Format(9)),
+            (8, 9, FrameError::Priority(9)),
+            (25, 0x80, FrameError::Extensions(0x80)),
+        ] {
+            // Version 13 has no TLV area.
+            let mut bytes = Frame {
+                version: 13,
+                ..Frame::new(Vec::new())
+            }
+            .to_bytes();
+            bytes[offset] = value;
+            let checksum = crc32fast::hash(&bytes[5..]).to_be_bytes();
+            bytes[1..5].copy_from_slice(&checksum);
+            assert_eq!(Frame::from_bytes(&bytes), Err(error));
+        }
+    }
+
+    #[test]
+    fn version_12_frames_leave_out_the_type() {
+        let frame = Frame {
+            version: 12,
+            flags: Frame::ENCRYPTED,
+            msg_type: 7,
+            nonce: Some([9; NONCE_LEN]),
+            ..Frame::new(b"old".to_vec())
+        };
+        let mut bytes = frame.to_bytes();
+        assert_eq!(bytes.len(), 26 + NONCE_LEN + 3);
+        assert_eq!(bytes[26..26 + NONCE_LEN], [9; NONCE_LEN]);
+        assert_eq!(
+            Frame::from_bytes(&bytes),
+            Ok(Frame {
+                msg_type: 0,
+                ..frame
+            })
+        );
+
+        bytes[0] = MIN_VERSION - 1;
+        assert_eq!(
+            Frame::from_bytes(&bytes),
+            Err(FrameError::Version(MIN_VERSION - 1))
+        );
+
+        // Newer frames are checksummed with their version, so one corrupted to 12 is not read.
+        let mut bytes = Frame::new(b"new".to_vec()).to_bytes();
+        bytes[0] = 12;
+        assert_eq!(Frame::from_bytes(&bytes), Err(FrameError::Checksum));
+    }
+}
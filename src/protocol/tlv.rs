@@ -0,0 +1,69 @@
+//! The TLV extension area, where metadata can be added to frames without a new layout. It is a
+//! run of entries, each a type (1 byte), the length of its value (1 byte) and the value.
+//! Receivers skip the entries of types they do not know, so a sender may add entries of new
+//! types without older receivers dropping its frames. Types below 128 are reserved for crumb;
+//! applications may use the rest.
+
+use super::{FrameError, MAX_TLV_LEN};
+
+/// The first entry type left to applications.
+pub const FIRST_APPLICATION_TLV: u8 = 128;
+
+/// An entry of a frame's TLV extension area.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Tlv {
+    pub kind: u8,
+    pub value: Vec<u8>,
+}
+
+impl Tlv {
+    pub fn new(kind: u8, value: impl Into<Vec<u8>>) -> Tlv {
+        Tlv {
+            kind,
+            value: value.into(),
+        }
+    }
+}
+
+// The length of the area `entries` take, its length byte included, or 0 if there are none.
+pub(super) fn area_len(entries: &[Tlv]) -> Result<usize, FrameError> {
+    match entries.iter().map(|entry| 2 + entry.value.len()).sum() {
+        0 => Ok(0),
+        len if len <= MAX_TLV_LEN => Ok(1 + len),
+        len => Err(FrameError::TlvTooLong(len)),
+    }
+}
+
+// Writes `entries` to `area`, in order, as long as they fit, returning the length written.
+pub(super) fn encode(entries: &[Tlv], area: &mut [u8]) -> usize {
+    let mut len = 0;
+    for entry in entries {
+        let end = len + 2 + entry.value.len();
+        if end > area.len() {
+            break;
+        }
+        area[len] = entry.kind;
+        area[len + 1] = entry.value.len() as u8;
+        area[len + 2..end].copy_from_slice(&entry.value);
+        len = end;
+    }
+    len
+}
+
+// Reads the entries of an area, which must be neither empty nor end partway through one.
+pub(super) fn decode(mut area: &[u8]) -> Result<Vec<Tlv>, FrameError> {
+    if area.is_empty() {
+        return Err(FrameError::Tlv);
+    }
+    let mut entries = Vec::new();
+    while let [kind, len, rest @ ..] = area {
+        let value = rest.get(..*len as usize).ok_or(FrameError::Tlv)?;
+        entries.push(Tlv::new(*kind, value));
+        area = &rest[*len as usize..];
+    }
+    match area {
+        [] => Ok(entries),
+        _ => Err(FrameError::Tlv),
+    }
+}
@@ -0,0 +1,165 @@
+//! W3C trace context, carried in the `TRACE` header extension so that a message's way from
+//! producer through crumb to consumer can be followed as one distributed trace.
+
+use super::TRACE_LEN;
+use std::fmt;
+use std::str;
+use tracing::{debug_span, Span};
+
+const TARGET: &str = "crumb::protocol::trace";
+
+/// The `traceparent` of a W3C trace context: which trace a message belongs to and the span
+/// that sent it. It converts to and from the `traceparent` header's text form, which is how
+/// OpenTelemetry propagators inject and extract it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TraceContext {
+    /// The format version, 0 for the one this crate reads.
+    pub version: u8,
+    pub trace_id: [u8; 16],
+    /// The id of the span the message was sent from.
+    pub parent_id: [u8; 8],
+    /// Bit 0 says the sender sampled the trace.
+    pub flags: u8,
+}
+
+impl TraceContext {
+    pub fn new(trace_id: [u8; 16], parent_id: [u8; 8], sampled: bool) -> TraceContext {
+        TraceContext {
+            version: 0,
+            trace_id,
+            parent_id,
+            flags: sampled as u8,
+        }
+    }
+
+    pub fn is_sampled(&self) -> bool {
+        self.flags & 0x01 != 0
+    }
+
+    /// A span with the trace and parent ids as its `trace_id` and `parent_id` fields, for
+    /// consumers to handle the message in so their logs join the trace.
+    pub fn span(&self) -> Span {
+        debug_span!(
+            target: TARGET,
+            "traced message",
+            trace_id = %hex(&self.trace_id),
+            parent_id = %hex(&self.parent_id),
+        )
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; TRACE_LEN] {
+        let mut bytes = [0; TRACE_LEN];
+        bytes[0] = self.version;
+        bytes[1..17].copy_from_slice(&self.trace_id);
+        bytes[17..25].copy_from_slice(&self.parent_id);
+        bytes[25] = self.flags;
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8; TRACE_LEN]) -> TraceContext {
+        TraceContext {
+            version: bytes[0],
+            trace_id: bytes[1..17].try_into().unwrap(),
+            parent_id: bytes[17..25].try_into().unwrap(),
+            flags: bytes[25],
+        }
+    }
+}
+
+/// The `traceparent` text form, such as
+/// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02x}-{}-{}-{:02x}",
+            self.version,
+            hex(&self.trace_id),
+            hex(&self.parent_id),
+            self.flags
+        )
+    }
+}
+
+/// Parses a `traceparent` header. All-zero ids are invalid, as is version 255; later versions
+/// may append fields, which are ignored.
+impl str::FromStr for TraceContext {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<TraceContext, Self::Err> {
+        const INVALID: &str = "Invalid traceparent";
+        let mut fields = s.split('-');
+        let mut next = |len: usize| {
+            fields
+                .next()
+                .filter(|field| field.len() == len * 2)
+                .and_then(unhex)
+                .ok_or(INVALID)
+        };
+        let version = next(1)?[0];
+        let trace_id: [u8; 16] = next(16)?.try_into().unwrap();
+        let parent_id: [u8; 8] = next(8)?.try_into().unwrap();
+        let flags = next(1)?[0];
+        let trailing = fields.next().is_some();
+        if version == 0xff
+            || (version == 0 && trailing)
+            || trace_id == [0; 16]
+            || parent_id == [0; 8]
+        {
+            return Err(INVALID);
+        }
+        Ok(TraceContext {
+            version,
+            trace_id,
+            parent_id,
+            flags,
+        })
+    }
+}
+
+pub(super) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Lowercase hex only, as the specification requires.
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    let digit = |c: u8| match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        _ => None,
+    };
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| Some(digit(pair[0])? << 4 | digit(pair[1])?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traceparents_round_trip() {
+        let text = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context: TraceContext = text.parse().unwrap();
+        assert_eq!(context.trace_id[..2], [0x4b, 0xf9]);
+        assert_eq!(context.parent_id[7], 0xb7);
+        assert!(context.is_sampled());
+        assert_eq!(context.to_string(), text);
+        assert_eq!(TraceContext::from_bytes(&context.to_bytes()), context);
+
+        for invalid in [
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        ] {
+            assert!(invalid.parse::<TraceContext>().is_err(), "{}", invalid);
+        }
+        let later = "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-extra";
+        assert!(!later.parse::<TraceContext>().unwrap().is_sampled());
+    }
+}
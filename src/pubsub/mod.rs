@@ -0,0 +1,347 @@
+use crate::transport::udp::{Server, ShutdownHandle};
+use crate::transport::Transport;
+use crate::util::config::Config;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, IoSlice};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tracing::{debug, trace};
+
+const TARGET: &str = "crumb::pubsub";
+
+// Largest datagram the broker relays.
+pub(crate) const MAX_MESSAGE_SIZE: usize = 65_507;
+
+// Wire layout: kind (1 byte), topic length (u16, big endian), topic (UTF-8), payload.
+const HEADER_LEN: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Kind {
+    Subscribe = 1,
+    Unsubscribe = 2,
+    Publish = 3,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Message<'a> {
+    kind: Kind,
+    topic: &'a str,
+    payload: &'a [u8],
+}
+
+impl<'a> Message<'a> {
+    fn encode(kind: Kind, topic: &str, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let header = Message::header(kind, topic)?;
+        let mut message = Vec::with_capacity(HEADER_LEN + topic.len() + payload.len());
+        message.extend_from_slice(&header);
+        message.extend_from_slice(topic.as_bytes());
+        message.extend_from_slice(payload);
+        Ok(message)
+    }
+
+    fn header(kind: Kind, topic: &str) -> io::Result<[u8; HEADER_LEN]> {
+        let topic_len = u16::try_from(topic.len()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Topic longer than 65535 bytes")
+        })?;
+        let [high, low] = topic_len.to_be_bytes();
+        Ok([kind as u8, high, low])
+    }
+
+    fn decode(bytes: &'a [u8]) -> Option<Message<'a>> {
+        let kind = match *bytes.first()? {
+            1 => Kind::Subscribe,
+            2 => Kind::Unsubscribe,
+            3 => Kind::Publish,
+            _ => return None,
+        };
+        let topic_len = u16::from_be_bytes([*bytes.get(1)?, *bytes.get(2)?]) as usize;
+        let topic = bytes.get(HEADER_LEN..HEADER_LEN + topic_len)?;
+
+        Some(Message {
+            kind,
+            topic: std::str::from_utf8(topic).ok()?,
+            payload: &bytes[HEADER_LEN + topic_len..],
+        })
+    }
+}
+
+/// Returns whether `topic` matches the subscription `filter`.
+///
+/// Topics are `/`-separated levels. In a filter, `+` matches exactly one level and a trailing
+/// `#` matches any number of remaining levels (including none).
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return filter_levels.next().is_none(),
+            (Some("+"), Some(_)) => {}
+            (Some(expected), Some(level)) if expected == level => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Relays published messages to every client subscribed to a matching topic filter.
+pub struct Broker {
+    server: Server,
+    subscriptions: BTreeMap<String, HashSet<SocketAddr>>,
+}
+
+impl Broker {
+    pub fn init(conf: &Config) -> io::Result<Broker> {
+        Ok(Broker {
+            server: Server::init(conf)?,
+            subscriptions: BTreeMap::new(),
+        })
+    }
+
+    /// Receives and handles a single message. Malformed datagrams are dropped.
+    pub fn process(&mut self) -> io::Result<()> {
+        let mut buffer = vec![0u8; MAX_MESSAGE_SIZE];
+        let (received, source) = self.server.receive_from(&mut buffer)?;
+        let Some(message) = Message::decode(&buffer[..received]) else {
+            trace!(target: TARGET, %source, "dropping malformed message");
+            return Ok(());
+        };
+
+        match message.kind {
+            Kind::Subscribe => {
+                debug!(target: TARGET, %source, filter = message.topic, "subscribe");
+                self.subscriptions
+                    .entry(message.topic.to_string())
+                    .or_default()
+                    .insert(source);
+            }
+            Kind::Unsubscribe => {
+                debug!(target: TARGET, %source, filter = message.topic, "unsubscribe");
+                if let Some(subscribers) = self.subscriptions.get_mut(message.topic) {
+                    subscribers.remove(&source);
+                    if subscribers.is_empty() {
+                        self.subscriptions.remove(message.topic);
+                    }
+                }
+            }
+            Kind::Publish => {
+                // A subscriber with several matching filters still receives the message once.
+                let destinations: HashSet<SocketAddr> = self
+                    .subscriptions
+                    .iter()
+                    .filter(|(filter, _)| topic_matches(filter, message.topic))
+                    .flat_map(|(_, subscribers)| subscribers.iter().copied())
+                    .collect();
+                trace!(
+                    target: TARGET,
+                    topic = message.topic,
+                    subscribers = destinations.len(),
+                    "publish"
+                );
+                for destination in destinations {
+                    if let Err(e) = self.server.send_to(&buffer[..received], destination) {
+                        debug!(target: TARGET, %destination, error = %e, "relay failed");
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles messages until the broker is shut down or receiving fails.
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            match self.process() {
+                Ok(()) => {}
+                Err(_) if self.server.is_shut_down() => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Returns a handle that makes `run` return from another thread.
+    pub fn shutdown_handle(&self) -> io::Result<ShutdownHandle> {
+        self.server.shutdown_handle()
+    }
+
+    /// Number of clients subscribed with exactly `filter`.
+    pub fn subscriber_count(&self, filter: &str) -> usize {
+        self.subscriptions.get(filter).map_or(0, HashSet::len)
+    }
+
+    pub fn close(self) {
+        self.server.close();
+    }
+}
+
+/// Publishes to and subscribes through a `Broker`, over the transport selected in `Config`.
+pub struct Client {
+    transport: Box<dyn Transport>,
+}
+
+impl Client {
+    pub fn init(conf: &Config) -> io::Result<Client> {
+        Ok(Client {
+            transport: <dyn Transport>::from_config(conf)?,
+        })
+    }
+
+    pub fn subscribe(&self, filter: &str) -> io::Result<()> {
+        self.transport
+            .send(&Message::encode(Kind::Subscribe, filter, &[])?)?;
+        Ok(())
+    }
+
+    pub fn unsubscribe(&self, filter: &str) -> io::Result<()> {
+        self.transport
+            .send(&Message::encode(Kind::Unsubscribe, filter, &[])?)?;
+        Ok(())
+    }
+
+    pub fn publish(&self, topic: &str, payload: &[u8]) -> io::Result<usize> {
+        if topic.contains(['+', '#']) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Wildcards are only allowed in subscriptions",
+            ));
+        }
+        // Gathered from the caller's buffers rather than copied into a message.
+        self.transport.send_vectored(&[
+            IoSlice::new(&Message::header(Kind::Publish, topic)?),
+            IoSlice::new(topic.as_bytes()),
+            IoSlice::new(payload),
+        ])?;
+        Ok(payload.len())
+    }
+
+    /// Waits for the next published message and copies its payload into `buffer`, truncating
+    /// if necessary. Returns the topic and the number of bytes copied.
+    pub fn receive(&self, buffer: &mut [u8]) -> io::Result<(String, usize)> {
+        let mut datagram = vec![0u8; MAX_MESSAGE_SIZE];
+        loop {
+            let received = self.transport.receive(&mut datagram)?;
+            match Message::decode(&datagram[..received]) {
+                Some(message) if message.kind == Kind::Publish => {
+                    let len = message.payload.len().min(buffer.len());
+                    buffer[..len].copy_from_slice(&message.payload[..len]);
+                    return Ok((message.topic.to_string(), len));
+                }
+                _ => trace!(target: TARGET, "dropping unexpected message"),
+            }
+        }
+    }
+
+    /// Bounds how long `receive` blocks. `None` blocks until a message arrives.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.transport.set_read_timeout(timeout)
+    }
+
+    pub fn close(self) {
+        self.transport.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcard_matching() {
+        assert!(topic_matches("sensors/temp", "sensors/temp"));
+        assert!(!topic_matches("sensors/temp", "sensors/humidity"));
+        assert!(topic_matches("sensors/+", "sensors/temp"));
+        assert!(!topic_matches("sensors/+", "sensors/temp/kitchen"));
+        assert!(!topic_matches("sensors/+", "sensors"));
+        assert!(topic_matches("sensors/+/kitchen", "sensors/temp/kitchen"));
+        assert!(topic_matches("sensors/#", "sensors"));
+        assert!(topic_matches("sensors/#", "sensors/temp/kitchen"));
+        assert!(topic_matches("#", "anything/at/all"));
+        assert!(!topic_matches("sensors/#/kitchen", "sensors/temp/kitchen"));
+        assert!(!topic_matches("alerts/#", "sensors/temp"));
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let encoded = Message::encode(Kind::Publish, "sensors/temp", b"21.5").unwrap();
+        assert_eq!(
+            Message::decode(&encoded),
+            Some(Message {
+                kind: Kind::Publish,
+                topic: "sensors/temp",
+                payload: b"21.5",
+            })
+        );
+    }
+
+    #[test]
+    fn malformed_messages() {
+        assert_eq!(Message::decode(b""), None);
+        assert_eq!(Message::decode(&[9, 0, 0]), None);
+        assert_eq!(Message::decode(&[3, 0, 5, b'a']), None);
+        assert_eq!(Message::decode(&[3, 0, 1, 0xff]), None);
+    }
+
+    #[test]
+    fn broker_routes_by_topic() -> io::Result<()> {
+        let conf = Config {
+            host: "::1".to_string(),
+            port: 8095,
+            ..Default::default()
+        };
+        let mut broker = Broker::init(&conf)?;
+        let sensors = Client::init(&conf)?;
+        let alerts = Client::init(&conf)?;
+        let publisher = Client::init(&conf)?;
+
+        sensors.subscribe("sensors/+")?;
+        broker.process()?;
+        alerts.subscribe("alerts/#")?;
+        broker.process()?;
+        assert_eq!(broker.subscriber_count("sensors/+"), 1);
+
+        publisher.publish("sensors/temp", b"21.5")?;
+        broker.process()?;
+        publisher.publish("alerts/disk/full", b"/var")?;
+        broker.process()?;
+
+        let mut buffer = [0u8; 64];
+        let (topic, len) = sensors.receive(&mut buffer)?;
+        assert_eq!(
+            (topic.as_str(), &buffer[..len]),
+            ("sensors/temp", &b"21.5"[..])
+        );
+        let (topic, len) = alerts.receive(&mut buffer)?;
+        assert_eq!(
+            (topic.as_str(), &buffer[..len]),
+            ("alerts/disk/full", &b"/var"[..])
+        );
+
+        alerts.unsubscribe("alerts/#")?;
+        broker.process()?;
+        assert_eq!(broker.subscriber_count("alerts/#"), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn run_stops_on_shutdown() -> io::Result<()> {
+        let mut broker = Broker::init(&Config {
+            port: 8102,
+            ..Default::default()
+        })?;
+        let handle = broker.shutdown_handle()?;
+        let running = std::thread::spawn(move || broker.run());
+
+        handle.shutdown();
+        running.join().unwrap()
+    }
+
+    #[test]
+    fn publish_rejects_wildcards() -> io::Result<()> {
+        let client = Client::init(&Config::default())?;
+        assert!(client.publish("sensors/+", b"").is_err());
+        Ok(())
+    }
+}
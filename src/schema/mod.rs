@@ -0,0 +1,175 @@
+//! Schema versions for protobuf payloads.
+//!
+//! A schema version is the CRC-32 of the `.proto` file at `proto_path`, carried in the header of
+//! every protobuf frame a node sends. Receivers compare it against every version they have
+//! loaded, so after the file is edited and reloaded a node still accepts messages from peers
+//! running the previous version while warning about, or rejecting, versions it has never seen.
+
+use crate::protocol::Frame;
+use crate::util::config::{Config, PayloadFormat, SchemaPolicy};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tracing::{debug, info, warn};
+
+const TARGET: &str = "crumb::schema";
+
+/// The schema versions a node knows, reloadable while sessions are running.
+#[derive(Debug)]
+pub struct SchemaRegistry {
+    path: String,
+    policy: SchemaPolicy,
+    versions: Mutex<Versions>,
+    unknown: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Versions {
+    current: Option<u32>,
+    known: HashSet<u32>,
+}
+
+impl SchemaRegistry {
+    /// Loads the schema at `proto_path`. A missing or unreadable file leaves the registry empty:
+    /// frames are sent without a schema version and incoming versions are not checked until a
+    /// `reload` succeeds.
+    pub fn new(conf: &Config) -> SchemaRegistry {
+        let registry = SchemaRegistry {
+            path: conf.proto_path.clone(),
+            policy: conf.schema_policy,
+            versions: Mutex::default(),
+            unknown: AtomicU64::new(0),
+        };
+        if let Err(e) = registry.reload() {
+            debug!(target: TARGET, path = %registry.path, error = %e, "no schema loaded");
+        }
+        registry
+    }
+
+    /// Reads the schema file again, making its version the one sent from now on. Returns
+    /// whether the version changed. Earlier versions stay known.
+    pub fn reload(&self) -> io::Result<bool> {
+        let version = crc32fast::hash(&fs::read(&self.path)?);
+        let mut versions = self.versions.lock().unwrap_or_else(|e| e.into_inner());
+        versions.known.insert(version);
+        let changed = versions.current.replace(version) != Some(version);
+        if changed {
+            let version = format!("{:08x}", version);
+            info!(target: TARGET, path = %self.path, %version, "schema loaded");
+        }
+        Ok(changed)
+    }
+
+    /// The version stamped on outgoing protobuf frames, if a schema is loaded.
+    pub fn current(&self) -> Option<u32> {
+        self.versions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .current
+    }
+
+    pub fn is_known(&self, version: u32) -> bool {
+        let versions = self.versions.lock().unwrap_or_else(|e| e.into_inner());
+        versions.known.contains(&version)
+    }
+
+    /// Messages received with a schema version this registry has never loaded, whether they
+    /// were delivered or dropped.
+    pub fn unknown(&self) -> u64 {
+        self.unknown.load(Ordering::Relaxed)
+    }
+
+    /// The schema field for an outgoing frame in `format`.
+    pub(crate) fn stamp(&self, format: PayloadFormat) -> u32 {
+        match format {
+            PayloadFormat::Protobuf => self.current().unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    /// Whether `frame` should be delivered, applying the policy to unknown schema versions.
+    /// Frames without a version, and every frame while no schema is loaded, are accepted.
+    pub(crate) fn accepts(&self, frame: &Frame) -> bool {
+        if frame.schema == 0 || self.current().is_none() || self.is_known(frame.schema) {
+            return true;
+        }
+        self.unknown.fetch_add(1, Ordering::Relaxed);
+        let version = format!("{:08x}", frame.schema);
+        match self.policy {
+            SchemaPolicy::Warn => {
+                warn!(target: TARGET, %version, msg_id = frame.msg_id, "message from unknown schema");
+                true
+            }
+            SchemaPolicy::Reject => {
+                debug!(target: TARGET, %version, msg_id = frame.msg_id, "dropping message from unknown schema");
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn protobuf_frame(schema: u32) -> Frame {
+        Frame {
+            format: PayloadFormat::Protobuf,
+            schema,
+            ..Frame::new(Vec::new())
+        }
+    }
+
+    #[test]
+    fn reloads_keep_earlier_versions_known() -> io::Result<()> {
+        let path = env::temp_dir().join(format!("crumb-schema-{}.proto", std::process::id()));
+        fs::write(&path, "message A {}")?;
+        let registry = SchemaRegistry::new(&Config {
+            proto_path: path.to_string_lossy().into_owned(),
+            schema_policy: SchemaPolicy::Reject,
+            ..Default::default()
+        });
+        let first = registry.current().unwrap();
+        assert_eq!(registry.stamp(PayloadFormat::Protobuf), first);
+        assert_eq!(registry.stamp(PayloadFormat::Json), 0);
+        assert!(!registry.reload()?);
+
+        fs::write(&path, "message A { string b = 1; }")?;
+        assert!(registry.reload()?);
+        let second = registry.current().unwrap();
+        assert_ne!(first, second);
+        assert!(registry.accepts(&protobuf_frame(first)));
+        assert!(registry.accepts(&protobuf_frame(second)));
+        assert!(registry.accepts(&protobuf_frame(0)));
+        assert!(!registry.accepts(&protobuf_frame(first ^ second)));
+        assert_eq!(registry.unknown(), 1);
+
+        fs::remove_file(&path)
+    }
+
+    #[test]
+    fn unknown_versions_are_delivered_under_the_warn_policy() {
+        let registry = SchemaRegistry::new(&Config {
+            proto_path: "Cargo.toml".to_string(),
+            ..Default::default()
+        });
+        let unknown = registry.current().unwrap().wrapping_add(1);
+        assert!(registry.accepts(&protobuf_frame(unknown)));
+        assert_eq!(registry.unknown(), 1);
+    }
+
+    #[test]
+    fn nothing_is_checked_without_a_schema() {
+        let registry = SchemaRegistry::new(&Config {
+            proto_path: "missing.proto".to_string(),
+            schema_policy: SchemaPolicy::Reject,
+            ..Default::default()
+        });
+        assert_eq!(registry.current(), None);
+        assert_eq!(registry.stamp(PayloadFormat::Protobuf), 0);
+        assert!(registry.accepts(&protobuf_frame(7)));
+    }
+}
@@ -0,0 +1,232 @@
+use crate::error::Error;
+use crate::util::config::Config;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use tracing::debug;
+
+const TARGET: &str = "crumb::security::acl";
+
+type UnauthorizedHook = Box<dyn Fn(SocketAddr) + Send + Sync>;
+
+/// An IPv4 or IPv6 network, written `10.0.0.0/8` or `fd00::/8`. A bare address stands for a
+/// network holding only that address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => prefix_matches(
+                network.to_bits().into(),
+                ip.to_bits().into(),
+                self.prefix,
+                32,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(network.to_bits(), ip.to_bits(), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+// Whether the leading `prefix` of `width` bits agree.
+fn prefix_matches(network: u128, ip: u128, prefix: u8, width: u8) -> bool {
+    let shift = width - prefix;
+    u128::checked_shr(network ^ ip, shift.into()).unwrap_or(0) == 0
+}
+
+impl str::FromStr for Cidr {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, prefix) = match s.trim().split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let network: IpAddr = network
+            .parse::<IpAddr>()
+            .map_err(|_| "Invalid CIDR address.")?
+            .to_canonical();
+        let width = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| "Invalid CIDR prefix.")?,
+            None => width,
+        };
+        if prefix > width {
+            return Err("Invalid CIDR prefix.");
+        }
+        Ok(Cidr { network, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Which peers a server talks to, checked against the source address of every datagram and
+/// QUIC connection attempt before any handshake or handler runs.
+///
+/// A peer is admitted unless it falls in a `deny` network, or an `allow` list is configured and
+/// it falls in none of its networks.
+pub struct Acl {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    hook: RwLock<Option<UnauthorizedHook>>,
+    rejected: AtomicU64,
+}
+
+impl fmt::Debug for Acl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Acl")
+            .field("allow", &self.allow)
+            .field("deny", &self.deny)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Acl {
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> Acl {
+        Acl {
+            allow,
+            deny,
+            hook: RwLock::default(),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// The lists in `acl_allow` and `acl_deny`.
+    pub fn from_config(conf: &Config) -> io::Result<Acl> {
+        Ok(Acl::new(
+            parse_cidrs(&conf.acl_allow)?,
+            parse_cidrs(&conf.acl_deny)?,
+        ))
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Registers `hook` to be called with the address of every datagram or connection attempt
+    /// rejected, replacing any earlier hook. It runs on the receiving thread, so it should
+    /// return quickly.
+    pub fn on_unauthorized<F>(&self, hook: F)
+    where
+        F: Fn(SocketAddr) + Send + Sync + 'static,
+    {
+        *self.hook.write().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(hook));
+    }
+
+    /// Datagrams and connection attempts rejected so far.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Whether to handle traffic from `peer`, counting and reporting it if not.
+    pub(crate) fn admit(&self, peer: SocketAddr) -> bool {
+        if self.permits(peer.ip()) {
+            return true;
+        }
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        debug!(target: TARGET, %peer, "rejected unauthorized peer");
+        if let Some(hook) = &*self.hook.read().unwrap_or_else(|e| e.into_inner()) {
+            hook(peer);
+        }
+        false
+    }
+}
+
+impl Default for Acl {
+    fn default() -> Acl {
+        Acl::new(Vec::new(), Vec::new())
+    }
+}
+
+// A comma separated list of networks. Empty entries are ignored.
+pub(crate) fn parse_cidrs(list: &str) -> io::Result<Vec<Cidr>> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            entry
+                .parse()
+                .map_err(|e| Error::config(format!("{} {}", e, entry)).into())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn acl(allow: &str, deny: &str) -> Acl {
+        Acl::from_config(&Config {
+            acl_allow: allow.to_string(),
+            acl_deny: deny.to_string(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn networks_contain_their_addresses() {
+        let cidr: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(cidr.contains("10.1.200.3".parse().unwrap()));
+        assert!(cidr.contains("::ffff:10.1.0.1".parse().unwrap()));
+        assert!(!cidr.contains("10.2.0.1".parse().unwrap()));
+        assert!(!cidr.contains("fd00::1".parse().unwrap()));
+
+        let everything: Cidr = "::/0".parse().unwrap();
+        assert!(everything.contains("2001:db8::1".parse().unwrap()));
+        let host: Cidr = "2001:db8::1".parse().unwrap();
+        assert_eq!(host.to_string(), "2001:db8::1/128");
+        assert!(!host.contains("2001:db8::2".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let acl = acl("10.0.0.0/8, 192.168.1.7", "10.9.0.0/16");
+        assert!(acl.permits("10.1.2.3".parse().unwrap()));
+        assert!(acl.permits("192.168.1.7".parse().unwrap()));
+        assert!(!acl.permits("10.9.0.1".parse().unwrap()));
+        assert!(!acl.permits("192.168.1.8".parse().unwrap()));
+        assert!(Acl::default().permits("::1".parse().unwrap()));
+        assert!(Acl::from_config(&Config {
+            acl_deny: "localhost".to_string(),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn rejected_peers_are_reported() {
+        let acl = acl("", "127.0.0.0/8");
+        let reported = Arc::new(AtomicU64::new(0));
+        let counter = reported.clone();
+        acl.on_unauthorized(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        assert!(acl.admit("[::1]:4000".parse().unwrap()));
+        assert!(!acl.admit("127.0.0.1:4000".parse().unwrap()));
+        assert_eq!((acl.rejected(), reported.load(Ordering::Relaxed)), (1, 1));
+    }
+}
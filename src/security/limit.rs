@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+const TARGET: &str = "crumb::security::limit";
+
+// Sources tracked before idle ones are forgotten, bounding memory under spoofed floods.
+const MAX_TRACKED: usize = 1 << 16;
+
+/// Limits how often each source address may send, so one client cannot monopolize a server.
+///
+/// Every source IP gets a token bucket holding a second's worth of its allowance. A source that
+/// runs it dry is dropped until the bucket refills, or for the whole ban period if one is set.
+#[derive(Debug)]
+pub struct RateLimiter {
+    // Events per second. Zero disables limiting.
+    rate: f64,
+    ban: Duration,
+    sources: Mutex<HashMap<IpAddr, Bucket>>,
+    throttled: AtomicU64,
+    bans: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    banned_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u32, ban: Duration) -> RateLimiter {
+        RateLimiter {
+            rate: f64::from(per_second),
+            ban,
+            sources: Mutex::default(),
+            throttled: AtomicU64::new(0),
+            bans: AtomicU64::new(0),
+        }
+    }
+
+    /// Events dropped because their source exceeded its rate or was banned.
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    /// How many times a source has been banned.
+    pub fn bans(&self) -> u64 {
+        self.bans.load(Ordering::Relaxed)
+    }
+
+    /// Whether `source` may send now, taking one event from its allowance if so.
+    pub(crate) fn admit(&self, source: IpAddr, now: Instant) -> bool {
+        if self.rate == 0.0 {
+            return true;
+        }
+        let source = source.to_canonical();
+        let mut sources = self.sources.lock().unwrap_or_else(|e| e.into_inner());
+        if sources.len() >= MAX_TRACKED && !sources.contains_key(&source) {
+            sources.retain(|_, bucket| bucket.is_limiting(now));
+        }
+        let bucket = sources.entry(source).or_insert(Bucket {
+            tokens: self.rate,
+            refilled_at: now,
+            banned_until: None,
+        });
+
+        if bucket.banned_until.is_some_and(|until| now < until) {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+        bucket.refilled_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return true;
+        }
+
+        self.throttled.fetch_add(1, Ordering::Relaxed);
+        if !self.ban.is_zero() {
+            bucket.banned_until = Some(now + self.ban);
+            self.bans.fetch_add(1, Ordering::Relaxed);
+            debug!(target: TARGET, %source, ban = ?self.ban, "source banned for flooding");
+        }
+        false
+    }
+}
+
+impl Bucket {
+    // Whether forgetting this source would let it send sooner than it otherwise could.
+    fn is_limiting(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| now < until)
+            || now.saturating_duration_since(self.refilled_at) < Duration::from_secs(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+
+    #[test]
+    fn sources_are_throttled_until_their_bucket_refills() {
+        let limiter = RateLimiter::new(2, Duration::ZERO);
+        let now = Instant::now();
+        assert!(limiter.admit(SOURCE, now));
+        assert!(limiter.admit(SOURCE, now));
+        assert!(!limiter.admit(SOURCE, now));
+        assert!(limiter.admit("10.0.0.2".parse().unwrap(), now));
+        assert!(limiter.admit(SOURCE, now + Duration::from_millis(500)));
+        assert_eq!((limiter.throttled(), limiter.bans()), (1, 0));
+    }
+
+    #[test]
+    fn flooding_sources_are_banned() {
+        let limiter = RateLimiter::new(1, Duration::from_secs(10));
+        let now = Instant::now();
+        assert!(limiter.admit(SOURCE, now));
+        assert!(!limiter.admit(SOURCE, now));
+        assert!(!limiter.admit(SOURCE, now + Duration::from_secs(5)));
+        assert!(limiter.admit(SOURCE, now + Duration::from_secs(10)));
+        assert_eq!((limiter.throttled(), limiter.bans()), (2, 1));
+    }
+
+    #[test]
+    fn zero_disables_limiting() {
+        let limiter = RateLimiter::new(0, Duration::from_secs(10));
+        let now = Instant::now();
+        assert!((0..1000).all(|_| limiter.admit(SOURCE, now)));
+    }
+}
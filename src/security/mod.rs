@@ -0,0 +1,317 @@
+//! Access control and flood protection for servers, and application-layer frame protection
+//! with a pre-shared key.
+//!
+//! With `security = psk`, every frame a session sends, acknowledgements included, is sealed with
+//! XChaCha20-Poly1305: the payload is encrypted and the header authenticated, with a random
+//! nonce carried in the header. This needs no TLS library, so it works on targets where the
+//! QUIC transport cannot be built, but it offers no forward secrecy and every peer holding the
+//! key can read and forge traffic.
+//!
+//! Giving every client its own key, named by `psk_identity`, confines a leaked key to one
+//! client. Sealed frames then carry the identity, and a server looks the key up with the
+//! callback `session::Server::on_psk_identity` registers.
+//!
+//! Numbering keys with `psk_key_id` lets a fleet rotate to a new key without stopping, as the
+//! `rotation` module describes.
+
+mod acl;
+mod limit;
+mod rotation;
+
+pub(crate) use acl::parse_cidrs;
+pub use acl::{Acl, Cidr};
+pub use limit::RateLimiter;
+pub(crate) use rotation::parse_keys;
+pub use rotation::KeyRing;
+
+use crate::error::Error;
+use crate::protocol::{Frame, KEY_ID_LEN, MAX_IDENTITY_LEN, NONCE_LEN};
+use crate::util::config::{Config, SecurityMode};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::sync::RwLock;
+
+/// Length of a pre-shared key.
+pub const KEY_LEN: usize = 32;
+
+type Lookup = Box<dyn Fn(&[u8]) -> Option<[u8; KEY_LEN]> + Send + Sync>;
+
+/// A pre-shared key, ready to seal and open frames.
+pub struct Psk {
+    cipher: XChaCha20Poly1305,
+    identity: Option<Vec<u8>>,
+    key_id: Option<u32>,
+}
+
+impl fmt::Debug for Psk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.identity {
+            Some(identity) => write!(f, "Psk({})", String::from_utf8_lossy(identity)),
+            None => f.write_str("Psk(..)"),
+        }
+    }
+}
+
+impl Psk {
+    /// Bytes the authentication tag adds to a sealed payload.
+    pub const TAG_LEN: usize = 16;
+
+    pub fn new(key: &[u8; KEY_LEN]) -> Psk {
+        Psk {
+            cipher: XChaCha20Poly1305::new(key.into()),
+            identity: None,
+            key_id: None,
+        }
+    }
+
+    /// The same key, named `identity` in every frame it seals. Fails if `identity` is empty or
+    /// longer than `MAX_IDENTITY_LEN` bytes.
+    pub fn with_identity(self, identity: &[u8]) -> crate::Result<Psk> {
+        if identity.is_empty() || identity.len() > MAX_IDENTITY_LEN {
+            return Err(Error::config(format!(
+                "Pre-shared key identity must be 1 to {} bytes",
+                MAX_IDENTITY_LEN
+            )));
+        }
+        Ok(Psk {
+            identity: Some(identity.to_vec()),
+            ..self
+        })
+    }
+
+    /// The identity frames sealed with this key carry, if it has one.
+    pub fn identity(&self) -> Option<&[u8]> {
+        self.identity.as_deref()
+    }
+
+    /// The same key, numbered `key_id` in every frame it seals.
+    pub fn with_key_id(self, key_id: u32) -> Psk {
+        Psk {
+            key_id: Some(key_id),
+            ..self
+        }
+    }
+
+    /// The id frames sealed with this key carry, if it has one.
+    pub fn key_id(&self) -> Option<u32> {
+        self.key_id
+    }
+
+    /// Bytes sealing adds to a frame: the nonce, the identity, the key id and the tag.
+    pub fn overhead(&self) -> usize {
+        let identity = self
+            .identity
+            .as_ref()
+            .map_or(0, |identity| 1 + identity.len());
+        let key_id = self.key_id.map_or(0, |_| KEY_ID_LEN);
+        NONCE_LEN + identity + key_id + Psk::TAG_LEN
+    }
+
+    /// The key `conf` selects: `psk` if set, otherwise the contents of `psk_path`. `None` unless
+    /// `security` is `psk`. It is numbered `psk_key_id`, if set.
+    pub fn from_config(conf: &Config) -> io::Result<Option<Psk>> {
+        if conf.security != SecurityMode::Psk {
+            return Ok(None);
+        }
+        let key = match (conf.psk.is_empty(), conf.psk_path.is_empty()) {
+            (false, _) => decode_hex(conf.psk.trim()),
+            (true, false) => {
+                let contents = fs::read(&conf.psk_path)?;
+                match contents.len() {
+                    KEY_LEN => contents.try_into().ok(),
+                    _ => std::str::from_utf8(&contents)
+                        .ok()
+                        .and_then(|hex| decode_hex(hex.trim())),
+                }
+            }
+            (true, true) => {
+                return Err(
+                    Error::config("CRUMB_SECURITY=psk needs CRUMB_PSK or CRUMB_PSK_PATH").into(),
+                )
+            }
+        };
+        let psk = key
+            .map(|key| Psk::new(&key))
+            .ok_or_else(|| Error::config("Pre-shared key must be 32 bytes, hex encoded or raw"))?;
+        let psk = match conf.psk_identity.as_str() {
+            "" => psk,
+            identity => psk.with_identity(identity.as_bytes())?,
+        };
+        match conf.psk_key_id {
+            0 => Ok(Some(psk)),
+            key_id => Ok(Some(psk.with_key_id(key_id))),
+        }
+    }
+
+    /// Encrypts `frame`'s payload in place under a fresh nonce.
+    pub fn seal(&self, frame: &mut Frame) {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        frame.nonce = Some(nonce.into());
+        frame.identity = self.identity.clone();
+        frame.key_id = self.key_id;
+        let aad = frame.associated_data();
+        frame.payload = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &frame.payload,
+                    aad: &aad,
+                },
+            )
+            .expect("XChaCha20-Poly1305 encryption is infallible for frame-sized payloads");
+    }
+
+    /// Decrypts `frame`'s payload in place, failing if the frame is not encrypted or was not
+    /// sealed with this key, or its header or payload were altered.
+    pub fn open(&self, frame: &mut Frame) -> io::Result<()> {
+        let nonce = frame
+            .nonce
+            .ok_or_else(|| Error::protocol("Frame is not encrypted"))?;
+        let aad = frame.associated_data();
+        frame.payload = self
+            .cipher
+            .decrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: &frame.payload,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| Error::protocol("Frame failed authentication"))?;
+        Ok(())
+    }
+}
+
+/// The callback a server finds the keys of identified frames with. Unset, it finds none.
+#[derive(Default)]
+pub(crate) struct PskLookup {
+    lookup: RwLock<Option<Lookup>>,
+}
+
+impl PskLookup {
+    pub(crate) fn set(&self, lookup: Lookup) {
+        *self.lookup.write().unwrap_or_else(|e| e.into_inner()) = Some(lookup);
+    }
+
+    pub(crate) fn is_set(&self) -> bool {
+        self.lookup
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_some()
+    }
+
+    /// The key named `identity`, if the callback knows it.
+    pub(crate) fn find(&self, identity: &[u8]) -> Option<Psk> {
+        let lookup = self.lookup.read().unwrap_or_else(|e| e.into_inner());
+        let key = lookup.as_ref()?(identity)?;
+        Psk::new(&key).with_identity(identity).ok()
+    }
+}
+
+pub(crate) fn decode_hex(hex: &str) -> Option<[u8; KEY_LEN]> {
+    if hex.len() != KEY_LEN * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0u8; KEY_LEN];
+    for (byte, pair) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn psk_conf(psk: &str, psk_path: &str) -> Config {
+        Config {
+            security: SecurityMode::Psk,
+            psk: psk.to_string(),
+            psk_path: psk_path.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sealed_frames_open_only_unaltered_and_with_the_same_key() -> io::Result<()> {
+        let psk = Psk::from_config(&psk_conf(KEY, ""))?.unwrap();
+        let mut frame = Frame {
+            seq: 7,
+            ..Frame::new(b"secret".to_vec())
+        };
+        psk.seal(&mut frame);
+        assert_ne!(frame.payload, b"secret");
+
+        let sealed = Frame::from_bytes(&frame.to_bytes()).unwrap();
+        let mut opened = sealed.clone();
+        psk.open(&mut opened)?;
+        assert_eq!(opened.payload, b"secret");
+
+        let mut altered = Frame {
+            seq: 8,
+            ..sealed.clone()
+        };
+        assert!(psk.open(&mut altered).is_err());
+        let other = Psk::new(&[7; KEY_LEN]);
+        assert!(other.open(&mut sealed.clone()).is_err());
+        assert!(psk.open(&mut Frame::new(b"plain".to_vec())).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn keys_load_from_config_or_file() -> io::Result<()> {
+        assert!(Psk::from_config(&Config::default())?.is_none());
+        assert!(Psk::from_config(&psk_conf("", "")).is_err());
+        assert!(Psk::from_config(&psk_conf("abcd", "")).is_err());
+        assert!(Psk::from_config(&psk_conf(&KEY.replace('0', "g"), "")).is_err());
+
+        let path = env::temp_dir().join(format!("crumb-{}.key", std::process::id()));
+        let path_str = path.to_string_lossy().into_owned();
+        fs::write(&path, format!("{}\n", KEY))?;
+        assert!(Psk::from_config(&psk_conf("", &path_str))?.is_some());
+        fs::write(&path, [9; KEY_LEN])?;
+        assert!(Psk::from_config(&psk_conf("", &path_str))?.is_some());
+        fs::remove_file(&path)
+    }
+
+    #[test]
+    fn identities_name_the_key_in_sealed_frames() -> io::Result<()> {
+        let psk = Psk::from_config(&Config {
+            psk_identity: "sensor-7".to_string(),
+            ..psk_conf(KEY, "")
+        })?
+        .unwrap();
+        let mut frame = Frame::new(b"secret".to_vec());
+        psk.seal(&mut frame);
+        let mut sealed = Frame::from_bytes(&frame.to_bytes()).unwrap();
+        assert_eq!(sealed.identity.as_deref(), Some(&b"sensor-7"[..]));
+
+        let lookup = PskLookup::default();
+        assert!(lookup.find(b"sensor-7").is_none());
+        let key = decode_hex(KEY).unwrap();
+        lookup.set(Box::new(move |identity| {
+            (identity == b"sensor-7").then_some(key)
+        }));
+        assert!(lookup.find(b"sensor-8").is_none());
+        let found = lookup.find(b"sensor-7").unwrap();
+        assert_eq!(found.identity(), psk.identity());
+        found.open(&mut sealed)?;
+        assert_eq!(sealed.payload, b"secret");
+
+        let mut renamed = Frame {
+            identity: Some(b"sensor-8".to_vec()),
+            ..Frame::from_bytes(&frame.to_bytes()).unwrap()
+        };
+        assert!(found.open(&mut renamed).is_err());
+        assert!(Psk::new(&key).with_identity(b"").is_err());
+        assert!(Psk::new(&key).with_identity(&[b'x'; 256]).is_err());
+        Ok(())
+    }
+}
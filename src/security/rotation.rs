@@ -0,0 +1,157 @@
+//! Pre-shared key rotation. A key numbered with `psk_key_id` names its id in every frame it
+//! seals, and peers that also hold the keys in `psk_keys` open frames sealed with any of them,
+//! so a fleet can move to a new key one node at a time:
+//!
+//! 1. Add the new key, with a larger id, to `psk_keys` everywhere.
+//! 2. Make it the `psk` and `psk_key_id` of each node in turn, moving the old key to
+//!    `psk_keys`.
+//! 3. Drop the old key from `psk_keys`.
+//!
+//! A key stops opening frames once a key with a larger id has been in use for `psk_key_grace`,
+//! here or by a peer, so a leaked old key is of little use even where step 3 is late.
+
+use super::{decode_hex, Psk};
+use crate::error::Error;
+use crate::util::config::{Config, SecurityMode};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+const TARGET: &str = "crumb::security::rotation";
+
+/// The keys a session accepts besides its own, by id, while keys are rotated.
+#[derive(Debug)]
+pub struct KeyRing {
+    keys: HashMap<u32, Arc<Psk>>,
+    grace: Duration,
+    // When each key id was first in use: the session's own from the start, others from the
+    // first frame they opened.
+    first_used: Mutex<BTreeMap<u32, Instant>>,
+}
+
+impl KeyRing {
+    /// The keys `psk_keys` lists, alongside the session's own numbered `current`.
+    pub fn new(current: u32, keys: HashMap<u32, Arc<Psk>>, grace: Duration) -> KeyRing {
+        KeyRing {
+            keys,
+            grace,
+            first_used: Mutex::new(BTreeMap::from([(current, Instant::now())])),
+        }
+    }
+
+    /// The ring `conf` selects. `None` unless `security` is `psk` and `psk_key_id` is set.
+    pub fn from_config(conf: &Config) -> io::Result<Option<KeyRing>> {
+        if conf.security != SecurityMode::Psk || conf.psk_key_id == 0 {
+            return Ok(None);
+        }
+        let mut keys = HashMap::new();
+        for (id, key) in parse_keys(&conf.psk_keys)? {
+            let psk = match conf.psk_identity.as_str() {
+                "" => Psk::new(&key),
+                identity => Psk::new(&key).with_identity(identity.as_bytes())?,
+            };
+            keys.insert(id, Arc::new(psk.with_key_id(id)));
+        }
+        Ok(Some(KeyRing::new(
+            conf.psk_key_id,
+            keys,
+            conf.psk_key_grace,
+        )))
+    }
+
+    /// The key numbered `id`, unless the ring lacks it or it has expired by `now`.
+    pub fn find(&self, id: u32, now: Instant) -> Option<Arc<Psk>> {
+        let key = self.keys.get(&id)?;
+        let first_used = self.first_used.lock().unwrap_or_else(|e| e.into_inner());
+        let superseded = first_used
+            .range(id.saturating_add(1)..)
+            .any(|(_, since)| now.saturating_duration_since(*since) > self.grace);
+        match superseded {
+            true => {
+                debug!(target: TARGET, id, "pre-shared key expired");
+                None
+            }
+            false => Some(key.clone()),
+        }
+    }
+
+    /// Notes that the key numbered `id` opened a frame at `now`, starting the grace period of
+    /// every key older than it.
+    pub fn opened(&self, id: u32, now: Instant) {
+        let mut first_used = self.first_used.lock().unwrap_or_else(|e| e.into_inner());
+        first_used.entry(id).or_insert(now);
+    }
+}
+
+/// Parses a comma separated list of `ID:KEY`, the key hex encoded, failing on a malformed entry,
+/// an id of 0 or one listed twice.
+pub(crate) fn parse_keys(list: &str) -> io::Result<Vec<(u32, [u8; super::KEY_LEN])>> {
+    let mut keys: Vec<(u32, [u8; super::KEY_LEN])> = Vec::new();
+    for entry in list
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let (id, key) = entry
+            .split_once(':')
+            .and_then(|(id, key)| Some((id.trim().parse::<u32>().ok()?, key)))
+            .filter(|(id, _)| *id != 0)
+            .ok_or_else(|| Error::config(format!("Key ids must be 1 or more: {}", entry)))?;
+        let key = decode_hex(key.trim())
+            .ok_or_else(|| Error::config(format!("Key {} must be 32 bytes, hex encoded", id)))?;
+        if keys.iter().any(|(listed, _)| *listed == id) {
+            return Err(Error::config(format!("Key {} is listed twice", id)).into());
+        }
+        keys.push((id, key));
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Frame;
+    use crate::security::KEY_LEN;
+
+    #[test]
+    fn older_keys_expire_once_a_newer_one_is_in_use() {
+        let grace = Duration::from_secs(60);
+        let keys = [1, 3]
+            .into_iter()
+            .map(|id| (id, Arc::new(Psk::new(&[id as u8; KEY_LEN]).with_key_id(id))))
+            .collect();
+        let ring = KeyRing::new(2, keys, grace);
+        let start = Instant::now();
+        assert!(ring.find(4, start).is_none());
+        assert!(ring.find(1, start).is_some());
+        assert!(ring.find(3, start + grace * 2).is_some());
+        assert!(ring.find(1, start + grace * 2).is_none());
+
+        let opened = start + grace * 3;
+        ring.opened(3, opened);
+        assert!(ring.find(3, opened + grace * 2).is_some());
+        let mut frame = Frame::new(b"secret".to_vec());
+        ring.find(3, opened).unwrap().seal(&mut frame);
+        assert_eq!(frame.key_id, Some(3));
+    }
+
+    #[test]
+    fn key_lists_parse() -> io::Result<()> {
+        let key = "ab".repeat(KEY_LEN);
+        let keys = parse_keys(&format!("1:{}, 7 : {} ,", key, key))?;
+        assert_eq!(keys, [(1, [0xab; KEY_LEN]), (7, [0xab; KEY_LEN])]);
+        assert!(parse_keys("").unwrap().is_empty());
+        for list in [
+            format!("0:{}", key),
+            format!("x:{}", key),
+            "1:abcd".to_string(),
+            key.clone(),
+            format!("2:{},2:{}", key, key),
+        ] {
+            assert!(parse_keys(&list).is_err(), "{}", list);
+        }
+        Ok(())
+    }
+}
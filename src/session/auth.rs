@@ -0,0 +1,263 @@
+//! Token authentication. A client with an `auth_token` presents it as soon as its session
+//! opens, and a server that registered a verifier with `Server::on_auth` takes no messages from
+//! a client until the verifier has accepted its token. Once it has, the client stays in and the
+//! tokens it presents later are not checked again.
+//!
+//! The token is the payload of an unreliable session message marked with the `AUTH` extension.
+//! The server answers with another whose payload is one status byte: 0 if the token was
+//! accepted, 1 if it was rejected, or 2 if the client sent messages before it authenticated,
+//! as it does to a server that restarted, to which a client holding a token answers by
+//! presenting it again. Messages from clients yet to authenticate, streams and control requests
+//! included, are dropped unacknowledged, so reliable ones are sent again once the client is in.
+//! Pings, probes, acknowledgements, window updates and node ID announcements pass, as they
+//! carry nothing for the application.
+//!
+//! The token is sent as it is, readable on the path unless the session is sealed with a
+//! pre-shared key. A token derived for each client, such as an HMAC of its name, limits what an
+//! overheard one gives away.
+
+use super::{lock, Client, ClientShared, PeerInfo, Server, ServerShared, TARGET};
+use crate::error::Error;
+use crate::protocol::{Frame, Priority};
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use tracing::{debug, trace};
+
+// How long a client waits for the server's verdict on its token.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How often a client presents its token again while no verdict arrives.
+const AUTH_RETRY: Duration = Duration::from_millis(500);
+
+const ACCEPTED: u8 = 0;
+const REJECTED: u8 = 1;
+pub(super) const REQUIRED: u8 = 2;
+
+type Verify = Box<dyn Fn(&[u8], &PeerInfo) -> bool + Send + Sync>;
+
+/// The callback a server checks tokens with. Unset, every client is taken without one.
+#[derive(Default)]
+pub(super) struct Verifier {
+    verify: RwLock<Option<Verify>>,
+    rejected: AtomicU64,
+}
+
+impl Verifier {
+    pub(super) fn is_set(&self) -> bool {
+        self.verify
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_some()
+    }
+
+    pub(super) fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    fn accepts(&self, token: &[u8], peer: &PeerInfo) -> bool {
+        let verify = self.verify.read().unwrap_or_else(|e| e.into_inner());
+        let accepted = verify.as_ref().is_none_or(|verify| verify(token, peer));
+        if !accepted {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        accepted
+    }
+}
+
+/// The server's verdict, or its demand for a token.
+pub(super) fn verdict(status: u8) -> Frame {
+    Frame {
+        auth: true,
+        priority: Priority::Control,
+        ..Frame::new(vec![status])
+    }
+}
+
+impl Server {
+    /// Registers `verify` to check the token every client presents, replacing any earlier
+    /// callback. It is given the token and who sent it, and returns whether to take the client's
+    /// messages. Until it accepts one, messages from a client are dropped, and `Client::init`
+    /// fails with `Error::AuthFailed` on a rejected token. Clients that presented a token before
+    /// a callback was registered stay in; others are asked for one.
+    pub fn on_auth<F>(&self, verify: F)
+    where
+        F: Fn(&[u8], &PeerInfo) -> bool + Send + Sync + 'static,
+    {
+        *self
+            .shared
+            .verifier
+            .verify
+            .write()
+            .unwrap_or_else(|e| e.into_inner()) = Some(Box::new(verify));
+    }
+
+    /// Tokens the verifier has rejected.
+    pub fn auth_rejected(&self) -> u64 {
+        self.shared.verifier.rejected()
+    }
+}
+
+impl ServerShared {
+    /// Checks the token `source` presented and tells it the verdict. A client stays in once a
+    /// token of its has been accepted, so tokens it presents later, bad ones included, are
+    /// answered as accepted without being checked.
+    pub(super) fn authenticate(&self, source: SocketAddr, token: &[u8]) {
+        let mut peers = lock(&self.peers);
+        let Some(peer) = peers.get_mut(&source) else {
+            return;
+        };
+        if !peer.authenticated {
+            peer.authenticated = self.verifier.accepts(token, &peer.peer_info(source));
+        }
+        let status = match peer.authenticated {
+            true => {
+                debug!(target: TARGET, %source, "client authenticated");
+                ACCEPTED
+            }
+            false => {
+                debug!(target: TARGET, %source, "client token rejected");
+                REJECTED
+            }
+        };
+        let packet = peer.seal(verdict(status));
+        drop(peers);
+        if let Err(e) = self.server.send_to(&packet, source) {
+            debug!(target: TARGET, %source, error = %e, "auth verdict failed");
+        }
+    }
+}
+
+impl Client {
+    /// Presents `auth_token`, if there is one, until the server gives its verdict.
+    pub(super) fn authenticate(&self) -> io::Result<()> {
+        if self.shared.conf.auth_token.is_empty() {
+            return Ok(());
+        }
+        let deadline = Instant::now() + AUTH_TIMEOUT;
+        loop {
+            *lock(&self.shared.verdict) = None;
+            self.shared.present_token();
+            // The worker notifies after handling every packet, verdicts included.
+            let wait = AUTH_RETRY.min(deadline.saturating_duration_since(Instant::now()));
+            let state = lock(&self.shared.state);
+            let (state, _) = self
+                .shared
+                .queue_space
+                .wait_timeout_while(state, wait, |_| lock(&self.shared.verdict).is_none())
+                .unwrap_or_else(|e| e.into_inner());
+            drop(state);
+            match lock(&self.shared.verdict).take() {
+                Some(true) => return Ok(()),
+                Some(false) => return Err(Error::AuthFailed.into()),
+                None if Instant::now() >= deadline => {
+                    return Err(io::Error::new(
+                        ErrorKind::TimedOut,
+                        "No answer to the authentication request",
+                    ))
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+impl ClientShared {
+    /// Takes in the server's verdict on our token, or presents it again when the server asks.
+    pub(super) fn auth_answer(&self, payload: &[u8]) {
+        match payload {
+            [ACCEPTED] => *lock(&self.verdict) = Some(true),
+            [REJECTED] => *lock(&self.verdict) = Some(false),
+            [REQUIRED] if !self.conf.auth_token.is_empty() => {
+                trace!(target: TARGET, "server asked for the token again");
+                self.present_token();
+            }
+            _ => debug!(target: TARGET, "dropping auth message"),
+        }
+    }
+
+    fn present_token(&self) {
+        let frame = Frame {
+            auth: true,
+            priority: Priority::Control,
+            ..Frame::new(self.conf.auth_token.as_bytes().to_vec())
+        };
+        let packet = lock(&self.state).seal(frame);
+        if let Err(e) = self.transport().send(&packet) {
+            debug!(target: TARGET, error = %e, "presenting the token failed");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::PeerState;
+    use crate::util::config::Config;
+    use std::sync::Arc;
+
+    #[test]
+    fn messages_wait_for_an_accepted_token() {
+        let verifier = Arc::new(Verifier::default());
+        let mut state = PeerState::new(&Config::default()).with_verifier(verifier.clone());
+        let message = Frame::new(b"early".to_vec()).to_bytes();
+        assert_eq!(state.incoming(&message).0.len(), 1);
+
+        *verifier.verify.write().unwrap() = Some(Box::new(|token, _| token == b"secret"));
+        let (messages, reply) = state.incoming(&message);
+        assert!(messages.is_empty());
+        let reply = Frame::from_bytes(&reply.unwrap()).unwrap();
+        assert!(reply.auth);
+        assert_eq!(reply.payload, [REQUIRED]);
+        assert_eq!(state.metrics().dropped_unverified, 1);
+
+        let peer = state.peer_info("127.0.0.1:1".parse().unwrap());
+        assert!(!verifier.accepts(b"guess", &peer));
+        assert!(verifier.accepts(b"secret", &peer));
+        assert_eq!(verifier.rejected.load(Ordering::Relaxed), 1);
+        state.authenticated = true;
+        assert_eq!(state.incoming(&message).0.len(), 1);
+    }
+
+    #[test]
+    fn clients_present_their_token_when_they_connect() -> io::Result<()> {
+        let conf = Config {
+            host: "127.0.0.1".to_string(),
+            port: 8146,
+            bind_host: "127.0.0.1".to_string(),
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        server.on_auth(|token, _| token == b"letmein");
+        server.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        let e = Client::init(&Config {
+            auth_token: "guess".to_string(),
+            ..conf.clone()
+        })
+        .map_err(Error::from)
+        .err();
+        assert!(matches!(e, Some(Error::AuthFailed)), "{:?}", e);
+        assert_eq!(server.auth_rejected(), 1);
+
+        let client = Client::init(&Config {
+            auth_token: "letmein".to_string(),
+            ..conf.clone()
+        })?;
+        client.send(b"hello")?;
+        let mut buffer = [0u8; 16];
+        let (received, source) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"hello");
+
+        // A bad token after an accepted one leaves the client in.
+        server.shared.authenticate(source, b"guess");
+        assert!(lock(&server.shared.peers)[&source].authenticated);
+        assert_eq!(server.auth_rejected(), 1);
+        client.send(b"again")?;
+        let (received, _) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"again");
+        Ok(())
+    }
+}
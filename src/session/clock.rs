@@ -0,0 +1,199 @@
+//! Clock offsets. Every ping a session sends carries its wall clock reading, and the peer's pong
+//! hands it back along with the peer's own readings as the ping arrived and as the pong left,
+//! as an NTP exchange does. From the four times the session estimates how far the peer's clock
+//! is from its own, so that the one-way delay of stamped messages can be measured without the
+//! clocks being synchronised by other means. Queueing on either leg skews an estimate by half
+//! the extra delay, so, as NTP's clock filter does, the estimate from the quickest of the last
+//! few exchanges is the one used.
+//!
+//! Keepalives and `Client::ping` make exchanges as they go. With `clock_sync_interval` set, a
+//! session also pings its peer at that interval to keep the estimate fresh.
+//!
+//! Readings are microseconds since the Unix epoch, as 8 byte big endian integers. A ping
+//! carries its sender's, and the pong the three in the order taken. Pings without a reading are
+//! answered with an empty pong, and pongs without readings give no estimate, so peers that
+//! predate the exchange still answer pings.
+
+use super::{Client, PeerState, Server};
+use crate::protocol::Frame;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Instant, SystemTime};
+
+const READING_LEN: usize = 8;
+
+// Exchanges the estimate is chosen from.
+const SAMPLES: usize = 8;
+
+fn micros(time: SystemTime) -> i64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as i64
+}
+
+fn readings(payload: &[u8]) -> impl Iterator<Item = i64> + '_ {
+    payload
+        .chunks_exact(READING_LEN)
+        .map(|reading| i64::from_be_bytes(reading.try_into().unwrap()))
+}
+
+/// Ping number `id`, carrying the clock reading for an exchange.
+pub(super) fn ping(id: u32) -> Frame {
+    Frame {
+        payload: micros(SystemTime::now()).to_be_bytes().to_vec(),
+        ..Frame::ping(id)
+    }
+}
+
+/// The pong to a ping that arrived at `arrived`, handing back its reading with ours.
+pub(super) fn pong(ping: &Frame, arrived: SystemTime) -> Frame {
+    let mut payload = Vec::new();
+    if ping.payload.len() == READING_LEN {
+        payload.extend_from_slice(&ping.payload);
+        payload.extend_from_slice(&micros(arrived).to_be_bytes());
+        payload.extend_from_slice(&micros(SystemTime::now()).to_be_bytes());
+    }
+    Frame {
+        payload,
+        ..Frame::pong(ping.seq)
+    }
+}
+
+/// The offsets measured by the last few exchanges, with their round trips, in microseconds.
+#[derive(Debug, Default)]
+pub(super) struct ClockSync {
+    samples: VecDeque<(i64, i64)>,
+}
+
+impl ClockSync {
+    /// Takes in a pong that arrived at `arrived`.
+    pub(super) fn on_pong(&mut self, payload: &[u8], arrived: SystemTime) {
+        if payload.len() != 3 * READING_LEN {
+            return;
+        }
+        let mut readings = readings(payload);
+        let (sent, received, answered) = (
+            readings.next().unwrap(),
+            readings.next().unwrap(),
+            readings.next().unwrap(),
+        );
+        let arrived = micros(arrived);
+        let offset = ((received - sent) + (answered - arrived)) / 2;
+        let round_trip = (arrived - sent) - (answered - received);
+        if self.samples.len() == SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((round_trip, offset));
+    }
+
+    /// How many microseconds the peer's clock is ahead of ours, negative if behind, once an
+    /// exchange has completed.
+    pub(super) fn offset(&self) -> Option<i64> {
+        self.samples
+            .iter()
+            .min_by_key(|(round_trip, _)| *round_trip)
+            .map(|(_, offset)| *offset)
+    }
+}
+
+impl PeerState {
+    /// The ping due now to keep the clock offset fresh, if `clock_sync_interval` is set and it
+    /// has passed since the last one.
+    pub(super) fn clock_ping(&mut self, now: Instant) -> Option<Vec<u8>> {
+        if self.clock_sync_interval.is_zero()
+            || self
+                .last_clock_ping
+                .is_some_and(|last| now.saturating_duration_since(last) < self.clock_sync_interval)
+        {
+            return None;
+        }
+        self.last_clock_ping = Some(now);
+        if let Some(id) = self.clock_ping.take() {
+            self.take_pong(id);
+        }
+        let (id, packet) = self.ping(now);
+        self.clock_ping = Some(id);
+        Some(packet)
+    }
+}
+
+// Seconds, from microseconds.
+fn seconds(offset: i64) -> f64 {
+    offset as f64 / 1e6
+}
+
+impl Client {
+    /// How many seconds the server's clock is ahead of this one's, negative if it is behind, as
+    /// estimated from pings. `None` until a ping has been answered by a server that takes part.
+    pub fn clock_offset(&self) -> Option<f64> {
+        super::lock(&self.shared.state).clock.offset().map(seconds)
+    }
+}
+
+impl Server {
+    /// How many seconds the clock of the client at `peer` is ahead of this one's, as
+    /// `Client::clock_offset`.
+    pub fn clock_offset(&self, peer: SocketAddr) -> Option<f64> {
+        super::lock(&self.shared.peers)
+            .get(&peer)
+            .and_then(|state| state.clock.offset())
+            .map(seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::config::Config;
+    use std::time::Duration;
+
+    fn exchange(times: [i64; 4]) -> (Vec<u8>, SystemTime) {
+        let payload = times[..3].iter().flat_map(|t| t.to_be_bytes()).collect();
+        let arrived = SystemTime::UNIX_EPOCH + Duration::from_micros(times[3] as u64);
+        (payload, arrived)
+    }
+
+    #[test]
+    fn the_quickest_exchange_sets_the_offset() {
+        let mut clock = ClockSync::default();
+        assert_eq!(clock.offset(), None);
+        // The peer's clock is 5 ms ahead, with 10 ms each way, then 30 ms on the way back.
+        for times in [
+            [0, 15_000, 16_000, 21_000],
+            [100_000, 115_000, 116_000, 141_000],
+        ] {
+            let (payload, arrived) = exchange(times);
+            clock.on_pong(&payload, arrived);
+        }
+        assert_eq!(clock.offset(), Some(5_000));
+        clock.on_pong(&[], SystemTime::now());
+        assert_eq!(clock.samples.len(), 2);
+    }
+
+    #[test]
+    fn pongs_carry_the_readings() {
+        let conf = Config {
+            clock_sync_interval: Duration::from_secs(10),
+            ..Default::default()
+        };
+        let mut sender = PeerState::new(&conf);
+        let mut receiver = PeerState::new(&Config::default());
+        let now = Instant::now();
+        let ping = sender.clock_ping(now).unwrap();
+        assert!(sender.clock_ping(now + Duration::from_secs(5)).is_none());
+        let (_, pong) = receiver.incoming(&ping);
+        sender.incoming(&pong.unwrap());
+        // Both ends share a clock here.
+        let offset = sender.stats().clock_offset.unwrap();
+        assert!(offset.abs() < 0.1, "{}", offset);
+        assert!(receiver.stats().clock_offset.is_none());
+        assert!(sender.clock_ping(now + Duration::from_secs(10)).is_some());
+
+        // Pings from peers that predate readings get an empty pong.
+        let (_, pong) = receiver.incoming(&Frame::ping(7).to_bytes());
+        assert!(Frame::from_bytes(&pong.unwrap())
+            .unwrap()
+            .payload
+            .is_empty());
+    }
+}
@@ -0,0 +1,170 @@
+//! Frame codecs: a user-supplied transform applied to every message payload on its way to the
+//! wire and back, for proprietary compression, encryption or signing without a fork of the
+//! session. The codec is a stage of the session's pipeline, after compression unless `pipeline`
+//! says otherwise. Frames say whether their payload was encoded, and receivers without a codec
+//! drop those that were, so both ends must register codecs that undo each other.
+//!
+//! Stream chunks, authentication, node announcements and control requests are left alone, as
+//! interceptors leave them.
+
+use super::{Client, Server};
+use crate::error::Error;
+use crate::protocol::{Frame, Priority};
+use crate::util::config::PayloadFormat;
+use std::io;
+use std::sync::RwLock;
+
+/// A transform of message payloads, registered with `Client::set_codec` or `Server::set_codec`.
+/// It is called on the threads that send and on the session's worker, so it should not block.
+pub trait FrameCodec: Send + Sync {
+    /// Encodes the payload of a message about to be sent.
+    fn encode(&self, payload: Vec<u8>, context: &CodecContext) -> io::Result<Vec<u8>>;
+
+    /// Reverses `encode` for the payload of a message that arrived encoded. Messages it fails
+    /// on are dropped and counted as corrupt.
+    fn decode(&self, payload: Vec<u8>, context: &CodecContext) -> io::Result<Vec<u8>>;
+}
+
+/// What a codec is told about the message whose payload it transforms: the header fields the
+/// payload travels with, which are the same on both ends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CodecContext {
+    pub format: PayloadFormat,
+    pub priority: Priority,
+    /// The schema version stamped on protobuf messages, 0 for others.
+    pub schema: u32,
+}
+
+impl CodecContext {
+    fn of(frame: &Frame) -> CodecContext {
+        CodecContext {
+            format: frame.format,
+            priority: frame.priority,
+            schema: frame.schema,
+        }
+    }
+}
+
+/// The codec registered with a client or server, shared with its sessions.
+#[derive(Default)]
+pub(super) struct Codec(RwLock<Option<Box<dyn FrameCodec>>>);
+
+impl Codec {
+    pub(super) fn set(&self, codec: Box<dyn FrameCodec>) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = Some(codec);
+    }
+
+    /// Encodes the payload of `frame` and marks it encoded, if a codec is registered.
+    pub(super) fn encode(&self, frame: &mut Frame) -> io::Result<()> {
+        let codec = self.0.read().unwrap_or_else(|e| e.into_inner());
+        if let Some(codec) = &*codec {
+            let payload = std::mem::take(&mut frame.payload);
+            frame.payload = codec.encode(payload, &CodecContext::of(frame))?;
+            frame.encoded = true;
+        }
+        Ok(())
+    }
+
+    /// Decodes the payload of an encoded `frame`.
+    pub(super) fn decode(&self, frame: &mut Frame) -> io::Result<()> {
+        let codec = self.0.read().unwrap_or_else(|e| e.into_inner());
+        let Some(codec) = &*codec else {
+            return Err(Error::codec("Encoded message but no codec registered").into());
+        };
+        let payload = std::mem::take(&mut frame.payload);
+        frame.payload = codec.decode(payload, &CodecContext::of(frame))?;
+        frame.encoded = false;
+        Ok(())
+    }
+}
+
+impl Client {
+    /// Registers `codec` to encode the payload of every message the application sends and decode
+    /// those from the server, replacing any earlier one. Register it before sending, and only
+    /// with a server whose codec undoes it.
+    pub fn set_codec<C: FrameCodec + 'static>(&self, codec: C) {
+        self.shared.codec.set(Box::new(codec));
+    }
+}
+
+impl Server {
+    /// Registers `codec` for every client's messages, as `Client::set_codec`.
+    pub fn set_codec<C: FrameCodec + 'static>(&self, codec: C) {
+        self.shared.codec.set(Box::new(codec));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::PeerState;
+    use crate::util::config::Config;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    // XORs every byte with the key, refusing payloads that end in it.
+    struct Xor(u8);
+
+    impl FrameCodec for Xor {
+        fn encode(&self, payload: Vec<u8>, _: &CodecContext) -> io::Result<Vec<u8>> {
+            Ok(payload.into_iter().map(|b| b ^ self.0).collect())
+        }
+
+        fn decode(&self, payload: Vec<u8>, context: &CodecContext) -> io::Result<Vec<u8>> {
+            if payload.last() == Some(&self.0) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "bad payload"));
+            }
+            self.encode(payload, context)
+        }
+    }
+
+    #[test]
+    fn payloads_cross_encoded() {
+        let codec = Arc::new(Codec::default());
+        codec.set(Box::new(Xor(0x5a)));
+        let mut sender = PeerState::new(&Config::default());
+        let mut receiver = PeerState::new(&Config::default()).with_codec(codec.clone());
+        let mut frame = Frame::new(b"secret".to_vec());
+        codec.encode(&mut frame).unwrap();
+        assert!(frame.encoded);
+        assert_ne!(frame.payload, b"secret");
+        let (messages, _) = receiver.incoming(&sender.outgoing(frame));
+        assert_eq!(messages[0].payload, b"secret");
+
+        // Messages not marked encoded pass as they are, and undecodable ones are dropped.
+        let (messages, _) = receiver.incoming(&sender.outgoing(Frame::new(vec![0x5a])));
+        assert_eq!(messages[0].payload, [0x5a]);
+        let undecodable = Frame {
+            encoded: true,
+            ..Frame::new(vec![0x5a])
+        };
+        let (messages, _) = receiver.incoming(&sender.outgoing(undecodable));
+        assert!(messages.is_empty());
+        assert_eq!(receiver.metrics().dropped_corrupt, 1);
+    }
+
+    #[test]
+    fn clients_and_servers_share_a_codec() -> io::Result<()> {
+        let conf = Config {
+            host: "127.0.0.1".to_string(),
+            port: 8149,
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        server.set_codec(Xor(0x21));
+        server.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let client = Client::init(&conf)?;
+        client.set_codec(Xor(0x21));
+        client.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        client.send(b"ping")?;
+        let mut buffer = [0u8; 16];
+        let (len, source) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..len], b"ping");
+        server.send_to(b"pong", source)?;
+        let len = client.receive(&mut buffer)?;
+        assert_eq!(&buffer[..len], b"pong");
+        Ok(())
+    }
+}
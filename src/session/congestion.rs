@@ -0,0 +1,223 @@
+use std::time::{Duration, Instant};
+
+const INITIAL_WINDOW: usize = 8;
+const MAX_WINDOW: usize = 1024;
+
+// The retransmission timeout before any round trip has been measured. RFC 6298 suggests one
+// second; crumb mostly runs on local networks, so it starts lower.
+pub(crate) const INITIAL_RTO: Duration = Duration::from_millis(200);
+
+// Bounds on the retransmission timeout. The floor keeps delayed acknowledgements from causing
+// spurious retransmissions; the ceiling is RFC 6298's.
+const MIN_RTO: Duration = Duration::from_millis(50);
+const MAX_RTO: Duration = Duration::from_secs(60);
+
+// The clock granularity G of RFC 6298: retransmission timers are checked once per poll.
+const GRANULARITY: Duration = Duration::from_millis(20);
+
+/// Limits the average send rate while allowing short bursts.
+///
+/// A send is allowed whenever the bucket is not in debt, and takes its full size from it, so a
+/// single message larger than the burst still goes out but delays the ones after it.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    // Bytes per second. Zero disables limiting.
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(max_rate_kbps: u32) -> TokenBucket {
+        let rate = f64::from(max_rate_kbps) * 1000.0 / 8.0;
+        // A tenth of a second of traffic may be sent back to back.
+        let burst = rate / 10.0;
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Whether the bucket has repaid any debt, so another send may go out.
+    pub(crate) fn ready(&mut self, now: Instant) -> bool {
+        if self.rate == 0.0 {
+            return true;
+        }
+
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled_at = now;
+        self.tokens >= 0.0
+    }
+
+    /// Takes `bytes` from the bucket, leaving it in debt if it holds fewer.
+    pub(crate) fn take(&mut self, bytes: usize) {
+        if self.rate > 0.0 {
+            self.tokens -= bytes as f64;
+        }
+    }
+}
+
+/// Additive-increase/multiplicative-decrease control of how many reliable messages may be
+/// awaiting acknowledgement at once.
+///
+/// The window grows by one message per window's worth of acknowledgements and halves when a
+/// retransmission timer fires.
+#[derive(Debug)]
+pub(crate) struct Aimd {
+    window: usize,
+    acked: usize,
+}
+
+impl Aimd {
+    pub(crate) fn new() -> Aimd {
+        Aimd {
+            window: INITIAL_WINDOW,
+            acked: 0,
+        }
+    }
+
+    pub(crate) fn window(&self) -> usize {
+        self.window
+    }
+
+    pub(crate) fn on_ack(&mut self) {
+        self.acked += 1;
+        if self.acked >= self.window {
+            self.acked = 0;
+            self.window = (self.window + 1).min(MAX_WINDOW);
+        }
+    }
+
+    pub(crate) fn on_loss(&mut self) {
+        self.acked = 0;
+        self.window = (self.window / 2).max(1);
+    }
+}
+
+/// Round-trip time estimation and the retransmission timeout derived from it, as in RFC 6298.
+#[derive(Debug)]
+pub(crate) struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+}
+
+impl RttEstimator {
+    pub(crate) fn new() -> RttEstimator {
+        RttEstimator {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+        }
+    }
+
+    /// The smoothed round-trip time, once one has been measured.
+    pub(crate) fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// How long a reliable message waits for its acknowledgement before it is sent again.
+    pub(crate) fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Takes a round-trip measurement. Measurements must not come from retransmitted messages,
+    /// whose acknowledgements are ambiguous (Karn's algorithm).
+    pub(crate) fn on_sample(&mut self, rtt: Duration) {
+        let (srtt, rttvar) = match self.srtt {
+            None => (rtt, rtt / 2),
+            Some(srtt) => {
+                let deviation = srtt.abs_diff(rtt);
+                (srtt * 7 / 8 + rtt / 8, self.rttvar * 3 / 4 + deviation / 4)
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rttvar = rttvar;
+        self.rto = (srtt + GRANULARITY.max(rttvar * 4)).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// Backs the timeout off after the timer fires, until the next measurement.
+    pub(crate) fn on_timeout(&mut self) {
+        self.rto = (self.rto * 2).min(MAX_RTO);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlimited_bucket_is_always_ready() {
+        let mut bucket = TokenBucket::new(0);
+        bucket.take(1 << 20);
+        assert!(bucket.ready(Instant::now()));
+    }
+
+    #[test]
+    fn bucket_paces_sends_beyond_the_burst() {
+        // 80 kbps is 10,000 bytes per second with a 1,000 byte burst.
+        let mut bucket = TokenBucket::new(80);
+        let now = bucket.refilled_at;
+        assert!(bucket.ready(now));
+        bucket.take(1_500);
+        assert!(!bucket.ready(now + Duration::from_millis(49)));
+        assert!(bucket.ready(now + Duration::from_millis(51)));
+
+        // The bucket refills no further than the burst.
+        let later = now + Duration::from_secs(1);
+        assert!(bucket.ready(later));
+        bucket.take(1_100);
+        assert!(!bucket.ready(later));
+    }
+
+    #[test]
+    fn window_grows_additively_and_shrinks_multiplicatively() {
+        let mut aimd = Aimd::new();
+        for _ in 0..INITIAL_WINDOW {
+            aimd.on_ack();
+        }
+        assert_eq!(aimd.window(), INITIAL_WINDOW + 1);
+
+        aimd.on_loss();
+        assert_eq!(aimd.window(), 4);
+        for _ in 0..8 {
+            aimd.on_loss();
+        }
+        assert_eq!(aimd.window(), 1);
+    }
+
+    #[test]
+    fn timeout_follows_measured_round_trips() {
+        let mut rtt = RttEstimator::new();
+        assert_eq!((rtt.srtt(), rtt.rto()), (None, INITIAL_RTO));
+
+        rtt.on_sample(Duration::from_millis(100));
+        assert_eq!(rtt.srtt(), Some(Duration::from_millis(100)));
+        assert_eq!(rtt.rto(), Duration::from_millis(300));
+
+        for _ in 0..100 {
+            rtt.on_sample(Duration::from_millis(1));
+        }
+        assert_eq!(rtt.rto(), MIN_RTO);
+    }
+
+    #[test]
+    fn timeouts_back_off_exponentially() {
+        let mut rtt = RttEstimator::new();
+        rtt.on_timeout();
+        assert_eq!(rtt.rto(), INITIAL_RTO * 2);
+        for _ in 0..20 {
+            rtt.on_timeout();
+        }
+        assert_eq!(rtt.rto(), MAX_RTO);
+
+        rtt.on_sample(Duration::from_millis(10));
+        assert_eq!(rtt.rto(), MIN_RTO);
+    }
+}
@@ -0,0 +1,468 @@
+//! The control channel, through which an operator holding `control_key` adjusts and inspects a
+//! running server with `Client::control` or `crumb control`, so that debugging it in
+//! production needs no restart.
+//!
+//! Requests and their answers are session messages marked with the `CONTROL` extension. Their
+//! payload, with integers big endian:
+//!
+//! | Offset | Size | Field   | Meaning                                                                |
+//! |--------|------|---------|------------------------------------------------------------------------|
+//! | 0      | 8    | `id`    | Names the request, and the answer to it.                               |
+//! | 8      | 24   | `nonce` | The XChaCha20-Poly1305 nonce.                                          |
+//! | 32     | -    | `body`  | Sealed under `control_key` with `id` as associated data, then the tag. |
+//!
+//! A request's body is the Unix time it was sent, in seconds (8 bytes), followed by the command
+//! in UTF-8. An answer's body is a status (1 byte, 0 for success and 1 for failure) followed by
+//! its text, JSON on success.
+//!
+//! Servers drop requests that fail authentication, were sent more than 30 seconds away from
+//! their clock, or repeat the id of one taken within that time, and all requests when they have
+//! no `control_key`. They answer none of these, so probing them learns nothing, and count them in
+//! `Server::control_rejected`.
+//!
+//! The commands are:
+//!
+//! - `stats`: the health summary and traffic totals over every session.
+//! - `sessions`: every client heard from, with its traffic statistics.
+//! - `log-level LEVEL`: hands LEVEL, one of `off`, `error`, `warn`, `info`, `debug` or `trace`,
+//!   to the hook `Server::on_log_level` registers.
+
+use super::health::escape;
+use super::{lock, Client, ClientShared, SendQueue, Server, ServerShared, PING_TIMEOUT, TARGET};
+use crate::error::Error;
+use crate::protocol::{Frame, Priority, NONCE_LEN};
+use crate::security::decode_hex;
+use crate::util::config::Config;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::level_filters::LevelFilter;
+use tracing::{debug, info};
+
+// How far a request's timestamp may be from the server's clock, and how long its id is
+// remembered to turn away replays.
+const MAX_SKEW: Duration = Duration::from_secs(30);
+
+const ID_LEN: usize = 8;
+
+const STATUS_OK: u8 = 0;
+const STATUS_FAILED: u8 = 1;
+
+/// Applies a log level an operator asked for.
+pub(super) type LogLevelHook = Box<dyn Fn(LevelFilter) + Send + Sync>;
+
+/// The key control messages are sealed with.
+pub(super) struct ControlKey {
+    cipher: XChaCha20Poly1305,
+}
+
+impl ControlKey {
+    /// The key `control_key` holds, if it is set.
+    pub(super) fn from_config(conf: &Config) -> io::Result<Option<ControlKey>> {
+        if conf.control_key.is_empty() {
+            return Ok(None);
+        }
+        let key = decode_hex(conf.control_key.trim())
+            .ok_or_else(|| Error::config("Control key must be 32 bytes, hex encoded"))?;
+        Ok(Some(ControlKey {
+            cipher: XChaCha20Poly1305::new(&key.into()),
+        }))
+    }
+
+    fn seal(&self, id: u64, body: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = id.to_be_bytes();
+        let sealed = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: body,
+                    aad: &aad,
+                },
+            )
+            .expect("XChaCha20-Poly1305 encryption is infallible for frame-sized payloads");
+        let mut payload = Vec::with_capacity(ID_LEN + NONCE_LEN + sealed.len());
+        payload.extend_from_slice(&aad);
+        payload.extend_from_slice(&nonce);
+        payload.extend_from_slice(&sealed);
+        payload
+    }
+
+    // The id and body of a payload sealed with this key, if it was.
+    fn open(&self, payload: &[u8]) -> Option<(u64, Vec<u8>)> {
+        let aad = payload.get(..ID_LEN)?;
+        let nonce = payload.get(ID_LEN..ID_LEN + NONCE_LEN)?;
+        let body = self
+            .cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: &payload[ID_LEN + NONCE_LEN..],
+                    aad,
+                },
+            )
+            .ok()?;
+        Some((u64::from_be_bytes(aad.try_into().unwrap()), body))
+    }
+}
+
+/// A server's side of the control channel.
+#[derive(Default)]
+pub(super) struct Control {
+    key: Option<ControlKey>,
+    // Ids of requests taken, with when, until they are older than `MAX_SKEW`.
+    seen: Mutex<HashMap<u64, Instant>>,
+    rejected: AtomicU64,
+    log_level: RwLock<Option<LogLevelHook>>,
+}
+
+impl Control {
+    pub(super) fn new(key: Option<ControlKey>) -> Control {
+        Control {
+            key,
+            ..Control::default()
+        }
+    }
+
+    // The id and command of an authentic, fresh request, counting any other as rejected.
+    fn accept(&self, payload: &[u8], now: Instant) -> Option<(u64, String)> {
+        let request = self.key.as_ref().and_then(|key| {
+            let (id, body) = key.open(payload)?;
+            let sent = u64::from_be_bytes(body.get(..8)?.try_into().unwrap());
+            if unix_time().abs_diff(sent) > MAX_SKEW.as_secs() {
+                return None;
+            }
+            let mut seen = lock(&self.seen);
+            seen.retain(|_, taken| now.duration_since(*taken) <= MAX_SKEW);
+            if seen.insert(id, now).is_some() {
+                return None;
+            }
+            Some((id, String::from_utf8(body[8..].to_vec()).ok()?))
+        });
+        if request.is_none() {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        request
+    }
+}
+
+impl Server {
+    /// Registers `hook` to apply the log level a `log-level` control request asks for,
+    /// replacing any earlier one, typically by swapping the filter of a reloadable tracing
+    /// subscriber. Without a hook such requests fail.
+    pub fn on_log_level<F>(&self, hook: F)
+    where
+        F: Fn(LevelFilter) + Send + Sync + 'static,
+    {
+        *self
+            .shared
+            .control
+            .log_level
+            .write()
+            .unwrap_or_else(|e| e.into_inner()) = Some(Box::new(hook));
+    }
+
+    /// Control requests dropped, unanswered, because they failed authentication or were stale
+    /// or replayed, or because this server has no `control_key`.
+    pub fn control_rejected(&self) -> u64 {
+        self.shared.control.rejected.load(Ordering::Relaxed)
+    }
+}
+
+impl ServerShared {
+    /// Carries out the control request `source` sent, if it is authentic, and answers it.
+    pub(super) fn control(&self, source: SocketAddr, payload: &[u8]) {
+        let Some((id, command)) = self.control.accept(payload, Instant::now()) else {
+            debug!(target: TARGET, %source, "dropping control request");
+            return;
+        };
+        info!(target: TARGET, %source, %command, "control request");
+        let (status, text) = match self.run_command(&command) {
+            Ok(text) => (STATUS_OK, text),
+            Err(e) => (STATUS_FAILED, e),
+        };
+        let Some(key) = &self.control.key else {
+            return;
+        };
+        let mut body = vec![status];
+        body.extend_from_slice(text.as_bytes());
+        let frame = Frame {
+            control: true,
+            priority: Priority::Control,
+            ..Frame::new(key.seal(id, &body))
+        };
+        let mut peers = lock(&self.peers);
+        let Some(peer) = peers.get_mut(&source) else {
+            return;
+        };
+        if let Err(e) = peer.enqueue(frame) {
+            debug!(target: TARGET, %source, error = %e, "control answer failed");
+        }
+        self.flush(source, peer);
+    }
+
+    fn run_command(&self, command: &str) -> Result<String, String> {
+        let words: Vec<_> = command.split_whitespace().collect();
+        match words[..] {
+            ["stats"] => Ok(self.stats_json()),
+            ["sessions"] => Ok(self.sessions_json()),
+            ["log-level", level] => {
+                let filter: LevelFilter = level
+                    .parse()
+                    .map_err(|_| format!("invalid log level: {}", level))?;
+                let hook = self
+                    .control
+                    .log_level
+                    .read()
+                    .unwrap_or_else(|e| e.into_inner());
+                let hook = hook
+                    .as_ref()
+                    .ok_or("this server cannot change its log level")?;
+                hook(filter);
+                Ok(format!("{{\"log_level\":\"{}\"}}", level.to_lowercase()))
+            }
+            _ => Err(format!("unknown command: {}", command)),
+        }
+    }
+
+    fn stats_json(&self) -> String {
+        let health = self.health().to_json();
+        let peers = lock(&self.peers);
+        let (mut sent, mut received, mut retransmits, mut corrupt, mut unauthenticated) =
+            (0, 0, 0, 0, 0);
+        for peer in peers.values() {
+            let (stats, metrics) = (peer.stats(), peer.metrics());
+            sent += stats.bytes_sent;
+            received += stats.bytes_received;
+            retransmits += stats.retransmits;
+            corrupt += metrics.dropped_corrupt;
+            unauthenticated += metrics.dropped_unauthenticated;
+        }
+        format!(
+            "{{\"health\":{},\"bytes_sent\":{},\"bytes_received\":{},\"retransmits\":{},\"dropped_corrupt\":{},\"dropped_unauthenticated\":{},\"dropped_unknown\":{},\"auth_rejected\":{},\"control_rejected\":{}}}",
+            health,
+            sent,
+            received,
+            retransmits,
+            corrupt,
+            unauthenticated,
+            self.dropped_unknown.load(Ordering::Relaxed),
+            self.verifier.rejected(),
+            self.control.rejected.load(Ordering::Relaxed),
+        )
+    }
+
+    fn sessions_json(&self) -> String {
+        let peers = lock(&self.peers);
+        let mut sessions: Vec<_> = peers
+            .iter()
+            .filter_map(|(addr, peer)| Some((addr, peer, peer.last_heard?)))
+            .collect();
+        sessions.sort_by_key(|(addr, ..)| **addr);
+        let mut json = String::from("[");
+        for (i, (addr, peer, heard)) in sessions.into_iter().enumerate() {
+            let (stats, metrics) = (peer.stats(), peer.metrics());
+            let node_id = match &peer.node_id {
+                Some(node_id) => format!("\"{}\"", escape(node_id)),
+                None => "null".to_string(),
+            };
+            let rtt = match stats.rtt {
+                Some(rtt) => format!("{:.3}", rtt.as_secs_f64() * 1000.0),
+                None => "null".to_string(),
+            };
+            let _ = write!(
+                json,
+                "{}{{\"peer\":\"{}\",\"node_id\":{},\"last_heard_secs\":{},\"rtt_ms\":{},\"loss_rate\":{:.4},\"queued\":{},\"bytes_sent\":{},\"bytes_received\":{}}}",
+                if i > 0 { "," } else { "" },
+                escape(&addr.to_string()),
+                node_id,
+                heard.elapsed().as_secs(),
+                rtt,
+                stats.loss_rate,
+                metrics.queued,
+                stats.bytes_sent,
+                stats.bytes_received,
+            );
+        }
+        json.push(']');
+        json
+    }
+}
+
+impl Client {
+    /// Sends `command` to the server as a control request, sealed with `control_key`, and
+    /// returns the server's answer, JSON describing the server or what changed. Fails with
+    /// `Error::Config` without a `control_key`, with `ErrorKind::Other` carrying the server's
+    /// explanation if the command failed, and with `ErrorKind::TimedOut` if no answer arrives
+    /// within the read timeout, or five seconds if none is set. A server that rejects the
+    /// request, for a wrong key or a clock too far from this one's, never answers.
+    pub fn control(&self, command: &str) -> io::Result<String> {
+        let _enter = self.shared.span.enter();
+        let key = self
+            .shared
+            .control
+            .as_ref()
+            .ok_or_else(|| Error::config("Control requests need CRUMB_CONTROL_KEY"))?;
+        let id = self.shared.next_control.fetch_add(1, Ordering::Relaxed);
+        let mut body = unix_time().to_be_bytes().to_vec();
+        body.extend_from_slice(command.as_bytes());
+        let frame = Frame {
+            control: true,
+            priority: Priority::Control,
+            ..Frame::new(key.seal(id, &body))
+        };
+        self.queue(frame, None, SendQueue::must_wait)?;
+
+        // The worker notifies after handling every packet, control answers included.
+        let timeout = lock(&self.read_timeout).unwrap_or(PING_TIMEOUT);
+        let state = lock(&self.shared.state);
+        let (state, _) = self
+            .shared
+            .queue_space
+            .wait_timeout_while(state, timeout, |_| {
+                !lock(&self.shared.answers).contains_key(&id)
+            })
+            .unwrap_or_else(|e| e.into_inner());
+        drop(state);
+        let answer = lock(&self.shared.answers).remove(&id).ok_or_else(|| {
+            io::Error::new(ErrorKind::TimedOut, "No answer to the control request")
+        })?;
+        answer.map_err(io::Error::other)
+    }
+}
+
+impl ClientShared {
+    /// Takes in a control answer the server sent, dropping it unless it is sealed with our key.
+    pub(super) fn control_answer(&self, payload: &[u8]) {
+        let answer = self.control.as_ref().and_then(|key| key.open(payload));
+        let Some((id, body)) = answer else {
+            debug!(target: TARGET, "dropping control answer");
+            return;
+        };
+        let Some((&status, text)) = body.split_first() else {
+            return;
+        };
+        let text = String::from_utf8_lossy(text).into_owned();
+        let answer = match status {
+            STATUS_OK => Ok(text),
+            _ => Err(text),
+        };
+        lock(&self.answers).insert(id, answer);
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn key(hex: &str) -> ControlKey {
+        ControlKey::from_config(&Config {
+            control_key: hex.to_string(),
+            ..Config::default()
+        })
+        .unwrap()
+        .unwrap()
+    }
+
+    fn request(key: &ControlKey, id: u64, sent: u64, command: &str) -> Vec<u8> {
+        let mut body = sent.to_be_bytes().to_vec();
+        body.extend_from_slice(command.as_bytes());
+        key.seal(id, &body)
+    }
+
+    #[test]
+    fn only_fresh_requests_under_the_key_are_accepted() {
+        let control = Control::new(Some(key(KEY)));
+        let now = Instant::now();
+        let fresh = request(&key(KEY), 7, unix_time(), "stats");
+        assert_eq!(control.accept(&fresh, now), Some((7, "stats".to_string())));
+        // Replayed.
+        assert_eq!(control.accept(&fresh, now), None);
+
+        let stale = request(&key(KEY), 8, unix_time() - 120, "stats");
+        assert_eq!(control.accept(&stale, now), None);
+        let other = "ff".repeat(32);
+        let forged = request(&key(&other), 9, unix_time(), "stats");
+        assert_eq!(control.accept(&forged, now), None);
+        let mut altered = request(&key(KEY), 10, unix_time(), "stats");
+        altered[0] ^= 1;
+        assert_eq!(control.accept(&altered, now), None);
+        assert_eq!(control.rejected.load(Ordering::Relaxed), 4);
+
+        let closed = Control::new(None);
+        assert_eq!(closed.accept(&fresh, now), None);
+        assert_eq!(closed.rejected.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn operators_inspect_and_adjust_a_running_server() -> io::Result<()> {
+        let conf = Config {
+            host: "127.0.0.1".to_string(),
+            port: 8145,
+            bind_host: "127.0.0.1".to_string(),
+            control_key: KEY.to_string(),
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        let levels = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let applied = levels.clone();
+        server.on_log_level(move |level| lock(&applied).push(level));
+
+        let client = Client::init(&conf)?;
+        client.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let sessions = client.control("sessions")?;
+        assert!(
+            sessions.starts_with("[{\"peer\":\"127.0.0.1:"),
+            "{}",
+            sessions
+        );
+        let stats = client.control("stats")?;
+        assert!(stats.contains("\"sessions\":1,"), "{}", stats);
+        assert!(stats.contains("\"control_rejected\":0}"), "{}", stats);
+        assert_eq!(
+            client.control("log-level DEBUG")?,
+            r#"{"log_level":"debug"}"#
+        );
+        assert_eq!(*lock(&levels), [LevelFilter::DEBUG]);
+        let e = client.control("log-level loud").unwrap_err();
+        assert_eq!(e.to_string(), "invalid log level: loud");
+        assert!(client.control("reboot").is_err());
+
+        let intruder = Client::init(&Config {
+            control_key: "ff".repeat(32),
+            ..conf.clone()
+        })?;
+        intruder.set_read_timeout(Some(Duration::from_millis(300)))?;
+        let e = intruder.control("stats").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        assert_eq!(server.control_rejected(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn ids_are_forgotten_after_the_skew() {
+        let control = Control::new(Some(key(KEY)));
+        let request = request(&key(KEY), 1, unix_time(), "stats");
+        let now = Instant::now();
+        assert!(control.accept(&request, now).is_some());
+        lock(&control.seen).insert(2, now);
+        let later = now + MAX_SKEW + Duration::from_secs(1);
+        assert!(control.accept(&request, later).is_some());
+        assert!(!lock(&control.seen).contains_key(&2));
+    }
+}
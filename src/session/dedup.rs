@@ -0,0 +1,70 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Remembers the ids of the last `capacity` messages received from one sender, forgetting the
+/// least recently seen first, so that retransmissions of a message already delivered are
+/// recognised whatever their sequence number.
+#[derive(Debug)]
+pub(crate) struct DedupCache {
+    capacity: usize,
+    // When each remembered id was last seen, and the ids by that time.
+    seen: HashMap<u64, u64>,
+    by_age: BTreeMap<u64, u64>,
+    clock: u64,
+    hits: u64,
+}
+
+impl DedupCache {
+    pub(crate) fn new(capacity: usize) -> DedupCache {
+        DedupCache {
+            capacity: capacity.max(1),
+            seen: HashMap::new(),
+            by_age: BTreeMap::new(),
+            clock: 0,
+            hits: 0,
+        }
+    }
+
+    /// Records `msg_id` as seen now, returning whether it is new.
+    pub(crate) fn check(&mut self, msg_id: u64) -> bool {
+        let now = self.clock;
+        self.clock += 1;
+        self.by_age.insert(now, msg_id);
+        if let Some(last) = self.seen.insert(msg_id, now) {
+            self.by_age.remove(&last);
+            self.hits += 1;
+            return false;
+        }
+        if self.seen.len() > self.capacity {
+            if let Some((_, oldest)) = self.by_age.pop_first() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// Messages recognised as already seen.
+    pub(crate) fn hits(&self) -> u64 {
+        self.hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_least_recently_seen_are_forgotten() {
+        let mut cache = DedupCache::new(2);
+        assert!(cache.check(1));
+        assert!(cache.check(2));
+        assert!(!cache.check(1));
+        assert!(cache.check(3));
+        assert_eq!(cache.hits(), 1);
+
+        // 2 was seen longest ago, so it made room for 3.
+        assert!(!cache.check(1));
+        assert!(cache.check(2));
+        assert!(!cache.check(2));
+        assert_eq!(cache.hits(), 3);
+    }
+}
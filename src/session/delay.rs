@@ -0,0 +1,135 @@
+//! One-way delay. With `timestamps` set, a session stamps every application message with the
+//! time it was sent by its wall clock, and the receiver measures how long each took to cross.
+//! Corrected by the offset between the clocks its pings have measured, or as it is where the
+//! clocks are kept in step by other means, as `clock_synced` says they are, that is the one-way
+//! delay, smoothed as the RTT is and shown in `Stats::one_way_delay`. Either way the
+//! variation from one message to the next is shown as `Stats::transit_jitter`, smoothed as
+//! RFC 3550 smooths its interarrival jitter, which clock offsets do not affect.
+//!
+//! A message arrives when the kernel stamped its datagram, with `kernel_timestamps`, or else
+//! when the session's worker read it.
+
+use std::time::{Duration, SystemTime};
+
+/// The time now by the wall clock, for stamping messages: microseconds since the Unix epoch,
+/// wrapping at 2^32.
+pub(super) fn timestamp() -> u32 {
+    stamp(SystemTime::now())
+}
+
+fn stamp(time: SystemTime) -> u32 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u32
+}
+
+/// How long the peer's stamped messages took to cross, in microseconds.
+#[derive(Debug, Default)]
+pub(super) struct Delays {
+    synced: bool,
+    last_transit: Option<i32>,
+    jitter: Option<f64>,
+    delay: Option<f64>,
+}
+
+impl Delays {
+    pub(super) fn new(synced: bool) -> Delays {
+        Delays {
+            synced,
+            ..Default::default()
+        }
+    }
+
+    /// Records a message stamped `sent` that arrived at `received`, from a peer whose clock is
+    /// `offset` microseconds ahead of ours if that has been measured.
+    pub(super) fn on_message(&mut self, sent: u32, received: SystemTime, offset: Option<i64>) {
+        // Stamps wrap, so the transit is their difference taken the short way round.
+        let transit = stamp(received).wrapping_sub(sent) as i32;
+        if let Some(last) = self.last_transit {
+            let variation = f64::from(transit.wrapping_sub(last)).abs();
+            let jitter = self.jitter.unwrap_or(0.0);
+            self.jitter = Some(jitter + (variation - jitter) / 16.0);
+        }
+        self.last_transit = Some(transit);
+        let offset = offset.or(self.synced.then_some(0));
+        if let Some(offset) = offset {
+            // Clocks a little apart can make a message seem to arrive before it left.
+            let sample = (i64::from(transit) + offset).max(0) as f64;
+            self.delay = Some(match self.delay {
+                Some(delay) => delay + (sample - delay) / 8.0,
+                None => sample,
+            });
+        }
+    }
+
+    /// The smoothed one-way delay, once a stamped message has arrived with the clocks synced or
+    /// their offset known.
+    pub(super) fn one_way_delay(&self) -> Option<Duration> {
+        self.delay.map(micros)
+    }
+
+    /// The smoothed variation in transit time, once two stamped messages have arrived.
+    pub(super) fn jitter(&self) -> Option<Duration> {
+        self.jitter.map(micros)
+    }
+}
+
+fn micros(micros: f64) -> Duration {
+    Duration::from_secs_f64(micros / 1e6)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Frame;
+    use crate::session::PeerState;
+    use crate::util::config::Config;
+
+    #[test]
+    fn delays_are_read_off_stamps() {
+        let ms = Duration::from_millis;
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(4_294) + ms(960);
+        let sent = |at| stamp(start + ms(at));
+        let mut synced = Delays::new(true);
+        let mut unsynced = Delays::new(false);
+        // Crossing in 10, 30 and 10 ms, across the stamps wrapping.
+        for (at, transit) in [(0, 10), (20, 30), (80, 10)] {
+            for delays in [&mut synced, &mut unsynced] {
+                delays.on_message(sent(at), start + ms(at + transit), None);
+            }
+        }
+        assert_eq!(unsynced.one_way_delay(), None);
+        assert_eq!(synced.jitter(), unsynced.jitter());
+        let jitter = synced.jitter().unwrap().as_secs_f64();
+        let expected = 0.020 / 16.0 + (0.020 - 0.020 / 16.0) / 16.0;
+        assert!((jitter - expected).abs() < 1e-6, "{}", jitter);
+        // 10 ms, then an eighth of the way to 30 ms, then an eighth of the way back to 10 ms.
+        let delay = synced.one_way_delay().unwrap().as_secs_f64();
+        assert!((delay - (0.0125 - 0.0025 / 8.0)).abs() < 1e-6, "{}", delay);
+
+        // A peer 5 ms ahead whose message seems to take 15 ms took 20.
+        unsynced.on_message(sent(100), start + ms(115), Some(5_000));
+        assert_eq!(unsynced.one_way_delay(), Some(ms(20)));
+    }
+
+    #[test]
+    fn sessions_measure_stamped_messages() {
+        let mut sender = PeerState::new(&Config::default());
+        let mut receiver = PeerState::new(&Config {
+            clock_synced: true,
+            ..Default::default()
+        });
+        let sent = timestamp();
+        let stamped = |sent| Frame {
+            timestamp: Some(sent),
+            ..Frame::new(b"reading".to_vec())
+        };
+        let received = SystemTime::now() + Duration::from_millis(40);
+        receiver.incoming_at(&sender.outgoing(stamped(sent)), Some(received));
+        receiver.incoming(&sender.outgoing(Frame::new(b"unstamped".to_vec())));
+        let delay = receiver.stats().one_way_delay.unwrap();
+        assert!(delay >= Duration::from_millis(40) && delay < Duration::from_secs(1));
+        assert_eq!(receiver.stats().transit_jitter, None);
+        assert_eq!(sender.stats().one_way_delay, None);
+    }
+}
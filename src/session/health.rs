@@ -0,0 +1,232 @@
+//! Health checks: a summary of a server's state for `Server::health`, also answered over HTTP
+//! on `health_port` so that liveness probes and load balancers need no crumb client.
+//!
+//! `GET /health` returns `200 OK` with the summary as JSON while the server runs, and
+//! `503 Service Unavailable` once it is shutting down:
+//!
+//! ```text
+//! {"status":"ok","uptime_secs":312,"sessions":2,"queued":5,"max_queued":4,"last_error":null}
+//! ```
+
+use super::{lock, Server, ServerShared, POLL_INTERVAL};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tracing::{debug, trace};
+
+const TARGET: &str = "crumb::session::health";
+
+// How long a health check client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Request lines are read no further than this, as no valid one is longer.
+const MAX_REQUEST_LINE: u64 = 1024;
+
+/// A summary of a server's state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Health {
+    /// Whether the server is still handling traffic.
+    pub running: bool,
+    /// How long ago the server started.
+    pub uptime: Duration,
+    /// Clients that have been heard from.
+    pub sessions: usize,
+    /// Messages waiting in all the send queues together.
+    pub queued: usize,
+    /// Messages waiting in the fullest send queue.
+    pub max_queued: usize,
+    /// The last socket error the server ran into, if any.
+    pub last_error: Option<String>,
+}
+
+impl Health {
+    /// The summary as a JSON object, as the HTTP endpoint returns it.
+    pub fn to_json(&self) -> String {
+        let last_error = match &self.last_error {
+            Some(e) => format!("\"{}\"", escape(e)),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"status\":\"{}\",\"uptime_secs\":{},\"sessions\":{},\"queued\":{},\"max_queued\":{},\"last_error\":{}}}",
+            if self.running { "ok" } else { "stopping" },
+            self.uptime.as_secs(),
+            self.sessions,
+            self.queued,
+            self.max_queued,
+            last_error,
+        )
+    }
+}
+
+impl Server {
+    /// The server's state as the health check endpoint reports it.
+    pub fn health(&self) -> Health {
+        self.shared.health()
+    }
+}
+
+impl ServerShared {
+    pub(super) fn health(&self) -> Health {
+        let peers = lock(&self.peers);
+        let queued = peers.values().map(|peer| peer.metrics().queued);
+        Health {
+            running: self.running.load(Ordering::Acquire) && !self.server.is_shut_down(),
+            uptime: self.started.elapsed(),
+            sessions: peers
+                .values()
+                .filter(|peer| peer.last_heard.is_some())
+                .count(),
+            queued: queued.clone().sum(),
+            max_queued: queued.max().unwrap_or(0),
+            last_error: lock(&self.last_error).clone(),
+        }
+    }
+
+    /// Remembers `e` as the last error, for health checks.
+    pub(super) fn failed(&self, e: &io::Error) {
+        *lock(&self.last_error) = Some(e.to_string());
+    }
+}
+
+/// Starts answering health checks on `health_port`, until the server stops running. `None`
+/// when no port is configured.
+pub(super) fn serve(shared: &Arc<ServerShared>) -> io::Result<Option<JoinHandle<()>>> {
+    if shared.conf.health_port == 0 {
+        return Ok(None);
+    }
+    let listener = TcpListener::bind((shared.conf.bind_host.as_str(), shared.conf.health_port))?;
+    // Accepting without blocking lets the thread notice the server stopping.
+    listener.set_nonblocking(true)?;
+    debug!(target: TARGET, addr = ?listener.local_addr(), "answering health checks");
+    let shared = shared.clone();
+    let worker = thread::Builder::new()
+        .name("crumb-health".to_string())
+        .spawn(move || {
+            while shared.running.load(Ordering::Acquire) {
+                match listener.accept() {
+                    Ok((stream, peer)) => {
+                        if let Err(e) = respond(&shared, stream) {
+                            trace!(target: TARGET, %peer, error = %e, "health check failed");
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                    Err(e) => {
+                        debug!(target: TARGET, error = %e, "accepting a health check failed");
+                        thread::sleep(POLL_INTERVAL);
+                    }
+                }
+            }
+        })?;
+    Ok(Some(worker))
+}
+
+// Answers one HTTP request, reading no further than its request line.
+fn respond(shared: &ServerShared, stream: TcpStream) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request = String::new();
+    BufReader::new(&stream)
+        .take(MAX_REQUEST_LINE)
+        .read_line(&mut request)?;
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/health")) => {
+            let health = shared.health();
+            let status = if health.running {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            (status, health.to_json())
+        }
+        (Some("GET"), _) => ("404 Not Found", "{}".to_string()),
+        _ => ("405 Method Not Allowed", "{}".to_string()),
+    };
+    write!(
+        &stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// `s` escaped for a JSON string.
+pub(crate) fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Client;
+    use crate::util::config::Config;
+
+    fn get(port: u16, path: &str) -> io::Result<String> {
+        let mut stream = TcpStream::connect(("127.0.0.1", port))?;
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path)?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    #[test]
+    fn health_checks_are_answered_over_http() -> io::Result<()> {
+        let conf = Config {
+            host: "127.0.0.1".to_string(),
+            port: 8136,
+            bind_host: "127.0.0.1".to_string(),
+            health_port: 8137,
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        let client = Client::init(&conf)?;
+        client.send(b"hello")?;
+        let mut buffer = [0u8; 16];
+        server.set_read_timeout(Some(Duration::from_secs(5)))?;
+        server.receive_from(&mut buffer)?;
+
+        let health = server.health();
+        assert!(health.running);
+        assert_eq!(health.sessions, 1);
+        assert_eq!(health.last_error, None);
+        let response = get(conf.health_port, "/health")?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\"sessions\":1,"), "{}", response);
+        assert!(get(conf.health_port, "/")?.starts_with("HTTP/1.1 404"));
+
+        server.shutdown_handle()?.shutdown();
+        let response = get(conf.health_port, "/health")?;
+        assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+        assert!(response.contains("\"status\":\"stopping\""));
+        Ok(())
+    }
+
+    #[test]
+    fn errors_are_escaped() {
+        let health = Health {
+            running: true,
+            uptime: Duration::from_secs(3),
+            sessions: 0,
+            queued: 0,
+            max_queued: 0,
+            last_error: Some("bad \"addr\"\n".to_string()),
+        };
+        assert_eq!(
+            health.to_json(),
+            r#"{"status":"ok","uptime_secs":3,"sessions":0,"queued":0,"max_queued":0,"last_error":"bad \"addr\"\u000a"}"#
+        );
+    }
+}
@@ -0,0 +1,314 @@
+//! Interceptors: hooks a server runs on every message from a client before the application
+//! receives it, for concerns such as token checks, logging or rewriting payloads that would
+//! otherwise need a fork of the server loop. Clients have a hook of each kind for the messages
+//! they send and receive.
+
+use super::{delay, frame, Client, ClientShared, Server, SessionId};
+use crate::protocol::{Frame, Priority};
+use crate::util::config::PayloadFormat;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::RwLock;
+use tracing::trace;
+
+const TARGET: &str = "crumb::session::intercept";
+
+type Interceptor = Box<dyn Fn(&mut Frame, &PeerInfo) -> Decision + Send + Sync>;
+
+type SendHook = Box<dyn Fn(&mut Frame) + Send + Sync>;
+
+type ReceiveHook = Box<dyn Fn(&mut Frame) -> Decision + Send + Sync>;
+
+/// What an interceptor wants done with a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// Hand the message, as the interceptor left it, to the next interceptor and then the
+    /// application.
+    Pass,
+    /// Drop the message. Reliable messages have already been acknowledged, so the client does
+    /// not send it again.
+    Drop,
+}
+
+/// The client a message came from, as interceptors and the application see it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub session: SessionId,
+    /// The address the client sent from, which may change under NAT rebinding.
+    pub addr: SocketAddr,
+    /// The node ID the client announced, which stays the same when its address changes. `None`
+    /// until the announcement arrives, and for clients that make none.
+    pub node_id: Option<String>,
+    /// Whether the verifier `Server::on_auth` registers accepted the client's token.
+    pub authenticated: bool,
+    /// The identity of the pre-shared key the client's frames are sealed with, if it named one.
+    pub identity: Option<Vec<u8>>,
+}
+
+/// The interceptors registered with a server, in the order they run.
+#[derive(Default)]
+pub(super) struct Interceptors(RwLock<Vec<Interceptor>>);
+
+impl Interceptors {
+    pub(super) fn is_empty(&self) -> bool {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).is_empty()
+    }
+
+    /// Runs every interceptor on `frame` in turn, stopping at the first that drops it.
+    pub(super) fn run(&self, frame: &mut Frame, peer: &PeerInfo) -> Decision {
+        let interceptors = self.0.read().unwrap_or_else(|e| e.into_inner());
+        for (i, interceptor) in interceptors.iter().enumerate() {
+            if interceptor(frame, peer) == Decision::Drop {
+                trace!(target: TARGET, session = %peer.session, interceptor = i, msg_id = frame.msg_id, "message dropped");
+                return Decision::Drop;
+            }
+        }
+        Decision::Pass
+    }
+}
+
+/// The hooks registered with a client.
+#[derive(Default)]
+pub(super) struct ClientHooks {
+    send: RwLock<Option<SendHook>>,
+    receive: RwLock<Option<ReceiveHook>>,
+}
+
+impl ClientHooks {
+    /// Runs the receive hook, if any, on `frame`.
+    pub(super) fn received(&self, frame: &mut Frame) -> Decision {
+        let hook = self.receive.read().unwrap_or_else(|e| e.into_inner());
+        let decision = hook.as_ref().map_or(Decision::Pass, |hook| hook(frame));
+        if decision == Decision::Drop {
+            trace!(target: TARGET, msg_id = frame.msg_id, "message dropped");
+        }
+        decision
+    }
+}
+
+impl Server {
+    /// Adds `interceptor` to the end of the chain run on every message from a client, on the
+    /// server's worker thread, before it is queued for `receive_from` and the like. Each
+    /// interceptor sees the message as the ones before left it, decompressed and with its schema
+    /// already checked, and may change it or drop it. Stream chunks are not intercepted.
+    ///
+    /// Interceptors hold up every session while they run, so they should not block.
+    pub fn intercept<F>(&self, interceptor: F)
+    where
+        F: Fn(&mut Frame, &PeerInfo) -> Decision + Send + Sync + 'static,
+    {
+        self.shared
+            .interceptors
+            .0
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(Box::new(interceptor));
+    }
+}
+
+impl Client {
+    /// Registers `hook` to be called on every message the application sends, replacing any
+    /// earlier one. It sees the message before compression, and may change its payload, format,
+    /// priority, schema, type, trace context or TLV entries; changes to other fields are lost
+    /// when the session numbers and sends it. Stream chunks are not passed to it.
+    pub fn on_send<F>(&self, hook: F)
+    where
+        F: Fn(&mut Frame) + Send + Sync + 'static,
+    {
+        *self
+            .shared
+            .hooks
+            .send
+            .write()
+            .unwrap_or_else(|e| e.into_inner()) = Some(Box::new(hook));
+    }
+
+    /// Registers `hook` to be called on every message from the server, replacing any earlier
+    /// one, as a server's interceptors are. It runs on the client's worker thread, so it should
+    /// not block.
+    pub fn on_receive<F>(&self, hook: F)
+    where
+        F: Fn(&mut Frame) -> Decision + Send + Sync + 'static,
+    {
+        *self
+            .shared
+            .hooks
+            .receive
+            .write()
+            .unwrap_or_else(|e| e.into_inner()) = Some(Box::new(hook));
+    }
+}
+
+impl ClientShared {
+    /// Frames `data` for the server, declared to be in `format`, passing it through the send
+    /// hook before the pipeline.
+    pub(super) fn frame(
+        &self,
+        format: PayloadFormat,
+        priority: Priority,
+        data: &[u8],
+    ) -> io::Result<Frame> {
+        self.typed_frame(format, priority, 0, data)
+    }
+
+    /// Like `frame`, for a message of type `msg_type`.
+    pub(super) fn typed_frame(
+        &self,
+        format: PayloadFormat,
+        priority: Priority,
+        msg_type: u16,
+        data: &[u8],
+    ) -> io::Result<Frame> {
+        let mut message = Frame {
+            format,
+            priority,
+            schema: self.schemas.stamp(format),
+            msg_type,
+            ..Frame::new(data.to_vec())
+        };
+        let timestamp = self.conf.timestamps.then(delay::timestamp);
+        let hook = self.hooks.send.read().unwrap_or_else(|e| e.into_inner());
+        let codec = Some(&*self.codec);
+        let Some(hook) = &*hook else {
+            let frame = frame(&self.conf, codec, message)?;
+            return Ok(Frame { timestamp, ..frame });
+        };
+        hook(&mut message);
+        let message = Frame {
+            format: message.format,
+            priority: message.priority,
+            schema: message.schema,
+            msg_type: message.msg_type,
+            trace: message.trace,
+            tlv: message.tlv,
+            ..Frame::new(message.payload)
+        };
+        let frame = frame(&self.conf, codec, message)?;
+        Ok(Frame { timestamp, ..frame })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::MAX_DATAGRAM_SIZE;
+    use super::*;
+    use crate::error::Error;
+    use crate::protocol::{Tlv, FIRST_APPLICATION_TLV, HEADER_LEN, MAX_TLV_LEN};
+    use crate::transport::memory;
+    use crate::util::config::{CompressionType, Config};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    #[test]
+    fn interceptors_run_in_order_and_may_drop() -> io::Result<()> {
+        let conf = Config {
+            host: "127.0.0.1".to_string(),
+            port: 8134,
+            ordered: true,
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        let seen = Arc::new(AtomicUsize::new(0));
+        let counter = seen.clone();
+        server.intercept(move |_, _| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            Decision::Pass
+        });
+        server.intercept(|frame, _| match frame.payload.strip_prefix(b"token:") {
+            Some(rest) => {
+                frame.payload = rest.to_vec();
+                Decision::Pass
+            }
+            None => Decision::Drop,
+        });
+        let client = Client::init(&conf)?;
+        client.send(b"anonymous")?;
+        client.send(b"token:hello")?;
+
+        let mut buffer = [0u8; 16];
+        server.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let (received, source) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"hello");
+        assert_eq!(source, client.local_addr()?);
+        assert_eq!(seen.load(Ordering::Relaxed), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn client_hooks_see_every_message() -> io::Result<()> {
+        let (a, b) = memory::pair(memory::Conditions::default());
+        let sender = Client::with_transport(&Config::default(), Box::new(a))?;
+        let receiver = Client::with_transport(&Config::default(), Box::new(b))?;
+        sender.on_send(|frame| {
+            frame.payload.extend_from_slice(b"#signed");
+            frame.priority = Priority::High;
+        });
+        receiver.on_receive(|frame| {
+            assert_eq!(frame.priority, Priority::High);
+            match frame.payload.strip_suffix(b"#signed") {
+                Some(message) if message != b"forged" => {
+                    frame.payload = message.to_vec();
+                    Decision::Pass
+                }
+                _ => Decision::Drop,
+            }
+        });
+        sender.send(b"forged")?;
+        sender.send(b"genuine")?;
+
+        let mut buffer = [0u8; 16];
+        let received = receiver.receive_timeout(&mut buffer, Duration::from_secs(5))?;
+        assert_eq!(&buffer[..received], b"genuine");
+        Ok(())
+    }
+
+    #[test]
+    fn tlv_entries_from_hooks_count_against_the_datagram() -> io::Result<()> {
+        let conf = Config {
+            compression_type: CompressionType::None,
+            ..Default::default()
+        };
+        let (a, b) = memory::pair(memory::Conditions::default());
+        let sender = Client::with_transport(&conf, Box::new(a))?;
+        let receiver = Client::with_transport(&conf, Box::new(b))?;
+        receiver.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let (tlv, seen) = mpsc::channel();
+        receiver.on_receive(move |frame| {
+            let _ = tlv.send(frame.find_tlv(FIRST_APPLICATION_TLV).map(<[u8]>::len));
+            Decision::Pass
+        });
+        sender.on_send(|frame| {
+            let len = match frame.payload.first() {
+                Some(0) => 100,
+                _ => MAX_TLV_LEN,
+            };
+            frame
+                .tlv
+                .push(Tlv::new(FIRST_APPLICATION_TLV, vec![0; len]));
+        });
+
+        let fits = MAX_DATAGRAM_SIZE - HEADER_LEN - (1 + 2 + 100);
+        match Error::from(sender.send(&vec![0; fits + 1]).unwrap_err()) {
+            Error::PayloadTooLarge { size, max } => {
+                assert_eq!((size, max), (MAX_DATAGRAM_SIZE + 1, MAX_DATAGRAM_SIZE))
+            }
+            e => panic!("unexpected error: {}", e),
+        }
+        sender.send(&vec![0; fits])?;
+        let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+        assert_eq!(receiver.receive(&mut buffer)?, fits);
+        assert_eq!(
+            seen.recv_timeout(Duration::from_secs(5)).unwrap(),
+            Some(100)
+        );
+
+        // An entry too long for the area is refused rather than left out.
+        match Error::from(sender.send(b"\x01").unwrap_err()) {
+            Error::Protocol(e) => assert!(e.to_string().contains("TLV entries take 257 bytes")),
+            e => panic!("unexpected error: {}", e),
+        }
+        sender.close();
+        Ok(())
+    }
+}
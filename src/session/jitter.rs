@@ -0,0 +1,250 @@
+//! Jitter buffers, for sampled sensor readings, audio and other payloads that are best taken in
+//! at the pace they were sent rather than the uneven one the network delivers them at.
+//!
+//! With `timestamps` set, a session stamps every application message with the time it was sent,
+//! in the `TIMESTAMP` extension. With `jitter_delay` set, a receiver holds the stamped messages
+//! it gets and plays each out `jitter_delay` after the time it would have arrived had it crossed
+//! as quickly as the quickest message seen lately, in the order they were stamped. A message
+//! arriving after its time, or after a later one was played out, is dropped and counted in
+//! `Metrics::jitter_dropped`, as a late sample is of no more use than a lost one. Messages
+//! without a stamp are delivered as they arrive.
+//!
+//! Held messages are played out by the session's worker, which wakes at least every 20 ms, so a
+//! message can come out up to that much after its time; target delays should allow for it.
+
+use super::{PeerState, TARGET};
+use crate::protocol::Frame;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use tracing::{debug, trace};
+
+// The quickest crossing is learnt again over this long, so that a sender's clock running slow
+// against ours does not make its messages late one after another.
+const BASE_WINDOW: Duration = Duration::from_secs(10);
+
+// Stamps further than this from the last, in microseconds, are taken for a restarted sender,
+// whose clock begins again, rather than for messages sent that far apart.
+const RESYNC_MICROS: i64 = 5_000_000;
+
+/// Holds stamped messages until their time to be played out.
+#[derive(Debug)]
+pub(super) struct JitterBuffer {
+    delay: i64,
+    // Arrival times are counted in microseconds from here.
+    origin: Instant,
+    // The last stamp seen, as sent and widened to 64 bits, to widen the next by.
+    last: Option<(u32, i64)>,
+    // The shortest time from stamp to arrival seen, over all time and in the current window.
+    base: Option<i64>,
+    window_base: Option<i64>,
+    window_start: Instant,
+    // Messages by widened stamp, then by arrival for messages stamped alike.
+    held: BTreeMap<(i64, u64), Frame>,
+    arrived: u64,
+    // Messages held when the sender's clock jumped, due at once.
+    overdue: Vec<Frame>,
+    played: Option<i64>,
+    dropped: u64,
+}
+
+impl JitterBuffer {
+    pub(super) fn new(delay: Duration) -> JitterBuffer {
+        let now = Instant::now();
+        JitterBuffer {
+            delay: delay.as_micros() as i64,
+            origin: now,
+            last: None,
+            base: None,
+            window_base: None,
+            window_start: now,
+            held: BTreeMap::new(),
+            arrived: 0,
+            overdue: Vec::new(),
+            played: None,
+            dropped: 0,
+        }
+    }
+
+    /// Takes in a message that arrived at `now`, handing it back if it is to be delivered at
+    /// once because it carries no stamp.
+    pub(super) fn push(&mut self, frame: Frame, now: Instant) -> Option<Frame> {
+        let Some(stamp) = frame.timestamp else {
+            return Some(frame);
+        };
+        let sent = self.widen(stamp);
+        let arrival = self.micros(now);
+        self.learn(arrival - sent, now);
+        if self.played.is_some_and(|played| sent < played) || self.playout(sent) < arrival {
+            trace!(target: TARGET, msg_id = frame.msg_id, "dropping message too late to play out");
+            self.dropped += 1;
+            return None;
+        }
+        self.held.insert((sent, self.arrived), frame);
+        self.arrived += 1;
+        None
+    }
+
+    /// The messages whose time has come by `now`, in the order they were stamped.
+    pub(super) fn release(&mut self, now: Instant) -> Vec<Frame> {
+        let now = self.micros(now);
+        let mut due = std::mem::take(&mut self.overdue);
+        while let Some(&(sent, arrived)) = self.held.keys().next() {
+            if self.playout(sent) > now {
+                break;
+            }
+            self.played = Some(sent);
+            due.extend(self.held.remove(&(sent, arrived)));
+        }
+        due
+    }
+
+    /// Messages held for their time.
+    pub(super) fn depth(&self) -> usize {
+        self.held.len() + self.overdue.len()
+    }
+
+    /// Messages dropped for arriving too late.
+    pub(super) fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    // Places `stamp` on a line that does not wrap, by its distance from the last one.
+    fn widen(&mut self, stamp: u32) -> i64 {
+        let sent = match self.last {
+            Some((last, widened)) => widened + i64::from(stamp.wrapping_sub(last) as i32),
+            None => i64::from(stamp),
+        };
+        let resync = self
+            .last
+            .is_some_and(|(_, widened)| (sent - widened).abs() > RESYNC_MICROS);
+        if resync {
+            debug!(target: TARGET, "sender clock jumped, resynchronising jitter buffer");
+            // What is held was timed by the old clock, so it is played out before anything new.
+            let held = std::mem::take(&mut self.held);
+            self.overdue.extend(held.into_values());
+            self.base = None;
+            self.window_base = None;
+            self.played = None;
+        }
+        self.last = Some((stamp, sent));
+        sent
+    }
+
+    // Records a message that took `transit` from stamp to arrival.
+    fn learn(&mut self, transit: i64, now: Instant) {
+        if now.saturating_duration_since(self.window_start) >= BASE_WINDOW {
+            self.base = self.window_base;
+            self.window_base = None;
+            self.window_start = now;
+        }
+        self.base = Some(self.base.map_or(transit, |base| base.min(transit)));
+        self.window_base = Some(self.window_base.map_or(transit, |base| base.min(transit)));
+    }
+
+    fn playout(&self, sent: i64) -> i64 {
+        sent + self.base.unwrap_or(0) + self.delay
+    }
+
+    fn micros(&self, now: Instant) -> i64 {
+        now.saturating_duration_since(self.origin).as_micros() as i64
+    }
+}
+
+impl PeerState {
+    /// Held messages whose time has come by `now`, counted as delivered.
+    pub(super) fn playout(&mut self, now: Instant) -> Vec<Frame> {
+        let Some(jitter) = &mut self.jitter else {
+            return Vec::new();
+        };
+        let due = jitter.release(now);
+        if self.receive_window > 0 {
+            self.undelivered = self.undelivered.saturating_add(due.len() as u32);
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::delay::timestamp;
+    use crate::util::config::Config;
+
+    fn stamped(stamp: u32, payload: u8) -> Frame {
+        Frame {
+            timestamp: Some(stamp),
+            ..Frame::new(vec![payload])
+        }
+    }
+
+    fn payloads(frames: Vec<Frame>) -> Vec<u8> {
+        frames.into_iter().map(|frame| frame.payload[0]).collect()
+    }
+
+    #[test]
+    fn messages_play_out_at_the_pace_they_were_sent() {
+        let ms = Duration::from_millis;
+        let mut buffer = JitterBuffer::new(ms(50));
+        let start = buffer.origin;
+        let at = |millis| start + ms(millis);
+        // Sent every 20 ms from just before the wrap, crossing in 10 to 40 ms, the third
+        // overtaking the second and the fourth past its time.
+        let first = u32::MAX - 5_000;
+        let stamp = |i: u32| first.wrapping_add(i * 20_000);
+        assert!(buffer.push(stamped(stamp(0), 0), at(10)).is_none());
+        assert!(buffer.push(stamped(stamp(2), 2), at(55)).is_none());
+        assert!(buffer.push(stamped(stamp(1), 1), at(60)).is_none());
+        assert!(buffer.push(stamped(stamp(3), 3), at(130)).is_none());
+        assert_eq!(buffer.depth(), 3);
+        assert_eq!(buffer.dropped(), 1);
+
+        assert!(buffer.release(at(59)).is_empty());
+        assert_eq!(payloads(buffer.release(at(60))), [0]);
+        assert_eq!(payloads(buffer.release(at(85))), [1]);
+        assert_eq!(payloads(buffer.release(at(200))), [2]);
+
+        // Behind what was played out, and unstamped.
+        assert!(buffer.push(stamped(stamp(1), 1), at(210)).is_none());
+        assert_eq!(buffer.dropped(), 2);
+        assert_eq!(
+            buffer.push(Frame::new(vec![9]), at(210)).map(|f| f.payload),
+            Some(vec![9])
+        );
+    }
+
+    #[test]
+    fn restarted_senders_are_followed() {
+        let ms = Duration::from_millis;
+        let mut buffer = JitterBuffer::new(ms(50));
+        let (start, later) = (buffer.origin, buffer.origin + ms(1_000));
+        buffer.push(stamped(60_000_000, 0), start);
+        buffer.push(stamped(60_020_000, 1), start + ms(20));
+        buffer.release(start + ms(50));
+        // The sender's clock began again, with the second message still held.
+        assert!(buffer.push(stamped(1_000, 2), later).is_none());
+        assert_eq!(buffer.dropped(), 0);
+        assert_eq!(payloads(buffer.release(later + ms(50))), [1, 2]);
+    }
+
+    #[test]
+    fn sessions_hold_stamped_messages() {
+        let conf = Config {
+            jitter_delay: Duration::from_millis(50),
+            receive_window: 8,
+            ..Default::default()
+        };
+        let mut sender = PeerState::new(&Config::default());
+        let mut receiver = PeerState::new(&conf);
+        let (messages, _) = receiver.incoming(&sender.outgoing(stamped(timestamp(), 1)));
+        assert!(messages.is_empty());
+        assert_eq!(receiver.metrics().jitter_depth, 1);
+        assert_eq!(receiver.undelivered, 0);
+        let (messages, _) = receiver.incoming(&sender.outgoing(Frame::new(vec![2])));
+        assert_eq!(payloads(messages), [2]);
+
+        let later = Instant::now() + Duration::from_millis(60);
+        assert_eq!(payloads(receiver.playout(later)), [1]);
+        assert_eq!(receiver.metrics().jitter_depth, 0);
+        assert_eq!(receiver.undelivered, 2);
+    }
+}
@@ -0,0 +1,234 @@
+//! Typed sending and receiving on top of the byte-oriented session API: protobuf messages with
+//! the `prost` feature, and serde values in the configured `payload_format` with the `json` or
+//! `msgpack` features.
+
+use super::{lock, next_message, Client, Server};
+#[cfg(feature = "prost")]
+use crate::error::Error;
+use crate::protocol::{Frame, Priority};
+#[cfg(feature = "prost")]
+use crate::util::config::PayloadFormat;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+impl Client {
+    fn next_frame(&self) -> io::Result<Frame> {
+        let timeout = *lock(&self.read_timeout);
+        let frame = next_message(&lock(&self.inbox), timeout)?;
+        self.shared.took_message();
+        Ok(frame)
+    }
+}
+
+impl Server {
+    fn next_frame_from(&self) -> io::Result<(Frame, SocketAddr)> {
+        let timeout = *lock(&self.read_timeout);
+        let (frame, source) = next_message(&lock(&self.inbox), timeout)?;
+        self.shared.took_message(source);
+        Ok((frame, source))
+    }
+}
+
+#[cfg(feature = "prost")]
+impl Client {
+    /// Encodes `message` and sends it, declared as protobuf, as `send` does.
+    pub fn send_message<M: prost::Message>(&self, message: &M) -> io::Result<usize> {
+        self.send_as(
+            PayloadFormat::Protobuf,
+            Priority::Normal,
+            &message.encode_to_vec(),
+        )
+    }
+
+    /// Waits for the next message from the server and decodes it as an `M`. A message that does
+    /// not decode, or that its sender declared to be JSON or MessagePack, is consumed and
+    /// reported as `InvalidData`.
+    pub fn receive_message<M: prost::Message + Default>(&self) -> io::Result<M> {
+        decode_message(&self.next_frame()?)
+    }
+}
+
+#[cfg(feature = "prost")]
+impl Server {
+    /// Encodes `message` and sends it, declared as protobuf, as `send_to` does.
+    pub fn send_message_to<M, A>(&self, message: &M, dest: A) -> io::Result<usize>
+    where
+        M: prost::Message,
+        A: ToSocketAddrs,
+    {
+        self.send_to_as(
+            PayloadFormat::Protobuf,
+            Priority::Normal,
+            &message.encode_to_vec(),
+            dest,
+        )
+    }
+
+    /// Waits for the next message from any client and decodes it as an `M`, as
+    /// `Client::receive_message` does.
+    pub fn receive_message_from<M: prost::Message + Default>(&self) -> io::Result<(M, SocketAddr)> {
+        let (frame, source) = self.next_frame_from()?;
+        Ok((decode_message(&frame)?, source))
+    }
+}
+
+// Raw payloads are accepted too, for peers that send protobuf without declaring it.
+#[cfg(feature = "prost")]
+fn decode_message<M: prost::Message + Default>(frame: &Frame) -> io::Result<M> {
+    match frame.format {
+        PayloadFormat::Protobuf | PayloadFormat::Raw => {
+            Ok(M::decode(frame.payload.as_slice()).map_err(Error::codec)?)
+        }
+        format => Err(Error::codec(format!("Expected protobuf, got {:?}", format)).into()),
+    }
+}
+
+#[cfg(any(feature = "json", feature = "msgpack"))]
+impl Client {
+    /// Serializes `value` in the configured `payload_format` and sends it as `send` does.
+    /// Fails with `InvalidData`, carrying `Error::Codec`, unless that format is a serde format
+    /// compiled into this build.
+    pub fn send_value<T: serde::Serialize>(&self, value: &T) -> io::Result<usize> {
+        let format = self.shared.format;
+        self.send_as(
+            format,
+            Priority::Normal,
+            &crate::payload::encode(format, value)?,
+        )
+    }
+
+    /// Waits for the next message from the server and deserializes it from whichever format
+    /// its sender declared. A message that does not deserialize is consumed and reported as an
+    /// error.
+    pub fn receive_value<T: serde::de::DeserializeOwned>(&self) -> io::Result<T> {
+        let frame = self.next_frame()?;
+        Ok(crate::payload::decode(frame.format, &frame.payload)?)
+    }
+}
+
+#[cfg(any(feature = "json", feature = "msgpack"))]
+impl Server {
+    /// Serializes `value` and sends it as `Client::send_value` does.
+    pub fn send_value_to<T, A>(&self, value: &T, dest: A) -> io::Result<usize>
+    where
+        T: serde::Serialize,
+        A: ToSocketAddrs,
+    {
+        let format = self.shared.conf.payload_format;
+        self.send_to_as(
+            format,
+            Priority::Normal,
+            &crate::payload::encode(format, value)?,
+            dest,
+        )
+    }
+
+    /// Waits for the next message from any client and deserializes it as
+    /// `Client::receive_value` does.
+    pub fn receive_value_from<T: serde::de::DeserializeOwned>(
+        &self,
+    ) -> io::Result<(T, SocketAddr)> {
+        let (frame, source) = self.next_frame_from()?;
+        Ok((
+            crate::payload::decode(frame.format, &frame.payload)?,
+            source,
+        ))
+    }
+}
+
+#[cfg(all(test, feature = "prost"))]
+mod tests {
+    use crate::session::{Client, Server};
+    use crate::util::config::Config;
+    use std::io;
+    use std::time::Duration;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Reading {
+        #[prost(string, tag = "1")]
+        sensor: String,
+        #[prost(double, tag = "2")]
+        value: f64,
+    }
+
+    #[test]
+    fn typed_messages_round_trip() -> io::Result<()> {
+        let conf = Config {
+            host: "::1".to_string(),
+            port: 8109,
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        server.set_read_timeout(Some(Duration::from_secs(2)))?;
+        let client = Client::init(&conf)?;
+        client.set_read_timeout(Some(Duration::from_secs(2)))?;
+
+        let reading = Reading {
+            sensor: "north".to_string(),
+            value: 21.5,
+        };
+        client.send_message(&reading)?;
+        let (received, source) = server.receive_message_from::<Reading>()?;
+        assert_eq!(received, reading);
+
+        server.send_to(&[0xff], source)?;
+        let error = client.receive_message::<Reading>().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        server.send_message_to(&reading, source)?;
+        assert_eq!(client.receive_message::<Reading>()?, reading);
+
+        client.close();
+        server.close();
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "json", feature = "msgpack"))]
+mod serde_tests {
+    use crate::session::{Client, Server};
+    use crate::util::config::{Config, PayloadFormat};
+    use std::io;
+    use std::time::Duration;
+
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Reading {
+        sensor: String,
+        value: f64,
+    }
+
+    #[test]
+    fn peers_with_different_formats_share_a_transport() -> io::Result<()> {
+        let server = Server::init(&Config {
+            port: 8110,
+            payload_format: PayloadFormat::Msgpack,
+            ..Default::default()
+        })?;
+        server.set_read_timeout(Some(Duration::from_secs(2)))?;
+        let client = Client::init(&Config {
+            host: "::1".to_string(),
+            port: 8110,
+            payload_format: PayloadFormat::Json,
+            ..Default::default()
+        })?;
+        client.set_read_timeout(Some(Duration::from_secs(2)))?;
+
+        let reading = Reading {
+            sensor: "north".to_string(),
+            value: 21.5,
+        };
+        client.send_value(&reading)?;
+        let (received, source) = server.receive_value_from::<Reading>()?;
+        assert_eq!(received, reading);
+
+        server.send_value_to(&reading, source)?;
+        assert_eq!(client.receive_value::<Reading>()?, reading);
+
+        server.send_to(b"opaque", source)?;
+        assert!(client.receive_value::<Reading>().is_err());
+
+        client.close();
+        server.close();
+        Ok(())
+    }
+}
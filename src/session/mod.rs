@@ -1,2 +1,3279 @@
+mod auth;
+mod clock;
+mod codec;
+mod congestion;
+mod control;
+mod dedup;
+mod delay;
+mod health;
+mod intercept;
+mod jitter;
+#[cfg(any(feature = "prost", feature = "json", feature = "msgpack"))]
+mod message;
+mod node;
+#[cfg(feature = "otel")]
+mod otel;
+mod pipeline;
+mod pmtu;
+mod pool;
+mod queue;
+mod receipt;
+mod reconnect;
+mod reorder;
+mod replay;
+mod report;
+mod route;
+mod select;
+mod spool;
+mod stream;
+mod version;
 
+use crate::capture;
+use crate::error::Error;
+use crate::protocol::{Frame, FrameError, Priority, TraceContext, HEADER_LEN, VERSION};
+use crate::schema::SchemaRegistry;
+use crate::security::{KeyRing, Psk, PskLookup, RateLimiter, KEY_LEN};
+use crate::stream::IncomingStream;
+use crate::transport::{check_size, is_timeout, timed_out, udp, Transport};
+use crate::util;
+use crate::util::config::{CapturePoint, Config, DeliveryMode, PayloadFormat};
+use auth::Verifier;
+use clock::ClockSync;
+use codec::Codec;
+pub use codec::{CodecContext, FrameCodec};
+pub(crate) use congestion::TokenBucket;
+use congestion::{Aimd, RttEstimator};
+use control::{Control, ControlKey};
+use dedup::DedupCache;
+use delay::Delays;
+#[cfg(feature = "ingest")]
+pub(crate) use health::escape;
+pub use health::Health;
+use intercept::{ClientHooks, Interceptors};
+pub use intercept::{Decision, PeerInfo};
+use jitter::JitterBuffer;
+use pmtu::PathMtu;
+pub use pool::ClientPool;
+use queue::SendQueue;
+use receipt::Pending;
+pub use receipt::Receipt;
+pub use reconnect::ConnectionState;
+use reconnect::{is_connection_lost, Reconnector, UNANSWERED_LIMIT};
+use reorder::{ReorderBuffer, Reordered};
+use replay::ReplayWindow;
+use report::{Arrivals, Observed};
+use route::Routes;
+#[cfg(feature = "tokio")]
+pub use select::Messages;
+pub use select::{Message, SessionId};
+use spool::{Spool, Spooled};
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use std::task::Waker;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+use stream::{ResumeHook, Streams};
+use tracing::{debug, debug_span, trace, Span};
 
+const TARGET: &str = "crumb::session";
+
+// Largest datagram a session reads.
+const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+// How long the worker blocks on the socket before checking for retransmissions and shutdown.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// Upper bound on how long closing waits for queued and unacknowledged messages.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long `ping` waits for its pong when no read timeout is set.
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Where captures record the sessions of transports without socket addresses.
+// Sessions a server holds before idle ones are forgotten, bounding memory under spoofed floods.
+const MAX_PEERS: usize = 1 << 14;
+
+// How long a server keeps a session that has nothing left to send and has not been heard from.
+const PEER_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+const UNSPECIFIED: SocketAddr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0);
+
+/// Counters describing a session with one peer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// How many reliable messages may currently await acknowledgement before further ones are
+    /// held in the send queue.
+    pub send_window: usize,
+    /// Messages waiting in the send queue.
+    pub queued: usize,
+    /// Messages discarded from a full send queue under the drop-oldest policy.
+    pub queue_dropped: u64,
+    /// Messages currently buffered waiting for an earlier one. Zero unless `ordered` is set, as
+    /// are the other reordering counters.
+    pub reorder_depth: usize,
+    /// The largest `reorder_depth` seen.
+    pub max_reorder_depth: usize,
+    /// Messages that arrived after their place in the sequence had been delivered, usually
+    /// retransmissions whose acknowledgement was lost.
+    pub dropped_late: u64,
+    /// Reliable messages dropped as duplicates or replays by an unordered receiver's replay
+    /// window. Ordered receivers count these as `dropped_late` instead.
+    pub dropped_replayed: u64,
+    /// Reliable messages dropped because the dedup cache had seen their id. Zero unless
+    /// `dedup_cache` is set.
+    pub dedup_hits: u64,
+    /// Packets dropped because their checksum did not match, their header was invalid or their
+    /// payload could not be decompressed.
+    pub dropped_corrupt: u64,
+    /// Packets dropped because the peer speaks another protocol version.
+    pub dropped_version: u64,
+    /// Packets dropped because they failed pre-shared key authentication, or were encrypted
+    /// when this end has no key or plain when it has one.
+    pub dropped_unauthenticated: u64,
+    /// Messages dropped because the client sent them before its token was accepted. Zero
+    /// unless the server verifies tokens with `Server::on_auth`.
+    pub dropped_unverified: u64,
+    /// Stamped messages the jitter buffer holds until their time. Zero unless `jitter_delay`
+    /// is set, as is `jitter_dropped`.
+    pub jitter_depth: usize,
+    /// Stamped messages dropped for arriving too late to be played out in time and order.
+    pub jitter_dropped: u64,
+}
+
+/// Traffic statistics for a session with one peer, for dashboards and send rate adaptation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    /// Smoothed round-trip time, measured from acknowledgements of reliable messages that were
+    /// sent once. `None` until the first such acknowledgement.
+    pub rtt: Option<Duration>,
+    /// How long a reliable message currently waits for its acknowledgement before it is sent
+    /// again, derived from the round-trip time and its variation as in RFC 6298.
+    pub rto: Duration,
+    /// The fraction of reliable transmissions that were retransmissions, between 0 and 1.
+    pub loss_rate: f64,
+    /// Reliable messages sent again because their acknowledgement was overdue.
+    pub retransmits: u64,
+    /// Bytes sent to the peer, acknowledgements and retransmissions included.
+    pub bytes_sent: u64,
+    /// Bytes received from the peer, including packets that were dropped.
+    pub bytes_received: u64,
+    /// When a packet was last sent to or received from the peer.
+    pub last_activity: Instant,
+    /// The largest datagram, in bytes of UDP payload, known to reach the peer. `None` unless
+    /// `pmtud` is set. Messages are never split, so sending larger ones fails with
+    /// `Error::PayloadTooLarge` where the path MTU is enforced.
+    pub path_mtu: Option<usize>,
+    /// The fraction of messages the peer reported lost on the way to it, between 0 and 1.
+    /// `None` until the peer reports, which it does with `report_interval` set.
+    pub remote_loss_rate: Option<f64>,
+    /// How irregularly the peer reported messages arriving, in its latest report.
+    pub remote_jitter: Option<Duration>,
+    /// How long the peer's stamped messages take to arrive, smoothed. `None` unless the peer
+    /// sets `timestamps` and this end either has `clock_synced` or has had a ping answered,
+    /// giving it the offset between the clocks.
+    pub one_way_delay: Option<Duration>,
+    /// How much the time the peer's stamped messages take to arrive varies, whether or not the
+    /// clocks are synced. `None` until two have arrived.
+    pub transit_jitter: Option<Duration>,
+    /// How many seconds the peer's clock is ahead of this one's, negative if it is behind, as
+    /// estimated from pings. `None` until a ping has been answered.
+    pub clock_offset: Option<f64>,
+    /// The frame layout version written to the peer: the newest both ends speak, once the peer
+    /// has been heard from.
+    pub protocol_version: u8,
+}
+
+/// Session client over the transport selected in `Config`.
+///
+/// Unless `delivery` is at-most-once, every message carries a sequence number and is
+/// retransmitted until the server acknowledges it. Exactly-once receivers drop the duplicates
+/// this can cause, and with `ordered` set, received messages are delivered in the order they
+/// were sent. Acknowledgements and retransmissions are handled by a background thread that
+/// stops when the client is closed or dropped.
+///
+/// Messages wait in a bounded send queue while the congestion window is full or the send rate
+/// exceeds `max_rate_kbps`. When the queue is full, `send_queue_policy` decides whether `send`
+/// blocks, fails or discards the oldest queued message.
+///
+/// With `reconnect_attempts` set, a client that loses the server, because the socket reports it
+/// unreachable or a reliable message goes unacknowledged through several retransmissions, opens
+/// a new transport to it with exponential backoff. Messages sent meanwhile are queued, and once
+/// the server answers, unacknowledged ones are sent again. A restarted server starts a new
+/// session, so with `ordered` set it waits for sequence numbers the client has long passed.
+///
+/// With several `endpoints`, the client starts with the first it can open a transport to and
+/// fails over to the next ones in turn when reconnecting. `endpoint` reports which is in use.
+///
+/// With a `spool_dir`, reliable messages are written to disk before they are queued and
+/// removed once acknowledged, and a client started on the same directory sends those left over
+/// first. A message the server got but had not acknowledged is sent again, so delivery across a
+/// restart is at least once. Only one client at a time should use a directory.
+pub struct Client {
+    shared: Arc<ClientShared>,
+    inbox: Mutex<mpsc::Receiver<Frame>>,
+    read_timeout: Mutex<Option<Duration>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+type ConnectionHook = Box<dyn Fn(ConnectionState) + Send + Sync>;
+
+struct ClientShared {
+    // Replaced by the worker on every reconnection attempt.
+    transport: RwLock<Box<dyn Transport>>,
+    conf: Config,
+    // Index into `conf.endpoints` of the endpoint `transport` is connected to.
+    active: AtomicUsize,
+    connection: Mutex<ConnectionState>,
+    hook: RwLock<Option<ConnectionHook>>,
+    hooks: ClientHooks,
+    codec: Arc<Codec>,
+    format: PayloadFormat,
+    schemas: SchemaRegistry,
+    node_id: String,
+    // Names this client's session to the server, wherever it sends from.
+    token: u64,
+    state: Mutex<PeerState>,
+    queue_space: Condvar,
+    pacer: Mutex<TokenBucket>,
+    next_transfer: AtomicU64,
+    // Answers to resume requests, by transfer, awaiting collection by `send_resumable`.
+    resumes: Mutex<HashMap<u64, u64>>,
+    control: Option<ControlKey>,
+    // Numbers control requests, from a random start like message ids.
+    next_control: AtomicU64,
+    // Answers to control requests, by id, awaiting collection by `control`.
+    answers: Mutex<HashMap<u64, Result<String, String>>>,
+    // The server's verdict on `auth_token`, awaiting collection by `authenticate`.
+    verdict: Mutex<Option<bool>>,
+    running: AtomicBool,
+    span: Span,
+}
+
+impl Client {
+    pub fn init(conf: &Config) -> io::Result<Client> {
+        let (active, transport) = connect_first(conf)?;
+        Client::start(conf, transport, active)
+    }
+
+    /// A client over `transport` rather than one opened from `conf`, such as an in-memory
+    /// endpoint in tests. Reconnection attempts still open transports from `conf`.
+    pub fn with_transport(conf: &Config, transport: Box<dyn Transport>) -> io::Result<Client> {
+        transport.set_read_timeout(Some(POLL_INTERVAL))?;
+        Client::start(conf, transport, 0)
+    }
+
+    fn start(conf: &Config, transport: Box<dyn Transport>, active: usize) -> io::Result<Client> {
+        let span = debug_span!(target: TARGET, "client", peer = ?transport.peer_addr());
+        let capture = capture::Writer::from_config(conf)?.map(|writer| {
+            capture::Endpoint::new(
+                writer,
+                conf.capture_point,
+                transport.local_addr().unwrap_or(UNSPECIFIED),
+                transport.peer_addr().unwrap_or(UNSPECIFIED),
+            )
+        });
+
+        let codec = Arc::new(Codec::default());
+        let shared = Arc::new(ClientShared {
+            transport: RwLock::new(transport),
+            conf: conf.clone(),
+            active: AtomicUsize::new(active),
+            connection: Mutex::new(ConnectionState::Connected),
+            hook: RwLock::default(),
+            hooks: ClientHooks::default(),
+            codec: codec.clone(),
+            format: conf.payload_format,
+            schemas: SchemaRegistry::new(conf),
+            node_id: util::node_id(conf),
+            token: first_msg_id(),
+            state: Mutex::new(
+                PeerState::new(conf)
+                    .with_psk(Psk::from_config(conf)?.map(Arc::new))
+                    .with_ring(KeyRing::from_config(conf)?.map(Arc::new))
+                    .with_spool(Spool::open(conf)?)
+                    .with_capture(capture)
+                    .with_codec(codec),
+            ),
+            queue_space: Condvar::new(),
+            pacer: Mutex::new(TokenBucket::new(conf.max_rate_kbps)),
+            next_transfer: AtomicU64::new(0),
+            resumes: Mutex::default(),
+            control: ControlKey::from_config(conf)?,
+            next_control: AtomicU64::new(first_msg_id()),
+            answers: Mutex::default(),
+            verdict: Mutex::default(),
+            running: AtomicBool::new(true),
+            span,
+        });
+        let (inbox_sender, inbox) = mpsc::channel();
+        let worker = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("crumb-session-client".to_string())
+                .spawn(move || run_client(&shared, inbox_sender))?
+        };
+
+        let client = Client {
+            shared,
+            inbox: Mutex::new(inbox),
+            read_timeout: Mutex::default(),
+            worker: Some(worker),
+        };
+        client.shared.probe_versions();
+        client.shared.announce(true);
+        client.authenticate()?;
+        Ok(client)
+    }
+
+    /// Queues `data` for the server and sends as much of the queue as the congestion window and
+    /// rate limit allow. Socket errors are logged rather than returned, since queued messages
+    /// may be sent later by the background thread. Fails with `ErrorKind::NotConnected` once
+    /// every reconnection attempt has failed.
+    pub fn send(&self, data: &[u8]) -> io::Result<usize> {
+        self.send_as(self.shared.format, Priority::Normal, data)
+    }
+
+    /// Like `send`, but queues `data` in the lane for `priority`, so it overtakes queued
+    /// messages of less urgent priorities.
+    pub fn send_with_priority(&self, data: &[u8], priority: Priority) -> io::Result<usize> {
+        self.send_as(self.shared.format, priority, data)
+    }
+
+    /// Like `send`, but carries `trace` with the message, so that the server's handling of it
+    /// joins the sender's distributed trace. It replaces any trace the send hook set.
+    pub fn send_traced(&self, data: &[u8], trace: &TraceContext) -> io::Result<usize> {
+        let _enter = self.shared.span.enter();
+        let mut frame = self
+            .shared
+            .frame(self.shared.format, Priority::Normal, data)?;
+        frame.trace = Some(*trace);
+        trace!(target: TARGET, traceparent = %trace, "traced message queued");
+        self.queue(frame, None, SendQueue::must_wait)?;
+        Ok(data.len())
+    }
+
+    // Sends `data` declared to be in `format`, regardless of `payload_format`.
+    pub(crate) fn send_as(
+        &self,
+        format: PayloadFormat,
+        priority: Priority,
+        data: &[u8],
+    ) -> io::Result<usize> {
+        let _enter = self.shared.span.enter();
+        let frame = self.shared.frame(format, priority, data)?;
+        self.queue(frame, None, SendQueue::must_wait)?;
+        Ok(data.len())
+    }
+
+    // Waits for as long as `wait` holds for the send queue, then queues `frame`, with the
+    // receipt to settle for it if any, and sends what the congestion window and rate limit
+    // allow.
+    fn queue(
+        &self,
+        frame: Frame,
+        receipt: Option<Pending>,
+        wait: fn(&SendQueue<Queued>) -> bool,
+    ) -> io::Result<()> {
+        let state = lock(&self.shared.state);
+        let mut state = self
+            .shared
+            .queue_space
+            .wait_while(state, |state| {
+                wait(&state.queue) && !self.shared.is_disconnected()
+            })
+            .unwrap_or_else(|e| e.into_inner());
+        if self.shared.is_disconnected() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Every reconnection attempt failed",
+            ));
+        }
+        state.check_size(&frame)?;
+        state.enqueue_with_receipt(frame, receipt)?;
+        self.shared.flush(&mut state);
+        Ok(())
+    }
+
+    /// Waits for the next message from the server and copies it into `buffer`, truncating if
+    /// necessary.
+    pub fn receive(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let timeout = *lock(&self.read_timeout);
+        let message = next_message(&lock(&self.inbox), timeout)?;
+        self.shared.took_message();
+        Ok(copy_truncated(&message.payload, buffer))
+    }
+
+    /// Like `receive`, but fails with `Error::Timeout` if no message arrives within `timeout`,
+    /// whatever the read timeout.
+    pub fn receive_timeout(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let message = timed_out(next_message(&lock(&self.inbox), Some(timeout)))?;
+        self.shared.took_message();
+        Ok(copy_truncated(&message.payload, buffer))
+    }
+
+    /// Like `receive_timeout`, giving up at `deadline`.
+    pub fn receive_until(&self, buffer: &mut [u8], deadline: Instant) -> io::Result<usize> {
+        self.receive_timeout(buffer, deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Bounds how long `receive` blocks. `None` blocks until a message arrives.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *lock(&self.read_timeout) = timeout;
+        Ok(())
+    }
+
+    /// Measures the round trip to the server through both ends' full codec and encryption
+    /// stack, with a ping frame the server's session layer answers by itself. Fails with
+    /// `ErrorKind::TimedOut` if no answer arrives within the read timeout, or five seconds if
+    /// none is set.
+    pub fn ping(&self) -> io::Result<Duration> {
+        let _enter = self.shared.span.enter();
+        let timeout = lock(&self.read_timeout).unwrap_or(PING_TIMEOUT);
+        let mut state = lock(&self.shared.state);
+        let (id, packet) = state.ping(Instant::now());
+        if let Err(e) = self.shared.transport().send(&packet) {
+            state.take_pong(id);
+            return Err(e);
+        }
+        // The worker notifies after handling every packet, pongs included.
+        let (mut state, _) = self
+            .shared
+            .queue_space
+            .wait_timeout_while(state, timeout, |state| !state.ponged(id))
+            .unwrap_or_else(|e| e.into_inner());
+        state
+            .take_pong(id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "No pong from the server"))
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        lock(&self.shared.state).metrics()
+    }
+
+    pub fn stats(&self) -> Stats {
+        lock(&self.shared.state).stats()
+    }
+
+    /// The schema versions this client stamps on and accepts in protobuf messages. Reload it
+    /// after the `.proto` file changes to switch versions without reconnecting.
+    pub fn schemas(&self) -> &SchemaRegistry {
+        &self.shared.schemas
+    }
+
+    /// Whether the client is connected, reconnecting or has given up on the server.
+    pub fn connection_state(&self) -> ConnectionState {
+        *lock(&self.shared.connection)
+    }
+
+    /// Registers `hook` to be called with every change of `connection_state`, replacing any
+    /// earlier hook. It runs on the background thread, so it should return quickly.
+    pub fn on_connection_change<F>(&self, hook: F)
+    where
+        F: Fn(ConnectionState) + Send + Sync + 'static,
+    {
+        *self.shared.hook.write().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(hook));
+    }
+
+    /// The entry of `endpoints` the client is connected or was last connected to. `None` if no
+    /// endpoints are configured, in which case it talks to `host:port`.
+    pub fn endpoint(&self) -> Option<SocketAddr> {
+        let active = self.shared.active.load(Ordering::Relaxed);
+        self.shared.conf.endpoints.get(active).copied()
+    }
+
+    /// The local address of the current transport, which changes when the client reconnects.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.transport().local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.transport().peer_addr()
+    }
+
+    /// Waits (up to a bound) for queued messages to be sent and acknowledged, then stops the
+    /// background thread and closes the transport. Dropping the client does the same.
+    pub fn close(self) {
+        drop(self);
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let state = lock(&self.shared.state);
+        let (state, linger) = self
+            .shared
+            .queue_space
+            .wait_timeout_while(state, CLOSE_TIMEOUT, |state| {
+                !state.is_idle() && !self.shared.is_disconnected()
+            })
+            .unwrap_or_else(|e| e.into_inner());
+        drop(state);
+        if linger.timed_out() {
+            debug!(target: TARGET, "gave up waiting for unacknowledged messages");
+        }
+
+        self.shared.running.store(false, Ordering::Release);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl ClientShared {
+    fn transport(&self) -> RwLockReadGuard<'_, Box<dyn Transport>> {
+        self.transport.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn is_disconnected(&self) -> bool {
+        *lock(&self.connection) == ConnectionState::Disconnected
+    }
+
+    // Called with the state locked so packets leave in sequence order. Messages stay queued
+    // while the client is not connected.
+    fn flush(&self, state: &mut PeerState) {
+        if *lock(&self.connection) != ConnectionState::Connected {
+            return;
+        }
+        for packet in state.ready_packets(&mut lock(&self.pacer), Instant::now()) {
+            if let Err(e) = self.transport().send(&packet) {
+                debug!(target: TARGET, error = %e, "send failed");
+            }
+        }
+    }
+
+    // Counts a message as taken from the inbox, telling the server if that reopens the receive
+    // window.
+    fn took_message(&self) {
+        let update = lock(&self.state).took();
+        self.send_all(update.into_iter().collect(), "window update");
+    }
+
+    fn send_all(&self, packets: Vec<Vec<u8>>, what: &str) {
+        for packet in packets {
+            lock(&self.pacer).take(packet.len());
+            if let Err(e) = self.transport().send(&packet) {
+                debug!(target: TARGET, error = %e, "{} failed", what);
+            }
+        }
+    }
+
+    // Opens a new transport for reconnection attempt `attempt`, resolving the host and
+    // handshaking afresh, and pings the server so that the first reply confirms the path. The
+    // session state is only locked once the transport is open, so sends are not held up for the
+    // length of a handshake.
+    fn reconnect(&self, attempt: u32) -> io::Result<()> {
+        let count = self.conf.endpoints.len().max(1);
+        let lost = self.active.load(Ordering::Relaxed);
+        let endpoint = (lost + attempt as usize) % count;
+        let transport = connect(&self.conf, endpoint)?;
+        debug!(target: TARGET, endpoint, peer = ?transport.peer_addr(), "reconnecting");
+        self.active.store(endpoint, Ordering::Relaxed);
+        let (old, probes) = {
+            let mut state = lock(&self.state);
+            if let Some(capture) = &mut state.capture {
+                capture.moved(
+                    transport.local_addr().unwrap_or(UNSPECIFIED),
+                    transport.peer_addr().unwrap_or(UNSPECIFIED),
+                );
+            }
+            let old = std::mem::replace(
+                &mut *self.transport.write().unwrap_or_else(|e| e.into_inner()),
+                transport,
+            );
+            // The new server may speak another version, so the exchange starts over.
+            (old, state.version_probes())
+        };
+        old.close();
+        for probe in probes {
+            self.transport().send(&probe)?;
+        }
+        Ok(())
+    }
+
+    // Records a change of connection state. The hook is called separately, without the session
+    // state locked, so that it may use the client.
+    fn set_connection(&self, connection: ConnectionState) {
+        debug!(target: TARGET, ?connection, "connection state changed");
+        *lock(&self.connection) = connection;
+    }
+
+    fn report_connection(&self, connection: ConnectionState) {
+        if let Some(hook) = &*self.hook.read().unwrap_or_else(|e| e.into_inner()) {
+            hook(connection);
+        }
+    }
+}
+
+fn run_client(shared: &ClientShared, inbox: mpsc::Sender<Frame>) {
+    let _enter = shared.span.enter();
+    let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut reconnector = Reconnector::new(&shared.conf);
+    while shared.running.load(Ordering::Acquire) {
+        let received = shared.transport().receive_timestamped(&mut buffer);
+        let mut lost = false;
+        let mut messages = Vec::new();
+        match received {
+            Ok((received, at)) => {
+                let ack;
+                (messages, ack) = lock(&shared.state).incoming_at(&buffer[..received], at);
+                if let Some(ack) = ack {
+                    if let Err(e) = shared.transport().send(&ack) {
+                        debug!(target: TARGET, error = %e, "ack failed");
+                    }
+                }
+            }
+            Err(e) => {
+                lost = is_connection_lost(&e);
+                wait_after(&e);
+            }
+        }
+        // Held messages come due whether or not anything arrived.
+        messages.extend(lock(&shared.state).playout(Instant::now()));
+        for mut message in messages {
+            if message.flags & Frame::STREAM != 0 {
+                shared.stream_chunk(&message.payload);
+            } else if message.control {
+                shared.control_answer(&message.payload);
+            } else if message.auth {
+                shared.auth_answer(&message.payload);
+            } else if message.hello {
+                shared.hello(&message.payload);
+            } else if shared.schemas.accepts(&message)
+                && shared.hooks.received(&mut message) == Decision::Pass
+            {
+                if let Some(trace) = &message.trace {
+                    trace!(target: TARGET, traceparent = %trace, "traced message received");
+                }
+                let _ = inbox.send(message);
+                continue;
+            }
+            shared.took_message();
+        }
+
+        let now = Instant::now();
+        let mut state = lock(&shared.state);
+        let mut changed = None;
+        let connected = *lock(&shared.connection) == ConnectionState::Connected;
+        if lost || (connected && state.unanswered >= UNANSWERED_LIMIT) {
+            changed = reconnector.on_lost(now);
+        }
+        if let Some(connection) = state
+            .last_heard
+            .and_then(|heard| reconnector.on_heard(heard))
+        {
+            changed = Some(connection);
+            shared.set_connection(connection);
+            shared.send_all(state.reconnected(now), "retransmission");
+        }
+        let (attempt, failed) = reconnector.poll(now);
+        changed = failed.or(changed);
+        if let Some(attempt) = attempt {
+            drop(state);
+            if let Err(e) = shared.reconnect(attempt) {
+                debug!(target: TARGET, error = %e, "reconnection attempt failed");
+                changed = reconnector.fail(now).or(changed);
+            }
+            state = lock(&shared.state);
+        }
+        if let Some(connection) = changed {
+            shared.set_connection(connection);
+        }
+        if changed == Some(ConnectionState::Disconnected) {
+            state.abandon_receipts();
+        }
+
+        if *lock(&shared.connection) == ConnectionState::Connected {
+            shared.send_all(state.retransmissions(now), "retransmission");
+            if let Some(probe) = state.probe(now) {
+                if let Err(e) = shared.transport().send(&probe) {
+                    trace!(target: TARGET, len = probe.len(), error = %e, "probe failed");
+                }
+            }
+            shared.send_all(
+                state.window_query(now).into_iter().collect(),
+                "window query",
+            );
+            shared.send_all(state.keepalive(now).into_iter().collect(), "keepalive");
+            shared.send_all(state.report(now).into_iter().collect(), "report");
+            shared.send_all(state.clock_ping(now).into_iter().collect(), "clock ping");
+            shared.flush(&mut state);
+        }
+        drop(state);
+        if let Some(connection) = changed {
+            shared.report_connection(connection);
+        }
+        shared.queue_space.notify_all();
+    }
+}
+
+// Opens a transport to the first of `conf.endpoints`, in order, that takes one, returning its
+// index. Only `host:port` is tried when there are none.
+fn connect_first(conf: &Config) -> io::Result<(usize, Box<dyn Transport>)> {
+    let last = conf.endpoints.len().saturating_sub(1);
+    for endpoint in 0..last {
+        match connect(conf, endpoint) {
+            Ok(transport) => return Ok((endpoint, transport)),
+            Err(e) => debug!(target: TARGET, endpoint, error = %e, "endpoint unavailable"),
+        }
+    }
+    connect(conf, last).map(|transport| (last, transport))
+}
+
+// Opens a transport to endpoint `endpoint` of `conf.endpoints`, or `host:port` if there are none.
+fn connect(conf: &Config, endpoint: usize) -> io::Result<Box<dyn Transport>> {
+    let transport = match conf.endpoints.get(endpoint) {
+        Some(addr) => <dyn Transport>::from_config(&Config {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            ..conf.clone()
+        })?,
+        None => <dyn Transport>::from_config(conf)?,
+    };
+    transport.set_read_timeout(Some(POLL_INTERVAL))?;
+    Ok(transport)
+}
+
+/// Session server over UDP, keeping separate reliability state and send queues for every client
+/// it hears from.
+pub struct Server {
+    shared: Arc<ServerShared>,
+    inbox: Mutex<mpsc::Receiver<(Frame, SocketAddr)>>,
+    streams: Mutex<mpsc::Receiver<(SocketAddr, IncomingStream)>>,
+    read_timeout: Mutex<Option<Duration>>,
+    worker: Option<JoinHandle<()>>,
+    health: Option<JoinHandle<()>>,
+}
+
+struct ServerShared {
+    server: udp::Server,
+    conf: Config,
+    schemas: SchemaRegistry,
+    node_id: String,
+    migrations: AtomicU64,
+    // Datagrams from sources without a session that made none.
+    dropped_unknown: AtomicU64,
+    psk: Option<Arc<Psk>>,
+    ring: Option<Arc<KeyRing>>,
+    keys: Arc<PskLookup>,
+    verifier: Arc<Verifier>,
+    peers: Mutex<HashMap<SocketAddr, PeerState>>,
+    queue_space: Condvar,
+    pacer: Mutex<TokenBucket>,
+    // The task last found the inbox empty while polling `Server::messages`.
+    waker: Mutex<Option<Waker>>,
+    resume: RwLock<Option<ResumeHook>>,
+    interceptors: Interceptors,
+    routes: Routes,
+    codec: Arc<Codec>,
+    capture: Option<Arc<capture::Writer>>,
+    control: Control,
+    running: AtomicBool,
+    started: Instant,
+    last_error: Mutex<Option<String>>,
+    span: Span,
+}
+
+impl Server {
+    pub fn init(conf: &Config) -> io::Result<Server> {
+        let server = udp::Server::init(conf)?;
+        server.set_read_timeout(Some(POLL_INTERVAL))?;
+        let span = debug_span!(target: TARGET, "server", bind = ?server.local_addr());
+
+        let shared = Arc::new(ServerShared {
+            server,
+            conf: conf.clone(),
+            schemas: SchemaRegistry::new(conf),
+            node_id: util::node_id(conf),
+            migrations: AtomicU64::new(0),
+            dropped_unknown: AtomicU64::new(0),
+            psk: Psk::from_config(conf)?.map(Arc::new),
+            ring: KeyRing::from_config(conf)?.map(Arc::new),
+            keys: Arc::default(),
+            verifier: Arc::default(),
+            peers: Mutex::default(),
+            queue_space: Condvar::new(),
+            pacer: Mutex::new(TokenBucket::new(conf.max_rate_kbps)),
+            waker: Mutex::default(),
+            resume: RwLock::default(),
+            interceptors: Interceptors::default(),
+            routes: Routes::default(),
+            codec: Arc::default(),
+            capture: capture::Writer::from_config(conf)?,
+            control: Control::new(ControlKey::from_config(conf)?),
+            running: AtomicBool::new(true),
+            started: Instant::now(),
+            last_error: Mutex::default(),
+            span,
+        });
+        let (inbox_sender, inbox) = mpsc::channel();
+        let (streams_sender, streams) = mpsc::channel();
+        let worker = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("crumb-session-server".to_string())
+                .spawn(move || run_server(&shared, inbox_sender, Streams::new(streams_sender)))?
+        };
+        let health = health::serve(&shared)?;
+
+        Ok(Server {
+            shared,
+            inbox: Mutex::new(inbox),
+            streams: Mutex::new(streams),
+            read_timeout: Mutex::default(),
+            worker: Some(worker),
+            health,
+        })
+    }
+
+    /// Queues `data` for the client at `dest`, as `Client::send` does.
+    pub fn send_to<A: ToSocketAddrs>(&self, data: &[u8], dest: A) -> io::Result<usize> {
+        self.send_to_as(
+            self.shared.conf.payload_format,
+            Priority::Normal,
+            data,
+            dest,
+        )
+    }
+
+    /// Like `send_to`, but queues `data` in the lane for `priority`, as
+    /// `Client::send_with_priority` does.
+    pub fn send_to_with_priority<A: ToSocketAddrs>(
+        &self,
+        data: &[u8],
+        dest: A,
+        priority: Priority,
+    ) -> io::Result<usize> {
+        self.send_to_as(self.shared.conf.payload_format, priority, data, dest)
+    }
+
+    // Sends `data` declared to be in `format`, regardless of `payload_format`.
+    fn send_to_as<A: ToSocketAddrs>(
+        &self,
+        format: PayloadFormat,
+        priority: Priority,
+        data: &[u8],
+        dest: A,
+    ) -> io::Result<usize> {
+        let _enter = self.shared.span.enter();
+        let dest = dest
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No address to send to"))?;
+        self.queue(dest, self.frame(format, priority, data)?)?;
+        Ok(data.len())
+    }
+
+    /// Queues `data` for every client that has been heard from, with one frame compressed for
+    /// all of them, and returns each session with the outcome of its send. Clients this server
+    /// has only sent to, or whose frames never authenticated, are left out.
+    ///
+    /// Sessions are queued for one after another, each waiting for queue space as `send_to`
+    /// would, so under the `error` queue policy a full queue fails that session's copy alone.
+    /// Fails outright only if the message cannot be compressed.
+    pub fn broadcast(&self, data: &[u8]) -> io::Result<Vec<(SessionId, io::Result<usize>)>> {
+        let _enter = self.shared.span.enter();
+        let frame = self.frame(self.shared.conf.payload_format, Priority::Normal, data)?;
+        let sessions: Vec<SocketAddr> = lock(&self.shared.peers)
+            .iter()
+            .filter(|(_, peer)| peer.last_heard.is_some())
+            .map(|(&addr, _)| addr)
+            .collect();
+        trace!(target: TARGET, sessions = sessions.len(), bytes = data.len(), "broadcast");
+
+        let results = sessions
+            .into_iter()
+            .map(|dest| {
+                let result = self.queue(dest, frame.clone()).map(|()| data.len());
+                if let Err(e) = &result {
+                    debug!(target: TARGET, %dest, error = %e, "broadcast send failed");
+                }
+                (dest.into(), result)
+            })
+            .collect();
+        Ok(results)
+    }
+
+    fn frame(&self, format: PayloadFormat, priority: Priority, data: &[u8]) -> io::Result<Frame> {
+        let message = Frame {
+            format,
+            priority,
+            schema: self.shared.schemas.stamp(format),
+            ..Frame::new(data.to_vec())
+        };
+        let frame = frame(&self.shared.conf, Some(&*self.shared.codec), message)?;
+        Ok(Frame {
+            timestamp: self.shared.conf.timestamps.then(delay::timestamp),
+            ..frame
+        })
+    }
+
+    // Waits for room in the queue for `dest`, then queues `frame` and sends what the congestion
+    // window allows.
+    fn queue(&self, dest: SocketAddr, frame: Frame) -> io::Result<()> {
+        let peers = lock(&self.shared.peers);
+        let mut peers = self
+            .shared
+            .queue_space
+            .wait_while(peers, |peers| {
+                peers.get(&dest).is_some_and(|peer| peer.queue.must_wait())
+            })
+            .unwrap_or_else(|e| e.into_inner());
+        let peer = peers
+            .entry(dest)
+            .or_insert_with(|| self.shared.new_peer(dest));
+        peer.check_size(&frame)?;
+        peer.enqueue(frame)?;
+        self.shared.flush(dest, peer);
+        Ok(())
+    }
+
+    /// Waits for the next message from any client and copies it into `buffer`, truncating if
+    /// necessary.
+    pub fn receive_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let timeout = *lock(&self.read_timeout);
+        let (message, source) = next_message(&lock(&self.inbox), timeout)?;
+        self.shared.took_message(source);
+        Ok((copy_truncated(&message.payload, buffer), source))
+    }
+
+    /// Bounds how long `receive_from` blocks. `None` blocks until a message arrives.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *lock(&self.read_timeout) = timeout;
+        Ok(())
+    }
+
+    /// Calls `handler` with every message received and queues the reply it returns, if any, for
+    /// the sender, as `udp::Server::serve` does. Returns once the server is shut down.
+    pub fn serve<F>(&self, handler: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8], SocketAddr) -> Option<Vec<u8>>,
+    {
+        let e = crate::transport::serve(
+            |buffer| self.receive_from(buffer),
+            |reply, peer| self.send_to(reply, peer),
+            handler,
+        );
+        if self.shared.server.is_shut_down() {
+            return Ok(());
+        }
+        Err(e)
+    }
+
+    /// Like `serve`, but runs `handler` on `conf.workers` threads, as
+    /// `udp::Server::serve_concurrent` does.
+    pub fn serve_concurrent<F>(&self, handler: F) -> io::Result<()>
+    where
+        F: Fn(&[u8], SocketAddr) -> Option<Vec<u8>> + Sync,
+    {
+        let e = crate::transport::serve_concurrent(
+            |buffer| self.receive_from(buffer),
+            |reply, peer| self.send_to(reply, peer),
+            handler,
+            self.shared.conf.workers,
+        );
+        if self.shared.server.is_shut_down() {
+            return Ok(());
+        }
+        Err(e)
+    }
+
+    /// Returns a handle that stops this server from another thread. Once it is used,
+    /// `receive_from` fails with `ErrorKind::NotConnected` and unacknowledged messages are
+    /// abandoned.
+    pub fn shutdown_handle(&self) -> io::Result<udp::ShutdownHandle> {
+        self.shared.server.shutdown_handle()
+    }
+
+    /// Registers `hook` to be called with the source address of every datagram the access
+    /// control list rejects, as `udp::Server::on_unauthorized` does. Rejected sources never get
+    /// session state.
+    pub fn on_unauthorized<F>(&self, hook: F)
+    where
+        F: Fn(SocketAddr) + Send + Sync + 'static,
+    {
+        self.shared.server.on_unauthorized(hook);
+    }
+
+    /// The per-source limit applied to incoming datagrams, acknowledgements included, with its
+    /// throttling counters.
+    pub fn rate_limit(&self) -> &RateLimiter {
+        self.shared.server.rate_limit()
+    }
+
+    /// Registers `lookup` to find the pre-shared key named by the identity in a client's sealed
+    /// frames, replacing any earlier one. It is called when a client first sends under an
+    /// identity, and the client is switched to the key it returns once a frame opens with it.
+    /// Frames naming an identity it does not know are dropped as unauthenticated.
+    ///
+    /// From then on clients must seal every frame, so `security` may stay `none` when every
+    /// client has an identity. Frames without one are opened with `psk`, if set.
+    pub fn on_psk_identity<F>(&self, lookup: F)
+    where
+        F: Fn(&[u8]) -> Option<[u8; KEY_LEN]> + Send + Sync + 'static,
+    {
+        self.shared.keys.set(Box::new(lookup));
+    }
+
+    /// Session counters for the client at `peer`, if it has been heard from.
+    pub fn metrics(&self, peer: SocketAddr) -> Option<Metrics> {
+        lock(&self.shared.peers).get(&peer).map(PeerState::metrics)
+    }
+
+    /// Traffic statistics for the client at `peer`, if it has been heard from.
+    pub fn stats(&self, peer: SocketAddr) -> Option<Stats> {
+        lock(&self.shared.peers).get(&peer).map(PeerState::stats)
+    }
+
+    /// Datagrams from addresses the server held no session for that did not decode or, with a
+    /// pre-shared key, open, or came while the session table was full of busy ones. They make
+    /// no session, so `metrics` has nothing for their source.
+    pub fn dropped_unknown(&self) -> u64 {
+        self.shared.dropped_unknown.load(Ordering::Relaxed)
+    }
+
+    /// The schema versions this server stamps on and accepts in protobuf messages, as
+    /// `Client::schemas`.
+    pub fn schemas(&self) -> &SchemaRegistry {
+        &self.shared.schemas
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.server.local_addr()
+    }
+
+    /// Closes every session as `Client::close` does. Dropping the server does the same.
+    pub fn close(self) {
+        drop(self);
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let peers = lock(&self.shared.peers);
+        let (peers, linger) = self
+            .shared
+            .queue_space
+            .wait_timeout_while(peers, CLOSE_TIMEOUT, |peers| {
+                !self.shared.server.is_shut_down() && !peers.values().all(PeerState::is_idle)
+            })
+            .unwrap_or_else(|e| e.into_inner());
+        drop(peers);
+        if linger.timed_out() {
+            debug!(target: TARGET, "gave up waiting for unacknowledged messages");
+        }
+
+        self.shared.running.store(false, Ordering::Release);
+        for worker in [self.worker.take(), self.health.take()]
+            .into_iter()
+            .flatten()
+        {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl ServerShared {
+    fn new_peer(&self, addr: SocketAddr) -> PeerState {
+        let capture = self.capture.clone().map(|writer| {
+            let local = self.server.local_addr().unwrap_or(UNSPECIFIED);
+            capture::Endpoint::new(writer, self.conf.capture_point, local, addr)
+        });
+        PeerState::new(&self.conf)
+            .with_psk(self.psk.clone())
+            .with_ring(self.ring.clone())
+            .with_keys(self.keys.clone())
+            .with_verifier(self.verifier.clone())
+            .with_capture(capture)
+            .with_codec(self.codec.clone())
+    }
+
+    // Lets a task polling `Server::messages` know the inbox has changed.
+    fn wake(&self) {
+        if let Some(waker) = lock(&self.waker).take() {
+            waker.wake();
+        }
+    }
+
+    // Counts a message from `source` as taken from the inbox, telling the client if that
+    // reopens the receive window.
+    fn took_message(&self, source: SocketAddr) {
+        let update = lock(&self.peers).get_mut(&source).and_then(PeerState::took);
+        if let Some(update) = update {
+            if let Err(e) = self.server.send_to(&update, source) {
+                debug!(target: TARGET, %source, error = %e, "window update failed");
+            }
+        }
+    }
+
+    // Called with the peer table locked so packets leave in sequence order.
+    fn flush(&self, peer: SocketAddr, state: &mut PeerState) {
+        for packet in state.ready_packets(&mut lock(&self.pacer), Instant::now()) {
+            if let Err(e) = self.server.send_to(&packet, peer) {
+                debug!(target: TARGET, %peer, error = %e, "send failed");
+                self.failed(&e);
+            }
+        }
+    }
+
+    // Hands what `source` sent to the session machinery it is for, or to the application.
+    fn dispatch(
+        &self,
+        inbox: &mpsc::Sender<(Frame, SocketAddr)>,
+        streams: &mut Streams,
+        source: SocketAddr,
+        messages: Vec<Frame>,
+        peer: Option<PeerInfo>,
+    ) {
+        for mut message in messages {
+            if message.flags & Frame::STREAM != 0 {
+                self.stream_chunk(streams, source, &message.payload);
+            } else if message.control {
+                self.control(source, &message.payload);
+            } else if message.auth {
+                self.authenticate(source, &message.payload);
+            } else if message.hello {
+                self.hello(source, &message.payload);
+            } else if self.schemas.accepts(&message)
+                && peer.as_ref().map_or(Decision::Pass, |peer| {
+                    self.interceptors.run(&mut message, peer)
+                }) == Decision::Pass
+            {
+                if let Some(trace) = &message.trace {
+                    trace!(target: TARGET, %source, traceparent = %trace, "traced message received");
+                }
+                if let Some(message) = self.routes.dispatch(source, message) {
+                    let _ = inbox.send((message, source));
+                    self.wake();
+                    continue;
+                }
+            }
+            self.took_message(source);
+        }
+    }
+}
+
+fn run_server(
+    shared: &ServerShared,
+    inbox: mpsc::Sender<(Frame, SocketAddr)>,
+    mut streams: Streams,
+) {
+    let _enter = shared.span.enter();
+    let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+    while shared.running.load(Ordering::Acquire) {
+        match shared.server.receive_from_timestamped(&mut buffer) {
+            Ok((received, source, at)) => {
+                let (messages, ack, ask, peer) = {
+                    let mut peers = lock(&shared.peers);
+                    let mut fresh = None;
+                    let state = match peers.get_mut(&source) {
+                        Some(state) => state,
+                        None => fresh.insert(shared.new_peer(source)),
+                    };
+                    let (messages, ack) = state.incoming_at(&buffer[..received], at);
+                    // Anything the peer sent but its announcement, acknowledgements and pings
+                    // included, so a client whose address changed is asked where it now is.
+                    let announced = messages.iter().any(|message| message.hello);
+                    let ask = match state.last_heard.is_some() && !announced {
+                        true => state.ask_node_id(&shared.node_id),
+                        false => None,
+                    };
+                    let peer = (!messages.is_empty() && !shared.interceptors.is_empty())
+                        .then(|| state.peer_info(source));
+                    match fresh {
+                        // A session is only made for a source whose datagram decoded and, with
+                        // a key, opened, so spoofed garbage cannot fill the table.
+                        Some(state)
+                            if state.last_heard.is_none()
+                                || !make_room(&mut peers, Instant::now()) =>
+                        {
+                            shared.dropped_unknown.fetch_add(1, Ordering::Relaxed);
+                            (Vec::new(), None, None, None)
+                        }
+                        Some(state) => {
+                            peers.insert(source, state);
+                            (messages, ack, ask, peer)
+                        }
+                        None => (messages, ack, ask, peer),
+                    }
+                };
+                if let Some(ack) = ack {
+                    if let Err(e) = shared.server.send_to(&ack, source) {
+                        debug!(target: TARGET, %source, error = %e, "ack failed");
+                        shared.failed(&e);
+                    }
+                }
+                if let Some(ask) = ask {
+                    if let Err(e) = shared.server.send_to(&ask, source) {
+                        debug!(target: TARGET, %source, error = %e, "hello failed");
+                    }
+                }
+                shared.dispatch(&inbox, &mut streams, source, messages, peer);
+            }
+            Err(_) if shared.server.is_shut_down() => break,
+            Err(e) => {
+                if !is_timeout(&e) {
+                    shared.failed(&e);
+                }
+                wait_after(&e)
+            }
+        }
+
+        let now = Instant::now();
+        let mut peers = lock(&shared.peers);
+        let mut due = Vec::new();
+        for (&peer, state) in peers.iter_mut() {
+            let messages = state.playout(now);
+            if !messages.is_empty() {
+                let info = (!shared.interceptors.is_empty()).then(|| state.peer_info(peer));
+                due.push((peer, messages, info));
+            }
+            for packet in state.retransmissions(now) {
+                lock(&shared.pacer).take(packet.len());
+                if let Err(e) = shared.server.send_to(&packet, peer) {
+                    debug!(target: TARGET, %peer, error = %e, "retransmission failed");
+                    shared.failed(&e);
+                }
+            }
+            if let Some(probe) = state.probe(now) {
+                if let Err(e) = shared.server.send_to(&probe, peer) {
+                    trace!(target: TARGET, %peer, len = probe.len(), error = %e, "probe failed");
+                }
+            }
+            if let Some(query) = state.window_query(now) {
+                if let Err(e) = shared.server.send_to(&query, peer) {
+                    debug!(target: TARGET, %peer, error = %e, "window query failed");
+                }
+            }
+            // Only clients that have been heard from are kept alive, not every address that sent
+            // something the server dropped.
+            let keepalive = match state.last_heard {
+                Some(_) => state.keepalive(now),
+                None => None,
+            };
+            if let Some(keepalive) = keepalive {
+                if let Err(e) = shared.server.send_to(&keepalive, peer) {
+                    debug!(target: TARGET, %peer, error = %e, "keepalive failed");
+                }
+            }
+            if let Some(report) = state.report(now) {
+                if let Err(e) = shared.server.send_to(&report, peer) {
+                    debug!(target: TARGET, %peer, error = %e, "report failed");
+                }
+            }
+            let clock_ping = match state.last_heard {
+                Some(_) => state.clock_ping(now),
+                None => None,
+            };
+            if let Some(ping) = clock_ping {
+                if let Err(e) = shared.server.send_to(&ping, peer) {
+                    debug!(target: TARGET, %peer, error = %e, "clock ping failed");
+                }
+            }
+            shared.flush(peer, state);
+        }
+        forget_idle(&mut peers, PEER_IDLE_TIMEOUT, now);
+        drop(peers);
+        for (source, messages, peer) in due {
+            shared.dispatch(&inbox, &mut streams, source, messages, peer);
+        }
+        shared.queue_space.notify_all();
+    }
+    shared.queue_space.notify_all();
+    // The inbox closes as this returns, which ends the stream.
+    drop(inbox);
+    shared.wake();
+}
+
+// Forgets the sessions that have had nothing left to send and have not been heard from for
+// `idle`.
+fn forget_idle(peers: &mut HashMap<SocketAddr, PeerState>, idle: Duration, now: Instant) {
+    peers.retain(|_, state| {
+        let heard = state.last_heard.unwrap_or(state.last_activity);
+        !state.is_idle() || now.saturating_duration_since(heard) < idle
+    });
+}
+
+// Whether there is room for another session, forgetting idle ones not heard from in the last
+// second if the table is full.
+fn make_room(peers: &mut HashMap<SocketAddr, PeerState>, now: Instant) -> bool {
+    if peers.len() >= MAX_PEERS {
+        forget_idle(peers, Duration::from_secs(1), now);
+    }
+    peers.len() < MAX_PEERS
+}
+
+/// Sequencing, acknowledgement, queueing and reordering state for one remote endpoint.
+struct PeerState {
+    reliable: bool,
+    // Retransmissions after which a message sent with a receipt is given up, unless ordered
+    // delivery forbids skipping it.
+    give_up_after: Option<u32>,
+    queue: SendQueue<Queued>,
+    // Where unacknowledged reliable messages are persisted, for clients with a `spool_dir`.
+    spool: Option<Spool>,
+    next_seq: u32,
+    next_msg_id: u64,
+    in_flight: HashMap<u32, InFlight>,
+    congestion: Aimd,
+    reorder: Option<ReorderBuffer<Frame>>,
+    replay: Option<ReplayWindow>,
+    dedup: Option<DedupCache>,
+    // Messages the application may have waiting before the peer is told to pause, 0 for any
+    // number, those it has waiting, and the room last advertised to the peer.
+    receive_window: u32,
+    undelivered: u32,
+    advertised: u32,
+    // The room the peer last advertised, if it has, and when it was last asked for it.
+    peer_window: Option<u32>,
+    last_window_query: Option<Instant>,
+    psk: Option<Arc<Psk>>,
+    ring: Option<Arc<KeyRing>>,
+    keys: Option<Arc<PskLookup>>,
+    dropped_corrupt: u64,
+    dropped_version: u64,
+    dropped_unauthenticated: u64,
+    // The frame version written to the peer, and the newest it has written to us.
+    version: u8,
+    peer_version: Option<u8>,
+    // Checks the tokens of clients, on servers, and whether this one's has been accepted.
+    verifier: Option<Arc<Verifier>>,
+    authenticated: bool,
+    codec: Option<Arc<Codec>>,
+    dropped_unverified: u64,
+    // The node ID and session token the peer announced, and whether it has been asked for them.
+    node_id: Option<String>,
+    token: Option<u64>,
+    node_asked: bool,
+    // The challenge sent to the peer before a session held under another address moves to it.
+    challenge: Option<node::PathChallenge>,
+    // The peer's messages since the last report on them, and what its reports said of ours.
+    arrivals: Arrivals,
+    report_interval: Duration,
+    last_report: Instant,
+    observed: Observed,
+    delays: Delays,
+    // How the peer's clock stands against ours, the interval to ping it at to keep that fresh,
+    // when that was last done, and the ping last sent for it, forgotten once the next one is.
+    clock: ClockSync,
+    clock_sync_interval: Duration,
+    last_clock_ping: Option<Instant>,
+    clock_ping: Option<u32>,
+    // Holds stamped messages until their time, with `jitter_delay` set.
+    jitter: Option<JitterBuffer>,
+    rtt: RttEstimator,
+    pmtu: Option<PathMtu>,
+    reliable_sent: u64,
+    retransmits: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+    last_activity: Instant,
+    // How long the session may go without sending before a keepalive ping, zero for never, when
+    // it last sent, and the keepalive ping last sent, forgotten once the next one is.
+    keepalive: Duration,
+    last_sent: Instant,
+    keepalive_ping: Option<u32>,
+    next_ping: u32,
+    // Pings awaiting collection by `Client::ping`, with their round trip once answered.
+    pings: HashMap<u32, (Instant, Option<Duration>)>,
+    // Retransmission rounds since the peer was last heard from, and when that was.
+    unanswered: u32,
+    last_heard: Option<Instant>,
+    capture: Option<capture::Endpoint>,
+}
+
+/// A message in the send queue, with the id it is spooled under and the receipt to settle for
+/// it, if any.
+struct Queued {
+    frame: Frame,
+    spooled: Option<u64>,
+    receipt: Option<Pending>,
+}
+
+struct InFlight {
+    packet: Vec<u8>,
+    sent_at: Instant,
+    // Acknowledgements of retransmitted packets are ambiguous, so they give no RTT sample.
+    retransmitted: bool,
+    retries: u32,
+    spooled: Option<u64>,
+    receipt: Option<Pending>,
+}
+
+impl PeerState {
+    fn new(conf: &Config) -> PeerState {
+        PeerState {
+            reliable: conf.delivery.is_reliable(),
+            give_up_after: (!conf.ordered).then_some(UNANSWERED_LIMIT),
+            queue: SendQueue::new(conf.send_queue_capacity, conf.send_queue_policy),
+            spool: None,
+            next_seq: 0,
+            next_msg_id: first_msg_id(),
+            in_flight: HashMap::new(),
+            congestion: Aimd::new(),
+            reorder: (conf.delivery.is_reliable() && conf.ordered)
+                .then(|| ReorderBuffer::new(conf.reorder_window)),
+            replay: (conf.delivery == DeliveryMode::ExactlyOnce
+                && !conf.ordered
+                && conf.replay_window > 0)
+                .then(|| ReplayWindow::new(conf.replay_window)),
+            dedup: (conf.delivery.is_reliable() && !conf.ordered && conf.dedup_cache > 0)
+                .then(|| DedupCache::new(conf.dedup_cache)),
+            receive_window: conf.receive_window,
+            undelivered: 0,
+            advertised: conf.receive_window,
+            peer_window: None,
+            last_window_query: None,
+            psk: None,
+            ring: None,
+            keys: None,
+            dropped_corrupt: 0,
+            dropped_version: 0,
+            dropped_unauthenticated: 0,
+            version: VERSION,
+            peer_version: None,
+            verifier: None,
+            codec: None,
+            authenticated: false,
+            dropped_unverified: 0,
+            node_id: None,
+            token: None,
+            node_asked: false,
+            challenge: None,
+            arrivals: Arrivals::default(),
+            report_interval: conf.report_interval,
+            last_report: Instant::now(),
+            observed: Observed::default(),
+            delays: Delays::new(conf.clock_synced),
+            clock: ClockSync::default(),
+            clock_sync_interval: conf.clock_sync_interval,
+            last_clock_ping: None,
+            clock_ping: None,
+            jitter: (!conf.jitter_delay.is_zero()).then(|| JitterBuffer::new(conf.jitter_delay)),
+            rtt: RttEstimator::new(),
+            pmtu: conf.pmtud.then(PathMtu::new),
+            reliable_sent: 0,
+            retransmits: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            last_activity: Instant::now(),
+            keepalive: conf.keepalive_interval,
+            last_sent: Instant::now(),
+            keepalive_ping: None,
+            next_ping: 0,
+            pings: HashMap::new(),
+            unanswered: 0,
+            last_heard: None,
+            capture: None,
+        }
+    }
+
+    /// Records the frames sent to and received from the peer to `capture`.
+    fn with_capture(mut self, capture: Option<capture::Endpoint>) -> PeerState {
+        self.capture = capture;
+        self
+    }
+
+    /// Decodes the peer's messages with whatever codec is registered with `codec`.
+    fn with_codec(mut self, codec: Arc<Codec>) -> PeerState {
+        self.codec = Some(codec);
+        self
+    }
+
+    /// Seals every frame sent to, and requires every frame received from, the peer to be
+    /// sealed with `psk`.
+    fn with_psk(mut self, psk: Option<Arc<Psk>>) -> PeerState {
+        self.psk = psk;
+        self
+    }
+
+    /// Also opens frames sealed with the other keys `ring` holds while they are current.
+    fn with_ring(mut self, ring: Option<Arc<KeyRing>>) -> PeerState {
+        self.ring = ring;
+        self
+    }
+
+    /// Takes no messages from the peer until `verifier` accepts its token, once a callback is
+    /// registered with it.
+    fn with_verifier(mut self, verifier: Arc<Verifier>) -> PeerState {
+        self.verifier = Some(verifier);
+        self
+    }
+
+    /// Persists reliable messages to `spool` until they are acknowledged, queueing the messages
+    /// it already holds to be sent first.
+    fn with_spool(mut self, spool: Option<(Spool, Spooled)>) -> PeerState {
+        let Some((spool, spooled)) = spool else {
+            return self;
+        };
+        for (id, frame) in spooled {
+            let priority = frame.priority;
+            let queued = Queued {
+                frame,
+                spooled: Some(id),
+                receipt: None,
+            };
+            self.queue.restore(queued, priority);
+        }
+        self.spool = Some(spool);
+        self
+    }
+
+    /// Opens frames naming a key identity with the key `keys` finds for it, and switches the
+    /// peer to that key once one opens.
+    fn with_keys(mut self, keys: Arc<PskLookup>) -> PeerState {
+        self.keys = Some(keys);
+        self
+    }
+
+    /// Fails with `Error::PayloadTooLarge` unless `frame` fits in one datagram once sealed. That
+    /// is the path MTU while discovery has the socket refuse to fragment, and the largest UDP
+    /// payload otherwise.
+    fn check_size(&self, frame: &Frame) -> io::Result<()> {
+        let len = frame.encoded_len().map_err(Error::from)?;
+        check_size(len + self.sealing_overhead(), self.max_datagram())
+    }
+
+    /// The largest payload a frame to the peer can carry, as `check_size` allows of a frame
+    /// with no optional header fields.
+    fn max_payload(&self) -> usize {
+        self.max_datagram()
+            .saturating_sub(HEADER_LEN + self.sealing_overhead())
+    }
+
+    // The largest datagram the peer is sent.
+    fn max_datagram(&self) -> usize {
+        match &self.pmtu {
+            Some(pmtu) if cfg!(target_os = "linux") => pmtu.mtu(),
+            _ => MAX_DATAGRAM_SIZE,
+        }
+    }
+
+    // How much sealing adds to a frame to the peer.
+    fn sealing_overhead(&self) -> usize {
+        self.psk.as_ref().map_or(0, |psk| psk.overhead())
+    }
+
+    /// Queues `frame` for the peer, first writing it to the spool if it is to be sent reliably.
+    /// Stream chunks are not spooled, since a restarted sender resumes its transfers instead.
+    fn enqueue(&mut self, frame: Frame) -> io::Result<()> {
+        self.enqueue_with_receipt(frame, None)
+    }
+
+    /// Like `enqueue`, settling `receipt` once the message is acknowledged or given up.
+    fn enqueue_with_receipt(&mut self, frame: Frame, receipt: Option<Pending>) -> io::Result<()> {
+        let spooled = match &mut self.spool {
+            Some(spool) if self.reliable && frame.flags & Frame::STREAM == 0 => {
+                Some(spool.write(&frame)?)
+            }
+            _ => None,
+        };
+        let priority = frame.priority;
+        let queued = Queued {
+            frame,
+            spooled,
+            receipt,
+        };
+        match self.queue.push(queued, priority) {
+            Ok(shed) => self.unspool(shed.and_then(|queued| queued.spooled)),
+            Err(e) => {
+                self.unspool(spooled);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    // Removes a message from the spool once it is acknowledged or shed.
+    fn unspool(&mut self, spooled: Option<u64>) {
+        if let (Some(spool), Some(id)) = (&mut self.spool, spooled) {
+            spool.remove(id);
+        }
+    }
+
+    /// Whether everything sent to the peer has left the queue and been acknowledged.
+    fn is_idle(&self) -> bool {
+        self.queue.is_empty() && self.in_flight.is_empty()
+    }
+
+    /// Whether a reliable send has to wait for acknowledgements first, because the congestion
+    /// window or the window the peer advertised is full.
+    fn window_full(&self) -> bool {
+        let window = match self.peer_window {
+            Some(credit) => self.congestion.window().min(credit as usize),
+            None => self.congestion.window(),
+        };
+        self.reliable && self.in_flight.len() >= window
+    }
+
+    /// Takes queued messages off the queue for as long as the congestion window and `pacer`
+    /// allow, returning their packets.
+    fn ready_packets(&mut self, pacer: &mut TokenBucket, now: Instant) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        while !self.queue.is_empty() && !self.window_full() && pacer.ready(now) {
+            let Some(queued) = self.queue.pop() else {
+                break;
+            };
+            let seq = self.next_seq;
+            let packet = self.outgoing(queued.frame);
+            if let Some(in_flight) = self.in_flight.get_mut(&seq) {
+                in_flight.spooled = queued.spooled;
+                in_flight.receipt = queued.receipt;
+            }
+            pacer.take(packet.len());
+            packets.push(packet);
+        }
+        packets
+    }
+
+    /// Numbers `frame` and encodes it for sending, tracking it for retransmission in reliable
+    /// mode.
+    fn outgoing(&mut self, mut frame: Frame) -> Vec<u8> {
+        frame.msg_id = self.next_msg_id;
+        self.next_msg_id = self.next_msg_id.wrapping_add(1);
+        if !self.reliable {
+            return self.seal(frame);
+        }
+
+        let seq = self.next_seq;
+        self.next_seq = seq.wrapping_add(1);
+        frame.flags |= Frame::RELIABLE;
+        frame.seq = seq;
+        let packet = self.seal(frame);
+        self.reliable_sent += 1;
+        self.in_flight.insert(
+            seq,
+            InFlight {
+                packet: packet.clone(),
+                sent_at: self.last_activity,
+                retransmitted: false,
+                retries: 0,
+                spooled: None,
+                receipt: None,
+            },
+        );
+        packet
+    }
+
+    /// Like `incoming_at`, for a packet that arrived just now.
+    #[cfg(test)]
+    fn incoming(&mut self, bytes: &[u8]) -> (Vec<Frame>, Option<Vec<u8>>) {
+        self.incoming_at(bytes, None)
+    }
+
+    /// Handles a packet from the peer, returning the messages now ready for the application,
+    /// decompressed, and the acknowledgement to send back, if any. `received` is when the
+    /// kernel stamped the packet as arriving, if it did.
+    fn incoming_at(
+        &mut self,
+        bytes: &[u8],
+        received: Option<SystemTime>,
+    ) -> (Vec<Frame>, Option<Vec<u8>>) {
+        self.bytes_received += bytes.len() as u64;
+        self.last_activity = Instant::now();
+        let arrived = received.unwrap_or_else(SystemTime::now);
+        if let Some(capture) = &self.capture {
+            if capture.point() == CapturePoint::Wire {
+                capture.received(bytes);
+            }
+        }
+        let mut frame = match Frame::from_bytes(bytes) {
+            Ok(frame) => frame,
+            Err(FrameError::Version(version)) => {
+                debug!(target: TARGET, version, "dropping packet of unknown version");
+                self.dropped_version += 1;
+                return (Vec::new(), None);
+            }
+            Err(e) => {
+                trace!(target: TARGET, error = %e, "dropping invalid packet");
+                self.dropped_corrupt += 1;
+                return (Vec::new(), None);
+            }
+        };
+        if let Err(e) = self.open(&mut frame) {
+            debug!(target: TARGET, error = %e, "dropping unauthenticated packet");
+            self.dropped_unauthenticated += 1;
+            return (Vec::new(), None);
+        }
+        self.negotiate(frame.version);
+        if let Some(capture) = &self.capture {
+            if capture.point() == CapturePoint::Plain {
+                capture.received(&frame.to_bytes());
+            }
+        }
+        self.unanswered = 0;
+        self.last_heard = Some(self.last_activity);
+
+        if frame.is_probe() {
+            return match (frame.is_ack(), &mut self.pmtu) {
+                (true, Some(pmtu)) => {
+                    pmtu.on_probe_ack(frame.seq as usize);
+                    (Vec::new(), None)
+                }
+                (true, None) => (Vec::new(), None),
+                (false, _) => (Vec::new(), Some(self.seal(Frame::probe_ack(bytes.len())))),
+            };
+        }
+        if frame.is_ping() {
+            if !frame.is_ack() {
+                return (Vec::new(), Some(self.seal(clock::pong(&frame, arrived))));
+            }
+            self.clock.on_pong(&frame.payload, arrived);
+            if let Some((sent_at, rtt @ None)) = self.pings.get_mut(&frame.seq) {
+                let sample = self.last_activity.saturating_duration_since(*sent_at);
+                *rtt = Some(sample);
+                self.rtt.on_sample(sample);
+            }
+            return (Vec::new(), None);
+        }
+        if frame.is_window() {
+            if frame.payload.is_empty() {
+                return (Vec::new(), Some(self.window_update()));
+            }
+            self.peer_window = frame.credit().or(self.peer_window);
+            return (Vec::new(), None);
+        }
+        if frame.is_ack() {
+            if let Some(credit) = frame.credit() {
+                self.peer_window = Some(credit);
+            }
+            if let Some(mut in_flight) = self.in_flight.remove(&frame.seq) {
+                self.unspool(in_flight.spooled);
+                if let Some(receipt) = in_flight.receipt.take() {
+                    receipt.delivered();
+                }
+                self.congestion.on_ack();
+                if !in_flight.retransmitted {
+                    let sample = self
+                        .last_activity
+                        .saturating_duration_since(in_flight.sent_at);
+                    self.rtt.on_sample(sample);
+                }
+            }
+            return (Vec::new(), None);
+        }
+        if frame.auth || frame.hello {
+            return (self.deliver(vec![frame]), None);
+        }
+        if !self.authenticated && self.verifier.as_ref().is_some_and(|v| v.is_set()) {
+            trace!(target: TARGET, seq = frame.seq, "dropping message before authentication");
+            self.dropped_unverified += 1;
+            return (Vec::new(), Some(self.seal(auth::verdict(auth::REQUIRED))));
+        }
+        if frame.report {
+            self.take_report(&frame.payload);
+            return (Vec::new(), None);
+        }
+        self.arrivals.on_message(frame.msg_id, self.last_activity);
+        if let Some(sent) = frame.timestamp {
+            self.delays.on_message(sent, arrived, self.clock.offset());
+        }
+        if !frame.is_reliable() {
+            return (self.deliver(vec![frame]), None);
+        }
+
+        let seq = frame.seq;
+        let Some(reorder) = &mut self.reorder else {
+            // Acknowledged all the same, as the duplicate may be a retransmission whose
+            // acknowledgement was lost.
+            if self
+                .replay
+                .as_mut()
+                .is_some_and(|replay| !replay.check(seq))
+            {
+                trace!(target: TARGET, seq, "dropping replayed message");
+                return (Vec::new(), Some(self.acknowledge(seq)));
+            }
+            if self
+                .dedup
+                .as_mut()
+                .is_some_and(|dedup| !dedup.check(frame.msg_id))
+            {
+                trace!(target: TARGET, seq, msg_id = frame.msg_id, "dropping duplicate message");
+                return (Vec::new(), Some(self.acknowledge(seq)));
+            }
+            let messages = self.deliver(vec![frame]);
+            return (messages, Some(self.acknowledge(seq)));
+        };
+        match reorder.push(seq, frame) {
+            Reordered::Ready(frames) => {
+                let messages = self.deliver(frames);
+                (messages, Some(self.acknowledge(seq)))
+            }
+            Reordered::Late => {
+                trace!(target: TARGET, seq, "dropping late message");
+                (Vec::new(), Some(self.acknowledge(seq)))
+            }
+            // Left unacknowledged so the peer sends it again once the window has moved.
+            Reordered::OutOfWindow => {
+                trace!(target: TARGET, seq, "message beyond reorder window");
+                (Vec::new(), None)
+            }
+        }
+    }
+
+    // Encodes the acknowledgement of `seq`, advertising the room left in the receive window if
+    // there is one. Called once the messages it releases are counted as undelivered.
+    fn acknowledge(&mut self, seq: u32) -> Vec<u8> {
+        let mut ack = Frame::ack(seq);
+        if self.receive_window > 0 {
+            self.advertised = self.credit();
+            ack.payload = self.advertised.to_be_bytes().to_vec();
+        }
+        self.seal(ack)
+    }
+
+    // Messages the peer may still send before the application has to take some.
+    fn credit(&self) -> u32 {
+        match self.receive_window {
+            0 => u32::MAX,
+            window => window.saturating_sub(self.undelivered),
+        }
+    }
+
+    // Encodes an announcement of the room left in the receive window.
+    fn window_update(&mut self) -> Vec<u8> {
+        self.advertised = self.credit();
+        self.seal(Frame::window(self.advertised))
+    }
+
+    /// Counts a message delivered by `incoming` as taken by the application, returning the
+    /// window announcement to send if that reopens a window advertised as full.
+    fn took(&mut self) -> Option<Vec<u8>> {
+        if self.receive_window == 0 {
+            return None;
+        }
+        self.undelivered = self.undelivered.saturating_sub(1);
+        (self.advertised == 0 && self.credit() > 0).then(|| self.window_update())
+    }
+
+    /// The query asking the peer for its window, if one is due: while the window it advertised
+    /// is full with nothing in flight whose acknowledgement could reopen it, once per
+    /// retransmission timeout in case the announcement that would was lost. Unanswered queries
+    /// count as unanswered retransmission rounds.
+    fn window_query(&mut self, now: Instant) -> Option<Vec<u8>> {
+        if self.peer_window != Some(0) || !self.in_flight.is_empty() || self.queue.is_empty() {
+            return None;
+        }
+        let rto = self.rtt.rto();
+        if self
+            .last_window_query
+            .is_some_and(|last| now.saturating_duration_since(last) < rto)
+        {
+            return None;
+        }
+        self.last_window_query = Some(now);
+        self.unanswered += 1;
+        Some(self.seal(Frame::window_query()))
+    }
+
+    // Encodes `frame`, encrypted if the session has a pre-shared key, and counts it as sent.
+    fn seal(&mut self, mut frame: Frame) -> Vec<u8> {
+        frame.version = frame.version.min(self.version);
+        if let Some(capture) = &self.capture {
+            if capture.point() == CapturePoint::Plain {
+                capture.sent(&frame.to_bytes());
+            }
+        }
+        if let Some(psk) = &self.psk {
+            psk.seal(&mut frame);
+        }
+        let packet = frame.to_bytes();
+        if let Some(capture) = &self.capture {
+            if capture.point() == CapturePoint::Wire {
+                capture.sent(&packet);
+            }
+        }
+        self.bytes_sent += packet.len() as u64;
+        self.last_activity = Instant::now();
+        self.last_sent = self.last_activity;
+        packet
+    }
+
+    fn open(&mut self, frame: &mut Frame) -> io::Result<()> {
+        let keys = self.keys.as_ref().filter(|keys| keys.is_set());
+        if let (Some(keys), Some(identity)) = (keys, frame.identity.clone()) {
+            if self.psk.as_ref().and_then(|psk| psk.identity()) != Some(identity.as_slice()) {
+                // Only a frame that opens with the new key may switch the peer to it.
+                let psk = keys
+                    .find(&identity)
+                    .ok_or_else(|| Error::protocol("Unknown pre-shared key identity"))?;
+                psk.open(frame)?;
+                debug!(
+                    target: TARGET,
+                    identity = %String::from_utf8_lossy(&identity),
+                    "pre-shared key identified"
+                );
+                self.psk = Some(Arc::new(psk));
+                return Ok(());
+            }
+        }
+        if let (Some(ring), Some(key_id)) = (&self.ring, frame.key_id) {
+            if self.psk.as_ref().and_then(|psk| psk.key_id()) != Some(key_id) {
+                // Frames keep being sealed with our own key, which the peer holds as well.
+                let now = Instant::now();
+                let psk = ring
+                    .find(key_id, now)
+                    .ok_or_else(|| Error::protocol("Unknown or expired pre-shared key id"))?;
+                psk.open(frame)?;
+                ring.opened(key_id, now);
+                return Ok(());
+            }
+        }
+        match (&self.psk, frame.nonce) {
+            (Some(psk), _) => psk.open(frame),
+            (None, None) if keys.is_some() => {
+                Err(Error::protocol("Unencrypted frame but keys are looked up").into())
+            }
+            (None, None) => Ok(()),
+            (None, Some(_)) => Err(Error::protocol("Encrypted frame but no pre-shared key").into()),
+        }
+    }
+
+    // Decodes and decompresses messages for the application, counting them against the receive
+    // window until it takes them.
+    fn deliver(&mut self, frames: Vec<Frame>) -> Vec<Frame> {
+        let mut messages = self.decode(frames);
+        if let Some(jitter) = &mut self.jitter {
+            let now = self.last_activity;
+            messages = messages
+                .into_iter()
+                .filter_map(|message| jitter.push(message, now))
+                .collect();
+        }
+        if self.receive_window > 0 {
+            self.undelivered = self.undelivered.saturating_add(messages.len() as u32);
+        }
+        messages
+    }
+
+    /// Packets whose acknowledgement is overdue. Their timers are restarted, and the congestion
+    /// window shrinks and the retransmission timeout backs off once for the lot.
+    fn retransmissions(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let rto = self.rtt.rto();
+        let is_overdue =
+            |in_flight: &InFlight| now.saturating_duration_since(in_flight.sent_at) >= rto;
+        if let Some(limit) = self.give_up_after {
+            let given_up: Vec<u32> = self
+                .in_flight
+                .iter()
+                .filter(|(_, in_flight)| {
+                    in_flight.receipt.is_some()
+                        && in_flight.retries >= limit
+                        && is_overdue(in_flight)
+                })
+                .map(|(&seq, _)| seq)
+                .collect();
+            for seq in given_up {
+                let mut in_flight = self.in_flight.remove(&seq).unwrap();
+                debug!(target: TARGET, seq, "giving up unacknowledged message");
+                self.unspool(in_flight.spooled);
+                if let Some(receipt) = in_flight.receipt.take() {
+                    receipt.failed(io::ErrorKind::TimedOut, "Message went unacknowledged");
+                }
+            }
+        }
+        let overdue: Vec<Vec<u8>> = self
+            .in_flight
+            .values_mut()
+            .filter(|in_flight| is_overdue(in_flight))
+            .map(|in_flight| {
+                in_flight.sent_at = now;
+                in_flight.retransmitted = true;
+                in_flight.retries += 1;
+                in_flight.packet.clone()
+            })
+            .collect();
+        if !overdue.is_empty() {
+            self.unanswered += 1;
+            self.congestion.on_loss();
+            self.rtt.on_timeout();
+            if let Some(pmtu) = &mut self.pmtu {
+                pmtu.on_loss();
+            }
+            let bytes: usize = overdue.iter().map(Vec::len).sum();
+            self.retransmits += overdue.len() as u64;
+            self.reliable_sent += overdue.len() as u64;
+            self.bytes_sent += bytes as u64;
+            self.last_activity = now;
+            self.last_sent = now;
+            self.capture_resent(&overdue);
+        }
+        overdue
+    }
+
+    /// Fails the receipts of every message still queued or unacknowledged, once the client has
+    /// given up on the server.
+    fn abandon_receipts(&mut self) {
+        let queued = self.queue.iter_mut().map(|queued| &mut queued.receipt);
+        let in_flight = self
+            .in_flight
+            .values_mut()
+            .map(|in_flight| &mut in_flight.receipt);
+        for receipt in queued.chain(in_flight).filter_map(Option::take) {
+            receipt.failed(
+                io::ErrorKind::NotConnected,
+                "Every reconnection attempt failed",
+            );
+        }
+    }
+
+    // Records packets sent again as they were, for wire captures. Plain captures record each
+    // frame once.
+    fn capture_resent(&self, packets: &[Vec<u8>]) {
+        if let Some(capture) = &self.capture {
+            if capture.point() == CapturePoint::Wire {
+                packets.iter().for_each(|packet| capture.sent(packet));
+            }
+        }
+    }
+
+    /// Every unacknowledged packet, to send again over a new path. The path's round trip, send
+    /// window and MTU are measured afresh.
+    fn reconnected(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        self.rtt = RttEstimator::new();
+        self.congestion = Aimd::new();
+        if self.pmtu.is_some() {
+            self.pmtu = Some(PathMtu::new());
+        }
+        self.unanswered = 0;
+
+        let mut unacknowledged: Vec<_> = self.in_flight.iter_mut().collect();
+        unacknowledged.sort_unstable_by_key(|(&seq, _)| seq);
+        let packets: Vec<Vec<u8>> = unacknowledged
+            .into_iter()
+            .map(|(_, in_flight)| {
+                in_flight.sent_at = now;
+                in_flight.retransmitted = true;
+                in_flight.packet.clone()
+            })
+            .collect();
+        let bytes: usize = packets.iter().map(Vec::len).sum();
+        self.retransmits += packets.len() as u64;
+        self.reliable_sent += packets.len() as u64;
+        self.bytes_sent += bytes as u64;
+        self.last_activity = now;
+        self.last_sent = now;
+        self.capture_resent(&packets);
+        packets
+    }
+
+    /// The path MTU probe due now, if discovery is on and one is.
+    fn probe(&mut self, now: Instant) -> Option<Vec<u8>> {
+        let len = self.pmtu.as_mut()?.next_probe(now, self.rtt.rto())?;
+        let overhead = HEADER_LEN + self.psk.as_ref().map_or(0, |psk| psk.overhead());
+        Some(self.seal(Frame::probe(len, overhead)))
+    }
+
+    /// The keepalive ping due now, if keepalives are on and nothing has been sent for the
+    /// interval. Its answer is an RTT sample like any other pong.
+    fn keepalive(&mut self, now: Instant) -> Option<Vec<u8>> {
+        if self.keepalive.is_zero()
+            || now.saturating_duration_since(self.last_sent) < self.keepalive
+        {
+            return None;
+        }
+        if let Some(id) = self.keepalive_ping.take() {
+            self.take_pong(id);
+        }
+        let (id, packet) = self.ping(now);
+        self.keepalive_ping = Some(id);
+        Some(packet)
+    }
+
+    /// Who the peer at `addr` is, for interceptors and the application.
+    fn peer_info(&self, addr: SocketAddr) -> PeerInfo {
+        PeerInfo {
+            session: addr.into(),
+            addr,
+            node_id: self.node_id.clone(),
+            authenticated: self.authenticated,
+            identity: self
+                .psk
+                .as_ref()
+                .and_then(|psk| psk.identity())
+                .map(<[u8]>::to_vec),
+        }
+    }
+
+    /// Numbers a ping and encodes it for sending.
+    fn ping(&mut self, now: Instant) -> (u32, Vec<u8>) {
+        let id = self.next_ping;
+        self.next_ping = id.wrapping_add(1);
+        self.pings.insert(id, (now, None));
+        (id, self.seal(clock::ping(id)))
+    }
+
+    fn ponged(&self, id: u32) -> bool {
+        self.pings.get(&id).is_some_and(|(_, rtt)| rtt.is_some())
+    }
+
+    /// Forgets ping `id`, returning its round trip if it was answered.
+    fn take_pong(&mut self, id: u32) -> Option<Duration> {
+        self.pings.remove(&id).and_then(|(_, rtt)| rtt)
+    }
+
+    fn stats(&self) -> Stats {
+        Stats {
+            rtt: self.rtt.srtt(),
+            rto: self.rtt.rto(),
+            loss_rate: match self.reliable_sent {
+                0 => 0.0,
+                sent => self.retransmits as f64 / sent as f64,
+            },
+            retransmits: self.retransmits,
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            last_activity: self.last_activity,
+            path_mtu: self.pmtu.as_ref().map(PathMtu::mtu),
+            remote_loss_rate: self.observed.loss_rate(),
+            remote_jitter: self.observed.jitter(),
+            one_way_delay: self.delays.one_way_delay(),
+            transit_jitter: self.delays.jitter(),
+            clock_offset: self.clock.offset().map(|offset| offset as f64 / 1e6),
+            protocol_version: self.version,
+        }
+    }
+
+    fn metrics(&self) -> Metrics {
+        let mut metrics = Metrics {
+            send_window: self.congestion.window(),
+            queued: self.queue.len(),
+            queue_dropped: self.queue.dropped(),
+            dropped_corrupt: self.dropped_corrupt,
+            dropped_version: self.dropped_version,
+            dropped_unauthenticated: self.dropped_unauthenticated,
+            dropped_unverified: self.dropped_unverified,
+            ..Default::default()
+        };
+        if let Some(reorder) = &self.reorder {
+            metrics.reorder_depth = reorder.depth();
+            metrics.max_reorder_depth = reorder.max_depth();
+            metrics.dropped_late = reorder.dropped_late();
+        }
+        if let Some(replay) = &self.replay {
+            metrics.dropped_replayed = replay.replayed();
+        }
+        if let Some(dedup) = &self.dedup {
+            metrics.dedup_hits = dedup.hits();
+        }
+        if let Some(jitter) = &self.jitter {
+            metrics.jitter_depth = jitter.depth();
+            metrics.jitter_dropped = jitter.dropped();
+        }
+        metrics
+    }
+}
+
+// Message ids start at random, so that a dedup cache does not take the messages of a restarted
+// sender for those of the one it replaced.
+fn first_msg_id() -> u64 {
+    RandomState::new().hash_one(Instant::now())
+}
+
+fn next_message<T>(inbox: &mpsc::Receiver<T>, timeout: Option<Duration>) -> io::Result<T> {
+    let closed = || io::Error::new(io::ErrorKind::NotConnected, "Session closed");
+    match timeout {
+        Some(timeout) => inbox.recv_timeout(timeout).map_err(|e| match e {
+            mpsc::RecvTimeoutError::Timeout => {
+                io::Error::new(io::ErrorKind::TimedOut, "Session receive timed out")
+            }
+            mpsc::RecvTimeoutError::Disconnected => closed(),
+        }),
+        None => inbox.recv().map_err(|_| closed()),
+    }
+}
+
+// Read timeouts are how the workers poll; other errors (such as ICMP port unreachable reported
+// on a later receive) are logged, with a pause so a persistently failing socket does not spin.
+fn wait_after(e: &io::Error) {
+    if !is_timeout(e) {
+        debug!(target: TARGET, error = %e, "receive failed");
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+// Runs the payload of `message` through the pipeline into the frame that carries it,
+// compressing it as `compression_policy` says for its type and priority, and encoding it with
+// `codec` if it is a message a codec may encode.
+fn frame(conf: &Config, codec: Option<&Codec>, message: Frame) -> io::Result<Frame> {
+    let compression =
+        conf.compression_policy
+            .choose(conf.compression_type, message.msg_type, message.priority);
+    conf.pipeline.encode(compression, codec, message)
+}
+
+fn copy_truncated(message: &[u8], buffer: &mut [u8]) -> usize {
+    let len = message.len().min(buffer.len());
+    buffer[..len].copy_from_slice(&message[..len]);
+    len
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::congestion::INITIAL_RTO;
+    use super::*;
+    use crate::protocol::TIMESTAMP_LEN;
+    use crate::transport::{memory, sim};
+    use crate::util::config::{CompressionType, QueuePolicy, SchemaPolicy, SecurityMode};
+
+    fn payloads(messages: Vec<Frame>) -> Vec<Vec<u8>> {
+        messages
+            .into_iter()
+            .map(|message| message.payload)
+            .collect()
+    }
+
+    fn ordered_conf(reorder_window: usize) -> Config {
+        Config {
+            ordered: true,
+            reorder_window,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn unacknowledged_messages_are_retransmitted() {
+        let mut sender = PeerState::new(&Config::default());
+        let mut receiver = PeerState::new(&Config::default());
+        let packet = sender.outgoing(Frame::new(b"hello".to_vec()));
+
+        let now = Instant::now();
+        assert!(sender.retransmissions(now).is_empty());
+        let later = now + INITIAL_RTO;
+        assert_eq!(sender.retransmissions(later), vec![packet.clone()]);
+        assert!(sender.retransmissions(later).is_empty());
+
+        let (messages, ack) = receiver.incoming(&packet);
+        assert_eq!(payloads(messages), vec![b"hello".to_vec()]);
+        sender.incoming(&ack.unwrap());
+        assert!(sender.retransmissions(later + INITIAL_RTO).is_empty());
+    }
+
+    #[test]
+    fn reconnecting_resends_unacknowledged_messages() {
+        let mut sender = PeerState::new(&Config::default());
+        let mut receiver = PeerState::new(&Config::default());
+        let packets: Vec<_> = [b"one", b"two"]
+            .map(|payload| sender.outgoing(Frame::new(payload.to_vec())))
+            .into();
+
+        let mut now = Instant::now();
+        for round in 1..=3 {
+            now += Duration::from_secs(60);
+            assert_eq!(sender.retransmissions(now).len(), 2);
+            assert_eq!(sender.unanswered, round);
+        }
+        assert_eq!(sender.reconnected(now), packets);
+        assert_eq!(sender.unanswered, 0);
+        assert_eq!(sender.stats().rto, INITIAL_RTO);
+        assert_eq!(sender.stats().retransmits, 8);
+
+        let (_, ack) = receiver.incoming(&packets[1]);
+        sender.incoming(&ack.unwrap());
+        assert!(sender.last_heard.is_some());
+        assert_eq!(sender.reconnected(now), vec![packets[0].clone()]);
+    }
+
+    #[test]
+    fn stats_follow_traffic_and_acknowledgements() {
+        let mut sender = PeerState::new(&Config::default());
+        let mut receiver = PeerState::new(&Config::default());
+        let first = sender.outgoing(Frame::new(b"a".to_vec()));
+        let second = sender.outgoing(Frame::new(b"b".to_vec()));
+        assert_eq!(
+            sender.retransmissions(Instant::now() + INITIAL_RTO).len(),
+            2
+        );
+
+        let (_, ack) = receiver.incoming(&first);
+        let ack = ack.unwrap();
+        sender.incoming(&ack);
+        let stats = sender.stats();
+        assert_eq!(stats.rtt, None);
+        assert_eq!(stats.retransmits, 2);
+        assert_eq!(stats.loss_rate, 0.5);
+        let sent = 2 * (first.len() + second.len());
+        assert_eq!(stats.bytes_sent, sent as u64);
+        assert_eq!(stats.bytes_received, ack.len() as u64);
+        assert_eq!(receiver.stats().bytes_sent, ack.len() as u64);
+
+        let third = sender.outgoing(Frame::new(b"c".to_vec()));
+        sender.incoming(&receiver.incoming(&third).1.unwrap());
+        assert!(sender.stats().rtt.is_some());
+    }
+
+    #[test]
+    fn peers_rotating_keys_open_each_others_frames() -> io::Result<()> {
+        let rotating = |key: &str, key_id: u32, keys: &str| -> io::Result<PeerState> {
+            let conf = Config {
+                security: SecurityMode::Psk,
+                psk: key.repeat(64),
+                psk_key_id: key_id,
+                psk_keys: keys.to_string(),
+                ..Default::default()
+            };
+            Ok(PeerState::new(&conf)
+                .with_psk(Psk::from_config(&conf)?.map(Arc::new))
+                .with_ring(KeyRing::from_config(&conf)?.map(Arc::new)))
+        };
+        let mut old = rotating("1", 1, &format!("2:{}", "2".repeat(64)))?;
+        let mut new = rotating("2", 2, &format!("1:{}", "1".repeat(64)))?;
+        let mut stale = rotating("1", 1, "")?;
+
+        let packet = new.outgoing(Frame::new(b"new".to_vec()));
+        assert_eq!(old.incoming(&packet).0[0].payload, b"new");
+        let packet = old.outgoing(Frame::new(b"old".to_vec()));
+        assert_eq!(new.incoming(&packet).0[0].payload, b"old");
+        let packet = new.outgoing(Frame::new(b"new".to_vec()));
+        assert!(stale.incoming(&packet).0.is_empty());
+        assert_eq!(stale.metrics().dropped_unauthenticated, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn probes_are_acknowledged_with_their_size() {
+        for security in [SecurityMode::None, SecurityMode::Psk] {
+            let conf = Config {
+                security,
+                psk: "7".repeat(64),
+                pmtud: true,
+                ..Default::default()
+            };
+            let psk = Psk::from_config(&conf).unwrap().map(Arc::new);
+            let mut sender = PeerState::new(&conf).with_psk(psk.clone());
+            let mut receiver = PeerState::new(&Config::default()).with_psk(psk);
+            assert_eq!(sender.stats().path_mtu, Some(pmtu::BASE_PLPMTU));
+
+            let probe = sender.probe(Instant::now()).unwrap();
+            assert!(probe.len() > pmtu::BASE_PLPMTU);
+            assert!(sender.probe(Instant::now()).is_none());
+            let (messages, ack) = receiver.incoming(&probe);
+            assert!(messages.is_empty());
+            sender.incoming(&ack.unwrap());
+            assert_eq!(sender.stats().path_mtu, Some(probe.len()));
+            assert_eq!(receiver.stats().path_mtu, None);
+        }
+    }
+
+    #[test]
+    fn pings_are_answered_and_timed() {
+        let mut sender = PeerState::new(&Config::default());
+        let mut receiver = PeerState::new(&Config::default());
+        let (id, ping) = sender.ping(Instant::now());
+        let (messages, pong) = receiver.incoming(&ping);
+        assert!(messages.is_empty());
+        assert!(!sender.ponged(id));
+
+        sender.incoming(&pong.unwrap());
+        assert!(sender.ponged(id));
+        assert!(sender.take_pong(id).is_some());
+        assert!(sender.stats().rtt.is_some());
+        assert_eq!(sender.take_pong(id), None);
+    }
+
+    #[test]
+    fn idle_sessions_are_kept_alive() {
+        let interval = Duration::from_secs(15);
+        let conf = Config {
+            keepalive_interval: interval,
+            ..Default::default()
+        };
+        let mut sender = PeerState::new(&conf);
+        let mut receiver = PeerState::new(&Config::default());
+        let now = Instant::now();
+        assert!(sender.keepalive(now).is_none());
+        assert!(PeerState::new(&Config::default())
+            .keepalive(now + interval)
+            .is_none());
+
+        sender.outgoing(Frame::new(b"a".to_vec()));
+        let sent = sender.last_sent;
+        assert!(sender.keepalive(sent + interval / 2).is_none());
+        let keepalive = sender.keepalive(sent + interval).unwrap();
+        assert!(sender.keepalive(sent + interval).is_none());
+        let (messages, pong) = receiver.incoming(&keepalive);
+        assert!(messages.is_empty());
+        sender.incoming(&pong.unwrap());
+        assert!(sender.stats().rtt.is_some());
+
+        sender.keepalive(sender.last_sent + interval).unwrap();
+        assert_eq!(sender.pings.len(), 1);
+    }
+
+    #[test]
+    fn corrupt_packets_are_dropped_and_counted() {
+        let mut sender = PeerState::new(&Config::default());
+        let mut receiver = PeerState::new(&Config::default());
+        let mut packet = sender.outgoing(Frame::new(b"hello".to_vec()));
+
+        let last = packet.len() - 1;
+        packet[last] ^= 0xff;
+        assert_eq!(receiver.incoming(&packet), (Vec::new(), None));
+        assert_eq!(receiver.incoming(&packet[..3]), (Vec::new(), None));
+        packet[0] = 0;
+        assert_eq!(receiver.incoming(&packet), (Vec::new(), None));
+
+        let metrics = receiver.metrics();
+        assert_eq!(metrics.dropped_corrupt, 2);
+        assert_eq!(metrics.dropped_version, 1);
+    }
+
+    #[test]
+    fn unreliable_messages_are_not_acknowledged() {
+        let conf = Config {
+            delivery: DeliveryMode::AtMostOnce,
+            ..Default::default()
+        };
+        let mut sender = PeerState::new(&conf);
+        let packet = sender.outgoing(Frame::new(b"hello".to_vec()));
+        assert!(sender
+            .retransmissions(Instant::now() + INITIAL_RTO)
+            .is_empty());
+        let (messages, ack) = PeerState::new(&conf).incoming(&packet);
+        assert_eq!((payloads(messages), ack), (vec![b"hello".to_vec()], None));
+    }
+
+    #[test]
+    fn ordered_delivery_survives_reordering() {
+        let mut sender = PeerState::new(&Config::default());
+        let mut receiver = PeerState::new(&ordered_conf(2));
+        let packets: Vec<Vec<u8>> = [b"a", b"b", b"c", b"d"]
+            .iter()
+            .map(|payload| sender.outgoing(Frame::new(payload.to_vec())))
+            .collect();
+
+        let (messages, ack) = receiver.incoming(&packets[1]);
+        assert!(messages.is_empty());
+        assert!(ack.is_some());
+        assert_eq!(receiver.metrics().reorder_depth, 1);
+
+        // Beyond the window: dropped without an acknowledgement so it is retransmitted.
+        assert_eq!(receiver.incoming(&packets[3]), (Vec::new(), None));
+
+        let (messages, _) = receiver.incoming(&packets[0]);
+        assert_eq!(payloads(messages), vec![b"a".to_vec(), b"b".to_vec()]);
+        let (messages, _) = receiver.incoming(&packets[3]);
+        assert!(messages.is_empty());
+        let (messages, _) = receiver.incoming(&packets[2]);
+        assert_eq!(payloads(messages), vec![b"c".to_vec(), b"d".to_vec()]);
+
+        let (messages, ack) = receiver.incoming(&packets[0]);
+        assert!(messages.is_empty());
+        assert!(ack.is_some());
+        let metrics = receiver.metrics();
+        assert_eq!(
+            (
+                metrics.reorder_depth,
+                metrics.max_reorder_depth,
+                metrics.dropped_late
+            ),
+            (0, 1, 1)
+        );
+    }
+
+    #[test]
+    fn unordered_delivery_is_immediate() {
+        let mut sender = PeerState::new(&Config::default());
+        let mut receiver = PeerState::new(&Config::default());
+        let first = sender.outgoing(Frame::new(b"a".to_vec()));
+        let second = sender.outgoing(Frame::new(b"b".to_vec()));
+
+        assert_eq!(payloads(receiver.incoming(&second).0), vec![b"b".to_vec()]);
+        assert_eq!(payloads(receiver.incoming(&first).0), vec![b"a".to_vec()]);
+        assert_eq!(receiver.metrics().max_reorder_depth, 0);
+    }
+
+    #[test]
+    fn replayed_messages_are_acknowledged_but_not_delivered() {
+        let mut sender = PeerState::new(&Config::default());
+        let mut receiver = PeerState::new(&Config::default());
+        let packet = sender.outgoing(Frame::new(b"a".to_vec()));
+
+        assert_eq!(payloads(receiver.incoming(&packet).0), vec![b"a".to_vec()]);
+        let (messages, ack) = receiver.incoming(&packet);
+        assert!(messages.is_empty());
+        assert!(ack.is_some());
+        assert_eq!(receiver.metrics().dropped_replayed, 1);
+
+        let mut unchecked = PeerState::new(&Config {
+            delivery: DeliveryMode::AtLeastOnce,
+            ..Default::default()
+        });
+        unchecked.incoming(&packet);
+        assert_eq!(payloads(unchecked.incoming(&packet).0), vec![b"a".to_vec()]);
+    }
+
+    #[test]
+    fn dedup_caches_drop_messages_seen_under_another_sequence_number() {
+        let conf = Config {
+            delivery: DeliveryMode::AtLeastOnce,
+            dedup_cache: 16,
+            ..Default::default()
+        };
+        let mut sender = PeerState::new(&conf);
+        let mut receiver = PeerState::new(&conf);
+        let packet = sender.outgoing(Frame::new(b"a".to_vec()));
+        let mut resent = Frame::from_bytes(&packet).unwrap();
+        resent.seq += 1;
+
+        assert_eq!(payloads(receiver.incoming(&packet).0), vec![b"a".to_vec()]);
+        let (messages, ack) = receiver.incoming(&resent.to_bytes());
+        assert!(messages.is_empty());
+        assert!(ack.is_some());
+        assert_eq!(receiver.metrics().dedup_hits, 1);
+        let next = sender.outgoing(Frame::new(b"b".to_vec()));
+        assert_eq!(payloads(receiver.incoming(&next).0), vec![b"b".to_vec()]);
+    }
+
+    #[test]
+    fn senders_pause_while_the_receive_window_is_full() {
+        let mut sender = PeerState::new(&Config::default());
+        let mut receiver = PeerState::new(&Config {
+            receive_window: 2,
+            ..Default::default()
+        });
+        let mut pacer = TokenBucket::new(0);
+        for i in 0..4u8 {
+            sender.enqueue(Frame::new(vec![i])).unwrap();
+        }
+        // Nothing is advertised until the first acknowledgement.
+        let sent = sender.ready_packets(&mut pacer, Instant::now());
+        assert_eq!(sent.len(), 4);
+        for packet in &sent[..2] {
+            let (messages, ack) = receiver.incoming(packet);
+            assert_eq!(messages.len(), 1);
+            sender.incoming(&ack.unwrap());
+        }
+        assert_eq!(sender.peer_window, Some(0));
+
+        // The rest are acknowledged but must not be followed by more.
+        for i in 4..6u8 {
+            sender.enqueue(Frame::new(vec![i])).unwrap();
+        }
+        for packet in &sent[2..] {
+            let (_, ack) = receiver.incoming(packet);
+            sender.incoming(&ack.unwrap());
+        }
+        assert!(sender.window_full());
+        assert!(sender.ready_packets(&mut pacer, Instant::now()).is_empty());
+        let now = Instant::now();
+        let query = sender.window_query(now).unwrap();
+        assert!(sender.window_query(now).is_none());
+        let (_, answer) = receiver.incoming(&query);
+        sender.incoming(&answer.unwrap());
+        assert_eq!(sender.peer_window, Some(0));
+
+        // Taking one of four undelivered messages leaves the window full; two reopen it.
+        assert!(receiver.took().is_none());
+        assert!(receiver.took().is_none());
+        let update = receiver.took().unwrap();
+        sender.incoming(&update);
+        assert_eq!(sender.peer_window, Some(1));
+        assert!(receiver.took().is_none());
+        assert_eq!(sender.ready_packets(&mut pacer, Instant::now()).len(), 1);
+    }
+
+    #[test]
+    fn losses_shrink_the_send_window() {
+        let mut sender = PeerState::new(&Config::default());
+        let window = sender.metrics().send_window;
+        for _ in 0..window {
+            assert!(!sender.window_full());
+            sender.outgoing(Frame::new(b"hello".to_vec()));
+        }
+        assert!(sender.window_full());
+
+        sender.retransmissions(Instant::now() + INITIAL_RTO);
+        assert_eq!(sender.metrics().send_window, window / 2);
+    }
+
+    #[test]
+    fn full_window_holds_messages_in_the_queue() {
+        let conf = Config {
+            send_queue_capacity: 2,
+            send_queue_policy: QueuePolicy::Error,
+            ..Default::default()
+        };
+        let mut sender = PeerState::new(&conf);
+        let mut pacer = TokenBucket::new(0);
+        let window = sender.metrics().send_window;
+        for _ in 0..window {
+            sender.enqueue(Frame::new(b"hello".to_vec())).unwrap();
+            assert_eq!(sender.ready_packets(&mut pacer, Instant::now()).len(), 1);
+        }
+
+        sender.enqueue(Frame::new(b"one".to_vec())).unwrap();
+        sender.enqueue(Frame::new(b"two".to_vec())).unwrap();
+        assert!(sender.ready_packets(&mut pacer, Instant::now()).is_empty());
+        assert!(sender.enqueue(Frame::new(b"three".to_vec())).is_err());
+        assert_eq!(sender.metrics().queued, 2);
+
+        let mut receiver = PeerState::new(&Config::default());
+        let in_flight: Vec<Vec<u8>> = sender
+            .in_flight
+            .values()
+            .map(|in_flight| in_flight.packet.clone())
+            .collect();
+        let (_, ack) = receiver.incoming(&in_flight[0]);
+        sender.incoming(&ack.unwrap());
+        assert_eq!(sender.ready_packets(&mut pacer, Instant::now()).len(), 1);
+        assert_eq!(sender.metrics().queued, 1);
+    }
+
+    #[test]
+    fn receipts_fail_once_retransmissions_run_out() {
+        let mut sender = PeerState::new(&Config::default());
+        let mut pacer = TokenBucket::new(0);
+        let mut now = Instant::now();
+        let (pending, receipt) = Pending::new();
+        let frame = Frame::new(b"unheard".to_vec());
+        sender.enqueue_with_receipt(frame, Some(pending)).unwrap();
+        assert_eq!(sender.ready_packets(&mut pacer, now).len(), 1);
+        sender.outgoing(Frame::new(b"untracked".to_vec()));
+
+        for _ in 0..UNANSWERED_LIMIT {
+            now += Duration::from_secs(3600);
+            assert_eq!(sender.retransmissions(now).len(), 2);
+        }
+        assert!(receipt.status().is_none());
+        now += Duration::from_secs(3600);
+        assert_eq!(sender.retransmissions(now).len(), 1);
+        let e = receipt.status().unwrap().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+
+        let (pending, receipt) = Pending::new();
+        let frame = Frame::new(b"stranded".to_vec());
+        sender.enqueue_with_receipt(frame, Some(pending)).unwrap();
+        sender.abandon_receipts();
+        let e = receipt.status().unwrap().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotConnected);
+    }
+
+    #[test]
+    fn urgent_messages_overtake_queued_bulk() {
+        let mut sender = PeerState::new(&Config::default());
+        let mut receiver = PeerState::new(&Config::default());
+        let mut pacer = TokenBucket::new(0);
+        for _ in 0..sender.metrics().send_window {
+            sender.enqueue(Frame::new(b"fill".to_vec())).unwrap();
+        }
+        let in_flight = sender.ready_packets(&mut pacer, Instant::now());
+        for (payload, priority) in [(b"bulk", Priority::Bulk), (b"high", Priority::High)] {
+            let frame = Frame {
+                priority,
+                ..Frame::new(payload.to_vec())
+            };
+            sender.enqueue(frame).unwrap();
+        }
+        assert!(sender.ready_packets(&mut pacer, Instant::now()).is_empty());
+
+        let (_, ack) = receiver.incoming(&in_flight[0]);
+        sender.incoming(&ack.unwrap());
+        let packets = sender.ready_packets(&mut pacer, Instant::now());
+        let (messages, _) = receiver.incoming(&packets[0]);
+        assert_eq!(messages[0].priority, Priority::High);
+        assert_eq!(payloads(messages), vec![b"high".to_vec()]);
+    }
+
+    #[test]
+    fn reliable_messages_survive_a_lossy_link() -> io::Result<()> {
+        let (a, b) = memory::pair(memory::Conditions {
+            latency: Duration::from_millis(1),
+            loss: 0.1,
+            reorder: 0.05,
+            ..Default::default()
+        });
+        let conf = ordered_conf(64);
+        let sender = Client::with_transport(&conf, Box::new(a))?;
+        let receiver = Client::with_transport(&conf, Box::new(b))?;
+        receiver.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        for i in 0..100u8 {
+            sender.send(&[i])?;
+        }
+        let mut buffer = [0u8; 8];
+        for i in 0..100u8 {
+            assert_eq!(receiver.receive(&mut buffer)?, 1);
+            assert_eq!(buffer[0], i);
+        }
+        assert!(sender.stats().retransmits > 0);
+        sender.close();
+        Ok(())
+    }
+
+    #[test]
+    fn reliable_messages_survive_corruption_and_duplication() -> io::Result<()> {
+        let (a, b) = memory::pair(memory::Conditions::default());
+        let faults = sim::Faults {
+            loss: 0.05,
+            duplicate: 0.1,
+            corrupt: 0.1,
+            delay: 0.1,
+            max_delay: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let conf = ordered_conf(64);
+        let sender = Client::with_transport(&conf, Box::new(sim::Sim::new(Box::new(a), faults)))?;
+        let receiver = Client::with_transport(&conf, Box::new(b))?;
+        // Which packets the faults hit depends on how the threads interleave, and a run of them
+        // hitting fresh messages leaves no round trip to sample while the retransmission timeout
+        // doubles every round, which can take several seconds to recover from.
+        receiver.set_read_timeout(Some(Duration::from_secs(30)))?;
+
+        for i in 0..100u8 {
+            sender.send(&[i])?;
+        }
+        let mut buffer = [0u8; 8];
+        for i in 0..100u8 {
+            assert_eq!(receiver.receive(&mut buffer)?, 1);
+            assert_eq!(buffer[0], i);
+        }
+        let metrics = receiver.metrics();
+        assert!(metrics.dropped_corrupt + metrics.dropped_version > 0);
+        sender.close();
+        Ok(())
+    }
+
+    #[test]
+    fn spooled_messages_are_sent_after_a_restart() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("crumb-spool-restart-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let conf = Config {
+            spool_dir: dir.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let spooled = || std::fs::read_dir(&dir).map(Iterator::count);
+
+        // Nothing reads the other end, so nothing is acknowledged before it goes away.
+        let (a, b) = memory::pair(memory::Conditions::default());
+        let crashed = Client::with_transport(&conf, Box::new(a))?;
+        crashed.send(b"one")?;
+        crashed.send(b"two")?;
+        drop(b);
+        crashed.close();
+        assert_eq!(spooled()?, 2);
+
+        let (a, b) = memory::pair(memory::Conditions::default());
+        let restarted = Client::with_transport(&conf, Box::new(a))?;
+        let receiver = Client::with_transport(&Config::default(), Box::new(b))?;
+        receiver.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut buffer = [0u8; 8];
+        for expected in [b"one", b"two"] {
+            let received = receiver.receive(&mut buffer)?;
+            assert_eq!(&buffer[..received], expected);
+        }
+        restarted.close();
+        assert_eq!(spooled()?, 0);
+        std::fs::remove_dir_all(&dir)
+    }
+
+    #[test]
+    fn session_round_trip() -> io::Result<()> {
+        let conf = Config {
+            host: "::1".to_string(),
+            port: 8096,
+            ..ordered_conf(16)
+        };
+        let server = Server::init(&conf)?;
+        server.set_read_timeout(Some(Duration::from_secs(2)))?;
+        let client = Client::init(&conf)?;
+        client.set_read_timeout(Some(Duration::from_secs(2)))?;
+
+        let mut buffer = [0u8; 16];
+        let mut source = None;
+        for message in [&b"one"[..], b"two", b"three"] {
+            client.send(message)?;
+            let (received, from) = server.receive_from(&mut buffer)?;
+            assert_eq!(&buffer[..received], message);
+            source = Some(from);
+        }
+
+        server.send_to(b"done", source.unwrap())?;
+        let received = client.receive(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"done");
+        let metrics = server.metrics(source.unwrap()).unwrap();
+        assert_eq!(metrics.max_reorder_depth, 0);
+        assert!(metrics.send_window > 0);
+
+        client.close();
+        server.close();
+        Ok(())
+    }
+
+    #[test]
+    fn broadcasts_reach_every_client_heard_from() -> io::Result<()> {
+        let conf = Config {
+            host: "127.0.0.1".to_string(),
+            port: 8130,
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        server.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let clients = [Client::init(&conf)?, Client::init(&conf)?];
+        for client in &clients {
+            client.set_read_timeout(Some(Duration::from_secs(5)))?;
+            client.send(b"subscribe")?;
+            server.receive_any()?;
+        }
+        // Clients ping the server as they start, so a peer that has never sent is a bare socket,
+        // which acknowledges the message itself.
+        let silent = std::net::UdpSocket::bind("[::1]:0")?;
+        silent.set_read_timeout(Some(Duration::from_secs(5)))?;
+        server.send_to(b"direct", silent.local_addr()?)?;
+
+        let mut results = server.broadcast(b"notice")?;
+        results.sort_by_key(|(session, _)| *session);
+        let mut expected = clients
+            .iter()
+            .map(|client| client.local_addr().map(SessionId::from))
+            .collect::<io::Result<Vec<_>>>()?;
+        expected.sort();
+        let sessions: Vec<_> = results.iter().map(|(session, _)| *session).collect();
+        assert_eq!(sessions, expected);
+        assert!(results
+            .iter()
+            .all(|(_, result)| result.as_ref().ok() == Some(&6)));
+
+        let mut buffer = [0u8; 16];
+        for client in &clients {
+            let received = client.receive(&mut buffer)?;
+            assert_eq!(&buffer[..received], b"notice");
+        }
+        let mut packet = [0u8; 1500];
+        let received = silent.recv(&mut packet)?;
+        let frame = Frame::from_bytes(&packet[..received]).unwrap();
+        assert_eq!(frame.payload, b"direct");
+        silent.send_to(&Frame::ack(frame.seq).to_bytes(), ("::1", conf.port))?;
+        Ok(())
+    }
+
+    #[test]
+    fn messages_sent_before_the_server_starts_are_retransmitted() -> io::Result<()> {
+        let conf = Config {
+            host: "::1".to_string(),
+            port: 8097,
+            ..Default::default()
+        };
+        let client = Client::init(&conf)?;
+        client.send(b"early")?;
+        thread::sleep(INITIAL_RTO / 2);
+
+        let server = Server::init(&conf)?;
+        server.set_read_timeout(Some(Duration::from_secs(2)))?;
+        let mut buffer = [0u8; 16];
+        let (received, _) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"early");
+
+        client.close();
+        server.close();
+        Ok(())
+    }
+
+    #[test]
+    fn serve_stops_on_shutdown() -> io::Result<()> {
+        let conf = Config {
+            host: "::1".to_string(),
+            port: 8104,
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        let handle = server.shutdown_handle()?;
+        let serving =
+            thread::spawn(move || server.serve(|message, _| Some(message.to_ascii_uppercase())));
+
+        let client = Client::init(&conf)?;
+        client.set_read_timeout(Some(Duration::from_secs(2)))?;
+        client.send(b"ping")?;
+        let mut buffer = [0u8; 16];
+        let received = client.receive(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"PING");
+
+        handle.shutdown();
+        serving.join().unwrap()?;
+        client.close();
+        Ok(())
+    }
+
+    #[test]
+    fn peers_with_different_compression_understand_each_other() -> io::Result<()> {
+        let server = Server::init(&Config {
+            port: 8108,
+            compression_type: CompressionType::Gzip,
+            ..Default::default()
+        })?;
+        server.set_read_timeout(Some(Duration::from_secs(2)))?;
+        let client = Client::init(&Config {
+            host: "::1".to_string(),
+            port: 8108,
+            compression_type: CompressionType::Zstd,
+            ..Default::default()
+        })?;
+        client.set_read_timeout(Some(Duration::from_secs(2)))?;
+
+        let message = b"compressible ".repeat(64);
+        let mut buffer = [0u8; 1024];
+        client.send(&message)?;
+        let (received, source) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..received], &message[..]);
+
+        server.send_to(&message, source)?;
+        let received = client.receive(&mut buffer)?;
+        assert_eq!(&buffer[..received], &message[..]);
+
+        client.close();
+        server.close();
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_schemas_are_rejected_until_reloaded() -> io::Result<()> {
+        let dir = std::env::temp_dir();
+        let client_proto = dir.join(format!("crumb-client-{}.proto", std::process::id()));
+        let server_proto = dir.join(format!("crumb-server-{}.proto", std::process::id()));
+        std::fs::write(&client_proto, "message A { string b = 1; }")?;
+        std::fs::write(&server_proto, "message A {}")?;
+
+        let server = Server::init(&Config {
+            port: 8111,
+            proto_path: server_proto.to_string_lossy().into_owned(),
+            schema_policy: SchemaPolicy::Reject,
+            ..Default::default()
+        })?;
+        server.set_read_timeout(Some(Duration::from_millis(300)))?;
+        let client = Client::init(&Config {
+            host: "::1".to_string(),
+            port: 8111,
+            proto_path: client_proto.to_string_lossy().into_owned(),
+            ..Default::default()
+        })?;
+
+        let mut buffer = [0u8; 16];
+        client.send(b"new")?;
+        assert!(server.receive_from(&mut buffer).is_err());
+        assert_eq!(server.schemas().unknown(), 1);
+
+        std::fs::write(&server_proto, "message A { string b = 1; }")?;
+        assert!(server.schemas().reload()?);
+        assert_eq!(server.schemas().current(), client.schemas().current());
+        client.send(b"newer")?;
+        let (received, _) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"newer");
+
+        client.close();
+        server.close();
+        std::fs::remove_file(client_proto)?;
+        std::fs::remove_file(server_proto)
+    }
+
+    #[test]
+    fn path_mtu_is_discovered_over_loopback() -> io::Result<()> {
+        let conf = Config {
+            host: "::1".to_string(),
+            port: 8115,
+            pmtud: true,
+            compression_type: CompressionType::None,
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        let client = Client::init(&conf)?;
+        let message = vec![7; 2000];
+        let too_large = |e: io::Error| matches!(Error::from(e), Error::PayloadTooLarge { .. });
+        assert!(client.send(&message).is_err_and(too_large));
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while client.stats().path_mtu <= Some(pmtu::BASE_PLPMTU) && Instant::now() < deadline {
+            thread::sleep(POLL_INTERVAL);
+        }
+        let mtu = client.stats().path_mtu.unwrap();
+        assert!(mtu > message.len());
+        client.send(&message)?;
+        match Error::from(client.send(&vec![7; mtu]).unwrap_err()) {
+            Error::PayloadTooLarge { size, max } => {
+                assert_eq!((size, max), (mtu + HEADER_LEN, mtu))
+            }
+            e => panic!("unexpected error: {}", e),
+        }
+
+        client.close();
+        server.close();
+        Ok(())
+    }
+
+    #[test]
+    fn timestamps_count_against_the_datagram_size() -> io::Result<()> {
+        let conf = Config {
+            timestamps: true,
+            compression_type: CompressionType::None,
+            ..Default::default()
+        };
+        let (a, b) = memory::pair(memory::Conditions::default());
+        let sender = Client::with_transport(&conf, Box::new(a))?;
+        let receiver = Client::with_transport(&conf, Box::new(b))?;
+        receiver.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        let fits = MAX_DATAGRAM_SIZE - HEADER_LEN - TIMESTAMP_LEN;
+        match Error::from(sender.send(&vec![7; fits + 1]).unwrap_err()) {
+            Error::PayloadTooLarge { size, max } => {
+                assert_eq!((size, max), (MAX_DATAGRAM_SIZE + 1, MAX_DATAGRAM_SIZE))
+            }
+            e => panic!("unexpected error: {}", e),
+        }
+        sender.send(&vec![7; fits])?;
+        let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+        assert_eq!(receiver.receive(&mut buffer)?, fits);
+        sender.close();
+        Ok(())
+    }
+
+    #[test]
+    fn ping_measures_the_round_trip() -> io::Result<()> {
+        let conf = Config {
+            host: "::1".to_string(),
+            port: 8118,
+            security: SecurityMode::Psk,
+            psk: "5".repeat(64),
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        let client = Client::init(&conf)?;
+        let rtt = client.ping()?;
+        assert!(rtt < Duration::from_secs(1));
+        server.close();
+
+        client.set_read_timeout(Some(Duration::from_millis(100)))?;
+        assert_eq!(client.ping().unwrap_err().kind(), io::ErrorKind::TimedOut);
+        client.close();
+        Ok(())
+    }
+
+    #[test]
+    fn clients_reconnect_to_a_restarted_server() -> io::Result<()> {
+        let conf = Config {
+            host: "127.0.0.1".to_string(),
+            port: 8119,
+            reconnect_attempts: 20,
+            reconnect_backoff: Duration::from_millis(50),
+            reconnect_max_backoff: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        server.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let client = Client::init(&conf)?;
+        let (changes, changed) = mpsc::channel();
+        client.on_connection_change(move |state| changes.send(state).unwrap());
+
+        let mut buffer = [0u8; 64];
+        client.send(b"before")?;
+        let (received, _) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"before");
+        server.close();
+
+        // Refused by the closed port, so the client starts reconnecting and keeps the message.
+        client.send(b"during")?;
+        let timeout = Duration::from_secs(5);
+        assert!(matches!(
+            changed.recv_timeout(timeout),
+            Ok(ConnectionState::Reconnecting { attempt: 1 })
+        ));
+
+        let server = Server::init(&conf)?;
+        server.set_read_timeout(Some(timeout))?;
+        let (received, _) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"during");
+        assert_eq!(client.connection_state(), ConnectionState::Connected);
+        assert!(std::iter::from_fn(|| changed.recv_timeout(timeout).ok())
+            .any(|state| state == ConnectionState::Connected));
+        client.close();
+        Ok(())
+    }
+
+    #[test]
+    fn clients_fail_over_to_the_next_endpoint() -> io::Result<()> {
+        let conf = Config {
+            endpoints: vec![
+                "127.0.0.1:8120".parse().unwrap(),
+                "127.0.0.1:8121".parse().unwrap(),
+            ],
+            port: 8121,
+            reconnect_attempts: 3,
+            reconnect_backoff: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        server.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let client = Client::init(&conf)?;
+        assert_eq!(client.endpoint(), Some(conf.endpoints[0]));
+
+        // Nothing listens on the first endpoint, so the message follows the client to the second.
+        client.send(b"hello")?;
+        let mut buffer = [0u8; 64];
+        let (received, _) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"hello");
+        assert_eq!(client.endpoint(), Some(conf.endpoints[1]));
+        assert_eq!(client.peer_addr()?.port(), 8121);
+        client.close();
+        Ok(())
+    }
+
+    #[test]
+    fn garbage_from_new_sources_makes_no_session() -> io::Result<()> {
+        let conf = Config {
+            host: "::1".to_string(),
+            port: 8151,
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        server.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let spoofer = std::net::UdpSocket::bind("[::1]:0")?;
+        let mut corrupt = Frame::new(b"forged".to_vec()).to_bytes();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xff;
+        spoofer.send_to(&corrupt, ("::1", conf.port))?;
+        spoofer.send_to(b"garbage", ("::1", conf.port))?;
+
+        // The server takes datagrams in order, so both are dealt with once this arrives.
+        let client = Client::init(&conf)?;
+        client.send(b"hello")?;
+        let mut buffer = [0u8; 16];
+        let (received, source) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"hello");
+        assert!(server.metrics(source).is_some());
+        assert!(server.metrics(spoofer.local_addr()?).is_none());
+        assert_eq!(server.dropped_unknown(), 2);
+
+        client.close();
+        server.close();
+        Ok(())
+    }
+
+    #[test]
+    fn idle_sessions_are_forgotten() {
+        let conf = Config::default();
+        let (idle, busy) = ("[::1]:1".parse().unwrap(), "[::1]:2".parse().unwrap());
+        let mut peers =
+            HashMap::from([(idle, PeerState::new(&conf)), (busy, PeerState::new(&conf))]);
+        peers
+            .get_mut(&busy)
+            .unwrap()
+            .outgoing(Frame::new(b"unacknowledged".to_vec()));
+        let now = Instant::now();
+
+        forget_idle(&mut peers, PEER_IDLE_TIMEOUT, now);
+        assert_eq!(peers.len(), 2);
+        forget_idle(&mut peers, PEER_IDLE_TIMEOUT, now + PEER_IDLE_TIMEOUT);
+        assert!(peers.contains_key(&busy) && !peers.contains_key(&idle));
+    }
+
+    #[test]
+    fn psk_sessions_reject_peers_without_the_key() -> io::Result<()> {
+        let psk_conf = |key: &str| Config {
+            host: "::1".to_string(),
+            port: 8112,
+            security: SecurityMode::Psk,
+            psk: key.repeat(64),
+            ..Default::default()
+        };
+        let server = Server::init(&psk_conf("a"))?;
+        server.set_read_timeout(Some(Duration::from_millis(300)))?;
+        let client = Client::init(&psk_conf("a"))?;
+        client.set_read_timeout(Some(Duration::from_secs(2)))?;
+
+        let mut buffer = [0u8; 16];
+        client.send(b"secret")?;
+        let (received, source) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"secret");
+        server.send_to(b"reply", source)?;
+        let received = client.receive(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"reply");
+
+        // Unreliable, so closing them does not wait for acknowledgements that never come.
+        let intruder = Client::init(&Config {
+            delivery: DeliveryMode::AtMostOnce,
+            ..psk_conf("b")
+        })?;
+        intruder.send(b"forged")?;
+        let plain = Client::init(&Config {
+            delivery: DeliveryMode::AtMostOnce,
+            security: SecurityMode::None,
+            ..psk_conf("")
+        })?;
+        plain.send(b"plain")?;
+        assert!(server.receive_from(&mut buffer).is_err());
+        // Nothing they sent opened, so neither was given a session.
+        for peer in [&intruder, &plain] {
+            assert!(server.metrics(peer.local_addr()?).is_none());
+        }
+        assert!(server.dropped_unknown() > 0);
+
+        for client in [client, intruder, plain] {
+            client.close();
+        }
+        server.close();
+        Ok(())
+    }
+
+    #[test]
+    fn servers_look_up_keys_by_identity() -> io::Result<()> {
+        let conf = Config {
+            host: "::1".to_string(),
+            port: 8126,
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        server.on_psk_identity(|identity| match identity {
+            b"sensor-1" => Some([1; KEY_LEN]),
+            b"sensor-2" => Some([2; KEY_LEN]),
+            _ => None,
+        });
+        server.set_read_timeout(Some(Duration::from_millis(300)))?;
+        let identified = |identity: &str, key: &str| Config {
+            security: SecurityMode::Psk,
+            psk: key.repeat(KEY_LEN),
+            psk_identity: identity.to_string(),
+            ..conf.clone()
+        };
+
+        let mut buffer = [0u8; 16];
+        for (identity, key) in [("sensor-1", "01"), ("sensor-2", "02")] {
+            let client = Client::init(&identified(identity, key))?;
+            client.set_read_timeout(Some(Duration::from_secs(2)))?;
+            client.send(identity.as_bytes())?;
+            let (received, source) = server.receive_from(&mut buffer)?;
+            assert_eq!(&buffer[..received], identity.as_bytes());
+            server.send_to(b"reply", source)?;
+            let received = client.receive(&mut buffer)?;
+            assert_eq!(&buffer[..received], b"reply");
+            client.close();
+        }
+
+        let unreliable = |conf: Config| Config {
+            delivery: DeliveryMode::AtMostOnce,
+            ..conf
+        };
+        let impostor = Client::init(&unreliable(identified("sensor-1", "02")))?;
+        impostor.send(b"forged")?;
+        let stranger = Client::init(&unreliable(identified("sensor-3", "03")))?;
+        stranger.send(b"unknown")?;
+        let plain = Client::init(&unreliable(conf.clone()))?;
+        plain.send(b"plain")?;
+        assert!(server.receive_from(&mut buffer).is_err());
+        for peer in [&impostor, &stranger, &plain] {
+            assert!(server.metrics(peer.local_addr()?).is_none());
+        }
+        assert!(server.dropped_unknown() >= 3);
+
+        for client in [impostor, stranger, plain] {
+            client.close();
+        }
+        server.close();
+        Ok(())
+    }
+}
@@ -0,0 +1,445 @@
+//! Node IDs. Session clients and servers go by the node ID `node_id` configures, or a random
+//! UUID, and a client tells the server its own as its session opens, so applications can key
+//! state on who a client is rather than on its address, which NAT rebinding and reconnection
+//! change. `PeerInfo` carries it to interceptors, the verifier and `Server::receive_from_peer`.
+//!
+//! The announcement is an unreliable session message marked with the `HELLO` extension. Its
+//! payload is a flags byte, bit 0 asking the receiver to announce itself in return, then a
+//! session token (8 bytes, big endian) and the node ID in UTF-8. Bit 1 marks a path challenge
+//! and bit 2 the answer to one, whose token field carries the challenge's nonce instead. A client announces itself as
+//! it starts, asking for the server's ID. A server that hears from a client whose ID it lacks,
+//! because the announcement was lost, the server restarted or the client's address changed,
+//! announces itself once asking for the client's.
+//!
+//! The token is drawn at random by each client and names its session. With
+//! `session_migration`, a client announcing the node ID and token of a session held under
+//! another address has that session moved to its new one, unacknowledged messages and
+//! authentication included, as QUIC migrates connections. The session only moves once the
+//! client has answered a path challenge, a random nonce the server sends to the new address,
+//! from there, which shows the client can be reached there: an announcement replayed from a
+//! spoofed address moves nothing, as whoever sent it never sees the nonce. The token is
+//! readable on the path unless the session is sealed with a pre-shared key, so without one an
+//! observer could take the session over.
+
+use super::{lock, Client, ClientShared, PeerInfo, PeerState, Server, ServerShared, TARGET};
+use crate::protocol::{Frame, Priority};
+use crate::transport::relay::MAX_PEER_ID_LEN;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use tracing::{debug, info};
+
+// The flag asking the receiver to announce itself in return.
+const ANSWER: u8 = 0x01;
+
+// The flags marking a path challenge and the answer to one.
+const CHALLENGE: u8 = 0x02;
+const RESPONSE: u8 = 0x04;
+
+const TOKEN_LEN: usize = 8;
+
+/// An announcement of `node_id` and the session `token`, asking for the peer's if `answer` is
+/// set. Servers, whose sessions never move, announce a token of 0.
+pub(super) fn hello(node_id: &str, token: u64, answer: bool) -> Frame {
+    announcement(node_id, token, if answer { ANSWER } else { 0 })
+}
+
+fn announcement(node_id: &str, token: u64, flags: u8) -> Frame {
+    let mut payload = Vec::with_capacity(1 + TOKEN_LEN + node_id.len());
+    payload.push(flags);
+    payload.extend_from_slice(&token.to_be_bytes());
+    payload.extend_from_slice(node_id.as_bytes());
+    Frame {
+        hello: true,
+        priority: Priority::Control,
+        ..Frame::new(payload)
+    }
+}
+
+/// What a peer announced about itself.
+#[derive(Debug, PartialEq, Eq)]
+struct Hello {
+    node_id: String,
+    token: u64,
+    answer: bool,
+    challenge: bool,
+    response: bool,
+}
+
+/// A path challenge sent to a client's new address, which the session held under `from`
+/// moves to once the client answers it from there.
+#[derive(Debug)]
+pub(super) struct PathChallenge {
+    from: SocketAddr,
+    nonce: u64,
+}
+
+fn parse(payload: &[u8]) -> Option<Hello> {
+    let (&flags, rest) = payload.split_first()?;
+    let token = u64::from_be_bytes(rest.get(..TOKEN_LEN)?.try_into().unwrap());
+    let node_id = &rest[TOKEN_LEN..];
+    if node_id.is_empty() || node_id.len() > MAX_PEER_ID_LEN {
+        return None;
+    }
+    Some(Hello {
+        node_id: String::from_utf8(node_id.to_vec()).ok()?,
+        token,
+        answer: flags & ANSWER != 0,
+        challenge: flags & CHALLENGE != 0,
+        response: flags & RESPONSE != 0,
+    })
+}
+
+impl Server {
+    /// The node ID this server announces to its clients.
+    pub fn node_id(&self) -> &str {
+        &self.shared.node_id
+    }
+
+    /// Who the client at `addr` is, if it has been heard from.
+    pub fn peer_info(&self, addr: SocketAddr) -> Option<PeerInfo> {
+        lock(&self.shared.peers)
+            .get(&addr)
+            .map(|peer| peer.peer_info(addr))
+    }
+
+    /// The address the client that announced `node_id` was most recently heard from, to reach
+    /// it after its address changed.
+    pub fn node_addr(&self, node_id: &str) -> Option<SocketAddr> {
+        lock(&self.shared.peers)
+            .iter()
+            .filter(|(_, peer)| peer.node_id.as_deref() == Some(node_id))
+            .max_by_key(|(_, peer)| peer.last_heard)
+            .map(|(addr, _)| *addr)
+    }
+
+    /// Like `receive_from`, but says who sent the message, node ID included once the client
+    /// has announced it.
+    pub fn receive_from_peer(&self, buffer: &mut [u8]) -> io::Result<(usize, PeerInfo)> {
+        let (received, source) = self.receive_from(buffer)?;
+        let peer = self.peer_info(source).unwrap_or(PeerInfo {
+            session: source.into(),
+            addr: source,
+            node_id: None,
+            authenticated: false,
+            identity: None,
+        });
+        Ok((received, peer))
+    }
+
+    /// Sessions moved to a client's new address.
+    pub fn migrations(&self) -> u64 {
+        self.shared.migrations.load(Ordering::Relaxed)
+    }
+}
+
+impl ServerShared {
+    /// Records the node ID `source` announced, challenging it to show it can be reached there
+    /// if it names a session held under another address, and announces ours in return if it
+    /// asks. The session moves once the answer to the challenge comes back from `source`.
+    pub(super) fn hello(&self, source: SocketAddr, payload: &[u8]) {
+        let Some(announced) = parse(payload) else {
+            debug!(target: TARGET, %source, "dropping malformed hello");
+            return;
+        };
+        let mut peers = lock(&self.peers);
+        if announced.response {
+            let challenge = peers
+                .get_mut(&source)
+                .and_then(|peer| peer.challenge.take())
+                .filter(|challenge| challenge.nonce == announced.token);
+            match challenge {
+                Some(challenge) => self.migrate(&mut peers, challenge.from, source),
+                None => debug!(target: TARGET, %source, "dropping unexpected path response"),
+            }
+            return;
+        }
+        let moved = peers
+            .iter()
+            .find(|(addr, peer)| {
+                **addr != source
+                    && peer.node_id.as_ref() == Some(&announced.node_id)
+                    && peer.token == Some(announced.token)
+            })
+            .map(|(addr, _)| *addr)
+            .filter(|_| self.conf.session_migration);
+        let Some(peer) = peers.get_mut(&source) else {
+            return;
+        };
+        if let Some(from) = moved {
+            let nonce = OsRng.next_u64();
+            peer.challenge = Some(PathChallenge { from, nonce });
+            let packet = peer.seal(announcement(&self.node_id, nonce, CHALLENGE));
+            drop(peers);
+            debug!(target: TARGET, %from, to = %source, "challenging new address");
+            if let Err(e) = self.server.send_to(&packet, source) {
+                debug!(target: TARGET, %source, error = %e, "path challenge failed");
+            }
+            return;
+        }
+        if peer.node_id.as_ref() != Some(&announced.node_id) {
+            debug!(target: TARGET, %source, node_id = %announced.node_id, "client identified");
+        }
+        peer.node_id = Some(announced.node_id);
+        peer.token = Some(announced.token);
+        if !announced.answer {
+            return;
+        }
+        let packet = peer.seal(hello(&self.node_id, 0, false));
+        drop(peers);
+        if let Err(e) = self.server.send_to(&packet, source) {
+            debug!(target: TARGET, %source, error = %e, "hello failed");
+        }
+    }
+}
+
+impl ServerShared {
+    // Moves the session held under `from` to `to`, unless it has gone meanwhile.
+    fn migrate(
+        &self,
+        peers: &mut HashMap<SocketAddr, PeerState>,
+        from: SocketAddr,
+        to: SocketAddr,
+    ) {
+        let Some(mut session) = peers.remove(&from) else {
+            return;
+        };
+        // The capture names the peer by address, so the one made for the new address is kept.
+        if let Some(fresh) = peers.remove(&to) {
+            session.capture = fresh.capture;
+        }
+        let node_id = session.node_id.clone().unwrap_or_default();
+        peers.insert(to, session);
+        self.migrations.fetch_add(1, Ordering::Relaxed);
+        info!(target: TARGET, %from, %to, %node_id, "session migrated");
+    }
+}
+
+impl PeerState {
+    /// Our announcement as `node_id`, asking for the peer's, unless the peer has announced
+    /// itself or has been asked already.
+    pub(super) fn ask_node_id(&mut self, node_id: &str) -> Option<Vec<u8>> {
+        if self.node_id.is_some() || self.node_asked {
+            return None;
+        }
+        self.node_asked = true;
+        Some(self.seal(hello(node_id, 0, true)))
+    }
+}
+
+impl Client {
+    /// The node ID this client announces to the server.
+    pub fn node_id(&self) -> &str {
+        &self.shared.node_id
+    }
+
+    /// The node ID the server announced, once it has.
+    pub fn server_node_id(&self) -> Option<String> {
+        lock(&self.shared.state).node_id.clone()
+    }
+}
+
+impl ClientShared {
+    /// Announces our node ID to the server, asking for its own if `answer` is set.
+    pub(super) fn announce(&self, answer: bool) {
+        let packet = lock(&self.state).seal(hello(&self.node_id, self.token, answer));
+        if let Err(e) = self.transport().send(&packet) {
+            debug!(target: TARGET, error = %e, "hello failed");
+        }
+    }
+
+    /// Records the node ID the server announced, announcing ours in return if it asks and
+    /// answering its path challenges.
+    pub(super) fn hello(&self, payload: &[u8]) {
+        let Some(announced) = parse(payload) else {
+            debug!(target: TARGET, "dropping malformed hello");
+            return;
+        };
+        let mut state = lock(&self.state);
+        state.node_id = Some(announced.node_id);
+        if announced.challenge {
+            let packet = state.seal(announcement(&self.node_id, announced.token, RESPONSE));
+            drop(state);
+            if let Err(e) = self.transport().send(&packet) {
+                debug!(target: TARGET, error = %e, "path response failed");
+            }
+            return;
+        }
+        drop(state);
+        if announced.answer {
+            self.announce(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::config::Config;
+    use std::net::UdpSocket;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn announcements_round_trip() {
+        let frame = Frame::from_bytes(&hello("pod-7", 42, true).to_bytes()).unwrap();
+        assert!(frame.hello);
+        let announced = parse(&frame.payload).unwrap();
+        assert_eq!(announced.node_id, "pod-7");
+        assert_eq!(announced.token, 42);
+        assert!(announced.answer);
+        assert!(!parse(&hello("pod-7", 42, false).payload).unwrap().answer);
+        assert_eq!(parse(&hello("", 42, true).payload), None);
+        assert_eq!(parse(&[ANSWER, 0, 0]), None);
+        assert_eq!(parse(&[]), None);
+    }
+
+    #[test]
+    fn clients_announce_their_node_id() -> io::Result<()> {
+        let conf = Config {
+            host: "127.0.0.1".to_string(),
+            port: 8147,
+            bind_host: "127.0.0.1".to_string(),
+            ..Default::default()
+        };
+        let server = Server::init(&Config {
+            node_id: "server-1".to_string(),
+            ..conf.clone()
+        })?;
+        server.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let client = Client::init(&Config {
+            node_id: "sensor-7".to_string(),
+            ..conf
+        })?;
+        assert_eq!(client.node_id(), "sensor-7");
+
+        client.send(b"hello")?;
+        let mut buffer = [0u8; 16];
+        let (received, peer) = server.receive_from_peer(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"hello");
+        assert_eq!(peer.node_id.as_deref(), Some("sensor-7"));
+        assert_eq!(server.node_addr("sensor-7"), Some(peer.addr));
+        assert_eq!(server.node_addr("sensor-8"), None);
+
+        // The server announced itself before this reply, so the client has taken that in.
+        client.set_read_timeout(Some(Duration::from_secs(5)))?;
+        server.send_to(b"reply", peer.addr)?;
+        client.receive(&mut buffer)?;
+        assert_eq!(client.server_node_id().as_deref(), Some("server-1"));
+        Ok(())
+    }
+
+    #[test]
+    fn servers_ask_clients_they_cannot_name() {
+        let mut state = PeerState::new(&Config::default());
+        let ask = Frame::from_bytes(&state.ask_node_id("server-1").unwrap()).unwrap();
+        let announced = parse(&ask.payload).unwrap();
+        assert_eq!(
+            (announced.node_id.as_str(), announced.answer),
+            ("server-1", true)
+        );
+        assert!(state.ask_node_id("server-1").is_none());
+
+        let mut named = PeerState::new(&Config::default());
+        named.node_id = Some("sensor-7".to_string());
+        assert!(named.ask_node_id("server-1").is_none());
+    }
+
+    #[test]
+    fn sessions_follow_clients_that_hold_their_token() -> io::Result<()> {
+        let server = Server::init(&Config {
+            host: "127.0.0.1".to_string(),
+            port: 8148,
+            bind_host: "127.0.0.1".to_string(),
+            ..Default::default()
+        })?;
+        server.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let socket = || -> io::Result<UdpSocket> {
+            let socket = UdpSocket::bind("127.0.0.1:0")?;
+            socket.connect("127.0.0.1:8148")?;
+            socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+            Ok(socket)
+        };
+        let mut buffer = [0u8; 512];
+        // Sends a message, then answers the server's request to announce itself and its path
+        // challenge, if it sends one.
+        let rebind = |socket: &UdpSocket, token: u64, buffer: &mut [u8]| -> io::Result<()> {
+            socket.send(&Frame::new(b"moved".to_vec()).to_bytes())?;
+            let received = socket.recv(buffer)?;
+            assert!(Frame::from_bytes(&buffer[..received]).unwrap().hello);
+            socket.send(&hello("sensor-7", token, true).to_bytes())?;
+            let received = socket.recv(buffer)?;
+            let reply = parse(&Frame::from_bytes(&buffer[..received]).unwrap().payload).unwrap();
+            if reply.challenge {
+                socket.send(&announcement("sensor-7", reply.token, RESPONSE).to_bytes())?;
+            }
+            Ok(())
+        };
+
+        let first = socket()?;
+        first.send(&hello("sensor-7", 42, true).to_bytes())?;
+        first.recv(&mut buffer)?;
+        let second = socket()?;
+        rebind(&second, 42, &mut buffer)?;
+        let moved = second.local_addr()?;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.migrations() == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(server.migrations(), 1);
+        assert!(server.stats(first.local_addr()?).is_none());
+        assert_eq!(server.node_addr("sensor-7"), Some(moved));
+
+        // Claiming the node ID without the token starts a session of its own.
+        let impostor = socket()?;
+        rebind(&impostor, 43, &mut buffer)?;
+        assert_eq!(server.migrations(), 1);
+        assert!(server.stats(moved).is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn replayed_announcements_do_not_move_sessions() -> io::Result<()> {
+        let server = Server::init(&Config {
+            host: "127.0.0.1".to_string(),
+            port: 8152,
+            bind_host: "127.0.0.1".to_string(),
+            ..Default::default()
+        })?;
+        server.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let socket = || -> io::Result<UdpSocket> {
+            let socket = UdpSocket::bind("127.0.0.1:0")?;
+            socket.connect("127.0.0.1:8152")?;
+            socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+            Ok(socket)
+        };
+        let mut buffer = [0u8; 512];
+        let client = socket()?;
+        client.send(&hello("sensor-7", 42, true).to_bytes())?;
+        client.recv(&mut buffer)?;
+
+        // The announcement comes again from another address, which is challenged and answers
+        // with the wrong nonce, and a third answers a challenge it was never sent.
+        let spoofed = socket()?;
+        spoofed.send(&hello("sensor-7", 42, false).to_bytes())?;
+        let received = spoofed.recv(&mut buffer)?;
+        let challenge = parse(&Frame::from_bytes(&buffer[..received]).unwrap().payload).unwrap();
+        assert!(challenge.challenge);
+        let guess = challenge.token.wrapping_add(1);
+        spoofed.send(&announcement("sensor-7", guess, RESPONSE).to_bytes())?;
+        let unsolicited = socket()?;
+        unsolicited.send(&announcement("sensor-7", 0, RESPONSE).to_bytes())?;
+
+        // The server takes datagrams in order, so the others are dealt with once this arrives.
+        spoofed.send(&Frame::new(b"after".to_vec()).to_bytes())?;
+        let (received, _) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"after");
+        assert_eq!(server.migrations(), 0);
+        assert!(server.stats(client.local_addr()?).is_some());
+        assert_eq!(server.node_addr("sensor-7"), Some(client.local_addr()?));
+        Ok(())
+    }
+}
@@ -0,0 +1,221 @@
+//! Session metrics as OpenTelemetry instruments. Each is observed when the meter exports, from
+//! the `Metrics` and `Stats` of every session a client or server has at that moment.
+
+use super::{lock, Client, Metrics, Server, Stats};
+use crate::otel::Telemetry;
+use opentelemetry::metrics::Meter;
+use opentelemetry::KeyValue;
+use std::sync::Arc;
+
+/// The sessions to report, each with the attributes to report it under.
+type Sampler = Arc<dyn Fn() -> Vec<(Vec<KeyValue>, Metrics, Stats)> + Send + Sync>;
+
+impl Client {
+    /// Reports the session's metrics through `telemetry`'s meter from now on, under the
+    /// attribute `crumb.role` set to `client`. They stop being reported once the client is
+    /// closed.
+    pub fn export_metrics(&self, telemetry: &Telemetry) {
+        let shared = Arc::downgrade(&self.shared);
+        register(
+            &telemetry.meter(),
+            Arc::new(move || {
+                let Some(shared) = shared.upgrade() else {
+                    return Vec::new();
+                };
+                let state = lock(&shared.state);
+                let attributes = vec![KeyValue::new("crumb.role", "client")];
+                vec![(attributes, state.metrics(), state.stats())]
+            }),
+        );
+    }
+}
+
+impl Server {
+    /// Reports the metrics of every session through `telemetry`'s meter from now on, under the
+    /// attribute `crumb.role` set to `server` and `crumb.peer` set to the client's address.
+    pub fn export_metrics(&self, telemetry: &Telemetry) {
+        let shared = Arc::downgrade(&self.shared);
+        register(
+            &telemetry.meter(),
+            Arc::new(move || {
+                let Some(shared) = shared.upgrade() else {
+                    return Vec::new();
+                };
+                let peers = lock(&shared.peers);
+                peers
+                    .iter()
+                    .map(|(addr, state)| {
+                        let attributes = vec![
+                            KeyValue::new("crumb.role", "server"),
+                            KeyValue::new("crumb.peer", addr.to_string()),
+                        ];
+                        (attributes, state.metrics(), state.stats())
+                    })
+                    .collect()
+            }),
+        );
+    }
+}
+
+fn register(meter: &Meter, sampler: Sampler) {
+    gauge(
+        meter,
+        "crumb.session.send_window",
+        "Reliable messages that may await acknowledgement",
+        &sampler,
+        |metrics, _| metrics.send_window as u64,
+    );
+    gauge(
+        meter,
+        "crumb.session.queued",
+        "Messages waiting in the send queue",
+        &sampler,
+        |metrics, _| metrics.queued as u64,
+    );
+    gauge(
+        meter,
+        "crumb.session.reorder_depth",
+        "Messages buffered waiting for an earlier one",
+        &sampler,
+        |metrics, _| metrics.reorder_depth as u64,
+    );
+    gauge(
+        meter,
+        "crumb.session.jitter_depth",
+        "Stamped messages held until their time to be played out",
+        &sampler,
+        |metrics, _| metrics.jitter_depth as u64,
+    );
+    counter(
+        meter,
+        "crumb.session.retransmits",
+        "Reliable messages sent again",
+        &sampler,
+        |_, stats| stats.retransmits,
+    );
+    counter(
+        meter,
+        "crumb.session.bytes_sent",
+        "Bytes sent to the peer",
+        &sampler,
+        |_, stats| stats.bytes_sent,
+    );
+    counter(
+        meter,
+        "crumb.session.bytes_received",
+        "Bytes received from the peer",
+        &sampler,
+        |_, stats| stats.bytes_received,
+    );
+
+    let sample = sampler.clone();
+    meter
+        .u64_observable_counter("crumb.session.dropped")
+        .with_description("Messages and packets dropped, by reason")
+        .with_callback(move |observer| {
+            for (attributes, metrics, _) in sample() {
+                for (reason, count) in [
+                    ("queue_full", metrics.queue_dropped),
+                    ("late", metrics.dropped_late),
+                    ("replayed", metrics.dropped_replayed),
+                    ("duplicate", metrics.dedup_hits),
+                    ("corrupt", metrics.dropped_corrupt),
+                    ("version", metrics.dropped_version),
+                    ("unauthenticated", metrics.dropped_unauthenticated),
+                    ("unverified", metrics.dropped_unverified),
+                    ("jitter_late", metrics.jitter_dropped),
+                ] {
+                    let mut attributes = attributes.clone();
+                    attributes.push(KeyValue::new("crumb.reason", reason));
+                    observer.observe(count, &attributes);
+                }
+            }
+        })
+        .build();
+
+    let sample = sampler.clone();
+    meter
+        .f64_observable_gauge("crumb.session.rtt")
+        .with_description("Smoothed round-trip time")
+        .with_unit("s")
+        .with_callback(move |observer| {
+            for (attributes, _, stats) in sample() {
+                if let Some(rtt) = stats.rtt {
+                    observer.observe(rtt.as_secs_f64(), &attributes);
+                }
+            }
+        })
+        .build();
+    let sample = sampler.clone();
+    meter
+        .f64_observable_gauge("crumb.session.loss_rate")
+        .with_description("Fraction of reliable transmissions that were retransmissions")
+        .with_callback(move |observer| {
+            for (attributes, _, stats) in sample() {
+                observer.observe(stats.loss_rate, &attributes);
+            }
+        })
+        .build();
+    let sample = sampler.clone();
+    meter
+        .f64_observable_gauge("crumb.session.one_way_delay")
+        .with_description("Smoothed time the peer's stamped messages take to arrive")
+        .with_unit("s")
+        .with_callback(move |observer| {
+            for (attributes, _, stats) in sample() {
+                if let Some(delay) = stats.one_way_delay {
+                    observer.observe(delay.as_secs_f64(), &attributes);
+                }
+            }
+        })
+        .build();
+    meter
+        .f64_observable_gauge("crumb.session.remote_loss_rate")
+        .with_description("Fraction of messages the peer reported lost")
+        .with_callback(move |observer| {
+            for (attributes, _, stats) in sampler() {
+                if let Some(loss_rate) = stats.remote_loss_rate {
+                    observer.observe(loss_rate, &attributes);
+                }
+            }
+        })
+        .build();
+}
+
+fn gauge(
+    meter: &Meter,
+    name: &'static str,
+    description: &'static str,
+    sampler: &Sampler,
+    value: fn(&Metrics, &Stats) -> u64,
+) {
+    let sample = sampler.clone();
+    meter
+        .u64_observable_gauge(name)
+        .with_description(description)
+        .with_callback(move |observer| {
+            for (attributes, metrics, stats) in sample() {
+                observer.observe(value(&metrics, &stats), &attributes);
+            }
+        })
+        .build();
+}
+
+fn counter(
+    meter: &Meter,
+    name: &'static str,
+    description: &'static str,
+    sampler: &Sampler,
+    value: fn(&Metrics, &Stats) -> u64,
+) {
+    let sample = sampler.clone();
+    meter
+        .u64_observable_counter(name)
+        .with_description(description)
+        .with_callback(move |observer| {
+            for (attributes, metrics, stats) in sample() {
+                observer.observe(value(&metrics, &stats), &attributes);
+            }
+        })
+        .build();
+}
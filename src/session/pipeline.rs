@@ -0,0 +1,192 @@
+//! The pipeline between the application and the wire. A message payload passes through the
+//! stages `pipeline` lists, in order, then is sealed with the session's pre-shared key, if it
+//! has one, and framed. The frame header records which stages changed the payload and in which
+//! order, in its `compression` byte, so a receiver undoes them in reverse without knowing how
+//! the sender's pipeline was set up.
+//!
+//! Stream chunks pass through the compression stage only.
+
+use super::codec::Codec;
+use super::{PeerState, TARGET};
+use crate::compression;
+use crate::error::Error;
+use crate::protocol::Frame;
+use crate::util::config::{CompressionType, Pipeline, Stage};
+use std::io;
+use tracing::{debug, trace};
+
+impl Pipeline {
+    /// Runs the payload of `frame` through the stages in order, compressing with `compression`
+    /// and encoding with `codec`, if the message is one a codec may encode.
+    pub(super) fn encode(
+        &self,
+        compression: CompressionType,
+        codec: Option<&Codec>,
+        mut frame: Frame,
+    ) -> io::Result<Frame> {
+        for stage in self.stages() {
+            match stage {
+                Stage::Compress if compression::is_compressed(&frame.payload) => {
+                    trace!(target: TARGET, "payload compressed already, not compressing");
+                }
+                Stage::Compress => {
+                    let (used, payload) = compression::compress(compression, &frame.payload)?;
+                    frame.compressed_last = frame.encoded && used != CompressionType::None;
+                    frame.compression = used;
+                    frame.payload = payload;
+                }
+                Stage::Codec => {
+                    if let Some(codec) = codec {
+                        codec.encode(&mut frame)?;
+                    }
+                }
+            }
+        }
+        Ok(frame)
+    }
+}
+
+impl PeerState {
+    /// Undoes what the header says was done to each payload, in reverse, dropping and counting
+    /// as corrupt the messages that cannot be. Runs after reordering so an undecodable message
+    /// still fills its place in the sequence.
+    pub(super) fn decode(&mut self, frames: Vec<Frame>) -> Vec<Frame> {
+        let mut messages = Vec::with_capacity(frames.len());
+        for frame in frames {
+            let msg_id = frame.msg_id;
+            match self.unwind(frame) {
+                Ok(frame) => messages.push(frame),
+                Err(e) => {
+                    debug!(target: TARGET, msg_id, error = %e, "undecodable message dropped");
+                    self.dropped_corrupt += 1;
+                }
+            }
+        }
+        messages
+    }
+
+    fn unwind(&self, mut frame: Frame) -> io::Result<Frame> {
+        if frame.compressed_last {
+            decompress(&mut frame)?;
+        }
+        if frame.encoded {
+            let codec = self
+                .codec
+                .as_ref()
+                .ok_or_else(|| Error::codec("Encoded message but no codec registered"))?;
+            codec.decode(&mut frame)?;
+        }
+        decompress(&mut frame)?;
+        Ok(frame)
+    }
+}
+
+fn decompress(frame: &mut Frame) -> io::Result<()> {
+    frame.payload = compression::decompress(frame.compression, &frame.payload)?;
+    frame.compression = CompressionType::None;
+    frame.compressed_last = false;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Priority;
+    use crate::session::{CodecContext, FrameCodec};
+    use crate::util::config::Config;
+    use std::sync::Arc;
+
+    const TEXT: &[u8] = b"crumb crumb crumb crumb crumb crumb crumb crumb crumb crumb crumb";
+
+    // Appends a signature byte, which a compressor could not have produced.
+    struct Sign;
+
+    impl FrameCodec for Sign {
+        fn encode(&self, mut payload: Vec<u8>, _: &CodecContext) -> io::Result<Vec<u8>> {
+            payload.push(0xee);
+            Ok(payload)
+        }
+
+        fn decode(&self, mut payload: Vec<u8>, _: &CodecContext) -> io::Result<Vec<u8>> {
+            match payload.pop() {
+                Some(0xee) => Ok(payload),
+                _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unsigned")),
+            }
+        }
+    }
+
+    #[test]
+    fn receivers_undo_either_order() -> io::Result<()> {
+        let codec = Arc::new(Codec::default());
+        codec.set(Box::new(Sign));
+        let mut sender = PeerState::new(&Config::default());
+        let mut receiver = PeerState::new(&Config::default()).with_codec(codec.clone());
+        for pipeline in ["compress,codec", "codec,compress", "codec", "none"] {
+            let pipeline: Pipeline = pipeline.parse().unwrap();
+            let frame = pipeline.encode(
+                CompressionType::Gzip,
+                Some(&codec),
+                Frame::new(TEXT.to_vec()),
+            )?;
+            let compressed = CompressionType::Gzip.is_supported()
+                && pipeline.stages().contains(&Stage::Compress);
+            assert_eq!(frame.compression != CompressionType::None, compressed);
+            assert_eq!(frame.encoded, pipeline.stages().contains(&Stage::Codec));
+            assert_eq!(
+                frame.compressed_last,
+                compressed && pipeline.stages()[0] == Stage::Codec
+            );
+            let (messages, _) = receiver.incoming(&sender.outgoing(frame));
+            assert_eq!(messages[0].payload, TEXT);
+        }
+
+        // Without the codec, encoded messages cannot be read.
+        let mut plain = PeerState::new(&Config::default());
+        let frame = Pipeline::default().encode(
+            CompressionType::None,
+            Some(&codec),
+            Frame::new(TEXT.to_vec()),
+        )?;
+        let (messages, _) = plain.incoming(&sender.outgoing(frame));
+        assert!(messages.is_empty());
+        assert_eq!(plain.metrics().dropped_corrupt, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn policies_pick_compression_by_type_and_priority() -> io::Result<()> {
+        let conf = Config {
+            compression_type: CompressionType::Gzip,
+            compression_policy: "type:9=none,priority:control=none".parse().unwrap(),
+            ..Default::default()
+        };
+        let gzip = match CompressionType::Gzip.is_supported() {
+            true => CompressionType::Gzip,
+            false => CompressionType::None,
+        };
+        for (msg_type, priority, expected) in [
+            (0, Priority::Bulk, gzip),
+            (9, Priority::Bulk, CompressionType::None),
+            (0, Priority::Control, CompressionType::None),
+        ] {
+            let message = Frame {
+                msg_type,
+                priority,
+                ..Frame::new(TEXT.to_vec())
+            };
+            assert_eq!(
+                super::super::frame(&conf, None, message)?.compression,
+                expected
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_payloads_skip_compression() -> io::Result<()> {
+        let gzipped = [&[0x1f, 0x8b], TEXT].concat();
+        let frame = Pipeline::default().encode(CompressionType::Gzip, None, Frame::new(gzipped))?;
+        assert_eq!(frame.compression, CompressionType::None);
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+use std::time::{Duration, Instant};
+
+/// Datagram size assumed to cross any path: the IPv6 minimum MTU less IP and UDP headers,
+/// rounded down as QUIC does.
+pub(crate) const BASE_PLPMTU: usize = 1200;
+
+// Largest size searched for: a 9000 byte jumbo frame less IPv4 and UDP headers.
+const MAX_PLPMTU: usize = 8972;
+
+// Unanswered probes of one size before the size is taken not to fit.
+const MAX_PROBES: u32 = 3;
+
+// The search stops once the largest size known to fit is this close to the smallest known not to.
+const SEARCH_PRECISION: usize = 16;
+
+// How long a finished search stands before the path is probed for a larger size again.
+const RAISE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Datagram packetization layer path MTU discovery (RFC 8899) for one peer.
+///
+/// Probes of increasing size are sent in a binary search between the largest size confirmed to
+/// cross the path and the smallest that went unanswered `MAX_PROBES` times. When reliable
+/// messages start being lost after the search has finished, the current size is probed again,
+/// and if it no longer gets through the search restarts from `BASE_PLPMTU`.
+#[derive(Debug)]
+pub(crate) struct PathMtu {
+    mtu: usize,
+    // The smallest size known not to fit, or one past the largest searched.
+    ceiling: usize,
+    probe: Option<Probe>,
+    confirming: bool,
+    raise_at: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Probe {
+    len: usize,
+    sent_at: Instant,
+    attempts: u32,
+}
+
+impl PathMtu {
+    pub(crate) fn new() -> PathMtu {
+        PathMtu {
+            mtu: BASE_PLPMTU,
+            ceiling: MAX_PLPMTU + 1,
+            probe: None,
+            confirming: false,
+            raise_at: None,
+        }
+    }
+
+    /// The largest datagram known to cross the path.
+    pub(crate) fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// The size of the probe to send now, if one is due. A probe unanswered after `timeout` is
+    /// sent again.
+    pub(crate) fn next_probe(&mut self, now: Instant, timeout: Duration) -> Option<usize> {
+        if let Some(probe) = &mut self.probe {
+            if now.saturating_duration_since(probe.sent_at) < timeout {
+                return None;
+            }
+            probe.attempts += 1;
+            if probe.attempts < MAX_PROBES {
+                probe.sent_at = now;
+                return Some(probe.len);
+            }
+            self.ceiling = probe.len;
+            self.probe = None;
+            if self.confirming {
+                // Black hole: what used to fit no longer does.
+                self.confirming = false;
+                self.mtu = BASE_PLPMTU;
+                self.raise_at = None;
+            }
+        }
+
+        let len = match self.confirming {
+            true => self.mtu,
+            false if self.ceiling - self.mtu <= SEARCH_PRECISION => {
+                let raise_at = *self.raise_at.get_or_insert(now + RAISE_INTERVAL);
+                if now < raise_at {
+                    return None;
+                }
+                self.raise_at = None;
+                self.ceiling = MAX_PLPMTU + 1;
+                self.mtu + (self.ceiling - self.mtu) / 2
+            }
+            false => self.mtu + (self.ceiling - self.mtu) / 2,
+        };
+        self.probe = Some(Probe {
+            len,
+            sent_at: now,
+            attempts: 0,
+        });
+        Some(len)
+    }
+
+    /// Takes the acknowledgement of a probe of `len` bytes.
+    pub(crate) fn on_probe_ack(&mut self, len: usize) {
+        if self.probe.as_ref().is_none_or(|probe| probe.len != len) {
+            return;
+        }
+        self.probe = None;
+        self.confirming = false;
+        self.mtu = self.mtu.max(len);
+    }
+
+    /// Reports that reliable messages went unacknowledged, which may mean the path MTU shrank.
+    pub(crate) fn on_loss(&mut self) {
+        let searched = self.ceiling - self.mtu <= SEARCH_PRECISION;
+        if self.probe.is_none() && searched && self.mtu > BASE_PLPMTU {
+            self.confirming = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    // Runs the search against a path that carries datagrams up to `path` bytes.
+    fn search(pmtu: &mut PathMtu, path: usize, mut now: Instant) -> Instant {
+        while let Some(len) = pmtu.next_probe(now, TIMEOUT) {
+            if len <= path {
+                pmtu.on_probe_ack(len);
+            }
+            now += TIMEOUT;
+        }
+        now
+    }
+
+    #[test]
+    fn search_converges_on_the_path_mtu() {
+        let mut pmtu = PathMtu::new();
+        search(&mut pmtu, 1472, Instant::now());
+        assert!(pmtu.mtu() <= 1472);
+        assert!(pmtu.mtu() > 1472 - SEARCH_PRECISION);
+    }
+
+    #[test]
+    fn paths_below_the_base_keep_the_base() {
+        let mut pmtu = PathMtu::new();
+        search(&mut pmtu, 1000, Instant::now());
+        assert_eq!(pmtu.mtu(), BASE_PLPMTU);
+    }
+
+    #[test]
+    fn black_holes_restart_the_search() {
+        let mut pmtu = PathMtu::new();
+        let now = search(&mut pmtu, MAX_PLPMTU, Instant::now());
+        assert!(pmtu.mtu() > MAX_PLPMTU - SEARCH_PRECISION);
+
+        pmtu.on_loss();
+        search(&mut pmtu, 1400, now);
+        assert!(pmtu.mtu() <= 1400);
+        assert!(pmtu.mtu() > 1400 - SEARCH_PRECISION);
+    }
+
+    #[test]
+    fn larger_sizes_are_probed_again_later() {
+        let mut pmtu = PathMtu::new();
+        let now = search(&mut pmtu, 1400, Instant::now());
+        search(&mut pmtu, 4000, now + RAISE_INTERVAL);
+        assert!(pmtu.mtu() > 4000 - SEARCH_PRECISION);
+    }
+}
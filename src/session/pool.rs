@@ -0,0 +1,100 @@
+use super::{Client, ConnectionState};
+use crate::util::config::Config;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Several session clients sending to the same server, or spread over `endpoints`, with sends
+/// balanced across them round-robin.
+///
+/// Each client has its own socket, background thread and sequence space, so a pool gets past
+/// the throughput of a single socket and spreads the cost of sealing and acknowledging over
+/// several threads. Messages sent through different clients may arrive in any order, even with
+/// `ordered` set, and the server sees each client as a separate peer.
+pub struct ClientPool {
+    clients: Vec<Client>,
+    next: AtomicUsize,
+}
+
+impl ClientPool {
+    /// Opens `size` clients, at least one. With several `endpoints`, client `i` starts with
+    /// endpoint `i` and fails over to the ones after it, so the pool is spread over them all.
+    pub fn init(conf: &Config, size: usize) -> io::Result<ClientPool> {
+        let clients = (0..size.max(1))
+            .map(|i| {
+                let mut endpoints = conf.endpoints.clone();
+                if !endpoints.is_empty() {
+                    endpoints.rotate_left(i % conf.endpoints.len());
+                }
+                Client::init(&Config {
+                    endpoints,
+                    ..conf.clone()
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(ClientPool {
+            clients,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Sends `data` through the next client in turn, as `Client::send` does, skipping clients
+    /// that have given up reconnecting. Fails with `ErrorKind::NotConnected` if all have.
+    pub fn send(&self, data: &[u8]) -> io::Result<usize> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let count = self.clients.len();
+        (0..count)
+            .map(|i| &self.clients[(start + i) % count])
+            .find(|client| client.connection_state() != ConnectionState::Disconnected)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "Every pooled client disconnected",
+                )
+            })?
+            .send(data)
+    }
+
+    /// The pooled clients, to receive replies, read their statistics or ping through.
+    pub fn clients(&self) -> &[Client] {
+        &self.clients
+    }
+
+    /// Closes every client as `Client::close` does. Dropping the pool does the same.
+    pub fn close(self) {
+        drop(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Server;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    #[test]
+    fn sends_are_spread_over_the_pool() -> io::Result<()> {
+        let conf = Config {
+            host: "127.0.0.1".to_string(),
+            port: 8122,
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        server.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let pool = ClientPool::init(&conf, 3)?;
+        for i in 0..6 {
+            pool.send(format!("message {}", i).as_bytes())?;
+        }
+
+        let mut per_client = HashMap::new();
+        let mut buffer = [0u8; 64];
+        for _ in 0..6 {
+            let (_, source) = server.receive_from(&mut buffer)?;
+            *per_client.entry(source.port()).or_insert(0) += 1;
+        }
+        assert_eq!(per_client.len(), 3);
+        assert!(per_client.values().all(|&messages| messages == 2));
+        pool.close();
+        Ok(())
+    }
+}
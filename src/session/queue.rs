@@ -0,0 +1,149 @@
+use crate::protocol::Priority;
+use crate::util::config::QueuePolicy;
+use std::collections::VecDeque;
+use std::io;
+
+/// Messages accepted from the application but not yet allowed out by the congestion window or
+/// the rate limit, in one lane per priority. Lanes are drained most urgent first, and share the
+/// capacity.
+#[derive(Debug)]
+pub(crate) struct SendQueue<T> {
+    lanes: [VecDeque<T>; Priority::ALL.len()],
+    len: usize,
+    capacity: usize,
+    policy: QueuePolicy,
+    dropped: u64,
+}
+
+impl<T> SendQueue<T> {
+    pub(crate) fn new(capacity: usize, policy: QueuePolicy) -> SendQueue<T> {
+        SendQueue {
+            lanes: Default::default(),
+            len: 0,
+            capacity: capacity.max(1),
+            policy,
+            dropped: 0,
+        }
+    }
+
+    /// Whether a sender has to wait for space before calling `push`.
+    pub(crate) fn must_wait(&self) -> bool {
+        self.policy == QueuePolicy::Block && self.is_full()
+    }
+
+    /// Whether the queue holds as many messages as it takes, whatever the policy.
+    pub(crate) fn is_full(&self) -> bool {
+        self.len >= self.capacity
+    }
+
+    /// Queues `message` in the lane for `priority`, applying the policy if the queue is full.
+    /// The drop-oldest policy sheds the oldest message of the least urgent lane holding any,
+    /// and returns it.
+    pub(crate) fn push(&mut self, message: T, priority: Priority) -> io::Result<Option<T>> {
+        let mut shed = None;
+        if self.is_full() {
+            match self.policy {
+                QueuePolicy::Error => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        "Send queue is full",
+                    ))
+                }
+                QueuePolicy::DropOldest => {
+                    if let Some(lane) = self.lanes.iter_mut().rfind(|lane| !lane.is_empty()) {
+                        shed = lane.pop_front();
+                        self.len -= 1;
+                        self.dropped += 1;
+                    }
+                }
+                // Callers wait on must_wait() first, so this is only reached by racing senders
+                // and briefly overfills the queue.
+                QueuePolicy::Block => {}
+            }
+        }
+        self.restore(message, priority);
+        Ok(shed)
+    }
+
+    /// Queues `message` in the lane for `priority` whatever the capacity, for messages
+    /// accepted earlier, such as those spooled by a previous process.
+    pub(crate) fn restore(&mut self, message: T, priority: Priority) {
+        self.lanes[priority.tag() as usize].push_back(message);
+        self.len += 1;
+    }
+
+    /// Takes the oldest message of the most urgent lane holding any.
+    pub(crate) fn pop(&mut self) -> Option<T> {
+        let message = self.lanes.iter_mut().find_map(VecDeque::pop_front)?;
+        self.len -= 1;
+        Some(message)
+    }
+
+    /// Every queued message, most urgent lane first.
+    pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.lanes.iter_mut().flatten()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Messages discarded by the drop-oldest policy.
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_policy_rejects_when_full() {
+        let mut queue = SendQueue::new(1, QueuePolicy::Error);
+        queue.push(b"a".to_vec(), Priority::Normal).unwrap();
+        assert!(!queue.must_wait());
+        let err = queue.push(b"b".to_vec(), Priority::Normal).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(queue.pop(), Some(b"a".to_vec()));
+    }
+
+    #[test]
+    fn drop_oldest_policy_sheds_the_head() {
+        let mut queue = SendQueue::new(2, QueuePolicy::DropOldest);
+        queue.push(b"a".to_vec(), Priority::Normal).unwrap();
+        queue.push(b"b".to_vec(), Priority::Normal).unwrap();
+        let shed = queue.push(b"c".to_vec(), Priority::Normal).unwrap();
+        assert_eq!((shed, queue.dropped()), (Some(b"a".to_vec()), 1));
+        assert_eq!(queue.pop(), Some(b"b".to_vec()));
+        assert_eq!(queue.pop(), Some(b"c".to_vec()));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn block_policy_asks_senders_to_wait() {
+        let mut queue = SendQueue::new(1, QueuePolicy::Block);
+        assert!(!queue.must_wait());
+        queue.push(b"a".to_vec(), Priority::Normal).unwrap();
+        assert!(queue.must_wait());
+    }
+
+    #[test]
+    fn urgent_lanes_go_first_and_bulk_is_shed_first() {
+        let mut queue = SendQueue::new(3, QueuePolicy::DropOldest);
+        queue.push(b"bulk".to_vec(), Priority::Bulk).unwrap();
+        queue.push(b"normal".to_vec(), Priority::Normal).unwrap();
+        queue.push(b"high".to_vec(), Priority::High).unwrap();
+        queue.push(b"control".to_vec(), Priority::Control).unwrap();
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(queue.len(), 3);
+
+        let order: Vec<_> = std::iter::from_fn(|| queue.pop()).collect();
+        assert_eq!(order, [&b"control"[..], b"high", b"normal"]);
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,211 @@
+//! Delivery receipts, for producers that keep their own record of what has been delivered:
+//! `Client::send_with_receipt` returns a `Receipt` that settles once the server acknowledges
+//! the message or the client gives it up.
+
+use super::{lock, Client};
+use crate::error::Error;
+use crate::protocol::Priority;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+/// How a message sent with a receipt fared, once known.
+type Outcome = Result<(), (io::ErrorKind, &'static str)>;
+
+#[derive(Default)]
+struct Slot {
+    state: Mutex<SlotState>,
+    settled: Condvar,
+}
+
+#[derive(Default)]
+struct SlotState {
+    outcome: Option<Outcome>,
+    waker: Option<Waker>,
+}
+
+/// Tells whether the server acknowledged a message, once it has or the client has given the
+/// message up. Wait for it with `wait`, poll it with `status`, or await it.
+///
+/// A receipt fails with `ErrorKind::TimedOut` when the message went unacknowledged through as
+/// many retransmissions as make a reconnecting client take its server to be gone, after which
+/// it is not sent again. Messages of ordered sessions cannot be skipped, so they are retried
+/// for as long as the session lasts. It fails with `ErrorKind::NotConnected` when every
+/// reconnection attempt failed first, and with `ErrorKind::ConnectionAborted` when the message
+/// was shed from a full send queue or the client closed before it was acknowledged.
+///
+/// A failed receipt means the client stopped trying, not that the server never received the
+/// message: only its acknowledgement may have been lost.
+pub struct Receipt {
+    slot: Arc<Slot>,
+}
+
+impl Receipt {
+    /// Waits until the message is acknowledged or given up.
+    pub fn wait(&self) -> io::Result<()> {
+        let state = lock(&self.slot.state);
+        let state = self
+            .slot
+            .settled
+            .wait_while(state, |state| state.outcome.is_none())
+            .unwrap_or_else(|e| e.into_inner());
+        into_result(state.outcome.unwrap())
+    }
+
+    /// Like `wait`, but fails with `Error::Timeout` if the receipt is not settled within
+    /// `timeout`.
+    pub fn wait_timeout(&self, timeout: Duration) -> io::Result<()> {
+        let state = lock(&self.slot.state);
+        let (state, _) = self
+            .slot
+            .settled
+            .wait_timeout_while(state, timeout, |state| state.outcome.is_none())
+            .unwrap_or_else(|e| e.into_inner());
+        match state.outcome {
+            Some(outcome) => into_result(outcome),
+            None => Err(Error::Timeout.into()),
+        }
+    }
+
+    /// How the message fared, or `None` while that is not known yet.
+    pub fn status(&self) -> Option<io::Result<()>> {
+        lock(&self.slot.state).outcome.map(into_result)
+    }
+}
+
+/// Receipts settle on the client's worker thread, which wakes the task awaiting them, so they
+/// need no particular runtime.
+impl Future for Receipt {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut state = lock(&self.slot.state);
+        match state.outcome {
+            Some(outcome) => Poll::Ready(into_result(outcome)),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+fn into_result(outcome: Outcome) -> io::Result<()> {
+    outcome.map_err(|(kind, reason)| io::Error::new(kind, reason))
+}
+
+/// The client's side of a `Receipt`, travelling with the message through the send queue and
+/// until acknowledged. Dropping it unsettled fails the receipt as aborted.
+pub(super) struct Pending(Option<Arc<Slot>>);
+
+impl Pending {
+    pub(super) fn new() -> (Pending, Receipt) {
+        let slot = Arc::new(Slot::default());
+        (Pending(Some(slot.clone())), Receipt { slot })
+    }
+
+    pub(super) fn delivered(mut self) {
+        self.settle(Ok(()));
+    }
+
+    pub(super) fn failed(mut self, kind: io::ErrorKind, reason: &'static str) {
+        self.settle(Err((kind, reason)));
+    }
+
+    fn settle(&mut self, outcome: Outcome) {
+        let Some(slot) = self.0.take() else {
+            return;
+        };
+        let mut state = lock(&slot.state);
+        state.outcome = Some(outcome);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        slot.settled.notify_all();
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.settle(Err((
+            io::ErrorKind::ConnectionAborted,
+            "Message discarded before it was acknowledged",
+        )));
+    }
+}
+
+impl Client {
+    /// Like `send`, but returns a `Receipt` telling when the server has acknowledged the
+    /// message, or that the client gave it up. Fails with `Error::Config` when the session's
+    /// `delivery` is at-most-once, since nothing is acknowledged then.
+    pub fn send_with_receipt(&self, data: &[u8]) -> io::Result<Receipt> {
+        if !self.shared.conf.delivery.is_reliable() {
+            return Err(Error::config("Receipts need a reliable session").into());
+        }
+        let _enter = self.shared.span.enter();
+        let frame = self
+            .shared
+            .frame(self.shared.format, Priority::Normal, data)?;
+        let (pending, receipt) = Pending::new();
+        self.queue(frame, Some(pending), super::SendQueue::must_wait)?;
+        Ok(receipt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Server;
+    use crate::transport::memory;
+    use crate::util::config::{Config, DeliveryMode};
+    use std::thread;
+
+    #[test]
+    fn receipts_settle_when_acknowledged() -> io::Result<()> {
+        let conf = Config {
+            host: "127.0.0.1".to_string(),
+            port: 8133,
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        let client = Client::init(&conf)?;
+        let receipt = client.send_with_receipt(b"counted")?;
+        receipt.wait_timeout(Duration::from_secs(5))?;
+        assert!(matches!(receipt.status(), Some(Ok(()))));
+
+        let mut buffer = [0u8; 16];
+        server.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let (received, _) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"counted");
+
+        let unreliable = Client::init(&Config {
+            delivery: DeliveryMode::AtMostOnce,
+            ..conf
+        })?;
+        let e = unreliable.send_with_receipt(b"untracked").err().unwrap();
+        assert!(matches!(Error::from(e), Error::Config(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn closing_settles_outstanding_receipts() -> io::Result<()> {
+        // Nothing reads the other end, so nothing is acknowledged.
+        let (a, b) = memory::pair(memory::Conditions::default());
+        let client = Client::with_transport(&Config::default(), Box::new(a))?;
+        let receipt = client.send_with_receipt(b"abandoned")?;
+        assert!(receipt.status().is_none());
+        let e = receipt.wait_timeout(Duration::from_millis(50)).unwrap_err();
+        assert!(matches!(Error::from(e), Error::Timeout));
+        assert!(receipt.status().is_none());
+
+        let waiter = thread::spawn(move || receipt.wait());
+        drop(b);
+        client.close();
+        let e = waiter.join().unwrap().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted);
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+use crate::util::config::Config;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Retransmission rounds without a packet from the server after which a reconnecting client
+/// takes it to be gone. With the RTO doubling from 200ms that is about 13 seconds of silence.
+pub(crate) const UNANSWERED_LIMIT: u32 = 6;
+
+// How long a reconnection attempt waits for the server to answer its ping.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Where a session client stands with its server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    /// The server was lost and attempt `attempt`, counting from 1, is pending or under way.
+    /// Messages sent meanwhile are queued.
+    Reconnecting {
+        attempt: u32,
+    },
+    /// Every reconnection attempt failed. Sending fails with `ErrorKind::NotConnected`.
+    Disconnected,
+}
+
+/// Whether `e` means the path to the server is gone rather than merely quiet.
+pub(crate) fn is_connection_lost(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+/// Decides when a client that lost its server tries to reach it again.
+///
+/// Attempt `n` waits `reconnect_backoff * 2^(n-1)`, capped at `reconnect_max_backoff`, less a
+/// random share of up to `reconnect_jitter` of that. An attempt connects a fresh transport,
+/// re-resolving the host and redoing any handshake, and pings the server over it; it succeeds
+/// once anything authentic comes back. With several endpoints configured, attempt `n` goes to
+/// the `n`th endpoint after the one that was lost, wrapping around.
+#[derive(Debug)]
+pub(crate) struct Reconnector {
+    attempts: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+    jitter: f64,
+    state: ConnectionState,
+    // When the pending attempt is made or, once made, given up.
+    due: Instant,
+    attempted_at: Option<Instant>,
+}
+
+impl Reconnector {
+    pub(crate) fn new(conf: &Config) -> Reconnector {
+        Reconnector {
+            attempts: conf.reconnect_attempts,
+            backoff: conf.reconnect_backoff,
+            max_backoff: conf.reconnect_max_backoff,
+            jitter: conf.reconnect_jitter.clamp(0.0, 1.0),
+            state: ConnectionState::Connected,
+            due: Instant::now(),
+            attempted_at: None,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.attempts > 0
+    }
+
+    /// Reports that the server is gone, returning the new state if this starts reconnecting or
+    /// fails the attempt under way.
+    pub(crate) fn on_lost(&mut self, now: Instant) -> Option<ConnectionState> {
+        match self.state {
+            ConnectionState::Connected if self.enabled() => {
+                self.schedule(1, now);
+                Some(self.state)
+            }
+            ConnectionState::Reconnecting { .. } if self.attempted_at.is_some() => self.fail(now),
+            _ => None,
+        }
+    }
+
+    /// The number of the attempt to make now, if one is due. An attempt that has gone
+    /// unanswered for too long fails, possibly changing the state.
+    pub(crate) fn poll(&mut self, now: Instant) -> (Option<u32>, Option<ConnectionState>) {
+        let ConnectionState::Reconnecting { attempt } = self.state else {
+            return (None, None);
+        };
+        if now < self.due {
+            return (None, None);
+        }
+        if self.attempted_at.is_some() {
+            return (None, self.fail(now));
+        }
+        self.attempted_at = Some(now);
+        self.due = now + REPLY_TIMEOUT;
+        (Some(attempt), None)
+    }
+
+    /// Reports when an authentic packet last came from the server. One since the attempt under
+    /// way was made completes it.
+    pub(crate) fn on_heard(&mut self, heard: Instant) -> Option<ConnectionState> {
+        match self.state {
+            ConnectionState::Reconnecting { .. }
+                if self.attempted_at.is_some_and(|at| heard >= at) =>
+            {
+                self.state = ConnectionState::Connected;
+                Some(self.state)
+            }
+            _ => None,
+        }
+    }
+
+    /// Fails the attempt under way, scheduling the next or giving up.
+    pub(crate) fn fail(&mut self, now: Instant) -> Option<ConnectionState> {
+        let ConnectionState::Reconnecting { attempt } = self.state else {
+            return None;
+        };
+        match attempt < self.attempts {
+            true => self.schedule(attempt + 1, now),
+            false => self.state = ConnectionState::Disconnected,
+        }
+        Some(self.state)
+    }
+
+    fn schedule(&mut self, attempt: u32, now: Instant) {
+        self.state = ConnectionState::Reconnecting { attempt };
+        self.due = now + self.delay(attempt, random_unit());
+        self.attempted_at = None;
+    }
+
+    // The wait before `attempt`, with `random` between 0 and 1 picking the jitter.
+    fn delay(&self, attempt: u32, random: f64) -> Duration {
+        let doubled = self
+            .backoff
+            .saturating_mul(1 << (attempt - 1).min(31))
+            .min(self.max_backoff);
+        doubled.mul_f64(1.0 - self.jitter * random)
+    }
+}
+
+// A number in [0, 1), random enough to spread clients' attempts.
+fn random_unit() -> f64 {
+    (RandomState::new().hash_one(Instant::now()) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reconnector(attempts: u32, jitter: f64) -> Reconnector {
+        Reconnector::new(&Config {
+            reconnect_attempts: attempts,
+            reconnect_backoff: Duration::from_millis(100),
+            reconnect_max_backoff: Duration::from_millis(500),
+            reconnect_jitter: jitter,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn delays_double_up_to_the_cap_less_jitter() {
+        let exact = reconnector(10, 0.0);
+        let delays: Vec<_> = (1..=5).map(|n| exact.delay(n, 0.7).as_millis()).collect();
+        assert_eq!(delays, [100, 200, 400, 500, 500]);
+        assert_eq!(exact.delay(u32::MAX, 0.0), Duration::from_millis(500));
+
+        let jittered = reconnector(10, 0.5);
+        assert_eq!(jittered.delay(2, 0.0), Duration::from_millis(200));
+        assert_eq!(jittered.delay(2, 1.0), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn attempts_run_out() {
+        let mut reconnector = reconnector(2, 0.0);
+        let now = Instant::now();
+        assert_eq!(
+            reconnector.on_lost(now),
+            Some(ConnectionState::Reconnecting { attempt: 1 })
+        );
+        assert_eq!(reconnector.poll(now), (None, None));
+        let now = now + Duration::from_millis(100);
+        assert_eq!(reconnector.poll(now), (Some(1), None));
+        assert_eq!(
+            reconnector.poll(now + REPLY_TIMEOUT),
+            (None, Some(ConnectionState::Reconnecting { attempt: 2 }))
+        );
+        assert_eq!(reconnector.on_heard(now + REPLY_TIMEOUT), None);
+
+        let now = now + REPLY_TIMEOUT + Duration::from_millis(200);
+        assert_eq!(reconnector.poll(now), (Some(2), None));
+        assert_eq!(
+            reconnector.on_lost(now),
+            Some(ConnectionState::Disconnected)
+        );
+        assert_eq!(reconnector.on_lost(now), None);
+    }
+
+    #[test]
+    fn packets_after_an_attempt_complete_it() {
+        let mut reconnector = reconnector(3, 0.5);
+        let now = Instant::now();
+        assert_eq!(reconnector.on_heard(now), None);
+        reconnector.on_lost(now);
+        let attempted = now + Duration::from_millis(100);
+        assert_eq!(reconnector.poll(attempted).0, Some(1));
+        // Packets from before the attempt do not count.
+        assert_eq!(reconnector.on_heard(now), None);
+        assert_eq!(
+            reconnector.on_heard(attempted),
+            Some(ConnectionState::Connected)
+        );
+
+        let mut disabled = self::reconnector(0, 0.5);
+        assert_eq!(disabled.on_lost(now), None);
+        assert_eq!(disabled.state, ConnectionState::Connected);
+    }
+}
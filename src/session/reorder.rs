@@ -0,0 +1,139 @@
+use std::collections::VecDeque;
+
+/// What became of a sequenced message offered to a `ReorderBuffer`.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Reordered<T> {
+    /// The messages now deliverable in sequence. Empty when the message was buffered to wait for
+    /// an earlier one.
+    Ready(Vec<T>),
+    /// The message's place in the sequence has already been delivered.
+    Late,
+    /// The message is too far ahead of the next expected one to be buffered.
+    OutOfWindow,
+}
+
+/// Holds messages that arrive ahead of the next expected sequence number until the gap before
+/// them is filled.
+///
+/// Sequence numbers wrap, so "ahead" and "behind" are judged by the wrapping distance from the
+/// next expected number, as in RFC 1982 serial number arithmetic.
+#[derive(Debug)]
+pub(crate) struct ReorderBuffer<T> {
+    next: u32,
+    window: usize,
+    // slots[i] holds the message with sequence number next + i, if it has arrived.
+    slots: VecDeque<Option<T>>,
+    buffered: usize,
+    max_buffered: usize,
+    dropped_late: u64,
+}
+
+impl<T> ReorderBuffer<T> {
+    pub(crate) fn new(window: usize) -> ReorderBuffer<T> {
+        ReorderBuffer {
+            next: 0,
+            window: window.max(1),
+            slots: VecDeque::new(),
+            buffered: 0,
+            max_buffered: 0,
+            dropped_late: 0,
+        }
+    }
+
+    pub(crate) fn push(&mut self, seq: u32, message: T) -> Reordered<T> {
+        let distance = seq.wrapping_sub(self.next);
+        if distance > u32::MAX / 2 {
+            self.dropped_late += 1;
+            return Reordered::Late;
+        }
+        let distance = distance as usize;
+        if distance >= self.window {
+            return Reordered::OutOfWindow;
+        }
+
+        if self.slots.len() <= distance {
+            self.slots.resize_with(distance + 1, || None);
+        }
+        if self.slots[distance].is_none() {
+            self.slots[distance] = Some(message);
+            self.buffered += 1;
+        }
+
+        let mut ready = Vec::new();
+        while let Some(Some(_)) = self.slots.front() {
+            ready.extend(self.slots.pop_front().flatten());
+            self.next = self.next.wrapping_add(1);
+            self.buffered -= 1;
+        }
+        self.max_buffered = self.max_buffered.max(self.buffered);
+        Reordered::Ready(ready)
+    }
+
+    /// Messages currently held waiting for an earlier one.
+    pub(crate) fn depth(&self) -> usize {
+        self.buffered
+    }
+
+    pub(crate) fn max_depth(&self) -> usize {
+        self.max_buffered
+    }
+
+    /// Messages that arrived after their place in the sequence had been delivered.
+    pub(crate) fn dropped_late(&self) -> u64 {
+        self.dropped_late
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ready(messages: &[&[u8]]) -> Reordered<Vec<u8>> {
+        Reordered::Ready(messages.iter().map(|m| m.to_vec()).collect())
+    }
+
+    #[test]
+    fn in_order_messages_pass_through() {
+        let mut buffer = ReorderBuffer::new(4);
+        assert_eq!(buffer.push(0, b"a".to_vec()), ready(&[b"a"]));
+        assert_eq!(buffer.push(1, b"b".to_vec()), ready(&[b"b"]));
+        assert_eq!(buffer.max_depth(), 0);
+    }
+
+    #[test]
+    fn gaps_are_filled_before_delivery() {
+        let mut buffer = ReorderBuffer::new(4);
+        assert_eq!(buffer.push(2, b"c".to_vec()), ready(&[]));
+        assert_eq!(buffer.push(1, b"b".to_vec()), ready(&[]));
+        assert_eq!(buffer.depth(), 2);
+        assert_eq!(buffer.push(0, b"a".to_vec()), ready(&[b"a", b"b", b"c"]));
+        assert_eq!(buffer.depth(), 0);
+        assert_eq!(buffer.max_depth(), 2);
+    }
+
+    #[test]
+    fn late_and_out_of_window_messages_are_dropped() {
+        let mut buffer = ReorderBuffer::new(4);
+        assert_eq!(buffer.push(0, b"a".to_vec()), ready(&[b"a"]));
+        assert_eq!(buffer.push(0, b"a".to_vec()), Reordered::Late);
+        assert_eq!(buffer.push(5, b"f".to_vec()), Reordered::OutOfWindow);
+        assert_eq!(buffer.dropped_late(), 1);
+    }
+
+    #[test]
+    fn duplicates_of_buffered_messages_are_ignored() {
+        let mut buffer = ReorderBuffer::new(4);
+        assert_eq!(buffer.push(1, b"b".to_vec()), ready(&[]));
+        assert_eq!(buffer.push(1, b"b".to_vec()), ready(&[]));
+        assert_eq!(buffer.push(0, b"a".to_vec()), ready(&[b"a", b"b"]));
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        let mut buffer = ReorderBuffer::new(4);
+        buffer.next = u32::MAX;
+        assert_eq!(buffer.push(0, b"b".to_vec()), ready(&[]));
+        assert_eq!(buffer.push(u32::MAX, b"a".to_vec()), ready(&[b"a", b"b"]));
+        assert_eq!(buffer.push(u32::MAX, b"a".to_vec()), Reordered::Late);
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::VecDeque;
+
+/// Detects sequence numbers that have been seen before, in the manner of the DTLS anti-replay
+/// window (RFC 6347, section 4.1.2.6).
+///
+/// The window remembers which of the `size` sequence numbers up to the highest one received
+/// have arrived. Anything older than the window cannot be told apart from a replay and is
+/// treated as one.
+#[derive(Debug)]
+pub(crate) struct ReplayWindow {
+    highest: Option<u32>,
+    // seen[i] records whether highest - i has arrived.
+    seen: VecDeque<bool>,
+    size: usize,
+    replayed: u64,
+}
+
+impl ReplayWindow {
+    pub(crate) fn new(size: usize) -> ReplayWindow {
+        let size = size.max(1);
+        ReplayWindow {
+            highest: None,
+            seen: VecDeque::from(vec![false; size]),
+            size,
+            replayed: 0,
+        }
+    }
+
+    /// Records `seq`, returning whether it is new.
+    pub(crate) fn check(&mut self, seq: u32) -> bool {
+        let Some(highest) = self.highest else {
+            self.advance(seq, self.size);
+            return true;
+        };
+
+        let ahead = seq.wrapping_sub(highest);
+        if ahead != 0 && ahead <= u32::MAX / 2 {
+            self.advance(seq, ahead as usize);
+            return true;
+        }
+        let behind = highest.wrapping_sub(seq) as usize;
+        match self.seen.get_mut(behind) {
+            Some(seen) if !*seen => {
+                *seen = true;
+                true
+            }
+            _ => {
+                self.replayed += 1;
+                false
+            }
+        }
+    }
+
+    /// Sequence numbers rejected as already seen or too old.
+    pub(crate) fn replayed(&self) -> u64 {
+        self.replayed
+    }
+
+    fn advance(&mut self, seq: u32, by: usize) {
+        self.seen.rotate_right(by.min(self.size));
+        for seen in self.seen.iter_mut().take(by) {
+            *seen = false;
+        }
+        self.seen[0] = true;
+        self.highest = Some(seq);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicates_are_rejected_in_any_order() {
+        let mut window = ReplayWindow::new(8);
+        assert!(window.check(3));
+        assert!(window.check(1));
+        assert!(window.check(5));
+        assert!(window.check(4));
+        assert!(!window.check(3));
+        assert!(!window.check(5));
+        assert!(window.check(2));
+        assert_eq!(window.replayed(), 2);
+    }
+
+    #[test]
+    fn numbers_older_than_the_window_are_rejected() {
+        let mut window = ReplayWindow::new(4);
+        assert!(window.check(10));
+        assert!(window.check(7));
+        assert!(!window.check(6));
+        assert!(window.check(100));
+        assert!(!window.check(10));
+        assert!(window.check(98));
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        let mut window = ReplayWindow::new(4);
+        assert!(window.check(u32::MAX - 1));
+        assert!(window.check(1));
+        assert!(window.check(u32::MAX));
+        assert!(!window.check(u32::MAX - 1));
+        assert!(window.check(0));
+        assert!(!window.check(1));
+    }
+}
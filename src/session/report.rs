@@ -0,0 +1,213 @@
+//! Receiver reports. With `report_interval` set, a session tells its peer at that interval how
+//! many of the peer's messages arrived since the last report, how many were lost and how
+//! irregularly they arrived. The peer shows the loss its receiver saw in `Stats`, and shrinks
+//! its congestion window when messages went missing.
+//!
+//! A report is an unreliable session message marked with the `REPORT` extension. Its payload is
+//! three big endian 32-bit counts: messages received, messages lost, and the arrival jitter in
+//! microseconds. Messages are told apart by their `msg_id`, which senders number one after
+//! another. Skipped ids count as lost, and messages arriving behind the highest id seen, such as
+//! retransmissions, are not counted again, so a reordered message counts as lost. Jitter is the
+//! variation between successive gaps between arrivals, smoothed as RFC 3550 smooths its
+//! interarrival jitter, so it includes any irregularity in the sender's own pace. Periods in
+//! which nothing arrived or went missing are not reported.
+
+use super::{PeerState, TARGET};
+use crate::protocol::{Frame, Priority};
+use std::time::{Duration, Instant};
+use tracing::trace;
+
+const REPORT_LEN: usize = 12;
+
+// Jumps in `msg_id` larger than this are taken for a restarted sender, which numbers its
+// messages from a new random start, rather than for lost messages.
+const MAX_GAP: u64 = 1 << 16;
+
+/// What a receiver saw of its peer's messages over one period.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Report {
+    pub(super) received: u32,
+    pub(super) lost: u32,
+    pub(super) jitter: Duration,
+}
+
+impl Report {
+    fn to_frame(self) -> Frame {
+        let jitter = u32::try_from(self.jitter.as_micros()).unwrap_or(u32::MAX);
+        let mut payload = Vec::with_capacity(REPORT_LEN);
+        payload.extend_from_slice(&self.received.to_be_bytes());
+        payload.extend_from_slice(&self.lost.to_be_bytes());
+        payload.extend_from_slice(&jitter.to_be_bytes());
+        Frame {
+            report: true,
+            priority: Priority::Control,
+            ..Frame::new(payload)
+        }
+    }
+
+    fn parse(payload: &[u8]) -> Option<Report> {
+        let payload: &[u8; REPORT_LEN] = payload.try_into().ok()?;
+        let field = |i: usize| u32::from_be_bytes(payload[i..i + 4].try_into().unwrap());
+        Some(Report {
+            received: field(0),
+            lost: field(4),
+            jitter: Duration::from_micros(u64::from(field(8))),
+        })
+    }
+}
+
+/// Counts the peer's messages as they arrive, for the next report.
+#[derive(Debug, Default)]
+pub(super) struct Arrivals {
+    highest: Option<u64>,
+    received: u32,
+    lost: u32,
+    last_arrival: Option<Instant>,
+    last_gap: Option<Duration>,
+    // In seconds.
+    jitter: f64,
+}
+
+impl Arrivals {
+    pub(super) fn on_message(&mut self, msg_id: u64, now: Instant) {
+        if let Some(highest) = self.highest {
+            let ahead = msg_id.wrapping_sub(highest);
+            if ahead == 0 || ahead > u64::MAX / 2 {
+                return;
+            }
+            if ahead <= MAX_GAP {
+                self.lost = self.lost.saturating_add(ahead as u32 - 1);
+            }
+        }
+        self.highest = Some(msg_id);
+        self.received = self.received.saturating_add(1);
+        if let Some(last) = self.last_arrival {
+            let gap = now.saturating_duration_since(last);
+            if let Some(last_gap) = self.last_gap {
+                let variation = (gap.as_secs_f64() - last_gap.as_secs_f64()).abs();
+                self.jitter += (variation - self.jitter) / 16.0;
+            }
+            self.last_gap = Some(gap);
+        }
+        self.last_arrival = Some(now);
+    }
+
+    // The counts since the last report, if there are any, starting them again.
+    fn take(&mut self) -> Option<Report> {
+        if self.received == 0 && self.lost == 0 {
+            return None;
+        }
+        let report = Report {
+            received: self.received,
+            lost: self.lost,
+            jitter: Duration::from_secs_f64(self.jitter),
+        };
+        self.received = 0;
+        self.lost = 0;
+        Some(report)
+    }
+}
+
+/// What the peer's reports said of this end's messages, over the whole session.
+#[derive(Debug, Default)]
+pub(super) struct Observed {
+    received: u64,
+    lost: u64,
+    jitter: Option<Duration>,
+}
+
+impl Observed {
+    /// The fraction of messages the peer reported lost, once it has reported.
+    pub(super) fn loss_rate(&self) -> Option<f64> {
+        let total = self.received + self.lost;
+        (total > 0).then(|| self.lost as f64 / total as f64)
+    }
+
+    /// The arrival jitter in the peer's latest report.
+    pub(super) fn jitter(&self) -> Option<Duration> {
+        self.jitter
+    }
+}
+
+impl PeerState {
+    /// The report due now, if reports are on, the interval has passed since the last, and the
+    /// peer has sent anything in it.
+    pub(super) fn report(&mut self, now: Instant) -> Option<Vec<u8>> {
+        if self.report_interval.is_zero()
+            || now.saturating_duration_since(self.last_report) < self.report_interval
+        {
+            return None;
+        }
+        self.last_report = now;
+        let report = self.arrivals.take()?;
+        Some(self.seal(report.to_frame()))
+    }
+
+    /// Takes in the peer's report on the messages it got from us, treating any it lost as
+    /// congestion.
+    pub(super) fn take_report(&mut self, payload: &[u8]) {
+        let Some(report) = Report::parse(payload) else {
+            trace!(target: TARGET, "dropping malformed report");
+            return;
+        };
+        trace!(target: TARGET, received = report.received, lost = report.lost, "report received");
+        self.observed.received += u64::from(report.received);
+        self.observed.lost += u64::from(report.lost);
+        self.observed.jitter = Some(report.jitter);
+        if report.lost > 0 {
+            self.congestion.on_loss();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::config::Config;
+
+    #[test]
+    fn gaps_in_message_ids_count_as_lost() {
+        let mut arrivals = Arrivals::default();
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        for (msg_id, at) in [(u64::MAX, 0), (0, 10), (3, 20), (2, 25), (3, 30), (4, 50)] {
+            arrivals.on_message(msg_id, start + ms(at));
+        }
+        let report = arrivals.take().unwrap();
+        assert_eq!((report.received, report.lost), (4, 2));
+        // Gaps of 10, 10 and 30 ms.
+        assert!((report.jitter.as_secs_f64() - 0.020 / 16.0).abs() < 1e-6);
+        assert!(arrivals.take().is_none());
+
+        arrivals.on_message(4 + MAX_GAP * 2, start + ms(60));
+        assert_eq!(arrivals.take().unwrap().lost, 0);
+    }
+
+    #[test]
+    fn senders_learn_what_their_peer_lost() {
+        let interval = Duration::from_secs(1);
+        let conf = Config {
+            report_interval: interval,
+            ..Default::default()
+        };
+        let mut sender = PeerState::new(&Config::default());
+        let mut receiver = PeerState::new(&conf);
+        let start = receiver.last_report;
+        assert!(receiver.report(start + interval).is_none());
+
+        for i in 0..4 {
+            let packet = sender.outgoing(Frame::new(vec![i]));
+            if i != 1 {
+                receiver.incoming(&packet);
+            }
+        }
+        assert!(receiver.report(start + interval * 3 / 2).is_none());
+        let report = receiver.report(start + interval * 2).unwrap();
+        assert_eq!(sender.stats().remote_loss_rate, None);
+        let window = sender.metrics().send_window;
+        sender.incoming(&report);
+        assert_eq!(sender.stats().remote_loss_rate, Some(0.25));
+        assert!(sender.stats().remote_jitter.is_some());
+        assert!(sender.metrics().send_window < window);
+    }
+}
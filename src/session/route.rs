@@ -0,0 +1,125 @@
+//! Message types. A client can mark a message with a type of the application's choosing, in
+//! the frame's `msg_type` field, and a server can hand every message of a type to a handler of
+//! its own, so that telemetry, commands and whatever else an application sends can share one
+//! port and one session without the application telling them apart itself:
+//!
+//! ```no_run
+//! # use crumb::session::{Client, Server};
+//! # use crumb::util::config::Config;
+//! const MSG_TELEMETRY: u16 = 1;
+//!
+//! # fn main() -> std::io::Result<()> {
+//! # let conf = Config::default();
+//! let server = Server::init(&conf)?;
+//! server.route(MSG_TELEMETRY, |session, message| {
+//!     println!("{} bytes of telemetry from {}", message.payload.len(), session);
+//! });
+//!
+//! let client = Client::init(&conf)?;
+//! client.send_typed(MSG_TELEMETRY, b"temperature=21.5")?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Messages of types without a handler, untyped ones included, are queued for `receive_from`
+//! and the like as before.
+
+use super::{Client, Message, SendQueue, Server, SessionId, TARGET};
+use crate::protocol::{Frame, Priority};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::RwLock;
+use tracing::trace;
+
+type Handler = Box<dyn Fn(SessionId, Message) + Send + Sync>;
+
+/// The handlers registered with a server, by message type.
+#[derive(Default)]
+pub(super) struct Routes(RwLock<HashMap<u16, Handler>>);
+
+impl Routes {
+    /// Hands `message` to the handler for its type, or back if there is none.
+    pub(super) fn dispatch(&self, source: SocketAddr, message: Frame) -> Option<Frame> {
+        let routes = self.0.read().unwrap_or_else(|e| e.into_inner());
+        let Some(handler) = routes.get(&message.msg_type) else {
+            return Some(message);
+        };
+        trace!(target: TARGET, %source, msg_type = message.msg_type, "message routed");
+        handler(source.into(), message.into());
+        None
+    }
+}
+
+impl Server {
+    /// Registers `handler` to be called with every message of type `msg_type`, in place of
+    /// queueing it for `receive_from` and the like, replacing any earlier handler for the type.
+    /// It runs on the server's worker thread after the interceptors, so it should not block;
+    /// one with slow work to do can pass the message to a thread of its own.
+    pub fn route<F>(&self, msg_type: u16, handler: F)
+    where
+        F: Fn(SessionId, Message) + Send + Sync + 'static,
+    {
+        self.shared
+            .routes
+            .0
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(msg_type, Box::new(handler));
+    }
+}
+
+impl Client {
+    /// Like `send`, but marks the message as being of type `msg_type`, so that the server can
+    /// hand it to the handler it registered for the type with `Server::route`.
+    pub fn send_typed(&self, msg_type: u16, data: &[u8]) -> io::Result<usize> {
+        let _enter = self.shared.span.enter();
+        let frame =
+            self.shared
+                .typed_frame(self.shared.format, Priority::Normal, msg_type, data)?;
+        self.queue(frame, None, SendQueue::must_wait)?;
+        Ok(data.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::config::Config;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn typed_messages_reach_their_handlers() -> io::Result<()> {
+        let conf = Config {
+            host: "127.0.0.1".to_string(),
+            port: 8150,
+            ordered: true,
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        server.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let (telemetry, received) = mpsc::channel();
+        server.route(1, move |_, message| {
+            let _ = telemetry.send(message);
+        });
+        let client = Client::init(&conf)?;
+
+        client.send_typed(1, b"reading")?;
+        client.send_typed(2, b"command")?;
+        client.send(b"untyped")?;
+        let message = received.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            (message.msg_type, &message.payload[..]),
+            (1, &b"reading"[..])
+        );
+        for (msg_type, payload) in [(2, &b"command"[..]), (0, b"untyped")] {
+            let (_, message) = server.receive_any()?;
+            assert_eq!(
+                (message.msg_type, &message.payload[..]),
+                (msg_type, payload)
+            );
+        }
+        Ok(())
+    }
+}
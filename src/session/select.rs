@@ -0,0 +1,271 @@
+//! Receiving from every session a server has at once, so a consumer needs neither a thread per
+//! client nor a buffer sized in advance: `Server::receive_any` and `try_receive_any` for
+//! synchronous callers, and, with the `tokio` feature, `Server::messages` as a `Stream`.
+
+use super::{lock, next_message, Server};
+use crate::protocol::{Frame, Priority, TraceContext};
+use crate::util::config::PayloadFormat;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::option;
+#[cfg(feature = "tokio")]
+use std::pin::Pin;
+use std::sync::mpsc;
+#[cfg(feature = "tokio")]
+use std::task::{Context, Poll};
+
+/// Names the session a server has with one client. Clients are told apart by address, so this
+/// is the client's address, and it can be passed straight to `Server::send_to` to answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SessionId(SocketAddr);
+
+impl SessionId {
+    pub fn addr(&self) -> SocketAddr {
+        self.0
+    }
+}
+
+impl From<SocketAddr> for SessionId {
+    fn from(addr: SocketAddr) -> SessionId {
+        SessionId(addr)
+    }
+}
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl ToSocketAddrs for SessionId {
+    type Iter = option::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        Ok(Some(self.0).into_iter())
+    }
+}
+
+/// A message received in a session, decompressed, with what its sender declared about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub payload: Vec<u8>,
+    pub format: PayloadFormat,
+    pub priority: Priority,
+    /// The schema version stamped on protobuf messages, 0 for others.
+    pub schema: u32,
+    /// The distributed trace the sender sent the message in, if it said.
+    pub trace: Option<TraceContext>,
+    /// The kind of message the sender said it is, 0 if it gave none.
+    pub msg_type: u16,
+}
+
+impl From<Frame> for Message {
+    fn from(frame: Frame) -> Message {
+        Message {
+            payload: frame.payload,
+            format: frame.format,
+            priority: frame.priority,
+            schema: frame.schema,
+            trace: frame.trace,
+            msg_type: frame.msg_type,
+        }
+    }
+}
+
+impl Server {
+    /// Waits for the next message from any client, as `receive_from` does, but returns it whole
+    /// with the session it arrived on.
+    pub fn receive_any(&self) -> io::Result<(SessionId, Message)> {
+        let timeout = *lock(&self.read_timeout);
+        let (frame, source) = next_message(&lock(&self.inbox), timeout)?;
+        self.shared.took_message(source);
+        Ok((source.into(), frame.into()))
+    }
+
+    /// Returns the next message from any client if one has arrived, without waiting. Fails with
+    /// `ErrorKind::NotConnected` once the server has shut down.
+    pub fn try_receive_any(&self) -> io::Result<Option<(SessionId, Message)>> {
+        match lock(&self.inbox).try_recv() {
+            Ok((frame, source)) => {
+                self.shared.took_message(source);
+                Ok(Some((source.into(), frame.into())))
+            }
+            Err(mpsc::TryRecvError::Empty) => Ok(None),
+            Err(mpsc::TryRecvError::Disconnected) => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Session closed",
+            )),
+        }
+    }
+}
+
+/// The messages a server receives from all its clients, as a `Stream` of the session each
+/// arrived on and the message. It ends when the server shuts down.
+///
+/// The stream does not need a particular runtime: the server's worker thread wakes the task
+/// polling it. Only the most recently polled of several streams on one server is woken.
+#[cfg(feature = "tokio")]
+pub struct Messages<'a> {
+    server: &'a Server,
+}
+
+#[cfg(feature = "tokio")]
+impl Server {
+    /// Returns a stream of the messages from every client, for async consumers. Messages taken
+    /// by `receive_from` or `receive_any` meanwhile are not yielded again.
+    pub fn messages(&self) -> Messages<'_> {
+        Messages { server: self }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl futures_core::Stream for Messages<'_> {
+    type Item = (SessionId, Message);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let inbox = lock(&self.server.inbox);
+        let mut registered = false;
+        loop {
+            match inbox.try_recv() {
+                Ok((frame, source)) => {
+                    self.server.shared.took_message(source);
+                    return Poll::Ready(Some((source.into(), frame.into())));
+                }
+                Err(mpsc::TryRecvError::Disconnected) => return Poll::Ready(None),
+                // Checked again once the waker is in place, in case the worker sent a message
+                // just before.
+                Err(mpsc::TryRecvError::Empty) if !registered => {
+                    *lock(&self.server.shared.waker) = Some(cx.waker().clone());
+                    registered = true;
+                }
+                Err(mpsc::TryRecvError::Empty) => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Client;
+    use crate::util::config::Config;
+    use std::collections::HashSet;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn conf(port: u16) -> Config {
+        Config {
+            host: "127.0.0.1".to_string(),
+            port,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn messages_from_every_session_are_polled_in_one_place() -> io::Result<()> {
+        let conf = conf(8128);
+        let server = Server::init(&conf)?;
+        let clients = [Client::init(&conf)?, Client::init(&conf)?];
+        assert!(server.try_receive_any()?.is_none());
+        for (i, client) in clients.iter().enumerate() {
+            client.send(format!("from {}", i).as_bytes())?;
+        }
+
+        let mut sessions = HashSet::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while sessions.len() < clients.len() && Instant::now() < deadline {
+            match server.try_receive_any()? {
+                Some((session, message)) => {
+                    assert!(message.payload.starts_with(b"from "));
+                    assert_eq!(message.format, conf.payload_format);
+                    sessions.insert(session);
+                }
+                None => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        let expected: HashSet<_> = clients
+            .iter()
+            .map(|client| client.local_addr().map(SessionId::from))
+            .collect::<io::Result<_>>()?;
+        assert_eq!(sessions, expected);
+
+        let session = *sessions.iter().next().unwrap();
+        server.send_to(b"answer", session)?;
+        clients[0].send(b"blocking")?;
+        server.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let (session, message) = server.receive_any()?;
+        assert_eq!(session.addr(), clients[0].local_addr()?);
+        assert_eq!(message.payload, b"blocking");
+
+        server.shutdown_handle()?.shutdown();
+        let closed = (0..100).find_map(|_| match server.try_receive_any() {
+            Err(e) => Some(e),
+            Ok(_) => {
+                thread::sleep(Duration::from_millis(10));
+                None
+            }
+        });
+        assert_eq!(closed.map(|e| e.kind()), Some(io::ErrorKind::NotConnected));
+        Ok(())
+    }
+
+    #[test]
+    fn trace_context_travels_with_the_message() -> io::Result<()> {
+        let conf = conf(8135);
+        let server = Server::init(&conf)?;
+        let client = Client::init(&conf)?;
+        let trace: TraceContext = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+            .parse()
+            .unwrap();
+        client.send(b"untraced")?;
+        client.send_traced(b"traced", &trace)?;
+
+        server.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut traces = HashSet::new();
+        for _ in 0..2 {
+            let (_, message) = server.receive_any()?;
+            traces.insert((message.payload, message.trace));
+        }
+        assert!(traces.contains(&(b"untraced".to_vec(), None)));
+        assert!(traces.contains(&(b"traced".to_vec(), Some(trace))));
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn messages_stream_until_shutdown() -> io::Result<()> {
+        use futures_core::Stream;
+
+        let conf = conf(8129);
+        let server = Server::init(&conf)?;
+        let handle = server.shutdown_handle()?;
+        let client = Client::init(&conf)?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+
+        let mut messages = server.messages();
+        let mut next = || {
+            runtime.block_on(std::future::poll_fn(|cx| {
+                Pin::new(&mut messages).poll_next(cx)
+            }))
+        };
+        client.send(b"streamed")?;
+        let (session, message) = next().unwrap();
+        assert_eq!(session.addr(), client.local_addr()?);
+        assert_eq!(message.payload, b"streamed");
+
+        // Sent once the stream has found the inbox empty, so only the waker can deliver it.
+        let sender = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            client.send(b"later")
+        });
+        assert_eq!(next().unwrap().1.payload, b"later");
+        sender.join().unwrap()?;
+
+        handle.shutdown();
+        assert!(next().is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+//! The disk-backed spool a client keeps its unacknowledged reliable messages in, so that a
+//! client started again after a crash or restart sends what its predecessor could not.
+//!
+//! Each message is a file in `spool_dir` holding the frame as queued, named after a counter in
+//! hex with a `.msg` extension, and is removed once the server acknowledges it. Files are
+//! written under a temporary name and renamed into place once on disk, so a crash mid-write
+//! leaves no half written message behind.
+
+use crate::protocol::Frame;
+use crate::util::config::Config;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+const TARGET: &str = "crumb::session::spool";
+
+const EXTENSION: &str = "msg";
+
+/// Spooled messages with their ids, oldest first.
+pub(super) type Spooled = Vec<(u64, Frame)>;
+
+/// The messages spooled in one directory, with how many bytes they take.
+pub(super) struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    used: u64,
+    sizes: HashMap<u64, u64>,
+    next_id: u64,
+}
+
+impl Spool {
+    /// Opens the spool in `spool_dir`, creating the directory if need be, and returns it with
+    /// the messages left in it, oldest first. `None` when no directory is configured.
+    ///
+    /// Files that do not hold a frame this version understands are logged and removed.
+    pub(super) fn open(conf: &Config) -> io::Result<Option<(Spool, Spooled)>> {
+        if conf.spool_dir.is_empty() {
+            return Ok(None);
+        }
+        let dir = PathBuf::from(&conf.spool_dir);
+        fs::create_dir_all(&dir)?;
+        let mut spool = Spool {
+            dir,
+            max_bytes: conf.spool_max_bytes,
+            used: 0,
+            sizes: HashMap::new(),
+            next_id: 0,
+        };
+
+        let mut spooled = Vec::new();
+        for entry in fs::read_dir(&spool.dir)? {
+            let path = entry?.path();
+            let Some(id) = message_id(&path) else {
+                // Left over from a write cut short.
+                if path.extension().is_some_and(|extension| extension == "tmp") {
+                    let _ = fs::remove_file(&path);
+                }
+                continue;
+            };
+            let bytes = fs::read(&path)?;
+            match Frame::from_bytes(&bytes) {
+                Ok(frame) => {
+                    spool.used += bytes.len() as u64;
+                    spool.sizes.insert(id, bytes.len() as u64);
+                    spool.next_id = spool.next_id.max(id + 1);
+                    spooled.push((id, frame));
+                }
+                Err(e) => {
+                    warn!(target: TARGET, path = %path.display(), error = %e, "dropping unreadable spooled message");
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+        spooled.sort_by_key(|(id, _)| *id);
+        debug!(target: TARGET, dir = %spool.dir.display(), messages = spooled.len(), bytes = spool.used, "spool opened");
+        Ok(Some((spool, spooled)))
+    }
+
+    /// Writes `frame` to disk, returning the id to remove it by. Fails with
+    /// `ErrorKind::StorageFull` when it would take the spool past `spool_max_bytes`.
+    pub(super) fn write(&mut self, frame: &Frame) -> io::Result<u64> {
+        let bytes = frame.to_bytes();
+        let size = bytes.len() as u64;
+        if self.used + size > self.max_bytes {
+            return Err(io::Error::new(io::ErrorKind::StorageFull, "Spool is full"));
+        }
+
+        let id = self.next_id;
+        let path = self.path(id);
+        let temporary = path.with_extension("tmp");
+        let mut file = File::create(&temporary)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&temporary, &path)?;
+
+        self.next_id += 1;
+        self.used += size;
+        self.sizes.insert(id, size);
+        Ok(id)
+    }
+
+    /// Removes the message spooled as `id`, once it needs keeping no longer.
+    pub(super) fn remove(&mut self, id: u64) {
+        let Some(size) = self.sizes.remove(&id) else {
+            return;
+        };
+        self.used -= size;
+        if let Err(e) = fs::remove_file(self.path(id)) {
+            debug!(target: TARGET, id, error = %e, "removing spooled message failed");
+        }
+    }
+
+    fn path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}.{}", id, EXTENSION))
+    }
+}
+
+// The id a spooled message file is named after.
+fn message_id(path: &Path) -> Option<u64> {
+    if path.extension()? != EXTENSION {
+        return None;
+    }
+    u64::from_str_radix(path.file_stem()?.to_str()?, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn conf(name: &str, max_bytes: u64) -> Config {
+        let dir = std::env::temp_dir().join(format!("crumb-spool-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        Config {
+            spool_dir: dir.to_str().unwrap().to_string(),
+            spool_max_bytes: max_bytes,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn spooled_messages_outlive_the_spool() -> io::Result<()> {
+        let conf = conf("reopen", 1 << 20);
+        assert!(Spool::open(&Config::default())?.is_none());
+        let (mut spool, spooled) = Spool::open(&conf)?.unwrap();
+        assert!(spooled.is_empty());
+
+        let frames: Vec<_> = (0..3u8).map(|i| Frame::new(vec![i; 10])).collect();
+        let ids = frames
+            .iter()
+            .map(|frame| spool.write(frame))
+            .collect::<io::Result<Vec<_>>>()?;
+        spool.remove(ids[1]);
+        fs::write(
+            Path::new(&conf.spool_dir).join("0000000000000009.msg"),
+            b"torn",
+        )?;
+        fs::write(
+            Path::new(&conf.spool_dir).join("000000000000000a.tmp"),
+            b"cut",
+        )?;
+        drop(spool);
+
+        let (mut spool, spooled) = Spool::open(&conf)?.unwrap();
+        assert_eq!(
+            spooled,
+            vec![(ids[0], frames[0].clone()), (ids[2], frames[2].clone())]
+        );
+        assert_eq!(spool.write(&frames[1])?, ids[2] + 1);
+        assert_eq!(fs::read_dir(&conf.spool_dir)?.count(), 3);
+        fs::remove_dir_all(&conf.spool_dir)
+    }
+
+    #[test]
+    fn the_size_cap_refuses_more() -> io::Result<()> {
+        let frame = Frame::new(vec![0; 100]);
+        let size = frame.to_bytes().len() as u64;
+        let conf = conf("cap", 2 * size);
+        let (mut spool, _) = Spool::open(&conf)?.unwrap();
+        let first = spool.write(&frame)?;
+        spool.write(&frame)?;
+        let e = spool.write(&frame).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::StorageFull);
+
+        spool.remove(first);
+        spool.write(&frame)?;
+        fs::remove_dir_all(&conf.spool_dir)
+    }
+}
@@ -0,0 +1,397 @@
+//! Sending and receiving blobs of any size as streams of chunks, laid out as `crate::stream`
+//! describes.
+
+use super::{frame, lock, Client, ClientShared, SendQueue, Server, ServerShared, SessionId};
+use super::{PING_TIMEOUT, TARGET};
+use crate::error::Error;
+use crate::protocol::{Frame, Priority};
+use crate::stream::{Chunk, IncomingStream, CHUNK_HEADER_LEN};
+use crate::util::config::Config;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use tracing::{debug, trace};
+
+/// Finds how much of a transfer, by id, the server already holds.
+pub(super) type ResumeHook = Box<dyn Fn(u64) -> u64 + Send + Sync>;
+
+impl Client {
+    /// Sends everything `reader` yields to the server as one stream, which the server takes
+    /// with `Server::receive_stream`, and returns its length once the last chunk is queued.
+    ///
+    /// The blob is cut into chunks sized to fill a datagram, sent as reliable messages in the
+    /// bulk lane. Each waits for room in the send queue whatever its policy, so the stream goes
+    /// out no faster than the congestion window and rate limit allow, and a slow reader is
+    /// never outrun by more than the queue holds. Fails with `Error::Config` when the session's
+    /// `delivery` is at-most-once.
+    ///
+    /// Transfers sent this way are numbered down from `u64::MAX`, clear of the ids applications
+    /// give `send_resumable`.
+    pub fn send_stream<R: Read>(&self, reader: R) -> io::Result<u64> {
+        let _enter = self.shared.span.enter();
+        check_reliable(&self.shared)?;
+        let transfer = u64::MAX - self.shared.next_transfer.fetch_add(1, Ordering::Relaxed);
+        self.send_chunks(transfer, 0, reader)
+    }
+
+    /// Sends the blob `reader` holds as transfer `transfer`, resuming where an earlier attempt
+    /// was cut short, and returns the offset it resumed from.
+    ///
+    /// The server is first asked how much of the transfer it holds, which its
+    /// `Server::on_resume` hook says, and `reader` is sought there before the rest is sent as
+    /// `send_stream` does. The first attempt at a transfer is made the same way, and starts at 0.
+    /// Waits for the answer for at most the read timeout, or 5 seconds without one, then fails
+    /// with `ErrorKind::TimedOut`.
+    pub fn send_resumable<R: Read + Seek>(&self, transfer: u64, mut reader: R) -> io::Result<u64> {
+        let _enter = self.shared.span.enter();
+        check_reliable(&self.shared)?;
+        lock(&self.shared.resumes).remove(&transfer);
+        self.send_chunk(Chunk::Resume { transfer }, Priority::Control)?;
+
+        // The worker notifies after handling every packet, resume answers included.
+        let timeout = lock(&self.read_timeout).unwrap_or(PING_TIMEOUT);
+        let state = lock(&self.shared.state);
+        let (state, _) = self
+            .shared
+            .queue_space
+            .wait_timeout_while(state, timeout, |_| {
+                !lock(&self.shared.resumes).contains_key(&transfer)
+            })
+            .unwrap_or_else(|e| e.into_inner());
+        drop(state);
+        let offset = lock(&self.shared.resumes)
+            .remove(&transfer)
+            .ok_or_else(|| {
+                io::Error::new(ErrorKind::TimedOut, "No answer to the resume request")
+            })?;
+
+        debug!(target: TARGET, transfer, offset, "resuming stream");
+        reader.seek(SeekFrom::Start(offset))?;
+        self.send_chunks(transfer, offset, reader)?;
+        Ok(offset)
+    }
+
+    // Sends what `reader` yields as the blob of `transfer` from `offset` on, then its end.
+    fn send_chunks<R: Read>(
+        &self,
+        transfer: u64,
+        mut offset: u64,
+        mut reader: R,
+    ) -> io::Result<u64> {
+        let chunk_len = lock(&self.shared.state)
+            .max_payload()
+            .saturating_sub(CHUNK_HEADER_LEN)
+            .max(1);
+        debug!(target: TARGET, transfer, offset, chunk_len, "sending stream");
+
+        let mut buffer = vec![0; chunk_len];
+        let mut checksum = crc32fast::Hasher::new();
+        loop {
+            let read = read_up_to(&mut reader, &mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            let data = buffer[..read].to_vec();
+            checksum.update(&data);
+            let chunk = Chunk::Data {
+                transfer,
+                offset,
+                data,
+            };
+            self.send_chunk(chunk, Priority::Bulk)?;
+            offset += read as u64;
+        }
+        let end = Chunk::End {
+            transfer,
+            len: offset,
+            checksum: checksum.finalize(),
+        };
+        self.send_chunk(end, Priority::Bulk)?;
+        debug!(target: TARGET, transfer, len = offset, "stream queued");
+        Ok(offset)
+    }
+
+    fn send_chunk(&self, chunk: Chunk, priority: Priority) -> io::Result<()> {
+        let frame = chunk_frame(&self.shared.conf, priority, &chunk)?;
+        self.queue(frame, None, SendQueue::is_full)
+    }
+}
+
+impl ClientShared {
+    /// Takes in a chunk the server sent, which can only be a resume answer.
+    pub(super) fn stream_chunk(&self, payload: &[u8]) {
+        match Chunk::from_bytes(payload) {
+            Ok(Chunk::ResumeAt { transfer, offset }) => {
+                lock(&self.resumes).insert(transfer, offset);
+            }
+            Ok(chunk) => {
+                trace!(target: TARGET, transfer = chunk.transfer(), "dropping stream chunk")
+            }
+            Err(e) => debug!(target: TARGET, error = %e, "dropping stream chunk"),
+        }
+    }
+}
+
+impl Server {
+    /// Waits for the next stream a client starts with `Client::send_stream` or
+    /// `Client::send_resumable`, returning the session it came from and the stream to read the
+    /// blob from. Streams are handed over as they start, in that order, and read with this
+    /// server's read timeout at the time.
+    pub fn receive_stream(&self) -> io::Result<(SessionId, IncomingStream)> {
+        let timeout = *lock(&self.read_timeout);
+        let (source, mut stream) = super::next_message(&lock(&self.streams), timeout)?;
+        stream.set_read_timeout(timeout);
+        Ok((source.into(), stream))
+    }
+
+    /// Registers `hook` to say how much of a resumable transfer, by id, the application has
+    /// already stored, replacing any earlier one. It should count only bytes from the start of
+    /// the blob with none missing. The transfer's stream then starts at that offset. Without a
+    /// hook every transfer starts from the beginning.
+    pub fn on_resume<F>(&self, hook: F)
+    where
+        F: Fn(u64) -> u64 + Send + Sync + 'static,
+    {
+        *self
+            .shared
+            .resume
+            .write()
+            .unwrap_or_else(|e| e.into_inner()) = Some(Box::new(hook));
+    }
+}
+
+impl ServerShared {
+    fn resume_offset(&self, transfer: u64) -> u64 {
+        let hook = self.resume.read().unwrap_or_else(|e| e.into_inner());
+        hook.as_ref().map_or(0, |hook| hook(transfer))
+    }
+
+    /// Takes in a stream chunk from `source`, answering resume requests.
+    pub(super) fn stream_chunk(&self, streams: &mut Streams, source: SocketAddr, payload: &[u8]) {
+        let Some(answer) = streams.route(source, payload, |transfer| self.resume_offset(transfer))
+        else {
+            return;
+        };
+        let frame = Frame {
+            flags: Frame::STREAM,
+            priority: Priority::Control,
+            ..Frame::new(answer.to_bytes())
+        };
+        let mut peers = lock(&self.peers);
+        let Some(peer) = peers.get_mut(&source) else {
+            return;
+        };
+        if let Err(e) = peer.enqueue(frame) {
+            debug!(target: TARGET, %source, error = %e, "resume answer failed");
+        }
+        self.flush(source, peer);
+    }
+}
+
+/// The streams the server's worker is receiving, routing each chunk to the reader of its
+/// stream and announcing new streams.
+pub(super) struct Streams {
+    open: HashMap<(SocketAddr, u64), Open>,
+    announce: mpsc::Sender<(SocketAddr, IncomingStream)>,
+}
+
+struct Open {
+    chunks: mpsc::Sender<Chunk>,
+    // Data bytes passed on, against the end chunk's length less `start` once it is known.
+    received: u64,
+    start: u64,
+    len: Option<u64>,
+}
+
+impl Streams {
+    pub(super) fn new(announce: mpsc::Sender<(SocketAddr, IncomingStream)>) -> Streams {
+        Streams {
+            open: HashMap::new(),
+            announce,
+        }
+    }
+
+    /// Passes the chunk in `payload` from `source` on, closing its stream once every byte and
+    /// the end have been through, and returns the answer to a resume request. A request starts
+    /// the transfer afresh at the offset `resume_offset` gives, abandoning any earlier attempt
+    /// from the same source. The reliable layer has already dropped duplicates.
+    pub(super) fn route<F>(
+        &mut self,
+        source: SocketAddr,
+        payload: &[u8],
+        resume_offset: F,
+    ) -> Option<Chunk>
+    where
+        F: FnOnce(u64) -> u64,
+    {
+        let chunk = match Chunk::from_bytes(payload) {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                debug!(target: TARGET, %source, error = %e, "dropping stream chunk");
+                return None;
+            }
+        };
+        let transfer = chunk.transfer();
+        let key = (source, transfer);
+        if let Chunk::Resume { .. } = chunk {
+            let offset = resume_offset(transfer);
+            trace!(target: TARGET, %source, transfer, offset, "stream resumed");
+            self.open.remove(&key);
+            self.start(key, offset);
+            return Some(Chunk::ResumeAt { transfer, offset });
+        }
+
+        if !self.open.contains_key(&key) {
+            self.start(key, 0);
+        }
+        let open = self.open.get_mut(&key).unwrap();
+        match &chunk {
+            Chunk::Data { data, .. } => open.received += data.len() as u64,
+            Chunk::End { len, .. } => open.len = Some(*len),
+            Chunk::Resume { .. } | Chunk::ResumeAt { .. } => return None,
+        }
+        let done = open
+            .len
+            .is_some_and(|len| open.received >= len.saturating_sub(open.start));
+        // A reader that has gone away just stops being fed.
+        let _ = open.chunks.send(chunk);
+        if done {
+            trace!(target: TARGET, %source, transfer, "stream received");
+            self.open.remove(&key);
+        }
+        None
+    }
+
+    fn start(&mut self, key: (SocketAddr, u64), start: u64) {
+        trace!(target: TARGET, source = %key.0, transfer = key.1, "stream started");
+        let (chunks, receiver) = mpsc::channel();
+        let _ = self
+            .announce
+            .send((key.0, IncomingStream::new(receiver, key.1, start)));
+        let open = Open {
+            chunks,
+            received: 0,
+            start,
+            len: None,
+        };
+        self.open.insert(key, open);
+    }
+}
+
+fn check_reliable(shared: &ClientShared) -> io::Result<()> {
+    if !shared.conf.delivery.is_reliable() {
+        return Err(Error::config("Streams need a reliable session").into());
+    }
+    Ok(())
+}
+
+// The session message carrying `chunk`.
+fn chunk_frame(conf: &Config, priority: Priority, chunk: &Chunk) -> io::Result<Frame> {
+    let chunk = Frame {
+        priority,
+        ..Frame::new(chunk.to_bytes())
+    };
+    let mut frame = frame(conf, None, chunk)?;
+    frame.flags |= Frame::STREAM;
+    Ok(frame)
+}
+
+// Fills as much of `buffer` as `reader` yields before reaching its end.
+fn read_up_to<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::config::{Config, DeliveryMode};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn blobs_cross_as_streams() -> io::Result<()> {
+        let conf = Config {
+            host: "127.0.0.1".to_string(),
+            port: 8131,
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        server.set_read_timeout(Some(Duration::from_secs(10)))?;
+        let client = Client::init(&conf)?;
+        let local = client.local_addr()?;
+        let blob: Vec<u8> = (0..1_000_000u32)
+            .map(|i| (i * 7 + i / 1000) as u8)
+            .collect();
+
+        let sender = {
+            let blob = blob.clone();
+            thread::spawn(move || {
+                let sent = client.send_stream(&blob[..]);
+                client.close();
+                sent
+            })
+        };
+        let (session, mut stream) = server.receive_stream()?;
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received)?;
+        assert_eq!(sender.join().unwrap()?, blob.len() as u64);
+        assert_eq!(session.addr(), local);
+        assert_eq!(stream.total_len(), Some(blob.len() as u64));
+        assert!(received == blob);
+
+        let unreliable = Client::init(&Config {
+            delivery: DeliveryMode::AtMostOnce,
+            ..conf
+        })?;
+        let e = unreliable.send_stream(&b"lost"[..]).unwrap_err();
+        assert!(matches!(Error::from(e), Error::Config(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn transfers_resume_from_what_the_server_holds() -> io::Result<()> {
+        let conf = Config {
+            host: "127.0.0.1".to_string(),
+            port: 8132,
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        server.set_read_timeout(Some(Duration::from_secs(10)))?;
+        server.on_resume(|transfer| if transfer == 42 { 300_000 } else { 0 });
+        let client = Client::init(&conf)?;
+        let blob: Vec<u8> = (0..500_000u32).map(|i| (i % 251) as u8).collect();
+
+        let sender = {
+            let blob = blob.clone();
+            thread::spawn(move || {
+                let resumed = client.send_resumable(42, io::Cursor::new(&blob))?;
+                let fresh = client.send_resumable(43, io::Cursor::new(&blob[..10]))?;
+                Ok::<_, io::Error>((resumed, fresh))
+            })
+        };
+        let (_, mut stream) = server.receive_stream()?;
+        assert_eq!((stream.transfer(), stream.start()), (42, 300_000));
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest)?;
+        assert!(rest == blob[300_000..]);
+        assert_eq!(stream.total_len(), Some(blob.len() as u64));
+
+        let (_, mut stream) = server.receive_stream()?;
+        let mut all = Vec::new();
+        stream.read_to_end(&mut all)?;
+        assert_eq!((stream.transfer(), all.as_slice()), (43, &blob[..10]));
+        assert_eq!(sender.join().unwrap()?, (300_000, 0));
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+//! Protocol version negotiation. A session writes its frames in the newest layout both ends
+//! speak, so that a fleet can be upgraded a node at a time. Every frame names its version, and a
+//! session that hears from a peer writing an older one than it does drops to that version,
+//! leaving out what the older layout has no room for, such as `msg_type`. Until it has heard
+//! from the peer it writes the newest.
+//!
+//! A peer drops frames of versions it does not speak without answering, so a client opens the
+//! exchange by pinging the server once in each version it speaks, newest first, when it starts
+//! and whenever it reconnects. A server as new as the client answers in the newest, and an older
+//! one answers only the ping it can read, in its own version. Reliable messages already sent in
+//! a version the peer turns out not to speak are rewritten in the older one before they are
+//! sent again.
+
+use super::{clock, lock, Client, ClientShared, PeerState, Server, TARGET};
+use crate::protocol::{Frame, MIN_VERSION, VERSION};
+use std::net::SocketAddr;
+use tracing::debug;
+
+impl PeerState {
+    /// Pings the peer in every version this build speaks, newest first, forgetting the version
+    /// it was heard in before. The pongs are not timed.
+    pub(super) fn version_probes(&mut self) -> Vec<Vec<u8>> {
+        self.version = VERSION;
+        self.peer_version = None;
+        (MIN_VERSION..=VERSION)
+            .rev()
+            .map(|version| {
+                let id = self.next_ping;
+                self.next_ping = id.wrapping_add(1);
+                self.seal(Frame {
+                    version,
+                    ..clock::ping(id)
+                })
+            })
+            .collect()
+    }
+
+    /// Takes note of an authentic frame of `version` from the peer, writing to it in the newest
+    /// version it has been heard in from then on.
+    pub(super) fn negotiate(&mut self, version: u8) {
+        if self.peer_version.is_some_and(|newest| newest >= version) {
+            return;
+        }
+        self.peer_version = Some(version);
+        let negotiated = version.min(VERSION);
+        if negotiated == self.version {
+            return;
+        }
+        debug!(target: TARGET, version = negotiated, "protocol version negotiated");
+        let downgraded = negotiated < self.version;
+        self.version = negotiated;
+        if downgraded {
+            self.rewrite_in_flight();
+        }
+    }
+
+    // Rewrites unacknowledged packets of a newer version than the peer speaks in the version it
+    // does, sealing them afresh if the session has a key. Packets that will not open are left
+    // as they are.
+    fn rewrite_in_flight(&mut self) {
+        for in_flight in self.in_flight.values_mut() {
+            let Ok(mut frame) = Frame::from_bytes(&in_flight.packet) else {
+                continue;
+            };
+            if frame.version <= self.version {
+                continue;
+            }
+            if let Some(psk) = &self.psk {
+                if psk.open(&mut frame).is_err() {
+                    continue;
+                }
+            }
+            frame.version = self.version;
+            if let Some(psk) = &self.psk {
+                psk.seal(&mut frame);
+            }
+            in_flight.packet = frame.to_bytes();
+        }
+    }
+}
+
+impl ClientShared {
+    /// Opens the version exchange with the server.
+    pub(super) fn probe_versions(&self) {
+        let probes = lock(&self.state).version_probes();
+        for probe in probes {
+            if let Err(e) = self.transport().send(&probe) {
+                debug!(target: TARGET, error = %e, "version probe failed");
+            }
+        }
+    }
+}
+
+impl Client {
+    /// The frame layout version the client writes to the server: the newest both speak, once
+    /// the server has answered, and `protocol::VERSION` until then.
+    pub fn protocol_version(&self) -> u8 {
+        lock(&self.shared.state).version
+    }
+}
+
+impl Server {
+    /// The frame layout version the server writes to the client at `peer`, as
+    /// `Client::protocol_version`.
+    pub fn protocol_version(&self, peer: SocketAddr) -> Option<u8> {
+        lock(&self.shared.peers)
+            .get(&peer)
+            .map(|state| state.version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::Psk;
+    use crate::util::config::{Config, SecurityMode};
+    use std::io;
+    use std::sync::Arc;
+
+    fn peer(conf: &Config) -> io::Result<PeerState> {
+        Ok(PeerState::new(conf).with_psk(Psk::from_config(conf)?.map(Arc::new)))
+    }
+
+    #[test]
+    fn sessions_downgrade_to_older_peers() -> io::Result<()> {
+        let conf = Config {
+            security: SecurityMode::Psk,
+            psk: "3".repeat(64),
+            ..Default::default()
+        };
+        let mut client = peer(&conf)?;
+        let early = client.outgoing(Frame {
+            msg_type: 5,
+            ..Frame::new(b"early".to_vec())
+        });
+        assert_eq!(early[0], VERSION);
+        let probes = client.version_probes();
+        assert_eq!(
+            probes.iter().map(|probe| probe[0]).collect::<Vec<_>>(),
+            (MIN_VERSION..=VERSION).rev().collect::<Vec<_>>()
+        );
+        // Probes the server cannot read go unanswered without holding up the session.
+        assert_eq!((client.in_flight.len(), client.unanswered), (1, 0));
+
+        // A server that speaks only version 12 reads, and so answers, only the older probe.
+        let mut server = peer(&conf)?;
+        let (_, pong) = server.incoming(probes.last().unwrap());
+        let pong = pong.unwrap();
+        assert_eq!(pong[0], 12);
+        client.incoming(&pong);
+        assert_eq!(client.stats().protocol_version, 12);
+        let resent: Vec<_> = client
+            .in_flight
+            .values()
+            .map(|f| f.packet.clone())
+            .collect();
+        let (messages, _) = server.incoming(&resent[0]);
+        assert_eq!(
+            (&messages[0].payload[..], messages[0].msg_type),
+            (&b"early"[..], 0)
+        );
+
+        // One as new as the client answers both in the newest.
+        let mut server = peer(&conf)?;
+        let mut client = peer(&conf)?;
+        for probe in client.version_probes() {
+            let (_, pong) = server.incoming(&probe);
+            assert_eq!(pong.as_ref().unwrap()[0], VERSION);
+            client.incoming(&pong.unwrap());
+        }
+        assert_eq!(client.stats().protocol_version, VERSION);
+        assert_eq!(server.stats().protocol_version, VERSION);
+        Ok(())
+    }
+}
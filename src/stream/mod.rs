@@ -1,2 +1,328 @@
+//! Transfers too large for one message. `session::Client::send_stream` cuts a blob into chunks
+//! that each fit a datagram and sends them as reliable session messages flagged
+//! `Frame::STREAM`; `session::Server::receive_stream` hands each blob over as an
+//! `IncomingStream` to read from. `session::Client::send_resumable` first asks where to start,
+//! so a transfer cut short carries on from what the receiver already has.
+//!
+//! The payload of a stream message is a chunk, with integers big endian:
+//!
+//! | Offset | Size | Field      | Meaning                                                                     |
+//! |--------|------|------------|-----------------------------------------------------------------------------|
+//! | 0      | 8    | `transfer` | Names the transfer, unique among the sender's.                              |
+//! | 8      | 8    | `offset`   | Where `data` starts in the blob, the blob's length, or where to resume.     |
+//! | 16     | 1    | `kind`     | 0 data, 1 end, 2 resume request, 3 resume answer.                           |
+//! | 17     | -    | `data`     | The blob's bytes, or in an end chunk the CRC-32 (4 bytes) of what was sent. |
+//!
+//! Chunks may arrive in any order, so receivers place data by `offset`. The end chunk's length
+//! and checksum let the receiver tell that it has the whole blob and that it is intact.
+//!
+//! A resumable transfer opens with a resume request, with `offset` 0 and no data. The receiver
+//! answers with a resume answer whose `offset` is how much of the blob, from its start, it
+//! already holds, and the sender sends data from there on. The checksum then covers only the
+//! bytes sent from that offset.
 
+use crate::error::Error;
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::sync::mpsc;
+use std::time::Duration;
 
+/// Bytes of every chunk taken by its header.
+pub(crate) const CHUNK_HEADER_LEN: usize = 17;
+
+const KIND_DATA: u8 = 0;
+const KIND_END: u8 = 1;
+const KIND_RESUME: u8 = 2;
+const KIND_RESUME_AT: u8 = 3;
+
+/// One piece of a stream, as carried by one session message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Chunk {
+    Data {
+        transfer: u64,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    End {
+        transfer: u64,
+        len: u64,
+        checksum: u32,
+    },
+    /// Asks the receiver where to resume `transfer`.
+    Resume { transfer: u64 },
+    /// Tells the sender to resume `transfer` from `offset`.
+    ResumeAt { transfer: u64, offset: u64 },
+}
+
+impl Chunk {
+    pub(crate) fn transfer(&self) -> u64 {
+        match self {
+            Chunk::Data { transfer, .. }
+            | Chunk::End { transfer, .. }
+            | Chunk::Resume { transfer }
+            | Chunk::ResumeAt { transfer, .. } => *transfer,
+        }
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let checksum;
+        let (transfer, offset, kind, data) = match self {
+            Chunk::Data {
+                transfer,
+                offset,
+                data,
+            } => (transfer, *offset, KIND_DATA, &data[..]),
+            Chunk::End {
+                transfer,
+                len,
+                checksum: sum,
+            } => {
+                checksum = sum.to_be_bytes();
+                (transfer, *len, KIND_END, &checksum[..])
+            }
+            Chunk::Resume { transfer } => (transfer, 0, KIND_RESUME, &[][..]),
+            Chunk::ResumeAt { transfer, offset } => (transfer, *offset, KIND_RESUME_AT, &[][..]),
+        };
+        let mut bytes = Vec::with_capacity(CHUNK_HEADER_LEN + data.len());
+        bytes.extend_from_slice(&transfer.to_be_bytes());
+        bytes.extend_from_slice(&offset.to_be_bytes());
+        bytes.push(kind);
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> crate::Result<Chunk> {
+        let malformed = || Error::protocol("Malformed stream chunk");
+        let header = bytes.get(..CHUNK_HEADER_LEN).ok_or_else(malformed)?;
+        let transfer = u64::from_be_bytes(header[..8].try_into().unwrap());
+        let offset = u64::from_be_bytes(header[8..16].try_into().unwrap());
+        let data = &bytes[CHUNK_HEADER_LEN..];
+        match header[16] {
+            KIND_DATA => Ok(Chunk::Data {
+                transfer,
+                offset,
+                data: data.to_vec(),
+            }),
+            KIND_END => Ok(Chunk::End {
+                transfer,
+                len: offset,
+                checksum: u32::from_be_bytes(data.try_into().map_err(|_| malformed())?),
+            }),
+            KIND_RESUME => Ok(Chunk::Resume { transfer }),
+            KIND_RESUME_AT => Ok(Chunk::ResumeAt { transfer, offset }),
+            _ => Err(malformed()),
+        }
+    }
+}
+
+/// A blob arriving from a peer, read in order as its chunks come in.
+///
+/// Reads block until the next bytes arrive, for at most the read timeout, and return 0 once
+/// the whole blob has been read and its checksum matches. A blob that does not match fails
+/// with `ErrorKind::InvalidData`, carrying `Error::Protocol`, and one whose sender went away
+/// first with `ErrorKind::UnexpectedEof`. Chunks not read yet are held in memory.
+///
+/// A resumed transfer starts at `start`: its reads yield the blob from there on, and the
+/// checksum is of those bytes.
+pub struct IncomingStream {
+    chunks: mpsc::Receiver<Chunk>,
+    transfer: u64,
+    start: u64,
+    // Data that arrived ahead of `position`, by offset.
+    pending: BTreeMap<u64, Vec<u8>>,
+    current: Vec<u8>,
+    consumed: usize,
+    position: u64,
+    checksum: crc32fast::Hasher,
+    end: Option<(u64, u32)>,
+    read_timeout: Option<Duration>,
+}
+
+impl IncomingStream {
+    pub(crate) fn new(chunks: mpsc::Receiver<Chunk>, transfer: u64, start: u64) -> IncomingStream {
+        IncomingStream {
+            chunks,
+            transfer,
+            start,
+            pending: BTreeMap::new(),
+            current: Vec::new(),
+            consumed: 0,
+            position: start,
+            checksum: crc32fast::Hasher::new(),
+            end: None,
+            read_timeout: None,
+        }
+    }
+
+    /// Bounds how long a read waits for the next chunk, failing with `Error::Timeout` when it
+    /// runs out. `None` waits until the sender finishes or goes away.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// The id the sender gave the transfer.
+    pub fn transfer(&self) -> u64 {
+        self.transfer
+    }
+
+    /// Where in the blob this stream starts: 0, or for a resumed transfer the offset the
+    /// receiver said it already held.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// The length of the whole blob, once its end chunk has arrived.
+    pub fn total_len(&self) -> Option<u64> {
+        self.end.map(|(len, _)| len)
+    }
+
+    /// The offset in the blob of the next byte to read.
+    pub fn position(&self) -> u64 {
+        self.position - (self.current.len() - self.consumed) as u64
+    }
+
+    // Takes in the next chunk from the sender, keeping data not already read.
+    fn receive(&mut self) -> io::Result<()> {
+        let chunk = match self.read_timeout {
+            Some(timeout) => self.chunks.recv_timeout(timeout).map_err(|e| match e {
+                mpsc::RecvTimeoutError::Timeout => Error::Timeout.into(),
+                mpsc::RecvTimeoutError::Disconnected => ended_early(),
+            }),
+            None => self.chunks.recv().map_err(|_| ended_early()),
+        }?;
+        match chunk {
+            Chunk::Data { offset, data, .. } if offset >= self.position && !data.is_empty() => {
+                self.pending.entry(offset).or_insert(data);
+            }
+            Chunk::Data { .. } => {}
+            Chunk::End { len, checksum, .. } => self.end = Some((len, checksum)),
+            Chunk::Resume { .. } | Chunk::ResumeAt { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+impl Read for IncomingStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let available = &self.current[self.consumed..];
+            if !available.is_empty() {
+                let len = available.len().min(buffer.len());
+                buffer[..len].copy_from_slice(&available[..len]);
+                self.consumed += len;
+                return Ok(len);
+            }
+            if let Some(data) = self.pending.remove(&self.position) {
+                self.checksum.update(&data);
+                self.position += data.len() as u64;
+                self.current = data;
+                self.consumed = 0;
+                continue;
+            }
+            match self.end {
+                Some((len, checksum)) if len == self.position => {
+                    if self.checksum.clone().finalize() != checksum {
+                        return Err(Error::protocol("Stream checksum mismatch").into());
+                    }
+                    return Ok(0);
+                }
+                Some((len, _)) if len < self.position => {
+                    return Err(Error::protocol("Stream longer than its end chunk says").into());
+                }
+                _ => self.receive()?,
+            }
+        }
+    }
+}
+
+fn ended_early() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "Stream sender went away before the end",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream_of(chunks: Vec<Chunk>) -> IncomingStream {
+        let (sender, receiver) = mpsc::channel();
+        for chunk in chunks {
+            sender.send(chunk).unwrap();
+        }
+        IncomingStream::new(receiver, 7, 0)
+    }
+
+    fn data(offset: u64, data: &[u8]) -> Chunk {
+        Chunk::Data {
+            transfer: 7,
+            offset,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn chunks_round_trip() {
+        let chunks = [
+            data(1 << 40, b"bytes"),
+            Chunk::End {
+                transfer: 7,
+                len: 12,
+                checksum: 0xdead_beef,
+            },
+            Chunk::Resume { transfer: u64::MAX },
+            Chunk::ResumeAt {
+                transfer: 7,
+                offset: 1 << 33,
+            },
+        ];
+        for chunk in chunks {
+            assert_eq!(Chunk::from_bytes(&chunk.to_bytes()).unwrap(), chunk);
+        }
+        assert!(Chunk::from_bytes(&[0; CHUNK_HEADER_LEN - 1]).is_err());
+        assert!(Chunk::from_bytes(&[1; CHUNK_HEADER_LEN]).is_err());
+    }
+
+    #[test]
+    fn chunks_are_read_in_order_and_checked() -> io::Result<()> {
+        let end = Chunk::End {
+            transfer: 7,
+            len: 11,
+            checksum: crc32fast::hash(b"hello world"),
+        };
+        let mut stream = stream_of(vec![
+            data(6, b"world"),
+            end.clone(),
+            data(0, b"hello "),
+            data(0, b"hello "),
+        ]);
+        let mut blob = Vec::new();
+        stream.read_to_end(&mut blob)?;
+        assert_eq!(blob, b"hello world");
+        assert_eq!((stream.total_len(), stream.position()), (Some(11), 11));
+
+        let mut corrupt = stream_of(vec![data(0, b"hello"), data(5, b" there"), end]);
+        let e = corrupt.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(matches!(Error::from(e), Error::Protocol(_)));
+
+        let (sender, receiver) = mpsc::channel();
+        sender.send(data(6, b"world")).unwrap();
+        sender
+            .send(Chunk::End {
+                transfer: 7,
+                len: 11,
+                checksum: crc32fast::hash(b"world"),
+            })
+            .unwrap();
+        let mut resumed = IncomingStream::new(receiver, 7, 6);
+        let mut rest = Vec::new();
+        resumed.read_to_end(&mut rest)?;
+        assert_eq!((rest.as_slice(), resumed.start()), (&b"world"[..], 6));
+
+        let mut cut = stream_of(vec![data(0, b"hello")]);
+        let e = cut.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+//! Systemd socket activation: servers adopt the UDP sockets passed by the service manager
+//! through `LISTEN_FDS`, as `sd_listen_fds` does, instead of binding their own, so a service
+//! restarts without its port ever being closed.
+//!
+//! Adopted sockets are used as the `.socket` unit configured them, so `bind_host`, `port` and
+//! the socket options in `Config` do not apply to them. Each is adopted once, by servers in the
+//! order they are created, and servers created once every one has been taken bind as usual.
+
+use socket2::{Domain, Socket, Type};
+use std::env;
+use std::net::UdpSocket;
+use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+use std::process;
+use std::sync::{Mutex, OnceLock};
+use tracing::debug;
+
+const TARGET: &str = "crumb::transport::activation";
+
+// The first descriptor systemd passes.
+const LISTEN_FDS_START: RawFd = 3;
+
+// The datagram sockets passed to this process and not yet adopted, in the order passed.
+static INHERITED: OnceLock<Mutex<Vec<Socket>>> = OnceLock::new();
+
+/// The next UDP socket passed by the service manager, or `None` when the process was not
+/// socket activated or every such socket has been adopted.
+pub(crate) fn take_socket() -> Option<UdpSocket> {
+    let inherited = INHERITED.get_or_init(|| Mutex::new(inherit()));
+    let mut inherited = inherited.lock().unwrap_or_else(|e| e.into_inner());
+    if inherited.is_empty() {
+        return None;
+    }
+    let socket = inherited.remove(0);
+    debug!(target: TARGET, addr = ?socket.local_addr().ok().and_then(|addr| addr.as_socket()), "adopted activated socket");
+    Some(socket.into())
+}
+
+// Takes over the descriptors named by the environment, which is then cleared so that child
+// processes do not take them for their own.
+fn inherit() -> Vec<Socket> {
+    let count = listen_fds(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        process::id(),
+    );
+    if count == 0 {
+        return Vec::new();
+    }
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        // SAFETY: systemd passes these descriptors to this process for it to own, and the
+        // environment naming them is cleared so that nothing takes them twice.
+        .filter_map(|fd| unsafe { adopt(fd) })
+        .collect()
+}
+
+// How many descriptors the `LISTEN_PID` and `LISTEN_FDS` values pass to the process `pid`.
+fn listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> RawFd {
+    if listen_pid.and_then(|listen_pid| listen_pid.parse().ok()) != Some(pid) {
+        return 0;
+    }
+    listen_fds
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .filter(|&count| count > 0)
+        .unwrap_or(0)
+}
+
+/// `fd` as a socket if it is an IPv4 or IPv6 datagram socket. Others, such as the stream
+/// sockets of other units, are left open for whoever wants them.
+///
+/// # Safety
+///
+/// `fd` must be an open descriptor the caller owns.
+unsafe fn adopt(fd: RawFd) -> Option<Socket> {
+    let socket = Socket::from_raw_fd(fd);
+    let is_udp = socket.r#type().is_ok_and(|kind| kind == Type::DGRAM)
+        && socket
+            .local_addr()
+            .is_ok_and(|addr| matches!(addr.domain(), Domain::IPV4 | Domain::IPV6));
+    if !is_udp {
+        let _ = socket.into_raw_fd();
+        return None;
+    }
+    if let Err(e) = socket.set_cloexec(true) {
+        debug!(target: TARGET, fd, error = %e, "activated socket left inheritable");
+    }
+    Some(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::os::fd::AsRawFd;
+
+    #[test]
+    fn descriptors_are_for_the_named_process() {
+        assert_eq!(listen_fds(Some("42"), Some("2"), 42), 2);
+        assert_eq!(listen_fds(Some("42"), Some("2"), 43), 0);
+        assert_eq!(listen_fds(None, Some("2"), 42), 0);
+        assert_eq!(listen_fds(Some("42"), None, 42), 0);
+        assert_eq!(listen_fds(Some("42"), Some("-1"), 42), 0);
+        assert_eq!(listen_fds(Some("42"), Some("many"), 42), 0);
+    }
+
+    #[test]
+    fn only_udp_sockets_are_adopted() -> std::io::Result<()> {
+        let udp = UdpSocket::bind("127.0.0.1:0")?;
+        let addr = udp.local_addr()?;
+        // SAFETY: the descriptor is released by `udp`, so the adopted socket owns it.
+        let adopted = unsafe { adopt(udp.into_raw_fd()) }.unwrap();
+        assert_eq!(UdpSocket::from(adopted).local_addr()?, addr);
+
+        let tcp = TcpListener::bind("127.0.0.1:0")?;
+        // SAFETY: `tcp` keeps owning the descriptor, which `adopt` hands back unclosed.
+        assert!(unsafe { adopt(tcp.as_raw_fd()) }.is_none());
+        assert!(tcp.local_addr().is_ok());
+        Ok(())
+    }
+}
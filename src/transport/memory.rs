@@ -0,0 +1,372 @@
+use super::{check_size, timed_out, Transport, Xorshift, MAX_DATAGRAM_SIZE};
+use crate::util::config::Config;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tracing::trace;
+
+const TARGET: &str = "crumb::transport::memory";
+
+// Hands out the placeholder ports endpoints report as their addresses.
+static NEXT_PORT: AtomicU16 = AtomicU16::new(1);
+
+/// How a link between two in-memory endpoints treats the datagrams sent over it. Losses and
+/// reordering are drawn from a generator seeded with `seed`, so a run repeats exactly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Conditions {
+    /// How long every datagram takes to arrive.
+    pub latency: Duration,
+    /// Upper bound on a random delay added to `latency`, which also reorders datagrams sent
+    /// closer together than it.
+    pub jitter: Duration,
+    /// The probability, between 0 and 1, that a datagram is lost.
+    pub loss: f64,
+    /// The probability, between 0 and 1, that a datagram is held back and delivered right after
+    /// the next one sent. A held datagram is lost if none follows.
+    pub reorder: f64,
+    pub seed: u64,
+}
+
+impl Default for Conditions {
+    fn default() -> Self {
+        Conditions {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            loss: 0.0,
+            reorder: 0.0,
+            seed: 1,
+        }
+    }
+}
+
+/// Two connected in-memory endpoints. Datagrams either one sends reach the other under
+/// `conditions`, in both directions independently.
+pub fn pair(conditions: Conditions) -> (Endpoint, Endpoint) {
+    let there = Arc::new(Link::new(conditions, conditions.seed));
+    let back = Arc::new(Link::new(conditions, !conditions.seed));
+    let addr = || {
+        SocketAddr::from((
+            Ipv4Addr::LOCALHOST,
+            NEXT_PORT.fetch_add(1, Ordering::Relaxed),
+        ))
+    };
+    let (a, b) = (addr(), addr());
+    (
+        Endpoint::new(a, b, there.clone(), back.clone()),
+        Endpoint::new(b, a, back, there),
+    )
+}
+
+/// One end of an in-memory link made by `pair`, implementing `Transport` without sockets so the
+/// layers above can be tested deterministically.
+///
+/// Endpoints report placeholder loopback addresses, unique within the process. Once the other
+/// end is closed, sends fail with `ErrorKind::ConnectionRefused`, and receives fail with
+/// `ErrorKind::NotConnected` when nothing more is on its way.
+pub struct Endpoint {
+    local: SocketAddr,
+    peer: SocketAddr,
+    outgoing: Arc<Link>,
+    incoming: Arc<Link>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+struct Link {
+    conditions: Conditions,
+    state: Mutex<LinkState>,
+    arrived: Condvar,
+}
+
+struct LinkState {
+    // Datagrams on their way, earliest delivery first, in send order among equals.
+    in_transit: BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>>,
+    held: Option<Vec<u8>>,
+    sent: u64,
+    random: Xorshift,
+    closed: bool,
+}
+
+impl Link {
+    fn new(conditions: Conditions, seed: u64) -> Link {
+        Link {
+            conditions,
+            state: Mutex::new(LinkState {
+                in_transit: BinaryHeap::new(),
+                held: None,
+                sent: 0,
+                random: Xorshift::new(seed),
+                closed: false,
+            }),
+            arrived: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, LinkState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.arrived.notify_all();
+    }
+}
+
+impl LinkState {
+    fn push(&mut self, deliver_at: Instant, datagram: Vec<u8>) {
+        self.in_transit
+            .push(Reverse((deliver_at, self.sent, datagram)));
+        self.sent += 1;
+    }
+}
+
+impl Endpoint {
+    fn new(local: SocketAddr, peer: SocketAddr, outgoing: Arc<Link>, incoming: Arc<Link>) -> Self {
+        Endpoint {
+            local,
+            peer,
+            outgoing,
+            incoming,
+            read_timeout: Mutex::default(),
+        }
+    }
+
+    pub fn send(&self, data: &[u8]) -> io::Result<usize> {
+        check_size(data.len(), MAX_DATAGRAM_SIZE)?;
+        let conditions = &self.outgoing.conditions;
+        let mut link = self.outgoing.lock();
+        if link.closed {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "The other endpoint is closed",
+            ));
+        }
+        if link.random.unit() < conditions.loss {
+            trace!(target: TARGET, bytes = data.len(), "datagram lost");
+            return Ok(data.len());
+        }
+        if link.held.is_none() && link.random.unit() < conditions.reorder {
+            trace!(target: TARGET, bytes = data.len(), "datagram held back");
+            link.held = Some(data.to_vec());
+            return Ok(data.len());
+        }
+
+        let jitter = conditions.jitter.mul_f64(link.random.unit());
+        let deliver_at = Instant::now() + conditions.latency + jitter;
+        link.push(deliver_at, data.to_vec());
+        if let Some(held) = link.held.take() {
+            link.push(deliver_at, held);
+        }
+        drop(link);
+        self.outgoing.arrived.notify_all();
+        Ok(data.len())
+    }
+
+    /// Waits for the next datagram due, copying it into `buffer` and truncating if necessary.
+    pub fn receive(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let timeout = *self.read_timeout.lock().unwrap_or_else(|e| e.into_inner());
+        self.receive_within(buffer, timeout)
+    }
+
+    /// Like `receive`, but fails with `Error::Timeout` if nothing is due within `timeout`.
+    pub fn receive_timeout(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        timed_out(self.receive_within(buffer, Some(timeout)))
+    }
+
+    fn receive_within(&self, buffer: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut link = self.incoming.lock();
+        loop {
+            let now = Instant::now();
+            let due = link.in_transit.peek().map(|Reverse((at, _, _))| *at);
+            if due.is_some_and(|at| at <= now) {
+                let Reverse((_, _, datagram)) = link.in_transit.pop().unwrap();
+                let len = datagram.len().min(buffer.len());
+                buffer[..len].copy_from_slice(&datagram[..len]);
+                return Ok(len);
+            }
+            if due.is_none() && link.closed {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "The other endpoint is closed",
+                ));
+            }
+            if deadline.is_some_and(|deadline| deadline <= now) {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "Memory receive timed out",
+                ));
+            }
+            // Until the next datagram is due or the timeout ends, whichever is first.
+            let wait = [due, deadline]
+                .into_iter()
+                .flatten()
+                .min()
+                .map(|until| until - now);
+            link = match wait {
+                Some(wait) => {
+                    self.incoming
+                        .arrived
+                        .wait_timeout(link, wait)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+                None => self
+                    .incoming
+                    .arrived
+                    .wait(link)
+                    .unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+    }
+
+    /// Bounds how long `receive` blocks. `None` blocks until a datagram arrives.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap_or_else(|e| e.into_inner()) = timeout;
+        Ok(())
+    }
+
+    /// Closes this end. Dropping the endpoint does the same.
+    pub fn close(self) {
+        drop(self);
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        self.outgoing.close();
+        self.incoming.close();
+    }
+}
+
+impl Transport for Endpoint {
+    /// Endpoints only come in pairs, from `pair`.
+    fn init(_: &Config) -> io::Result<Endpoint> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "In-memory endpoints are made with memory::pair",
+        ))
+    }
+
+    fn send(&self, data: &[u8]) -> io::Result<usize> {
+        Endpoint::send(self, data)
+    }
+
+    fn receive(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        Endpoint::receive(self, buffer)
+    }
+
+    fn receive_timeout(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        Endpoint::receive_timeout(self, buffer, timeout)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Endpoint::set_read_timeout(self, timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+
+    fn close(self: Box<Self>) {
+        Endpoint::close(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::protocol::Frame;
+
+    fn receive_all(endpoint: &Endpoint) -> Vec<Vec<u8>> {
+        endpoint
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        let mut buffer = [0u8; 64];
+        std::iter::from_fn(|| {
+            let received = endpoint.receive(&mut buffer).ok()?;
+            Some(buffer[..received].to_vec())
+        })
+        .collect()
+    }
+
+    #[test]
+    fn frames_cross_both_ways() -> io::Result<()> {
+        let (a, b) = pair(Conditions::default());
+        let frame = Frame::new(b"hello".to_vec());
+        a.send(&frame.to_bytes())?;
+        b.send(b"back")?;
+
+        let mut buffer = [0u8; 64];
+        let received = b.receive(&mut buffer)?;
+        assert_eq!(Frame::from_bytes(&buffer[..received]).unwrap(), frame);
+        assert_eq!(receive_all(&a), [b"back".to_vec()]);
+        assert_eq!(a.peer_addr()?, b.local_addr()?);
+
+        let polled = a.receive_timeout(&mut buffer, Duration::ZERO);
+        assert!(matches!(polled.map_err(Error::from), Err(Error::Timeout)));
+        b.send(b"now")?;
+        assert_eq!(a.receive_timeout(&mut buffer, Duration::ZERO)?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn losses_and_reordering_repeat_with_the_seed() {
+        let run = |seed| {
+            let (a, b) = pair(Conditions {
+                loss: 0.3,
+                reorder: 0.2,
+                seed,
+                ..Default::default()
+            });
+            for i in 0..50u8 {
+                a.send(&[i]).unwrap();
+            }
+            receive_all(&b).concat()
+        };
+        let received = run(7);
+        assert_eq!(received, run(7));
+        assert_ne!(received, run(8));
+        assert!(received.len() < 50);
+        assert!(received.windows(2).any(|pair| pair[0] > pair[1]));
+    }
+
+    #[test]
+    fn latency_delays_delivery() -> io::Result<()> {
+        let latency = Duration::from_millis(30);
+        let (a, b) = pair(Conditions {
+            latency,
+            ..Default::default()
+        });
+        b.set_read_timeout(Some(Duration::from_millis(5)))?;
+        let sent_at = Instant::now();
+        a.send(b"late")?;
+        let mut buffer = [0u8; 8];
+        assert_eq!(
+            b.receive(&mut buffer).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        b.set_read_timeout(None)?;
+        assert_eq!(b.receive(&mut buffer)?, 4);
+        assert!(sent_at.elapsed() >= latency);
+
+        a.close();
+        assert_eq!(
+            b.receive(&mut buffer).unwrap_err().kind(),
+            io::ErrorKind::NotConnected
+        );
+        assert_eq!(
+            b.send(b"gone").unwrap_err().kind(),
+            io::ErrorKind::ConnectionRefused
+        );
+        Ok(())
+    }
+}
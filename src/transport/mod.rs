@@ -1,2 +1,646 @@
-mod udp;
+#[cfg(unix)]
+mod activation;
+pub mod memory;
+mod pool;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub(crate) mod relay;
+pub mod sim;
+pub(crate) mod socks;
+mod timestamp;
+pub mod udp;
+#[cfg(unix)]
+pub mod unix;
 
+pub use pool::{BufferPool, PooledBuffer};
+
+use crate::error::Error;
+use crate::util::config::{Config, TransportType};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{self, IoSlice};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, warn};
+
+const TARGET: &str = "crumb::transport";
+
+// DSCP is a six bit field.
+const MAX_DSCP: u8 = 63;
+
+// Largest payload a UDP datagram can carry.
+pub(crate) const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+// Sockets refuse a zero read timeout, so receives that only take what has arrived wait this
+// long instead.
+const MIN_READ_TIMEOUT: Duration = Duration::from_micros(1);
+
+// Messages waiting for each `serve_concurrent` worker before the receiving thread blocks.
+const WORKER_QUEUE_DEPTH: usize = 256;
+
+/// A connected, datagram-oriented channel to a single peer.
+///
+/// Application code should hold the `Box<dyn Transport>` returned by
+/// `<dyn Transport>::from_config` rather than a concrete backend, so the backend can be switched
+/// with `CRUMB_TRANSPORT` without code changes.
+pub trait Transport: Send + Sync {
+    fn init(conf: &Config) -> io::Result<Self>
+    where
+        Self: Sized;
+
+    fn send(&self, data: &[u8]) -> io::Result<usize>;
+
+    /// Sends the concatenation of `bufs` as one datagram. Backends that can gather from several
+    /// buffers do so without copying; the default joins them first.
+    fn send_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let data: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+        self.send(&data)
+    }
+
+    fn receive(&self, buffer: &mut [u8]) -> io::Result<usize>;
+
+    /// Like `receive`, also giving the time the datagram arrived by the kernel's clock, for
+    /// backends that can tell with `kernel_timestamps` set.
+    fn receive_timestamped(&self, buffer: &mut [u8]) -> io::Result<(usize, Option<SystemTime>)> {
+        self.receive(buffer).map(|received| (received, None))
+    }
+
+    /// Like `receive`, but into a buffer taken from `pool` that returns to it when dropped.
+    fn receive_pooled(&self, pool: &BufferPool) -> io::Result<PooledBuffer> {
+        pool.receive_with(|buffer| self.receive(buffer))
+    }
+
+    /// Like `receive`, but fails with `Error::Timeout` if nothing arrives within `timeout`,
+    /// whatever the read timeout. A zero `timeout` only takes what has already arrived.
+    fn receive_timeout(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<usize>;
+
+    /// Like `receive_timeout`, giving up at `deadline`, so a sequence of receives can share one
+    /// time budget.
+    fn receive_until(&self, buffer: &mut [u8], deadline: Instant) -> io::Result<usize> {
+        self.receive_timeout(buffer, deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Bounds how long `receive` blocks. `None` blocks until a message arrives.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    fn close(self: Box<Self>);
+}
+
+impl dyn Transport {
+    /// Builds the client transport selected by `conf.transport_type`.
+    pub fn from_config(conf: &Config) -> io::Result<Box<dyn Transport>> {
+        match conf.transport_type {
+            TransportType::Udp => Ok(Box::new(udp::Client::init(conf)?)),
+            #[cfg(feature = "quic")]
+            TransportType::Quic => Ok(Box::new(quic::Client::init(conf)?)),
+            #[cfg(not(feature = "quic"))]
+            TransportType::Quic => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "crumb was built without the `quic` feature",
+            )),
+            #[cfg(unix)]
+            TransportType::Unix => Ok(Box::new(unix::Client::init(conf)?)),
+            #[cfg(not(unix))]
+            TransportType::Unix => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "UNIX sockets are not available on this platform",
+            )),
+        }
+    }
+}
+
+/// Drives a server's `serve` method: receives messages until `receive` fails with anything
+/// other than a read timeout, passes each to `handler` and sends any reply back to its source.
+/// A handler that panics loses only the message it was handling.
+pub(crate) fn serve<P, F>(
+    mut receive: impl FnMut(&mut [u8]) -> io::Result<(usize, P)>,
+    send: impl Fn(&[u8], P) -> io::Result<usize>,
+    mut handler: F,
+) -> io::Error
+where
+    P: Clone + fmt::Debug,
+    F: FnMut(&[u8], P) -> Option<Vec<u8>>,
+{
+    let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        match receive(&mut buffer) {
+            Ok((received, peer)) => handle(&mut handler, &buffer[..received], peer, &send),
+            Err(e) if is_timeout(&e) => continue,
+            Err(e) => return e,
+        }
+    }
+}
+
+/// Like `serve`, but runs `handler` on `workers` threads while the calling thread receives.
+///
+/// Messages are assigned to workers by source address, so each peer's messages are handled one
+/// at a time and in order, while a slow handler only delays the peers sharing its worker.
+pub(crate) fn serve_concurrent<F>(
+    mut receive: impl FnMut(&mut [u8]) -> io::Result<(usize, SocketAddr)>,
+    send: impl Fn(&[u8], SocketAddr) -> io::Result<usize> + Sync,
+    handler: F,
+    workers: usize,
+) -> io::Error
+where
+    F: Fn(&[u8], SocketAddr) -> Option<Vec<u8>> + Sync,
+{
+    thread::scope(|scope| {
+        let mut queues = Vec::new();
+        for _ in 0..workers.max(1) {
+            let (queue, messages) = mpsc::sync_channel::<(Vec<u8>, SocketAddr)>(WORKER_QUEUE_DEPTH);
+            let (mut handler, send) = (&handler, &send);
+            scope.spawn(move || {
+                for (message, peer) in messages {
+                    handle(&mut handler, &message, peer, send);
+                }
+            });
+            queues.push(queue);
+        }
+
+        let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            match receive(&mut buffer) {
+                Ok((received, peer)) => {
+                    let worker = worker_for(&peer, queues.len());
+                    // Workers run until their queue is dropped, so this cannot fail.
+                    let _ = queues[worker].send((buffer[..received].to_vec(), peer));
+                }
+                Err(e) if is_timeout(&e) => continue,
+                // Dropping the queues lets the workers drain them and exit.
+                Err(e) => return e,
+            }
+        }
+    })
+}
+
+fn worker_for(peer: &SocketAddr, workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    peer.hash(&mut hasher);
+    (hasher.finish() % workers as u64) as usize
+}
+
+fn handle<P, F>(
+    handler: &mut F,
+    message: &[u8],
+    peer: P,
+    send: &impl Fn(&[u8], P) -> io::Result<usize>,
+) where
+    P: Clone + fmt::Debug,
+    F: FnMut(&[u8], P) -> Option<Vec<u8>>,
+{
+    match panic::catch_unwind(AssertUnwindSafe(|| handler(message, peer.clone()))) {
+        Ok(Some(reply)) => {
+            if let Err(e) = send(&reply, peer.clone()) {
+                debug!(target: TARGET, ?peer, error = %e, "reply failed");
+            }
+        }
+        Ok(None) => {}
+        Err(_) => warn!(target: TARGET, ?peer, "handler panicked, message dropped"),
+    }
+}
+
+/// Fails with `Error::PayloadTooLarge` unless `size` bytes fit in `max`, so oversized sends are
+/// refused before they reach a socket.
+pub(crate) fn check_size(size: usize, max: usize) -> io::Result<()> {
+    match size <= max {
+        true => Ok(()),
+        false => Err(Error::PayloadTooLarge { size, max }.into()),
+    }
+}
+
+/// `result` with read timeouts replaced by `Error::Timeout`, as `receive_timeout` fails.
+pub(crate) fn timed_out<T>(result: io::Result<T>) -> io::Result<T> {
+    result.map_err(|e| match is_timeout(&e) {
+        true => Error::Timeout.into(),
+        false => e,
+    })
+}
+
+pub(crate) fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Binds a client socket to `conf.bind_host` and `conf.bind_port`.
+pub(crate) fn bind_client(conf: &Config) -> io::Result<UdpSocket> {
+    bind(conf, conf.bind_port)
+}
+
+/// Binds a server socket to `conf.bind_host` on `conf.bind_port`, or `conf.port` if unset,
+/// unless the process was socket activated and has a UDP socket left to adopt.
+pub(crate) fn bind_server(conf: &Config) -> io::Result<UdpSocket> {
+    #[cfg(unix)]
+    if let Some(socket) = activation::take_socket() {
+        return Ok(socket);
+    }
+    let port = match conf.bind_port {
+        0 => conf.port,
+        bind_port => bind_port,
+    };
+    bind(conf, port)
+}
+
+fn bind(conf: &Config, port: u16) -> io::Result<UdpSocket> {
+    let ip: IpAddr = conf
+        .bind_host
+        .parse()
+        .map_err(|_| Error::config(format!("Invalid bind address: {}", conf.bind_host)))?;
+    let addr = SocketAddr::new(ip, port);
+
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    // Set either way, as the default differs: Linux and macOS sockets are dual-stack unless told
+    // otherwise, Windows ones IPv6 only.
+    if addr.is_ipv6() {
+        if let Err(e) = socket.set_only_v6(!conf.dual_stack) {
+            // Platforms without dual-stack sockets, such as OpenBSD, get an IPv4 socket instead
+            // when no particular address was asked for.
+            if !(conf.dual_stack && ip.is_unspecified()) {
+                return Err(e);
+            }
+            warn!(target: TARGET, error = %e, "no dual-stack sockets, binding IPv4 only");
+            let v4 = Config {
+                bind_host: Ipv4Addr::UNSPECIFIED.to_string(),
+                ..conf.clone()
+            };
+            return bind(&v4, port);
+        }
+    }
+    if !conf.bind_device.is_empty() {
+        bind_device(&socket, &addr, conf)?;
+    }
+    if conf.recv_buffer_size > 0 {
+        socket.set_recv_buffer_size(conf.recv_buffer_size)?;
+    }
+    if conf.send_buffer_size > 0 {
+        socket.set_send_buffer_size(conf.send_buffer_size)?;
+    }
+    if conf.dscp > 0 {
+        set_dscp(&socket, &addr, conf)?;
+    }
+    if conf.pmtud {
+        set_dont_fragment(&socket, &addr, conf)?;
+    }
+    if conf.kernel_timestamps {
+        timestamp::enable(&socket)?;
+    }
+    // Lets several consumers on one host receive the same multicast or broadcast traffic.
+    if !conf.multicast_group.is_empty() || conf.broadcast {
+        socket.set_reuse_address(true)?;
+    }
+    socket.bind(&addr.into())?;
+
+    Ok(socket.into())
+}
+
+/// `addr` in the form a socket bound to `local` sends to: IPv4 addresses become IPv4-mapped IPv6
+/// ones for IPv6 sockets, which Windows and macOS require of dual-stack sockets and Linux
+/// accepts, and IPv4-mapped addresses become plain IPv4 ones for IPv4 sockets.
+pub(crate) fn for_socket(local: SocketAddr, addr: SocketAddr) -> SocketAddr {
+    match (local, addr) {
+        (SocketAddr::V6(_), SocketAddr::V4(v4)) => {
+            SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+        }
+        (SocketAddr::V4(_), SocketAddr::V6(v6)) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr,
+        },
+        _ => addr,
+    }
+}
+
+/// Resolves `dest` for a socket bound to `local`, preferring addresses of the socket's own
+/// family, as `for_socket` gives them.
+pub(crate) fn resolve_for<A: ToSocketAddrs>(local: SocketAddr, dest: A) -> io::Result<SocketAddr> {
+    let addrs: Vec<SocketAddr> = dest.to_socket_addrs()?.collect();
+    addrs
+        .iter()
+        .find(|addr| addr.is_ipv6() == local.is_ipv6())
+        .or(addrs.first())
+        .map(|&addr| for_socket(local, addr))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No address to send to"))
+}
+
+// DSCP occupies the upper six bits of the IPv4 TOS and IPv6 traffic class bytes. Dual-stack
+// sockets send IPv4 packets too, so both are set on them.
+fn set_dscp(socket: &Socket, addr: &SocketAddr, conf: &Config) -> io::Result<()> {
+    if conf.dscp > MAX_DSCP {
+        return Err(Error::config(format!(
+            "DSCP must be between 0 and {}: {}",
+            MAX_DSCP, conf.dscp
+        ))
+        .into());
+    }
+
+    let tos = u32::from(conf.dscp) << 2;
+    if addr.is_ipv4() {
+        return socket.set_tos_v4(tos);
+    }
+
+    socket.set_tclass_v6(tos)?;
+    if conf.dual_stack {
+        socket.set_tos_v4(tos)?;
+    }
+    Ok(())
+}
+
+// Only traffic through the device is received, and sends leave through it whatever the route.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_device(socket: &Socket, _: &SocketAddr, conf: &Config) -> io::Result<()> {
+    socket.bind_device(Some(conf.bind_device.as_bytes()))
+}
+
+#[cfg(target_vendor = "apple")]
+fn bind_device(socket: &Socket, addr: &SocketAddr, conf: &Config) -> io::Result<()> {
+    let name = std::ffi::CString::new(conf.bind_device.as_str())
+        .map_err(|_| Error::config(format!("Invalid interface name: {}", conf.bind_device)))?;
+    // SAFETY: `name` is a NUL-terminated string living for the duration of the call.
+    let index = std::num::NonZeroU32::new(unsafe { libc::if_nametoindex(name.as_ptr()) })
+        .ok_or_else(|| Error::config(format!("No such interface: {}", conf.bind_device)))?;
+    if addr.is_ipv4() {
+        return socket.bind_device_by_index_v4(Some(index));
+    }
+    socket.bind_device_by_index_v6(Some(index))?;
+    if conf.dual_stack {
+        socket.bind_device_by_index_v4(Some(index))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_vendor = "apple")))]
+fn bind_device(_: &Socket, _: &SocketAddr, conf: &Config) -> io::Result<()> {
+    Err(Error::config(format!(
+        "Sockets cannot be bound to an interface on this platform: {}",
+        conf.bind_device
+    ))
+    .into())
+}
+
+// Path MTU probes only tell something if oversized datagrams are dropped rather than
+// fragmented, so the kernel is told to set DF and ignore its own path MTU estimate.
+#[cfg(target_os = "linux")]
+fn set_dont_fragment(socket: &Socket, addr: &SocketAddr, conf: &Config) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let set = |level, name, value: libc::c_int| {
+        // SAFETY: the option value is a c_int living for the duration of the call.
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        match result {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    };
+    let v4 = || {
+        set(
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_PROBE,
+        )
+    };
+    if addr.is_ipv4() {
+        return v4();
+    }
+
+    set(
+        libc::IPPROTO_IPV6,
+        libc::IPV6_MTU_DISCOVER,
+        libc::IPV6_PMTUDISC_PROBE,
+    )?;
+    if conf.dual_stack {
+        v4()?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_dont_fragment(_: &Socket, _: &SocketAddr, _: &Config) -> io::Result<()> {
+    debug!(target: TARGET, "DF cannot be set on this platform, path MTU probes may be fragmented");
+    Ok(())
+}
+
+/// Xorshift64* generator behind the simulated links' losses, kept out of `std`'s randomness so
+/// a seed replays a run exactly.
+#[derive(Debug, Clone)]
+pub(crate) struct Xorshift(u64);
+
+impl Xorshift {
+    pub(crate) fn new(seed: u64) -> Xorshift {
+        // Xorshift never leaves zero.
+        Xorshift(seed.max(1))
+    }
+
+    /// A number in [0, 1).
+    pub(crate) fn unit(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// A number in [0, `n`), for `n` above zero.
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        ((self.unit() * n as f64) as usize).min(n - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_config_defaults_to_udp() -> io::Result<()> {
+        let server_conf = Config {
+            port: 8081,
+            ..Default::default()
+        };
+        let server = udp::Server::init(&server_conf)?;
+
+        let client_conf = Config {
+            host: "::1".to_string(),
+            port: 8081,
+            ..Default::default()
+        };
+        let client = <dyn Transport>::from_config(&client_conf)?;
+        assert_eq!(client.peer_addr()?, "[::1]:8081".parse().unwrap());
+
+        client.send(b"ping")?;
+        let mut buffer = [0u8; 16];
+        let (bytes_received, client_addr) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..bytes_received], b"ping");
+        assert_eq!(client_addr.port(), client.local_addr()?.port());
+
+        server.send_to(b"pong", client_addr)?;
+        let bytes_received = client.receive(&mut buffer)?;
+        assert_eq!(&buffer[..bytes_received], b"pong");
+
+        client.close();
+        server.close();
+
+        Ok(())
+    }
+
+    #[test]
+    fn receive_timeouts_fail_with_timeout_and_leave_the_read_timeout() -> io::Result<()> {
+        let client = <dyn Transport>::from_config(&Config {
+            host: "127.0.0.1".to_string(),
+            port: 8127,
+            bind_host: "127.0.0.1".to_string(),
+            ..Default::default()
+        })?;
+        client.set_read_timeout(Some(Duration::from_millis(10)))?;
+        let mut buffer = [0u8; 16];
+
+        let started = Instant::now();
+        let e = client.receive_timeout(&mut buffer, Duration::from_millis(50));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        let e = e.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert!(matches!(Error::from(e), Error::Timeout));
+        assert!(matches!(
+            client
+                .receive_until(&mut buffer, started)
+                .map_err(Error::from),
+            Err(Error::Timeout)
+        ));
+
+        let started = Instant::now();
+        assert!(is_timeout(&client.receive(&mut buffer).unwrap_err()));
+        assert!(started.elapsed() < Duration::from_millis(50));
+        Ok(())
+    }
+
+    #[test]
+    fn bind_honors_config() -> io::Result<()> {
+        let conf = Config {
+            port: 8085,
+            bind_host: "127.0.0.1".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            bind_server(&conf)?.local_addr()?,
+            "127.0.0.1:8085".parse().unwrap()
+        );
+
+        let conf = Config {
+            bind_host: "::1".to_string(),
+            bind_port: 8086,
+            ..Default::default()
+        };
+        assert_eq!(
+            bind_client(&conf)?.local_addr()?,
+            "[::1]:8086".parse().unwrap()
+        );
+        assert_eq!(bind_server(&conf)?.local_addr()?.port(), 8086);
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn sockets_bind_to_the_configured_device() -> io::Result<()> {
+        let conf = Config {
+            bind_host: "127.0.0.1".to_string(),
+            bind_device: "lo".to_string(),
+            ..Default::default()
+        };
+        let socket = Socket::from(bind_client(&conf)?);
+        assert_eq!(socket.device()?.as_deref(), Some(&b"lo"[..]));
+
+        let missing = Config {
+            bind_device: "crumb-missing0".to_string(),
+            ..conf
+        };
+        assert!(bind_client(&missing).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn v6_only_rejects_ipv4_peers() -> io::Result<()> {
+        let conf = Config {
+            dual_stack: false,
+            ..Default::default()
+        };
+        let socket = bind_client(&conf)?;
+        assert!(socket.connect("127.0.0.1:8087").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn destinations_match_the_socket_family() -> io::Result<()> {
+        let v6: SocketAddr = "[::]:1".parse().unwrap();
+        let v4: SocketAddr = "0.0.0.0:1".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:10.0.0.1]:80".parse().unwrap();
+        let plain: SocketAddr = "10.0.0.1:80".parse().unwrap();
+        assert_eq!(for_socket(v6, plain), mapped);
+        assert_eq!(for_socket(v4, mapped), plain);
+        assert_eq!(for_socket(v6, mapped), mapped);
+        let loopback: SocketAddr = "[::1]:80".parse().unwrap();
+        assert_eq!(for_socket(v4, loopback), loopback);
+
+        assert_eq!(resolve_for(v6, "10.0.0.1:80")?, mapped);
+        assert_eq!(
+            resolve_for(v4, ("localhost", 80))?.ip(),
+            Ipv4Addr::LOCALHOST
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn socket_options_applied() -> io::Result<()> {
+        let conf = Config {
+            recv_buffer_size: 256 * 1024,
+            send_buffer_size: 128 * 1024,
+            dscp: 46,
+            ..Default::default()
+        };
+        let socket = bind_client(&conf)?;
+        let sock = socket2::SockRef::from(&socket);
+
+        // The kernel may round buffer sizes up (Linux doubles them for bookkeeping).
+        assert!(sock.recv_buffer_size()? >= 256 * 1024);
+        assert!(sock.send_buffer_size()? >= 128 * 1024);
+        assert_eq!(sock.tclass_v6()?, 46 << 2);
+        assert_eq!(sock.tos_v4()?, 46 << 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dscp_out_of_range() {
+        let conf = Config {
+            dscp: 64,
+            ..Default::default()
+        };
+        let err = bind_client(&conf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn bad_bind_host() {
+        let conf = Config {
+            bind_host: "localhost".to_string(),
+            ..Default::default()
+        };
+        let err = bind_client(&conf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,177 @@
+use super::MAX_DATAGRAM_SIZE;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Idle buffers a default pool keeps for reuse.
+const DEFAULT_CAPACITY: usize = 64;
+
+/// Receive buffers that are handed back for reuse when dropped, so receiving at a high rate
+/// does not allocate per datagram.
+///
+/// Cloning a pool yields a handle to the same buffers, so datagrams received on one thread can
+/// be dropped on another and still return to it.
+#[derive(Clone)]
+pub struct BufferPool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    buffer_size: usize,
+    capacity: usize,
+    free: Mutex<Vec<Vec<u8>>>,
+    allocated: AtomicU64,
+}
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("buffer_size", &self.inner.buffer_size)
+            .field("capacity", &self.inner.capacity)
+            .field("allocated", &self.allocated())
+            .finish_non_exhaustive()
+    }
+}
+
+impl BufferPool {
+    /// A pool of `buffer_size` byte buffers, keeping up to `capacity` of them idle. Datagrams
+    /// longer than `buffer_size` are truncated, as with `receive`.
+    pub fn new(buffer_size: usize, capacity: usize) -> BufferPool {
+        BufferPool {
+            inner: Arc::new(Inner {
+                buffer_size,
+                capacity,
+                free: Mutex::new(Vec::with_capacity(capacity)),
+                allocated: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Buffers allocated so far. Once a consumer reaches a steady state this stops growing.
+    pub fn allocated(&self) -> u64 {
+        self.inner.allocated.load(Ordering::Relaxed)
+    }
+
+    /// Takes a buffer, lets `receive` fill it and keeps the number of bytes it reports. On
+    /// failure the buffer goes straight back to the pool.
+    pub fn receive_with<F>(&self, receive: F) -> io::Result<PooledBuffer>
+    where
+        F: FnOnce(&mut [u8]) -> io::Result<usize>,
+    {
+        let mut buffer = self.take();
+        let len = receive(&mut buffer.buffer)?;
+        buffer.len = len.min(buffer.buffer.len());
+        Ok(buffer)
+    }
+
+    fn take(&self) -> PooledBuffer {
+        let free = self
+            .inner
+            .free
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .pop();
+        let buffer = free.unwrap_or_else(|| {
+            self.inner.allocated.fetch_add(1, Ordering::Relaxed);
+            vec![0u8; self.inner.buffer_size]
+        });
+        PooledBuffer {
+            buffer,
+            len: 0,
+            pool: self.inner.clone(),
+        }
+    }
+}
+
+impl Default for BufferPool {
+    /// Buffers large enough for any UDP datagram.
+    fn default() -> BufferPool {
+        BufferPool::new(MAX_DATAGRAM_SIZE, DEFAULT_CAPACITY)
+    }
+}
+
+/// A received datagram, borrowed from a `BufferPool` until dropped.
+pub struct PooledBuffer {
+    buffer: Vec<u8>,
+    len: usize,
+    pool: Arc<Inner>,
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+}
+
+impl AsRef<[u8]> for PooledBuffer {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for PooledBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PooledBuffer")
+            .field("len", &self.len)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let mut free = self.pool.free.lock().unwrap_or_else(|e| e.into_inner());
+        if free.len() < self.pool.capacity {
+            free.push(mem::take(&mut self.buffer));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(data: &'static [u8]) -> impl FnOnce(&mut [u8]) -> io::Result<usize> {
+        move |buffer| {
+            buffer[..data.len()].copy_from_slice(data);
+            Ok(data.len())
+        }
+    }
+
+    #[test]
+    fn buffers_are_reused_once_dropped() -> io::Result<()> {
+        let pool = BufferPool::new(16, 2);
+        for _ in 0..10 {
+            let buffer = pool.receive_with(fill(b"hello"))?;
+            assert_eq!(&buffer[..], b"hello");
+        }
+        assert_eq!(pool.allocated(), 1);
+
+        let held: Vec<_> = (0..3)
+            .map(|_| pool.receive_with(fill(b"x")))
+            .collect::<io::Result<_>>()?;
+        assert_eq!(pool.allocated(), 3);
+        drop(held);
+        let _a = pool.receive_with(fill(b"x"))?;
+        let _b = pool.receive_with(fill(b"x"))?;
+        let _c = pool.receive_with(fill(b"x"))?;
+        // Only `capacity` buffers were kept when the three were dropped.
+        assert_eq!(pool.allocated(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn failed_receives_return_the_buffer() {
+        let pool = BufferPool::new(16, 2);
+        let err = pool
+            .receive_with(|_| Err(io::ErrorKind::WouldBlock.into()))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(pool.receive_with(fill(b"x")).is_ok());
+        assert_eq!(pool.allocated(), 1);
+    }
+}
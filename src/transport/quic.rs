@@ -0,0 +1,1033 @@
+use super::{bind_client, bind_server, check_size, timed_out, Transport};
+use crate::error::Error;
+use crate::security::{Acl, RateLimiter};
+use crate::util::config::Config;
+use quinn::crypto::rustls::{HandshakeData, QuicClientConfig, QuicServerConfig};
+use quinn::{Connection, Endpoint, EndpointConfig, TokioRuntime, VarInt};
+use rustls::client::{ClientSessionMemoryCache, ClientSessionStore, Resumption};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+use tokio::task::JoinSet;
+use tracing::{debug, debug_span, trace, Instrument, Span};
+
+// aws-lc-rs is preferred when both providers are enabled; ring needs only a C compiler, which
+// makes it the easier choice when cross-compiling for musl or ARM.
+#[cfg(feature = "aws-lc-rs")]
+use rustls::crypto::aws_lc_rs as provider;
+#[cfg(all(feature = "ring", not(feature = "aws-lc-rs")))]
+use rustls::crypto::ring as provider;
+#[cfg(not(any(feature = "aws-lc-rs", feature = "ring")))]
+compile_error!("the `quic` feature needs a TLS crypto provider: enable `aws-lc-rs` or `ring`");
+
+const TARGET: &str = "crumb::transport::quic";
+
+// Largest message accepted on a reliable stream. This matches the largest UDP payload so the
+// same messages can be carried by either transport.
+const MAX_MESSAGE_SIZE: usize = 65_507;
+
+// Upper bound on how long close() waits for in-flight reliable messages to be acknowledged.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How often sessions check whether their traffic keys are due for an update.
+const REKEY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// TLS sessions remembered for resumption, shared by every client in the process so reconnects
+// skip the full handshake.
+const SESSION_CACHE_SIZE: usize = 256;
+
+type Inbox = mpsc::Receiver<(Vec<u8>, SocketAddr)>;
+type InboxSender = mpsc::Sender<(Vec<u8>, SocketAddr)>;
+type Connections = Arc<Mutex<HashMap<SocketAddr, Connection>>>;
+
+/// What the TLS handshake of a QUIC session negotiated, for logging it or refusing sessions
+/// that fall short of a security policy.
+///
+/// quinn does not report the cipher suite. Every suite this build offers is a TLS 1.3 AEAD
+/// suite from the crypto provider's defaults.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// Always TLS 1.3, the only version QUIC runs over.
+    pub protocol_version: rustls::ProtocolVersion,
+    /// The application protocol agreed through ALPN, if `alpn` offered any.
+    pub alpn: Option<Vec<u8>>,
+    /// The server name the client asked for. Only servers see it.
+    pub server_name: Option<String>,
+    /// The certificate chain the peer presented, leaf first. Empty on servers, as clients do
+    /// not authenticate.
+    pub peer_certificates: Vec<CertificateDer<'static>>,
+}
+
+impl TlsInfo {
+    fn of(connection: &Connection) -> TlsInfo {
+        let handshake = connection
+            .handshake_data()
+            .and_then(|data| data.downcast::<HandshakeData>().ok());
+        let peer_certificates = connection
+            .peer_identity()
+            .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok())
+            .map(|certs| *certs)
+            .unwrap_or_default();
+        TlsInfo {
+            protocol_version: rustls::ProtocolVersion::TLSv1_3,
+            alpn: handshake.as_ref().and_then(|data| data.protocol.clone()),
+            server_name: handshake.and_then(|data| data.server_name),
+            peer_certificates,
+        }
+    }
+}
+
+/// QUIC client with the same blocking API as `udp::Client`.
+///
+/// Unless `delivery` is at-most-once, every message is sent on its own unidirectional stream and
+/// is retransmitted by QUIC until acknowledged, once. Otherwise messages are sent as QUIC datagrams,
+/// which are encrypted but may be lost.
+///
+/// Reconnecting to a server resumes the previous TLS session when `tls_resumption` is set, and
+/// traffic keys are updated after `rekey_interval` or `rekey_bytes`, whichever comes first.
+///
+/// QUIC retransmits lost handshake packets itself, in packets of 1200 bytes that every QUIC
+/// path must carry, so a small path MTU cannot stall the handshake. A peer that stops answering
+/// can, and `init` gives up after `handshake_timeout` with `ErrorKind::TimedOut`, carrying
+/// `Error::Timeout`, so the caller can retry.
+pub struct Client {
+    runtime: Runtime,
+    endpoint: Endpoint,
+    connection: Connection,
+    reliable: bool,
+    inbox: Mutex<Inbox>,
+    read_timeout: Mutex<Option<Duration>>,
+    pending: Mutex<JoinSet<()>>,
+    key_updates: Arc<AtomicU64>,
+    span: Span,
+}
+
+impl Client {
+    pub fn init(conf: &Config) -> io::Result<Client> {
+        let addr = resolve((conf.host.as_str(), conf.port))?;
+        let span = debug_span!(target: TARGET, "client", peer = %addr);
+        let _enter = span.enter();
+
+        let client_config = client_config(conf)?;
+        let runtime = runtime()?;
+        let _guard = runtime.enter();
+
+        let mut endpoint = endpoint(bind_client(conf)?, None)?;
+        endpoint.set_default_client_config(client_config);
+
+        debug!(target: TARGET, "handshake started");
+        let connecting = endpoint
+            .connect(addr, &conf.host)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let connection = runtime
+            .block_on(tokio::time::timeout(conf.handshake_timeout, connecting))
+            .map_err(|_| Error::Timeout)?
+            .map_err(|e| match e {
+                quinn::ConnectionError::TimedOut => Error::Timeout.into(),
+                e => io::Error::new(io::ErrorKind::ConnectionRefused, e),
+            })?;
+        debug!(
+            target: TARGET,
+            local_addr = ?endpoint.local_addr(),
+            alpn = ?alpn(&connection),
+            "client session opened"
+        );
+
+        let (inbox_sender, inbox) = mpsc::channel();
+        spawn_readers(connection.clone(), inbox_sender, None);
+        let key_updates = Arc::new(AtomicU64::new(0));
+        runtime.spawn(
+            rekey(connection.clone(), Rekey::from(conf), key_updates.clone())
+                .instrument(span.clone()),
+        );
+
+        drop(_enter);
+        Ok(Client {
+            runtime,
+            endpoint,
+            connection,
+            reliable: conf.delivery.is_reliable(),
+            inbox: Mutex::new(inbox),
+            read_timeout: Mutex::default(),
+            pending: Mutex::default(),
+            key_updates,
+            span,
+        })
+    }
+
+    /// What the handshake with the server negotiated.
+    pub fn tls_info(&self) -> TlsInfo {
+        TlsInfo::of(&self.connection)
+    }
+
+    /// Number of traffic key updates this client has initiated.
+    pub fn key_updates(&self) -> u64 {
+        self.key_updates.load(Ordering::Relaxed)
+    }
+
+    pub fn send(&self, data: &[u8]) -> io::Result<usize> {
+        let _enter = self.span.enter();
+        send_message(
+            &self.runtime,
+            &self.connection,
+            self.reliable,
+            &self.pending,
+            data,
+        )
+    }
+
+    pub fn receive(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let timeout = *lock(&self.read_timeout);
+        self.receive_within(buffer, timeout)
+    }
+
+    /// Like `receive`, but fails with `Error::Timeout` if no message arrives within `timeout`.
+    pub fn receive_timeout(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        timed_out(self.receive_within(buffer, Some(timeout)))
+    }
+
+    fn receive_within(&self, buffer: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        let _enter = self.span.enter();
+        let (message, _) = next_message(&lock(&self.inbox), timeout, "QUIC session closed")?;
+        trace!(target: TARGET, bytes = message.len(), "receive");
+        Ok(copy_truncated(&message, buffer))
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *lock(&self.read_timeout) = timeout;
+        Ok(())
+    }
+
+    /// Waits (up to a bound) for reliable messages to be acknowledged, then closes the session
+    /// with a CONNECTION_CLOSE that tells the server to release it. Dropping the client does the
+    /// same.
+    pub fn close(self) {
+        drop(self);
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let _enter = self.span.enter();
+        let pending = std::mem::take(&mut *lock(&self.pending));
+        self.runtime.block_on(async {
+            drain(pending).await;
+            self.connection.close(VarInt::from_u32(0), b"");
+            self.endpoint.wait_idle().await;
+        });
+        debug!(target: TARGET, "client session closed");
+    }
+}
+
+impl Transport for Client {
+    fn init(conf: &Config) -> io::Result<Client> {
+        Client::init(conf)
+    }
+
+    fn send(&self, data: &[u8]) -> io::Result<usize> {
+        Client::send(self, data)
+    }
+
+    fn receive(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        Client::receive(self, buffer)
+    }
+
+    fn receive_timeout(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        Client::receive_timeout(self, buffer, timeout)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Client::set_read_timeout(self, timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.endpoint.local_addr()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.connection.remote_address())
+    }
+
+    fn close(self: Box<Self>) {
+        Client::close(*self)
+    }
+}
+
+/// QUIC server with the same blocking API as `udp::Server`.
+///
+/// Sessions are accepted in the background. `receive_from` yields messages from every session
+/// and `send_to` replies to the session established from the given address. With
+/// `address_validation` set, a client first has to echo a Retry token bound to its address, so
+/// handshakes from spoofed addresses never reach the TLS layer. Connection attempts rejected by
+/// the access control list, or beyond their source's `peer_handshake_rate`, are ignored before
+/// either happens, and messages beyond a source's `peer_packet_rate` are dropped.
+///
+/// Listing several protocols in `alpn` lets clients of different protocol versions share the
+/// port. Each session gets the first protocol in the server's list that its client offers, which
+/// `tls_info` reports, so the application can tell them apart.
+pub struct Server {
+    runtime: Runtime,
+    endpoint: Endpoint,
+    admission: Arc<Admission>,
+    reliable: bool,
+    connections: Connections,
+    inbox: Mutex<Inbox>,
+    read_timeout: Mutex<Option<Duration>>,
+    pending: Mutex<JoinSet<()>>,
+    key_updates: Arc<AtomicU64>,
+    span: Span,
+}
+
+impl Server {
+    pub fn init(conf: &Config) -> io::Result<Server> {
+        let socket = bind_server(conf)?;
+        let span = debug_span!(target: TARGET, "server", bind = ?socket.local_addr());
+        let _enter = span.enter();
+
+        let server_config = server_config(conf)?;
+        let runtime = runtime()?;
+        let _guard = runtime.enter();
+
+        let endpoint = endpoint(socket, Some(server_config))?;
+        debug!(target: TARGET, "server listening");
+
+        let (inbox_sender, inbox) = mpsc::channel();
+        let connections = Connections::default();
+        let key_updates = Arc::new(AtomicU64::new(0));
+        let admission = Arc::new(Admission {
+            acl: Acl::from_config(conf)?,
+            handshakes: RateLimiter::new(conf.peer_handshake_rate, conf.peer_ban),
+            packets: Arc::new(RateLimiter::new(conf.peer_packet_rate, conf.peer_ban)),
+            validate_address: conf.address_validation,
+            handshake_timeout: conf.handshake_timeout,
+        });
+        runtime.spawn(
+            accept(
+                endpoint.clone(),
+                admission.clone(),
+                connections.clone(),
+                inbox_sender,
+                Rekey::from(conf),
+                key_updates.clone(),
+            )
+            .instrument(span.clone()),
+        );
+
+        drop(_enter);
+        Ok(Server {
+            runtime,
+            endpoint,
+            admission,
+            reliable: conf.delivery.is_reliable(),
+            connections,
+            inbox: Mutex::new(inbox),
+            read_timeout: Mutex::default(),
+            pending: Mutex::default(),
+            key_updates,
+            span,
+        })
+    }
+
+    /// What the handshake of the session established from `peer` negotiated, or `None` if there
+    /// is no such session.
+    pub fn tls_info(&self, peer: SocketAddr) -> Option<TlsInfo> {
+        lock(&self.connections).get(&peer).map(TlsInfo::of)
+    }
+
+    /// Number of traffic key updates initiated across all of this server's sessions.
+    pub fn key_updates(&self) -> u64 {
+        self.key_updates.load(Ordering::Relaxed)
+    }
+
+    /// The access control list applied to connection attempts.
+    pub fn acl(&self) -> &Acl {
+        &self.admission.acl
+    }
+
+    /// The per-source limit applied to connection attempts, with its throttling counters.
+    pub fn handshake_limit(&self) -> &RateLimiter {
+        &self.admission.handshakes
+    }
+
+    /// The per-source limit applied to received messages, with its throttling counters.
+    pub fn rate_limit(&self) -> &RateLimiter {
+        &self.admission.packets
+    }
+
+    /// Registers `hook` to be called with the address of every connection attempt the access
+    /// control list rejects. See `Acl::on_unauthorized`.
+    pub fn on_unauthorized<F>(&self, hook: F)
+    where
+        F: Fn(SocketAddr) + Send + Sync + 'static,
+    {
+        self.admission.acl.on_unauthorized(hook);
+    }
+
+    /// Loads the certificate chain and key from `conf.pem_path` for handshakes accepted from
+    /// now on. Established sessions keep their keys. If loading fails the current certificate
+    /// stays in use.
+    ///
+    /// Session tickets issued before the reload are no longer accepted, so those clients fall
+    /// back to a full handshake once.
+    pub fn reload_certificates(&self, conf: &Config) -> io::Result<()> {
+        let _enter = self.span.enter();
+        let server_config = server_config(conf)?;
+        self.endpoint.set_server_config(Some(server_config));
+        debug!(target: TARGET, pem_path = %conf.pem_path, "certificates reloaded");
+        Ok(())
+    }
+
+    pub fn send_to<A: ToSocketAddrs>(&self, data: &[u8], dest: A) -> io::Result<usize> {
+        let _enter = self.span.enter();
+        let dest = resolve(dest)?;
+        let connection = lock(&self.connections).get(&dest).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("No QUIC session with {}", dest),
+            )
+        })?;
+        send_message(
+            &self.runtime,
+            &connection,
+            self.reliable,
+            &self.pending,
+            data,
+        )
+    }
+
+    pub fn receive_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let _enter = self.span.enter();
+        let timeout = *lock(&self.read_timeout);
+        let (message, peer) = next_message(&lock(&self.inbox), timeout, "QUIC endpoint closed")?;
+        trace!(target: TARGET, bytes = message.len(), %peer, "receive_from");
+        Ok((copy_truncated(&message, buffer), peer))
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *lock(&self.read_timeout) = timeout;
+        Ok(())
+    }
+
+    /// Closes every session as `Client::close` does. Dropping the server does the same.
+    pub fn close(self) {
+        drop(self);
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _enter = self.span.enter();
+        let pending = std::mem::take(&mut *lock(&self.pending));
+        self.runtime.block_on(async {
+            drain(pending).await;
+            self.endpoint.close(VarInt::from_u32(0), b"");
+            self.endpoint.wait_idle().await;
+        });
+        debug!(target: TARGET, "server closed");
+    }
+}
+
+// Waits for the next message from the background readers, for at most `timeout` if set.
+fn next_message(
+    inbox: &Inbox,
+    timeout: Option<Duration>,
+    closed: &'static str,
+) -> io::Result<(Vec<u8>, SocketAddr)> {
+    match timeout {
+        Some(timeout) => inbox.recv_timeout(timeout).map_err(|e| match e {
+            mpsc::RecvTimeoutError::Timeout => {
+                io::Error::new(io::ErrorKind::TimedOut, "QUIC receive timed out")
+            }
+            mpsc::RecvTimeoutError::Disconnected => {
+                io::Error::new(io::ErrorKind::NotConnected, closed)
+            }
+        }),
+        None => inbox
+            .recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, closed)),
+    }
+}
+
+// What a server checks before accepting a connection attempt or a message.
+struct Admission {
+    acl: Acl,
+    handshakes: RateLimiter,
+    packets: Arc<RateLimiter>,
+    validate_address: bool,
+    handshake_timeout: Duration,
+}
+
+async fn accept(
+    endpoint: Endpoint,
+    admission: Arc<Admission>,
+    connections: Connections,
+    inbox: InboxSender,
+    policy: Rekey,
+    key_updates: Arc<AtomicU64>,
+) {
+    while let Some(incoming) = endpoint.accept().await {
+        let peer = incoming.remote_address();
+        if !admission.acl.admit(peer) || !admission.handshakes.admit(peer.ip(), Instant::now()) {
+            incoming.ignore();
+            continue;
+        }
+        // The Retry token is authenticated and bound to the client's address, so no state is
+        // kept until the client echoes it back from that address.
+        if admission.validate_address && !incoming.remote_address_validated() {
+            match incoming.retry() {
+                Ok(()) => trace!(target: TARGET, %peer, "sent retry"),
+                Err(e) => debug!(target: TARGET, %peer, error = %e, "retry failed"),
+            }
+            continue;
+        }
+
+        let connections = connections.clone();
+        let inbox = inbox.clone();
+        let packets = admission.packets.clone();
+        let key_updates = key_updates.clone();
+        let handshake_timeout = admission.handshake_timeout;
+        tokio::spawn(
+            async move {
+                // Dropping a stalled handshake abandons it.
+                let connection = match tokio::time::timeout(handshake_timeout, incoming).await {
+                    Ok(Ok(connection)) => connection,
+                    Ok(Err(e)) => {
+                        debug!(target: TARGET, error = %e, "handshake failed");
+                        return;
+                    }
+                    Err(_) => {
+                        debug!(target: TARGET, %peer, "handshake timed out");
+                        return;
+                    }
+                };
+                let peer = connection.remote_address();
+                debug!(target: TARGET, %peer, alpn = ?alpn(&connection), "session opened");
+
+                lock(&connections).insert(peer, connection.clone());
+                spawn_readers(connection.clone(), inbox, Some(packets));
+                tokio::spawn(rekey(connection.clone(), policy, key_updates).in_current_span());
+
+                let reason = connection.closed().await;
+                lock(&connections).remove(&peer);
+                debug!(target: TARGET, %peer, %reason, "session closed");
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+// Forwards every stream and datagram received on `connection` to `inbox`. Must be called from
+// within the runtime.
+fn spawn_readers(connection: Connection, inbox: InboxSender, limit: Option<Arc<RateLimiter>>) {
+    let peer = connection.remote_address();
+    let admit = move || {
+        limit
+            .as_ref()
+            .is_none_or(|limit| limit.admit(peer.ip(), Instant::now()))
+    };
+    let stream_admit = admit.clone();
+
+    let streams = connection.clone();
+    let stream_inbox = inbox.clone();
+    tokio::spawn(
+        async move {
+            while let Ok(mut stream) = streams.accept_uni().await {
+                match stream.read_to_end(MAX_MESSAGE_SIZE).await {
+                    Ok(_) if !stream_admit() => {}
+                    Ok(message) => {
+                        if stream_inbox.send((message, peer)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => debug!(target: TARGET, %peer, error = %e, "stream read failed"),
+                }
+            }
+        }
+        .in_current_span(),
+    );
+
+    tokio::spawn(
+        async move {
+            while let Ok(datagram) = connection.read_datagram().await {
+                if !admit() {
+                    continue;
+                }
+                if inbox.send((datagram.to_vec(), peer)).is_err() {
+                    break;
+                }
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+/// When a session's traffic keys are updated. Zero disables the corresponding trigger.
+#[derive(Debug, Clone, Copy)]
+struct Rekey {
+    interval: Duration,
+    bytes: u64,
+}
+
+impl From<&Config> for Rekey {
+    fn from(conf: &Config) -> Rekey {
+        Rekey {
+            interval: conf.rekey_interval,
+            bytes: conf.rekey_bytes,
+        }
+    }
+}
+
+// Updates the traffic keys of `connection` whenever `policy` says they are due, until the
+// connection closes. QUIC also updates keys on its own as they near their AEAD usage limits.
+async fn rekey(connection: Connection, policy: Rekey, key_updates: Arc<AtomicU64>) {
+    if policy.interval.is_zero() && policy.bytes == 0 {
+        return;
+    }
+    let check_every = match policy.interval.is_zero() {
+        true => REKEY_CHECK_INTERVAL,
+        false => policy.interval.min(REKEY_CHECK_INTERVAL),
+    };
+
+    let mut updated_at = tokio::time::Instant::now();
+    let mut sent_at_update = connection.stats().udp_tx.bytes;
+    while tokio::time::timeout(check_every, connection.closed())
+        .await
+        .is_err()
+    {
+        let sent = connection.stats().udp_tx.bytes;
+        let time_due = !policy.interval.is_zero() && updated_at.elapsed() >= policy.interval;
+        let bytes_due = policy.bytes > 0 && sent - sent_at_update >= policy.bytes;
+        if time_due || bytes_due {
+            connection.force_key_update();
+            key_updates.fetch_add(1, Ordering::Relaxed);
+            updated_at = tokio::time::Instant::now();
+            sent_at_update = sent;
+            debug!(target: TARGET, peer = %connection.remote_address(), "traffic keys updated");
+        }
+    }
+}
+
+fn send_message(
+    runtime: &Runtime,
+    connection: &Connection,
+    reliable: bool,
+    pending: &Mutex<JoinSet<()>>,
+    data: &[u8],
+) -> io::Result<usize> {
+    if !reliable {
+        // None when the peer takes no datagrams, which send_datagram reports itself.
+        if let Some(max) = connection.max_datagram_size() {
+            check_size(data.len(), max)?;
+        }
+        connection
+            .send_datagram(data.to_vec().into())
+            .map_err(io::Error::other)?;
+        trace!(target: TARGET, bytes = data.len(), "send datagram");
+        return Ok(data.len());
+    }
+
+    check_size(data.len(), MAX_MESSAGE_SIZE)?;
+    let mut stream = runtime.block_on(async {
+        let mut stream = connection.open_uni().await.map_err(io::Error::other)?;
+        stream.write_all(data).await.map_err(io::Error::other)?;
+        Ok::<_, io::Error>(stream)
+    })?;
+    stream.finish().map_err(io::Error::other)?;
+    trace!(target: TARGET, bytes = data.len(), "send stream");
+
+    // Keep the stream alive until the peer has read it so close() can wait for delivery.
+    let mut pending = lock(pending);
+    while pending.try_join_next().is_some() {}
+    pending.spawn_on(
+        async move {
+            let _ = stream.stopped().await;
+        },
+        runtime.handle(),
+    );
+
+    Ok(data.len())
+}
+
+async fn drain(mut pending: JoinSet<()>) {
+    let all_done = async { while pending.join_next().await.is_some() {} };
+    if tokio::time::timeout(CLOSE_TIMEOUT, all_done).await.is_err() {
+        debug!(target: TARGET, "gave up waiting for unacknowledged messages");
+    }
+}
+
+fn client_config(conf: &Config) -> io::Result<quinn::ClientConfig> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in load_certs(&conf.pem_path)? {
+        roots.add(cert).map_err(tls_error)?;
+    }
+
+    let mut crypto = rustls::ClientConfig::builder_with_provider(crypto_provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(tls_error)?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    crypto.resumption = match conf.tls_resumption {
+        true => Resumption::store(session_cache()),
+        false => Resumption::disabled(),
+    };
+    crypto.alpn_protocols = alpn_protocols(conf);
+    let crypto = QuicClientConfig::try_from(crypto).map_err(tls_error)?;
+
+    Ok(quinn::ClientConfig::new(Arc::new(crypto)))
+}
+
+// The server PEM file holds the certificate chain followed by the private key.
+fn server_config(conf: &Config) -> io::Result<quinn::ServerConfig> {
+    let certs = load_certs(&conf.pem_path)?;
+    let key =
+        PrivateKeyDer::from_pem_file(&conf.pem_path).map_err(|e| pem_error(&conf.pem_path, e))?;
+
+    let mut crypto = rustls::ServerConfig::builder_with_provider(crypto_provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(tls_error)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(tls_error)?;
+    // Stateless tickets, so resumption does not depend on the server's session cache size.
+    match conf.tls_resumption {
+        true => crypto.ticketer = provider::Ticketer::new().map_err(tls_error)?,
+        false => crypto.send_tls13_tickets = 0,
+    }
+    crypto.alpn_protocols = alpn_protocols(conf);
+    let crypto = QuicServerConfig::try_from(crypto).map_err(tls_error)?;
+
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+fn alpn_protocols(conf: &Config) -> Vec<Vec<u8>> {
+    conf.alpn
+        .split(',')
+        .map(str::trim)
+        .filter(|protocol| !protocol.is_empty())
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect()
+}
+
+fn session_cache() -> Arc<dyn ClientSessionStore> {
+    static CACHE: OnceLock<Arc<ClientSessionMemoryCache>> = OnceLock::new();
+    CACHE
+        .get_or_init(|| Arc::new(ClientSessionMemoryCache::new(SESSION_CACHE_SIZE)))
+        .clone()
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect())
+        .map_err(|e| pem_error(path, e))
+}
+
+fn crypto_provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(provider::default_provider())
+}
+
+// Must be called from within the runtime.
+fn endpoint(
+    socket: std::net::UdpSocket,
+    server_config: Option<quinn::ServerConfig>,
+) -> io::Result<Endpoint> {
+    socket.set_nonblocking(true)?;
+    Endpoint::new(
+        EndpointConfig::default(),
+        server_config,
+        socket,
+        Arc::new(TokioRuntime),
+    )
+}
+
+fn runtime() -> io::Result<Runtime> {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+}
+
+fn resolve<A: ToSocketAddrs>(addr: A) -> io::Result<SocketAddr> {
+    addr.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Address did not resolve to anything",
+        )
+    })
+}
+
+// The negotiated application protocol, for logging.
+fn alpn(connection: &Connection) -> Option<String> {
+    let protocol = TlsInfo::of(connection).alpn?;
+    Some(String::from_utf8_lossy(&protocol).into_owned())
+}
+
+fn copy_truncated(message: &[u8], buffer: &mut [u8]) -> usize {
+    let len = message.len().min(buffer.len());
+    buffer[..len].copy_from_slice(&message[..len]);
+    len
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn pem_error(path: &str, e: rustls::pki_types::pem::Error) -> io::Error {
+    Error::tls(format!("{}: {}", path, e)).into()
+}
+
+fn tls_error<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    Error::tls(e).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::config::{DeliveryMode, TransportType};
+    use rustls::pki_types::ServerName;
+
+    const TEST_CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/transport/.test-cert.pem");
+
+    fn round_trip(port: u16, delivery: DeliveryMode) -> io::Result<()> {
+        let server_conf = Config {
+            port,
+            delivery,
+            pem_path: TEST_CERT.to_string(),
+            ..Default::default()
+        };
+        let server = Server::init(&server_conf)?;
+
+        let client_conf = Config {
+            host: "127.0.0.1".to_string(),
+            port,
+            delivery,
+            transport_type: TransportType::Quic,
+            pem_path: TEST_CERT.to_string(),
+            ..Default::default()
+        };
+        let client = <dyn Transport>::from_config(&client_conf)?;
+        client.send(b"Hello, Server!")?;
+
+        let mut buffer = [0u8; 1024];
+        let (bytes_received, client_addr) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..bytes_received], b"Hello, Server!");
+
+        server.send_to(b"Hello, Client!", client_addr)?;
+        let bytes_received = client.receive(&mut buffer)?;
+        assert_eq!(&buffer[..bytes_received], b"Hello, Client!");
+
+        client.close();
+        server.close();
+
+        Ok(())
+    }
+
+    #[test]
+    fn reliable_round_trip() -> io::Result<()> {
+        round_trip(8082, DeliveryMode::ExactlyOnce)
+    }
+
+    #[test]
+    fn unreliable_round_trip() -> io::Result<()> {
+        round_trip(8083, DeliveryMode::AtMostOnce)
+    }
+
+    #[test]
+    fn handshakes_with_a_silent_peer_time_out() {
+        let silent = std::net::UdpSocket::bind("127.0.0.1:8123").unwrap();
+        let conf = Config {
+            host: "127.0.0.1".to_string(),
+            port: 8123,
+            pem_path: TEST_CERT.to_string(),
+            handshake_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        let e = Client::init(&conf).err().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert!(matches!(Error::from(e), Error::Timeout));
+        drop(silent);
+    }
+
+    #[test]
+    fn negotiated_tls_details_are_exposed() -> io::Result<()> {
+        let conf = Config {
+            host: "localhost".to_string(),
+            port: 8124,
+            pem_path: TEST_CERT.to_string(),
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        let client = Client::init(&conf)?;
+        let tls = client.tls_info();
+        assert_eq!(tls.protocol_version, rustls::ProtocolVersion::TLSv1_3);
+        assert_eq!(tls.alpn, None);
+        assert_eq!(tls.peer_certificates, load_certs(TEST_CERT)?);
+
+        client.send(b"hello")?;
+        let mut buffer = [0u8; 16];
+        let (_, client_addr) = server.receive_from(&mut buffer)?;
+        let tls = server.tls_info(client_addr).unwrap();
+        assert_eq!(tls.server_name.as_deref(), Some("localhost"));
+        assert!(tls.peer_certificates.is_empty());
+        assert_eq!(server.tls_info("127.0.0.1:1".parse().unwrap()), None);
+
+        client.close();
+        server.close();
+        Ok(())
+    }
+
+    #[test]
+    fn alpn_selects_a_protocol_each_session() -> io::Result<()> {
+        let conf = Config {
+            host: "localhost".to_string(),
+            port: 8125,
+            pem_path: TEST_CERT.to_string(),
+            alpn: "crumb/2, crumb/1".to_string(),
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        let mut buffer = [0u8; 16];
+        for (offered, selected) in [("crumb/1,crumb/2", "crumb/2"), ("crumb/1", "crumb/1")] {
+            let client = Client::init(&Config {
+                alpn: offered.to_string(),
+                ..conf.clone()
+            })?;
+            assert_eq!(client.tls_info().alpn.as_deref(), Some(selected.as_bytes()));
+            client.send(b"hello")?;
+            let (_, client_addr) = server.receive_from(&mut buffer)?;
+            let tls = server.tls_info(client_addr).unwrap();
+            assert_eq!(tls.alpn.as_deref(), Some(selected.as_bytes()));
+            client.close();
+        }
+
+        let refused = Client::init(&Config {
+            alpn: "other".to_string(),
+            ..conf
+        });
+        assert_eq!(
+            refused.err().map(|e| e.kind()),
+            Some(io::ErrorKind::ConnectionRefused)
+        );
+        server.close();
+        Ok(())
+    }
+
+    #[test]
+    fn reconnects_resume_the_tls_session() -> io::Result<()> {
+        let conf = Config {
+            host: "localhost".to_string(),
+            port: 8098,
+            pem_path: TEST_CERT.to_string(),
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        let server_name = ServerName::try_from("localhost").unwrap();
+
+        let mut buffer = [0u8; 16];
+        for _ in 0..2 {
+            let client = Client::init(&conf)?;
+            client.send(b"hello")?;
+            let (_, client_addr) = server.receive_from(&mut buffer)?;
+            server.send_to(b"hello", client_addr)?;
+            client.receive(&mut buffer)?;
+            client.close();
+
+            // Tickets arrive after the handshake; by now the round trip has delivered them.
+            assert!(session_cache().take_tls13_ticket(&server_name).is_some());
+        }
+
+        server.close();
+        Ok(())
+    }
+
+    #[test]
+    fn traffic_keys_are_updated_periodically() -> io::Result<()> {
+        let conf = Config {
+            host: "localhost".to_string(),
+            port: 8099,
+            pem_path: TEST_CERT.to_string(),
+            rekey_interval: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        let client = Client::init(&conf)?;
+
+        let mut buffer = [0u8; 16];
+        for _ in 0..4 {
+            client.send(b"hello")?;
+            let (bytes_received, _) = server.receive_from(&mut buffer)?;
+            assert_eq!(&buffer[..bytes_received], b"hello");
+            std::thread::sleep(Duration::from_millis(60));
+        }
+        assert!(client.key_updates() > 0);
+        assert!(server.key_updates() > 0);
+
+        client.close();
+        server.close();
+        Ok(())
+    }
+
+    #[test]
+    fn certificates_reload_for_new_handshakes() -> io::Result<()> {
+        let conf = Config {
+            port: 8107,
+            pem_path: TEST_CERT.to_string(),
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        let missing = Config {
+            pem_path: "does/not/exist.pem".to_string(),
+            ..conf.clone()
+        };
+        assert!(server.reload_certificates(&missing).is_err());
+        server.reload_certificates(&conf)?;
+
+        let client = Client::init(&conf)?;
+        client.send(b"hello")?;
+        let mut buffer = [0u8; 16];
+        let (bytes_received, _) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..bytes_received], b"hello");
+
+        client.close();
+        server.close();
+        Ok(())
+    }
+
+    #[test]
+    fn handshake_without_address_validation() -> io::Result<()> {
+        let conf = Config {
+            port: 8100,
+            address_validation: false,
+            pem_path: TEST_CERT.to_string(),
+            ..Default::default()
+        };
+        let server = Server::init(&conf)?;
+        let client = Client::init(&conf)?;
+
+        client.send(b"hello")?;
+        let mut buffer = [0u8; 16];
+        let (bytes_received, _) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..bytes_received], b"hello");
+
+        client.close();
+        server.close();
+        Ok(())
+    }
+
+    #[test]
+    fn missing_pem_file_is_an_error() {
+        let client_conf = Config {
+            port: 8084,
+            pem_path: "does/not/exist.pem".to_string(),
+            ..Default::default()
+        };
+        assert!(Client::init(&client_conf).is_err());
+    }
+}
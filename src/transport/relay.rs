@@ -0,0 +1,356 @@
+//! Relaying for peers that cannot reach each other directly, in the manner of a much reduced
+//! TURN. Clients register with a server that has `relay` on under their node ID, and address
+//! datagrams to one another by node ID; the server forwards each to the address the named peer
+//! registered from, naming the sender instead.
+//!
+//! Relay datagrams start with `crumb/relay\n`, then a kind byte, the length of a node ID in one
+//! byte and the node ID. Clients register by sending a `REGISTER` (0) datagram naming their own
+//! node ID, which the server sends back once it has. A `DATA` (1) datagram also carries a
+//! datagram to relay: clients name the destination's node ID, and the server forwards it naming
+//! the source's.
+//!
+//! Registrations last `REGISTRATION_TTL` after the client last sent anything through the relay,
+//! so relayed sessions should send keepalives. A node ID belongs to the address that registered
+//! it until then, and registrations of it from anywhere else go unanswered, so a client that
+//! restarts on another address registers once its old registration has expired. The relayed
+//! datagrams are crumb frames and are
+//! forwarded untouched, so sessions sealed with a pre-shared key stay sealed end to end.
+
+use crate::session::TokenBucket;
+use std::collections::HashMap;
+use std::net::{SocketAddr, UdpSocket};
+use std::str;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, trace};
+
+const TARGET: &str = "crumb::transport::relay";
+
+// Marks relay datagrams, so the server takes them rather than delivering them.
+pub(crate) const RELAY_PREFIX: &[u8] = b"crumb/relay\n";
+
+/// Longest node ID a relay datagram can carry.
+pub(crate) const MAX_PEER_ID_LEN: usize = 255;
+
+const REGISTER: u8 = 0;
+const DATA: u8 = 1;
+
+// How long a client stays registered without sending anything through the relay.
+const REGISTRATION_TTL: Duration = Duration::from_secs(60);
+
+// How often expired registrations are swept away.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// Registrations a relay holds, bounding memory under floods of made-up node IDs.
+const MAX_REGISTRATIONS: usize = 1 << 14;
+
+/// The server side: the clients registered with it and the send rate left to each pair.
+pub(crate) struct Relay {
+    pair_rate_kbps: u32,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    // Registered node IDs, with where each registered from and when it was last heard from.
+    peers: HashMap<String, (SocketAddr, Instant)>,
+    ids: HashMap<SocketAddr, String>,
+    // The rate limit of each source and destination node ID.
+    pairs: HashMap<(String, String), TokenBucket>,
+    // When expired registrations were last swept away.
+    swept: Option<Instant>,
+}
+
+impl Relay {
+    pub(crate) fn new(pair_rate_kbps: u32) -> Relay {
+        Relay {
+            pair_rate_kbps,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Handles the relay datagram `packet`, with its prefix stripped, from `source`: registers
+    /// the client or forwards the datagram it carries through `socket`. Datagrams that cannot be
+    /// relayed are dropped.
+    pub(crate) fn handle(&self, socket: &UdpSocket, packet: &[u8], source: SocketAddr) {
+        let Some((kind, id, payload)) = decode(packet) else {
+            trace!(target: TARGET, %source, "dropping malformed relay datagram");
+            return;
+        };
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state
+            .swept
+            .is_none_or(|swept| now.saturating_duration_since(swept) >= EXPIRY_INTERVAL)
+        {
+            state.expire(now);
+        }
+        match kind {
+            REGISTER => {
+                if let Some(&(owner, heard)) = state.peers.get(id) {
+                    if owner != source && now.saturating_duration_since(heard) < REGISTRATION_TTL {
+                        debug!(target: TARGET, id, %owner, %source, "refusing registration held by another address");
+                        return;
+                    }
+                } else if state.peers.len() >= MAX_REGISTRATIONS {
+                    debug!(target: TARGET, id, %source, "refusing registration, relay full");
+                    return;
+                }
+                if let Some((previous, _)) = state.peers.insert(id.to_string(), (source, now)) {
+                    if previous != source {
+                        debug!(target: TARGET, id, %previous, %source, "peer moved");
+                        state.ids.remove(&previous);
+                    }
+                }
+                state.ids.insert(source, id.to_string());
+                drop(state);
+                debug!(target: TARGET, id, %source, "peer registered");
+                if let Err(e) = socket.send_to(&encode(REGISTER, id), source) {
+                    debug!(target: TARGET, %source, error = %e, "registration reply failed");
+                }
+            }
+            DATA => {
+                let Some(from) = state.ids.get(&source).cloned() else {
+                    trace!(target: TARGET, %source, "dropping datagram from unregistered peer");
+                    return;
+                };
+                if let Some((_, heard)) = state.peers.get_mut(&from) {
+                    *heard = now;
+                }
+                let Some(&(dest, _)) = state.peers.get(id) else {
+                    trace!(target: TARGET, from, to = id, "dropping datagram for unregistered peer");
+                    return;
+                };
+                let bucket = state
+                    .pairs
+                    .entry((from.clone(), id.to_string()))
+                    .or_insert_with(|| TokenBucket::new(self.pair_rate_kbps));
+                if !bucket.ready(now) {
+                    trace!(target: TARGET, from, to = id, "dropping datagram over the pair's rate");
+                    return;
+                }
+                bucket.take(payload.len());
+                drop(state);
+
+                let mut relayed = encode(DATA, &from);
+                relayed.extend_from_slice(payload);
+                match socket.send_to(&relayed, dest) {
+                    Ok(_) => {
+                        trace!(target: TARGET, from, to = id, bytes = payload.len(), "relayed")
+                    }
+                    Err(e) => debug!(target: TARGET, from, to = id, error = %e, "relaying failed"),
+                }
+            }
+            _ => trace!(target: TARGET, %source, kind, "dropping relay datagram of unknown kind"),
+        }
+    }
+}
+
+impl State {
+    fn expire(&mut self, now: Instant) {
+        self.swept = Some(now);
+        let before = self.peers.len();
+        self.peers
+            .retain(|_, (_, heard)| now.saturating_duration_since(*heard) < REGISTRATION_TTL);
+        if self.peers.len() == before {
+            return;
+        }
+        let peers = &self.peers;
+        self.ids.retain(|_, id| peers.contains_key(id));
+        self.pairs
+            .retain(|(from, to), _| peers.contains_key(from) && peers.contains_key(to));
+    }
+}
+
+/// The client side: the header addressing datagrams to one peer, and the one marking those it
+/// relays back.
+pub(crate) struct Route {
+    header: Vec<u8>,
+}
+
+impl Route {
+    pub(crate) fn new(peer: &str) -> Route {
+        Route {
+            header: encode(DATA, peer),
+        }
+    }
+
+    /// The header to send datagrams to the peer behind, and that marks datagrams from it.
+    pub(crate) fn header(&self) -> &[u8] {
+        &self.header
+    }
+
+    /// Strips the header from the datagram filling `buffer[..len]`, moving its payload to the
+    /// start of the buffer, and returns the payload's length. `None` for datagrams relayed from
+    /// any other peer, and for anything else.
+    pub(crate) fn unwrap(&self, buffer: &mut [u8], len: usize) -> Option<usize> {
+        let datagram = &buffer[..len];
+        if datagram.len() < self.header.len() || datagram[..self.header.len()] != self.header[..] {
+            return None;
+        }
+        buffer.copy_within(self.header.len()..len, 0);
+        Some(len - self.header.len())
+    }
+}
+
+/// The datagram registering `id` with a relay.
+pub(crate) fn registration(id: &str) -> Vec<u8> {
+    encode(REGISTER, id)
+}
+
+/// Whether `datagram` is the relay confirming the registration of `id`.
+pub(crate) fn is_registered(datagram: &[u8], id: &str) -> bool {
+    datagram == registration(id)
+}
+
+fn encode(kind: u8, id: &str) -> Vec<u8> {
+    let mut header = Vec::with_capacity(RELAY_PREFIX.len() + 2 + id.len());
+    header.extend_from_slice(RELAY_PREFIX);
+    header.push(kind);
+    header.push(id.len() as u8);
+    header.extend_from_slice(id.as_bytes());
+    header
+}
+
+// The kind, node ID and payload of a relay datagram whose prefix is stripped.
+fn decode(packet: &[u8]) -> Option<(u8, &str, &[u8])> {
+    let (&kind, rest) = packet.split_first()?;
+    let (&len, rest) = rest.split_first()?;
+    let (id, payload) = rest.split_at_checked(len as usize)?;
+    let id = str::from_utf8(id).ok().filter(|id| !id.is_empty())?;
+    Some((kind, id, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session;
+    use crate::util::config::Config;
+    use std::io;
+
+    fn register(relay: &Relay, relay_socket: &UdpSocket, id: &str) -> io::Result<UdpSocket> {
+        let socket = UdpSocket::bind("127.0.0.1:0")?;
+        socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let registration = registration(id);
+        relay.handle(
+            relay_socket,
+            &registration[RELAY_PREFIX.len()..],
+            socket.local_addr()?,
+        );
+        let mut buffer = [0u8; 64];
+        let received = socket.recv(&mut buffer)?;
+        assert!(is_registered(&buffer[..received], id));
+        Ok(socket)
+    }
+
+    #[test]
+    fn pairs_are_held_to_their_rate() -> io::Result<()> {
+        // 8 kbps allows a burst of 100 bytes.
+        let relay = Relay::new(8);
+        let relay_socket = UdpSocket::bind("127.0.0.1:0")?;
+        let a = register(&relay, &relay_socket, "a")?;
+        let b = register(&relay, &relay_socket, "b")?;
+        let c = register(&relay, &relay_socket, "c")?;
+
+        let mut to_b = Route::new("b").header()[RELAY_PREFIX.len()..].to_vec();
+        to_b.extend_from_slice(&[7u8; 200]);
+        let mut to_c = Route::new("c").header()[RELAY_PREFIX.len()..].to_vec();
+        to_c.extend_from_slice(&[8u8; 200]);
+        for packet in [&to_b, &to_b, &to_c] {
+            relay.handle(&relay_socket, packet, a.local_addr()?);
+        }
+        relay.handle(&relay_socket, &to_b, "127.0.0.1:9".parse().unwrap());
+
+        let from_a = Route::new("a");
+        let mut buffer = [0u8; 512];
+        for (socket, byte) in [(&b, 7), (&c, 8)] {
+            let received = socket.recv(&mut buffer)?;
+            assert_eq!(from_a.unwrap(&mut buffer, received), Some(200));
+            assert_eq!(buffer[..200], [byte; 200]);
+        }
+        b.set_read_timeout(Some(Duration::from_millis(100)))?;
+        assert!(b.recv(&mut buffer).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn registrations_belong_to_the_first_address() -> io::Result<()> {
+        let relay = Relay::new(0);
+        let relay_socket = UdpSocket::bind("127.0.0.1:0")?;
+        let a = register(&relay, &relay_socket, "a")?;
+        let b = register(&relay, &relay_socket, "b")?;
+
+        // Another address claiming the node ID is not answered, and traffic still goes to the
+        // address that registered it.
+        let thief = UdpSocket::bind("127.0.0.1:0")?;
+        thief.set_read_timeout(Some(Duration::from_millis(100)))?;
+        let registration = registration("a");
+        relay.handle(
+            &relay_socket,
+            &registration[RELAY_PREFIX.len()..],
+            thief.local_addr()?,
+        );
+        let mut to_a = Route::new("a").header()[RELAY_PREFIX.len()..].to_vec();
+        to_a.extend_from_slice(b"hello");
+        relay.handle(&relay_socket, &to_a, b.local_addr()?);
+
+        let mut buffer = [0u8; 64];
+        assert!(thief.recv(&mut buffer).is_err());
+        let received = a.recv(&mut buffer)?;
+        assert_eq!(Route::new("b").unwrap(&mut buffer, received), Some(5));
+        assert_eq!(&buffer[..5], b"hello");
+
+        // The address that registered it may register again.
+        relay.handle(
+            &relay_socket,
+            &registration[RELAY_PREFIX.len()..],
+            a.local_addr()?,
+        );
+        let received = a.recv(&mut buffer)?;
+        assert!(is_registered(&buffer[..received], "a"));
+        Ok(())
+    }
+
+    #[test]
+    fn sessions_run_through_a_relaying_server() -> io::Result<()> {
+        let conf = Config {
+            host: "127.0.0.1".to_string(),
+            port: 8140,
+            bind_host: "127.0.0.1".to_string(),
+            ..Default::default()
+        };
+        let server = session::Server::init(&Config {
+            relay: true,
+            ..conf.clone()
+        })?;
+        let relayed = |id: &str, peer: &str| {
+            session::Client::init(&Config {
+                node_id: id.to_string(),
+                relay_peer: peer.to_string(),
+                ..conf.clone()
+            })
+        };
+        let a = relayed("a", "b")?;
+        let b = relayed("b", "a")?;
+        a.send(b"hello")?;
+        let mut buffer = [0u8; 16];
+        let received = b.receive_timeout(&mut buffer, Duration::from_secs(5))?;
+        assert_eq!(&buffer[..received], b"hello");
+
+        server.set_read_timeout(Some(Duration::from_millis(100)))?;
+        assert!(server.receive_from(&mut buffer).is_err());
+        assert!(relayed("", "a").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_datagrams_are_not_decoded() {
+        assert_eq!(decode(b""), None);
+        assert_eq!(decode(&[DATA, 3, b'a']), None);
+        assert_eq!(decode(&[DATA, 0, b'a']), None);
+        assert_eq!(
+            decode(&[DATA, 1, b'a', 1, 2]),
+            Some((DATA, "a", &[1u8, 2][..]))
+        );
+    }
+}
@@ -0,0 +1,380 @@
+use super::{is_timeout, timed_out, Transport, Xorshift, MAX_DATAGRAM_SIZE};
+use crate::util::config::Config;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tracing::trace;
+
+const TARGET: &str = "crumb::transport::sim";
+
+// The shortest read timeout passed to the wrapped transport, which may reject zero.
+const MIN_WAIT: Duration = Duration::from_millis(1);
+
+/// The faults a `Sim` injects, each as the probability, between 0 and 1, that a datagram
+/// suffers it. Every decision is drawn from a generator seeded with `seed`, so a run repeats
+/// exactly as long as the calls into the transport do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Faults {
+    /// Datagrams dropped.
+    pub loss: f64,
+    /// Datagrams passed on twice.
+    pub duplicate: f64,
+    /// Datagrams with one random bit flipped.
+    pub corrupt: f64,
+    /// Datagrams held back and passed on right after the next one. A held datagram is lost if
+    /// none follows.
+    pub reorder: f64,
+    /// Datagrams held back for a random time up to `max_delay`.
+    pub delay: f64,
+    pub max_delay: Duration,
+    pub seed: u64,
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Faults {
+            loss: 0.0,
+            duplicate: 0.0,
+            corrupt: 0.0,
+            reorder: 0.0,
+            delay: 0.0,
+            max_delay: Duration::from_millis(50),
+            seed: 1,
+        }
+    }
+}
+
+/// How many datagrams a `Sim` has injected each fault into, in both directions.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SimStats {
+    pub lost: u64,
+    pub duplicated: u64,
+    pub corrupted: u64,
+    pub reordered: u64,
+    pub delayed: u64,
+}
+
+/// Wraps any transport, injecting `Faults` into the datagrams it sends and receives, to check
+/// the reliable mode against a bad network and make integration tests reproducible.
+///
+/// Both directions draw from their own generator. A delayed datagram being sent goes out with
+/// the first send or receive after it is due, so a sender that goes quiet holds it back longer.
+pub struct Sim {
+    inner: Box<dyn Transport>,
+    faults: Faults,
+    outgoing: Mutex<Direction>,
+    incoming: Mutex<Direction>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+struct Direction {
+    random: Xorshift,
+    held: Option<Vec<u8>>,
+    // Delayed datagrams, earliest due first, in arrival order among equals.
+    delayed: BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>>,
+    // Received datagrams ready to hand out.
+    ready: VecDeque<Vec<u8>>,
+    count: u64,
+    stats: SimStats,
+}
+
+impl Direction {
+    fn new(seed: u64) -> Direction {
+        Direction {
+            random: Xorshift::new(seed),
+            held: None,
+            delayed: BinaryHeap::new(),
+            ready: VecDeque::new(),
+            count: 0,
+            stats: SimStats::default(),
+        }
+    }
+
+    // Runs `datagram` through `faults`, returning what passes on right away.
+    fn apply(&mut self, faults: &Faults, mut datagram: Vec<u8>, now: Instant) -> Vec<Vec<u8>> {
+        if self.random.unit() < faults.loss {
+            trace!(target: TARGET, bytes = datagram.len(), "datagram lost");
+            self.stats.lost += 1;
+            return Vec::new();
+        }
+        if !datagram.is_empty() && self.random.unit() < faults.corrupt {
+            let bit = self.random.below(datagram.len() * 8);
+            trace!(target: TARGET, bytes = datagram.len(), bit, "datagram corrupted");
+            datagram[bit / 8] ^= 1 << (bit % 8);
+            self.stats.corrupted += 1;
+        }
+        if self.held.is_none() && self.random.unit() < faults.reorder {
+            trace!(target: TARGET, bytes = datagram.len(), "datagram held back");
+            self.held = Some(datagram);
+            self.stats.reordered += 1;
+            return Vec::new();
+        }
+
+        let mut passed = vec![datagram];
+        if self.random.unit() < faults.duplicate {
+            trace!(target: TARGET, bytes = passed[0].len(), "datagram duplicated");
+            passed.push(passed[0].clone());
+            self.stats.duplicated += 1;
+        }
+        passed.extend(self.held.take());
+        if self.random.unit() < faults.delay {
+            let due = now + faults.max_delay.mul_f64(self.random.unit());
+            trace!(target: TARGET, datagrams = passed.len(), ?due, "datagrams delayed");
+            for datagram in passed.drain(..) {
+                self.delayed.push(Reverse((due, self.count, datagram)));
+                self.count += 1;
+            }
+            self.stats.delayed += 1;
+        }
+        passed
+    }
+
+    // Takes the delayed datagrams due by `now`.
+    fn due(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut due = Vec::new();
+        while self
+            .delayed
+            .peek()
+            .is_some_and(|Reverse((at, _, _))| *at <= now)
+        {
+            let Reverse((_, _, datagram)) = self.delayed.pop().unwrap();
+            due.push(datagram);
+        }
+        due
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.delayed.peek().map(|Reverse((at, _, _))| *at)
+    }
+}
+
+impl Sim {
+    pub fn new(inner: Box<dyn Transport>, faults: Faults) -> Sim {
+        Sim {
+            inner,
+            faults,
+            outgoing: Mutex::new(Direction::new(faults.seed)),
+            incoming: Mutex::new(Direction::new(!faults.seed)),
+            read_timeout: Mutex::default(),
+        }
+    }
+
+    /// The faults injected so far.
+    pub fn stats(&self) -> SimStats {
+        let (outgoing, incoming) = (self.outgoing().stats, self.incoming().stats);
+        SimStats {
+            lost: outgoing.lost + incoming.lost,
+            duplicated: outgoing.duplicated + incoming.duplicated,
+            corrupted: outgoing.corrupted + incoming.corrupted,
+            reordered: outgoing.reordered + incoming.reordered,
+            delayed: outgoing.delayed + incoming.delayed,
+        }
+    }
+
+    fn outgoing(&self) -> MutexGuard<'_, Direction> {
+        self.outgoing.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn incoming(&self) -> MutexGuard<'_, Direction> {
+        self.incoming.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Sends whatever is due, followed by `data` if it passes.
+    fn send_through(&self, data: Option<&[u8]>) -> io::Result<()> {
+        let now = Instant::now();
+        let mut outgoing = self.outgoing();
+        let mut passed = outgoing.due(now);
+        if let Some(data) = data {
+            passed.extend(outgoing.apply(&self.faults, data.to_vec(), now));
+        }
+        passed
+            .iter()
+            .try_for_each(|datagram| self.inner.send(datagram).map(drop))
+    }
+
+    pub fn send(&self, data: &[u8]) -> io::Result<usize> {
+        self.send_through(Some(data))?;
+        Ok(data.len())
+    }
+
+    /// Waits for the next datagram to get through, copying it into `buffer` and truncating if
+    /// necessary.
+    pub fn receive(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let timeout = *self.read_timeout.lock().unwrap_or_else(|e| e.into_inner());
+        self.receive_within(buffer, timeout)
+    }
+
+    /// Like `receive`, but fails with `Error::Timeout` if nothing gets through within `timeout`.
+    pub fn receive_timeout(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        timed_out(self.receive_within(buffer, Some(timeout)))
+    }
+
+    fn receive_within(&self, buffer: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        self.send_through(None)?;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut received = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            let now = Instant::now();
+            let mut incoming = self.incoming();
+            let due = incoming.due(now);
+            incoming.ready.extend(due);
+            if let Some(datagram) = incoming.ready.pop_front() {
+                let len = datagram.len().min(buffer.len());
+                buffer[..len].copy_from_slice(&datagram[..len]);
+                return Ok(len);
+            }
+            if deadline.is_some_and(|deadline| deadline <= now) {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "Simulated receive timed out",
+                ));
+            }
+            // Until the next delayed datagram is due or the timeout ends, whichever is first.
+            let wait = [incoming.next_due(), deadline]
+                .into_iter()
+                .flatten()
+                .min()
+                .map(|until| (until - now).max(MIN_WAIT));
+            drop(incoming);
+
+            self.inner.set_read_timeout(wait)?;
+            match self.inner.receive(&mut received) {
+                Ok(len) => {
+                    let now = Instant::now();
+                    let mut incoming = self.incoming();
+                    let passed = incoming.apply(&self.faults, received[..len].to_vec(), now);
+                    incoming.ready.extend(passed);
+                }
+                Err(e) if is_timeout(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Bounds how long `receive` blocks. `None` blocks until a datagram gets through.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap_or_else(|e| e.into_inner()) = timeout;
+        Ok(())
+    }
+
+    /// Closes the wrapped transport, losing any datagrams still held back. Dropping the `Sim`
+    /// does the same.
+    pub fn close(self) {
+        self.inner.close()
+    }
+}
+
+impl Transport for Sim {
+    /// A `Sim` wraps a transport made some other way.
+    fn init(_: &Config) -> io::Result<Sim> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Simulated transports are made with Sim::new",
+        ))
+    }
+
+    fn send(&self, data: &[u8]) -> io::Result<usize> {
+        Sim::send(self, data)
+    }
+
+    fn receive(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        Sim::receive(self, buffer)
+    }
+
+    fn receive_timeout(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        Sim::receive_timeout(self, buffer, timeout)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Sim::set_read_timeout(self, timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn close(self: Box<Self>) {
+        Sim::close(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::memory;
+
+    fn receive_all(transport: &dyn Transport) -> Vec<Vec<u8>> {
+        transport
+            .set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        let mut buffer = [0u8; 64];
+        std::iter::from_fn(|| {
+            let received = transport.receive(&mut buffer).ok()?;
+            Some(buffer[..received].to_vec())
+        })
+        .collect()
+    }
+
+    #[test]
+    fn faults_repeat_with_the_seed() {
+        let run = |seed| {
+            let (a, b) = memory::pair(memory::Conditions::default());
+            let sim = Sim::new(
+                Box::new(a),
+                Faults {
+                    loss: 0.1,
+                    duplicate: 0.1,
+                    corrupt: 0.1,
+                    reorder: 0.1,
+                    seed,
+                    ..Default::default()
+                },
+            );
+            for i in 0..100u8 {
+                sim.send(&[i, i]).unwrap();
+            }
+            (receive_all(&b), sim.stats())
+        };
+        let (received, stats) = run(3);
+        assert_eq!(run(3), (received.clone(), stats));
+        assert_ne!(run(4).0, received);
+
+        assert!(stats.lost > 0 && stats.duplicated > 0);
+        assert!(stats.corrupted > 0 && stats.reordered > 0);
+        assert!(received.iter().any(|datagram| datagram[0] != datagram[1]));
+        assert!(received.windows(2).any(|pair| pair[0] == pair[1]));
+        assert!(received.windows(2).any(|pair| pair[0][0] > pair[1][0]));
+    }
+
+    #[test]
+    fn delayed_datagrams_arrive_late() -> io::Result<()> {
+        let max_delay = Duration::from_millis(40);
+        let (a, b) = memory::pair(memory::Conditions::default());
+        let sim = Sim::new(
+            Box::new(b),
+            Faults {
+                delay: 1.0,
+                max_delay,
+                seed: 9,
+                ..Default::default()
+            },
+        );
+        let sent_at = Instant::now();
+        for i in 0..5u8 {
+            a.send(&[i])?;
+        }
+        let received = receive_all(&sim);
+        assert!(sent_at.elapsed() < max_delay + Duration::from_millis(200));
+        assert_eq!(received.len(), 5);
+        assert_ne!(received.concat(), [0, 1, 2, 3, 4]);
+        assert_eq!(sim.stats().delayed, 5);
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+//! Kernel receive timestamps. With `kernel_timestamps`, UDP sockets on Linux have the kernel
+//! stamp every datagram as it arrives, with `SO_TIMESTAMPING`, so that delays measured from the
+//! stamp leave out the time a datagram then waits in the socket buffer and for the receiving
+//! thread. Network cards that stamp in hardware, once enabled for it (with `hwstamp_ctl`, say),
+//! stamp datagrams as they come off the wire instead. Their stamps are in the card's own clock,
+//! which must be kept in step with the system's, as `phc2sys` does, for them to be of use.
+//!
+//! The kernel turns stamping on shortly after a socket first asks, so the first datagrams may go
+//! unstamped, and are taken to arrive when they are read.
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::SystemTime;
+
+/// Asks the kernel to stamp datagrams arriving on `socket`.
+#[cfg(target_os = "linux")]
+pub(super) fn enable(socket: &socket2::Socket) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let flags = (libc::SOF_TIMESTAMPING_RX_SOFTWARE
+        | libc::SOF_TIMESTAMPING_SOFTWARE
+        | libc::SOF_TIMESTAMPING_RX_HARDWARE
+        | libc::SOF_TIMESTAMPING_RAW_HARDWARE) as libc::c_int;
+    // SAFETY: the option value is a c_int living for the duration of the call.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TIMESTAMPING,
+            &flags as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(super) fn enable(_: &socket2::Socket) -> io::Result<()> {
+    Err(crate::error::Error::config("Kernel timestamps are only available on Linux").into())
+}
+
+/// Receives a datagram like `UdpSocket::recv_from`, with the time the kernel stamped it with,
+/// if it did.
+#[cfg(target_os = "linux")]
+pub(super) fn recv_from(
+    socket: &UdpSocket,
+    buffer: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<SystemTime>)> {
+    use std::mem;
+    use std::os::fd::AsRawFd;
+
+    let mut iov = libc::iovec {
+        iov_base: buffer.as_mut_ptr().cast(),
+        iov_len: buffer.len(),
+    };
+    // Room for the three timespecs of a timestamp, aligned as control messages must be.
+    let mut control = [0u64; 16];
+    let mut stamp = None;
+    // SAFETY: `try_init` hands over storage for any socket address and its length, which
+    // `recvmsg` fills in. The message header points at buffers living for the duration of the
+    // call, and the control messages are only read within the length the kernel reports.
+    let (received, addr) = unsafe {
+        socket2::SockAddr::try_init(|storage, len| {
+            let mut msg: libc::msghdr = mem::zeroed();
+            msg.msg_name = storage.cast();
+            msg.msg_namelen = *len;
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr().cast();
+            msg.msg_controllen = mem::size_of_val(&control) as _;
+            let received = libc::recvmsg(socket.as_raw_fd(), &mut msg, 0);
+            if received < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            *len = msg.msg_namelen;
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET
+                    && (*cmsg).cmsg_type == libc::SCM_TIMESTAMPING
+                {
+                    let stamps: [libc::timespec; 3] =
+                        std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast());
+                    stamp = from_stamps(&stamps);
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+            Ok(received as usize)
+        })?
+    };
+    let addr = addr.as_socket().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "Datagram from a non-IP address")
+    })?;
+    Ok((received, addr, stamp))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(super) fn recv_from(
+    socket: &UdpSocket,
+    buffer: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<SystemTime>)> {
+    let (received, addr) = socket.recv_from(buffer)?;
+    Ok((received, addr, None))
+}
+
+// The kernel passes the software stamp first, then one no longer used, then the card's raw
+// hardware stamp. The card's is closer to the wire, so it wins where there is one.
+#[cfg(target_os = "linux")]
+fn from_stamps(stamps: &[libc::timespec; 3]) -> Option<SystemTime> {
+    [stamps[2], stamps[0]]
+        .into_iter()
+        .find(|stamp| stamp.tv_sec != 0 || stamp.tv_nsec != 0)
+        .map(|stamp| {
+            SystemTime::UNIX_EPOCH
+                + std::time::Duration::new(stamp.tv_sec as u64, stamp.tv_nsec as u32)
+        })
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use socket2::{Domain, Protocol, Socket, Type};
+    use std::time::Duration;
+
+    #[test]
+    fn datagrams_carry_the_time_they_arrived() -> io::Result<()> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        enable(&socket)?;
+        socket.bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap().into())?;
+        let socket: UdpSocket = socket.into();
+        socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let sender = UdpSocket::bind("127.0.0.1:0")?;
+
+        // The kernel turns stamping on shortly after it is first asked, so the first datagrams
+        // may go unstamped.
+        let mut buffer = [0u8; 16];
+        for _ in 0..100 {
+            let before = SystemTime::now();
+            sender.send_to(b"stamped", socket.local_addr()?)?;
+            let (received, source, stamp) = recv_from(&socket, &mut buffer)?;
+            assert_eq!(&buffer[..received], b"stamped");
+            assert_eq!(source, sender.local_addr()?);
+            if let Some(stamp) = stamp {
+                assert!(stamp >= before - Duration::from_millis(1) && stamp <= SystemTime::now());
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("no datagram was stamped");
+    }
+}
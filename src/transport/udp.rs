@@ -1,63 +1,961 @@
+use super::relay::{self, Relay, Route, RELAY_PREFIX};
+use super::socks::{Association, Proxy};
+use super::{
+    bind_client, bind_server, check_size, for_socket, is_timeout, resolve_for, timed_out,
+    timestamp, BufferPool, PooledBuffer, Transport, MAX_DATAGRAM_SIZE, MIN_READ_TIMEOUT,
+};
+use crate::error::Error;
+use crate::security::{Acl, RateLimiter};
 use crate::util::config::Config;
-use std::io::{self, Error};
-use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use socket2::SockRef;
+use std::io::{self, IoSlice};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, debug_span, trace, Span};
 
+// Events are emitted under this target so operators can filter UDP transport verbosity
+// independently, e.g. `RUST_LOG=crumb::transport::udp=trace`.
+const TARGET: &str = "crumb::transport::udp";
+
+// Marks datagrams sent with `Client::send_broadcast` so servers can route them to their
+// announcement hook instead of the application.
+pub(crate) const ANNOUNCE_PREFIX: &[u8] = b"crumb/announce\n";
+
+// Registrations with a relay sent before giving up, and how long each waits for the relay to
+// confirm it.
+const REGISTER_ATTEMPTS: u32 = 5;
+const REGISTER_TIMEOUT: Duration = Duration::from_millis(200);
+
+type AnnouncementHook = Box<dyn Fn(SocketAddr, &[u8]) + Send + Sync>;
+
+/// UDP client connected to `host:port`, or to `multicast_group:port` when a group is configured.
+///
+/// With a `proxy` configured, datagrams travel through a SOCKS5 UDP association instead, each
+/// sent to the proxy's relay behind a header naming the server. Datagrams relayed from anywhere
+/// else are dropped.
+///
+/// With a `relay_peer` configured, `host:port` is a relay the client registers with under its
+/// `node_id`, and datagrams are exchanged with the peer registered under `relay_peer` through
+/// it. Datagrams relayed from any other peer are dropped.
 pub struct Client {
     socket: UdpSocket,
+    // The server, which the socket is connected to unless proxied.
+    peer: SocketAddr,
+    proxy: Option<Association>,
+    relay: Option<Route>,
+    // Whether the kernel stamps datagrams as they arrive, with `kernel_timestamps`.
+    timestamps: bool,
+    span: Span,
 }
 
 impl Client {
     pub fn init(conf: &Config) -> io::Result<Client> {
-        let addr = format!("{}:{}", conf.host, conf.port);
-        let socket = UdpSocket::bind("[::]:0")?;
-        socket.connect(&addr)?;
+        let group = multicast_group(conf)?;
+        let addr = match group {
+            Some(group) => SocketAddr::new(group, conf.port).to_string(),
+            None => format!("{}:{}", conf.host, conf.port),
+        };
+        let span = debug_span!(target: TARGET, "client", peer = %addr);
+        let _enter = span.enter();
+
+        let socket = bind_client(conf)?;
+        socket.set_broadcast(conf.broadcast)?;
+        if let Some(group) = group {
+            let iface = MulticastIface::parse(&conf.multicast_iface)?;
+            let sock = SockRef::from(&socket);
+            match group {
+                IpAddr::V4(_) => sock.set_multicast_if_v4(&iface.v4)?,
+                IpAddr::V6(_) => sock.set_multicast_if_v6(iface.v6)?,
+            }
+        }
+        let peer = resolve_for(socket.local_addr()?, &addr)?;
+        let proxy = match conf.proxy.as_str() {
+            "" => None,
+            url => Some(Proxy::parse(url)?.associate(peer)?),
+        };
+        match &proxy {
+            Some(association) => {
+                socket.connect(resolve_for(socket.local_addr()?, association.relay())?)?
+            }
+            None => socket.connect(peer)?,
+        }
+        debug!(target: TARGET, local_addr = ?socket.local_addr(), "client session opened");
+
+        drop(_enter);
+        let mut client = Client {
+            socket,
+            peer,
+            proxy,
+            relay: None,
+            timestamps: conf.kernel_timestamps,
+            span,
+        };
+        if !conf.relay_peer.is_empty() {
+            client.register(&conf.node_id)?;
+            client.relay = Some(Route::new(&conf.relay_peer));
+        }
+        Ok(client)
+    }
 
-        Ok(Client { socket })
+    // Registers with the relay under `id`, trying again while it does not confirm.
+    fn register(&self, id: &str) -> io::Result<()> {
+        if id.is_empty() {
+            return Err(Error::config("Relayed clients need a node ID to register under").into());
+        }
+        let registration = relay::registration(id);
+        let mut buffer = [0u8; 512];
+        for _ in 0..REGISTER_ATTEMPTS {
+            self.send(&registration)?;
+            let deadline = Instant::now() + REGISTER_TIMEOUT;
+            loop {
+                let wait = deadline.saturating_duration_since(Instant::now());
+                match self.receive_timeout(&mut buffer, wait) {
+                    Ok(received) if relay::is_registered(&buffer[..received], id) => {
+                        let _enter = self.span.enter();
+                        debug!(target: TARGET, id, "registered with relay");
+                        return Ok(());
+                    }
+                    Ok(_) => {}
+                    Err(e) if is_timeout(&e) => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "Relay did not confirm the registration",
+        ))
+    }
+
+    /// Enables SO_BROADCAST, which `send_broadcast` requires.
+    pub fn set_broadcast(&self, enabled: bool) -> io::Result<()> {
+        self.socket.set_broadcast(enabled)
+    }
+
+    /// Announces `data` to every crumb server listening on `port` in the local subnet.
+    pub fn send_broadcast(&self, data: &[u8], port: u16) -> io::Result<usize> {
+        let _enter = self.span.enter();
+        let dest = SocketAddr::from((Ipv4Addr::BROADCAST, port));
+        let result = check_size(ANNOUNCE_PREFIX.len() + data.len(), MAX_DATAGRAM_SIZE)
+            .and_then(|()| {
+                SockRef::from(&self.socket).send_to_vectored(
+                    &[IoSlice::new(ANNOUNCE_PREFIX), IoSlice::new(data)],
+                    &dest.into(),
+                )
+            })
+            .map(|sent| sent - ANNOUNCE_PREFIX.len());
+        match &result {
+            Ok(sent) => trace!(target: TARGET, bytes = sent, port, "send_broadcast"),
+            Err(e) => debug!(target: TARGET, error = %e, "send_broadcast failed"),
+        }
+        result
+    }
+
+    /// Sets how many hops multicast datagrams sent by this client may travel.
+    pub fn set_multicast_ttl(&self, ttl: u32) -> io::Result<()> {
+        set_multicast_ttl(&self.socket, ttl)
+    }
+
+    /// Sets whether multicast datagrams sent by this client are looped back to local members.
+    pub fn set_multicast_loop(&self, enabled: bool) -> io::Result<()> {
+        set_multicast_loop(&self.socket, enabled)
     }
 
     pub fn send(&self, data: &[u8]) -> io::Result<usize> {
-        self.socket.send(data)
+        if self.proxy.is_some() || self.relay.is_some() {
+            return self.send_vectored(&[IoSlice::new(data)]);
+        }
+        let _enter = self.span.enter();
+        let result =
+            check_size(data.len(), MAX_DATAGRAM_SIZE).and_then(|()| self.socket.send(data));
+        match &result {
+            Ok(sent) => trace!(target: TARGET, bytes = sent, "send"),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                trace!(target: TARGET, "send would block")
+            }
+            Err(e) => debug!(target: TARGET, error = %e, "send failed"),
+        }
+        result
+    }
+
+    /// Sends the concatenation of `bufs` as one datagram, gathered by the kernel.
+    pub fn send_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let _enter = self.span.enter();
+        // The proxy's header comes first, as the proxy unwraps datagrams before the relay does.
+        let headers = [
+            self.proxy.as_ref().map_or(&[][..], Association::header),
+            self.relay.as_ref().map_or(&[][..], Route::header),
+        ];
+        let header_len: usize = headers.iter().map(|header| header.len()).sum();
+        let result =
+            check_size(header_len + vectored_len(bufs), MAX_DATAGRAM_SIZE).and_then(|()| {
+                match header_len {
+                    0 => SockRef::from(&self.socket).send_vectored(bufs),
+                    _ => {
+                        let mut wrapped = Vec::with_capacity(bufs.len() + 2);
+                        wrapped.extend(
+                            headers
+                                .iter()
+                                .filter(|header| !header.is_empty())
+                                .map(|header| IoSlice::new(header)),
+                        );
+                        wrapped.extend_from_slice(bufs);
+                        let sent = SockRef::from(&self.socket).send_vectored(&wrapped)?;
+                        Ok(sent.saturating_sub(header_len))
+                    }
+                }
+            });
+        match &result {
+            Ok(sent) => trace!(target: TARGET, bytes = sent, "send_vectored"),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                trace!(target: TARGET, "send_vectored would block")
+            }
+            Err(e) => debug!(target: TARGET, error = %e, "send_vectored failed"),
+        }
+        result
     }
 
     pub fn receive(&self, buffer: &mut [u8]) -> io::Result<usize> {
-        self.socket.recv(buffer)
+        self.receive_timestamped(buffer)
+            .map(|(received, _)| received)
     }
 
+    /// Like `receive`, also giving the time the kernel stamped the datagram with as it arrived,
+    /// with `kernel_timestamps` set on Linux.
+    pub fn receive_timestamped(
+        &self,
+        buffer: &mut [u8],
+    ) -> io::Result<(usize, Option<SystemTime>)> {
+        let _enter = self.span.enter();
+        let result = match (&self.proxy, &self.relay) {
+            (None, None) => self.recv(buffer),
+            (proxy, relay) => loop {
+                match self.recv(buffer) {
+                    Ok((received, stamp)) => {
+                        let payload = match proxy {
+                            Some(association) => association.unwrap(buffer, received),
+                            None => Some(received),
+                        };
+                        let payload = match (payload, relay) {
+                            (Some(len), Some(route)) => route.unwrap(buffer, len),
+                            (payload, _) => payload,
+                        };
+                        match payload {
+                            Some(payload) => break Ok((payload, stamp)),
+                            None => {
+                                trace!(target: TARGET, bytes = received, "dropped unrelayable datagram")
+                            }
+                        }
+                    }
+                    Err(e) => break Err(e),
+                }
+            },
+        };
+        match &result {
+            Ok((received, _)) => trace!(target: TARGET, bytes = received, "receive"),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                trace!(target: TARGET, "receive would block")
+            }
+            Err(e) => debug!(target: TARGET, error = %e, "receive failed"),
+        }
+        result
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> io::Result<(usize, Option<SystemTime>)> {
+        if !self.timestamps {
+            return self.socket.recv(buffer).map(|received| (received, None));
+        }
+        timestamp::recv_from(&self.socket, buffer).map(|(received, _, stamp)| (received, stamp))
+    }
+
+    /// Receives into a buffer from `pool` rather than one the caller provides, so high-rate
+    /// consumers neither allocate nor copy per datagram.
+    pub fn receive_pooled(&self, pool: &BufferPool) -> io::Result<PooledBuffer> {
+        pool.receive_with(|buffer| self.receive(buffer))
+    }
+
+    /// Like `receive`, but fails with `Error::Timeout` if nothing arrives within `timeout`. The
+    /// socket's read timeout is swapped for the call, so receives on other threads see it too.
+    pub fn receive_timeout(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let previous = self.socket.read_timeout()?;
+        self.socket
+            .set_read_timeout(Some(timeout.max(MIN_READ_TIMEOUT)))?;
+        let result = self.receive(buffer);
+        self.socket.set_read_timeout(previous)?;
+        timed_out(result)
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    /// Switches the socket to non-blocking mode for use with an external event loop.
+    pub fn into_nonblocking(self) -> io::Result<NonBlockingClient> {
+        self.socket.set_nonblocking(true)?;
+        Ok(NonBlockingClient { client: self })
+    }
+
+    /// Closes the socket. Dropping the client does the same.
     pub fn close(self) {
-        drop(self.socket);
+        drop(self);
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let _enter = self.span.enter();
+        debug!(target: TARGET, "client session closed");
     }
 }
 
+impl Transport for Client {
+    fn init(conf: &Config) -> io::Result<Client> {
+        Client::init(conf)
+    }
+
+    fn send(&self, data: &[u8]) -> io::Result<usize> {
+        Client::send(self, data)
+    }
+
+    fn send_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        Client::send_vectored(self, bufs)
+    }
+
+    fn receive(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        Client::receive(self, buffer)
+    }
+
+    fn receive_timestamped(&self, buffer: &mut [u8]) -> io::Result<(usize, Option<SystemTime>)> {
+        Client::receive_timestamped(self, buffer)
+    }
+
+    fn receive_pooled(&self, pool: &BufferPool) -> io::Result<PooledBuffer> {
+        Client::receive_pooled(self, pool)
+    }
+
+    fn receive_timeout(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        Client::receive_timeout(self, buffer, timeout)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Client::set_read_timeout(self, timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+
+    fn close(self: Box<Self>) {
+        Client::close(*self)
+    }
+}
+
+/// UDP server. When a multicast group is configured it is joined on init.
+///
+/// Datagrams from sources outside `acl_allow`, or inside `acl_deny`, are dropped before they
+/// are returned from `receive_from` or reach a handler, as are datagrams beyond a source's
+/// `peer_packet_rate`.
 pub struct Server {
     socket: UdpSocket,
+    // The socket's address, whose family decides the form destinations are given in.
+    bound: SocketAddr,
+    multicast_iface: MulticastIface,
+    acl: Acl,
+    rate_limit: RateLimiter,
+    announcement_hook: Option<AnnouncementHook>,
+    relay: Option<Relay>,
+    shutdown: Arc<AtomicBool>,
+    workers: usize,
+    timestamps: bool,
+    span: Span,
 }
 
 impl Server {
     pub fn init(conf: &Config) -> io::Result<Server> {
-        let addr = format!("[::]:{}", conf.port);
-        let socket = UdpSocket::bind(&addr)?;
+        let socket = bind_server(conf)?;
+        let span = debug_span!(target: TARGET, "server", bind = ?socket.local_addr());
+        let _enter = span.enter();
+        debug!(target: TARGET, "server listening");
 
-        Ok(Server { socket })
+        let server = Server {
+            bound: socket.local_addr()?,
+            socket,
+            multicast_iface: MulticastIface::parse(&conf.multicast_iface)?,
+            acl: Acl::from_config(conf)?,
+            rate_limit: RateLimiter::new(conf.peer_packet_rate, conf.peer_ban),
+            announcement_hook: None,
+            relay: conf.relay.then(|| Relay::new(conf.relay_pair_rate_kbps)),
+            shutdown: Arc::default(),
+            workers: conf.workers,
+            timestamps: conf.kernel_timestamps,
+            span: span.clone(),
+        };
+        if let Some(group) = multicast_group(conf)? {
+            server.join_multicast(group)?;
+        }
+
+        drop(_enter);
+        Ok(server)
+    }
+
+    pub fn join_multicast(&self, group: IpAddr) -> io::Result<()> {
+        let _enter = self.span.enter();
+        match group {
+            IpAddr::V4(group) => self
+                .socket
+                .join_multicast_v4(&group, &self.multicast_iface.v4)?,
+            IpAddr::V6(group) => self
+                .socket
+                .join_multicast_v6(&group, self.multicast_iface.v6)?,
+        }
+        debug!(target: TARGET, %group, "joined multicast group");
+        Ok(())
+    }
+
+    pub fn leave_multicast(&self, group: IpAddr) -> io::Result<()> {
+        let _enter = self.span.enter();
+        match group {
+            IpAddr::V4(group) => self
+                .socket
+                .leave_multicast_v4(&group, &self.multicast_iface.v4)?,
+            IpAddr::V6(group) => self
+                .socket
+                .leave_multicast_v6(&group, self.multicast_iface.v6)?,
+        }
+        debug!(target: TARGET, %group, "left multicast group");
+        Ok(())
     }
 
+    /// Sets how many hops multicast datagrams sent by this server may travel.
+    pub fn set_multicast_ttl(&self, ttl: u32) -> io::Result<()> {
+        set_multicast_ttl(&self.socket, ttl)
+    }
+
+    /// Sets whether multicast datagrams sent by this server are looped back to local members.
+    pub fn set_multicast_loop(&self, enabled: bool) -> io::Result<()> {
+        set_multicast_loop(&self.socket, enabled)
+    }
+
+    /// Sends `data` to `dest`. IPv4 destinations work whether or not the server is bound to a
+    /// dual-stack IPv6 address.
     pub fn send_to<A: ToSocketAddrs>(&self, data: &[u8], dest: A) -> io::Result<usize> {
-        self.socket.send_to(data, dest)
+        let _enter = self.span.enter();
+        let result = check_size(data.len(), MAX_DATAGRAM_SIZE)
+            .and_then(|()| resolve_for(self.bound, dest))
+            .and_then(|dest| self.socket.send_to(data, dest));
+        match &result {
+            Ok(sent) => trace!(target: TARGET, bytes = sent, "send_to"),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                trace!(target: TARGET, "send_to would block")
+            }
+            Err(e) => debug!(target: TARGET, error = %e, "send_to failed"),
+        }
+        result
+    }
+
+    /// Sets how long `receive_from` blocks before failing with `WouldBlock` or `TimedOut`.
+    /// `None` blocks indefinitely.
+    /// Sends the concatenation of `bufs` to `dest` as one datagram, gathered by the kernel.
+    pub fn send_to_vectored(&self, bufs: &[IoSlice<'_>], dest: SocketAddr) -> io::Result<usize> {
+        let _enter = self.span.enter();
+        let result = check_size(vectored_len(bufs), MAX_DATAGRAM_SIZE).and_then(|()| {
+            SockRef::from(&self.socket).send_to_vectored(bufs, &for_socket(self.bound, dest).into())
+        });
+        match &result {
+            Ok(sent) => trace!(target: TARGET, bytes = sent, %dest, "send_to_vectored"),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                trace!(target: TARGET, "send_to_vectored would block")
+            }
+            Err(e) => debug!(target: TARGET, error = %e, "send_to_vectored failed"),
+        }
+        result
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Registers `hook` to be called with the source address and payload of every broadcast
+    /// announcement. Announcements handled by the hook are not returned from `receive_from`.
+    pub fn on_announcement<F>(&mut self, hook: F)
+    where
+        F: Fn(SocketAddr, &[u8]) + Send + Sync + 'static,
+    {
+        self.announcement_hook = Some(Box::new(hook));
+    }
+
+    /// The access control list applied to incoming datagrams.
+    pub fn acl(&self) -> &Acl {
+        &self.acl
+    }
+
+    /// The per-source limit applied to incoming datagrams, with its throttling counters.
+    pub fn rate_limit(&self) -> &RateLimiter {
+        &self.rate_limit
+    }
+
+    /// Registers `hook` to be called with the source address of every datagram the access
+    /// control list rejects. See `Acl::on_unauthorized`.
+    pub fn on_unauthorized<F>(&self, hook: F)
+    where
+        F: Fn(SocketAddr) + Send + Sync + 'static,
+    {
+        self.acl.on_unauthorized(hook);
+    }
+
+    /// Returns a handle that stops this server from another thread.
+    pub fn shutdown_handle(&self) -> io::Result<ShutdownHandle> {
+        let local_addr = self.socket.local_addr()?;
+        let wake_ip = match local_addr.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ip => ip,
+        };
+        Ok(ShutdownHandle {
+            shutdown: self.shutdown.clone(),
+            wake_addr: SocketAddr::new(wake_ip, local_addr.port()),
+        })
     }
 
+    /// Whether `ShutdownHandle::shutdown` has been called for this server.
+    pub fn is_shut_down(&self) -> bool {
+        self.shutdown.load(Ordering::Acquire)
+    }
+
+    /// Fails with `ErrorKind::ConnectionAborted` once the server has been shut down.
     pub fn receive_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
-        self.socket.recv_from(buffer)
+        self.receive_from_timestamped(buffer)
+            .map(|(received, peer, _)| (received, peer))
+    }
+
+    /// Like `receive_from`, also giving the time the kernel stamped the datagram with as it
+    /// arrived, with `kernel_timestamps` set on Linux.
+    pub fn receive_from_timestamped(
+        &self,
+        buffer: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Option<SystemTime>)> {
+        let _enter = self.span.enter();
+        loop {
+            if self.is_shut_down() {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "Server shut down",
+                ));
+            }
+            let result = match self.timestamps {
+                true => timestamp::recv_from(&self.socket, buffer),
+                false => self
+                    .socket
+                    .recv_from(buffer)
+                    .map(|(received, peer)| (received, peer, None)),
+            };
+            match &result {
+                // Either the shutdown wake-up or a datagram that raced it.
+                Ok(_) if self.is_shut_down() => continue,
+                Ok((_, peer, _)) if !self.admit(*peer) => continue,
+                Ok((received, peer, _)) => {
+                    trace!(target: TARGET, bytes = received, %peer, "receive_from");
+                    if self.take_announcement(&buffer[..*received], *peer)
+                        || self.take_relayed(&buffer[..*received], *peer)
+                    {
+                        continue;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    trace!(target: TARGET, "receive_from would block")
+                }
+                Err(e) => debug!(target: TARGET, error = %e, "receive_from failed"),
+            }
+            return result;
+        }
+    }
+
+    /// `receive_from` into a buffer taken from `pool`, which returns to it when dropped.
+    pub fn receive_from_pooled(&self, pool: &BufferPool) -> io::Result<(PooledBuffer, SocketAddr)> {
+        let mut peer = None;
+        let buffer = pool.receive_with(|buffer| {
+            let (received, from) = self.receive_from(buffer)?;
+            peer = Some(from);
+            Ok(received)
+        })?;
+        Ok((
+            buffer,
+            peer.expect("peer is set whenever a datagram is received"),
+        ))
+    }
+
+    /// Calls `handler` with every datagram received and sends back the reply it returns, if any,
+    /// until the server is shut down. A panicking handler drops the datagram it was handling and
+    /// serving continues. Fails if receiving does, except for read timeouts.
+    pub fn serve<F>(&self, handler: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8], SocketAddr) -> Option<Vec<u8>>,
+    {
+        let e = super::serve(
+            |buffer| self.receive_from(buffer),
+            |reply, peer| self.send_to(reply, peer),
+            handler,
+        );
+        if self.is_shut_down() {
+            return Ok(());
+        }
+        Err(e)
+    }
+
+    /// Like `serve`, but runs `handler` on `conf.workers` threads so a slow handler does not
+    /// stall every client. Datagrams from the same peer are still handled one at a time, in the
+    /// order they arrived.
+    pub fn serve_concurrent<F>(&self, handler: F) -> io::Result<()>
+    where
+        F: Fn(&[u8], SocketAddr) -> Option<Vec<u8>> + Sync,
+    {
+        let e = super::serve_concurrent(
+            |buffer| self.receive_from(buffer),
+            |reply, peer| self.send_to(reply, peer),
+            handler,
+            self.workers,
+        );
+        if self.is_shut_down() {
+            return Ok(());
+        }
+        Err(e)
     }
 
+    /// Async equivalent of `serve` for use inside a Tokio runtime.
+    ///
+    /// Each handler future is spawned as its own task, so a panic is contained to that task and
+    /// its datagram. Datagrams are still handled one at a time. The socket is non-blocking while
+    /// this runs.
+    #[cfg(feature = "tokio")]
+    pub async fn serve_async<F, Fut>(&self, mut handler: F) -> io::Result<()>
+    where
+        F: FnMut(Vec<u8>, SocketAddr) -> Fut,
+        Fut: std::future::Future<Output = Option<Vec<u8>>> + Send + 'static,
+    {
+        self.socket.set_nonblocking(true)?;
+        let socket = tokio::net::UdpSocket::from_std(self.socket.try_clone()?)?;
+        let result = self.serve_with(&socket, &mut handler).await;
+        // The clone shares the file description, so this restores the server's blocking mode.
+        self.socket.set_nonblocking(false)?;
+        result
+    }
+
+    #[cfg(feature = "tokio")]
+    async fn serve_with<F, Fut>(
+        &self,
+        socket: &tokio::net::UdpSocket,
+        handler: &mut F,
+    ) -> io::Result<()>
+    where
+        F: FnMut(Vec<u8>, SocketAddr) -> Fut,
+        Fut: std::future::Future<Output = Option<Vec<u8>>> + Send + 'static,
+    {
+        let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            let (received, peer) = socket.recv_from(&mut buffer).await?;
+            if self.is_shut_down() {
+                return Ok(());
+            }
+            if !self.admit(peer) {
+                continue;
+            }
+            let datagram = &buffer[..received];
+            trace!(target: TARGET, bytes = received, %peer, "receive_from");
+            if self.take_announcement(datagram, peer) {
+                continue;
+            }
+
+            match tokio::spawn(handler(datagram.to_vec(), peer)).await {
+                Ok(Some(reply)) => {
+                    if let Err(e) = socket.send_to(&reply, peer).await {
+                        debug!(target: TARGET, %peer, error = %e, "reply failed");
+                    }
+                }
+                Ok(None) => {}
+                Err(_) => {
+                    tracing::warn!(target: TARGET, %peer, "handler panicked, message dropped")
+                }
+            }
+        }
+    }
+
+    // Applies the access control list, then the rate limit, to a datagram from `peer`.
+    fn admit(&self, peer: SocketAddr) -> bool {
+        self.acl.admit(peer) && self.rate_limit.admit(peer.ip(), Instant::now())
+    }
+
+    // Passes announcements to the hook, if one is registered. Returns whether `datagram` was one.
+    fn take_announcement(&self, datagram: &[u8], peer: SocketAddr) -> bool {
+        let Some(hook) = &self.announcement_hook else {
+            return false;
+        };
+        let Some(payload) = datagram.strip_prefix(ANNOUNCE_PREFIX) else {
+            return false;
+        };
+        debug!(target: TARGET, %peer, "announcement received");
+        hook(peer, payload);
+        true
+    }
+
+    // Registers or forwards relay datagrams when relaying. Returns whether `datagram` was one.
+    fn take_relayed(&self, datagram: &[u8], peer: SocketAddr) -> bool {
+        let Some(relay) = &self.relay else {
+            return false;
+        };
+        let Some(packet) = datagram.strip_prefix(RELAY_PREFIX) else {
+            return false;
+        };
+        relay.handle(&self.socket, packet, peer);
+        true
+    }
+
+    /// Switches the socket to non-blocking mode for use with an external event loop.
+    pub fn into_nonblocking(self) -> io::Result<NonBlockingServer> {
+        self.socket.set_nonblocking(true)?;
+        Ok(NonBlockingServer { server: self })
+    }
+
+    /// Closes the socket. Dropping the server does the same.
     pub fn close(self) {
-        drop(self.socket);
+        drop(self);
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _enter = self.span.enter();
+        debug!(target: TARGET, "server closed");
     }
 }
 
+/// Stops a `Server` from another thread: a blocked `receive_from` returns
+/// `ErrorKind::ConnectionAborted`, as does every later call.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    shutdown: Arc<AtomicBool>,
+    wake_addr: SocketAddr,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::Release);
+        // An empty datagram wakes a receive_from blocked on the socket.
+        let unspecified = match self.wake_addr {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let woken = UdpSocket::bind((unspecified, 0))
+            .and_then(|socket| socket.send_to(&[], self.wake_addr));
+        if let Err(e) = woken {
+            debug!(target: TARGET, error = %e, "shutdown wake-up failed");
+        }
+    }
+}
+
+/// A `Client` in non-blocking mode. Operations that cannot complete immediately fail with
+/// `ErrorKind::WouldBlock`; wait for readiness on the raw socket (or register it with mio
+/// when the `mio` feature is enabled) before retrying.
+pub struct NonBlockingClient {
+    client: Client,
+}
+
+impl NonBlockingClient {
+    pub fn try_send(&self, data: &[u8]) -> io::Result<usize> {
+        self.client.send(data)
+    }
+
+    pub fn try_receive(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.client.receive(buffer)
+    }
+
+    pub fn into_blocking(self) -> io::Result<Client> {
+        self.client.socket.set_nonblocking(false)?;
+        Ok(self.client)
+    }
+
+    pub fn close(self) {
+        self.client.close()
+    }
+}
+
+/// A `Server` in non-blocking mode. See `NonBlockingClient`.
+pub struct NonBlockingServer {
+    server: Server,
+}
+
+impl NonBlockingServer {
+    pub fn try_send_to<A: ToSocketAddrs>(&self, data: &[u8], dest: A) -> io::Result<usize> {
+        self.server.send_to(data, dest)
+    }
+
+    /// Announcements consumed by the server's hook are skipped, as in `Server::receive_from`.
+    pub fn try_receive_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.server.receive_from(buffer)
+    }
+
+    pub fn into_blocking(self) -> io::Result<Server> {
+        self.server.socket.set_nonblocking(false)?;
+        Ok(self.server)
+    }
+
+    pub fn close(self) {
+        self.server.close()
+    }
+}
+
+macro_rules! impl_raw_socket {
+    ($type:ty, $($field:ident).+) => {
+        #[cfg(unix)]
+        impl std::os::fd::AsRawFd for $type {
+            fn as_raw_fd(&self) -> std::os::fd::RawFd {
+                self.$($field).+.as_raw_fd()
+            }
+        }
+
+        #[cfg(unix)]
+        impl std::os::fd::AsFd for $type {
+            fn as_fd(&self) -> std::os::fd::BorrowedFd<'_> {
+                self.$($field).+.as_fd()
+            }
+        }
+
+        #[cfg(windows)]
+        impl std::os::windows::io::AsRawSocket for $type {
+            fn as_raw_socket(&self) -> std::os::windows::io::RawSocket {
+                self.$($field).+.as_raw_socket()
+            }
+        }
+
+        #[cfg(windows)]
+        impl std::os::windows::io::AsSocket for $type {
+            fn as_socket(&self) -> std::os::windows::io::BorrowedSocket<'_> {
+                self.$($field).+.as_socket()
+            }
+        }
+
+        #[cfg(all(feature = "mio", unix))]
+        impl mio::event::Source for $type {
+            fn register(
+                &mut self,
+                registry: &mio::Registry,
+                token: mio::Token,
+                interests: mio::Interest,
+            ) -> io::Result<()> {
+                use std::os::fd::AsRawFd;
+                mio::unix::SourceFd(&self.as_raw_fd()).register(registry, token, interests)
+            }
+
+            fn reregister(
+                &mut self,
+                registry: &mio::Registry,
+                token: mio::Token,
+                interests: mio::Interest,
+            ) -> io::Result<()> {
+                use std::os::fd::AsRawFd;
+                mio::unix::SourceFd(&self.as_raw_fd()).reregister(registry, token, interests)
+            }
+
+            fn deregister(&mut self, registry: &mio::Registry) -> io::Result<()> {
+                use std::os::fd::AsRawFd;
+                mio::unix::SourceFd(&self.as_raw_fd()).deregister(registry)
+            }
+        }
+    };
+}
+
+impl_raw_socket!(NonBlockingClient, client.socket);
+impl_raw_socket!(NonBlockingServer, server.socket);
+
+// Interface used for multicast membership and sends. IPv4 selects interfaces by address and
+// IPv6 by index; the unspecified address and index 0 let the OS choose.
+struct MulticastIface {
+    v4: Ipv4Addr,
+    v6: u32,
+}
+
+impl MulticastIface {
+    fn parse(iface: &str) -> io::Result<MulticastIface> {
+        let mut parsed = MulticastIface {
+            v4: Ipv4Addr::UNSPECIFIED,
+            v6: 0,
+        };
+        if iface.is_empty() {
+            return Ok(parsed);
+        }
+
+        if let Ok(addr) = iface.parse() {
+            parsed.v4 = addr;
+        } else if let Ok(index) = iface.parse() {
+            parsed.v6 = index;
+        } else {
+            return Err(Error::config(format!(
+                "Invalid multicast interface, expected an IPv4 address or index: {}",
+                iface
+            ))
+            .into());
+        }
+        Ok(parsed)
+    }
+}
+
+fn multicast_group(conf: &Config) -> io::Result<Option<IpAddr>> {
+    if conf.multicast_group.is_empty() {
+        return Ok(None);
+    }
+
+    match conf.multicast_group.parse::<IpAddr>() {
+        Ok(group) if group.is_multicast() => Ok(Some(group)),
+        _ => {
+            Err(Error::config(format!("Invalid multicast group: {}", conf.multicast_group)).into())
+        }
+    }
+}
+
+// Dual-stack IPv6 sockets can carry IPv4 groups too, so both option families are set on them.
+fn set_multicast_ttl(socket: &UdpSocket, ttl: u32) -> io::Result<()> {
+    let sock = SockRef::from(socket);
+    if socket.local_addr()?.is_ipv4() {
+        return sock.set_multicast_ttl_v4(ttl);
+    }
+
+    sock.set_multicast_hops_v6(ttl)?;
+    if !sock.only_v6()? {
+        sock.set_multicast_ttl_v4(ttl)?;
+    }
+    Ok(())
+}
+
+fn set_multicast_loop(socket: &UdpSocket, enabled: bool) -> io::Result<()> {
+    let sock = SockRef::from(socket);
+    if socket.local_addr()?.is_ipv4() {
+        return sock.set_multicast_loop_v4(enabled);
+    }
+
+    sock.set_multicast_loop_v6(enabled)?;
+    if !sock.only_v6()? {
+        sock.set_multicast_loop_v4(enabled)?;
+    }
+    Ok(())
+}
+
+fn vectored_len(bufs: &[IoSlice<'_>]) -> usize {
+    bufs.iter().map(|buf| buf.len()).sum()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::thread;
-    use std::time::Duration;
 
     #[test]
     fn test_client_server_interaction() -> io::Result<()> {
@@ -112,4 +1010,423 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn multicast_fan_out() -> io::Result<()> {
+        let conf = Config {
+            port: 8088,
+            multicast_group: "239.255.0.1".to_string(),
+            multicast_iface: "127.0.0.1".to_string(),
+            ..Default::default()
+        };
+
+        let consumers = [Server::init(&conf)?, Server::init(&conf)?];
+        let client = Client::init(&conf)?;
+        client.set_multicast_ttl(1)?;
+        client.set_multicast_loop(true)?;
+        client.send(b"telemetry")?;
+
+        for consumer in &consumers {
+            consumer.set_read_timeout(Some(Duration::from_secs(2)))?;
+            let mut buffer = [0u8; 64];
+            let (bytes_received, _) = consumer.receive_from(&mut buffer)?;
+            assert_eq!(&buffer[..bytes_received], b"telemetry");
+        }
+
+        for consumer in consumers {
+            consumer.leave_multicast("239.255.0.1".parse().unwrap())?;
+            consumer.close();
+        }
+        client.close();
+
+        Ok(())
+    }
+
+    #[test]
+    fn broadcast_announcements_reach_hook() -> io::Result<()> {
+        use std::sync::mpsc;
+
+        let server_conf = Config {
+            port: 8089,
+            bind_host: "0.0.0.0".to_string(),
+            ..Default::default()
+        };
+        let mut server = Server::init(&server_conf)?;
+        let (announced, announcements) = mpsc::channel();
+        server.on_announcement(move |source, payload| {
+            announced.send((source, payload.to_vec())).unwrap();
+        });
+
+        let client_conf = Config {
+            port: 8089,
+            broadcast: true,
+            ..Default::default()
+        };
+        let client = Client::init(&client_conf)?;
+        client.send_broadcast(b"node-1", 8089)?;
+        client.send(b"data")?;
+
+        // The announcement goes to the hook; only the regular datagram is returned.
+        let mut buffer = [0u8; 64];
+        let (bytes_received, _) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..bytes_received], b"data");
+
+        let (source, payload) = announcements.try_recv().unwrap();
+        assert_eq!(payload, b"node-1");
+        assert_eq!(source.port(), Transport::local_addr(&client)?.port());
+
+        Ok(())
+    }
+
+    #[test]
+    fn denied_sources_are_dropped_and_reported() -> io::Result<()> {
+        use std::sync::mpsc;
+
+        let server = Server::init(&Config {
+            port: 8113,
+            acl_deny: "::1".to_string(),
+            ..Default::default()
+        })?;
+        let (rejected, rejections) = mpsc::channel();
+        server.on_unauthorized(move |source| rejected.send(source).unwrap());
+
+        let denied = Client::init(&Config {
+            host: "::1".to_string(),
+            port: 8113,
+            ..Default::default()
+        })?;
+        let allowed = Client::init(&Config {
+            host: "127.0.0.1".to_string(),
+            port: 8113,
+            ..Default::default()
+        })?;
+        denied.send(b"intruder")?;
+        thread::sleep(Duration::from_millis(50));
+        allowed.send(b"friend")?;
+
+        let mut buffer = [0u8; 64];
+        let (bytes_received, _) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..bytes_received], b"friend");
+        let source = rejections.try_recv().unwrap();
+        assert_eq!(source.port(), Transport::local_addr(&denied)?.port());
+        assert_eq!(server.acl().rejected(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn flooding_sources_are_throttled() -> io::Result<()> {
+        let server = Server::init(&Config {
+            port: 8114,
+            peer_packet_rate: 1,
+            ..Default::default()
+        })?;
+        server.set_read_timeout(Some(Duration::from_millis(200)))?;
+        let client = Client::init(&Config {
+            host: "::1".to_string(),
+            port: 8114,
+            ..Default::default()
+        })?;
+        for _ in 0..3 {
+            client.send(b"flood")?;
+        }
+
+        let mut buffer = [0u8; 64];
+        server.receive_from(&mut buffer)?;
+        assert!(server.receive_from(&mut buffer).is_err());
+        assert_eq!(server.rate_limit().throttled(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn pooled_receives_reuse_buffers() -> io::Result<()> {
+        let server = Server::init(&Config {
+            port: 8116,
+            ..Default::default()
+        })?;
+        let client = Client::init(&Config {
+            host: "::1".to_string(),
+            port: 8116,
+            ..Default::default()
+        })?;
+        let pool = BufferPool::default();
+        for i in 0..10u8 {
+            client.send(&[i; 3])?;
+            let (datagram, peer) = server.receive_from_pooled(&pool)?;
+            assert_eq!(&datagram[..], &[i; 3]);
+            server.send_to(&datagram, peer)?;
+            assert_eq!(&client.receive_pooled(&pool)?[..], &[i; 3]);
+        }
+        // One for the server's datagram, held while the client receives into another.
+        assert_eq!(pool.allocated(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn vectored_sends_arrive_as_one_datagram() -> io::Result<()> {
+        let server = Server::init(&Config {
+            port: 8117,
+            ..Default::default()
+        })?;
+        let client = Client::init(&Config {
+            host: "::1".to_string(),
+            port: 8117,
+            ..Default::default()
+        })?;
+        let sent = client.send_vectored(&[IoSlice::new(b"head"), IoSlice::new(b"-body")])?;
+        assert_eq!(sent, 9);
+
+        let mut buffer = [0u8; 64];
+        let (received, peer) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"head-body");
+        server.send_to_vectored(&[IoSlice::new(b"re"), IoSlice::new(b"ply")], peer)?;
+        let received = client.receive(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"reply");
+
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_datagrams_are_refused() -> io::Result<()> {
+        let client = Client::init(&Config {
+            host: "127.0.0.1".to_string(),
+            bind_host: "127.0.0.1".to_string(),
+            ..Default::default()
+        })?;
+        let e = client.send(&vec![0; MAX_DATAGRAM_SIZE + 1]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(matches!(
+            Error::from(e),
+            Error::PayloadTooLarge {
+                size: 65_508,
+                max: MAX_DATAGRAM_SIZE
+            }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn broadcast_requires_opt_in() -> io::Result<()> {
+        let client = Client::init(&Config::default())?;
+        assert!(client.send_broadcast(b"node-1", 8090).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn nonblocking_would_block() -> io::Result<()> {
+        let server = Server::init(&Config {
+            port: 8093,
+            ..Default::default()
+        })?
+        .into_nonblocking()?;
+        let client = Client::init(&Config {
+            host: "::1".to_string(),
+            port: 8093,
+            ..Default::default()
+        })?
+        .into_nonblocking()?;
+
+        let mut buffer = [0u8; 64];
+        let err = server.try_receive_from(&mut buffer).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        let err = client.try_receive(&mut buffer).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        client.try_send(b"ping")?;
+        let server = server.into_blocking()?;
+        let (bytes_received, _) = server.receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..bytes_received], b"ping");
+
+        Ok(())
+    }
+
+    #[cfg(all(feature = "mio", unix))]
+    #[test]
+    fn mio_readiness() -> io::Result<()> {
+        use mio::{Events, Interest, Poll, Token};
+
+        let mut server = Server::init(&Config {
+            port: 8094,
+            ..Default::default()
+        })?
+        .into_nonblocking()?;
+        let client = Client::init(&Config {
+            host: "::1".to_string(),
+            port: 8094,
+            ..Default::default()
+        })?;
+
+        let mut poll = Poll::new()?;
+        poll.registry()
+            .register(&mut server, Token(0), Interest::READABLE)?;
+        client.send(b"ping")?;
+
+        let mut events = Events::with_capacity(4);
+        poll.poll(&mut events, Some(Duration::from_secs(2)))?;
+        assert!(events.iter().any(|event| event.token() == Token(0)));
+
+        let mut buffer = [0u8; 64];
+        let (bytes_received, _) = server.try_receive_from(&mut buffer)?;
+        assert_eq!(&buffer[..bytes_received], b"ping");
+        let err = server.try_receive_from(&mut buffer).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        Ok(())
+    }
+
+    #[test]
+    fn shutdown_wakes_a_blocked_receive() -> io::Result<()> {
+        let server = Server::init(&Config {
+            port: 8101,
+            ..Default::default()
+        })?;
+        let handle = server.shutdown_handle()?;
+
+        let receiver = thread::spawn(move || {
+            let mut buffer = [0u8; 16];
+            server.receive_from(&mut buffer).map(|_| ())
+        });
+        thread::sleep(Duration::from_millis(50));
+        handle.shutdown();
+
+        let err = receiver.join().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        Ok(())
+    }
+
+    #[test]
+    fn serve_replies_and_survives_panics() -> io::Result<()> {
+        let server = Server::init(&Config {
+            port: 8103,
+            ..Default::default()
+        })?;
+        let handle = server.shutdown_handle()?;
+        let serving = thread::spawn(move || {
+            server.serve(|message, _| match message {
+                b"panic" => panic!("handler failure"),
+                b"quiet" => None,
+                _ => Some([b"echo: ", message].concat()),
+            })
+        });
+
+        let client = Client::init(&Config {
+            host: "::1".to_string(),
+            port: 8103,
+            ..Default::default()
+        })?;
+        client.set_read_timeout(Some(Duration::from_secs(2)))?;
+        let mut buffer = [0u8; 64];
+        for message in [&b"panic"[..], b"quiet", b"ping"] {
+            client.send(message)?;
+        }
+        let received = client.receive(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"echo: ping");
+
+        handle.shutdown();
+        serving.join().unwrap()
+    }
+
+    #[test]
+    fn serve_concurrent_isolates_slow_peers() -> io::Result<()> {
+        let server = Server::init(&Config {
+            port: 8106,
+            workers: 2,
+            ..Default::default()
+        })?;
+        let handle = server.shutdown_handle()?;
+        let serving = thread::spawn(move || {
+            server.serve_concurrent(|message, _| {
+                if message == b"slow" {
+                    thread::sleep(Duration::from_millis(500));
+                }
+                Some(message.to_vec())
+            })
+        });
+
+        // Pick client ports whose datagrams go to different workers.
+        let peer = |port| SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port);
+        let slow_port = 9000;
+        let fast_port = (slow_port + 1..)
+            .find(|&port| {
+                crate::transport::worker_for(&peer(port), 2)
+                    != crate::transport::worker_for(&peer(slow_port), 2)
+            })
+            .unwrap();
+        let client = |bind_port| {
+            Client::init(&Config {
+                host: "::1".to_string(),
+                port: 8106,
+                bind_port,
+                ..Default::default()
+            })
+        };
+        let (slow, fast) = (client(slow_port)?, client(fast_port)?);
+
+        slow.send(b"slow")?;
+        slow.send(b"next")?;
+        thread::sleep(Duration::from_millis(50));
+        fast.send(b"fast")?;
+
+        let mut buffer = [0u8; 16];
+        fast.set_read_timeout(Some(Duration::from_millis(250)))?;
+        let received = fast.receive(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"fast");
+
+        slow.set_read_timeout(Some(Duration::from_secs(2)))?;
+        for expected in [&b"slow"[..], b"next"] {
+            let received = slow.receive(&mut buffer)?;
+            assert_eq!(&buffer[..received], expected);
+        }
+
+        handle.shutdown();
+        serving.join().unwrap()
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn serve_async_replies() -> io::Result<()> {
+        let server = Server::init(&Config {
+            port: 8105,
+            ..Default::default()
+        })?;
+        let handle = server.shutdown_handle()?;
+        let serving = thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            runtime.block_on(server.serve_async(|message, _| async move {
+                if message == b"panic" {
+                    panic!("handler failure");
+                }
+                Some([b"echo: ", &message[..]].concat())
+            }))
+        });
+
+        let client = Client::init(&Config {
+            host: "::1".to_string(),
+            port: 8105,
+            ..Default::default()
+        })?;
+        client.set_read_timeout(Some(Duration::from_secs(2)))?;
+        client.send(b"panic")?;
+        client.send(b"ping")?;
+        let mut buffer = [0u8; 64];
+        let received = client.receive(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"echo: ping");
+
+        handle.shutdown();
+        serving.join().unwrap()
+    }
+
+    #[test]
+    fn multicast_rejects_unicast_group() {
+        let conf = Config {
+            multicast_group: "127.0.0.1".to_string(),
+            ..Default::default()
+        };
+        assert!(Client::init(&conf).is_err());
+    }
 }
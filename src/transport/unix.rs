@@ -0,0 +1,352 @@
+use super::{timed_out, BufferPool, PooledBuffer, Transport, MIN_READ_TIMEOUT};
+use crate::error::Error;
+use crate::util::config::Config;
+use socket2::SockRef;
+use std::fs;
+use std::io::{self, IoSlice};
+use std::net::SocketAddr;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::{debug, debug_span, trace, Span};
+
+const TARGET: &str = "crumb::transport::unix";
+
+// Numbers the sockets of clients in this process, so each binds its own path.
+static NEXT_CLIENT: AtomicU64 = AtomicU64::new(0);
+
+/// UNIX domain datagram client connected to the server socket at `socket_path`.
+///
+/// Datagrams need a named sender to be answered, so the client binds its own socket next to
+/// the server's, named after it with the process id and a counter appended, and removes it when
+/// closed. Having paths rather than IP addresses, it reports none through `Transport`.
+pub struct Client {
+    socket: UnixDatagram,
+    path: PathBuf,
+    span: Span,
+}
+
+impl Client {
+    pub fn init(conf: &Config) -> io::Result<Client> {
+        let server = socket_path(conf)?;
+        let path = PathBuf::from(format!(
+            "{}.{}-{}",
+            server.display(),
+            process::id(),
+            NEXT_CLIENT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let span = debug_span!(target: TARGET, "client", peer = %server.display());
+        let _enter = span.enter();
+
+        // Left behind by an earlier process with the same id.
+        let _ = fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path)?;
+        if let Err(e) = socket.connect(server) {
+            let _ = fs::remove_file(&path);
+            return Err(e);
+        }
+        debug!(target: TARGET, local_path = %path.display(), "client session opened");
+
+        drop(_enter);
+        Ok(Client { socket, path, span })
+    }
+
+    pub fn send(&self, data: &[u8]) -> io::Result<usize> {
+        let _enter = self.span.enter();
+        let result = self.socket.send(data);
+        match &result {
+            Ok(sent) => trace!(target: TARGET, bytes = sent, "send"),
+            Err(e) => debug!(target: TARGET, error = %e, "send failed"),
+        }
+        result
+    }
+
+    /// Sends the concatenation of `bufs` as one datagram, gathered by the kernel.
+    pub fn send_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let _enter = self.span.enter();
+        let result = SockRef::from(&self.socket).send_vectored(bufs);
+        match &result {
+            Ok(sent) => trace!(target: TARGET, bytes = sent, "send_vectored"),
+            Err(e) => debug!(target: TARGET, error = %e, "send_vectored failed"),
+        }
+        result
+    }
+
+    pub fn receive(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let _enter = self.span.enter();
+        let result = self.socket.recv(buffer);
+        match &result {
+            Ok(received) => trace!(target: TARGET, bytes = received, "receive"),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                trace!(target: TARGET, "receive would block")
+            }
+            Err(e) => debug!(target: TARGET, error = %e, "receive failed"),
+        }
+        result
+    }
+
+    pub fn receive_pooled(&self, pool: &BufferPool) -> io::Result<PooledBuffer> {
+        pool.receive_with(|buffer| self.receive(buffer))
+    }
+
+    /// Like `receive`, but fails with `Error::Timeout` if nothing arrives within `timeout`. The
+    /// socket's read timeout is swapped for the call, so receives on other threads see it too.
+    pub fn receive_timeout(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let previous = self.socket.read_timeout()?;
+        self.socket
+            .set_read_timeout(Some(timeout.max(MIN_READ_TIMEOUT)))?;
+        let result = self.receive(buffer);
+        self.socket.set_read_timeout(previous)?;
+        timed_out(result)
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    /// The path this client's socket is bound to, which the server sees as its source.
+    pub fn local_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Closes the socket and removes its path. Dropping the client does the same.
+    pub fn close(self) {
+        drop(self);
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let _enter = self.span.enter();
+        let _ = fs::remove_file(&self.path);
+        debug!(target: TARGET, "client session closed");
+    }
+}
+
+impl Transport for Client {
+    fn init(conf: &Config) -> io::Result<Client> {
+        Client::init(conf)
+    }
+
+    fn send(&self, data: &[u8]) -> io::Result<usize> {
+        Client::send(self, data)
+    }
+
+    fn send_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        Client::send_vectored(self, bufs)
+    }
+
+    fn receive(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        Client::receive(self, buffer)
+    }
+
+    fn receive_pooled(&self, pool: &BufferPool) -> io::Result<PooledBuffer> {
+        Client::receive_pooled(self, pool)
+    }
+
+    fn receive_timeout(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        Client::receive_timeout(self, buffer, timeout)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Client::set_read_timeout(self, timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(no_ip_address())
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Err(no_ip_address())
+    }
+
+    fn close(self: Box<Self>) {
+        Client::close(*self)
+    }
+}
+
+/// UNIX domain datagram server bound to `socket_path`, with peers identified by the paths their
+/// sockets are bound to.
+///
+/// A socket file left at the path by a server that is no longer running is replaced; one that
+/// a live server is bound to makes `init` fail with `ErrorKind::AddrInUse`. The file is removed
+/// when the server is closed.
+pub struct Server {
+    socket: UnixDatagram,
+    path: PathBuf,
+    span: Span,
+}
+
+impl Server {
+    pub fn init(conf: &Config) -> io::Result<Server> {
+        let path = socket_path(conf)?.to_path_buf();
+        let span = debug_span!(target: TARGET, "server", bind = %path.display());
+        let _enter = span.enter();
+
+        let socket = match UnixDatagram::bind(&path) {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse && is_stale(&path) => {
+                debug!(target: TARGET, "replacing stale socket");
+                fs::remove_file(&path)?;
+                UnixDatagram::bind(&path)?
+            }
+            result => result?,
+        };
+        debug!(target: TARGET, "server listening");
+
+        drop(_enter);
+        Ok(Server { socket, path, span })
+    }
+
+    /// Receives the next datagram, with the path of the socket that sent it. The path is empty
+    /// for unbound senders, which cannot be answered.
+    pub fn receive_from(&self, buffer: &mut [u8]) -> io::Result<(usize, PathBuf)> {
+        let _enter = self.span.enter();
+        let result = self.socket.recv_from(buffer);
+        match &result {
+            Ok((received, peer)) => trace!(target: TARGET, bytes = received, ?peer, "receive_from"),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                trace!(target: TARGET, "receive_from would block")
+            }
+            Err(e) => debug!(target: TARGET, error = %e, "receive_from failed"),
+        }
+        let (received, peer) = result?;
+        let peer = peer
+            .as_pathname()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        Ok((received, peer))
+    }
+
+    pub fn send_to<P: AsRef<Path>>(&self, data: &[u8], dest: P) -> io::Result<usize> {
+        let _enter = self.span.enter();
+        let result = self.socket.send_to(data, dest.as_ref());
+        match &result {
+            Ok(sent) => trace!(target: TARGET, bytes = sent, "send_to"),
+            Err(e) => debug!(target: TARGET, error = %e, "send_to failed"),
+        }
+        result
+    }
+
+    /// Sets how long `receive_from` blocks before failing with `WouldBlock` or `TimedOut`.
+    /// `None` blocks indefinitely.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    /// Calls `handler` with every datagram received and the path it came from, sending back the
+    /// reply it returns, if any, until receiving fails. A panicking handler drops the datagram it
+    /// was handling and is called again for the next one.
+    pub fn serve<F>(&self, handler: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8], PathBuf) -> Option<Vec<u8>>,
+    {
+        Err(super::serve(
+            |buffer| self.receive_from(buffer),
+            |reply, peer| self.send_to(reply, peer),
+            handler,
+        ))
+    }
+
+    pub fn local_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Closes the socket and removes its path. Dropping the server does the same.
+    pub fn close(self) {
+        drop(self);
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _enter = self.span.enter();
+        let _ = fs::remove_file(&self.path);
+        debug!(target: TARGET, "server closed");
+    }
+}
+
+fn socket_path(conf: &Config) -> io::Result<&Path> {
+    match conf.socket_path.as_str() {
+        "" => Err(Error::config("The UNIX transport needs CRUMB_SOCKET_PATH").into()),
+        path => Ok(Path::new(path)),
+    }
+}
+
+// Whether the socket file at `path` has no server behind it any more.
+fn is_stale(path: &Path) -> bool {
+    UnixDatagram::unbound()
+        .and_then(|probe| probe.connect(path))
+        .is_err_and(|e| e.kind() == io::ErrorKind::ConnectionRefused)
+}
+
+fn no_ip_address() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "UNIX sockets have paths, not IP addresses",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::config::TransportType;
+    use std::thread;
+
+    fn conf(name: &str) -> Config {
+        let path = std::env::temp_dir().join(format!("crumb-{}-{}.sock", name, process::id()));
+        Config {
+            transport_type: TransportType::Unix,
+            socket_path: path.to_str().unwrap().to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn echo_round_trip() -> io::Result<()> {
+        let conf = conf("echo");
+        let server = Server::init(&conf)?;
+        let client = <dyn Transport>::from_config(&conf)?;
+        client.set_read_timeout(Some(Duration::from_secs(5)))?;
+        assert_eq!(
+            client.peer_addr().unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+
+        let echo = thread::spawn(move || {
+            let mut buffer = [0u8; 64];
+            let (received, peer) = server.receive_from(&mut buffer).unwrap();
+            server.send_to(&buffer[..received], &peer).unwrap();
+            peer
+        });
+        client.send_vectored(&[IoSlice::new(b"hello "), IoSlice::new(b"unix")])?;
+        let mut buffer = [0u8; 64];
+        let received = client.receive(&mut buffer)?;
+        assert_eq!(&buffer[..received], b"hello unix");
+
+        let peer = echo.join().unwrap();
+        assert!(peer.exists());
+        client.close();
+        assert!(!peer.exists());
+        assert!(!Path::new(&conf.socket_path).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn stale_sockets_are_replaced_but_live_ones_are_not() -> io::Result<()> {
+        let conf = conf("stale");
+        let path = Path::new(&conf.socket_path);
+        let _ = fs::remove_file(path);
+        drop(UnixDatagram::bind(path)?);
+        assert!(path.exists());
+
+        let server = Server::init(&conf)?;
+        assert_eq!(
+            Server::init(&conf).err().map(|e| e.kind()),
+            Some(io::ErrorKind::AddrInUse)
+        );
+        server.close();
+        Ok(())
+    }
+}
@@ -1,15 +1,37 @@
-use log::warn;
+use crate::error::Error;
+use crate::protocol::Priority;
 use std::{
-    env, error,
-    fs::{metadata, File},
+    collections::{BTreeMap, HashMap},
+    env, fmt,
+    fs::{self, metadata, File},
     io::{BufRead, BufReader},
-    net, str,
+    net,
+    path::Path,
+    str,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime},
 };
+use tracing::{debug, warn};
+
+mod file;
+mod secret;
+mod validate;
+
+pub use secret::{CommandProvider, SecretProvider, SECRET_VARS};
+pub use validate::Violation;
 
 // MAX_ENV_FILE_SIZE should be set to the limit of BufReader, this is 8kb right now.
 const MAX_ENV_FILE_SIZE: u64 = 8 * 1024;
 
-#[derive(Debug, PartialEq, Eq)]
+// How often a ConfigWatcher checks its files for changes.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum CompressionType {
     Zstd,
     Gzip,
@@ -29,19 +51,741 @@ impl str::FromStr for CompressionType {
     }
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for CompressionType {
     fn default() -> Self {
         CompressionType::Zstd
     }
 }
 
+/// How message payloads are serialized. Frames name their format so that one transport can
+/// carry payloads from differently-serialized producers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PayloadFormat {
+    /// Messages described by `proto_path`.
+    #[default]
+    Protobuf,
+    Json,
+    /// MessagePack.
+    Msgpack,
+    /// Opaque bytes with no declared serialization.
+    Raw,
+}
+
+impl str::FromStr for PayloadFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "protobuf" => Ok(PayloadFormat::Protobuf),
+            "json" => Ok(PayloadFormat::Json),
+            "msgpack" => Ok(PayloadFormat::Msgpack),
+            "raw" => Ok(PayloadFormat::Raw),
+            _ => Err("Invalid payload format."),
+        }
+    }
+}
+
+/// A step a message payload passes through on its way to the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Compression with `compression_type`, skipped for payloads that are compressed already.
+    Compress,
+    /// The codec registered with `Client::set_codec` or `Server::set_codec`, if any.
+    Codec,
+}
+
+impl str::FromStr for Stage {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "compress" => Ok(Stage::Compress),
+            "codec" => Ok(Stage::Codec),
+            _ => Err("Invalid pipeline stage."),
+        }
+    }
+}
+
+/// The order of the steps a message payload passes through before it is sealed and framed,
+/// written as a list such as `compress,codec`. Steps left out are skipped, and `none` skips
+/// them all. The frame header records which ran, and in which order, so receivers undo them
+/// whatever order their own pipeline has.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pipeline(Vec<Stage>);
+
+impl Pipeline {
+    pub fn stages(&self) -> &[Stage] {
+        &self.0
+    }
+}
+
+impl Default for Pipeline {
+    fn default() -> Pipeline {
+        Pipeline(vec![Stage::Compress, Stage::Codec])
+    }
+}
+
+impl str::FromStr for Pipeline {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().eq_ignore_ascii_case("none") {
+            return Ok(Pipeline(Vec::new()));
+        }
+        let stages = s
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<Stage>, _>>()?;
+        if (1..stages.len()).any(|i| stages[..i].contains(&stages[i])) {
+            return Err("Pipeline stage listed twice.");
+        }
+        Ok(Pipeline(stages))
+    }
+}
+
+/// Which compression applies to which messages, in place of `compression_type`, written as a
+/// list of `selector=algorithm` rules such as `type:7=zstd,priority:control=none`. A selector
+/// is `type:N`, for messages of type N, or `priority:NAME`, for those sent in that lane. Rules
+/// for a type take precedence over those for a priority.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompressionPolicy {
+    types: BTreeMap<u16, CompressionType>,
+    priorities: BTreeMap<Priority, CompressionType>,
+}
+
+impl CompressionPolicy {
+    /// The compression for a message of `msg_type` sent in the lane for `priority`, `default`
+    /// if no rule covers it.
+    pub fn choose(
+        &self,
+        default: CompressionType,
+        msg_type: u16,
+        priority: Priority,
+    ) -> CompressionType {
+        self.types
+            .get(&msg_type)
+            .or_else(|| self.priorities.get(&priority))
+            .copied()
+            .unwrap_or(default)
+    }
+}
+
+impl str::FromStr for CompressionPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut policy = CompressionPolicy::default();
+        for rule in s.split(',').map(str::trim).filter(|rule| !rule.is_empty()) {
+            let (selector, algorithm) = rule
+                .split_once('=')
+                .ok_or("Invalid compression policy rule.")?;
+            let algorithm: CompressionType = algorithm.trim().parse()?;
+            match selector.trim().split_once(':') {
+                Some(("type", msg_type)) => {
+                    let msg_type = msg_type
+                        .parse()
+                        .map_err(|_| "Invalid message type in compression policy.")?;
+                    policy.types.insert(msg_type, algorithm);
+                }
+                Some(("priority", priority)) => {
+                    let priority = match priority.to_lowercase().as_str() {
+                        "control" => Priority::Control,
+                        "high" => Priority::High,
+                        "normal" => Priority::Normal,
+                        "bulk" => Priority::Bulk,
+                        _ => return Err("Invalid priority in compression policy."),
+                    };
+                    policy.priorities.insert(priority, algorithm);
+                }
+                _ => return Err("Invalid compression policy rule."),
+            }
+        }
+        Ok(policy)
+    }
+}
+
+/// Where in a session `capture` records frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CapturePoint {
+    /// As the datagrams on the wire, sealed if the session has a pre-shared key.
+    #[default]
+    Wire,
+    /// Before they are sealed and after they are opened.
+    Plain,
+}
+
+impl str::FromStr for CapturePoint {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "wire" => Ok(CapturePoint::Wire),
+            "plain" => Ok(CapturePoint::Plain),
+            _ => Err("Invalid capture point."),
+        }
+    }
+}
+
+/// What `bridge::kafka` waits for before counting a record as produced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KafkaDelivery {
+    /// Records are not acknowledged or retried, and may be lost.
+    AtMostOnce,
+    /// Records are acknowledged by all in-sync replicas and retried until they are, so a retry
+    /// after a lost acknowledgement may duplicate one.
+    #[default]
+    AtLeastOnce,
+    /// As `AtLeastOnce`, with the idempotent producer, so retries are not duplicated within a
+    /// partition.
+    Idempotent,
+}
+
+impl str::FromStr for KafkaDelivery {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "at-most-once" => Ok(KafkaDelivery::AtMostOnce),
+            "at-least-once" => Ok(KafkaDelivery::AtLeastOnce),
+            "idempotent" => Ok(KafkaDelivery::Idempotent),
+            _ => Err("Invalid Kafka delivery guarantee."),
+        }
+    }
+}
+
+/// What a node does with a protobuf message produced with a schema it has never loaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SchemaPolicy {
+    /// Deliver it and log a warning.
+    #[default]
+    Warn,
+    /// Drop it.
+    Reject,
+}
+
+impl str::FromStr for SchemaPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "warn" => Ok(SchemaPolicy::Warn),
+            "reject" => Ok(SchemaPolicy::Reject),
+            _ => Err("Invalid schema policy."),
+        }
+    }
+}
+
+/// Application-layer protection for session frames, independent of the transport.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SecurityMode {
+    /// Frames are sent as they are; use the QUIC transport for TLS.
+    #[default]
+    None,
+    /// Frames are encrypted and authenticated with XChaCha20-Poly1305 under a pre-shared key.
+    Psk,
+}
+
+impl str::FromStr for SecurityMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(SecurityMode::None),
+            "psk" => Ok(SecurityMode::Psk),
+            _ => Err("Invalid security mode."),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransportType {
+    #[default]
+    Udp,
+    Quic,
+    /// UNIX domain datagram sockets at `socket_path`, for processes on the same host.
+    Unix,
+}
+
+impl str::FromStr for TransportType {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "udp" => Ok(TransportType::Udp),
+            "quic" => Ok(TransportType::Quic),
+            "unix" => Ok(TransportType::Unix),
+            _ => Err("Invalid transport type."),
+        }
+    }
+}
+
+/// What a session does with a message sent while its send queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Wait for space.
+    #[default]
+    Block,
+    /// Fail with `io::ErrorKind::WouldBlock`.
+    Error,
+    /// Discard the oldest queued message to make room.
+    DropOldest,
+}
+
+impl str::FromStr for QueuePolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "block" => Ok(QueuePolicy::Block),
+            "error" => Ok(QueuePolicy::Error),
+            "drop-oldest" => Ok(QueuePolicy::DropOldest),
+            _ => Err("Invalid queue policy."),
+        }
+    }
+}
+
+/// What a session promises about each message it carries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeliveryMode {
+    /// Sent once, unacknowledged, and lost if the network loses it.
+    AtMostOnce,
+    /// Acknowledged and retransmitted until it is, so a receiver may see it more than once
+    /// when an acknowledgement is lost, unless its `dedup_cache` remembers the message.
+    AtLeastOnce,
+    /// Acknowledged and retransmitted, with receivers dropping duplicates among the last
+    /// `replay_window` messages.
+    #[default]
+    ExactlyOnce,
+}
+
+impl DeliveryMode {
+    /// Whether messages are acknowledged and retransmitted.
+    pub fn is_reliable(self) -> bool {
+        self != DeliveryMode::AtMostOnce
+    }
+}
+
+impl str::FromStr for DeliveryMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "at-most-once" => Ok(DeliveryMode::AtMostOnce),
+            "at-least-once" => Ok(DeliveryMode::AtLeastOnce),
+            "exactly-once" => Ok(DeliveryMode::ExactlyOnce),
+            _ => Err("Invalid delivery mode."),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Config {
     pub host: String,
     pub port: u16,
+    /// Servers a session client fails over between, in order of preference. `CRUMB_HOST` set to
+    /// a comma-separated list gives every address in it with `port`. Empty means `host:port`
+    /// alone.
+    pub endpoints: Vec<net::SocketAddr>,
+    /// Local address sockets are bound to. Use `0.0.0.0` on hosts without IPv6.
+    pub bind_host: String,
+    /// Local port for the client socket, and for the server when non-zero (otherwise `port`).
+    pub bind_port: u16,
+    /// Accept IPv4 traffic on an IPv6 `bind_host` (clears IPV6_V6ONLY).
+    pub dual_stack: bool,
+    /// Network interface, e.g. `eth1`, sockets are bound to, so multi-homed hosts can force
+    /// crumb traffic through one NIC whatever the routing table prefers. Set with
+    /// SO_BINDTODEVICE on Linux and Android and IP_BOUND_IF on Apple platforms, and an error
+    /// elsewhere. Empty leaves the choice to the routing table.
+    pub bind_device: String,
+    /// Multicast group the server joins and the client sends to. Empty disables multicast.
+    pub multicast_group: String,
+    /// An IPv4 interface address for IPv4 groups or an interface index for IPv6 groups. Empty
+    /// lets the OS choose.
+    pub multicast_iface: String,
+    /// SO_RCVBUF size in bytes. 0 keeps the OS default.
+    pub recv_buffer_size: usize,
+    /// SO_SNDBUF size in bytes. 0 keeps the OS default.
+    pub send_buffer_size: usize,
+    /// DSCP code point (0-63) written to the IP TOS / traffic class of outgoing packets.
+    pub dscp: u8,
+    /// Enables SO_BROADCAST on client sockets.
+    pub broadcast: bool,
+    /// Identifies this node to its peers, in discovery announcements and when a session opens.
+    /// At most 255 bytes. Empty generates a random UUID per process.
+    pub node_id: String,
+    pub discovery_port: u16,
+    /// How often discovery announcements are sent. Peers expire after three missed intervals.
+    pub discovery_interval: Duration,
+    pub transport_type: TransportType,
+    /// Path of the server's socket with the UNIX transport. Clients bind beside it.
+    pub socket_path: String,
+    /// A SOCKS5 proxy, as `socks5://[user:password@]host:port`, that UDP clients reach their
+    /// server through with a UDP ASSOCIATE. Empty sends directly.
+    pub proxy: String,
+    /// How long a session may go without sending before a ping is sent to keep NAT and
+    /// firewall mappings open. Zero sends none.
+    pub keepalive_interval: Duration,
+    /// How often a session tells its peer how many of the peer's messages arrived, how many
+    /// were lost and the arrival jitter, for the peer's `Stats` and congestion control. Zero
+    /// sends no reports.
+    pub report_interval: Duration,
+    /// Stamps every application message with the time it was sent, for receivers that play
+    /// messages out through a jitter buffer.
+    pub timestamps: bool,
+    /// How long after the quickest crossing seen a receiver plays out stamped messages,
+    /// smoothing their arrival times so they come out at the pace they were sent. Messages that
+    /// arrive too late for their time are dropped. Zero delivers messages as they arrive.
+    pub jitter_delay: Duration,
+    /// Whether this host's clock is kept in step with its peers', by NTP or PTP, so the one-way
+    /// delay of stamped messages can be read off their stamps. Their transit jitter is measured
+    /// either way.
+    pub clock_synced: bool,
+    /// Has the kernel stamp UDP datagrams as they arrive, on Linux, so delays are measured from
+    /// then rather than from when the session's worker got to them. Network cards set up to
+    /// stamp in hardware do so on the wire.
+    pub kernel_timestamps: bool,
+    /// How often a session pings its peer to measure the offset between their clocks, which
+    /// corrects one-way delays without `clock_synced`. Zero leaves it to keepalives and
+    /// `Client::ping`.
+    pub clock_sync_interval: Duration,
+    /// Makes servers relay datagrams between the clients registered with them, for peers that
+    /// cannot reach each other directly.
+    pub relay: bool,
+    /// Bytes per second, in kbps, a server relays from one registered client to another. 0 is
+    /// unlimited.
+    pub relay_pair_rate_kbps: u32,
+    /// The node ID of the peer UDP clients reach through the relay at `host:port`, registering
+    /// with it under `node_id`. Empty sends directly.
+    pub relay_peer: String,
     pub compression_type: CompressionType,
-    pub reliable: bool,
+    /// Exceptions to `compression_type` for messages of particular types or priorities.
+    pub compression_policy: CompressionPolicy,
+    /// The steps message payloads pass through on their way to the wire, in order.
+    pub pipeline: Pipeline,
+    /// The format frames sent by this node declare for their payloads.
+    pub payload_format: PayloadFormat,
+    /// Whether session messages are acknowledged and retransmitted, and duplicates dropped.
+    /// `CRUMB_RELIABLE`, which predates it, still picks at-most-once when false.
+    pub delivery: DeliveryMode,
+    /// In reliable mode, deliver messages to the application in the order they were sent.
+    pub ordered: bool,
+    /// How many messages ahead of the next expected one an ordered receiver buffers. Messages
+    /// beyond the window are dropped unacknowledged, so the sender retransmits them later.
+    pub reorder_window: usize,
+    /// How many of the most recent sequence numbers an unordered exactly-once receiver remembers
+    /// to drop duplicated and replayed messages. Older messages are dropped too, so this should
+    /// not be smaller than the sender's send window (at most 1024).
+    pub replay_window: usize,
+    /// How many message ids an unordered reliable receiver remembers per sender, dropping
+    /// messages whose id it has seen, as retransmissions an at-least-once sender made when an
+    /// acknowledgement was lost. 0 disables the cache.
+    pub dedup_cache: usize,
+    /// Discover how large a datagram the path to each session peer carries, reported in
+    /// `Stats::path_mtu`. Sockets are switched to never fragment (on Linux), so messages that
+    /// would not fit in the path MTU are refused with `Error::PayloadTooLarge` rather than sent
+    /// and dropped.
+    pub pmtud: bool,
+    /// Let a session server move a client's session to the new address it sends from, once the
+    /// client has shown it holds the session's token and answered a path challenge from there,
+    /// rather than start a new one.
+    pub session_migration: bool,
+    /// Upper bound on the session send rate in kilobits per second. 0 leaves it unlimited.
+    pub max_rate_kbps: u32,
+    /// Messages a session holds while the congestion window or rate limit delays them.
+    pub send_queue_capacity: usize,
+    pub send_queue_policy: QueuePolicy,
+    /// Messages a reliable session receiver holds for the application before its sender has to
+    /// wait for them to be taken. Advertised to the sender with every acknowledgement. 0 leaves
+    /// the sender to its congestion window.
+    pub receive_window: u32,
+    /// Directory where session clients persist unacknowledged reliable messages, to send them
+    /// after a restart. Empty keeps them in memory only.
+    pub spool_dir: String,
+    /// Upper bound on the bytes of messages spooled. Sends that would exceed it fail with
+    /// `ErrorKind::StorageFull`.
+    pub spool_max_bytes: u64,
+    /// Times a session client tries to reconnect after losing the server before giving up. 0
+    /// disables reconnection. Each attempt opens a new socket while the old one is still bound,
+    /// so clients with a fixed `bind_port` cannot reconnect.
+    pub reconnect_attempts: u32,
+    /// Delay before the first reconnection attempt, doubling with every further attempt.
+    pub reconnect_backoff: Duration,
+    /// Upper bound on the delay between reconnection attempts.
+    pub reconnect_max_backoff: Duration,
+    /// Fraction of each reconnection delay, between 0 and 1, randomly taken off so that clients
+    /// which lost the same server do not all return at once.
+    pub reconnect_jitter: f64,
+    /// Let QUIC clients resume earlier TLS sessions with the same server using session tickets.
+    pub tls_resumption: bool,
+    /// Update QUIC traffic keys after this long. Zero disables time-based rekeying.
+    pub rekey_interval: Duration,
+    /// Update QUIC traffic keys after sending this many bytes. 0 disables volume-based
+    /// rekeying.
+    pub rekey_bytes: u64,
+    /// Make QUIC clients prove they own their source address with a Retry round trip before the
+    /// server allocates any session state for them.
+    pub address_validation: bool,
+    /// How long a QUIC handshake may take, lost packets retransmitted included, before the
+    /// client gives up with `Error::Timeout` and the server drops the attempt.
+    pub handshake_timeout: Duration,
+    /// Comma separated application protocols, e.g. `crumb/2,crumb/1`, QUIC clients offer and
+    /// servers accept through ALPN. Servers pick the first of theirs the client offers. Empty
+    /// negotiates none. When both sides set some, a handshake without one in common fails.
+    pub alpn: String,
+    /// Handler threads used by `serve_concurrent`.
+    pub workers: usize,
+    /// TCP port on `bind_host` where session servers answer HTTP health checks, for liveness
+    /// probes and load balancers. 0 disables it.
+    pub health_port: u16,
+    /// `host[:port]` of the MQTT broker `bridge::mqtt` bridges pub/sub topics to. The port
+    /// defaults to 1883.
+    pub mqtt_broker: String,
+    /// Client ID the bridge connects to the MQTT broker with. Empty derives one from `node_id`.
+    pub mqtt_client_id: String,
+    /// QoS (0-2) of the bridge's MQTT subscriptions and publications.
+    pub mqtt_qos: u8,
+    /// Comma separated `filter=topic` rules naming the crumb topics the bridge republishes to
+    /// MQTT, and under which MQTT topics, e.g. `sensors/#=site1/sensors/#`.
+    pub mqtt_uplink: String,
+    /// Comma separated `filter=topic` rules naming the MQTT topics the bridge republishes to
+    /// crumb, and under which crumb topics.
+    pub mqtt_downlink: String,
+    /// Comma separated `host:port` bootstrap servers of the Kafka cluster `bridge::kafka`
+    /// produces to.
+    pub kafka_brokers: String,
+    /// Kafka topic the sink produces crumb messages to.
+    pub kafka_topic: String,
+    /// Comma separated crumb topic filters the sink subscribes to.
+    pub kafka_filters: String,
+    /// What the sink keys records by, which picks their partition: empty for no key, `topic`
+    /// for the crumb topic, or `level:N` for its Nth level, counting from 0.
+    pub kafka_key: String,
+    pub kafka_delivery: KafkaDelivery,
+    /// Most records the producer sends to a partition in one request.
+    pub kafka_batch_size: usize,
+    /// How long the producer waits for a batch to fill before sending it.
+    pub kafka_linger: Duration,
+    /// TCP port on `bind_host` where `bridge::http` accepts messages POSTed for `host:port`.
+    pub ingest_port: u16,
+    /// Bearer token POSTs to `bridge::http` must carry in `Authorization`. Empty accepts any.
+    pub ingest_token: String,
+    /// pcapng file session clients and servers record every frame they send and receive to,
+    /// replaced if it exists. Empty records nothing.
+    pub capture_path: String,
+    pub capture_point: CapturePoint,
     pub pem_path: String,
     pub proto_path: String,
+    pub schema_policy: SchemaPolicy,
+    pub security: SecurityMode,
+    /// The 32 byte pre-shared key for `SecurityMode::Psk`, hex encoded. Takes precedence over
+    /// `psk_path`.
+    pub psk: String,
+    /// A file holding the pre-shared key, either hex encoded or as 32 raw bytes.
+    pub psk_path: String,
+    /// Names the pre-shared key in every frame it seals, so a server holding one key per client
+    /// can tell which to open them with. Empty names none.
+    pub psk_identity: String,
+    /// Numbers the pre-shared key in every frame it seals, so peers that also accept keys from
+    /// `psk_keys` can tell which opens it. 0 numbers none.
+    pub psk_key_id: u32,
+    /// Further pre-shared keys frames are accepted under while a fleet rotates keys, as a comma
+    /// separated list of `ID:KEY`, the key hex encoded, e.g. `1:00ff..`. Empty accepts none.
+    pub psk_keys: String,
+    /// How long a key from `psk_keys` is still accepted once a key with a larger id is in use,
+    /// here or by a peer.
+    pub psk_key_grace: Duration,
+    /// The 32 byte key, hex encoded, that operators seal control requests with, such as those
+    /// `crumb control` sends, to adjust or inspect a running server. Empty leaves servers
+    /// ignoring control requests.
+    pub control_key: String,
+    /// The token, or HMAC, a session client presents to the server when it connects, which a
+    /// server verifying clients with `session::Server::on_auth` must accept before it takes
+    /// any messages from it. Empty presents none.
+    pub auth_token: String,
+    /// A directory of mounted secrets, such as a Kubernetes secret volume, holding one file per
+    /// variable named after it, e.g. `CRUMB_PSK`. Files not named `CRUMB_*` are ignored, and a
+    /// trailing newline is dropped from each value. Empty reads none.
+    pub secrets_dir: String,
+    /// Comma separated networks, e.g. `10.0.0.0/8,fd00::/8`, servers accept traffic from.
+    /// Empty admits every source not in `acl_deny`.
+    pub acl_allow: String,
+    /// Comma separated networks servers drop traffic from, even when also allowed.
+    pub acl_deny: String,
+    /// Datagrams or QUIC messages per second a server accepts from each source address. 0
+    /// leaves them unlimited.
+    pub peer_packet_rate: u32,
+    /// QUIC connection attempts per second a server accepts from each source address. 0
+    /// leaves them unlimited.
+    pub peer_handshake_rate: u32,
+    /// How long a source exceeding either rate is ignored. Zero only drops the excess.
+    pub peer_ban: Duration,
+    /// Where each value came from, for debugging layered configs. Configs built in code report
+    /// every value as coming from the defaults.
+    pub sources: Sources,
+}
+
+/// The layer a config value was taken from. Later layers win: defaults, then the config file,
+/// then the files in `secrets_dir`, then environment variables, then `ConfigBuilder::set`
+/// overrides. The .env file given to `Config::from_env` is the exception and wins over the
+/// environment.
+///
+/// Values from the config file and the .env file may refer to environment variables as
+/// `${NAME}`, or `${NAME:-default}` for a fallback when it is unset or empty, so that
+/// `CRUMB_HOST=${POD_IP}` picks up an address from the Kubernetes downward API. Unset variables
+/// without a fallback expand to nothing, and `$$` stands for a literal `$`. Values from the
+/// environment, overrides and the files in `secrets_dir` are taken as they are. Secrets such as
+/// `CRUMB_PSK` may also name a file or variable to take the secret from, as the `secret` module
+/// describes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Source {
+    #[default]
+    Default,
+    File(String),
+    /// The secrets directory at the path.
+    Secret(String),
+    Env,
+    Override,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File(path) => write!(f, "file {}", path),
+            Source::Secret(dir) => write!(f, "secret in {}", dir),
+            Source::Env => write!(f, "env"),
+            Source::Override => write!(f, "override"),
+        }
+    }
+}
+
+/// The source of every config value, keyed by its `CRUMB_*` variable name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sources {
+    vars: BTreeMap<&'static str, Source>,
+    // The files `file:` secret references were read from, which a `ConfigWatcher` watches.
+    secret_files: Vec<String>,
+}
+
+impl Sources {
+    /// Where the value for the variable `var` came from.
+    pub fn get(&self, var: &str) -> &Source {
+        self.vars.get(var).unwrap_or(&Source::Default)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &Source)> {
+        self.vars.iter().map(|(var, source)| (*var, source))
+    }
+}
+
+/// One `VARIABLE: source` line per value.
+impl fmt::Display for Sources {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (var, source) in self.iter() {
+            writeln!(f, "{}: {}", var, source)?;
+        }
+        Ok(())
+    }
+}
+
+/// Builds a config from layered sources. See `Source` for the precedence.
+#[derive(Debug, Default)]
+pub struct ConfigBuilder {
+    file: Option<String>,
+    overrides: HashMap<String, String>,
+    secrets: secret::Providers,
+}
+
+impl ConfigBuilder {
+    /// Reads values from a TOML or YAML file, as `Config::from_file` does.
+    pub fn file(mut self, path: &str) -> Self {
+        self.file = Some(path.to_string());
+        self
+    }
+
+    /// Overrides the variable `var`, e.g. `CRUMB_PORT`, regardless of the other layers.
+    pub fn set(mut self, var: &str, value: impl ToString) -> Self {
+        self.overrides.insert(var.to_string(), value.to_string());
+        self
+    }
+
+    /// Resolves secret references of the form `scheme:reference` with `provider`, as the
+    /// `secret` module describes, replacing any earlier provider for the scheme.
+    pub fn secret_provider<P>(mut self, scheme: &str, provider: P) -> Self
+    where
+        P: SecretProvider + 'static,
+    {
+        self.secrets.insert(scheme, provider);
+        self
+    }
+
+    pub fn build(self) -> crate::Result<Config> {
+        let mut layers = Layers::default();
+        layers.above_env.push((Source::Override, self.overrides));
+        if let Some(path) = self.file {
+            let values = file::load(&path)?;
+            layers.below_env.push((Source::File(path), values));
+        }
+        Config::from_layers(layers, &self.secrets)
+    }
+}
+
+// Values from every layer above the defaults, each list highest precedence first, and the
+// secrets resolved from the references among them.
+#[derive(Default)]
+struct Layers {
+    above_env: Vec<(Source, HashMap<String, String>)>,
+    below_env: Vec<(Source, HashMap<String, String>)>,
+    secrets: HashMap<&'static str, String>,
+}
+
+impl Layers {
+    fn var(&self, key: &str) -> Result<String, env::VarError> {
+        if let Some(secret) = self.secrets.get(key) {
+            return Ok(secret.clone());
+        }
+        if let Some((source, value)) = Layers::find(&self.above_env, key) {
+            return Ok(Layers::expand(source, value));
+        }
+        match env::var(key) {
+            Err(env::VarError::NotPresent) => Layers::find(&self.below_env, key)
+                .map(|(source, value)| Layers::expand(source, value))
+                .ok_or(env::VarError::NotPresent),
+            found => found,
+        }
+    }
+
+    // Expands references to environment variables in values from files, leaving secrets, which
+    // may well contain a `$`, and values already from the environment alone.
+    fn expand(source: &Source, value: &str) -> String {
+        match source {
+            Source::File(_) => interpolate(value),
+            _ => value.to_string(),
+        }
+    }
+
+    fn source(&self, key: &str) -> Source {
+        if let Some((source, _)) = Layers::find(&self.above_env, key) {
+            return source.clone();
+        }
+        if env::var_os(key).is_some() {
+            return Source::Env;
+        }
+        Layers::find(&self.below_env, key)
+            .map(|(source, _)| source.clone())
+            .unwrap_or_default()
+    }
+
+    fn find<'a>(
+        layers: &'a [(Source, HashMap<String, String>)],
+        key: &str,
+    ) -> Option<(&'a Source, &'a String)> {
+        layers
+            .iter()
+            .find_map(|(source, values)| Some((source, values.get(key)?)))
+    }
 }
 
 impl Default for Config {
@@ -49,27 +793,181 @@ impl Default for Config {
         Config {
             host: "127.0.0.1".to_string(),
             port: 50505,
+            endpoints: Vec::new(),
+            bind_host: "::".to_string(),
+            bind_port: 0,
+            dual_stack: true,
+            bind_device: String::new(),
+            multicast_group: String::new(),
+            multicast_iface: String::new(),
+            recv_buffer_size: 0,
+            send_buffer_size: 0,
+            dscp: 0,
+            broadcast: false,
+            node_id: String::new(),
+            discovery_port: 50506,
+            discovery_interval: Duration::from_secs(1),
+            transport_type: TransportType::default(),
+            socket_path: String::new(),
+            proxy: String::new(),
+            keepalive_interval: Duration::ZERO,
+            report_interval: Duration::ZERO,
+            timestamps: false,
+            jitter_delay: Duration::ZERO,
+            clock_synced: false,
+            kernel_timestamps: false,
+            clock_sync_interval: Duration::ZERO,
+            relay: false,
+            relay_pair_rate_kbps: 0,
+            relay_peer: String::new(),
             compression_type: CompressionType::default(),
-            reliable: true,
+            compression_policy: CompressionPolicy::default(),
+            pipeline: Pipeline::default(),
+            payload_format: PayloadFormat::default(),
+            delivery: DeliveryMode::default(),
+            ordered: false,
+            reorder_window: 64,
+            replay_window: 1024,
+            dedup_cache: 0,
+            pmtud: false,
+            session_migration: true,
+            max_rate_kbps: 0,
+            send_queue_capacity: 1024,
+            send_queue_policy: QueuePolicy::default(),
+            receive_window: 0,
+            spool_dir: String::new(),
+            spool_max_bytes: 64 * 1024 * 1024,
+            reconnect_attempts: 0,
+            reconnect_backoff: Duration::from_millis(100),
+            reconnect_max_backoff: Duration::from_secs(30),
+            reconnect_jitter: 0.5,
+            tls_resumption: true,
+            rekey_interval: Duration::from_secs(60 * 60),
+            rekey_bytes: 1 << 30,
+            address_validation: true,
+            handshake_timeout: Duration::from_secs(10),
+            alpn: String::new(),
+            workers: 4,
+            health_port: 0,
+            mqtt_broker: String::new(),
+            mqtt_client_id: String::new(),
+            mqtt_qos: 1,
+            mqtt_uplink: String::new(),
+            mqtt_downlink: String::new(),
+            kafka_brokers: String::new(),
+            kafka_topic: String::new(),
+            kafka_filters: "#".to_string(),
+            kafka_key: String::new(),
+            kafka_delivery: KafkaDelivery::default(),
+            kafka_batch_size: 1000,
+            kafka_linger: Duration::from_millis(5),
+            ingest_port: 8080,
+            ingest_token: String::new(),
+            capture_path: String::new(),
+            capture_point: CapturePoint::default(),
             pem_path: "cert.pem".to_string(),
             proto_path: "message.proto".to_string(),
+            schema_policy: SchemaPolicy::default(),
+            security: SecurityMode::default(),
+            psk: String::new(),
+            psk_path: String::new(),
+            psk_identity: String::new(),
+            psk_key_id: 0,
+            psk_keys: String::new(),
+            psk_key_grace: Duration::from_secs(60 * 60),
+            control_key: String::new(),
+            auth_token: String::new(),
+            secrets_dir: String::new(),
+            acl_allow: String::new(),
+            acl_deny: String::new(),
+            peer_packet_rate: 0,
+            peer_handshake_rate: 0,
+            peer_ban: Duration::ZERO,
+            sources: Sources::default(),
         }
     }
 }
 
 impl Config {
-    pub fn from_env(file_path: Option<&str>) -> Result<Self, Box<dyn error::Error>> {
+    /// Loads the config from `CRUMB_*` environment variables. Variables in the .env file at
+    /// `file_path` take precedence over the environment; the process environment itself is
+    /// never modified.
+    pub fn from_env(file_path: Option<&str>) -> crate::Result<Self> {
+        let mut layers = Layers::default();
         if let Some(path) = file_path {
-            set_env_vars(path)?;
+            let vars = read_env_file(path)?;
+            layers
+                .above_env
+                .push((Source::File(path.to_string()), vars));
+        }
+        Config::from_layers(layers, &secret::Providers::default())
+    }
+
+    /// Loads a TOML file, or a YAML file when built with the `yaml` feature, picking the format
+    /// from the extension.
+    ///
+    /// Top-level keys and the `[transport]`, `[tls]`, `[security]`, `[compression]` and
+    /// `[payload]` sections are described in the `file` module. A `CRUMB_*` environment
+    /// variable that is set takes precedence over the file, and the file over the defaults.
+    pub fn from_file(path: &str) -> crate::Result<Self> {
+        Config::builder().file(path).build()
+    }
+
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    fn from_layers(mut layers: Layers, providers: &secret::Providers) -> crate::Result<Self> {
+        // The directory is itself configured, by any layer but the secrets it names.
+        if let Ok(dir) = layers.var("CRUMB_SECRETS_DIR") {
+            let dir = from_raw_string(&dir);
+            if !dir.is_empty() {
+                let secrets = read_secrets(&dir)?;
+                layers.below_env.insert(0, (Source::Secret(dir), secrets));
+            }
         }
+        let mut secret_files = Vec::new();
+        for var in SECRET_VARS {
+            let Ok(value) = layers.var(var) else {
+                continue;
+            };
+            let value = from_raw_string(&value);
+            if let Some(secret) = providers.resolve(var, &value)? {
+                layers.secrets.insert(var, secret);
+                if let Some(path) = value.strip_prefix("file:") {
+                    secret_files.push(path.to_string());
+                }
+            }
+        }
+        let mut config = Config::from_vars(&|key| layers.var(key))?;
+        config.sources = Sources {
+            vars: file::KEYS
+                .iter()
+                .map(|(_, var)| (*var, layers.source(var)))
+                .collect(),
+            secret_files,
+        };
+        Ok(config)
+    }
 
-        let host = match env::var("CRUMB_HOST") {
+    // Builds a config from `CRUMB_*` variables looked up with `var`.
+    fn from_vars(var: &Vars<'_>) -> crate::Result<Self> {
+        // A list of hosts names the endpoints to fail over between, the first being `host`.
+        let hosts = match var("CRUMB_HOST") {
             Ok(value) => {
-                let clean_host = from_raw_string(&value);
-                if !is_valid_ip(&clean_host) {
-                    panic!("Invalid IP address provided for CRUMB_HOST: {}", clean_host);
+                let clean_hosts: Vec<String> = from_raw_string(&value)
+                    .split(',')
+                    .map(from_raw_string)
+                    .collect();
+                for clean_host in &clean_hosts {
+                    if !is_valid_ip(clean_host) {
+                        return Err(Error::config(format!(
+                            "Invalid IP address provided for CRUMB_HOST: {}",
+                            clean_host
+                        )));
+                    }
                 }
-                clean_host
+                clean_hosts
             }
             Err(e) => {
                 let default_host = Config::default().host;
@@ -77,23 +975,306 @@ impl Config {
                     "CRUMB_HOST not set or invalid. Defaulting to {}. Error: {}",
                     default_host, e
                 );
-                default_host
+                vec![default_host]
             }
         };
+        let host = hosts[0].clone();
 
-        let port: u16 = get_env_var("CRUMB_PORT");
-        let compression_type: CompressionType = get_env_var("CRUMB_COMPRESSION_TYPE");
-        let reliable: bool = get_env_var("CRUMB_RELIABLE");
-        let proto_path = match env::var("CRUMB_PROTO_PATH") {
+        let bind_host = match var("CRUMB_BIND_HOST") {
+            Ok(value) => {
+                let clean_host = from_raw_string(&value);
+                if !is_valid_ip(&clean_host) {
+                    return Err(Error::config(format!(
+                        "Invalid IP address provided for CRUMB_BIND_HOST: {}",
+                        clean_host
+                    )));
+                }
+                clean_host
+            }
+            Err(_) => Config::default().bind_host,
+        };
+
+        let multicast_group = match var("CRUMB_MULTICAST_GROUP") {
+            Ok(value) => {
+                let clean_group = from_raw_string(&value);
+                if !is_multicast_ip(&clean_group) {
+                    return Err(Error::config(format!(
+                        "Invalid multicast address provided for CRUMB_MULTICAST_GROUP: {}",
+                        clean_group
+                    )));
+                }
+                clean_group
+            }
+            Err(_) => Default::default(),
+        };
+        let multicast_iface = match var("CRUMB_MULTICAST_IFACE") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => Default::default(),
+        };
+
+        let defaults = Config::default();
+        let port: u16 = get_var(var, "CRUMB_PORT", defaults.port);
+        let endpoints = match hosts.len() {
+            1 => defaults.endpoints,
+            _ => hosts
+                .iter()
+                .map(|host| net::SocketAddr::new(host.parse().unwrap(), port))
+                .collect(),
+        };
+        let bind_port: u16 = get_var(var, "CRUMB_BIND_PORT", defaults.bind_port);
+        let dual_stack: bool = get_var(var, "CRUMB_DUAL_STACK", defaults.dual_stack);
+        let bind_device = match var("CRUMB_BIND_DEVICE") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.bind_device,
+        };
+        let recv_buffer_size: usize =
+            get_var(var, "CRUMB_RECV_BUFFER_SIZE", defaults.recv_buffer_size);
+        let send_buffer_size: usize =
+            get_var(var, "CRUMB_SEND_BUFFER_SIZE", defaults.send_buffer_size);
+        let dscp: u8 = get_var(var, "CRUMB_DSCP", defaults.dscp);
+        let broadcast: bool = get_var(var, "CRUMB_BROADCAST", defaults.broadcast);
+        let node_id = match var("CRUMB_NODE_ID") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.node_id,
+        };
+        let discovery_port: u16 = get_var(var, "CRUMB_DISCOVERY_PORT", defaults.discovery_port);
+        let discovery_interval = Duration::from_millis(get_var(
+            var,
+            "CRUMB_DISCOVERY_INTERVAL_MS",
+            defaults.discovery_interval.as_millis() as u64,
+        ));
+        let transport_type: TransportType =
+            get_var(var, "CRUMB_TRANSPORT", defaults.transport_type);
+        let socket_path = match var("CRUMB_SOCKET_PATH") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.socket_path,
+        };
+        let proxy = match var("CRUMB_PROXY") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.proxy,
+        };
+        let keepalive_interval = Duration::from_millis(get_var(
+            var,
+            "CRUMB_KEEPALIVE_INTERVAL_MS",
+            defaults.keepalive_interval.as_millis() as u64,
+        ));
+        let report_interval = Duration::from_millis(get_var(
+            var,
+            "CRUMB_REPORT_INTERVAL_MS",
+            defaults.report_interval.as_millis() as u64,
+        ));
+        let timestamps: bool = get_var(var, "CRUMB_TIMESTAMPS", defaults.timestamps);
+        let jitter_delay = Duration::from_millis(get_var(
+            var,
+            "CRUMB_JITTER_DELAY_MS",
+            defaults.jitter_delay.as_millis() as u64,
+        ));
+        let clock_synced: bool = get_var(var, "CRUMB_CLOCK_SYNCED", defaults.clock_synced);
+        let kernel_timestamps: bool =
+            get_var(var, "CRUMB_KERNEL_TIMESTAMPS", defaults.kernel_timestamps);
+        let clock_sync_interval = Duration::from_millis(get_var(
+            var,
+            "CRUMB_CLOCK_SYNC_INTERVAL_MS",
+            defaults.clock_sync_interval.as_millis() as u64,
+        ));
+        let relay: bool = get_var(var, "CRUMB_RELAY", defaults.relay);
+        let relay_pair_rate_kbps: u32 = get_var(
+            var,
+            "CRUMB_RELAY_PAIR_RATE_KBPS",
+            defaults.relay_pair_rate_kbps,
+        );
+        let relay_peer = match var("CRUMB_RELAY_PEER") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.relay_peer,
+        };
+        let compression_type: CompressionType =
+            get_var(var, "CRUMB_COMPRESSION_TYPE", defaults.compression_type);
+        let compression_policy: CompressionPolicy =
+            get_var(var, "CRUMB_COMPRESSION_POLICY", defaults.compression_policy);
+        let pipeline: Pipeline = get_var(var, "CRUMB_PIPELINE", defaults.pipeline);
+        let payload_format: PayloadFormat =
+            get_var(var, "CRUMB_PAYLOAD_FORMAT", defaults.payload_format);
+        let legacy = match get_var(var, "CRUMB_RELIABLE", defaults.delivery.is_reliable()) {
+            false => DeliveryMode::AtMostOnce,
+            true if defaults.delivery.is_reliable() => defaults.delivery,
+            true => DeliveryMode::ExactlyOnce,
+        };
+        let delivery: DeliveryMode = get_var(var, "CRUMB_DELIVERY", legacy);
+        let ordered: bool = get_var(var, "CRUMB_ORDERED", defaults.ordered);
+        let reorder_window: usize = get_var(var, "CRUMB_REORDER_WINDOW", defaults.reorder_window);
+        let replay_window: usize = get_var(var, "CRUMB_REPLAY_WINDOW", defaults.replay_window);
+        let dedup_cache: usize = get_var(var, "CRUMB_DEDUP_CACHE", defaults.dedup_cache);
+        let pmtud: bool = get_var(var, "CRUMB_PMTUD", defaults.pmtud);
+        let session_migration: bool =
+            get_var(var, "CRUMB_SESSION_MIGRATION", defaults.session_migration);
+        let max_rate_kbps: u32 = get_var(var, "CRUMB_MAX_RATE_KBPS", defaults.max_rate_kbps);
+        let send_queue_capacity: usize = get_var(
+            var,
+            "CRUMB_SEND_QUEUE_CAPACITY",
+            defaults.send_queue_capacity,
+        );
+        let send_queue_policy: QueuePolicy =
+            get_var(var, "CRUMB_SEND_QUEUE_POLICY", defaults.send_queue_policy);
+        let receive_window: u32 = get_var(var, "CRUMB_RECEIVE_WINDOW", defaults.receive_window);
+        let spool_dir = match var("CRUMB_SPOOL_DIR") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.spool_dir,
+        };
+        let spool_max_bytes: u64 = get_var(var, "CRUMB_SPOOL_MAX_BYTES", defaults.spool_max_bytes);
+        let reconnect_attempts: u32 =
+            get_var(var, "CRUMB_RECONNECT_ATTEMPTS", defaults.reconnect_attempts);
+        let reconnect_backoff = Duration::from_millis(get_var(
+            var,
+            "CRUMB_RECONNECT_BACKOFF_MS",
+            defaults.reconnect_backoff.as_millis() as u64,
+        ));
+        let reconnect_max_backoff = Duration::from_millis(get_var(
+            var,
+            "CRUMB_RECONNECT_MAX_BACKOFF_MS",
+            defaults.reconnect_max_backoff.as_millis() as u64,
+        ));
+        let reconnect_jitter: f64 =
+            get_var(var, "CRUMB_RECONNECT_JITTER", defaults.reconnect_jitter);
+        let tls_resumption: bool = get_var(var, "CRUMB_TLS_RESUMPTION", defaults.tls_resumption);
+        let rekey_interval = Duration::from_secs(get_var(
+            var,
+            "CRUMB_REKEY_INTERVAL_SECS",
+            defaults.rekey_interval.as_secs(),
+        ));
+        let rekey_bytes: u64 = get_var(var, "CRUMB_REKEY_BYTES", defaults.rekey_bytes);
+        let address_validation: bool =
+            get_var(var, "CRUMB_ADDRESS_VALIDATION", defaults.address_validation);
+        let handshake_timeout = Duration::from_millis(get_var(
+            var,
+            "CRUMB_HANDSHAKE_TIMEOUT_MS",
+            defaults.handshake_timeout.as_millis() as u64,
+        ));
+        let alpn = match var("CRUMB_ALPN") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.alpn,
+        };
+        let workers: usize = get_var(var, "CRUMB_WORKERS", defaults.workers);
+        let health_port: u16 = get_var(var, "CRUMB_HEALTH_PORT", defaults.health_port);
+        let mqtt_broker = match var("CRUMB_MQTT_BROKER") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.mqtt_broker,
+        };
+        let mqtt_client_id = match var("CRUMB_MQTT_CLIENT_ID") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.mqtt_client_id,
+        };
+        let mqtt_qos: u8 = get_var(var, "CRUMB_MQTT_QOS", defaults.mqtt_qos);
+        let mqtt_uplink = match var("CRUMB_MQTT_UPLINK") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.mqtt_uplink,
+        };
+        let mqtt_downlink = match var("CRUMB_MQTT_DOWNLINK") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.mqtt_downlink,
+        };
+        let kafka_brokers = match var("CRUMB_KAFKA_BROKERS") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.kafka_brokers,
+        };
+        let kafka_topic = match var("CRUMB_KAFKA_TOPIC") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.kafka_topic,
+        };
+        let kafka_filters = match var("CRUMB_KAFKA_FILTERS") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.kafka_filters,
+        };
+        let kafka_key = match var("CRUMB_KAFKA_KEY") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.kafka_key,
+        };
+        let kafka_delivery: KafkaDelivery =
+            get_var(var, "CRUMB_KAFKA_DELIVERY", defaults.kafka_delivery);
+        let kafka_batch_size: usize =
+            get_var(var, "CRUMB_KAFKA_BATCH_SIZE", defaults.kafka_batch_size);
+        let kafka_linger = Duration::from_millis(get_var(
+            var,
+            "CRUMB_KAFKA_LINGER_MS",
+            defaults.kafka_linger.as_millis() as u64,
+        ));
+        let ingest_port: u16 = get_var(var, "CRUMB_INGEST_PORT", defaults.ingest_port);
+        let ingest_token = match var("CRUMB_INGEST_TOKEN") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.ingest_token,
+        };
+        let capture_path = match var("CRUMB_CAPTURE_PATH") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.capture_path,
+        };
+        let capture_point: CapturePoint =
+            get_var(var, "CRUMB_CAPTURE_POINT", defaults.capture_point);
+        let proto_path = match var("CRUMB_PROTO_PATH") {
             Ok(value) => from_raw_string(&value),
             Err(e) => {
-                panic!(
+                return Err(Error::config(format!(
                     "CRUMB_PROTO_PATH not set or invalid. A .proto file is required. Error: {}",
                     e
-                );
+                )))
             }
         };
-        let pem_path = match env::var("CRUMB_PEM_PATH") {
+        let schema_policy: SchemaPolicy =
+            get_var(var, "CRUMB_SCHEMA_POLICY", defaults.schema_policy);
+        let security: SecurityMode = get_var(var, "CRUMB_SECURITY", defaults.security);
+        let psk = match var("CRUMB_PSK") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.psk,
+        };
+        let psk_path = match var("CRUMB_PSK_PATH") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.psk_path,
+        };
+        let secrets_dir = match var("CRUMB_SECRETS_DIR") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.secrets_dir,
+        };
+        let psk_identity = match var("CRUMB_PSK_IDENTITY") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.psk_identity,
+        };
+        let psk_key_id = get_var(var, "CRUMB_PSK_KEY_ID", defaults.psk_key_id);
+        let psk_keys = match var("CRUMB_PSK_KEYS") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.psk_keys,
+        };
+        let psk_key_grace = Duration::from_secs(get_var(
+            var,
+            "CRUMB_PSK_KEY_GRACE_SECS",
+            defaults.psk_key_grace.as_secs(),
+        ));
+        let control_key = match var("CRUMB_CONTROL_KEY") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.control_key,
+        };
+        let auth_token = match var("CRUMB_AUTH_TOKEN") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.auth_token,
+        };
+        let acl_allow = match var("CRUMB_ACL_ALLOW") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.acl_allow,
+        };
+        let acl_deny = match var("CRUMB_ACL_DENY") {
+            Ok(value) => from_raw_string(&value),
+            Err(_) => defaults.acl_deny,
+        };
+        let peer_packet_rate: u32 =
+            get_var(var, "CRUMB_PEER_PACKET_RATE", defaults.peer_packet_rate);
+        let peer_handshake_rate: u32 = get_var(
+            var,
+            "CRUMB_PEER_HANDSHAKE_RATE",
+            defaults.peer_handshake_rate,
+        );
+        let peer_ban = Duration::from_secs(get_var(
+            var,
+            "CRUMB_PEER_BAN_SECS",
+            defaults.peer_ban.as_secs(),
+        ));
+        let pem_path = match var("CRUMB_PEM_PATH") {
             Ok(value) => from_raw_string(&value),
             Err(e) => {
                 warn!(
@@ -107,45 +1288,310 @@ impl Config {
         let config = Config {
             host,
             port,
+            endpoints,
+            bind_host,
+            bind_port,
+            dual_stack,
+            bind_device,
+            multicast_group,
+            multicast_iface,
+            recv_buffer_size,
+            send_buffer_size,
+            dscp,
+            broadcast,
+            node_id,
+            discovery_port,
+            discovery_interval,
+            transport_type,
+            socket_path,
+            proxy,
+            keepalive_interval,
+            report_interval,
+            timestamps,
+            jitter_delay,
+            clock_synced,
+            kernel_timestamps,
+            clock_sync_interval,
+            relay,
+            relay_pair_rate_kbps,
+            relay_peer,
             compression_type,
-            reliable,
+            compression_policy,
+            pipeline,
+            payload_format,
+            delivery,
+            ordered,
+            reorder_window,
+            replay_window,
+            dedup_cache,
+            pmtud,
+            session_migration,
+            max_rate_kbps,
+            send_queue_capacity,
+            send_queue_policy,
+            receive_window,
+            spool_dir,
+            spool_max_bytes,
+            reconnect_attempts,
+            reconnect_backoff,
+            reconnect_max_backoff,
+            reconnect_jitter,
+            tls_resumption,
+            rekey_interval,
+            rekey_bytes,
+            address_validation,
+            handshake_timeout,
+            alpn,
+            workers,
+            health_port,
+            mqtt_broker,
+            mqtt_client_id,
+            mqtt_qos,
+            mqtt_uplink,
+            mqtt_downlink,
+            kafka_brokers,
+            kafka_topic,
+            kafka_filters,
+            kafka_key,
+            kafka_delivery,
+            kafka_batch_size,
+            kafka_linger,
+            ingest_port,
+            ingest_token,
+            capture_path,
+            capture_point,
             proto_path,
             pem_path,
+            schema_policy,
+            security,
+            psk,
+            psk_path,
+            psk_identity,
+            psk_key_id,
+            psk_keys,
+            psk_key_grace,
+            control_key,
+            auth_token,
+            secrets_dir,
+            acl_allow,
+            acl_deny,
+            peer_packet_rate,
+            peer_handshake_rate,
+            peer_ban,
+            sources: Sources::default(),
         };
 
         Ok(config)
     }
+
+    /// Loads the config from the env file at `path` and reloads it whenever that file, the
+    /// configured PEM or PSK file or a file in `secrets_dir` changes, or the process receives
+    /// SIGHUP. Kubernetes updates mounted secrets in place, so rotated keys are picked up
+    /// without a restart.
+    ///
+    /// Reloaded configs are published through the returned watcher; servers pick up new
+    /// certificates with `reload_certificates`. A reload that fails leaves the previous config
+    /// in place.
+    pub fn watch(path: &str) -> crate::Result<ConfigWatcher> {
+        // Taken before loading, so an edit made while loading is still noticed.
+        let env_file = fingerprint(path);
+        let config = Config::from_env(Some(path))?;
+        let files = fingerprints(env_file, &config);
+        ConfigWatcher::spawn(path.to_string(), config, files).map_err(Into::into)
+    }
+}
+
+/// Keeps a config up to date with its env file. See `Config::watch`.
+pub struct ConfigWatcher {
+    shared: Arc<WatchShared>,
+    updates: Mutex<mpsc::Receiver<Config>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+struct WatchShared {
+    current: Mutex<Config>,
+    reload_requested: AtomicBool,
+    running: AtomicBool,
+}
+
+impl ConfigWatcher {
+    fn spawn(path: String, config: Config, files: Fingerprints) -> std::io::Result<ConfigWatcher> {
+        #[cfg(unix)]
+        install_sighup_handler();
+
+        let shared = Arc::new(WatchShared {
+            current: Mutex::new(config),
+            reload_requested: AtomicBool::new(false),
+            running: AtomicBool::new(true),
+        });
+        let (updates_sender, updates) = mpsc::channel();
+        let worker = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("crumb-config-watcher".to_string())
+                .spawn(move || run_watcher(&shared, &path, files, updates_sender))?
+        };
+
+        Ok(ConfigWatcher {
+            shared,
+            updates: Mutex::new(updates),
+            worker: Some(worker),
+        })
+    }
+
+    /// The most recently loaded config.
+    pub fn current(&self) -> Config {
+        lock(&self.shared.current).clone()
+    }
+
+    /// Waits up to `timeout` for the next successful reload.
+    pub fn next_change(&self, timeout: Duration) -> Option<Config> {
+        lock(&self.updates).recv_timeout(timeout).ok()
+    }
+
+    /// Reloads on the watcher's next check even if nothing changed, as SIGHUP does.
+    pub fn reload(&self) {
+        self.shared.reload_requested.store(true, Ordering::Release);
+    }
+}
+
+impl Drop for ConfigWatcher {
+    fn drop(&mut self) {
+        self.shared.running.store(false, Ordering::Release);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+// The fingerprints of the env file, then the PEM and PSK files, then the secrets in `secrets_dir`,
+// then the files secrets were referred to by `file:`.
+type Fingerprints = Vec<Option<(SystemTime, u64)>>;
+
+fn run_watcher(
+    shared: &WatchShared,
+    path: &str,
+    mut files: Fingerprints,
+    updates: mpsc::Sender<Config>,
+) {
+    let mut sighups = SIGHUP_COUNT.load(Ordering::Acquire);
+
+    while shared.running.load(Ordering::Acquire) {
+        thread::sleep(WATCH_INTERVAL);
+
+        let count = SIGHUP_COUNT.load(Ordering::Acquire);
+        let signalled = count != sighups;
+        sighups = count;
+        let requested = shared.reload_requested.swap(false, Ordering::AcqRel);
+        let latest = fingerprints(fingerprint(path), &lock(&shared.current));
+        if !signalled && !requested && latest == files {
+            continue;
+        }
+        let env_file = latest[0];
+        files = latest;
+
+        match Config::from_env(Some(path)) {
+            Ok(config) => {
+                debug!(path, "config reloaded");
+                *lock(&shared.current) = config.clone();
+                files = fingerprints(env_file, &config);
+                let _ = updates.send(config);
+            }
+            Err(e) => warn!(
+                "Keeping previous config, reloading '{}' failed: {}",
+                path, e
+            ),
+        }
+    }
+}
+
+fn fingerprints(env_file: Option<(SystemTime, u64)>, config: &Config) -> Fingerprints {
+    let mut files = vec![
+        env_file,
+        fingerprint(&config.pem_path),
+        fingerprint(&config.psk_path),
+    ];
+    if let Ok(entries) = fs::read_dir(&config.secrets_dir) {
+        let mut secrets: Vec<_> = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .is_some_and(|name| name.to_string_lossy().starts_with("CRUMB_"))
+            })
+            .collect();
+        secrets.sort();
+        files.extend(secrets.iter().map(fingerprint));
+    }
+    files.extend(config.sources.secret_files.iter().map(fingerprint));
+    files
+}
+
+// Modification time and length, or None if the file cannot be read.
+fn fingerprint(path: impl AsRef<Path>) -> Option<(SystemTime, u64)> {
+    let metadata = metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+// Counts SIGHUPs received so that every watcher notices each one.
+static SIGHUP_COUNT: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(unix)]
+fn install_sighup_handler() {
+    extern "C" fn on_sighup(_: libc::c_int) {
+        SIGHUP_COUNT.fetch_add(1, Ordering::AcqRel);
+    }
+
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+    INSTALL.call_once(|| {
+        // SAFETY: the handler only touches an atomic, which is async-signal-safe.
+        unsafe {
+            libc::signal(libc::SIGHUP, on_sighup as *const () as libc::sighandler_t);
+        }
+    });
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
 }
 
 fn is_valid_ip(host: &str) -> bool {
     host.parse::<net::IpAddr>().is_ok()
 }
 
-fn get_env_var<T: str::FromStr + Default>(key: &str) -> T
-where
-    T::Err: std::fmt::Debug,
-{
-    env::var(key)
+fn is_multicast_ip(host: &str) -> bool {
+    host.parse::<net::IpAddr>()
+        .is_ok_and(|ip| ip.is_multicast())
+}
+
+// Looks up a `CRUMB_*` variable by name, with the error `env::var` would give.
+type Vars<'a> = dyn Fn(&str) -> Result<String, env::VarError> + 'a;
+
+fn get_var<T: str::FromStr>(var: &Vars<'_>, key: &str, default: T) -> T {
+    var(key)
         .ok()
         .and_then(|v| v.parse().ok())
-        .unwrap_or_default()
+        .unwrap_or(default)
 }
 
-fn set_env_vars(file_path: &str) -> Result<(), Box<dyn error::Error>> {
+// Parses a .env file into variables without touching the process environment.
+fn read_env_file(file_path: &str) -> crate::Result<HashMap<String, String>> {
     let file_size = metadata(file_path)?.len();
-    assert!(
-        file_size < MAX_ENV_FILE_SIZE,
-        ".env file exceeds BufReader::new limit of 8KiB"
-    );
+    if file_size >= MAX_ENV_FILE_SIZE {
+        return Err(Error::config(
+            ".env file exceeds BufReader::new limit of 8KiB",
+        ));
+    }
 
     let file = File::open(file_path)?;
     let reader = BufReader::new(file);
+    let mut vars = HashMap::new();
 
     for line in reader.lines() {
         let line = match line {
             Ok(l) => l.trim().to_string(),
             Err(e) => {
-                eprintln!("Skipping unreadable line in '{}': {}", file_path, e);
+                warn!("Skipping unreadable line in '{}': {}", file_path, e);
                 continue;
             }
         };
@@ -174,13 +1620,68 @@ fn set_env_vars(file_path: &str) -> Result<(), Box<dyn error::Error>> {
                 continue;
             }
 
-            env::set_var(key, value);
+            vars.insert(key.to_string(), value.to_string());
         } else {
             warn!("Skipping malformed ENV line: '{}'", line);
         }
     }
 
-    Ok(())
+    Ok(vars)
+}
+
+// Reads the `CRUMB_*` files in a secrets directory into variables.
+fn read_secrets(dir: &str) -> crate::Result<HashMap<String, String>> {
+    let mut vars = HashMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        // Kubernetes keeps its own bookkeeping beside the secrets, in `..data` and the like.
+        if !name.starts_with("CRUMB_") || !path.is_file() {
+            continue;
+        }
+        let value = fs::read_to_string(&path).map_err(|e| {
+            Error::config(format!("Reading secret '{}' failed: {}", path.display(), e))
+        })?;
+        let value = value.strip_suffix('\n').unwrap_or(&value);
+        let value = value.strip_suffix('\r').unwrap_or(value);
+        vars.insert(name.to_string(), value.to_string());
+    }
+    Ok(vars)
+}
+
+// Expands `${NAME}`, `${NAME:-default}` and `$$` in a value from the process environment.
+fn interpolate(value: &str) -> String {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(at) = rest.find('$') {
+        expanded.push_str(&rest[..at]);
+        rest = &rest[at..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            expanded.push('$');
+            rest = after;
+        } else if let Some((reference, after)) = rest
+            .strip_prefix("${")
+            .and_then(|inner| inner.split_once('}'))
+        {
+            let (name, fallback) = match reference.split_once(":-") {
+                Some((name, fallback)) => (name, Some(fallback)),
+                None => (reference, None),
+            };
+            match (env::var(name), fallback) {
+                (Ok(found), Some(fallback)) if found.is_empty() => expanded.push_str(fallback),
+                (Ok(found), _) => expanded.push_str(&found),
+                (Err(_), fallback) => expanded.push_str(fallback.unwrap_or_default()),
+            }
+            rest = after;
+        } else {
+            expanded.push('$');
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+    expanded
 }
 
 fn from_raw_string(input: &str) -> String {
@@ -191,6 +1692,7 @@ fn from_raw_string(input: &str) -> String {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
@@ -212,8 +1714,94 @@ mod tests {
         let vars = [
             "CRUMB_HOST",
             "CRUMB_PORT",
+            "CRUMB_BIND_HOST",
+            "CRUMB_BIND_PORT",
+            "CRUMB_DUAL_STACK",
+            "CRUMB_BIND_DEVICE",
+            "CRUMB_MULTICAST_GROUP",
+            "CRUMB_MULTICAST_IFACE",
+            "CRUMB_RECV_BUFFER_SIZE",
+            "CRUMB_SEND_BUFFER_SIZE",
+            "CRUMB_DSCP",
+            "CRUMB_BROADCAST",
+            "CRUMB_NODE_ID",
+            "CRUMB_DISCOVERY_PORT",
+            "CRUMB_DISCOVERY_INTERVAL_MS",
+            "CRUMB_TRANSPORT",
+            "CRUMB_SOCKET_PATH",
+            "CRUMB_PROXY",
+            "CRUMB_KEEPALIVE_INTERVAL_MS",
+            "CRUMB_REPORT_INTERVAL_MS",
+            "CRUMB_TIMESTAMPS",
+            "CRUMB_JITTER_DELAY_MS",
+            "CRUMB_CLOCK_SYNCED",
+            "CRUMB_KERNEL_TIMESTAMPS",
+            "CRUMB_CLOCK_SYNC_INTERVAL_MS",
+            "CRUMB_RELAY",
+            "CRUMB_RELAY_PAIR_RATE_KBPS",
+            "CRUMB_RELAY_PEER",
             "CRUMB_COMPRESSION_TYPE",
+            "CRUMB_COMPRESSION_POLICY",
+            "CRUMB_PIPELINE",
+            "CRUMB_PAYLOAD_FORMAT",
+            "CRUMB_SCHEMA_POLICY",
+            "CRUMB_SECURITY",
+            "CRUMB_PSK",
+            "CRUMB_PSK_PATH",
+            "CRUMB_PSK_IDENTITY",
+            "CRUMB_PSK_KEY_ID",
+            "CRUMB_PSK_KEYS",
+            "CRUMB_PSK_KEY_GRACE_SECS",
+            "CRUMB_CONTROL_KEY",
+            "CRUMB_AUTH_TOKEN",
+            "CRUMB_SECRETS_DIR",
+            "CRUMB_ACL_ALLOW",
+            "CRUMB_ACL_DENY",
+            "CRUMB_PEER_PACKET_RATE",
+            "CRUMB_PEER_HANDSHAKE_RATE",
+            "CRUMB_PEER_BAN_SECS",
             "CRUMB_RELIABLE",
+            "CRUMB_DELIVERY",
+            "CRUMB_ORDERED",
+            "CRUMB_REORDER_WINDOW",
+            "CRUMB_REPLAY_WINDOW",
+            "CRUMB_DEDUP_CACHE",
+            "CRUMB_PMTUD",
+            "CRUMB_SESSION_MIGRATION",
+            "CRUMB_MAX_RATE_KBPS",
+            "CRUMB_SEND_QUEUE_CAPACITY",
+            "CRUMB_SEND_QUEUE_POLICY",
+            "CRUMB_RECEIVE_WINDOW",
+            "CRUMB_SPOOL_DIR",
+            "CRUMB_SPOOL_MAX_BYTES",
+            "CRUMB_RECONNECT_ATTEMPTS",
+            "CRUMB_RECONNECT_BACKOFF_MS",
+            "CRUMB_RECONNECT_MAX_BACKOFF_MS",
+            "CRUMB_RECONNECT_JITTER",
+            "CRUMB_TLS_RESUMPTION",
+            "CRUMB_REKEY_INTERVAL_SECS",
+            "CRUMB_REKEY_BYTES",
+            "CRUMB_ADDRESS_VALIDATION",
+            "CRUMB_HANDSHAKE_TIMEOUT_MS",
+            "CRUMB_ALPN",
+            "CRUMB_WORKERS",
+            "CRUMB_HEALTH_PORT",
+            "CRUMB_MQTT_BROKER",
+            "CRUMB_MQTT_CLIENT_ID",
+            "CRUMB_MQTT_QOS",
+            "CRUMB_MQTT_UPLINK",
+            "CRUMB_MQTT_DOWNLINK",
+            "CRUMB_KAFKA_BROKERS",
+            "CRUMB_KAFKA_TOPIC",
+            "CRUMB_KAFKA_FILTERS",
+            "CRUMB_KAFKA_KEY",
+            "CRUMB_KAFKA_DELIVERY",
+            "CRUMB_KAFKA_BATCH_SIZE",
+            "CRUMB_KAFKA_LINGER_MS",
+            "CRUMB_INGEST_PORT",
+            "CRUMB_INGEST_TOKEN",
+            "CRUMB_CAPTURE_PATH",
+            "CRUMB_CAPTURE_POINT",
             "CRUMB_PEM_PATH",
             "CRUMB_PROTO_PATH",
         ];
@@ -229,23 +1817,31 @@ mod tests {
         // .test-env-full
         // CRUMB_HOST="1.2.3.4"
         // CRUMB_PORT=55555
+        // CRUMB_BIND_HOST="0.0.0.0"
+        // CRUMB_BIND_PORT=44444
+        // CRUMB_DUAL_STACK=false
         // CRUMB_COMPRESSION_TYPE=gzip
         // CRUMB_RELIABLE=false
         // CRUMB_PEM_PATH="its/just/a/test.pem"
         // CRUMB_PROTO_PATH="testing/tests/stuff.proto"
         clear_env_vars();
-        let config =
-            Config::from_env(Some("/home/neo/repos/crumb/src/util/.test-env-full")).unwrap();
+        let config = Config::from_env(Some(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/util/.test-env-full"
+        )))
+        .unwrap();
         assert_eq!(config.host, "1.2.3.4".to_owned());
         assert_eq!(config.port, 55555);
+        assert_eq!(config.bind_host, "0.0.0.0".to_owned());
+        assert_eq!(config.bind_port, 44444);
+        assert!(!config.dual_stack);
         assert_eq!(config.compression_type, CompressionType::Gzip);
-        assert_eq!(config.reliable, false);
+        assert_eq!(config.delivery, DeliveryMode::AtMostOnce);
         assert_eq!(config.pem_path, "its/just/a/test.pem".to_owned());
         assert_eq!(config.proto_path, "testing/tests/stuff.proto".to_owned());
     }
 
     #[test]
-    #[should_panic(expected = "Invalid IP address provided for CRUMB_HOST: 1234")]
     fn env_file_full_bad() {
         let _lock = get_env_lock();
         // .test-env-full-bad
@@ -256,14 +1852,15 @@ mod tests {
         // CRUMB_PEM_PATH=1
         // CRUMB_PROTO_PATH=1000
         clear_env_vars();
-        let config =
-            Config::from_env(Some("/home/neo/repos/crumb/src/util/.test-env-full-bad")).unwrap();
-        assert_eq!(config.host, "127.0.0.1".to_owned());
-        assert_eq!(config.port, 50505);
-        assert_eq!(config.compression_type, CompressionType::Zstd);
-        assert_eq!(config.reliable, true);
-        assert_eq!(config.pem_path, "cert.pem".to_owned());
-        assert_eq!(config.proto_path, "message.proto".to_owned());
+        let err = Config::from_env(Some(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/util/.test-env-full-bad"
+        )))
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Config(e) if e.to_string() == "Invalid IP address provided for CRUMB_HOST: 1234"
+        ));
     }
 
     #[test]
@@ -273,54 +1870,408 @@ mod tests {
         // # This is a comment
         // CRUMB_HOST="1.2.3.4" # This is also a comment
         // CRUMB_PORT=55555
+        // CRUMB_BIND_HOST="0.0.0.0"
+        // CRUMB_BIND_PORT=44444
+        // CRUMB_DUAL_STACK=false
         // CRUMB_COMPRESSION_TYPE=gzip
         // CRUMB_RELIABLE=false
         // # The comment in the path should be ignored
         // CRUMB_PEM_PATH="#its/just/a/test.pem" # And so should this "#"
         // CRUMB_PROTO_PATH="testing/tests/stuff.proto"
         clear_env_vars();
-        let config =
-            Config::from_env(Some("/home/neo/repos/crumb/src/util/.test-env-full")).unwrap();
+        let config = Config::from_env(Some(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/util/.test-env-full"
+        )))
+        .unwrap();
         assert_eq!(config.host, "1.2.3.4".to_owned());
         assert_eq!(config.port, 55555);
+        assert_eq!(config.bind_host, "0.0.0.0".to_owned());
+        assert_eq!(config.bind_port, 44444);
+        assert!(!config.dual_stack);
         assert_eq!(config.compression_type, CompressionType::Gzip);
-        assert_eq!(config.reliable, false);
+        assert_eq!(config.delivery, DeliveryMode::AtMostOnce);
         assert_eq!(config.pem_path, "its/just/a/test.pem".to_owned());
         assert_eq!(config.proto_path, "testing/tests/stuff.proto".to_owned());
     }
 
-    #[test]
-    #[should_panic(
-        expected = "CRUMB_PROTO_PATH not set or invalid. A .proto file is required. Error: environment variable not found"
-    )]
+    const MISSING_PROTO_PATH: &str = "Invalid config: CRUMB_PROTO_PATH not set or invalid. A .proto file is required. Error: environment variable not found";
 
+    #[test]
     fn env_file_empty() {
         let _lock = get_env_lock();
         clear_env_vars();
-        let config =
-            Config::from_env(Some("/home/neo/repos/crumb/src/util/.test-env-empty")).unwrap();
-        assert_eq!(config.host, "127.0.0.1".to_string());
-        assert_eq!(config.port, 50505);
-        assert_eq!(config.compression_type, CompressionType::Zstd);
-        assert_eq!(config.reliable, true);
-        assert_eq!(config.pem_path, "cert.pem".to_string());
-        assert_eq!(config.proto_path, "message.proto".to_string());
+        let err = Config::from_env(Some(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/util/.test-env-empty"
+        )))
+        .unwrap_err();
+        assert_eq!(err.to_string(), MISSING_PROTO_PATH);
     }
 
     #[test]
-    #[should_panic(
-        expected = "CRUMB_PROTO_PATH not set or invalid. A .proto file is required. Error: environment variable not found"
-    )]
     fn env_file_missing() {
         let _lock = get_env_lock();
         clear_env_vars();
+        let err = Config::from_env(None).unwrap_err();
+        assert_eq!(err.to_string(), MISSING_PROTO_PATH);
+    }
+
+    fn write_watched(path: &std::path::Path, port: u16) {
+        std::fs::write(
+            path,
+            format!("CRUMB_PORT={}\nCRUMB_PROTO_PATH=message.proto\n", port),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn watch_reloads_changed_files() {
+        let _lock = get_env_lock();
+        clear_env_vars();
+        let path = env::temp_dir().join(format!("crumb-watch-{}.env", std::process::id()));
+        write_watched(&path, 40001);
+
+        let watcher = Config::watch(path.to_str().unwrap()).unwrap();
+        assert_eq!(watcher.current().port, 40001);
+
+        // Length changes too, so coarse modification times cannot hide the edit.
+        write_watched(&path, 4002);
+        let config = watcher.next_change(Duration::from_secs(5)).unwrap();
+        assert_eq!(config.port, 4002);
+        assert_eq!(watcher.current().port, 4002);
+
+        watcher.reload();
+        assert!(watcher.next_change(Duration::from_secs(5)).is_some());
+
+        #[cfg(unix)]
+        {
+            // SAFETY: the watcher has installed a SIGHUP handler, so this does not terminate.
+            unsafe { libc::raise(libc::SIGHUP) };
+            assert!(watcher.next_change(Duration::from_secs(5)).is_some());
+        }
+
+        drop(watcher);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn secrets_and_references_fill_in_values() {
+        let _lock = get_env_lock();
+        clear_env_vars();
+        let dir = env::temp_dir().join(format!("crumb-secrets-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("..data")).unwrap();
+        fs::write(dir.join("CRUMB_PSK_IDENTITY"), "node-a\n").unwrap();
+        fs::write(dir.join("CRUMB_PROTO_PATH"), "message.proto").unwrap();
+        fs::write(dir.join("CRUMB_WORKERS"), "2").unwrap();
+        fs::write(
+            dir.join("CRUMB_AUTH_TOKEN"),
+            "pa$$word-${CRUMB_TEST_POD_IP}",
+        )
+        .unwrap();
+        fs::write(dir.join("README"), "not a variable").unwrap();
+        env::set_var("CRUMB_SECRETS_DIR", &dir);
+        env::set_var("CRUMB_WORKERS", "8");
+        env::set_var("CRUMB_TEST_POD_IP", "10.1.2.3");
+        env::set_var("CRUMB_NODE_ID", "${CRUMB_TEST_POD_IP}");
+
+        // Secrets and the environment are read verbatim.
         let config = Config::from_env(None).unwrap();
-        assert_eq!(config.host, "127.0.0.1".to_string());
-        assert_eq!(config.port, 50505);
-        assert_eq!(config.compression_type, CompressionType::Zstd);
-        assert_eq!(config.reliable, true);
-        assert_eq!(config.pem_path, "cert.pem".to_string());
-        assert_eq!(config.proto_path, "message.proto".to_string());
+        assert_eq!(config.psk_identity, "node-a");
+        assert_eq!(config.workers, 8);
+        assert_eq!(config.auth_token, "pa$$word-${CRUMB_TEST_POD_IP}");
+        assert_eq!(config.node_id, "${CRUMB_TEST_POD_IP}");
+        let secret = Source::Secret(dir.to_str().unwrap().to_string());
+        assert_eq!(config.sources.get("CRUMB_PSK_IDENTITY"), &secret);
+        assert_eq!(config.sources.get("CRUMB_WORKERS"), &Source::Env);
+
+        // The file layer's reference to the directory is honoured, and loses to the secrets,
+        // and its references to the environment are expanded.
+        env::remove_var("CRUMB_SECRETS_DIR");
+        env::remove_var("CRUMB_WORKERS");
+        env::remove_var("CRUMB_NODE_ID");
+        let path = dir.with_extension("toml");
+        fs::write(
+            &path,
+            format!(
+                "workers = 1\nhost = \"${{CRUMB_TEST_POD_IP}}\"\n\
+                 node_id = \"${{CRUMB_TEST_UNSET:-pod}}-$$-${{CRUMB_TEST_UNSET}}\"\n\
+                 [security]\nsecrets_dir = {:?}\n",
+                dir
+            ),
+        )
+        .unwrap();
+        let config = Config::from_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(config.workers, 2);
+        assert_eq!(config.host, "10.1.2.3");
+        assert_eq!(config.node_id, "pod-$-");
+        assert_eq!(config.auth_token, "pa$$word-${CRUMB_TEST_POD_IP}");
+
+        env::remove_var("CRUMB_TEST_POD_IP");
+        clear_env_vars();
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn secret_references_are_resolved() {
+        let _lock = get_env_lock();
+        clear_env_vars();
+        env::set_var("CRUMB_PROTO_PATH", "message.proto");
+        env::set_var("CRUMB_TEST_TOKEN", "token");
+        env::set_var("CRUMB_AUTH_TOKEN", "env:CRUMB_TEST_TOKEN");
+
+        let config = Config::builder()
+            .set("CRUMB_CONTROL_KEY", "vault:crumb/control")
+            .set("CRUMB_INGEST_TOKEN", "plain")
+            .set("CRUMB_NODE_ID", "env:CRUMB_TEST_TOKEN")
+            .secret_provider("vault", |reference: &str| {
+                Ok(format!("{}-key\n", reference))
+            })
+            .build()
+            .unwrap();
+        assert_eq!(config.auth_token, "token");
+        assert_eq!(config.control_key, "crumb/control-key");
+        assert_eq!(config.ingest_token, "plain");
+        assert_eq!(config.node_id, "env:CRUMB_TEST_TOKEN");
+        assert_eq!(config.sources.get("CRUMB_AUTH_TOKEN"), &Source::Env);
+
+        env::remove_var("CRUMB_TEST_TOKEN");
+        assert!(Config::from_env(None).is_err());
+        clear_env_vars();
+    }
+
+    #[test]
+    fn watch_reloads_rotated_secrets() {
+        let _lock = get_env_lock();
+        clear_env_vars();
+        let dir = env::temp_dir().join(format!("crumb-watch-secrets-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("CRUMB_PSK_IDENTITY"), "old").unwrap();
+        let path = dir.with_extension("env");
+        fs::write(
+            &path,
+            format!(
+                "CRUMB_SECRETS_DIR={}\nCRUMB_PROTO_PATH=message.proto\n",
+                dir.display()
+            ),
+        )
+        .unwrap();
+
+        let watcher = Config::watch(path.to_str().unwrap()).unwrap();
+        assert_eq!(watcher.current().psk_identity, "old");
+        fs::write(dir.join("CRUMB_PSK_IDENTITY"), "rotated").unwrap();
+        let config = watcher.next_change(Duration::from_secs(5)).unwrap();
+        assert_eq!(config.psk_identity, "rotated");
+
+        drop(watcher);
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn watch_reloads_rotated_secret_references() {
+        let _lock = get_env_lock();
+        clear_env_vars();
+        let secret = env::temp_dir().join(format!("crumb-watch-token-{}", std::process::id()));
+        fs::write(&secret, "old\n").unwrap();
+        let path = secret.with_extension("env");
+        fs::write(
+            &path,
+            format!(
+                "CRUMB_AUTH_TOKEN=file:{}\nCRUMB_PROTO_PATH=message.proto\n",
+                secret.display()
+            ),
+        )
+        .unwrap();
+
+        let watcher = Config::watch(path.to_str().unwrap()).unwrap();
+        assert_eq!(watcher.current().auth_token, "old");
+        fs::write(&secret, "rotated\n").unwrap();
+        let config = watcher.next_change(Duration::from_secs(5)).unwrap();
+        assert_eq!(config.auth_token, "rotated");
+
+        drop(watcher);
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(secret);
+    }
+
+    #[test]
+    fn env_file_leaves_the_environment_alone() {
+        let _lock = get_env_lock();
+        clear_env_vars();
+        env::set_var("CRUMB_PORT", "5000");
+        env::set_var("CRUMB_WORKERS", "8");
+
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/src/util/.test-env-full");
+        let config = Config::from_env(Some(path)).unwrap();
+        assert_eq!(config.port, 55555);
+        assert_eq!(config.workers, 8);
+        assert!(env::var("CRUMB_HOST").is_err());
+        assert_eq!(env::var("CRUMB_PORT").unwrap(), "5000");
+        assert_eq!(
+            config.sources.get("CRUMB_PORT"),
+            &Source::File(path.to_string())
+        );
+        assert_eq!(config.sources.get("CRUMB_WORKERS"), &Source::Env);
+
+        clear_env_vars();
+    }
+
+    #[test]
+    fn toml_file_with_env_overrides() {
+        let _lock = get_env_lock();
+        clear_env_vars();
+        let path = env::temp_dir().join(format!("crumb-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            port = 4000
+            proto_path = "file.proto"
+
+            [transport]
+            type = "quic"
+            send_queue_policy = "drop-oldest"
+
+            [tls]
+            pem_path = "file.pem"
+            rekey_interval_secs = 60
+
+            [compression]
+            type = "none"
+            "#,
+        )
+        .unwrap();
+        env::set_var("CRUMB_PORT", "5000");
+
+        let config = Config::from_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(config.port, 5000);
+        assert_eq!(config.proto_path, "file.proto");
+        assert_eq!(config.transport_type, TransportType::Quic);
+        assert_eq!(config.send_queue_policy, QueuePolicy::DropOldest);
+        assert_eq!(config.pem_path, "file.pem");
+        assert_eq!(config.rekey_interval, Duration::from_secs(60));
+        assert_eq!(config.compression_type, CompressionType::None);
+        assert_eq!(config.delivery, DeliveryMode::ExactlyOnce);
+
+        clear_env_vars();
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn layers_take_precedence_in_order() {
+        let _lock = get_env_lock();
+        clear_env_vars();
+        let path = env::temp_dir().join(format!("crumb-layers-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "port = 4000\nproto_path = \"file.proto\"\n[transport]\nreliable = false\n[tls]\npem_path = \"file.pem\"",
+        )
+        .unwrap();
+        env::set_var("CRUMB_PORT", "5000");
+        env::set_var("CRUMB_RELIABLE", "true");
+
+        let path = path.to_str().unwrap();
+        let config = Config::builder()
+            .file(path)
+            .set("CRUMB_PORT", 6000)
+            .build()
+            .unwrap();
+        assert_eq!(config.port, 6000);
+        assert_eq!(config.delivery, DeliveryMode::ExactlyOnce);
+        assert_eq!(config.pem_path, "file.pem");
+        assert_eq!(config.workers, 4);
+
+        let sources = &config.sources;
+        assert_eq!(sources.get("CRUMB_PORT"), &Source::Override);
+        assert_eq!(sources.get("CRUMB_RELIABLE"), &Source::Env);
+        assert_eq!(
+            sources.get("CRUMB_PEM_PATH"),
+            &Source::File(path.to_string())
+        );
+        assert_eq!(sources.get("CRUMB_WORKERS"), &Source::Default);
+        assert!(sources.to_string().contains("CRUMB_PORT: override\n"));
+
+        clear_env_vars();
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn delivery_modes_override_the_reliable_flag() {
+        let _lock = get_env_lock();
+        clear_env_vars();
+        let build = |vars: &[(&str, &str)]| {
+            let builder = vars
+                .iter()
+                .fold(Config::builder(), |builder, (var, value)| {
+                    builder.set(var, value)
+                });
+            builder
+                .set("CRUMB_PROTO_PATH", "")
+                .build()
+                .unwrap()
+                .delivery
+        };
+        assert_eq!(build(&[]), DeliveryMode::ExactlyOnce);
+        assert_eq!(
+            build(&[("CRUMB_RELIABLE", "false")]),
+            DeliveryMode::AtMostOnce
+        );
+        assert_eq!(
+            build(&[
+                ("CRUMB_RELIABLE", "false"),
+                ("CRUMB_DELIVERY", "at-least-once")
+            ]),
+            DeliveryMode::AtLeastOnce
+        );
+    }
+
+    #[test]
+    fn host_lists_become_endpoints() {
+        let _lock = get_env_lock();
+        clear_env_vars();
+        let config = Config::builder()
+            .set("CRUMB_HOST", "10.0.0.1, ::1")
+            .set("CRUMB_PORT", 6000)
+            .set("CRUMB_PROTO_PATH", "")
+            .build()
+            .unwrap();
+        assert_eq!(config.host, "10.0.0.1");
+        assert_eq!(
+            config.endpoints,
+            [
+                "10.0.0.1:6000".parse().unwrap(),
+                "[::1]:6000".parse().unwrap()
+            ]
+        );
+
+        let config = Config::builder()
+            .set("CRUMB_HOST", "10.0.0.1")
+            .set("CRUMB_PROTO_PATH", "")
+            .build()
+            .unwrap();
+        assert!(config.endpoints.is_empty());
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn yaml_file() {
+        let _lock = get_env_lock();
+        clear_env_vars();
+        let path = env::temp_dir().join(format!("crumb-{}.yaml", std::process::id()));
+        std::fs::write(
+            &path,
+            "port: 4000\nproto_path: file.proto\ntransport:\n  reliable: false\n",
+        )
+        .unwrap();
+
+        let config = Config::from_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(config.port, 4000);
+        assert_eq!(config.delivery, DeliveryMode::AtMostOnce);
+
+        let _ = std::fs::remove_file(path);
     }
 
     #[test]
@@ -371,6 +2322,68 @@ mod tests {
         assert_eq!(CompressionType::Zstd, "".parse().unwrap());
     }
 
+    #[test]
+    fn pipeline_from_str() {
+        let stages = |s: &str| s.parse::<Pipeline>().map(|p| p.stages().to_vec());
+        assert_eq!(
+            stages("codec, compress"),
+            Ok(vec![Stage::Codec, Stage::Compress])
+        );
+        assert_eq!(stages("Compress"), Ok(vec![Stage::Compress]));
+        assert_eq!(stages("none"), Ok(Vec::new()));
+        assert!(stages("codec,codec").is_err());
+        assert!(stages("compress,sign").is_err());
+    }
+
+    #[test]
+    fn compression_policy_from_str() {
+        let policy: CompressionPolicy = "type:7=gzip, priority:Control=none,priority:bulk=gzip"
+            .parse()
+            .unwrap();
+        let choose = |msg_type, priority| policy.choose(CompressionType::Zstd, msg_type, priority);
+        assert_eq!(choose(7, Priority::Control), CompressionType::Gzip);
+        assert_eq!(choose(0, Priority::Control), CompressionType::None);
+        assert_eq!(choose(0, Priority::Normal), CompressionType::Zstd);
+        assert_eq!("".parse(), Ok(CompressionPolicy::default()));
+        for bad in [
+            "type:7",
+            "type:x=gzip",
+            "priority:low=none",
+            "topic:a=gzip",
+            "type:7=lz4",
+        ] {
+            assert!(bad.parse::<CompressionPolicy>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn payload_format_from_str() {
+        assert_eq!(PayloadFormat::Protobuf, "protobuf".parse().unwrap());
+        assert_eq!(PayloadFormat::Json, "JSON".parse().unwrap());
+        assert_eq!(PayloadFormat::Msgpack, "msgpack".parse().unwrap());
+        assert_eq!(PayloadFormat::Raw, "raw".parse().unwrap());
+        assert!("xml".parse::<PayloadFormat>().is_err());
+    }
+
+    #[test]
+    fn transport_type_from_str() {
+        assert_eq!(TransportType::Udp, "udp".parse().unwrap());
+        assert_eq!(TransportType::Udp, "UDP".parse().unwrap());
+        assert_eq!(TransportType::Quic, "quic".parse().unwrap());
+        assert_eq!(TransportType::Unix, "unix".parse().unwrap());
+        assert!("carrier-pigeon".parse::<TransportType>().is_err());
+    }
+
+    #[test]
+    fn queue_policy_from_str() {
+        assert_eq!(QueuePolicy::Block, "block".parse().unwrap());
+        assert_eq!(QueuePolicy::Error, "Error".parse().unwrap());
+        assert_eq!(QueuePolicy::DropOldest, "drop-oldest".parse().unwrap());
+        assert!("drop-newest".parse::<QueuePolicy>().is_err());
+        assert_eq!(DeliveryMode::AtLeastOnce, "At-Least-Once".parse().unwrap());
+        assert!("once".parse::<DeliveryMode>().is_err());
+    }
+
     #[test]
     fn good_ipv4() {
         assert_eq!(true, is_valid_ip("127.0.0.1"))
@@ -390,4 +2403,12 @@ mod tests {
     fn bad_ipv6() {
         assert_eq!(false, is_valid_ip(":1"))
     }
+
+    #[test]
+    fn multicast_ips() {
+        assert!(is_multicast_ip("239.255.0.1"));
+        assert!(is_multicast_ip("ff02::1"));
+        assert!(!is_multicast_ip("127.0.0.1"));
+        assert!(!is_multicast_ip("::1"));
+    }
 }
@@ -0,0 +1,354 @@
+//! TOML and YAML config files.
+//!
+//! Values map onto the `CRUMB_*` environment variables so both go through the same parsing.
+//! Durations are given in the same units as their variables, and arrays of strings stand for
+//! comma separated lists:
+//!
+//! ```toml
+//! host = "10.0.0.2"
+//! port = 50505
+//! bind_host = "::"
+//! bind_port = 0
+//! dual_stack = true
+//! bind_device = ""
+//! multicast_group = ""
+//! multicast_iface = ""
+//! broadcast = false
+//! node_id = ""
+//! discovery_port = 50506
+//! discovery_interval_ms = 1000
+//! workers = 4
+//! health_port = 0
+//! proto_path = "message.proto"
+//! schema_policy = "warn"
+//!
+//! [transport]
+//! type = "udp"
+//! socket_path = ""
+//! proxy = ""
+//! keepalive_interval_ms = 0
+//! report_interval_ms = 0
+//! timestamps = false
+//! jitter_delay_ms = 0
+//! clock_synced = false
+//! kernel_timestamps = false
+//! clock_sync_interval_ms = 0
+//! delivery = "exactly-once"
+//! ordered = false
+//! reorder_window = 64
+//! replay_window = 1024
+//! dedup_cache = 0
+//! pmtud = false
+//! session_migration = true
+//! max_rate_kbps = 0
+//! send_queue_capacity = 1024
+//! send_queue_policy = "block"
+//! receive_window = 0
+//! spool_dir = ""
+//! spool_max_bytes = 67108864
+//! recv_buffer_size = 0
+//! send_buffer_size = 0
+//! dscp = 0
+//!
+//! [reconnect]
+//! attempts = 0
+//! backoff_ms = 100
+//! max_backoff_ms = 30000
+//! jitter = 0.5
+//!
+//! [relay]
+//! enabled = false
+//! pair_rate_kbps = 0
+//! peer = ""
+//!
+//! [tls]
+//! pem_path = "cert.pem"
+//! resumption = true
+//! rekey_interval_secs = 3600
+//! rekey_bytes = 1073741824
+//! address_validation = true
+//! handshake_timeout_ms = 10000
+//! alpn = ["crumb/1"]
+//!
+//! [security]
+//! mode = "none"
+//! psk = ""
+//! psk_path = ""
+//! psk_identity = ""
+//! psk_key_id = 0
+//! psk_keys = ""
+//! psk_key_grace_secs = 3600
+//! control_key = ""
+//! auth_token = ""
+//! secrets_dir = ""
+//!
+//! [acl]
+//! allow = ["10.0.0.0/8", "fd00::/8"]
+//! deny = []
+//!
+//! [limits]
+//! packets_per_sec = 0
+//! handshakes_per_sec = 0
+//! ban_secs = 0
+//!
+//! [mqtt]
+//! broker = "localhost:1883"
+//! client_id = ""
+//! qos = 1
+//! uplink = ["sensors/#=site1/sensors/#"]
+//! downlink = ["site1/commands/#=commands/#"]
+//!
+//! [kafka]
+//! brokers = ["kafka1:9092", "kafka2:9092"]
+//! topic = "crumb-telemetry"
+//! filters = ["sensors/#"]
+//! key = "level:1"
+//! delivery = "at-least-once"
+//! batch_size = 1000
+//! linger_ms = 5
+//!
+//! [ingest]
+//! port = 8080
+//! token = ""
+//!
+//! [capture]
+//! path = ""
+//! point = "wire"
+//!
+//! [compression]
+//! type = "zstd"
+//! policy = "priority:control=none,priority:bulk=zstd"
+//! pipeline = "compress,codec"
+//!
+//! [payload]
+//! format = "protobuf"
+//! ```
+
+use crate::error::Error;
+use std::{collections::HashMap, ffi::OsStr, fs, path::Path};
+
+// Dotted file keys and the environment variables they stand for.
+pub(super) const KEYS: &[(&str, &str)] = &[
+    ("host", "CRUMB_HOST"),
+    ("port", "CRUMB_PORT"),
+    ("bind_host", "CRUMB_BIND_HOST"),
+    ("bind_port", "CRUMB_BIND_PORT"),
+    ("dual_stack", "CRUMB_DUAL_STACK"),
+    ("bind_device", "CRUMB_BIND_DEVICE"),
+    ("multicast_group", "CRUMB_MULTICAST_GROUP"),
+    ("multicast_iface", "CRUMB_MULTICAST_IFACE"),
+    ("broadcast", "CRUMB_BROADCAST"),
+    ("node_id", "CRUMB_NODE_ID"),
+    ("discovery_port", "CRUMB_DISCOVERY_PORT"),
+    ("discovery_interval_ms", "CRUMB_DISCOVERY_INTERVAL_MS"),
+    ("workers", "CRUMB_WORKERS"),
+    ("health_port", "CRUMB_HEALTH_PORT"),
+    ("proto_path", "CRUMB_PROTO_PATH"),
+    ("schema_policy", "CRUMB_SCHEMA_POLICY"),
+    ("transport.type", "CRUMB_TRANSPORT"),
+    ("transport.socket_path", "CRUMB_SOCKET_PATH"),
+    ("transport.proxy", "CRUMB_PROXY"),
+    (
+        "transport.keepalive_interval_ms",
+        "CRUMB_KEEPALIVE_INTERVAL_MS",
+    ),
+    ("transport.report_interval_ms", "CRUMB_REPORT_INTERVAL_MS"),
+    ("transport.timestamps", "CRUMB_TIMESTAMPS"),
+    ("transport.jitter_delay_ms", "CRUMB_JITTER_DELAY_MS"),
+    ("transport.clock_synced", "CRUMB_CLOCK_SYNCED"),
+    ("transport.kernel_timestamps", "CRUMB_KERNEL_TIMESTAMPS"),
+    (
+        "transport.clock_sync_interval_ms",
+        "CRUMB_CLOCK_SYNC_INTERVAL_MS",
+    ),
+    ("transport.reliable", "CRUMB_RELIABLE"),
+    ("transport.delivery", "CRUMB_DELIVERY"),
+    ("transport.ordered", "CRUMB_ORDERED"),
+    ("transport.reorder_window", "CRUMB_REORDER_WINDOW"),
+    ("transport.replay_window", "CRUMB_REPLAY_WINDOW"),
+    ("transport.dedup_cache", "CRUMB_DEDUP_CACHE"),
+    ("transport.pmtud", "CRUMB_PMTUD"),
+    ("transport.session_migration", "CRUMB_SESSION_MIGRATION"),
+    ("transport.max_rate_kbps", "CRUMB_MAX_RATE_KBPS"),
+    ("transport.send_queue_capacity", "CRUMB_SEND_QUEUE_CAPACITY"),
+    ("transport.send_queue_policy", "CRUMB_SEND_QUEUE_POLICY"),
+    ("transport.receive_window", "CRUMB_RECEIVE_WINDOW"),
+    ("transport.spool_dir", "CRUMB_SPOOL_DIR"),
+    ("transport.spool_max_bytes", "CRUMB_SPOOL_MAX_BYTES"),
+    ("transport.recv_buffer_size", "CRUMB_RECV_BUFFER_SIZE"),
+    ("transport.send_buffer_size", "CRUMB_SEND_BUFFER_SIZE"),
+    ("transport.dscp", "CRUMB_DSCP"),
+    ("reconnect.attempts", "CRUMB_RECONNECT_ATTEMPTS"),
+    ("reconnect.backoff_ms", "CRUMB_RECONNECT_BACKOFF_MS"),
+    ("reconnect.max_backoff_ms", "CRUMB_RECONNECT_MAX_BACKOFF_MS"),
+    ("reconnect.jitter", "CRUMB_RECONNECT_JITTER"),
+    ("relay.enabled", "CRUMB_RELAY"),
+    ("relay.pair_rate_kbps", "CRUMB_RELAY_PAIR_RATE_KBPS"),
+    ("relay.peer", "CRUMB_RELAY_PEER"),
+    ("tls.pem_path", "CRUMB_PEM_PATH"),
+    ("tls.resumption", "CRUMB_TLS_RESUMPTION"),
+    ("tls.rekey_interval_secs", "CRUMB_REKEY_INTERVAL_SECS"),
+    ("tls.rekey_bytes", "CRUMB_REKEY_BYTES"),
+    ("tls.address_validation", "CRUMB_ADDRESS_VALIDATION"),
+    ("tls.handshake_timeout_ms", "CRUMB_HANDSHAKE_TIMEOUT_MS"),
+    ("tls.alpn", "CRUMB_ALPN"),
+    ("security.mode", "CRUMB_SECURITY"),
+    ("security.psk", "CRUMB_PSK"),
+    ("security.psk_path", "CRUMB_PSK_PATH"),
+    ("security.psk_identity", "CRUMB_PSK_IDENTITY"),
+    ("security.psk_key_id", "CRUMB_PSK_KEY_ID"),
+    ("security.psk_keys", "CRUMB_PSK_KEYS"),
+    ("security.psk_key_grace_secs", "CRUMB_PSK_KEY_GRACE_SECS"),
+    ("security.control_key", "CRUMB_CONTROL_KEY"),
+    ("security.auth_token", "CRUMB_AUTH_TOKEN"),
+    ("security.secrets_dir", "CRUMB_SECRETS_DIR"),
+    ("acl.allow", "CRUMB_ACL_ALLOW"),
+    ("acl.deny", "CRUMB_ACL_DENY"),
+    ("limits.packets_per_sec", "CRUMB_PEER_PACKET_RATE"),
+    ("limits.handshakes_per_sec", "CRUMB_PEER_HANDSHAKE_RATE"),
+    ("limits.ban_secs", "CRUMB_PEER_BAN_SECS"),
+    ("mqtt.broker", "CRUMB_MQTT_BROKER"),
+    ("mqtt.client_id", "CRUMB_MQTT_CLIENT_ID"),
+    ("mqtt.qos", "CRUMB_MQTT_QOS"),
+    ("mqtt.uplink", "CRUMB_MQTT_UPLINK"),
+    ("mqtt.downlink", "CRUMB_MQTT_DOWNLINK"),
+    ("kafka.brokers", "CRUMB_KAFKA_BROKERS"),
+    ("kafka.topic", "CRUMB_KAFKA_TOPIC"),
+    ("kafka.filters", "CRUMB_KAFKA_FILTERS"),
+    ("kafka.key", "CRUMB_KAFKA_KEY"),
+    ("kafka.delivery", "CRUMB_KAFKA_DELIVERY"),
+    ("kafka.batch_size", "CRUMB_KAFKA_BATCH_SIZE"),
+    ("kafka.linger_ms", "CRUMB_KAFKA_LINGER_MS"),
+    ("ingest.port", "CRUMB_INGEST_PORT"),
+    ("ingest.token", "CRUMB_INGEST_TOKEN"),
+    ("capture.path", "CRUMB_CAPTURE_PATH"),
+    ("capture.point", "CRUMB_CAPTURE_POINT"),
+    ("compression.type", "CRUMB_COMPRESSION_TYPE"),
+    ("compression.policy", "CRUMB_COMPRESSION_POLICY"),
+    ("compression.pipeline", "CRUMB_PIPELINE"),
+    ("payload.format", "CRUMB_PAYLOAD_FORMAT"),
+];
+
+/// Reads the file at `path` into values keyed by environment variable name.
+pub(super) fn load(path: &str) -> crate::Result<HashMap<String, String>> {
+    let text = fs::read_to_string(path)?;
+    let table: toml::Table = match Path::new(path).extension().and_then(OsStr::to_str) {
+        Some("toml") => toml::from_str(&text).map_err(Error::config)?,
+        #[cfg(feature = "yaml")]
+        Some("yaml" | "yml") => serde_yaml::from_str(&text).map_err(Error::config)?,
+        _ => {
+            return Err(Error::config(format!(
+                "Unsupported config file format: {}",
+                path
+            )))
+        }
+    };
+
+    let mut values = HashMap::new();
+    flatten(&table, "", &mut values)?;
+    Ok(values)
+}
+
+fn flatten(
+    table: &toml::Table,
+    section: &str,
+    values: &mut HashMap<String, String>,
+) -> crate::Result<()> {
+    for (key, value) in table {
+        let dotted = match section {
+            "" => key.clone(),
+            section => format!("{}.{}", section, key),
+        };
+
+        let value = match value {
+            toml::Value::Table(table) => {
+                flatten(table, &dotted, values)?;
+                continue;
+            }
+            toml::Value::String(value) => value.clone(),
+            toml::Value::Integer(value) => value.to_string(),
+            toml::Value::Float(value) => value.to_string(),
+            toml::Value::Boolean(value) => value.to_string(),
+            toml::Value::Array(items) => match items
+                .iter()
+                .map(toml::Value::as_str)
+                .collect::<Option<Vec<_>>>()
+            {
+                Some(items) => items.join(","),
+                None => {
+                    return Err(Error::config(format!(
+                        "Unsupported value for config key: {}",
+                        dotted
+                    )))
+                }
+            },
+            _ => {
+                return Err(Error::config(format!(
+                    "Unsupported value for config key: {}",
+                    dotted
+                )))
+            }
+        };
+        let Some((_, var)) = KEYS.iter().find(|(file_key, _)| *file_key == dotted) else {
+            return Err(Error::config(format!("Unknown config key: {}", dotted)));
+        };
+        values.insert(var.to_string(), value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> crate::Result<HashMap<String, String>> {
+        let mut values = HashMap::new();
+        flatten(
+            &toml::from_str(text).map_err(Error::config)?,
+            "",
+            &mut values,
+        )?;
+        Ok(values)
+    }
+
+    #[test]
+    fn sections_map_to_variables() {
+        let values = parse(
+            "port = 4000\n[transport]\ntype = \"quic\"\nreliable = false\n[tls]\npem_path = \"a.pem\"",
+        )
+        .unwrap();
+        assert_eq!(values["CRUMB_PORT"], "4000");
+        assert_eq!(values["CRUMB_TRANSPORT"], "quic");
+        assert_eq!(values["CRUMB_RELIABLE"], "false");
+        assert_eq!(values["CRUMB_PEM_PATH"], "a.pem");
+    }
+
+    #[test]
+    fn string_arrays_become_lists() {
+        let values = parse("[acl]\nallow = [\"10.0.0.0/8\", \"::1\"]\ndeny = []").unwrap();
+        assert_eq!(values["CRUMB_ACL_ALLOW"], "10.0.0.0/8,::1");
+        assert_eq!(values["CRUMB_ACL_DENY"], "");
+        assert!(parse("[acl]\nallow = [1]").is_err());
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let err = parse("[transport]\nhost = \"10.0.0.1\"").unwrap_err();
+        assert!(
+            matches!(&err, Error::Config(e) if e.to_string() == "Unknown config key: transport.host")
+        );
+        assert!(parse("ports = [1, 2]").is_err());
+    }
+
+    #[test]
+    fn format_comes_from_the_extension() {
+        let err = load(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/util/.test-env-full"
+        ))
+        .unwrap_err();
+        assert!(
+            matches!(&err, Error::Config(e) if e.to_string().starts_with("Unsupported config file format"))
+        );
+    }
+}
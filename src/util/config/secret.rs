@@ -0,0 +1,166 @@
+//! Secret references. The values that hold secrets, those of the variables in `SECRET_VARS`,
+//! may instead say where to find the secret, so that raw keys need not sit in an env file or a
+//! config file:
+//!
+//! ```text
+//! CRUMB_PSK=file:/run/secrets/psk
+//! CRUMB_AUTH_TOKEN=env:NODE_TOKEN
+//! ```
+//!
+//! `file:` reads a file and `env:` another environment variable, dropping a trailing newline.
+//! `ConfigBuilder::secret_provider` adds schemes of an application's own, for a vault or the
+//! operating system's keyring, or `CommandProvider` for secrets a program prints. Values without
+//! a known scheme are the secret itself. A `ConfigWatcher` reloads when a file a secret was read
+//! from changes, resolving every reference afresh.
+
+use crate::error::Error;
+use std::{collections::BTreeMap, env, fmt, fs, io, process::Command};
+
+/// The variables whose values may refer to secrets.
+pub const SECRET_VARS: &[&str] = &[
+    "CRUMB_PSK",
+    "CRUMB_PSK_KEYS",
+    "CRUMB_CONTROL_KEY",
+    "CRUMB_AUTH_TOKEN",
+    "CRUMB_INGEST_TOKEN",
+];
+
+/// Looks up secrets for a scheme registered with `ConfigBuilder::secret_provider`.
+pub trait SecretProvider: Send + Sync {
+    /// The secret `reference` names, the part of the value after the scheme and its colon.
+    fn resolve(&self, reference: &str) -> io::Result<String>;
+}
+
+impl<F> SecretProvider for F
+where
+    F: Fn(&str) -> io::Result<String> + Send + Sync,
+{
+    fn resolve(&self, reference: &str) -> io::Result<String> {
+        self(reference)
+    }
+}
+
+/// The providers a config is built with, by scheme.
+pub(super) struct Providers(BTreeMap<String, Box<dyn SecretProvider>>);
+
+impl Default for Providers {
+    fn default() -> Self {
+        let mut providers = Providers(BTreeMap::new());
+        providers.insert("file", read_file);
+        providers.insert("env", read_env);
+        providers
+    }
+}
+
+impl fmt::Debug for Providers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+impl Providers {
+    pub(super) fn insert(&mut self, scheme: &str, provider: impl SecretProvider + 'static) {
+        self.0.insert(scheme.to_string(), Box::new(provider));
+    }
+
+    /// The secret `value` of `var` refers to, or None if it is the secret itself.
+    pub(super) fn resolve(&self, var: &str, value: &str) -> crate::Result<Option<String>> {
+        let Some((scheme, reference)) = value.split_once(':') else {
+            return Ok(None);
+        };
+        let Some(provider) = self.0.get(scheme) else {
+            return Ok(None);
+        };
+        let secret = provider.resolve(reference).map_err(|e| {
+            Error::config(format!(
+                "Resolving {} from its {} reference failed: {}",
+                var, scheme, e
+            ))
+        })?;
+        let secret = secret.strip_suffix('\n').unwrap_or(&secret);
+        let secret = secret.strip_suffix('\r').unwrap_or(secret);
+        Ok(Some(secret.to_string()))
+    }
+}
+
+fn read_file(path: &str) -> io::Result<String> {
+    fs::read_to_string(path)
+}
+
+fn read_env(name: &str) -> io::Result<String> {
+    env::var(name).map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))
+}
+
+/// Runs the program a reference names, spaces and all, with no arguments, and takes what it
+/// prints as the secret. It is not registered by default, since whoever can write a config file
+/// could then have the process run any program; an application that wants it registers it
+/// itself, with `Config::builder().secret_provider("exec", CommandProvider)`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CommandProvider;
+
+impl SecretProvider for CommandProvider {
+    fn resolve(&self, program: &str) -> io::Result<String> {
+        let output = Command::new(program).output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "{} exited with {}",
+                program, output.status
+            )));
+        }
+        String::from_utf8(output.stdout).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn references_resolve_by_scheme() {
+        let path = env::temp_dir().join(format!("crumb-secret-{}", std::process::id()));
+        fs::write(&path, "from-file\n").unwrap();
+        let mut providers = Providers::default();
+        providers.insert("vault", |reference: &str| {
+            Ok(format!("vault:{}", reference))
+        });
+        let resolve = |value: &str| providers.resolve("CRUMB_PSK", value).unwrap();
+
+        assert_eq!(
+            resolve(&format!("file:{}", path.display())).as_deref(),
+            Some("from-file")
+        );
+        assert_eq!(
+            resolve("vault:crumb/psk").as_deref(),
+            Some("vault:crumb/psk")
+        );
+        assert_eq!(resolve("00ff00ff"), None);
+        assert_eq!(resolve("keyring:psk"), None);
+        assert_eq!(resolve("exec:/bin/false"), None);
+
+        // Failures name the variable but not the reference.
+        let _ = fs::remove_file(&path);
+        let e = providers
+            .resolve("CRUMB_PSK", &format!("file:{}", path.display()))
+            .unwrap_err();
+        assert!(e.to_string().contains("CRUMB_PSK from its file reference"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn commands_run_once_registered() {
+        let path = env::temp_dir().join(format!("crumb echo {}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        std::os::unix::fs::symlink("/bin/echo", &path).unwrap();
+        let mut providers = Providers::default();
+        providers.insert("exec", CommandProvider);
+
+        // The whole reference names the program, which echoes nothing but a newline when run
+        // without arguments.
+        let secret = providers
+            .resolve("CRUMB_PSK", &format!("exec:{}", path.display()))
+            .unwrap();
+        assert_eq!(secret.as_deref(), Some(""));
+        assert!(providers.resolve("CRUMB_PSK", "exec:/bin/false").is_err());
+        let _ = fs::remove_file(path);
+    }
+}
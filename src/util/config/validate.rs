@@ -0,0 +1,320 @@
+use super::{Config, DeliveryMode, SecurityMode, TransportType};
+use crate::protocol::MAX_IDENTITY_LEN;
+use crate::security::{decode_hex, parse_cidrs, parse_keys, Psk};
+use crate::transport::relay::MAX_PEER_ID_LEN;
+use crate::transport::socks::Proxy;
+use std::{fmt, fs::File, net::IpAddr};
+
+// DSCP is a six bit field.
+const MAX_DSCP: u8 = 63;
+
+// Ordered receivers allocate a slot per message in the window, and sequence numbers compare
+// with serial arithmetic, so windows stay far below 2^31.
+const MAX_REORDER_WINDOW: usize = 1 << 16;
+
+// Replay windows likewise keep a flag per sequence number.
+const MAX_REPLAY_WINDOW: usize = 1 << 16;
+
+/// A config value that cannot work, named by its `CRUMB_*` variable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub var: &'static str,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.var, self.message)
+    }
+}
+
+impl Config {
+    /// Checks the config for values that would fail or misbehave at runtime and returns every
+    /// violation found, so a config can be fixed in one pass.
+    pub fn validate(&self) -> Result<(), Vec<Violation>> {
+        let mut violations = Vec::new();
+        let mut check = |ok: bool, var: &'static str, message: String| {
+            if !ok {
+                violations.push(Violation { var, message });
+            }
+        };
+
+        check(
+            self.port != 0,
+            "CRUMB_PORT",
+            "port must not be 0".to_string(),
+        );
+        check(
+            !self.host.is_empty(),
+            "CRUMB_HOST",
+            "host must not be empty".to_string(),
+        );
+        check(
+            self.bind_host.parse::<IpAddr>().is_ok(),
+            "CRUMB_BIND_HOST",
+            format!("not an IP address: {}", self.bind_host),
+        );
+        check(
+            self.multicast_group.is_empty()
+                || self
+                    .multicast_group
+                    .parse::<IpAddr>()
+                    .is_ok_and(|ip| ip.is_multicast()),
+            "CRUMB_MULTICAST_GROUP",
+            format!("not a multicast address: {}", self.multicast_group),
+        );
+        check(
+            self.dscp <= MAX_DSCP,
+            "CRUMB_DSCP",
+            format!("must be between 0 and {}: {}", MAX_DSCP, self.dscp),
+        );
+        check(
+            !self.discovery_interval.is_zero(),
+            "CRUMB_DISCOVERY_INTERVAL_MS",
+            "discovery interval must not be 0".to_string(),
+        );
+        check(
+            !self.ordered || self.delivery.is_reliable(),
+            "CRUMB_ORDERED",
+            "ordered delivery requires a reliable CRUMB_DELIVERY".to_string(),
+        );
+        check(
+            (1..=MAX_REORDER_WINDOW).contains(&self.reorder_window),
+            "CRUMB_REORDER_WINDOW",
+            format!(
+                "must be between 1 and {}: {}",
+                MAX_REORDER_WINDOW, self.reorder_window
+            ),
+        );
+        check(
+            self.replay_window <= MAX_REPLAY_WINDOW,
+            "CRUMB_REPLAY_WINDOW",
+            format!(
+                "must be at most {}: {}",
+                MAX_REPLAY_WINDOW, self.replay_window
+            ),
+        );
+        check(
+            self.delivery != DeliveryMode::ExactlyOnce || self.ordered || self.replay_window > 0,
+            "CRUMB_REPLAY_WINDOW",
+            "exactly-once delivery needs a replay window to drop duplicates".to_string(),
+        );
+        check(
+            self.send_queue_capacity > 0,
+            "CRUMB_SEND_QUEUE_CAPACITY",
+            "send queue capacity must not be 0".to_string(),
+        );
+        if !self.spool_dir.is_empty() {
+            check(
+                self.delivery.is_reliable(),
+                "CRUMB_SPOOL_DIR",
+                "spooling requires a reliable CRUMB_DELIVERY".to_string(),
+            );
+            check(
+                self.spool_max_bytes > 0,
+                "CRUMB_SPOOL_MAX_BYTES",
+                "spool size cap must not be 0".to_string(),
+            );
+        }
+        check(
+            (0.0..=1.0).contains(&self.reconnect_jitter),
+            "CRUMB_RECONNECT_JITTER",
+            format!("must be between 0 and 1: {}", self.reconnect_jitter),
+        );
+        check(
+            self.workers > 0,
+            "CRUMB_WORKERS",
+            "at least one worker is required".to_string(),
+        );
+        if self.transport_type == TransportType::Quic {
+            check(
+                readable(&self.pem_path),
+                "CRUMB_PEM_PATH",
+                format!("QUIC needs a readable PEM file: {}", self.pem_path),
+            );
+            check(
+                !self.handshake_timeout.is_zero(),
+                "CRUMB_HANDSHAKE_TIMEOUT_MS",
+                "handshakes need time to complete".to_string(),
+            );
+        }
+        if self.transport_type == TransportType::Unix {
+            check(
+                cfg!(unix) && !self.socket_path.is_empty(),
+                "CRUMB_SOCKET_PATH",
+                "the UNIX transport needs a socket path, and a UNIX host".to_string(),
+            );
+        }
+        if !self.proxy.is_empty() {
+            let parsed = Proxy::parse(&self.proxy);
+            check(
+                parsed.is_ok(),
+                "CRUMB_PROXY",
+                parsed.err().map(|e| e.to_string()).unwrap_or_default(),
+            );
+            check(
+                self.transport_type == TransportType::Udp,
+                "CRUMB_PROXY",
+                "only the UDP transport can be proxied".to_string(),
+            );
+        }
+        if !self.relay_peer.is_empty() {
+            check(
+                !self.node_id.is_empty() && self.node_id.len() <= MAX_PEER_ID_LEN,
+                "CRUMB_NODE_ID",
+                format!(
+                    "relayed clients register under a node ID of 1 to {} bytes",
+                    MAX_PEER_ID_LEN
+                ),
+            );
+            check(
+                self.relay_peer.len() <= MAX_PEER_ID_LEN,
+                "CRUMB_RELAY_PEER",
+                format!("peer IDs are at most {} bytes", MAX_PEER_ID_LEN),
+            );
+            check(
+                self.transport_type == TransportType::Udp,
+                "CRUMB_RELAY_PEER",
+                "only the UDP transport can be relayed".to_string(),
+            );
+        } else {
+            check(
+                self.node_id.len() <= MAX_PEER_ID_LEN,
+                "CRUMB_NODE_ID",
+                format!("must be at most {} bytes", MAX_PEER_ID_LEN),
+            );
+        }
+        check(
+            self.mqtt_qos <= 2,
+            "CRUMB_MQTT_QOS",
+            format!("must be 0, 1 or 2: {}", self.mqtt_qos),
+        );
+        check(
+            self.kafka_batch_size > 0,
+            "CRUMB_KAFKA_BATCH_SIZE",
+            "must be at least 1".to_string(),
+        );
+        if self.security == SecurityMode::Psk {
+            let loaded = Psk::from_config(self);
+            let var = match (
+                self.psk_identity.len() > MAX_IDENTITY_LEN,
+                self.psk.is_empty(),
+            ) {
+                (true, _) => "CRUMB_PSK_IDENTITY",
+                (false, true) => "CRUMB_PSK_PATH",
+                (false, false) => "CRUMB_PSK",
+            };
+            check(
+                loaded.is_ok(),
+                var,
+                loaded.err().map(|e| e.to_string()).unwrap_or_default(),
+            );
+            let keys = parse_keys(&self.psk_keys);
+            check(
+                keys.is_ok(),
+                "CRUMB_PSK_KEYS",
+                keys.as_ref()
+                    .err()
+                    .map(|e| e.to_string())
+                    .unwrap_or_default(),
+            );
+            let listed = keys.unwrap_or_default();
+            check(
+                listed.is_empty() || self.psk_key_id != 0,
+                "CRUMB_PSK_KEYS",
+                "needs CRUMB_PSK_KEY_ID".to_string(),
+            );
+            check(
+                listed.iter().all(|(id, _)| *id != self.psk_key_id),
+                "CRUMB_PSK_KEYS",
+                format!("key {} is CRUMB_PSK_KEY_ID", self.psk_key_id),
+            );
+        }
+        check(
+            self.control_key.is_empty() || decode_hex(self.control_key.trim()).is_some(),
+            "CRUMB_CONTROL_KEY",
+            "must be 32 bytes, hex encoded".to_string(),
+        );
+        for (list, var) in [
+            (&self.acl_allow, "CRUMB_ACL_ALLOW"),
+            (&self.acl_deny, "CRUMB_ACL_DENY"),
+        ] {
+            let parsed = parse_cidrs(list);
+            check(
+                parsed.is_ok(),
+                var,
+                parsed.err().map(|e| e.to_string()).unwrap_or_default(),
+            );
+        }
+        check(
+            self.proto_path.is_empty() || readable(&self.proto_path),
+            "CRUMB_PROTO_PATH",
+            format!("not a readable file: {}", self.proto_path),
+        );
+
+        match violations.is_empty() {
+            true => Ok(()),
+            false => Err(violations),
+        }
+    }
+}
+
+fn readable(path: &str) -> bool {
+    File::open(path).is_ok_and(|file| file.metadata().is_ok_and(|metadata| metadata.is_file()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROTO: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+
+    #[test]
+    fn defaults_are_valid() {
+        let conf = Config {
+            proto_path: PROTO.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(conf.validate(), Ok(()));
+    }
+
+    #[test]
+    fn every_violation_is_reported() {
+        let conf = Config {
+            port: 0,
+            ordered: true,
+            delivery: DeliveryMode::AtMostOnce,
+            reorder_window: 0,
+            replay_window: usize::MAX,
+            reconnect_jitter: 2.0,
+            transport_type: TransportType::Quic,
+            pem_path: "does/not/exist.pem".to_string(),
+            handshake_timeout: std::time::Duration::ZERO,
+            security: SecurityMode::Psk,
+            psk: "not a key".to_string(),
+            acl_deny: "10.0.0.0/40".to_string(),
+            proto_path: PROTO.to_string(),
+            ..Default::default()
+        };
+        let vars: Vec<_> = conf
+            .validate()
+            .unwrap_err()
+            .into_iter()
+            .map(|violation| violation.var)
+            .collect();
+        assert_eq!(
+            vars,
+            [
+                "CRUMB_PORT",
+                "CRUMB_ORDERED",
+                "CRUMB_REORDER_WINDOW",
+                "CRUMB_REPLAY_WINDOW",
+                "CRUMB_RECONNECT_JITTER",
+                "CRUMB_PEM_PATH",
+                "CRUMB_HANDSHAKE_TIMEOUT_MS",
+                "CRUMB_PSK",
+                "CRUMB_ACL_DENY"
+            ]
+        );
+    }
+}
@@ -1 +1,54 @@
 pub mod config;
+
+use config::Config;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::OnceLock;
+
+/// The ID this node goes by: `node_id` if set, otherwise a random UUID generated once per
+/// process, so discovery and every session agree on it.
+pub(crate) fn node_id(conf: &Config) -> String {
+    static RANDOM: OnceLock<String> = OnceLock::new();
+    match conf.node_id.as_str() {
+        "" => RANDOM.get_or_init(random_uuid).clone(),
+        node_id => node_id.to_string(),
+    }
+}
+
+// A version 4 UUID, from the randomly keyed hasher the standard library seeds per instance.
+fn random_uuid() -> String {
+    let high = RandomState::new().hash_one(std::process::id());
+    let low = RandomState::new().hash_one(std::process::id());
+    let high = (high & !0xf000) | 0x4000;
+    let low = (low & !(0b11 << 62)) | (0b10 << 62);
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0xffff,
+        low >> 48,
+        low & 0xffff_ffff_ffff
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_ids_come_from_config_or_are_random_per_process() {
+        let random = node_id(&Config::default());
+        assert_eq!(random, node_id(&Config::default()));
+        assert_eq!(random.len(), 36);
+        assert_eq!(&random[14..15], "4");
+        assert!(
+            matches!(&random[19..20], "8" | "9" | "a" | "b"),
+            "{}",
+            random
+        );
+        let named = Config {
+            node_id: "pod-7".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(node_id(&named), "pod-7");
+    }
+}